@@ -0,0 +1,21 @@
+#!/bin/bash
+#
+#   package_proto.sh
+#
+#   This script packages the query protobuf definition as a standalone
+#   artifact, so that clients in other languages can be generated from
+#   it without checking out the server.
+
+set -e
+
+VERSION=`grep '^version' Cargo.toml | head -n 1 | cut -d '"' -f 2`
+OUTPUT=${CIRCLE_ARTIFACTS:-target}/largetable-proto-$VERSION
+
+mkdir -p $OUTPUT
+cp core/src/protobuf/query.proto $OUTPUT/
+
+# Make sure that the definition compiles for other languages.
+protoc --python_out $OUTPUT --proto_path core/src/protobuf core/src/protobuf/query.proto
+
+tar -czf $OUTPUT.tar.gz -C `dirname $OUTPUT` `basename $OUTPUT`
+echo "packaged $OUTPUT.tar.gz"
@@ -2,7 +2,7 @@
 
 set -e
 
-for file in src/testcases/*.txt;
+for file in core/src/testcases/*.txt;
  do
   EXPECTED=`awk 'NR % 2 == 0' $file`;
   RECEIVED=`awk 'NR % 2 == 1' $file | ./target/debug/largetable-cli --stdin localhost:8080`;
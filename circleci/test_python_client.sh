@@ -0,0 +1,12 @@
+#!/bin/bash
+#
+#   test_python_client.sh
+#
+#   This script generates the python protobuf code, and then runs the
+#   python client's tests against the docker container.
+
+set -e
+
+sudo pip install protobuf
+protoc --python_out clients/python --proto_path core/src/protobuf core/src/protobuf/query.proto
+python clients/python/test_largetable.py
@@ -0,0 +1,60 @@
+"""
+    largetable.py
+
+    A minimal python client for largetable, using the code generated from
+    query.proto. It speaks version 1 of the protobuf protocol: each query
+    is posted to /v1/query, and the response body is a QueryResult.
+
+    Generate query_pb2.py first, with:
+
+        protoc --python_out clients/python --proto_path core/src/protobuf \\
+            core/src/protobuf/query.proto
+"""
+
+try:
+    from urllib.request import Request, urlopen
+    from urllib.error import HTTPError
+except ImportError:
+    from urllib2 import Request, urlopen, HTTPError
+
+import query_pb2
+
+PROTOCOL_VERSION = "1"
+
+
+class LargeClient(object):
+    def __init__(self, hostname, auth_token=None):
+        self.url = "http://%s/v1/query" % hostname
+        self.auth_token = auth_token
+
+    def query(self, q):
+        """Send a query_pb2.Query, and return the query_pb2.QueryResult."""
+        request = Request(self.url, data=q.SerializeToString())
+        request.add_header("Content-Type", "application/x-protobuf")
+        if self.auth_token:
+            request.add_header("Authorization", "Bearer " + self.auth_token)
+
+        # Results like Busy and PermissionDenied come with an error
+        # status, but the body is still a QueryResult.
+        try:
+            response = urlopen(request)
+        except HTTPError as e:
+            response = e
+
+        version = response.headers.get("X-Largetable-Protocol")
+        if version != PROTOCOL_VERSION:
+            raise IOError("unsupported protocol version: %s" % version)
+
+        result = query_pb2.QueryResult()
+        result.ParseFromString(response.read())
+        return result
+
+    def select(self, row, columns):
+        q = query_pb2.Query(type=query_pb2.SELECT, row=row, columns=columns)
+        return self.query(q)
+
+    def update(self, row, values):
+        q = query_pb2.Query(type=query_pb2.UPDATE, row=row)
+        for key, value in values.items():
+            q.values[key] = value
+        return self.query(q)
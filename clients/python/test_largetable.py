@@ -0,0 +1,37 @@
+"""
+    test_largetable.py
+
+    These integration tests assume that a largetable service is running
+    on localhost:8080 (or LARGETABLE_DOCKER_SERVICE), and talk to it with
+    the python client.
+"""
+
+import os
+import unittest
+
+import query_pb2
+from largetable import LargeClient
+
+
+class LargeClientTest(unittest.TestCase):
+    def setUp(self):
+        hostname = os.environ.get("LARGETABLE_DOCKER_SERVICE", "localhost:8080")
+        self.client = LargeClient(hostname)
+
+    def test_can_update_and_select(self):
+        result = self.client.update("python_test", {"status": b"OK"})
+        self.assertEqual(result.type, query_pb2.OK)
+
+        result = self.client.select("python_test", ["status", "missing"])
+        self.assertEqual(result.type, query_pb2.DATA)
+        self.assertTrue(result.columns[0].has_data)
+        self.assertEqual(result.columns[0].data, b"OK")
+        self.assertFalse(result.columns[1].has_data)
+
+    def test_can_get_stats(self):
+        result = self.client.query(query_pb2.Query(type=query_pb2.STATS))
+        self.assertEqual(result.type, query_pb2.ENGINE_STATS)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,183 @@
+/*
+    acl.rs
+
+    Access control lists restrict which rows each auth token can read
+    and write, by row key prefix. With no rules, everything is allowed.
+*/
+
+use std::collections::HashMap;
+
+use keys;
+use query;
+
+// An AccessRule lets the holder of the token read rows whose keys start
+// with one of the read prefixes, and write rows whose keys start with one
+// of the write prefixes. An empty prefix matches every row.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccessRule {
+    pub token: String,
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>
+}
+
+pub struct AccessControl {
+    rules: HashMap<String, AccessRule>
+}
+
+// Check whether every key in [start, end) starts with the prefix. An
+// empty end means that the range has no upper bound.
+fn range_within(prefix: &str, start: &str, end: &str) -> bool {
+    if !start.starts_with(prefix) {
+        return false;
+    }
+
+    match keys::prefix_end(prefix) {
+        None    => prefix.is_empty(),
+        Some(e) => !end.is_empty() && end <= e.as_str()
+    }
+}
+
+impl AccessControl {
+    // With no rules, access control is disabled.
+    pub fn new() -> AccessControl {
+        AccessControl{
+            rules: HashMap::new()
+        }
+    }
+
+    pub fn add_rule(&mut self, rule: AccessRule) {
+        self.rules.insert(rule.token.clone(), rule);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    pub fn can_read(&self, token: &str, row: &str) -> bool {
+        match self.rules.get(token) {
+            Some(r) => r.read.iter().any(|p| row.starts_with(p.as_str())),
+            None    => !self.is_enabled()
+        }
+    }
+
+    pub fn can_write(&self, token: &str, row: &str) -> bool {
+        match self.rules.get(token) {
+            Some(r) => r.write.iter().any(|p| row.starts_with(p.as_str())),
+            None    => !self.is_enabled()
+        }
+    }
+
+    // Check whether the token may run the query. Key listings, scans and
+    // hot key reports are allowed for any known token, but the rows that
+    // they return have to be filtered down to the readable ones
+    // afterwards. Range deletions have to fall entirely within one
+    // writable prefix, and range checksums within one readable prefix,
+    // since they can't be filtered.
+    pub fn allows(&self, token: &str, q: &query::Query) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+
+        let rule = match self.rules.get(token) {
+            Some(r) => r,
+            None    => return false
+        };
+
+        match *q {
+            query::Query::Select{ref row, ..} |
+            query::Query::SelectList{ref row, ..} |
+            query::Query::History{ref row, ..} |
+            query::Query::Describe{ref row} |
+            query::Query::Checksum{ref row} |
+            query::Query::Diff{ref row, ..} |
+            query::Query::Execute{ref row, ..} => self.can_read(token, row),
+            query::Query::Insert{ref row, ..} |
+            query::Query::Update{ref row, ..} |
+            query::Query::Append{ref row, ..} |
+            query::Query::SoftDelete{ref row} |
+            query::Query::Undelete{ref row} => self.can_write(token, row),
+            query::Query::Transaction{ref updates} =>
+                updates.keys().all(|row| self.can_write(token, row)),
+            query::Query::DeleteRange{ref start_row, ref end_row} =>
+                rule.write.iter().any(|p| range_within(p, start_row, end_row)),
+            query::Query::ChecksumRange{ref start, ref end} =>
+                rule.read.iter().any(|p| range_within(p, start, end)),
+            query::Query::ListKeys{..} |
+            query::Query::TopKeys{..} |
+            query::Query::Scan{..} |
+            query::Query::Stats |
+            query::Query::CreateSnapshot |
+            query::Query::Prepare{..} => true,
+            // Compaction affects every row, so only a token which can
+            // write all of them may freeze it.
+            query::Query::FreezeCompaction |
+            query::Query::UnfreezeCompaction => rule.write.iter().any(|p| p.is_empty()),
+            // Explaining a query needs the same access as running it.
+            query::Query::Explain(ref e) => self.allows(token, e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use query;
+
+    fn rules() -> super::AccessControl {
+        let mut acl = super::AccessControl::new();
+        acl.add_rule(super::AccessRule{
+            token: String::from("alice"),
+            read: vec![String::from("users/"), String::from("public/")],
+            write: vec![String::from("users/alice/")]
+        });
+        acl
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        let acl = super::AccessControl::new();
+        assert!(acl.allows("", &query::Query::new_select("any", &[])));
+        assert!(acl.can_write("", "any"));
+    }
+
+    #[test]
+    fn enforces_prefixes() {
+        let acl = rules();
+        assert!(acl.allows("alice", &query::Query::new_select("public/page", &[])));
+        assert!(!acl.allows("alice", &query::Query::new_select("private/page", &[])));
+        assert!(acl.allows("alice", &query::Query::new_update("users/alice/name", vec![])));
+        assert!(!acl.allows("alice", &query::Query::new_update("users/bob/name", vec![])));
+        assert!(!acl.allows("mallory", &query::Query::new_select("public/page", &[])));
+        assert!(!acl.allows("", &query::Query::Stats));
+        assert!(!acl.allows("alice", &query::Query::FreezeCompaction));
+        assert!(!acl.allows("alice", &query::Query::Explain(Box::new(query::Query::new_select("private/page", &[])))));
+    }
+
+    #[test]
+    fn checks_range_deletions() {
+        let acl = rules();
+        let delete = |start: &str, end: &str| query::Query::DeleteRange{
+            start_row: start.to_owned(),
+            end_row: end.to_owned()
+        };
+        assert!(acl.allows("alice", &delete("users/alice/a", "users/alice/z")));
+        assert!(acl.allows("alice", &delete("users/alice/", "users/alice0")));
+        assert!(!acl.allows("alice", &delete("users/alice/a", "users/bob/")));
+        assert!(!acl.allows("alice", &delete("users/alice/a", "")));
+    }
+
+    #[test]
+    fn checks_range_checksums() {
+        let acl = rules();
+        let checksum = |start: &str, end: &str| query::Query::ChecksumRange{
+            start: start.to_owned(),
+            end: end.to_owned()
+        };
+        assert!(acl.allows("alice", &checksum("users/bob/", "users/bob0")));
+        assert!(acl.allows("alice", &checksum("public/a", "public/b")));
+        assert!(!acl.allows("alice", &checksum("public/", "users/")));
+        assert!(!acl.allows("alice", &checksum("", "")));
+        assert!(!acl.allows("alice", &query::Query::Checksum{row: String::from("private/page")}));
+    }
+}
@@ -0,0 +1,275 @@
+/*
+    audit.rs
+
+    The audit log records who changed which rows, and when. Unlike the
+    commit log, it's never replayed or truncated by compaction: it's an
+    append-only record for compliance, with one JSON entry per line,
+    which is rotated into numbered files once it gets too big.
+*/
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use serde_json;
+
+use query;
+use storage;
+
+// The file currently being written to. Rotated files are named by
+// sequence number, like 00000000000000000001.audit, so that sorting the
+// names puts them in the order they were written.
+const CURRENT_FILE: &'static str = "current.audit";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    // Wall clock time, in nanoseconds since the unix epoch.
+    pub timestamp: u64,
+
+    // Auth tokens are secrets, so only a fingerprint of the token is
+    // kept, which can be matched up with the tokens in the config.
+    pub token: String,
+    pub address: String,
+    pub trace_id: String,
+    pub operation: String,
+    pub row: String,
+    #[serde(default)]
+    pub end_row: String,
+    #[serde(default)]
+    pub columns: Vec<String>
+}
+
+impl AuditEntry {
+    // Describe the mutations made by the query, which is one for each row
+    // that a transaction changes, or none if the query doesn't change
+    // anything.
+    pub fn from_query(q: &query::Query, context: &query::QueryContext, timestamp: u64) -> Vec<AuditEntry> {
+        let entry = |operation: &str, row: &str, end_row: &str, mut columns: Vec<String>| {
+            columns.sort();
+            AuditEntry{
+                timestamp: timestamp,
+                token: fingerprint(&context.auth_token),
+                address: context.client_address.clone(),
+                trace_id: context.trace_id.clone(),
+                operation: operation.to_owned(),
+                row: row.to_owned(),
+                end_row: end_row.to_owned(),
+                columns: columns
+            }
+        };
+
+        let (operation, row, end_row, columns) = match *q {
+            query::Query::Insert{ref row, ref set} =>
+                ("insert", row.clone(), String::new(), set.keys().cloned().collect::<Vec<_>>()),
+            query::Query::Update{ref row, ref set} =>
+                ("update", row.clone(), String::new(), set.keys().cloned().collect::<Vec<_>>()),
+            query::Query::Append{ref row, ref set} =>
+                ("append", row.clone(), String::new(), set.keys().cloned().collect::<Vec<_>>()),
+            query::Query::DeleteRange{ref start_row, ref end_row} =>
+                ("delete_range", start_row.clone(), end_row.clone(), vec![]),
+            query::Query::Transaction{ref updates} => {
+                let mut entries = updates.iter()
+                    .map(|(row, set)| entry("transaction", row, "", set.keys().cloned().collect()))
+                    .collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.row.cmp(&b.row));
+                return entries;
+            },
+            _ => return vec![]
+        };
+
+        vec![entry(operation, &row, &end_row, columns)]
+    }
+
+    // Check whether the entry changed the row. Range deletions cover
+    // every row in [row, end_row), and an empty end_row is unbounded.
+    pub fn touches(&self, row: &str) -> bool {
+        match self.operation.as_str() {
+            "delete_range"  => row >= self.row.as_str() && (self.end_row.is_empty() || row < self.end_row.as_str()),
+            _               => row == self.row
+        }
+    }
+}
+
+// A short, stable fingerprint of the token (FNV-1a), or an empty string
+// if there's no token.
+pub fn fingerprint(token: &str) -> String {
+    if token.is_empty() {
+        return String::new();
+    }
+
+    let hash = token.as_bytes().iter()
+        .fold(0xcbf29ce484222325u64, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+pub struct AuditLog {
+    storage: Arc<storage::Storage>,
+    directory: String,
+    file: Box<storage::StorageFile>,
+    bytes: u64,
+
+    // max_bytes: the size at which the current file is rotated, or 0 to
+    // never rotate. max_files: the number of rotated files to keep, or 0
+    // to keep them all.
+    pub max_bytes: u64,
+    pub max_files: usize
+}
+
+impl AuditLog {
+    pub fn open(storage: Arc<storage::Storage>, directory: &str) -> io::Result<AuditLog> {
+        storage.create_dir_all(directory)?;
+        let file = storage.append(&format!("{}/{}", directory, CURRENT_FILE))?;
+        let bytes = file.len()?;
+
+        Ok(AuditLog{
+            storage: storage,
+            directory: directory.to_owned(),
+            file: file,
+            bytes: bytes,
+            max_bytes: 0,
+            max_files: 0
+        })
+    }
+
+    pub fn record(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to encode audit entry"))?;
+        line.push('\n');
+
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync()?;
+        self.bytes += line.len() as u64;
+
+        if self.max_bytes > 0 && self.bytes >= self.max_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    // The rotated files, oldest first.
+    fn rotated_files(&self) -> io::Result<Vec<String>> {
+        let current = format!("{}/{}", self.directory, CURRENT_FILE);
+        Ok(self.storage.list(&self.directory, "audit")?
+            .into_iter()
+            .filter(|p| *p != current)
+            .collect())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = self.rotated_files()?;
+        let next = rotated.last()
+            .and_then(|p| p.rsplit('/').next())
+            .and_then(|name| name.trim_right_matches(".audit").parse::<u64>().ok())
+            .unwrap_or(0) + 1;
+
+        let current = format!("{}/{}", self.directory, CURRENT_FILE);
+        self.storage.rename(&current, &format!("{}/{:020}.audit", self.directory, next))?;
+        self.file = self.storage.append(&current)?;
+        self.bytes = 0;
+
+        if self.max_files > 0 && rotated.len() + 1 > self.max_files {
+            for path in rotated.iter().take(rotated.len() + 1 - self.max_files) {
+                self.storage.remove(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Find the most recent entries (up to the limit) which changed the
+    // row, oldest first.
+    pub fn search(&self, row: &str, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        let mut paths = self.rotated_files()?;
+        paths.push(format!("{}/{}", self.directory, CURRENT_FILE));
+
+        let mut matches = vec![];
+        for path in paths {
+            let mut contents = String::new();
+            self.storage.open(&path)?.read_to_string(&mut contents)?;
+
+            // A line that doesn't parse was probably cut short by a
+            // crash, so it's skipped.
+            matches.extend(contents.lines()
+                .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                .filter(|e| e.touches(row)));
+        }
+
+        let skip = matches.len().saturating_sub(limit);
+        Ok(matches.into_iter().skip(skip).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::collections::HashMap as Map;
+    use std::iter::FromIterator;
+    use query;
+    use storage;
+
+    fn entry(row: &str, timestamp: u64) -> super::AuditEntry {
+        let q = query::Query::Update{
+            row: row.to_owned(),
+            set: Map::from_iter(vec![(String::from("status"), b"OK".to_vec())])
+        };
+        let context = query::QueryContext{
+            auth_token: String::from("secret"),
+            client_address: String::from("10.0.0.1"),
+            ..Default::default()
+        };
+        super::AuditEntry::from_query(&q, &context, timestamp).remove(0)
+    }
+
+    #[test]
+    fn describes_mutations() {
+        let e = entry("row1", 100);
+        assert_eq!(e.operation, "update");
+        assert_eq!(e.columns, vec![String::from("status")]);
+        assert_eq!(e.token, super::fingerprint("secret"));
+        assert!(e.token != "secret");
+
+        let context = query::QueryContext::new();
+        assert!(super::AuditEntry::from_query(&query::Query::new_select("row1", &[]), &context, 0).is_empty());
+
+        let delete = super::AuditEntry::from_query(&query::Query::DeleteRange{
+            start_row: String::from("b"),
+            end_row: String::from("d")
+        }, &context, 0).remove(0);
+        assert!(delete.touches("c"));
+        assert!(!delete.touches("d"));
+
+        // Each row in a transaction gets its own entry.
+        let set = || Map::from_iter(vec![(String::from("balance"), b"10".to_vec())]);
+        let transaction = super::AuditEntry::from_query(&query::Query::Transaction{
+            updates: Map::from_iter(vec![(String::from("b"), set()), (String::from("a"), set())])
+        }, &context, 0);
+        assert_eq!(
+            transaction.iter().map(|e| (e.operation.as_str(), e.row.as_str())).collect::<Vec<_>>(),
+            vec![("transaction", "a"), ("transaction", "b")]
+        );
+    }
+
+    #[test]
+    fn can_search_across_rotations() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut log = super::AuditLog::open(storage.clone(), "/audit").unwrap();
+        log.max_bytes = 200;
+        log.max_files = 2;
+
+        for i in 0..20 {
+            log.record(&entry(if i % 2 == 0 { "even" } else { "odd" }, i)).unwrap();
+        }
+
+        // Only the newest files are kept, so the oldest entries are gone.
+        let found = log.search("even", 100).unwrap();
+        assert!(found.len() > 0 && found.len() < 10);
+        assert_eq!(found.last().unwrap().timestamp, 18);
+        assert!(found.iter().all(|e| e.row == "even"));
+
+        assert_eq!(log.search("odd", 1).unwrap(), vec![entry("odd", 19)]);
+        assert_eq!(storage.list("/audit", "audit").unwrap().len(), 3);
+
+        // Reopening carries on with the same files.
+        let log = super::AuditLog::open(storage.clone(), "/audit").unwrap();
+        assert_eq!(log.search("odd", 1).unwrap(), vec![entry("odd", 19)]);
+    }
+}
@@ -0,0 +1,6596 @@
+/*
+    base.rs
+
+    This file contains the core functions of the database, such as
+    handling queries on the tables, generating and merging tables,
+    etc.
+*/
+
+use std;
+use std::fmt;
+use std::iter;
+use std::iter::FromIterator;
+use std::mem;
+use std::u64;
+use std::io;
+use std::io::{Read, Seek};
+use std::ffi::CString;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::cell::{Cell, RefCell};
+
+use time;
+use libc;
+use regex;
+use mtable;
+use dtable;
+use query;
+use scan;
+use keys;
+use acl;
+use schema;
+use salting;
+use families;
+use transform;
+use migration;
+use audit;
+use logarchive;
+use replication;
+use hints;
+use shards;
+use idempotency;
+use prepared;
+use rowcache;
+use resultcache;
+use hotrows;
+use storage;
+use spans;
+use tempdir;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use protobuf;
+use protobuf::Message;
+
+use generated::dtable::*;
+
+#[derive(Debug)]
+pub enum BaseError {
+    // A file couldn't be read or written.
+    Io{path: String, error: io::Error},
+
+    // A file was read, but what's in it doesn't make sense. The offset is
+    // where the problem was found, or 0 if it's about the whole file.
+    Corrupted{path: String, offset: u64, reason: String},
+
+    Problem{reason: String}
+}
+
+impl BaseError {
+    fn io(path: &str, error: io::Error) -> BaseError {
+        BaseError::Io{path: path.to_owned(), error: error}
+    }
+
+    fn corrupted(path: &str, offset: u64, reason: &str) -> BaseError {
+        BaseError::Corrupted{path: path.to_owned(), offset: offset, reason: reason.to_owned()}
+    }
+
+    // Add the path of the dtable to an error from reading or writing it.
+    fn from_dtable(path: &str, error: dtable::TError) -> BaseError {
+        match error {
+            dtable::TError::Io(e)                       => BaseError::io(path, e),
+            dtable::TError::Corrupted{offset, reason}   => BaseError::Corrupted{
+                path: path.to_owned(),
+                offset: offset,
+                reason: reason
+            },
+            e => BaseError::Problem{reason: format!("{}: {}", path, e)}
+        }
+    }
+}
+
+impl fmt::Display for BaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BaseError::Io{ref path, ref error}                  => write!(f, "unable to access {}: {}", path, error),
+            BaseError::Corrupted{ref path, offset, ref reason}  => write!(f, "{} is corrupted at offset {}: {}", path, offset, reason),
+            BaseError::Problem{ref reason}                      => write!(f, "{}", reason)
+        }
+    }
+}
+
+// The FsyncPolicy decides how often writes are flushed all the way to
+// disk. Always is the safest, but every write has to wait for the disk.
+// EveryNMs syncs the commit log at most once per interval, with a timer
+// to sync the last writes once the interval is up. OnFlushOnly only
+// syncs when the memtable is written out to a dtable.
+// The policy only applies to the commit log: dtables written by a flush
+// or a compaction are always synced before they replace anything.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    #[serde(rename = "always")]
+    Always,
+    #[serde(rename = "every_n_ms")]
+    EveryNMs,
+    #[serde(rename = "on_flush_only")]
+    OnFlushOnly
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                FsyncPolicy::Always         => "always",
+                FsyncPolicy::EveryNMs       => "every_n_ms",
+                FsyncPolicy::OnFlushOnly    => "on_flush_only"
+            }
+        )
+    }
+}
+
+// The DTablePlacement decides which of the data directories a new dtable
+// goes in. RoundRobin takes turns, LeastUsed picks the directory holding
+// the fewest bytes of live dtables, and MostFreeSpace picks the one on
+// the filesystem with the most room left, which suits disks of different
+// sizes. Ties are broken by taking turns.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum DTablePlacement {
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    #[serde(rename = "least_used")]
+    LeastUsed,
+    #[serde(rename = "most_free_space")]
+    MostFreeSpace
+}
+
+// When the commit log is replayed, entries are parsed and sent to be
+// applied to the memtable in batches of this many, with at most this many
+// batches waiting at once.
+const REPLAY_BATCH_ENTRIES: usize = 1024;
+const REPLAY_QUEUE_BATCHES: usize = 4;
+
+// Scans return at most this many rows at once, however many are asked
+// for, so that a single scan can't hold the database lock for too long.
+pub const MAX_SCAN_ROWS: usize = 10000;
+
+// Likewise, a page of a row's columns has at most this many columns.
+pub const MAX_PAGE_COLUMNS: usize = 10000;
+
+// The number of compactions remembered in the compaction history.
+const COMPACTION_HISTORY_LENGTH: usize = 20;
+
+// Free disk space is checked at most this often (in nanoseconds), rather
+// than before every write.
+const FREE_SPACE_CHECK_INTERVAL: u64 = 1_000_000_000;
+
+// A record of a single compaction, kept so that operators can see what
+// the database has been doing recently.
+#[derive(Serialize, Debug, Clone)]
+pub struct Compaction {
+    pub major: bool,
+    pub garbage_collection: bool,
+    pub timestamp: u64,
+    pub input_dtables: u64,
+    pub rows: u64,
+    pub bytes: u64,
+    pub max_fan_in: u64
+}
+
+// The result of checking that the database's files can be read, without
+// starting it up.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub dtables: usize,
+    pub archived: usize,
+    pub rows: u64,
+    pub bytes: u64,
+    pub commit_log_entries: u64,
+    pub memtable_rows: usize,
+    pub problems: Vec<String>
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "dtables: {} ({} rows, {} bytes)", self.dtables, self.rows, self.bytes)?;
+        writeln!(f, "archived dtables: {}", self.archived)?;
+        writeln!(f, "commit log: {} entries ({} rows)", self.commit_log_entries, self.memtable_rows)?;
+        for problem in &self.problems {
+            writeln!(f, "problem: {}", problem)?;
+        }
+        match self.is_ok() {
+            true  => write!(f, "OK"),
+            false => write!(f, "FAILED: {} problems", self.problems.len())
+        }
+    }
+}
+
+// A Snapshot pins a point in time, so that several selects can read a
+// consistent view of the database while writes continue.
+struct Snapshot {
+    timestamp: u64,
+    expires: u64
+}
+
+// A Peer is a replica which tails the commit log, for hinted handoff (see
+// hints.rs). Once the commit log is truncated before the replica has read
+// all of it, the rest is queued up as hints, which are sent to the
+// replica when it asks for the position it had reached, instead of the
+// commit log.
+struct Peer {
+    // The position that the replica last asked for.
+    position: replication::Position,
+    hints: hints::HintLog,
+    hinted: Option<Hinted>
+}
+
+struct Hinted {
+    // The position which the hints carry on from, and the one for the
+    // replica to carry on from once it has them.
+    start: replication::Position,
+    resume: replication::Position,
+
+    // The hints which have already been sent, in case the replica didn't
+    // receive them and asks again.
+    sent: Vec<u8>
+}
+
+pub struct Base {
+    directory: String,
+    disktable_index: u32,
+    generation: u64,
+    memtable: mtable::MTable,
+    disktables: Vec<dtable::DTable>,
+    archived: Vec<dtable::DTable>,
+    storage: Arc<storage::Storage>,
+    clock: Arc<storage::Clock>,
+    commit_log: Box<storage::StorageFile>,
+    last_fsync: u64,
+    unsynced_writes: bool,
+    last_checkpoint: u64,
+    started: u64,
+    minor_compactions: u64,
+    major_compactions: u64,
+    permission_denied: u64,
+
+    // The last reading of free_bytes, and when it was taken.
+    free_space_checked: Cell<Option<(u64, u64)>>,
+
+    // The most dtables that a row has been merged from, and the number of
+    // rows which were merged in batches because of it.
+    merge_max_fan_in: u64,
+    merge_batched_rows: u64,
+
+    // The number of writes which had to wait for the memtable to be
+    // flushed or dtables to be merged, how long they waited in total, and
+    // how long the most recent flush took.
+    write_stalls: u64,
+    write_stall_ns: u64,
+    last_flush_ns: u64,
+
+    // The number of queries run, and their costs added up.
+    queries: u64,
+    query_costs: query::QueryCost,
+    compaction_history: VecDeque<Compaction>,
+    snapshots: BTreeMap<u64, Snapshot>,
+    next_snapshot_id: u64,
+
+    // trace_id: the trace ID of the query currently being run, if any,
+    // which is added to log messages so they can be tied to a request.
+    // Spans recorded for the query are children of span_id.
+    trace_id: String,
+    span_id: String,
+
+    // The idempotency key of the write currently being run, which is
+    // recorded along with it in the commit log.
+    idempotency_key: String,
+    pub idempotency: idempotency::IdempotencyCache,
+
+    // Recently read rows (see rowcache.rs), which is disabled unless it's
+    // given a capacity. Reads only borrow the Base, so it's in a RefCell.
+    pub row_cache: RefCell<rowcache::RowCache>,
+
+    // Recent select results, for tables with a result cache TTL (see
+    // resultcache.rs).
+    pub result_cache: resultcache::ResultCache,
+
+    // The columns of prepared selects, by handle (see prepared.rs).
+    pub prepared: prepared::PreparedQueries,
+
+    // How often the busiest rows are being read and written (see
+    // hotrows.rs).
+    pub hot_rows: hotrows::HotRows,
+
+    // The leader's term (see election.rs), which is stamped on every
+    // commit log entry. Entries with an older token than one which has
+    // already been applied came from a deposed leader, and are skipped.
+    pub fencing_token: u64,
+
+    // Whether this server is a replica which is tailing its primary's
+    // commit log (see src/replica.rs), and the primary's commit timestamp
+    // that it has applied every write up to. A replica refuses writes.
+    pub replica: bool,
+    caught_up_to: u64,
+
+    // With leader elections turned on, when this server's lease as the
+    // leader expires (see election.rs). Writes are refused after that.
+    pub lease_expires: Option<u64>,
+
+    pub memtable_size_limit: usize,
+    pub disktable_limit: usize,
+    pub data_directories: Vec<String>,
+    pub dtable_placement: DTablePlacement,
+    pub min_free_bytes: u64,
+    pub compaction_bytes_per_second: u64,
+
+    // If set, major compactions keep the dtables they read and write from
+    // filling up the page cache.
+    pub compaction_drop_cache: bool,
+
+    // Rows which are in more than this many of the dtables being merged
+    // are merged a batch of this many at a time, to bound the memory
+    // that they take up. Zero merges them all at once.
+    pub max_merge_fan_in: usize,
+
+    // If set, running out of room for dtables only merges those which
+    // overlap another dtable by at least this fraction of their rows.
+    // DTables which don't are left alone, even past the limit.
+    pub compaction_min_overlap: f64,
+
+    // While compaction is frozen, dtables are never merged, rewritten or
+    // archived in the background. It's saved in the manifest, so that it
+    // stays frozen after a restart.
+    compaction_frozen: bool,
+
+    // Soft deleted rows can be undeleted for this long, after which they
+    // are dropped by the next major compaction of every dtable.
+    pub soft_delete_retention_ms: u64,
+
+    // Scans read at least this many bytes from a dtable at a time, so
+    // that the rows after the one being read come along with it.
+    pub readahead_bytes: u64,
+
+    // If set, only the fences of dtable headers are kept in memory, and
+    // up to header_cache_blocks blocks of each header are cached.
+    pub lazy_headers: bool,
+    pub header_cache_blocks: usize,
+
+    // When the memtable is written to disk, it's split into dtables of
+    // about this many bytes each. Zero means it's never split.
+    pub max_dtable_bytes: u64,
+    pub snapshot_ttl_ms: u64,
+    pub fsync_policy: FsyncPolicy,
+    pub fsync_interval_ms: u64,
+
+    // The memtable is checkpointed this often, so that a restart only
+    // replays the commit log written since. Zero means it never is.
+    pub checkpoint_interval_ms: u64,
+    pub archive_after_days: u64,
+    pub archive_directory: String,
+
+    // A dtable is rewritten without the data that range deletions hide
+    // once roughly this fraction of its rows are hidden. Zero disables it.
+    pub gc_garbage_ratio: f64,
+    pub key_rules: keys::KeyRules,
+    pub access_control: acl::AccessControl,
+    pub schemas: schema::Schemas,
+    pub salting: salting::Salting,
+    pub families: families::Families,
+    pub transforms: transform::Transforms,
+    pub audit_log: Option<audit::AuditLog>,
+    pub commit_log_archive: Option<logarchive::LogArchive>,
+
+    // If set, the commit log entries which a replica misses when the
+    // commit log is truncated are kept for it in a hint file in this
+    // directory, bounded by hint_max_bytes and hint_max_age_ms.
+    pub hint_directory: String,
+    pub hint_max_bytes: u64,
+    pub hint_max_age_ms: u64,
+    peers: BTreeMap<String, Peer>,
+
+    // The position that each replica last asked for when tailing the
+    // commit log, by name. It has applied every entry before then.
+    replica_positions: BTreeMap<String, replication::Position>,
+
+    // The cluster's shard map, and this server's address in it. Rows in
+    // other servers' shards are refused with WrongShard.
+    pub shard_map: shards::ShardMap,
+    pub shard_address: String,
+    pub slow_query_ms: u64,
+    pub span_sink: Option<Arc<spans::SpanSink>>,
+
+    // The directory that a stub was created in, which is removed when the
+    // Base is dropped. This is last so that it's dropped after the files.
+    temp_directory: Option<tempdir::TempDir>
+}
+
+// The header records how much data was written, which catches data files
+// that were truncated or belong to another dtable.
+fn check_dtable_size(d: &dtable::DTable, filename: &str) -> Result<(), BaseError> {
+    if d.lookup.get_total_bytes() > 0 && d.size_on_disk() != d.lookup.get_total_bytes() {
+        return Err(BaseError::corrupted(filename, 0, &format!(
+            "{} bytes on disk, but the header says {}",
+            d.size_on_disk(),
+            d.lookup.get_total_bytes()
+        )));
+    }
+    Ok(())
+}
+
+// Returns the number of bytes available to the database on the filesystem
+// containing the directory, or None if it can't be determined.
+fn free_space(directory: &str) -> Option<u64> {
+    let path = match CString::new(directory) {
+        Ok(p)   => p,
+        Err(_)  => return None
+    };
+
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        0 => Some(stat.f_bavail as u64 * stat.f_frsize as u64),
+        _ => None
+    }
+}
+
+impl Base {
+    pub fn new(directory: &str, memtable_size_limit: usize, disktable_limit: usize) -> Base {
+        Base::with_storage(
+            directory,
+            memtable_size_limit,
+            disktable_limit,
+            Arc::new(storage::DiskStorage),
+            Arc::new(storage::SystemClock)
+        )
+    }
+
+    // with_storage creates a database which does all of its file operations
+    // through the provided storage, and reads the time from the provided
+    // clock. The simulated versions are used to test crash recovery.
+    pub fn with_storage(
+        directory: &str,
+        memtable_size_limit: usize,
+        disktable_limit: usize,
+        storage: Arc<storage::Storage>,
+        clock: Arc<storage::Clock>
+    ) -> Base {
+        let log = storage.append(&format!("{}/commit.log", directory)).unwrap();
+        let started = clock.now();
+
+        Base{
+            directory: directory.to_owned(),
+            disktable_index: 0,
+            generation: 0,
+            memtable: mtable::MTable::new(),
+            disktables: vec![],
+            archived: vec![],
+            storage: storage,
+            clock: clock,
+            commit_log: log,
+            last_fsync: 0,
+            unsynced_writes: false,
+            last_checkpoint: started,
+            started: started,
+            minor_compactions: 0,
+            major_compactions: 0,
+            permission_denied: 0,
+            free_space_checked: Cell::new(None),
+            merge_max_fan_in: 0,
+            merge_batched_rows: 0,
+            write_stalls: 0,
+            write_stall_ns: 0,
+            last_flush_ns: 0,
+            queries: 0,
+            query_costs: query::QueryCost::default(),
+            compaction_history: VecDeque::new(),
+            snapshots: BTreeMap::new(),
+            next_snapshot_id: 0,
+            trace_id: String::new(),
+            span_id: String::new(),
+            idempotency_key: String::new(),
+            idempotency: idempotency::IdempotencyCache::new(idempotency::DEFAULT_CAPACITY),
+            row_cache: RefCell::new(rowcache::RowCache::new(0)),
+            result_cache: resultcache::ResultCache::new(resultcache::DEFAULT_CAPACITY),
+            prepared: prepared::PreparedQueries::new(prepared::DEFAULT_CAPACITY),
+            hot_rows: hotrows::HotRows::new(hotrows::DEFAULT_CAPACITY, started),
+            fencing_token: 0,
+            replica: false,
+            caught_up_to: 0,
+            lease_expires: None,
+            memtable_size_limit: memtable_size_limit,
+            disktable_limit: disktable_limit,
+            data_directories: vec![directory.to_owned()],
+            dtable_placement: DTablePlacement::RoundRobin,
+            min_free_bytes: 0,
+            compaction_bytes_per_second: 0,
+            compaction_drop_cache: false,
+            max_merge_fan_in: 32,
+            compaction_min_overlap: 0.0,
+            compaction_frozen: false,
+            soft_delete_retention_ms: 7 * 24 * 3600 * 1000,
+            readahead_bytes: 64 * 1024,
+            lazy_headers: false,
+            header_cache_blocks: 256,
+            max_dtable_bytes: 0,
+            snapshot_ttl_ms: 60000,
+            fsync_policy: FsyncPolicy::Always,
+            fsync_interval_ms: 1000,
+            checkpoint_interval_ms: 0,
+            archive_after_days: 0,
+            archive_directory: format!("{}/archive", directory),
+            gc_garbage_ratio: 0.0,
+            key_rules: keys::KeyRules::new(),
+            access_control: acl::AccessControl::new(),
+            schemas: schema::Schemas::new(),
+            salting: salting::Salting::new(),
+            families: families::Families::new(),
+            transforms: transform::Transforms::new(),
+            audit_log: None,
+            commit_log_archive: None,
+            hint_directory: String::new(),
+            hint_max_bytes: 0,
+            hint_max_age_ms: 0,
+            peers: BTreeMap::new(),
+            replica_positions: BTreeMap::new(),
+            shard_map: shards::ShardMap::default(),
+            shard_address: String::new(),
+            slow_query_ms: 0,
+            span_sink: None,
+            temp_directory: None
+        }
+    }
+
+    // new_stub creates a database in a new, empty directory under
+    // /tmp/largetable, which is removed when the Base is dropped.
+    pub fn new_stub() -> Base {
+        let directory = tempdir::TempDir::new("largetable").unwrap();
+        let mut b = Base::new_stub_in(directory.path());
+        b.temp_directory = Some(directory);
+        b
+    }
+
+    // new_stub_in creates a database with the same settings as new_stub,
+    // but in the provided directory, which is left alone when the Base
+    // is dropped.
+    pub fn new_stub_in(directory: &str) -> Base {
+        std::fs::create_dir_all(directory).unwrap_or(());
+        Base::new(directory, 10485760, 10)
+    }
+
+    // Try to load the complete state of the database from the filesystem.
+    pub fn load(&mut self) -> Result<(), BaseError> {
+        self.load_mtable(false)?;
+        self.load_dtables()?;
+        Ok(())
+    }
+
+    // Check that every file the database would load can be read: the
+    // manifest, the header and every row of each dtable, and the commit
+    // log, which is replayed into the memtable. Nothing is written, so
+    // the database shouldn't be used afterwards.
+    pub fn check(&mut self) -> CheckReport {
+        let mut report = CheckReport::default();
+        let manifest = match self.read_manifest() {
+            Ok(m)   => m,
+            Err(e)  => {
+                report.problems.push(format!("{}", e));
+                Manifest::new()
+            }
+        };
+
+        for filename in manifest.get_dtables() {
+            match self.check_dtable(filename) {
+                Ok(d)   => {
+                    report.dtables += 1;
+                    report.rows += d.lookup.get_row_count();
+                    report.bytes += d.total_bytes();
+                },
+                Err(e)  => report.problems.push(format!("{}", e))
+            }
+        }
+
+        // Archived dtables are compressed, so only their headers are read.
+        for filename in manifest.get_archived() {
+            match self.load_dtable(filename) {
+                Ok(_)   => report.archived += 1,
+                Err(e)  => report.problems.push(format!("{}", e))
+            }
+        }
+
+        match self.load_mtable(true) {
+            Ok(n)   => {
+                report.commit_log_entries = n;
+                report.memtable_rows = self.memtable.len();
+            },
+            Err(e)  => report.problems.push(format!("{}", e))
+        }
+
+        report
+    }
+
+    fn check_dtable(&mut self, filename: &str) -> Result<dtable::DTable, BaseError> {
+        let d = self.load_dtable(filename)?;
+        check_dtable_size(&d, filename)?;
+
+        {
+            let mut reader = d.read_ahead(self.readahead_bytes);
+            for index in 0..d.len() {
+                reader.get_row(index).map_err(|e| BaseError::from_dtable(filename, e))?;
+            }
+        }
+        Ok(d)
+    }
+
+    // Read from the commit log, and write all entries to the memtable.
+    // Returns the number of entries which were read. The log is read and
+    // parsed in another thread, which sends batches of entries back to be
+    // applied, so that parsing the next entries overlaps with writing the
+    // last ones to the memtable. With dry_run set, nothing on disk is
+    // changed, not even a checkpoint which can't be used.
+    fn load_mtable(&mut self, dry_run: bool) -> Result<u64, BaseError> {
+        let path = self.commit_log_path();
+        let commit_log = self.storage.open(&path)
+            .map_err(|e| BaseError::io(&path, e))?;
+        let length = commit_log.len()
+            .map_err(|e| BaseError::io(&path, e))?;
+        let started = time::precise_time_ns();
+        let start = self.load_checkpoint(length, dry_run)?;
+
+        let (sender, receiver) = mpsc::sync_channel(REPLAY_QUEUE_BATCHES);
+        let parser_path = path.clone();
+        let parser = std::thread::spawn(move || {
+            Base::parse_commit_log(commit_log, start, length, &parser_path, sender)
+        });
+
+        let mut entries = 0;
+        let mut failed = None;
+        for batch in receiver.iter() {
+            for (offset, clu) in batch {
+                if let Err(e) = self.replay_entry(&clu) {
+                    failed = Some(BaseError::corrupted(&path, offset, &e));
+                    break;
+                }
+                entries += 1;
+            }
+            if failed.is_some() {
+                break;
+            }
+        }
+
+        // Once the receiver is gone, the parser stops at its next batch.
+        drop(receiver);
+        let parsed = parser.join()
+            .unwrap_or_else(|_| Err(BaseError::Problem{reason: format!("{}: commit log parser panicked", path)}));
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        parsed?;
+
+        if entries > 0 {
+            let elapsed_ns = std::cmp::max(time::precise_time_ns() - started, 1);
+            info!(
+                "Replayed {} commit log entries ({} bytes) in {}ms ({:.1} MB/s).",
+                entries,
+                length - start,
+                elapsed_ns / 1_000_000,
+                (length - start) as f64 / (1 << 20) as f64 / (elapsed_ns as f64 / 1e9)
+            );
+        }
+        Ok(entries)
+    }
+
+    // Parse the entries in the commit log from the start offset, and send
+    // them in batches along with the offset of each one. Stops early if
+    // the receiver goes away.
+    fn parse_commit_log(
+        mut commit_log: Box<storage::StorageFile>,
+        start: u64,
+        length: u64,
+        path: &str,
+        sender: mpsc::SyncSender<Vec<(u64, CommitLogEntry)>>
+    ) -> Result<(), BaseError> {
+        commit_log.seek(io::SeekFrom::Start(start))
+            .map_err(|e| BaseError::io(path, e))?;
+        let mut remaining = length - start;
+        let mut batch = Vec::with_capacity(REPLAY_BATCH_ENTRIES);
+
+        loop {
+            // Try to read an entry from the commit log. First, get the size
+            // which is encoded as 4 bytes.
+            let offset = length - remaining;
+            let size = match commit_log.read_u32::<LittleEndian>() {
+                Ok(n)   => n,
+                // If we reach end of file, we'll quit.
+                Err(_) => {
+                    sender.send(batch).unwrap_or(());
+                    return Ok(())
+                }
+            };
+
+            // A corrupted size could be huge, so make sure that the entry
+            // actually fits in the file before allocating space for it.
+            remaining = remaining.saturating_sub(4);
+            if size as u64 > remaining {
+                sender.send(batch).unwrap_or(());
+                return Err(BaseError::corrupted(path, offset, &format!("entry of {} bytes runs past the end of the file", size)));
+            }
+            remaining -= size as u64;
+
+            // Next, load the next few bytes into a CommitLogUpdate.
+            let mut buf = vec![0; size as usize];
+            let parsed = commit_log.read_exact(&mut buf)
+                .map_err(|e| BaseError::io(path, e))
+                .and_then(|_| protobuf::parse_from_bytes::<CommitLogEntry>(&buf)
+                    .map_err(|e| BaseError::corrupted(path, offset, &format!("unable to parse entry: {}", e))));
+            let clu = match parsed {
+                Ok(c)   => c,
+                Err(e)  => {
+                    // The entries before this one are still applied, so
+                    // that the error is the same as if they'd been read
+                    // one at a time.
+                    sender.send(batch).unwrap_or(());
+                    return Err(e);
+                }
+            };
+
+            batch.push((offset, clu));
+            if batch.len() >= REPLAY_BATCH_ENTRIES {
+                let full = mem::replace(&mut batch, Vec::with_capacity(REPLAY_BATCH_ENTRIES));
+                if sender.send(full).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn checkpoint_path(&self) -> String {
+        format!("{}/memtable.checkpoint", self.directory)
+    }
+
+    // Load the memtable from its checkpoint, if there is one. Returns the
+    // offset in the commit log which the checkpoint covers up to, where
+    // replaying should start.
+    fn load_checkpoint(&mut self, log_length: u64, dry_run: bool) -> Result<u64, BaseError> {
+        let path = self.checkpoint_path();
+        let meta_path = format!("{}.meta", path);
+        let checkpoint = match self.storage.open(&meta_path) {
+            Ok(mut f)   => protobuf::parse_from_reader::<Checkpoint>(&mut f)
+                .map_err(|e| BaseError::corrupted(&meta_path, 0, &format!("unable to parse checkpoint: {}", e)))?,
+            Err(_)      => return Ok(0)
+        };
+
+        // The commit log may have lost writes which weren't synced, in
+        // which case the checkpoint has data that the log doesn't. It's
+        // removed, so that it can't be mistaken for covering the log once
+        // it has grown again.
+        if checkpoint.get_log_offset() > log_length {
+            warn!("Ignoring the memtable checkpoint, which covers {} bytes of a {} byte commit log.",
+                checkpoint.get_log_offset(), log_length);
+            if !dry_run {
+                self.remove_checkpoint()?;
+            }
+            return Ok(0);
+        }
+
+        let d = dtable::DTable::new(self.storage.clone(), path.clone())
+            .map_err(|e| BaseError::io(&path, e))?;
+        for t in d.lookup.get_tombstones() {
+            self.memtable.delete_range(t.get_start(), t.get_end(), t.get_timestamp(), false);
+        }
+        {
+            let mut reader = d.read_ahead(self.readahead_bytes);
+            for (index, entry) in d.lookup.get_entries().iter().enumerate() {
+                let row = reader.get_row(index).map_err(|e| BaseError::from_dtable(&path, e))?;
+                self.memtable.insert_row(entry.get_key(), row)
+                    .map_err(|_| BaseError::corrupted(&path, entry.get_offset(), &format!("row {} appears twice", entry.get_key())))?;
+            }
+        }
+
+        self.fencing_token = checkpoint.get_fencing_token();
+        for key in checkpoint.get_idempotency_keys() {
+            self.idempotency.insert(key);
+        }
+        info!("Loaded {} rows from the memtable checkpoint.", d.len());
+        Ok(checkpoint.get_log_offset())
+    }
+
+    // Write the memtable to a checkpoint, along with how much of the commit
+    // log it covers. The old checkpoint is removed before the new one is
+    // moved into place, and the file saying what it covers goes last, so
+    // a crash part way through at worst leaves no checkpoint at all.
+    pub fn checkpoint(&mut self) -> Result<(), BaseError> {
+        let span_start = self.span_start();
+        let log_path = self.commit_log_path();
+        self.commit_log.sync().map_err(|e| BaseError::io(&log_path, e))?;
+        let offset = self.commit_log.len().map_err(|e| BaseError::io(&log_path, e))?;
+
+        let path = self.checkpoint_path();
+        let temp_path = format!("{}.tmp", path);
+        {
+            let mut f = self.storage.create(&temp_path)
+                .map_err(|e| BaseError::io(&temp_path, e))?;
+            let mut h = self.storage.create(&format!("{}.header", temp_path))
+                .map_err(|e| BaseError::io(&temp_path, e))?;
+            let created = self.clock.now();
+            self.memtable.write_to_writer(&mut f, &mut h, created, self.generation)
+                .map_err(|e| BaseError::io(&temp_path, e))?;
+            self.sync_file(&*f, &temp_path)?;
+            self.sync_file(&*h, &format!("{}.header", temp_path))?;
+
+            let mut checkpoint = Checkpoint::new();
+            checkpoint.set_log_offset(offset);
+            checkpoint.set_fencing_token(self.fencing_token);
+            checkpoint.set_idempotency_keys(::protobuf::RepeatedField::from_vec(self.idempotency.keys()));
+            let mut m = self.storage.create(&format!("{}.meta", temp_path))
+                .map_err(|e| BaseError::io(&temp_path, e))?;
+            checkpoint.write_to_writer(&mut m)
+                .map_err(|e| BaseError::io(&temp_path, io::Error::from(e)))?;
+            self.sync_file(&*m, &format!("{}.meta", temp_path))?;
+        }
+
+        self.remove_checkpoint()?;
+        for suffix in &["", ".header", ".meta"] {
+            self.storage.rename(&format!("{}{}", temp_path, suffix), &format!("{}{}", path, suffix))
+                .map_err(|e| BaseError::io(&path, e))?;
+        }
+
+        self.last_checkpoint = self.clock.now();
+        self.record_span("memtable.checkpoint", span_start, vec![
+            (String::from("rows"), format!("{}", self.memtable.len())),
+            (String::from("log_offset"), format!("{}", offset))
+        ]);
+        Ok(())
+    }
+
+    // Remove the checkpoint, starting with the file saying what it covers,
+    // so that it's never used once it's partly gone.
+    fn remove_checkpoint(&mut self) -> Result<(), BaseError> {
+        let path = self.checkpoint_path();
+        for suffix in &[".meta", "", ".header"] {
+            let filename = format!("{}{}", path, suffix);
+            match self.storage.remove(&filename) {
+                Ok(())  => (),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e)  => return Err(BaseError::io(&filename, e))
+            }
+        }
+        Ok(())
+    }
+
+    // Write a commit log entry to the memtable. Entries from a deposed
+    // leader are skipped.
+    fn replay_entry(&mut self, clu: &CommitLogEntry) -> Result<(), String> {
+        if clu.get_fencing_token() < self.fencing_token {
+            warn!("Skipping commit log entry from a deposed leader (fencing token {} < {})",
+                clu.get_fencing_token(), self.fencing_token);
+            return Ok(());
+        }
+        self.fencing_token = clu.get_fencing_token();
+
+        for key in clu.get_idempotency_keys() {
+            self.idempotency.insert(key);
+        }
+
+        // A transaction holds an update for each of its rows.
+        match clu.get_field_type() {
+            CommitLogEntryType::ROW_UPDATE => match self.replay_update(clu) {
+                query::QueryResult::Done => (),
+                x => return Err(format!("unable to apply update to {}: {}", clu.get_key(), x))
+            },
+            CommitLogEntryType::TRANSACTION => for w in clu.get_writes() {
+                match self.replay_update(w) {
+                    query::QueryResult::Done => (),
+                    x => return Err(format!("unable to apply update to {}: {}", w.get_key(), x))
+                }
+            },
+            // Snapshots don't survive a restart, so the deleted data
+            // can be purged right away.
+            CommitLogEntryType::RANGE_DELETION => self.memtable.delete_range(
+                clu.get_key(),
+                clu.get_end_key(),
+                clu.get_timestamp(),
+                true
+            ),
+            CommitLogEntryType::IDEMPOTENCY_KEYS => ()
+        };
+        Ok(())
+    }
+
+    // Apply an update from the commit log to the memtable.
+    fn replay_update(&mut self, c: &CommitLogEntry) -> query::QueryResult {
+        let updates = c.get_updates()
+            .iter()
+            .map(|u| query::MUpdate::new(
+                u.get_column(),
+                u.get_value().to_owned()
+            )).collect::<Vec<_>>();
+        self.direct_update(c.get_key(), &updates, c.get_timestamp())
+    }
+
+    // Load up all of the DTables listed in the manifest. If there is no
+    // manifest yet, every data directory is scanned for DTables instead,
+    // and a manifest is written out listing the ones that were found.
+    fn load_dtables(&mut self) -> Result<(), BaseError> {
+        let manifest = self.read_manifest()?;
+        self.compaction_frozen = manifest.get_compaction_frozen();
+        if self.compaction_frozen {
+            warn!("Compaction is frozen, and won't run until it's unfrozen.");
+        }
+        for filename in manifest.get_dtables() {
+            let d = self.load_dtable(filename)?;
+            check_dtable_size(&d, filename)?;
+            self.disktables.push(d);
+        }
+
+        for filename in manifest.get_archived() {
+            let d = self.load_dtable(filename)?;
+            self.archived.push(d);
+        }
+
+        self.write_manifest()
+    }
+
+    fn read_manifest(&self) -> Result<Manifest, BaseError> {
+        let path = format!("{}/MANIFEST", self.directory);
+        match self.storage.open(&path) {
+            Ok(mut f) => protobuf::parse_from_reader::<Manifest>(&mut f)
+                .map_err(|e| BaseError::corrupted(&path, 0, &format!("unable to parse manifest: {}", e))),
+            Err(_) => {
+                let mut filenames = vec![];
+                for directory in &self.data_directories {
+                    let entries = self.storage.list(directory, ".dtable")
+                        .map_err(|e| BaseError::io(directory, e))?;
+                    filenames.extend(entries);
+                }
+                let mut manifest = Manifest::new();
+                manifest.set_dtables(protobuf::RepeatedField::from_vec(filenames));
+                Ok(manifest)
+            }
+        }
+    }
+
+    fn load_dtable(&mut self, data: &str) -> Result<dtable::DTable, BaseError> {
+        // First, let's check for a number in the filename. That'll let us know
+        // what index future dtables should be at. Archived dtables keep
+        // their index, with a .gz extension.
+        let file_scanner = regex::Regex::new(r"/([0-9]+)\.dtable(\.gz)?$").unwrap();
+        let invalid = || BaseError::Problem{
+            reason: format!("{} in the manifest isn't a dtable filename", data)
+        };
+        let mat = file_scanner.captures(data).ok_or_else(&invalid)?;
+        let index = mat.get(1).unwrap().as_str().parse::<u32>().map_err(|_| invalid())?;
+        if index > self.disktable_index {
+            self.disktable_index = index;
+        }
+
+        // We need two files to read a dtable. One is the dtable filename, and
+        // the second is the header, which must be read into memory, unless
+        // only its fences are kept.
+        let d = match self.lazy_headers {
+            true    => dtable::DTable::open_paged(self.storage.clone(), data.to_owned(), self.header_cache_blocks),
+            false   => dtable::DTable::new(self.storage.clone(), data.to_owned())
+        }.map_err(|e| BaseError::io(data, e))?;
+        if d.lookup.get_generation() > self.generation {
+            self.generation = d.lookup.get_generation();
+        }
+        info!("Loaded dtable: {}", data);
+
+        Ok(d)
+    }
+
+    fn commit_log_path(&self) -> String {
+        format!("{}/commit.log", self.directory)
+    }
+
+    // Picks the filename for the next dtable. DTables are spread across the
+    // data directories according to the placement policy, starting from
+    // the directory whose turn it is based on the dtable's index.
+    fn next_dtable_path(&mut self) -> String {
+        self.disktable_index += 1;
+        let count = self.data_directories.len();
+        let turn = self.disktable_index as usize % count;
+        let candidates = (0..count).map(|i| (turn + i) % count);
+        let chosen = match self.dtable_placement {
+            DTablePlacement::RoundRobin     => turn,
+            DTablePlacement::LeastUsed      => candidates
+                .min_by_key(|&i| self.bytes_in_directory(&self.data_directories[i]))
+                .unwrap_or(turn),
+            DTablePlacement::MostFreeSpace  => candidates
+                .min_by_key(|&i| u64::MAX - free_space(&self.data_directories[i]).unwrap_or(0))
+                .unwrap_or(turn)
+        };
+        format!("{}/{}.dtable", self.data_directories[chosen], self.disktable_index)
+    }
+
+    // Returns the size of the live dtables stored in the directory.
+    fn bytes_in_directory(&self, directory: &str) -> u64 {
+        self.disktables.iter()
+            .filter(|d| std::path::Path::new(d.filename()).parent() == Some(std::path::Path::new(directory)))
+            .map(|d| d.total_bytes())
+            .sum()
+    }
+
+    // With lazy_headers set, only the fences of each dtable's header are
+    // kept in memory (see keyindex.rs). DTables are loaded that way, and
+    // the ones which have been written since are paged out here.
+    fn page_out_headers(&mut self) {
+        if !self.lazy_headers {
+            return;
+        }
+        let cache_blocks = self.header_cache_blocks;
+        for d in self.disktables.iter_mut().chain(self.archived.iter_mut()).filter(|d| !d.is_paged()) {
+            if let Err(e) = d.page_out(cache_blocks) {
+                warn!("Unable to page out the header of {}: {}", d.filename(), e);
+            }
+        }
+    }
+
+    // Writes out the list of live dtables. The manifest is written to a
+    // temporary file first and then renamed into place, so a crash never
+    // leaves a partially written manifest behind.
+    fn write_manifest(&mut self) -> Result<(), BaseError> {
+        // Every change to the set of dtables ends up here, so it's where
+        // new dtables have their headers paged out.
+        self.page_out_headers();
+
+        let mut manifest = Manifest::new();
+        manifest.set_dtables(protobuf::RepeatedField::from_iter(
+            self.disktables.iter().map(|d| d.filename().to_owned())
+        ));
+        manifest.set_archived(protobuf::RepeatedField::from_iter(
+            self.archived.iter().map(|d| d.filename().to_owned())
+        ));
+        manifest.set_compaction_frozen(self.compaction_frozen);
+
+        let tmp = format!("{}/MANIFEST.tmp", self.directory);
+        let mut f = self.storage.create(&tmp)
+            .map_err(|e| BaseError::io(&tmp, e))?;
+        manifest.write_to_writer(&mut f)
+            .map_err(|e| BaseError::Problem{reason: format!("unable to write {}: {}", tmp, e)})?;
+        self.sync_file(&*f, &tmp)?;
+
+        self.storage.rename(&tmp, &format!("{}/MANIFEST", self.directory))
+            .map_err(|e| BaseError::io(&tmp, e))
+    }
+
+    // This function takes the current state of the memtable and empties it
+    // into a DTable, finally replacing the memtable with a new, blank one.
+    pub fn empty_memtable(&mut self) -> Result<(), BaseError> {
+        let span_start = self.span_start();
+
+        // First, need to check if creating this dtable will exceed
+        // the maximum number of dtables. If so, we'll first compactify
+        // the dtables together, then dump the memtable.
+        if self.disktables.len() + 1 > self.disktable_limit && self.compaction_frozen {
+            info!("Compaction is frozen, so the memtable is written to disk without merging disktables.{}", self.trace());
+        } else if self.disktables.len() + 1 > self.disktable_limit {
+            info!("Merging disktables before writing memtable to disk.{}", self.trace());
+            let candidates = self.compaction_candidates();
+            match candidates.is_empty() {
+                true  => info!("None of the dtables overlap enough to be worth merging.{}", self.trace()),
+                false => self.merge(&candidates)?
+            }
+        }
+
+        // The commit log is sealed into the archive before anything is
+        // flushed, so that if archiving fails, so does the flush, and the
+        // writes stay in the commit log until it's retried.
+        if self.commit_log_archive.is_some() {
+            let generation = self.generation + 1;
+            self.archive_commit_log(generation)?;
+        }
+
+        info!("Writing memtable to disk.{}", self.trace());
+        let started = time::precise_time_ns();
+        let created = self.clock.now();
+        self.generation += 1;
+
+        // A large memtable is split by key range into several dtables of
+        // about max_dtable_bytes each, so that no single dtable makes
+        // later compactions lopsided.
+        let mut written = vec![];
+        let mut start = String::new();
+        loop {
+            let path = self.next_dtable_path();
+
+            info!("Creating dtable header.");
+            let header_path = format!("{}.header", path);
+            let mut h = self.storage.create(&header_path)
+                .map_err(|e| BaseError::io(&header_path, e))?;
+
+            info!("Creating dtable file.");
+            let mut f = self.storage.create(&path).map_err(|e| BaseError::io(&path, e))?;
+
+            let (dheader, next) = self.memtable.write_part_to_writer(
+                &start, self.max_dtable_bytes, &self.families, &mut f, &mut h, created, self.generation
+            ).map_err(|e| BaseError::io(&path, e))?;
+
+            // Flush all buffers to disk. Every fsync policy syncs here, since
+            // this is the point where the commit log is about to be truncated.
+            self.sync_file(&*f, &path)?;
+            self.sync_file(&*h, &header_path)?;
+
+            written.push((path, dheader));
+            match next {
+                Some(k) => start = k,
+                None    => break
+            }
+        }
+
+        info!("Emptying memtable.");
+        mem::replace(&mut self.memtable, mtable::MTable::new());
+        self.minor_compactions += 1;
+        self.last_flush_ns = time::precise_time_ns() - started;
+        let rows = written.iter().map(|&(_, ref d)| d.get_row_count()).sum::<u64>();
+        let bytes = written.iter().map(|&(_, ref d)| d.get_total_bytes()).sum::<u64>();
+        self.record_compaction(Compaction{
+            major: false,
+            garbage_collection: false,
+            timestamp: created,
+            input_dtables: 0,
+            rows: rows,
+            bytes: bytes,
+            max_fan_in: 0
+        });
+        self.record_span("compaction.minor", span_start, vec![
+            (String::from("rows"), format!("{}", rows)),
+            (String::from("bytes"), format!("{}", bytes)),
+            (String::from("dtables"), format!("{}", written.len()))
+        ]);
+
+        for (path, dheader) in written {
+            self.disktables.push(dtable::DTable::from_dtableheader(self.storage.clone(), path, dheader));
+        }
+        self.write_manifest()?;
+
+        // The checkpoint covers a commit log which is about to be replaced.
+        self.remove_checkpoint()?;
+        self.last_checkpoint = self.clock.now();
+
+        // Delete the commit log, since we are writing it to disk.
+        info!("Truncating commit log.");
+        let commit_log_path = self.commit_log_path();
+        let generation = self.generation - 1;
+        let unreplicated = match self.unreplicated_offset(generation) {
+            Some(offset) => {
+                let mut data = vec![];
+                self.storage.open(&commit_log_path)
+                    .and_then(|mut f| {
+                        f.seek(io::SeekFrom::Start(offset))?;
+                        f.read_to_end(&mut data)
+                    })
+                    .map_err(|e| BaseError::io(&commit_log_path, e))?;
+                Some((offset, data))
+            },
+            None => None
+        };
+        mem::replace(
+            &mut self.commit_log,
+            self.storage.create(&commit_log_path)
+                .map_err(|e| BaseError::io(&commit_log_path, e))?
+        );
+        if let Some((offset, data)) = unreplicated {
+            self.queue_hints(&data, offset, generation);
+        }
+
+        // The idempotency keys of the flushed writes still need to be
+        // remembered after a restart, so they're carried over.
+        let keys = self.idempotency.keys();
+        if !keys.is_empty() {
+            let mut c = CommitLogEntry::new();
+            c.set_field_type(CommitLogEntryType::IDEMPOTENCY_KEYS);
+            c.set_idempotency_keys(::protobuf::RepeatedField::from_vec(keys));
+            self.append_to_commit_log(&mut c)?;
+        }
+
+        Ok(())
+    }
+
+    // Copy the commit log into the archive, as the segment whose writes
+    // are about to be flushed into dtables of the generation.
+    fn archive_commit_log(&mut self, generation: u64) -> Result<(), BaseError> {
+        let path = self.commit_log_path();
+        let mut data = vec![];
+        self.storage.open(&path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| BaseError::io(&path, e))?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let span_start = self.span_start();
+        let segment = match self.commit_log_archive {
+            Some(ref mut a) => a.archive(&data, generation).map_err(|e| BaseError::io(&path, e))?,
+            None            => return Ok(())
+        };
+        info!("Archived {} commit log entries as segment {}.{}", segment.entries, segment.sequence, self.trace());
+        self.record_span("commit_log.archive", span_start, vec![
+            (String::from("bytes"), format!("{}", segment.bytes))
+        ]);
+        Ok(())
+    }
+
+    // Start archiving the commit log into the directory each time the
+    // memtable is flushed. Generations carry on from the newest archived
+    // segment, in case the dtables which had them have since been deleted.
+    pub fn enable_commit_log_archive(&mut self, directory: &str) -> Result<(), io::Error> {
+        let archive = logarchive::LogArchive::open(self.storage.clone(), directory)?;
+        self.generation = std::cmp::max(self.generation, archive.last_generation());
+        self.commit_log_archive = Some(archive);
+        Ok(())
+    }
+
+    // Bring a data directory which was restored from a backup up to date,
+    // by replaying the archived segments which were flushed after its
+    // newest dtable. Only writes at or before the timestamp are replayed,
+    // or every write if it's 0. Each segment is flushed once it's been
+    // replayed, into dtables of the segment's generation, so restoring
+    // again carries on where it left off. The backup's own commit log is
+    // discarded, since the segments include everything that was in it.
+    // Returns the number of segments which were replayed.
+    pub fn restore_from_archive(&mut self, directory: &str, until: u64) -> Result<u64, BaseError> {
+        let archive = logarchive::LogArchive::open(self.storage.clone(), directory)
+            .map_err(|e| BaseError::io(directory, e))?;
+        let segments = archive.segments().iter()
+            .filter(|s| s.generation > self.generation)
+            .filter(|s| until == 0 || s.first_timestamp <= until)
+            .cloned()
+            .collect::<Vec<_>>();
+        if segments.is_empty() {
+            return Ok(0);
+        }
+
+        self.memtable = mtable::MTable::new();
+        let commit_log_path = self.commit_log_path();
+        self.commit_log = self.storage.create(&commit_log_path)
+            .map_err(|e| BaseError::io(&commit_log_path, e))?;
+
+        for segment in segments.iter() {
+            let path = format!("{}/{:020}.log", directory, segment.sequence);
+            let entries = archive.read_segment(segment)
+                .map_err(|e| BaseError::io(&path, e))?;
+            for entry in entries.iter().filter(|e| until == 0 || e.get_timestamp() <= until) {
+                self.replay_entry(entry)
+                    .map_err(|e| BaseError::corrupted(&path, 0, &e))?;
+            }
+
+            info!("Restored segment {} ({} entries).", segment.sequence, segment.entries);
+            self.generation = segment.generation - 1;
+            self.empty_memtable()?;
+        }
+        Ok(segments.len() as u64)
+    }
+
+    // The position that the commit log has reached, for replicas to tail
+    // it from (see replication.rs).
+    pub fn replication_position(&self) -> Result<replication::Position, BaseError> {
+        let offset = self.commit_log.len()
+            .map_err(|e| BaseError::io(&self.commit_log_path(), e))?;
+        Ok(replication::Position{generation: self.generation, offset: offset})
+    }
+
+    // Take a snapshot for a new replica to start from: the live dtables
+    // and the commit log. Only opening the files needs the database to be
+    // locked, and the snapshot is sent after that (see replication.rs).
+    // Archived dtables aren't included.
+    pub fn open_snapshot(&self) -> Result<replication::Snapshot, BaseError> {
+        let position = self.replication_position()?;
+        let name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_owned();
+        let mut files = vec![];
+        for d in self.disktables.iter() {
+            for path in &[format!("{}.header", d.filename()), d.filename().to_owned()] {
+                let f = self.storage.open(path).map_err(|e| BaseError::io(path, e))?;
+                let size = f.len().map_err(|e| BaseError::io(path, e))?;
+                files.push((name(path), size, f));
+            }
+        }
+
+        let commit_log_path = self.commit_log_path();
+        let mut commit_log = vec![];
+        self.storage.open(&commit_log_path)
+            .and_then(|f| f.take(position.offset).read_to_end(&mut commit_log))
+            .map_err(|e| BaseError::io(&commit_log_path, e))?;
+
+        info!("Taking a snapshot of {} dtables at {}.{}", self.disktables.len(), position, self.trace());
+        Ok(replication::Snapshot{
+            position: position,
+            files: files,
+            commit_log: (name(&commit_log_path), commit_log)
+        })
+    }
+
+    // Set up a new replica from a snapshot of its primary, before it's
+    // loaded. Returns the position in the primary's commit log to tail it
+    // from. The data directory has to be empty.
+    pub fn install_snapshot<R: io::Read>(&mut self, input: &mut R) -> Result<replication::Position, BaseError> {
+        let commit_log_path = self.commit_log_path();
+        let manifest_path = format!("{}/MANIFEST", self.directory);
+        let empty = self.commit_log.len().map(|l| l == 0).unwrap_or(false);
+        if self.storage.open(&manifest_path).is_ok() || !empty {
+            return Err(BaseError::Problem{reason: format!("{} already holds a database", self.directory)});
+        }
+
+        let (position, files) = replication::install_snapshot(input, &self.storage, &self.directory)
+            .map_err(|e| BaseError::io(&self.directory, e))?;
+        info!("Installed a snapshot of {} files at {}.", files, position);
+
+        // The commit log was replaced by the snapshot's.
+        self.commit_log = self.storage.append(&commit_log_path)
+            .map_err(|e| BaseError::io(&commit_log_path, e))?;
+        Ok(position)
+    }
+
+    // Read the commit log entries written since the position, for a
+    // replica which is tailing it, along with the position to carry on
+    // from. The rest of a commit log which has since been truncated is
+    // read from the archive. Returns None if it wasn't archived, in which
+    // case the replica needs a new snapshot.
+    pub fn read_commit_log(&self, from: &replication::Position, max_bytes: usize) -> Result<Option<(Vec<u8>, replication::Position)>, BaseError> {
+        let (path, mut f, length) = if from.generation == self.generation {
+            let path = self.commit_log_path();
+            let f = self.storage.open(&path).map_err(|e| BaseError::io(&path, e))?;
+            (path, f, self.replication_position()?.offset)
+        } else {
+            // The segment holding the commit log of a generation is the
+            // one that was sealed when the next generation was flushed.
+            let archive = match self.commit_log_archive {
+                Some(ref a) => a,
+                None        => return Ok(None)
+            };
+            let segment = match archive.segments().iter().find(|s| s.generation == from.generation + 1) {
+                Some(s) => s,
+                None    => return Ok(None)
+            };
+            let path = format!("segment {}", segment.sequence);
+            let f = archive.open_segment(segment).map_err(|e| BaseError::io(&path, e))?;
+            let length = f.len().map_err(|e| BaseError::io(&path, e))?;
+            (path, f, length)
+        };
+
+        // Only the entries being sent are read.
+        if from.offset > length {
+            return Ok(None);
+        }
+        let data = replication::read_entries(&mut f, from.offset, length, max_bytes)
+            .map_err(|e| BaseError::io(&path, e))?;
+        let offset = from.offset + data.len() as u64;
+        let next = match offset == length && from.generation != self.generation {
+            true    => replication::Position{generation: from.generation + 1, offset: 0},
+            false   => replication::Position{generation: from.generation, offset: offset}
+        };
+        Ok(Some((data, next)))
+    }
+
+    // Read the commit log for the named replica, like read_commit_log, but
+    // with hinted handoff. The position that each replica asks for is
+    // remembered, so that the entries it hasn't read yet can be queued up
+    // as hints when the commit log is truncated, and sent to it when it
+    // asks for that position again.
+    pub fn replicate_to(&mut self, peer: &str, from: &replication::Position, max_bytes: usize) -> Result<Option<(Vec<u8>, replication::Position)>, BaseError> {
+        if !peer.is_empty() {
+            self.replica_positions.insert(peer.to_owned(), *from);
+        }
+        if self.hint_directory.is_empty() || peer.is_empty() {
+            return self.read_commit_log(from, max_bytes);
+        }
+
+        if !self.peers.contains_key(peer) {
+            let mut hints = hints::HintLog::open(self.storage.clone(), &self.hint_directory, peer)
+                .map_err(|e| BaseError::io(&self.hint_directory, e))?;
+            hints.max_bytes = self.hint_max_bytes;
+            hints.max_age_ms = self.hint_max_age_ms;
+
+            // It's not known which positions hints left over from before
+            // a restart started from, so they can't be used.
+            if !hints.is_empty() {
+                warn!("Dropping hints for {} from before the restart.", peer);
+                hints.replay(0, |_| true).map_err(|e| BaseError::io(&self.hint_directory, e))?;
+            }
+            self.peers.insert(peer.to_owned(), Peer{position: *from, hints: hints, hinted: None});
+        }
+
+        let starts_hints = {
+            let p = self.peers.get_mut(peer).unwrap();
+            p.position = *from;
+            if !p.hinted.as_ref().map(|h| h.start == *from).unwrap_or(false) {
+                // The replica has moved on from its hints.
+                p.hinted = None;
+                false
+            } else {
+                true
+            }
+        };
+        if !starts_hints {
+            return self.read_commit_log(from, max_bytes);
+        }
+
+        let now = self.clock.now();
+        let directory = self.hint_directory.clone();
+        let p = self.peers.get_mut(peer).unwrap();
+        let mut hinted = p.hinted.take().unwrap();
+        let dropped = p.hints.dropped;
+        let mut data = mem::replace(&mut hinted.sent, vec![]);
+        p.hints.replay(now, |entry| {
+            data.write_u32::<LittleEndian>(entry.compute_size()).is_ok() &&
+                entry.write_to_writer(&mut data).is_ok()
+        }).map_err(|e| BaseError::io(&directory, e))?;
+
+        // If any of the hints expired, the replica is missing entries, and
+        // needs a new snapshot after all.
+        if p.hints.dropped > dropped {
+            warn!("Hints for {} expired, so it needs a new snapshot.", peer);
+            return Ok(None);
+        }
+
+        let resume = hinted.resume;
+        hinted.sent = data.clone();
+        p.hinted = Some(hinted);
+        Ok(Some((data, resume)))
+    }
+
+    // The number of replicas which have applied the commit log up to the
+    // position, and the number which are tailing it, for writes which
+    // wait for an AckLevel.
+    pub fn replicas_at(&self, position: &replication::Position) -> (usize, usize) {
+        let in_sync = self.replica_positions.values().filter(|p| p.reaches(position)).count();
+        (in_sync, self.replica_positions.len())
+    }
+
+    // The earliest offset in the commit log of the generation which a
+    // replica still needs hints for, if any of them do. A replica which
+    // was already behind an earlier truncation needs all of it.
+    fn unreplicated_offset(&self, generation: u64) -> Option<u64> {
+        self.peers.values().filter_map(|p| match p.hinted {
+            Some(ref h) if h.resume.generation == generation => Some(0),
+            _ if p.position.generation == generation => Some(p.position.offset),
+            _ => None
+        }).min()
+    }
+
+    // Queue up the entries of a commit log of the generation which was
+    // just truncated, for each replica which hadn't read all of it. The
+    // data is the commit log from the offset on (see unreplicated_offset).
+    // A replica which was already behind an earlier truncation only
+    // carries on from there if it's been hinted everything since. Hints
+    // are best effort: if they can't be queued, the replica needs a new
+    // snapshot.
+    fn queue_hints(&mut self, data: &[u8], offset: u64, generation: u64) {
+        let entries = match logarchive::parse_entries(data) {
+            Ok(e)   => e,
+            Err(e)  => {
+                error!("Unable to queue hints: {}", e);
+                return;
+            }
+        };
+
+        let now = self.clock.now();
+        let resume = replication::Position{generation: generation + 1, offset: 0};
+        for (name, p) in self.peers.iter_mut() {
+            let (start, from) = match p.hinted {
+                Some(ref h) if h.resume.generation == generation => (h.start, 0),
+                _ if p.position.generation == generation => (p.position, p.position.offset),
+                _ => continue
+            };
+
+            // Entries are added from the first one at or after the offset.
+            let mut position = offset;
+            let mut queued = true;
+            for entry in entries.iter() {
+                let length = 4 + entry.compute_size() as u64;
+                position += length;
+                if position - length < from {
+                    continue;
+                }
+                match p.hints.add(entry, now) {
+                    Ok(true)    => (),
+                    Ok(false)   => { queued = false; break },
+                    Err(e)      => {
+                        error!("Unable to queue hints for {}: {}", name, e);
+                        queued = false;
+                        break;
+                    }
+                };
+            }
+
+            if !queued {
+                warn!("Too many hints for {}, so it needs a new snapshot.", name);
+                p.hinted = None;
+                p.hints.replay(0, |_| true).unwrap_or(0);
+                continue;
+            }
+            let sent = p.hinted.take().map(|h| h.sent).unwrap_or_default();
+            p.hinted = Some(Hinted{start: start, resume: resume, sent: sent});
+        }
+    }
+
+    // Whether writes can be made here: not on a replica, which only
+    // applies its primary's, and with leader elections, only while this
+    // server holds the lease, so that a deposed leader can't write.
+    pub fn accepts_writes(&self) -> bool {
+        match self.lease_expires {
+            _ if self.replica   => false,
+            Some(expires)       => self.clock.now() < expires,
+            None                => true
+        }
+    }
+
+    // The commit timestamp that a replica which has read the commit log up
+    // to the position has caught up to, if that's all of it: every write
+    // so far was committed no later than now.
+    pub fn replicated_through(&self, position: &replication::Position) -> Result<Option<u64>, BaseError> {
+        match *position == self.replication_position()? {
+            true    => Ok(Some(self.clock.now())),
+            false   => Ok(None)
+        }
+    }
+
+    // On a replica, note that the primary said it has every write
+    // committed up to the timestamp (see replicated_through).
+    pub fn caught_up(&mut self, timestamp: u64) {
+        self.caught_up_to = std::cmp::max(self.caught_up_to, timestamp);
+    }
+
+    // Apply entries read from the primary's commit log, on a replica.
+    // They're written to the replica's own commit log as well, so that
+    // they survive a restart. Returns the number of entries.
+    pub fn apply_replicated(&mut self, data: &[u8]) -> Result<usize, BaseError> {
+        let entries = logarchive::parse_entries(data)
+            .map_err(|e| BaseError::Problem{reason: format!("invalid commit log entries from the primary: {}", e)})?;
+        let count = entries.len();
+        for mut entry in entries {
+            // Entries from a deposed leader are skipped, the same as when
+            // the commit log is replayed.
+            if entry.get_fencing_token() < self.fencing_token {
+                continue;
+            }
+            self.replay_entry(&entry).map_err(|e| BaseError::Problem{reason: e})?;
+            self.append_to_commit_log(&mut entry)?;
+            self.caught_up(entry.get_timestamp());
+        }
+        if count > 0 {
+            self.check_size_limits();
+        }
+        Ok(count)
+    }
+
+    // Merge the disktables into a single disktable.
+    pub fn merge_disktables(&mut self) -> Result<(), BaseError> {
+        let all = (0..self.disktables.len()).collect::<Vec<_>>();
+        self.merge(&all)
+    }
+
+    // Pick which dtables to merge so that there's room for one more. The
+    // dtables are ranked by how much merging them should help reads: a
+    // dtable whose key range overlaps many others gets searched by many
+    // reads, and one which is searched but rarely has the row wastes the
+    // most lookups. Returns the indices of the dtables to merge.
+    //
+    // With compaction_min_overlap set, only dtables which overlap another
+    // by enough are merged. Among those, the ones closest in size to what
+    // they overlap go first, since merging a small dtable into a much
+    // larger one rewrites a lot of data for little gain.
+    fn compaction_candidates(&self) -> Vec<usize> {
+        let count = std::cmp::min(
+            self.disktables.len(),
+            (self.disktables.len() + 2).saturating_sub(self.disktable_limit)
+        );
+
+        let mut scores = self.disktables.iter()
+            .enumerate()
+            .filter_map(|(i, d)| {
+                if self.compaction_min_overlap <= 0.0 {
+                    return Some((i, 1.0));
+                }
+                self.disktables.iter()
+                    .enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .filter_map(|(_, other)| {
+                        let overlap = d.overlap(other).max(other.overlap(d));
+                        let sizes = (d.total_bytes() as f64, other.total_bytes() as f64);
+                        let size_ratio = (sizes.0.min(sizes.1) + 1.0) / (sizes.0.max(sizes.1) + 1.0);
+                        match overlap >= self.compaction_min_overlap {
+                            true  => Some(size_ratio),
+                            false => None
+                        }
+                    })
+                    .fold(None, |best: Option<f64>, r| Some(best.map_or(r, |b| b.max(r))))
+                    .map(|r| (i, r))
+            })
+            .map(|(i, size_ratio)| {
+                let d = &self.disktables[i];
+                let overlaps = self.disktables.iter()
+                    .filter(|other| other.may_overlap(d.lookup.get_min_key(), "") &&
+                        d.may_overlap(other.lookup.get_min_key(), ""))
+                    .count() - 1;
+                let (hits, misses, _) = d.read_stats();
+                let score = (overlaps + 1) as f64 * (misses + 1) as f64 / (hits + misses + 1) as f64;
+                (i, score * size_ratio)
+            })
+            .collect::<Vec<_>>();
+
+        // A dtable can't be merged on its own.
+        if self.compaction_min_overlap > 0.0 && scores.len() < 2 {
+            return vec![];
+        }
+
+        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        scores.into_iter().take(count).map(|(i, _)| i).collect()
+    }
+
+    // Merge the dtables at the provided indices. The result is a single
+    // dtable, unless max_dtable_bytes splits it up by key range.
+    fn merge(&mut self, selected: &[usize]) -> Result<(), BaseError> {
+        let span_start = self.span_start();
+        let tables = mem::replace(&mut self.disktables, vec![]);
+        let (merging, kept): (Vec<_>, Vec<_>) = tables.into_iter()
+            .enumerate()
+            .partition(|&(i, _)| selected.contains(&i));
+        let merging = merging.into_iter().map(|(_, d)| d).collect::<Vec<_>>();
+        self.disktables = kept.into_iter().map(|(_, d)| d).collect();
+
+        info!(
+            "Merging {} of {} dtables ({} rows, {} bytes).",
+            merging.len(),
+            merging.len() + self.disktables.len(),
+            merging.iter().map(|d| d.lookup.get_row_count()).sum::<u64>(),
+            merging.iter().map(|d| d.total_bytes()).sum::<u64>()
+        );
+
+        // When every dtable is being merged, the range deletions that they
+        // contain can be applied to the data and then dropped, as long as
+        // no open snapshot still needs to read underneath them. Otherwise
+        // the deletions are kept around, to keep hiding the data in the
+        // dtables which weren't merged or were archived.
+        let tombstones = merging.iter()
+            .flat_map(|d| d.lookup.get_tombstones().iter().cloned())
+            .collect::<Vec<_>>();
+        let now = self.clock.now();
+        let gc_before = match self.disktables.is_empty() && self.archived.is_empty() {
+            true  => self.gc_before(now),
+            false => 0
+        };
+
+        // Rows whose soft deletion is past the retention window are dropped
+        // as well, under the same conditions. A soft deletion or undelete
+        // which is still in the memtable would be missed, so they wait for
+        // a merge after it has been written out.
+        let retention = self.soft_delete_retention_ms * 1_000_000;
+        let marked = self.memtable.range_from("")
+            .any(|(_, r)| r.get_column(dtable::SOFT_DELETE_COLUMN).is_some());
+        let soft_deleted_before = match gc_before > 0 && retention > 0 && !marked {
+            true  => std::cmp::min(gc_before, now.saturating_sub(retention)),
+            false => 0
+        };
+
+        let storage = self.storage.clone();
+        let policies = self.families.clone();
+        // The merged dtable is always synced, whatever the fsync policy,
+        // since the inputs are deleted as soon as the manifest is written.
+        let options = dtable::CompactionOptions{
+            sync: true,
+            bytes_per_second: self.compaction_bytes_per_second,
+            gc_before: gc_before,
+            created: now,
+            max_bytes: self.max_dtable_bytes,
+            drop_cache: self.compaction_drop_cache,
+            soft_deleted_before: soft_deleted_before,
+            max_fan_in: self.max_merge_fan_in
+        };
+        let mut paths = vec![];
+        let merged = dtable::DTable::merge_into(
+            storage,
+            &mut || {
+                let path = self.next_dtable_path();
+                paths.push(path.clone());
+                path
+            },
+            merging.as_slice(),
+            tombstones.as_slice(),
+            &policies,
+            options
+        );
+        let (merged, merge_stats) = match merged {
+            Ok(d)   => d,
+            Err(e)  => {
+                self.disktables.extend(merging);
+                let path = paths.pop().unwrap_or_default();
+                return Err(BaseError::from_dtable(&path, e));
+            }
+        };
+
+        self.last_fsync = self.clock.now();
+        self.major_compactions += 1;
+        self.merge_max_fan_in = std::cmp::max(self.merge_max_fan_in, merge_stats.max_fan_in);
+        self.merge_batched_rows += merge_stats.batched_rows;
+        let compaction = Compaction{
+            major: true,
+            garbage_collection: false,
+            timestamp: now,
+            input_dtables: merging.len() as u64,
+            rows: merged.iter().map(|d| d.lookup.get_row_count()).sum(),
+            bytes: merged.iter().map(|d| d.lookup.get_total_bytes()).sum(),
+            max_fan_in: merge_stats.max_fan_in
+        };
+        self.record_span("compaction.major", span_start, vec![
+            (String::from("input_dtables"), format!("{}", compaction.input_dtables)),
+            (String::from("output_dtables"), format!("{}", merged.len())),
+            (String::from("rows"), format!("{}", compaction.rows)),
+            (String::from("bytes"), format!("{}", compaction.bytes)),
+            (String::from("max_fan_in"), format!("{}", compaction.max_fan_in))
+        ]);
+        self.record_compaction(compaction);
+        self.disktables.extend(merged);
+        self.row_cache.borrow_mut().clear();
+
+        // Once the manifest no longer refers to the old dtables, their
+        // files can be deleted.
+        self.write_manifest()?;
+        for d in merging {
+            if let Err(e) = d.remove_files() {
+                warn!("Unable to remove dtable {}: {}", d.filename(), e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_compaction(&mut self, compaction: Compaction) {
+        if self.compaction_history.len() == COMPACTION_HISTORY_LENGTH {
+            self.compaction_history.pop_front();
+        }
+        self.compaction_history.push_back(compaction);
+    }
+
+    // Returns the most recent compactions, oldest first.
+    pub fn compaction_history(&self) -> Vec<Compaction> {
+        self.compaction_history.iter().cloned().collect()
+    }
+
+    // Move the dtables which were created more than archive_after_days ago
+    // into the archive directory, compressing them on the way. DTables
+    // which contain range deletions stay behind, since the deletions need
+    // to keep applying to the rest of the data. A row found in a hot
+    // dtable is read without looking in the archive, so a dtable is also
+    // kept hot if its key range overlaps one which stays hot, which might
+    // hold older versions of its rows.
+    pub fn archive_disktables(&mut self) -> Result<(), BaseError> {
+        if self.archive_after_days == 0 {
+            return Ok(());
+        }
+
+        let cutoff = self.clock.now()
+            .saturating_sub(self.archive_after_days * 24 * 3600 * 1_000_000_000);
+        let eligible = self.disktables.iter()
+            .map(|d| {
+                d.lookup.get_created() > 0 &&
+                    d.lookup.get_created() < cutoff &&
+                    d.lookup.get_tombstones().is_empty()
+            })
+            .collect::<Vec<_>>();
+        let mut archivable = self.disktables.iter().zip(&eligible)
+            .map(|(d, &e)| e && self.disktables.iter().zip(&eligible)
+                .all(|(other, &o)| o || !d.may_overlap_keys(other)))
+            .collect::<Vec<_>>();
+
+        let mut index = 0;
+        while index < self.disktables.len() {
+            if !archivable[index] {
+                index += 1;
+                continue;
+            }
+
+            self.storage.create_dir_all(&self.archive_directory)
+                .map_err(|e| BaseError::io(&self.archive_directory, e))?;
+            let path = format!(
+                "{}/{}.gz",
+                self.archive_directory,
+                self.disktables[index].filename().rsplit('/').next().unwrap()
+            );
+            let archived = self.disktables[index].archive(&path)
+                .map_err(|e| BaseError::io(&path, e))?;
+
+            let d = self.disktables.remove(index);
+            archivable.remove(index);
+            self.archived.push(archived);
+            self.row_cache.borrow_mut().clear();
+            self.write_manifest()?;
+            if let Err(e) = d.remove_files() {
+                warn!("Unable to remove dtable {}: {}", d.filename(), e);
+            }
+            info!("Archived dtable {} to {}.", d.filename(), path);
+        }
+
+        Ok(())
+    }
+
+    // Rewrite the dtables which are mostly hidden by range deletions,
+    // dropping the hidden data. This is much cheaper than merging every
+    // dtable, which is otherwise the only way that the data is removed.
+    // Deletions which an open snapshot may still need to read underneath
+    // are left alone. Returns the number of dtables which were rewritten.
+    pub fn collect_garbage(&mut self) -> Result<usize, BaseError> {
+        if self.gc_garbage_ratio <= 0.0 {
+            return Ok(0);
+        }
+
+        let now = self.clock.now();
+        let gc_before = self.gc_before(now);
+        let tombstones = self.tombstones()
+            .filter(|t| t.get_timestamp() <= gc_before)
+            .cloned()
+            .collect::<Vec<_>>();
+        if tombstones.is_empty() {
+            return Ok(0);
+        }
+
+        let mut collected = 0;
+        let mut index = 0;
+        while index < self.disktables.len() {
+            // Deletions which are older than the ones already applied to
+            // the dtable were either applied as well, or are left for the
+            // next major compaction.
+            let applicable = tombstones.iter()
+                .filter(|t| t.get_timestamp() > self.disktables[index].lookup.get_collected())
+                .filter(|t| self.disktables[index].may_overlap(t.get_start(), t.get_end()))
+                .collect::<Vec<_>>();
+            let ratio = self.disktables[index].garbage_ratio(&applicable);
+            if applicable.is_empty() || ratio < self.gc_garbage_ratio {
+                index += 1;
+                continue;
+            }
+
+            let span_start = self.span_start();
+            let path = self.next_dtable_path();
+            info!(
+                "Collecting garbage in dtable {} (~{:.0}% hidden by range deletions).",
+                self.disktables[index].filename(),
+                ratio * 100.0
+            );
+            // The rewrite is always synced, whatever the fsync policy, since
+            // the original is deleted once the manifest stops referring to it.
+            let d = self.disktables[index].drop_obsolete(&path, true, &self.families, &applicable)
+                .map_err(|e| BaseError::from_dtable(&path, e))?;
+            self.last_fsync = self.clock.now();
+
+            let compaction = Compaction{
+                major: false,
+                garbage_collection: true,
+                timestamp: now,
+                input_dtables: 1,
+                rows: d.lookup.get_row_count(),
+                bytes: d.lookup.get_total_bytes(),
+                max_fan_in: 0
+            };
+            self.record_span("compaction.gc", span_start, vec![
+                (String::from("rows"), format!("{}", compaction.rows)),
+                (String::from("bytes"), format!("{}", compaction.bytes))
+            ]);
+            self.record_compaction(compaction);
+
+            // If nothing is left of the dtable, it can just be dropped.
+            let old = if d.len() == 0 && d.lookup.get_tombstones().is_empty() {
+                d.remove_files().unwrap_or(());
+                self.disktables.remove(index)
+            } else {
+                index += 1;
+                mem::replace(&mut self.disktables[index - 1], d)
+            };
+            self.row_cache.borrow_mut().clear();
+
+            self.write_manifest()?;
+            if let Err(e) = old.remove_files() {
+                warn!("Unable to remove dtable {}: {}", old.filename(), e);
+            }
+            collected += 1;
+        }
+
+        Ok(collected)
+    }
+
+    // Rename columns and map their values in every row that the migration
+    // applies to. The memtable is flushed first, and archived dtables with
+    // matching rows are rehydrated, so that every row is in a dtable. Each
+    // of those dtables is then rewritten into a new one, and the new ones
+    // are swapped in through the manifest. Returns the number of dtables
+    // which were rewritten.
+    pub fn migrate_columns(&mut self, migration: &migration::ColumnMigration) -> Result<usize, BaseError> {
+        if migration.is_empty() {
+            return Ok(0);
+        }
+
+        if self.memtable.len() > 0 || !self.memtable.tombstones().is_empty() {
+            self.empty_memtable()?;
+        }
+
+        let mut index = 0;
+        while index < self.archived.len() {
+            let applies = self.archived[index].entries()
+                .map_err(|e| BaseError::io(self.archived[index].filename(), e))?
+                .iter()
+                .any(|e| migration.applies_to(e.get_key()));
+            if !applies {
+                index += 1;
+                continue;
+            }
+
+            let path = self.next_dtable_path();
+            let created = self.clock.now();
+            let d = self.archived[index].rehydrate(&path, created)
+                .map_err(|e| BaseError::io(&path, e))?;
+            let archived = self.archived.remove(index);
+            self.disktables.push(d);
+            self.row_cache.borrow_mut().clear();
+            self.write_manifest()?;
+            if let Err(e) = archived.remove_files() {
+                warn!("Unable to remove archived dtable {}: {}", archived.filename(), e);
+            }
+        }
+
+        // Each rewritten dtable is synced, whatever the fsync policy, since
+        // the old one is deleted as soon as the manifest is written.
+        let mut migrated = vec![];
+        for index in 0..self.disktables.len() {
+            let applies = self.disktables[index].entries()
+                .map_err(|e| BaseError::io(self.disktables[index].filename(), e))?
+                .iter()
+                .any(|e| migration.applies_to(e.get_key()));
+            if !applies {
+                continue;
+            }
+
+            let path = self.next_dtable_path();
+            match self.disktables[index].rewrite(&path, true, &self.families, |row| migration.applies_to(row), |_, row| migration.rewrite(row)) {
+                Ok(d)   => migrated.push((index, d)),
+                Err(e)  => {
+                    // Nothing has been swapped in yet, so the new dtables
+                    // can just be thrown away.
+                    for (_, d) in migrated {
+                        d.remove_files().unwrap_or(());
+                    }
+                    return Err(BaseError::from_dtable(self.disktables[index].filename(), e));
+                }
+            };
+        }
+
+        let count = migrated.len();
+        if count > 0 {
+            self.last_fsync = self.clock.now();
+        }
+        let mut replaced = vec![];
+        for (index, d) in migrated {
+            info!("Migrated dtable {} to {}.", self.disktables[index].filename(), d.filename());
+            replaced.push(mem::replace(&mut self.disktables[index], d));
+        }
+        self.row_cache.borrow_mut().clear();
+        self.result_cache.clear();
+
+        // Once the manifest no longer refers to the old dtables, their
+        // files can be deleted.
+        self.write_manifest()?;
+        for d in replaced {
+            if let Err(e) = d.remove_files() {
+                warn!("Unable to remove dtable {}: {}", d.filename(), e);
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Bring any archived dtables which contain the row back into the hot
+    // set. Returns whether any dtables were restored.
+    fn rehydrate(&mut self, row: &str) -> Result<bool, BaseError> {
+        let mut restored = false;
+        let mut index = 0;
+        while index < self.archived.len() {
+            let contains = self.archived[index].may_contain(row) &&
+                self.archived[index].get_row_offset(row)
+                    .map_err(|e| BaseError::io(self.archived[index].filename(), e))?
+                    .is_some();
+            if !contains {
+                index += 1;
+                continue;
+            }
+
+            let path = self.next_dtable_path();
+            let created = self.clock.now();
+            let d = self.archived[index].rehydrate(&path, created)
+                .map_err(|e| BaseError::io(&path, e))?;
+
+            let archived = self.archived.remove(index);
+            self.disktables.push(d);
+            self.row_cache.borrow_mut().clear();
+            self.write_manifest()?;
+            if let Err(e) = archived.remove_files() {
+                warn!("Unable to remove archived dtable {}: {}", archived.filename(), e);
+            }
+            info!("Restored archived dtable {} to {}.", archived.filename(), path);
+            restored = true;
+        }
+
+        Ok(restored)
+    }
+
+    // Run a query with timestamp set to now.
+    pub fn query_now(&mut self, q: query::Query) -> query::QueryResult {
+        self.query_now_with_context(q, &query::QueryContext::new())
+    }
+
+    // Run a query with timestamp set to now, as part of the request
+    // described by the context. Log messages caused by the query carry
+    // its trace ID, and if it takes longer than slow_query_ms, it's
+    // written to the slow query log.
+    pub fn query_now_with_context(&mut self, q: query::Query, context: &query::QueryContext) -> query::QueryResult {
+        self.query_now_with_timestamp(q, context).0
+    }
+
+    // Like query_now_with_context, but also returns the timestamp that
+    // the query ran at, which for a write is its commit timestamp. Reads
+    // run no earlier than the context's min_read_timestamp, so a client
+    // always sees its own writes even if the clock has gone backwards,
+    // e.g. after a restart on another machine. A replica refuses them
+    // with ReplicaBehind until it has caught up that far.
+    pub fn query_now_with_timestamp(&mut self, q: query::Query, context: &query::QueryContext) -> (query::QueryResult, u64) {
+        let (result, timestamp, _) = self.query_now_with_cost(q, context);
+        (result, timestamp)
+    }
+
+    // Like query_now_with_timestamp, but also returns what the query
+    // cost to run (see QueryCost). Its time in the worker pool's queue
+    // and waiting for the lock isn't known here, so those are left as 0.
+    pub fn query_now_with_cost(&mut self, q: query::Query, context: &query::QueryContext) -> (query::QueryResult, u64, query::QueryCost) {
+        let description = match self.slow_query_ms {
+            0 => String::new(),
+            _ => format!("{}", q)
+        };
+
+        let started = time::precise_time_ns();
+        self.trace_id = context.trace_id.clone();
+        self.span_id = context.span_id.clone();
+        let mut timestamp = self.clock.now();
+        if !q.is_write() && context.min_read_timestamp > timestamp {
+            timestamp = context.min_read_timestamp;
+        }
+        if !q.is_write() && self.replica && context.min_read_timestamp > self.caught_up_to {
+            return (query::QueryResult::ReplicaBehind, timestamp, query::QueryCost::default());
+        }
+        let counters = self.read_counters();
+        let result = self.query_as(q, timestamp, context);
+
+        // The dtables count their own reads, so the query's share is the
+        // difference. DTables added or removed by the query, e.g. by a
+        // flush, may make it a little off.
+        let (probed, rows, bytes) = self.read_counters();
+        let elapsed_ns = time::precise_time_ns() - started;
+        let cost = query::QueryCost{
+            dtables_probed: probed.saturating_sub(counters.0),
+            bytes_read: bytes.saturating_sub(counters.2),
+            rows_merged: rows.saturating_sub(counters.1),
+            execute_ns: elapsed_ns,
+            ..Default::default()
+        };
+        self.queries += 1;
+        self.query_costs.add(&cost);
+
+        let elapsed_ms = elapsed_ns / 1_000_000;
+        if self.slow_query_ms > 0 && elapsed_ms >= self.slow_query_ms {
+            warn!("Slow query ({} ms){}: {} => {}", elapsed_ms, self.trace(), description, result);
+        }
+        self.trace_id.clear();
+        self.span_id.clear();
+
+        (result, timestamp, cost)
+    }
+
+    // The number of times the dtables have been searched for a row, the
+    // number of rows read from them, and the bytes read, in total.
+    fn read_counters(&self) -> (u64, u64, u64) {
+        self.disktables.iter().fold((0, 0, 0), |(probed, rows, bytes), d| {
+            let (hits, misses, bytes_read) = d.read_stats();
+            (probed + hits + misses, rows + hits, bytes + bytes_read)
+        })
+    }
+
+    // The start time for a span, if spans are being recorded for the
+    // query currently being run.
+    fn span_start(&self) -> u64 {
+        match (&self.span_sink, self.trace_id.as_str()) {
+            (&Some(_), t) if !t.is_empty()  => time::precise_time_ns(),
+            _                               => 0
+        }
+    }
+
+    // Record a span which started at span_start and has just finished.
+    fn record_span(&self, name: &str, span_start: u64, attributes: Vec<(String, String)>) {
+        if span_start == 0 {
+            return;
+        }
+
+        if let Some(ref sink) = self.span_sink {
+            sink.record(spans::Span{
+                trace_id: self.trace_id.clone(),
+                span_id: String::new(),
+                parent_span_id: self.span_id.clone(),
+                name: name.to_owned(),
+                start: span_start,
+                end: time::precise_time_ns(),
+                attributes: attributes
+            });
+        }
+    }
+
+    // Run the query on behalf of the auth token, if the access control
+    // rules allow it. Listed keys are filtered down to the ones which the
+    // token can read, and successful writes go in the audit log.
+    fn query_as(&mut self, q: query::Query, timestamp: u64, context: &query::QueryContext) -> query::QueryResult {
+        let token = context.auth_token.as_str();
+        let q = self.key_rules.normalize_query(q);
+        if !self.access_control.allows(token, &q) {
+            self.permission_denied += 1;
+            return query::QueryResult::PermissionDenied;
+        }
+
+        // Rows which belong to another shard are refused, so that the
+        // client knows to fetch the new shard map and try again.
+        if self.shard_map.is_enabled() && !shards::query_rows(&q).iter().all(|r| self.owns_row(r)) {
+            return query::QueryResult::WrongShard{version: self.shard_map.version};
+        }
+
+        if q.is_write() && !self.accepts_writes() {
+            return query::QueryResult::NotLeader;
+        }
+
+        // A write which has already been applied is answered without
+        // being run again.
+        let idempotent = match q {
+            query::Query::Insert{..} | query::Query::Update{..} | query::Query::Append{..} |
+            query::Query::Transaction{..} =>
+                !context.idempotency_key.is_empty() && self.idempotency.capacity > 0,
+            _ => false
+        };
+        if idempotent && self.idempotency.check(&context.idempotency_key) {
+            info!("Skipping repeated write with idempotency key {}{}", context.idempotency_key, self.trace());
+            return query::QueryResult::Done;
+        }
+
+        let entries = match self.audit_log {
+            Some(_) => {
+                let now = time::get_time();
+                audit::AuditEntry::from_query(&q, context, now.sec as u64 * 1_000_000_000 + now.nsec as u64)
+            },
+            None    => vec![]
+        };
+
+        if idempotent {
+            self.idempotency_key = context.idempotency_key.clone();
+        }
+        let result = self.query(q, timestamp);
+        self.idempotency_key.clear();
+        if let query::QueryResult::Done = result {
+            for e in entries.iter() {
+                self.record_audit_entry(e);
+            }
+        }
+
+        match result {
+            query::QueryResult::Keys{keys: k} => query::QueryResult::Keys{
+                keys: k.into_iter().filter(|key| self.access_control.can_read(token, key)).collect()
+            },
+            query::QueryResult::Rows{rows: r, next: n} => query::QueryResult::Rows{
+                rows: r.into_iter().filter(|row| self.access_control.can_read(token, &row.key)).collect(),
+                next: n
+            },
+            query::QueryResult::HotKeys{keys: k} => query::QueryResult::HotKeys{
+                keys: k.into_iter().filter(|key| self.access_control.can_read(token, &key.key)).collect()
+            },
+            x => x
+        }
+    }
+
+    fn owns_row(&self, row: &str) -> bool {
+        match self.shard_map.shard_for(row) {
+            Some(s) => s.address == self.shard_address,
+            None    => false
+        }
+    }
+
+    // Replace the shard map with a newer one, e.g. after resharding.
+    // Maps which aren't newer than the current one are ignored, so that
+    // a late update can't roll it back. Returns whether it was replaced.
+    pub fn update_shard_map(&mut self, map: shards::ShardMap) -> Result<bool, String> {
+        map.validate()?;
+        if self.shard_map.is_enabled() && map.version <= self.shard_map.version {
+            return Ok(false);
+        }
+        info!("Shard map updated to version {}.", map.version);
+        self.shard_map = map;
+        Ok(true)
+    }
+
+    // Start recording mutations in the audit log in the directory. The
+    // log is rotated at max_bytes, keeping max_files old files (0 means
+    // no limit, for either of them).
+    pub fn enable_audit_log(&mut self, directory: &str, max_bytes: u64, max_files: usize) -> Result<(), io::Error> {
+        let mut log = audit::AuditLog::open(self.storage.clone(), directory)?;
+        log.max_bytes = max_bytes;
+        log.max_files = max_files;
+        self.audit_log = Some(log);
+        Ok(())
+    }
+
+    // The write has already happened by the time it's recorded, so a
+    // failure to record it is logged rather than failing the query.
+    fn record_audit_entry(&mut self, entry: &audit::AuditEntry) {
+        let trace = self.trace();
+        if let Some(ref mut log) = self.audit_log {
+            if let Err(e) = log.record(entry) {
+                error!("Unable to write to the audit log{}: {} ({} {})", trace, e, entry.operation, entry.row);
+            }
+        }
+    }
+
+    // Find the most recent audit log entries which changed the row.
+    pub fn search_audit_log(&self, row: &str, limit: usize) -> Result<Vec<audit::AuditEntry>, io::Error> {
+        match self.audit_log {
+            Some(ref log)   => log.search(&self.key_rules.normalize(row), limit),
+            None            => Ok(vec![])
+        }
+    }
+
+    // A suffix for log messages which ties them to the query currently
+    // being run, if it has a trace ID.
+    fn trace(&self) -> String {
+        match self.trace_id.as_str() {
+            ""  => String::new(),
+            t   => format!(" (trace_id={})", t)
+        }
+    }
+
+    pub fn query(&mut self, q: query::Query, timestamp: u64) -> query::QueryResult {
+        // Row keys are normalized before anything else happens, so that
+        // reads and writes always agree on them.
+        let q = self.key_rules.normalize_query(q);
+
+        // Executing a prepared query selects its columns from the row.
+        let q = match q {
+            query::Query::Execute{handle: h, row: r} => match self.prepared.execute(h) {
+                Some(g) => query::Query::Select{
+                    row: r,
+                    get: g.clone(),
+                    snapshot: 0,
+                    include_deleted: false,
+                    project: vec![],
+                    strict: false,
+                    column_start: String::new(),
+                    column_limit: 0
+                },
+                None    => return query::QueryResult::NotPrepared
+            },
+            x => x
+        };
+
+        match q {
+            query::Query::Select{row: ref r, ..} |
+            query::Query::Insert{row: ref r, ..} |
+            query::Query::Update{row: ref r, ..} |
+            query::Query::Append{row: ref r, ..} |
+            query::Query::SelectList{row: ref r, ..} |
+            query::Query::History{row: ref r, ..} |
+            query::Query::Describe{row: ref r} |
+            query::Query::Checksum{row: ref r} |
+            query::Query::Diff{row: ref r, ..} |
+            query::Query::SoftDelete{row: ref r} |
+            query::Query::Undelete{row: ref r} if !self.key_rules.is_valid(r) => {
+                return query::QueryResult::InvalidKey;
+            },
+            query::Query::Transaction{ref updates} if !updates.keys().all(|r| self.key_rules.is_valid(r)) => {
+                return query::QueryResult::InvalidKey;
+            },
+            query::Query::Explain(ref e) if !shards::query_rows(e).iter().all(|r| self.key_rules.is_valid(r)) => {
+                return query::QueryResult::InvalidKey;
+            },
+            _ => ()
+        }
+
+        match q {
+            query::Query::Select{row: ref r, ..} |
+            query::Query::SelectList{row: ref r, ..} |
+            query::Query::History{row: ref r, ..} |
+            query::Query::Describe{row: ref r} |
+            query::Query::Checksum{row: ref r} |
+            query::Query::Diff{row: ref r, ..} => self.record_use(r, false),
+            query::Query::Insert{row: ref r, ..} |
+            query::Query::Update{row: ref r, ..} |
+            query::Query::Append{row: ref r, ..} |
+            query::Query::SoftDelete{row: ref r} |
+            query::Query::Undelete{row: ref r} => self.record_use(r, true),
+            query::Query::Transaction{ref updates} => for r in updates.keys() {
+                self.record_use(r, true);
+            },
+            _ => ()
+        }
+
+        // Inserts get their table's default columns, and writes to tables
+        // with declared columns have to fit them.
+        let q = self.schemas.apply_defaults(q, timestamp);
+        if let Err(reason) = self.schemas.validate(&q) {
+            return query::QueryResult::SchemaViolation{reason: reason};
+        }
+
+        // Writes are refused when the disk is nearly full, so that there's
+        // always room left to flush the memtable and compact. Deletions are
+        // still allowed, since they're the way to free up space.
+        match q {
+            query::Query::Insert{..} | query::Query::Update{..} | query::Query::Append{..} |
+            query::Query::Transaction{..} => {
+                if self.out_of_space() {
+                    return query::QueryResult::OutOfSpace;
+                }
+            },
+            _ => ()
+        }
+
+        // Rows in salted tables are stored under their bucket, once the
+        // query has been checked against the unsalted keys.
+        let q = self.salting.salt_query(q);
+
+        match q {
+            query::Query::Select{row: r, get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl} => {
+                // If the select is reading from a snapshot, it sees the
+                // database as it was when the snapshot was created.
+                let read_timestamp = match s {
+                    0 => timestamp,
+                    id => match self.snapshots.get(&id) {
+                        Some(snapshot) if snapshot.expires > timestamp => snapshot.timestamp,
+                        _ => return query::QueryResult::SnapshotNotFound
+                    }
+                };
+
+                if cl > 0 || !cs.is_empty() {
+                    if !p.is_empty() {
+                        return query::QueryResult::InternalError{
+                            error: String::from("projections can't be used when paging through columns")
+                        };
+                    }
+                    return self.select_page(&r, &g, &cs, cl as usize, read_timestamp, d, t);
+                }
+
+                // Tables with a result cache TTL may be answered without
+                // reading the row at all. Selects of soft deleted rows
+                // aren't cached, since they get a different result, and
+                // neither are projections, which the cache doesn't key on.
+                // Strict selects skip it too, since a cached result may
+                // have come from a read which missed a dtable.
+                let ttl_ms = match d || t || !p.is_empty() {
+                    true  => 0,
+                    false => self.schemas.result_cache_ttl_ms(&r)
+                };
+                match self.result_cache.get(&r, &g, read_timestamp, ttl_ms) {
+                    Some(Some(c))   => return query::QueryResult::Data{columns: c},
+                    Some(None)      => return query::QueryResult::RowNotFound,
+                    None            => ()
+                }
+
+                // The columns that projections are worked out from, and
+                // the soft delete marker, are read along with the columns.
+                let mut cols = g.iter()
+                    .map(|s| s.as_str())
+                    .chain(p.iter().map(|p| p.column()))
+                    .collect::<Vec<&str>>();
+                cols.push(dtable::SOFT_DELETE_COLUMN);
+                let result = match self.select_with_archive(&r, &cols, read_timestamp, t) {
+                    query::QueryResult::Data{columns: mut c} => {
+                        let marker = c.pop().and_then(|m| m);
+                        let projected = c.split_off(g.len());
+                        c.extend(p.iter().zip(projected.into_iter()).map(|(p, v)| v.map(|v| p.evaluate(&v))));
+                        match self.soft_delete_visible(marker.as_ref().map(|m| m.as_slice()), d, read_timestamp) {
+                            true  => query::QueryResult::Data{columns: c},
+                            false => query::QueryResult::RowNotFound
+                        }
+                    },
+                    x => x
+                };
+                if ttl_ms > 0 {
+                    match result {
+                        query::QueryResult::Data{columns: ref c} =>
+                            self.result_cache.insert(&r, &g, read_timestamp, ttl_ms, Some(c.clone())),
+                        query::QueryResult::RowNotFound =>
+                            self.result_cache.insert(&r, &g, read_timestamp, ttl_ms, None),
+                        _ => ()
+                    }
+                }
+                result
+            },
+            query::Query::Insert{row: r, set: s} => {
+                self.insert(
+                    &r,
+                    s.into_iter().map(|(key, value)|
+                        query::MUpdate::new(key.as_str(), value)
+                    ).collect::<Vec<_>>(),
+                    timestamp
+                )
+            },
+            query::Query::Update{row: r, set: s} => {
+                self.update(
+                    &r,
+                    s.into_iter().map(|(key, value)|
+                        query::MUpdate::new(key.as_str(), value)
+                    ).collect::<Vec<_>>(),
+                    timestamp
+                )
+            },
+            query::Query::Stats => self.stats(),
+            query::Query::ListKeys{start: s, limit: l} => {
+                let salted = self.salting.table_end(&s).and_then(|end| {
+                    self.salting.bucket_ranges(&scan::KeyRange::new(&s, &end)).map(|r| (r, end))
+                });
+                match salted {
+                    Some((ranges, end)) => self.list_salted_keys(ranges, &end, l as usize),
+                    None => self.list_keys(&s, l as usize)
+                }
+            },
+            query::Query::DeleteRange{start_row: s, end_row: e} => {
+                // In a salted table, the range is deleted from every bucket.
+                match self.salting.bucket_ranges(&scan::KeyRange::new(&s, &e)) {
+                    Some(ranges) => {
+                        for r in ranges {
+                            match self.delete_range(&r.start, &r.end, timestamp) {
+                                query::QueryResult::Done => (),
+                                x => return x
+                            }
+                        }
+                        query::QueryResult::Done
+                    },
+                    None => self.delete_range(&s, &e, timestamp)
+                }
+            },
+            query::Query::CreateSnapshot => self.create_snapshot(timestamp),
+            query::Query::Append{row: r, set: s} => {
+                // Every column already keeps each value written to it, so
+                // an append is an update which is read back as a list.
+                self.append(
+                    &r,
+                    s.into_iter().map(|(key, value)|
+                        query::MUpdate::new(key.as_str(), value)
+                    ).collect::<Vec<_>>(),
+                    timestamp
+                )
+            },
+            query::Query::SelectList{row: r, column: c, limit: l, start: s, end: e} =>
+                self.select_list(&r, &c, l as usize, s, e, timestamp),
+            query::Query::Scan{start: s, end: e, get: g, limit: l, snapshot: n} => {
+                let read_timestamp = match n {
+                    0 => timestamp,
+                    id => match self.snapshots.get(&id) {
+                        Some(snapshot) if snapshot.expires > timestamp => snapshot.timestamp,
+                        _ => return query::QueryResult::SnapshotNotFound
+                    }
+                };
+                let range = scan::KeyRange::new(&s, &e);
+                match self.salting.bucket_ranges(&range) {
+                    Some(ranges) => {
+                        let results = ranges.into_iter()
+                            .map(|r| self.scan(r, &g, l as usize, read_timestamp))
+                            .collect();
+                        let limit = match l as usize {
+                            0 => MAX_SCAN_ROWS,
+                            l => std::cmp::min(l, MAX_SCAN_ROWS)
+                        };
+                        self.salting.merge_scans(results, limit)
+                    },
+                    None => self.scan(range, &g, l as usize, read_timestamp)
+                }
+            },
+            query::Query::Describe{row: r} => self.describe(&r, timestamp),
+            query::Query::TopKeys{limit: l} => query::QueryResult::HotKeys{
+                keys: self.hot_rows.top_keys(l as usize, self.clock.now())
+            },
+            query::Query::SoftDelete{row: r} => self.soft_delete(&r, timestamp),
+            query::Query::Undelete{row: r} => self.undelete(&r, timestamp),
+            query::Query::History{row: r, column: c, limit: l} =>
+                self.history(&r, &c, l as usize, timestamp),
+            query::Query::Transaction{updates: u} => self.transaction(u, timestamp),
+            query::Query::Prepare{get: g} => query::QueryResult::Prepared{handle: self.prepared.prepare(g)},
+            query::Query::Execute{..} => query::QueryResult::NotPrepared,
+            query::Query::Checksum{row: r} => self.checksum(&r, timestamp),
+            query::Query::ChecksumRange{start: s, end: e} => {
+                let range = scan::KeyRange::new(&s, &e);
+                match self.salting.bucket_ranges(&range) {
+                    Some(ranges) => self.checksum_buckets(ranges, timestamp),
+                    None => self.checksum_range(range, timestamp)
+                }
+            },
+            query::Query::Diff{row: r, from_ts: f, to_ts: t} => self.diff(&r, f, t, timestamp),
+            query::Query::FreezeCompaction => self.freeze_compaction(true),
+            query::Query::UnfreezeCompaction => self.freeze_compaction(false),
+            query::Query::Explain(q) => self.explain(*q)
+        }
+    }
+
+    // Read a page of the row's columns, in key order, starting from the
+    // column start. Only the columns in get are included, unless it's
+    // empty. The page is returned as a single row, whose columns are the
+    // ones with a value, and next is the column to start the next page
+    // from (or empty, on the last page).
+    fn select_page(&mut self, row: &str, get: &[String], start: &str, limit: usize, timestamp: u64, include_deleted: bool, strict: bool) -> query::QueryResult {
+        let limit = match limit {
+            0 => MAX_PAGE_COLUMNS,
+            l => std::cmp::min(l, MAX_PAGE_COLUMNS)
+        };
+
+        // One more column than fits on the page is found, which is where
+        // the next page starts.
+        let filter = |c: &str| c != dtable::SOFT_DELETE_COLUMN && (get.is_empty() || get.iter().any(|g| g == c));
+        let names = match self.column_names(row, start, limit + 1, &filter, strict) {
+            Ok(n)   => n,
+            Err(e)  => return query::QueryResult::InternalError{error: e}
+        };
+        let mut cols = names.iter().map(|n| n.as_str()).collect::<Vec<_>>();
+        let next = match cols.len() > limit {
+            true  => cols.pop().unwrap_or("").to_owned(),
+            false => String::new()
+        };
+
+        cols.push(dtable::SOFT_DELETE_COLUMN);
+        match self.select_with_archive(row, &cols, timestamp, strict) {
+            query::QueryResult::Data{columns: mut c} => {
+                let marker = c.pop().and_then(|m| m);
+                if !self.soft_delete_visible(marker.as_ref().map(|m| m.as_slice()), include_deleted, timestamp) {
+                    return query::QueryResult::RowNotFound;
+                }
+                query::QueryResult::Rows{
+                    rows: vec![query::ScanRow{
+                        key: row.to_owned(),
+                        columns: cols.iter()
+                            .zip(c.into_iter())
+                            .filter_map(|(name, value)| value.map(|v| (name.to_string(), v)))
+                            .collect()
+                    }],
+                    next: next
+                }
+            },
+            x => x
+        }
+    }
+
+    // The names of the row's columns from start onward, in key order, in
+    // the memtable and any of the dtables, up to limit of those which
+    // match the filter. The keys of a DRow are sorted, so each dtable's
+    // are searched from start.
+    fn column_names<F: Fn(&str) -> bool>(&self, row: &str, start: &str, limit: usize, filter: &F, strict: bool) -> Result<Vec<String>, String> {
+        let mut names = BTreeSet::new();
+        if let Some(keys) = self.memtable.get_row(row).map(|r| r.column_keys(start, limit, filter)) {
+            names.extend(keys);
+        }
+
+        for d in self.disktables.iter().filter(|d| d.may_contain(row)) {
+            match d.get_row(row) {
+                Ok(r)   => {
+                    let keys = r.get_keys();
+                    let from = match keys.binary_search_by(|k| k.as_str().cmp(start)) {
+                        Ok(i) | Err(i) => i
+                    };
+                    names.extend(keys[from..].iter().filter(|k| filter(k.as_str())).take(limit).cloned());
+                },
+                Err(dtable::TError::NotFound) => (),
+                Err(e)  => {
+                    error!("Unable to read row {} from {}: {}{}", row, d.filename(), e, self.trace());
+                    if strict {
+                        return Err(format!("unable to read row {} from {}: {}", row, d.filename(), e));
+                    }
+                }
+            }
+        }
+        Ok(names.into_iter().take(limit).collect())
+    }
+
+    // Whether a row with the soft delete marker can be read. Rows which
+    // aren't deleted always can, and deleted ones only if they're asked
+    // for and the retention window hasn't ended.
+    fn soft_delete_visible(&self, marker: Option<&[u8]>, include_deleted: bool, timestamp: u64) -> bool {
+        match marker.and_then(dtable::soft_deleted_at) {
+            None            => true,
+            Some(deleted)   => include_deleted && self.can_undelete(deleted, timestamp)
+        }
+    }
+
+    // A retention window of zero keeps soft deleted rows forever.
+    fn can_undelete(&self, deleted: u64, timestamp: u64) -> bool {
+        self.soft_delete_retention_ms == 0 ||
+            deleted + self.soft_delete_retention_ms * 1_000_000 > timestamp
+    }
+
+    // Returns when the row was soft deleted, if it is.
+    fn soft_deleted_at(&mut self, row: &str, timestamp: u64) -> Result<Option<u64>, query::QueryResult> {
+        match self.select_with_archive(row, &[dtable::SOFT_DELETE_COLUMN], timestamp, false) {
+            query::QueryResult::Data{columns: c} =>
+                Ok(c[0].as_ref().and_then(|m| dtable::soft_deleted_at(m))),
+            x => Err(x)
+        }
+    }
+
+    // Hide the row from selects and scans, by marking it with the time that
+    // it was deleted. Deleting a row again keeps the original time.
+    pub fn soft_delete(&mut self, row: &str, timestamp: u64) -> query::QueryResult {
+        match self.soft_deleted_at(row, timestamp) {
+            Ok(Some(_)) => query::QueryResult::Done,
+            Ok(None)    => self.update(row, vec![query::MUpdate::new(
+                dtable::SOFT_DELETE_COLUMN,
+                format!("{}", timestamp).into_bytes()
+            )], timestamp),
+            Err(x)      => x
+        }
+    }
+
+    // Restore a soft deleted row, as long as its retention window hasn't
+    // ended.
+    pub fn undelete(&mut self, row: &str, timestamp: u64) -> query::QueryResult {
+        match self.soft_deleted_at(row, timestamp) {
+            Ok(None)    => query::QueryResult::Done,
+            Ok(Some(deleted)) if !self.can_undelete(deleted, timestamp) =>
+                query::QueryResult::RowNotFound,
+            Ok(Some(_)) => self.update(row, vec![query::MUpdate::new(
+                dtable::SOFT_DELETE_COLUMN,
+                vec![]
+            )], timestamp),
+            Err(x)      => x
+        }
+    }
+
+    // Select from the row, and if the hot dtables don't have everything
+    // that was asked for, look for the rest in the archive.
+    fn select_with_archive(&mut self, row: &str, cols: &[&str], timestamp: u64, strict: bool) -> query::QueryResult {
+        let result = self.select_tables(row, cols, timestamp, strict);
+        let missed = match result {
+            query::QueryResult::RowNotFound => true,
+            query::QueryResult::Data{columns: ref c} => c.iter().zip(cols)
+                .any(|(x, col)| x.is_none() && *col != dtable::SOFT_DELETE_COLUMN),
+            _ => false
+        };
+        if !missed || self.archived.is_empty() {
+            return result;
+        }
+
+        match self.rehydrate(row) {
+            Ok(true)    => self.select_tables(row, cols, timestamp, strict),
+            Ok(false)   => result,
+            Err(e)      => {
+                error!("Unable to restore archived dtables: {}{}", e, self.trace());
+                result
+            }
+        }
+    }
+
+    // Count a read or write of the row, and warn if the row has become
+    // hot enough to hold up the rest of the queries.
+    fn record_use(&mut self, row: &str, write: bool) {
+        let now = self.clock.now();
+        if let Some(rate) = self.hot_rows.record(row, write, now) {
+            warn!(
+                "Row {:?} is hot: ~{:.0} queries per second, over the limit of {}.{}",
+                row,
+                rate,
+                self.hot_rows.qps_limit,
+                self.trace()
+            );
+        }
+    }
+
+    // Create a snapshot of the database at the provided timestamp, which
+    // stays readable until the snapshot TTL runs out.
+    pub fn create_snapshot(&mut self, timestamp: u64) -> query::QueryResult {
+        // Clean up any snapshots which have already expired.
+        let expired = self.snapshots.iter()
+            .filter(|&(_, s)| s.expires <= timestamp)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.snapshots.remove(&id);
+        }
+
+        self.next_snapshot_id += 1;
+        self.snapshots.insert(self.next_snapshot_id, Snapshot{
+            timestamp: timestamp,
+            expires: timestamp + self.snapshot_ttl_ms * 1_000_000
+        });
+
+        query::QueryResult::Snapshot{id: self.next_snapshot_id}
+    }
+
+    // Returns the timestamp of the oldest live snapshot. Data which is
+    // deleted at or before this timestamp can be garbage collected.
+    fn gc_before(&self, now: u64) -> u64 {
+        self.snapshots.values()
+            .filter(|s| s.expires > now)
+            .map(|s| s.timestamp)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    // List up to limit row keys, starting at the provided key. This only
+    // reads the memtable and the dtable headers, so it doesn't touch the
+    // disk, unless the headers are paged.
+    pub fn list_keys(&self, start: &str, limit: usize) -> query::QueryResult {
+        let mut keys = self.memtable.keys_from(start, limit);
+        for d in &self.disktables {
+            let entries = match d.entries_from(start) {
+                Ok(e)   => e,
+                Err(e)  => return query::QueryResult::InternalError{ error: format!("{}", e) }
+            };
+            keys.extend(
+                entries
+                    .iter()
+                    .map(|e| e.get_key())
+                    .filter(|k| self.is_visible(d, k))
+                    .take(limit)
+                    .map(|k| k.to_owned())
+            );
+        }
+
+        keys.sort();
+        keys.dedup();
+        keys.truncate(limit);
+
+        query::QueryResult::Keys{keys: keys}
+    }
+
+    // List the keys of a salted table, starting from the matching key in
+    // each of its buckets, without the buckets. If there's room, the page
+    // continues with the keys after the table.
+    fn list_salted_keys(&self, ranges: Vec<scan::KeyRange>, end: &str, limit: usize) -> query::QueryResult {
+        let mut keys = vec![];
+        for range in ranges {
+            match self.list_keys(&range.start, limit) {
+                query::QueryResult::Keys{keys: k} => keys.extend(
+                    k.into_iter()
+                        .take_while(|k| k.as_str() < range.end.as_str())
+                        .map(|k| self.salting.unsalt(&k))
+                ),
+                x => return x
+            }
+        }
+        keys.sort();
+
+        if keys.len() < limit {
+            match self.list_keys(end, limit - keys.len()) {
+                query::QueryResult::Keys{keys: k} => keys.extend(k),
+                x => return x
+            }
+        }
+        keys.truncate(limit);
+
+        query::QueryResult::Keys{keys: keys}
+    }
+
+    // Collect a summary of the internal state of the database.
+    pub fn stats(&self) -> query::QueryResult {
+        let row_cache = self.row_cache.borrow();
+        query::QueryResult::Stats{stats: query::Stats{
+            memtable_size: self.memtable.size as u64,
+            memtable_rows: self.memtable.len() as u64,
+            disktables: self.disktables.len() as u64,
+            disktable_rows: self.disktables.iter().map(|d| d.len() as u64).sum(),
+            disktable_bytes: self.disktables.iter().map(|d| d.total_bytes()).sum(),
+            commit_log_bytes: self.commit_log.len().unwrap_or(0),
+            uptime_seconds: (self.clock.now() - self.started) / 1_000_000_000,
+            minor_compactions: self.minor_compactions,
+            major_compactions: self.major_compactions,
+            merge_max_fan_in: self.merge_max_fan_in,
+            merge_batched_rows: self.merge_batched_rows,
+            write_stalls: self.write_stalls,
+            write_stall_ns: self.write_stall_ns,
+            last_flush_ns: self.last_flush_ns,
+            free_bytes: self.free_bytes(),
+            out_of_space: self.out_of_space(),
+            permission_denied: self.permission_denied,
+            row_cache_rows: row_cache.len() as u64,
+            row_cache_hits: row_cache.hits,
+            row_cache_misses: row_cache.misses,
+            result_cache_results: self.result_cache.len() as u64,
+            result_cache_hits: self.result_cache.hits,
+            result_cache_misses: self.result_cache.misses,
+            queries: self.queries,
+            query_dtables_probed: self.query_costs.dtables_probed,
+            query_bytes_read: self.query_costs.bytes_read,
+            query_rows_merged: self.query_costs.rows_merged,
+            query_execute_ns: self.query_costs.execute_ns,
+            prepared_handles: self.prepared.len() as u64,
+            prepared_hits: self.prepared.hits,
+            prepared_misses: self.prepared.misses,
+            degraded: self.is_degraded(),
+            compaction_frozen: self.compaction_frozen,
+            dtables: self.disktables.iter().map(|d| {
+                let (hits, misses, bytes_read) = d.read_stats();
+                query::DTableStats{
+                    filename: d.filename().to_owned(),
+                    rows: d.len() as u64,
+                    bytes: d.total_bytes(),
+                    hits: hits,
+                    misses: misses,
+                    bytes_read: bytes_read,
+                    read_errors: d.read_errors(),
+                    degraded: d.is_degraded()
+                }
+            }).collect()
+        }}
+    }
+
+    // Returns the free space on the fullest of the data directories. The
+    // reading is reused for FREE_SPACE_CHECK_INTERVAL, since it's needed
+    // for every write.
+    pub fn free_bytes(&self) -> u64 {
+        let now = self.clock.now();
+        if let Some((checked, bytes)) = self.free_space_checked.get() {
+            if now.saturating_sub(checked) < FREE_SPACE_CHECK_INTERVAL {
+                return bytes;
+            }
+        }
+
+        let bytes = self.data_directories.iter()
+            .filter_map(|d| free_space(d))
+            .min()
+            .unwrap_or(u64::MAX);
+        self.free_space_checked.set(Some((now, bytes)));
+        bytes
+    }
+
+    pub fn out_of_space(&self) -> bool {
+        self.free_bytes() < self.min_free_bytes
+    }
+
+    // Whether merging the dtables could free up any space, by dropping
+    // overwritten values or data hidden by range deletions.
+    fn is_reclaimable(&self) -> bool {
+        self.disktables.len() > 1 ||
+            self.disktables.iter().any(|d| !d.lookup.get_tombstones().is_empty())
+    }
+
+    // Freeze or unfreeze background compaction. The memtable is still
+    // written to disk when it's full, past the dtable limit if need be.
+    pub fn freeze_compaction(&mut self, frozen: bool) -> query::QueryResult {
+        if self.compaction_frozen == frozen {
+            return query::QueryResult::Done;
+        }
+
+        self.compaction_frozen = frozen;
+        if let Err(e) = self.write_manifest() {
+            self.compaction_frozen = !frozen;
+            return query::QueryResult::InternalError{error: format!("{}", e)};
+        }
+        match frozen {
+            true  => info!("Froze compaction.{}", self.trace()),
+            false => info!("Unfroze compaction.{}", self.trace())
+        };
+        query::QueryResult::Done
+    }
+
+    pub fn is_compaction_frozen(&self) -> bool {
+        self.compaction_frozen
+    }
+
+    // Whether any dtable can't be read, so that reads may be missing data.
+    pub fn is_degraded(&self) -> bool {
+        self.disktables.iter().any(|d| d.is_degraded())
+    }
+
+    // Check whether a row in a dtable still has any data which hasn't been
+    // hidden by a range deletion. Only rows covered by a tombstone need
+    // to be read from disk to find out.
+    fn is_visible(&self, d: &dtable::DTable, key: &str) -> bool {
+        match self.deleted_at(key, u64::MAX) {
+            0 => true,
+            t => match d.get_row(key) {
+                Ok(row) => row.get_columns()
+                    .iter()
+                    .any(|c| c.get_entries().iter().any(|e| e.get_timestamp() > t)),
+                Err(_)  => false
+            }
+        }
+    }
+
+    // Find the most recent range deletion covering the row, as seen at
+    // the provided timestamp. Returns zero if the row was never deleted.
+    fn deleted_at(&self, row: &str, timestamp: u64) -> u64 {
+        dtable::deleted_at(self.tombstones(), row, timestamp)
+    }
+
+    // Iterate over every range deletion in the memtable and dtables.
+    fn tombstones<'a>(&'a self) -> Box<Iterator<Item=&'a RangeTombstone> + 'a> {
+        Box::new(
+            iter::once(self.memtable.tombstones())
+                .chain(self.disktables.iter().map(|d| d.lookup.get_tombstones()))
+                .flat_map(|tombstones| tombstones.iter())
+        )
+    }
+
+    // Iterate over the rows in the range, as seen at the provided
+    // timestamp. Rows are merged from the memtable and dtables as the
+    // iterator advances, so only one row is held in memory at a time.
+    pub fn iter_rows<'a>(&'a self, range: scan::KeyRange, timestamp: u64) -> scan::RowIter<'a> {
+        scan::RowIter::new(
+            &self.memtable,
+            &self.disktables,
+            self.tombstones().collect(),
+            range,
+            timestamp,
+            self.readahead_bytes
+        )
+    }
+
+    // Delete all of the rows in the range [start, end). The deletion is
+    // recorded as a tombstone, which hides older data in the dtables until
+    // they are merged. If there's an older snapshot still open, the data
+    // in the memtable is kept around for it as well.
+    pub fn delete_range(&mut self, start: &str, end: &str, timestamp: u64) -> query::QueryResult {
+        let purge = timestamp <= self.gc_before(timestamp);
+        self.memtable.delete_range(start, end, timestamp, purge);
+        self.row_cache.borrow_mut().invalidate_range(start, end);
+        self.result_cache.invalidate_range(start, end);
+
+        match self.commit_delete_range(start, end, timestamp) {
+            Ok(_)   => query::QueryResult::Done,
+            Err(e)  => self.partial_commit(e)
+        }
+    }
+
+    // The write made it into the memtable, but not the commit log, so it
+    // would be lost on restart.
+    fn partial_commit(&self, e: BaseError) -> query::QueryResult {
+        error!("Unable to write to the commit log: {}{}", e, self.trace());
+        query::QueryResult::PartialCommit{ error: format!("{}", e) }
+    }
+
+    fn update_entry(row: &str, updates: &[query::MUpdate], timestamp: u64) -> CommitLogEntry {
+        let mut c = CommitLogEntry::new();
+        c.set_key(row.to_owned());
+        c.set_timestamp(timestamp);
+        c.set_updates(::protobuf::RepeatedField::from_iter(
+            updates.iter()
+                .map(|u| {
+                    let mut cu = CommitLogUpdate::new();
+                    cu.set_column(u.key.to_owned());
+                    cu.set_value(u.value.to_owned());
+                    cu
+                })
+        ));
+        c
+    }
+
+    // Publish an insert/update to the commit log.
+    pub fn commit(&mut self, row: &str, updates: &[query::MUpdate], timestamp: u64) -> Result<(), BaseError> {
+        let c = Base::update_entry(row, updates, timestamp);
+        self.commit_entry(c)
+    }
+
+    // Publish the updates of a transaction to the commit log, as a single
+    // entry, so that it's replayed completely or not at all.
+    fn commit_transaction(&mut self, rows: &[(String, Vec<query::MUpdate>)], timestamp: u64) -> Result<(), BaseError> {
+        let mut c = CommitLogEntry::new();
+        c.set_field_type(CommitLogEntryType::TRANSACTION);
+        c.set_timestamp(timestamp);
+        c.set_writes(::protobuf::RepeatedField::from_iter(
+            rows.iter().map(|&(ref row, ref updates)| Base::update_entry(row, updates, timestamp))
+        ));
+        self.commit_entry(c)
+    }
+
+    fn commit_entry(&mut self, mut c: CommitLogEntry) -> Result<(), BaseError> {
+        // The key is remembered as soon as the write is, so that it's
+        // carried over if this write causes the memtable to be flushed.
+        if !self.idempotency_key.is_empty() {
+            let key = self.idempotency_key.clone();
+            self.idempotency.insert(&key);
+            c.set_idempotency_keys(::protobuf::RepeatedField::from_vec(vec![key]));
+        }
+
+        self.append_to_commit_log(&mut c)
+    }
+
+    // Publish a range deletion to the commit log.
+    fn commit_delete_range(&mut self, start: &str, end: &str, timestamp: u64) -> Result<(), BaseError> {
+        let mut c = CommitLogEntry::new();
+        c.set_field_type(CommitLogEntryType::RANGE_DELETION);
+        c.set_key(start.to_owned());
+        c.set_end_key(end.to_owned());
+        c.set_timestamp(timestamp);
+
+        self.append_to_commit_log(&mut c)
+    }
+
+    // Write an entry to the commit log, prefixed by its size, and sync it
+    // to disk according to the fsync policy.
+    fn append_to_commit_log(&mut self, c: &mut CommitLogEntry) -> Result<(), BaseError> {
+        c.set_fencing_token(self.fencing_token);
+        let size = c.compute_size();
+        self.commit_log.write_u32::<LittleEndian>(size).map_err(|e| BaseError::io(&self.commit_log_path(), e))?;
+
+        c.write_to_writer(&mut self.commit_log)
+            .map_err(|e| BaseError::Problem{reason: format!("unable to write {}: {}", self.commit_log_path(), e)})?;
+
+        let now = self.clock.now();
+        let sync = match self.fsync_policy {
+            FsyncPolicy::Always         => true,
+            FsyncPolicy::EveryNMs       => now - self.last_fsync >= self.fsync_interval_ms * 1_000_000,
+            FsyncPolicy::OnFlushOnly    => false
+        };
+
+        if sync {
+            self.sync_commit_log()
+        } else {
+            self.unsynced_writes = true;
+            Ok(())
+        }
+    }
+
+    fn sync_commit_log(&mut self) -> Result<(), BaseError> {
+        let span_start = self.span_start();
+        self.commit_log.sync().map_err(|e| BaseError::io(&self.commit_log_path(), e))?;
+        self.last_fsync = self.clock.now();
+        self.unsynced_writes = false;
+        self.record_span("commit_log.fsync", span_start, vec![]);
+        Ok(())
+    }
+
+    // Under the EveryNMs policy, a write is only synced by a later write
+    // once the interval has passed, so this is called on a timer to sync
+    // the last writes even if no more writes arrive.
+    pub fn sync_unsynced_writes(&mut self) -> Result<(), BaseError> {
+        if self.unsynced_writes && self.fsync_policy == FsyncPolicy::EveryNMs {
+            self.sync_commit_log()?;
+        }
+        Ok(())
+    }
+
+    // Flush a file that is part of a dtable to disk, and remember when
+    // the last fsync happened so that the EveryNMs policy can count it.
+    fn sync_file(&mut self, f: &storage::StorageFile, path: &str) -> Result<(), BaseError> {
+        f.sync().map_err(|e| BaseError::io(path, e))?;
+        self.last_fsync = self.clock.now();
+        Ok(())
+    }
+
+    pub fn insert(&mut self, row: &str, updates: Vec<query::MUpdate>, timestamp: u64) -> query::QueryResult {
+        let updates = match self.transforms.apply(row, updates) {
+            Ok(u)       => u,
+            Err(reason) => return query::QueryResult::SchemaViolation{reason: reason}
+        };
+
+        let span_start = self.span_start();
+        let inserted = self.memtable.insert(row, &updates, timestamp);
+        self.record_span("memtable.insert", span_start, vec![]);
+        match inserted {
+            Ok(_)   => {
+                self.row_cache.borrow_mut().write(row, &updates, timestamp);
+                self.result_cache.invalidate(row);
+            },
+            Err(dtable::TError::AlreadyExists)  => return query::QueryResult::RowAlreadyExists,
+            Err(e) => return query::QueryResult::InternalError{ error: format!("{}", e) }
+        };
+
+        match self.commit(row, &updates, timestamp) {
+            Ok(_)   => (),
+            Err(e)  => return self.partial_commit(e)
+        };
+
+        // Because we just completed a write, we should check if we have
+        // exceeded memory limits.
+        self.check_size_limits();
+
+        query::QueryResult::Done
+    }
+
+    #[cfg(test)]
+    pub fn str_query(&mut self, input: &str) -> String {
+        format!("{}", self.query_now(query::Query::parse(input).unwrap()))
+    }
+
+    // This private method does an update without creating a commit log entry.
+    fn direct_update(&mut self, row: &str, updates: &[query::MUpdate], timestamp: u64) -> query::QueryResult {
+        let span_start = self.span_start();
+        let updated = self.memtable.update(row, updates, timestamp);
+        self.record_span("memtable.update", span_start, vec![]);
+        match updated {
+            Ok(_) => {
+                self.row_cache.borrow_mut().write(row, updates, timestamp);
+                self.result_cache.invalidate(row);
+                query::QueryResult::Done
+            },
+            Err(dtable::TError::NotFound) => query::QueryResult::RowNotFound,
+            Err(e) => query::QueryResult::InternalError{ error: format!("{}", e) }
+        }
+    }
+
+    // Leave out the updates to columns with dedup set which wouldn't
+    // change their latest value. If the column has a touch_column, the
+    // write's timestamp is written there instead.
+    fn deduplicate(&self, row: &str, updates: Vec<query::MUpdate>, timestamp: u64) -> Vec<query::MUpdate> {
+        let columns = updates.iter()
+            .filter(|u| self.schemas.column(row, &u.key).map(|c| c.dedup).unwrap_or(false))
+            .map(|u| u.key.to_owned())
+            .collect::<Vec<_>>();
+        if columns.is_empty() {
+            return updates;
+        }
+
+        let latest = match self.select(row, &columns.iter().map(|c| c.as_str()).collect::<Vec<_>>(), timestamp) {
+            query::QueryResult::Data{columns: c} => c,
+            _ => return updates
+        };
+        let unchanged = columns.into_iter()
+            .zip(latest.into_iter())
+            .filter(|&(ref column, ref value)| updates.iter().any(|u| &u.key == column && Some(&u.value) == value.as_ref()))
+            .map(|(column, _)| column)
+            .collect::<Vec<_>>();
+
+        let mut deduplicated = vec![];
+        for u in updates {
+            if !unchanged.contains(&u.key) {
+                deduplicated.push(u);
+            } else if let Some(touch) = self.schemas.column(row, &u.key).and_then(|c| c.touch_column.as_ref()) {
+                deduplicated.push(query::MUpdate::new(touch, format!("{}", timestamp).into_bytes()));
+            }
+        }
+        deduplicated
+    }
+
+    // This function does a commit-then-update, using the private direct_update method.
+    pub fn update(&mut self, row: &str, updates: Vec<query::MUpdate>, timestamp: u64) -> query::QueryResult {
+        self.update_columns(row, updates, timestamp, true)
+    }
+
+    // Appends are updates which are read back as a list, so a value which
+    // repeats the one before it is still a new item, and is never left
+    // out by dedup.
+    pub fn append(&mut self, row: &str, updates: Vec<query::MUpdate>, timestamp: u64) -> query::QueryResult {
+        self.update_columns(row, updates, timestamp, false)
+    }
+
+    fn update_columns(&mut self, row: &str, updates: Vec<query::MUpdate>, timestamp: u64, dedup: bool) -> query::QueryResult {
+        let updates = match self.transforms.apply(row, updates) {
+            Ok(u)       => u,
+            Err(reason) => return query::QueryResult::SchemaViolation{reason: reason}
+        };
+
+        // If dedup leaves out every update, there's nothing to write.
+        let updates = match dedup && !updates.is_empty() {
+            true    => {
+                let updates = self.deduplicate(row, updates, timestamp);
+                if updates.is_empty() {
+                    return query::QueryResult::Done;
+                }
+                updates
+            },
+            false   => updates
+        };
+
+        match self.direct_update(row, &updates, timestamp) {
+            query::QueryResult::Done => (),
+            x   => return x
+        };
+
+        match self.commit(row, &updates, timestamp) {
+            Ok(_)   => (),
+            Err(e)  => return self.partial_commit(e)
+        };
+
+        // Because we just completed a write, we should check if we have
+        // exceeded memory limits.
+        self.check_size_limits();
+
+        query::QueryResult::Done
+    }
+
+    // Update several rows as one write. The transforms for every row run
+    // before anything is written, so that a refused value stops the whole
+    // transaction. Unlike single row writes, the commit log entry goes
+    // first, so that a failure to write it leaves the memtable untouched.
+    // All of the rows are then updated within this call, while the lock on
+    // the database is held, so reads never see part of a transaction.
+    pub fn transaction(&mut self, updates: HashMap<String, HashMap<String, Vec<u8>>>, timestamp: u64) -> query::QueryResult {
+        let mut rows = updates.into_iter().collect::<Vec<_>>();
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut staged = vec![];
+        for (row, set) in rows {
+            let set = set.into_iter()
+                .map(|(key, value)| query::MUpdate::new(key.as_str(), value))
+                .collect::<Vec<_>>();
+            match self.transforms.apply(&row, set) {
+                Ok(u)       => staged.push((row, u)),
+                Err(reason) => return query::QueryResult::SchemaViolation{reason: reason}
+            };
+        }
+
+        // Rows where every update was left out by dedup aren't written.
+        let staged = staged.into_iter()
+            .filter_map(|(row, updates)| match updates.is_empty() {
+                true    => Some((row, updates)),
+                false   => {
+                    let updates = self.deduplicate(&row, updates, timestamp);
+                    match updates.is_empty() {
+                        true    => None,
+                        false   => Some((row, updates))
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        if staged.is_empty() {
+            return query::QueryResult::Done;
+        }
+
+        if let Err(e) = self.commit_transaction(&staged, timestamp) {
+            error!("Unable to write a transaction to the commit log: {}{}", e, self.trace());
+            return query::QueryResult::InternalError{ error: format!("{}", e) };
+        }
+
+        for &(ref row, ref updates) in staged.iter() {
+            match self.direct_update(row, updates, timestamp) {
+                query::QueryResult::Done => (),
+                x => return x
+            };
+        }
+
+        self.check_size_limits();
+        query::QueryResult::Done
+    }
+
+    pub fn select(&self, row: &str, cols: &[&str], timestamp: u64) -> query::QueryResult {
+        self.select_tables(row, cols, timestamp, false)
+    }
+
+    // If strict is set, a dtable which can't be read fails the select,
+    // rather than answering it from the rest of the tables.
+    fn select_tables(&self, row: &str, cols: &[&str], timestamp: u64, strict: bool) -> query::QueryResult {
+        let cached = self.row_cache.borrow().is_enabled();
+        let results = match cached {
+            true    => self.select_cached(row, cols, timestamp, strict),
+            false   => self.select_sources(row, cols, timestamp, strict)
+        };
+        let results = match results {
+            Ok(r)   => r,
+            Err(e)  => return query::QueryResult::InternalError{error: e}
+        };
+
+        // Any data written at or before a range deletion is hidden.
+        let deleted_at = self.deleted_at(row, timestamp);
+
+        let columns = match results.len() {
+            0 => return query::QueryResult::RowNotFound,
+            _ => cols.iter()
+                .enumerate()
+                .map(|(i, _)| {
+                    let mut newest_timestamp = 0;
+                    let mut newest_index = 0;
+                    for (j, row) in results.iter().enumerate() {
+                        match row[i] {
+                            Some(ref r) if r.get_timestamp() <= timestamp && r.get_timestamp() > newest_timestamp => {
+                                newest_timestamp = r.get_timestamp();
+                                newest_index = j;
+                            },
+                            Some(_) | None => continue
+                        }
+                    }
+                    match newest_timestamp {
+                        0 => None,
+                        t if t <= deleted_at => None,
+                        _ => Some(match results[newest_index][i] {
+                            Some(ref r) => r.get_value().to_vec(),
+                            None        => panic!("This should never occur.")
+                        })
+                    }
+                }).collect::<Vec<_>>()
+        };
+
+        // If the row was deleted, and nothing has been written since, then
+        // the row doesn't exist anymore.
+        if deleted_at > 0 && columns.iter().all(|c| c.is_none()) {
+            return query::QueryResult::RowNotFound;
+        }
+
+        query::QueryResult::Data{columns: columns}
+    }
+
+    // The versions of the columns in each of the memtable and dtables
+    // which have the row. Unless strict is set, a dtable which can't be
+    // read is skipped.
+    fn select_sources(&self, row: &str, cols: &[&str], timestamp: u64, strict: bool) -> Result<Vec<Vec<Option<DEntry>>>, String> {
+        // First, try to query the mtable.
+        let mresult = iter::once(&self.memtable)
+            .map(|m| {
+                let span_start = self.span_start();
+                let result = m.select(row, cols, timestamp);
+                self.record_span("memtable.select", span_start, vec![]);
+                result
+            });
+
+        // Now, merge the results with those in the dtables.
+        // DTables whose key range can't contain the row are skipped
+        // without searching them.
+        let mut dresults = vec![];
+        for d in self.disktables.iter().filter(|d| d.may_contain(row)) {
+            let span_start = self.span_start();
+            let result = d.try_select(row, cols, timestamp);
+            self.record_span("dtable.read", span_start, vec![
+                (String::from("dtable"), d.filename().to_owned())
+            ]);
+            match result {
+                Ok(r)   => dresults.push(r),
+                Err(e)  => {
+                    error!("Unable to read row {} from {}: {}{}", row, d.filename(), e, self.trace());
+                    if strict {
+                        return Err(format!("unable to read row {} from {}: {}", row, d.filename(), e));
+                    }
+                }
+            }
+        }
+
+        // Eliminate any misses, and collect up rows to merge.
+        Ok(mresult
+            .chain(dresults.into_iter())
+            .filter(|x| x.is_some())
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>())
+    }
+
+    // Like select_sources, but through the row cache. On a miss, the whole
+    // row is read and merged into one DRow, so that later reads of any of
+    // its columns can be answered from the cache. Columns in families
+    // which aren't cached are left out of it, and are always read from
+    // the tables.
+    fn select_cached(&self, row: &str, cols: &[&str], timestamp: u64, strict: bool) -> Result<Vec<Vec<Option<DEntry>>>, String> {
+        if !cols.iter().all(|c| self.families.is_cached(c)) {
+            return self.select_sources(row, cols, timestamp, strict);
+        }
+
+        let mut cache = self.row_cache.borrow_mut();
+        if let Some(r) = cache.get(row) {
+            return Ok(vec![r.select(cols, timestamp)]);
+        }
+
+        let (merged, complete) = self.read_row(row);
+        if strict && !complete {
+            // Go through the tables again, to find the one which failed.
+            drop(cache);
+            return self.select_sources(row, cols, timestamp, strict);
+        }
+        let merged = match merged {
+            Some(r) => r,
+            None    => return Ok(vec![])
+        };
+
+        let result = merged.select(cols, timestamp);
+        if complete {
+            match self.families.is_enabled() {
+                true  => cache.insert(row, self.families.cached_columns(&merged)),
+                false => cache.insert(row, merged)
+            };
+        }
+        Ok(vec![result])
+    }
+
+    // Read the whole row from the memtable and each dtable which has it,
+    // merged into one DRow. The flag is false if one of the dtables
+    // couldn't be read, so the row might be missing something.
+    fn read_row(&self, row: &str) -> (Option<DRow>, bool) {
+        // Once merged, the last of several entries with the same timestamp
+        // wins, so the tables are in the opposite order to select_sources
+        // in order to pick the same one.
+        let mut rows = vec![];
+        let mut complete = true;
+        for d in self.disktables.iter().rev().filter(|d| d.may_contain(row)) {
+            match d.get_row(row) {
+                Ok(r)                       => rows.push(r),
+                Err(dtable::TError::NotFound) => (),
+                Err(e)                      => {
+                    error!("Unable to read row {} from {}: {}{}", row, d.filename(), e, self.trace());
+                    complete = false;
+                }
+            }
+        }
+        if let Some(r) = self.memtable.get_row(row) {
+            rows.push(r.to_drow());
+        }
+
+        match rows.len() {
+            0 => (None, complete),
+            _ => (Some(DRow::from_vec(&rows)), complete)
+        }
+    }
+
+    // Explain a read of a row or range: which dtables it would search,
+    // once their key ranges have ruled some out, and how many bytes it
+    // would read from them, worked out from their headers without reading
+    // any rows. Caches aren't taken into account.
+    fn explain(&self, q: query::Query) -> query::QueryResult {
+        let (row, cols) = match q {
+            query::Query::Select{row: r, get: g, project: p, column_start: cs, column_limit: cl, ..} => {
+                // Pages of columns read the whole row.
+                let cols = match cl > 0 || !cs.is_empty() {
+                    true  => None,
+                    false => Some(g.into_iter()
+                        .chain(p.iter().map(|p| p.column().to_owned()))
+                        .chain(iter::once(dtable::SOFT_DELETE_COLUMN.to_owned()))
+                        .collect::<Vec<_>>())
+                };
+                (r, cols)
+            },
+            query::Query::Execute{handle: h, row: r} => match self.prepared.get(h) {
+                Some(g) => (r, Some(g.iter().cloned().chain(iter::once(dtable::SOFT_DELETE_COLUMN.to_owned())).collect())),
+                None    => return query::QueryResult::NotPrepared
+            },
+            query::Query::SelectList{row: r, column: c, ..} |
+            query::Query::History{row: r, column: c, ..} => (r, Some(vec![c])),
+            query::Query::Describe{row: r} |
+            query::Query::Checksum{row: r} |
+            query::Query::Diff{row: r, ..} => (r, None),
+            query::Query::Scan{start: s, end: e, ..} |
+            query::Query::ChecksumRange{start: s, end: e} => {
+                let range = scan::KeyRange::new(&s, &e);
+                return match self.salting.bucket_ranges(&range) {
+                    Some(ranges) => self.explain_ranges(&ranges),
+                    None         => self.explain_ranges(&[range])
+                };
+            },
+            _ => return query::QueryResult::InternalError{
+                error: String::from("only reads of rows and ranges can be explained")
+            }
+        };
+
+        let cols = cols.as_ref().map(|c| c.iter().map(|s| s.as_str()).collect::<Vec<_>>());
+        let mut explanation = query::Explanation::default();
+        explanation.memtable = self.memtable.get_row(&row).is_some();
+        for d in self.disktables.iter() {
+            if !d.may_contain(&row) {
+                explanation.skipped_dtables += 1;
+                continue;
+            }
+            let bytes = match d.estimate_read(&row, cols.as_ref().map(|c| c.as_slice())) {
+                Ok(b)   => b,
+                Err(e)  => return query::QueryResult::InternalError{
+                    error: format!("unable to search {}: {}", d.filename(), e)
+                }
+            };
+            explanation.bytes += bytes.unwrap_or(0);
+            explanation.dtables.push(query::DTableRead{
+                filename: d.filename().to_owned(),
+                rows: bytes.is_some() as u64,
+                bytes: bytes.unwrap_or(0)
+            });
+        }
+        query::QueryResult::Explanation{explanation: explanation}
+    }
+
+    // Explain a read of every row in the ranges. The bytes are for the
+    // whole of the ranges, so a scan with a limit may read much less.
+    fn explain_ranges(&self, ranges: &[scan::KeyRange]) -> query::QueryResult {
+        let mut explanation = query::Explanation::default();
+        explanation.memtable = ranges.iter().any(|r| {
+            self.memtable.keys_from(&r.start, 1).first()
+                .map(|k| r.end.is_empty() || *k < r.end)
+                .unwrap_or(false)
+        });
+        for d in self.disktables.iter() {
+            let overlapping = ranges.iter().filter(|r| d.may_overlap(&r.start, &r.end)).collect::<Vec<_>>();
+            if overlapping.is_empty() {
+                explanation.skipped_dtables += 1;
+                continue;
+            }
+
+            let mut read = query::DTableRead{filename: d.filename().to_owned(), rows: 0, bytes: 0};
+            for r in overlapping {
+                match d.estimate_range(&r.start, &r.end) {
+                    Ok((rows, bytes)) => {
+                        read.rows += rows;
+                        read.bytes += bytes;
+                    },
+                    Err(e) => return query::QueryResult::InternalError{
+                        error: format!("unable to search {}: {}", d.filename(), e)
+                    }
+                }
+            }
+            explanation.bytes += read.bytes;
+            explanation.dtables.push(read);
+        }
+        query::QueryResult::Explanation{explanation: explanation}
+    }
+
+    // Read up to limit rows in the range, with the columns in get (or
+    // every column, if get is empty). If the range has more rows, next is
+    // the key of the first one which wasn't returned, so that the scan
+    // can be continued from there.
+    pub fn scan(&self, range: scan::KeyRange, get: &[String], limit: usize, timestamp: u64) -> query::QueryResult {
+        let limit = match limit {
+            0 => MAX_SCAN_ROWS,
+            l => std::cmp::min(l, MAX_SCAN_ROWS)
+        };
+
+        let mut rows = vec![];
+        let mut next = String::new();
+        for row in self.iter_rows(range, timestamp) {
+            // Soft deleted rows are skipped, and the marker column is only
+            // returned if it's asked for.
+            if row.iter_columns().any(|(k, v)| k == dtable::SOFT_DELETE_COLUMN && dtable::soft_deleted_at(v).is_some()) {
+                continue;
+            }
+            if rows.len() == limit {
+                next = row.key().to_owned();
+                break;
+            }
+
+            rows.push(query::ScanRow{
+                key: row.key().to_owned(),
+                columns: row.iter_columns()
+                    .filter(|&(k, _)| match get.is_empty() {
+                        true  => k != dtable::SOFT_DELETE_COLUMN,
+                        false => get.iter().any(|g| g == k)
+                    })
+                    .map(|(k, v)| (k.to_owned(), v.to_vec()))
+                    .collect()
+            });
+        }
+
+        query::QueryResult::Rows{rows: rows, next: next}
+    }
+
+    // A checksum of the row's latest values, as seen at the timestamp.
+    // Soft deleted rows aren't found, the same as for a select.
+    pub fn checksum(&self, row: &str, timestamp: u64) -> query::QueryResult {
+        // No other key sorts between the row and the row followed by a
+        // zero byte, so the range only covers the row.
+        let range = scan::KeyRange::new(row, &format!("{}\0", row));
+        match self.iter_rows(range, timestamp).find(|r| !r.is_soft_deleted()) {
+            Some(r) => query::QueryResult::Checksum{checksum: r.checksum(), rows: 1},
+            None    => query::QueryResult::RowNotFound
+        }
+    }
+
+    // Combine the checksums of the visible rows in the range, in key
+    // order along with their keys, so that two copies of the range only
+    // match if they have the same rows with the same latest values.
+    pub fn checksum_range(&self, range: scan::KeyRange, timestamp: u64) -> query::QueryResult {
+        let mut checksum = scan::FNV_OFFSET;
+        let mut rows = 0;
+        for row in self.iter_rows(range, timestamp).filter(|r| !r.is_soft_deleted()) {
+            checksum = Base::checksum_row(checksum, row.key(), row.checksum());
+            rows += 1;
+        }
+        query::QueryResult::Checksum{checksum: checksum, rows: rows}
+    }
+
+    // Checksum a range of a salted table, split up into its buckets. The
+    // rows are checksummed in the order of their unsalted keys, so the
+    // result matches a copy of the table which isn't salted.
+    fn checksum_buckets(&self, ranges: Vec<scan::KeyRange>, timestamp: u64) -> query::QueryResult {
+        let mut rows = vec![];
+        for range in ranges {
+            rows.extend(
+                self.iter_rows(range, timestamp)
+                    .filter(|r| !r.is_soft_deleted())
+                    .map(|r| (self.salting.unsalt(r.key()), r.checksum()))
+            );
+        }
+        rows.sort();
+
+        let checksum = rows.iter()
+            .fold(scan::FNV_OFFSET, |c, &(ref key, row)| Base::checksum_row(c, key, row));
+        query::QueryResult::Checksum{checksum: checksum, rows: rows.len() as u64}
+    }
+
+    fn checksum_row(checksum: u64, key: &str, row: u64) -> u64 {
+        let mut row_checksum = [0u8; 8];
+        LittleEndian::write_u64(&mut row_checksum, row);
+        scan::fnv1a_field(scan::fnv1a_field(checksum, key.as_bytes()), &row_checksum)
+    }
+
+    // Read every element of a list column appended within [start, end],
+    // oldest first. If the limit is non-zero, only the most recent elements
+    // are returned. An end of zero means that there's no upper bound.
+    pub fn select_list(&self, row: &str, column: &str, limit: usize, start: u64, end: u64, timestamp: u64) -> query::QueryResult {
+        let end = match end {
+            0 => timestamp,
+            e => std::cmp::min(e, timestamp)
+        };
+
+        let mut found = false;
+        let mut columns = vec![];
+        if let Some(r) = self.memtable.get_row(row) {
+            found = true;
+            if let Some(c) = r.get_column(column) {
+                columns.push(c.clone());
+            }
+        }
+
+        for d in self.disktables.iter().filter(|d| d.may_contain(row)) {
+            if let Ok(r) = d.get_row(row) {
+                found = true;
+                if let Ok(c) = r.get_column(column) {
+                    columns.push(c.clone());
+                }
+            }
+        }
+
+        if !found {
+            return query::QueryResult::RowNotFound;
+        }
+
+        // Anything appended at or before a range deletion is hidden. The
+        // same entry can show up in more than one table while compactions
+        // are in flight, so duplicates are dropped.
+        let deleted_at = self.deleted_at(row, timestamp);
+        let merged = DColumn::from_vec(&columns.iter().collect::<Vec<_>>());
+        let mut entries = merged.get_entries()
+            .iter()
+            .filter(|e| e.get_timestamp() >= start && e.get_timestamp() <= end && e.get_timestamp() > deleted_at)
+            .map(|e| query::ListEntry{
+                timestamp: e.get_timestamp(),
+                value: e.get_value().to_vec()
+            })
+            .collect::<Vec<_>>();
+        entries.dedup_by_key(|e| e.timestamp);
+
+        if limit > 0 && entries.len() > limit {
+            let skip = entries.len() - limit;
+            entries.drain(..skip);
+        }
+
+        query::QueryResult::List{entries: entries}
+    }
+
+    // Every version of a column is kept until it's compacted away, so its
+    // history is read back the same way as a list. Soft deleted rows still
+    // have a history, since that's what an audit needs to see.
+    pub fn history(&self, row: &str, column: &str, limit: usize, timestamp: u64) -> query::QueryResult {
+        self.select_list(row, column, limit, 0, 0, timestamp)
+    }
+
+    // Summarize each of the row's columns as they were at the timestamp:
+    // how many versions there are, the first and last times they were
+    // written, and the total size of the values, without the values
+    // themselves.
+    pub fn describe(&self, row: &str, timestamp: u64) -> query::QueryResult {
+        let merged = match self.read_row(row).0 {
+            Some(r) => r,
+            None    => return query::QueryResult::RowNotFound
+        };
+
+        // The same entry can show up in more than one table while
+        // compactions are in flight, so duplicates are dropped.
+        let deleted_at = self.deleted_at(row, timestamp);
+        let columns = merged.get_keys().iter()
+            .zip(merged.get_columns().iter())
+            .filter_map(|(name, col)| {
+                let mut visible = col.get_entries()
+                    .iter()
+                    .filter(|e| e.get_timestamp() <= timestamp && e.get_timestamp() > deleted_at)
+                    .collect::<Vec<_>>();
+                visible.dedup_by_key(|e| e.get_timestamp());
+
+                let (first, last) = match (visible.first(), visible.last()) {
+                    (Some(f), Some(l))  => (f.get_timestamp(), l.get_timestamp()),
+                    _                   => return None
+                };
+                Some(query::ColumnDescription{
+                    name: name.to_owned(),
+                    entries: visible.len() as u64,
+                    first_timestamp: first,
+                    last_timestamp: last,
+                    bytes: visible.iter().map(|e| e.get_value().len() as u64).sum()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Like a select, a deleted row which hasn't been written to since
+        // doesn't exist anymore.
+        if deleted_at > 0 && columns.is_empty() {
+            return query::QueryResult::RowNotFound;
+        }
+
+        query::QueryResult::Description{columns: columns}
+    }
+
+    // Compare the latest value of each of the row's columns at from_ts
+    // with the latest value at to_ts (or the timestamp, if to_ts is zero),
+    // and return the columns which are different. Neither can be later
+    // than the timestamp. Like history, the soft delete marker isn't
+    // treated specially, so a diff shows when a row was soft deleted.
+    pub fn diff(&self, row: &str, from_ts: u64, to_ts: u64, timestamp: u64) -> query::QueryResult {
+        let from_ts = std::cmp::min(from_ts, timestamp);
+        let to_ts = match to_ts {
+            0 => timestamp,
+            t => std::cmp::min(t, timestamp)
+        };
+        let merged = match self.read_row(row).0 {
+            Some(r) => r,
+            None    => return query::QueryResult::RowNotFound
+        };
+
+        // Values written at or before a range deletion are hidden.
+        let value_at = |column: &DColumn, at: u64| {
+            let deleted_at = self.deleted_at(row, at);
+            column.get_entries().iter().rev()
+                .find(|e| e.get_timestamp() <= at)
+                .and_then(|e| match e.get_timestamp() > deleted_at {
+                    true  => Some(e.get_value().to_vec()),
+                    false => None
+                })
+        };
+        let changes = merged.get_keys().iter()
+            .zip(merged.get_columns().iter())
+            .filter_map(|(name, column)| {
+                let before = value_at(column, from_ts);
+                let after = value_at(column, to_ts);
+                match before == after {
+                    true  => None,
+                    false => Some(query::ColumnChange{
+                        column: name.to_owned(),
+                        before: before,
+                        after: after
+                    })
+                }
+            })
+            .collect::<Vec<_>>();
+
+        query::QueryResult::Changes{changes: changes}
+    }
+
+    // This function checks if the memtable size limit has been exceeded
+    // by the most recent write, and if so, we'll dump the memtable to disk.
+    // If that or anything else here flushes or merges, the time it takes
+    // is counted as a write stall.
+    pub fn check_size_limits(&mut self) {
+        let started = time::precise_time_ns();
+        let compactions = self.minor_compactions + self.major_compactions;
+        self.run_size_limits();
+        if self.minor_compactions + self.major_compactions > compactions {
+            self.write_stalls += 1;
+            self.write_stall_ns += time::precise_time_ns() - started;
+        }
+    }
+
+    fn run_size_limits(&mut self) {
+        info!("mentable: {} KiB", self.memtable.size/1024);
+
+        // The writes are already acknowledged, so if the flush fails they
+        // stay in the memtable and the commit log, and it's retried later.
+        if self.memtable.size > self.memtable_size_limit {
+            if let Err(e) = self.empty_memtable() {
+                error!("Unable to write the memtable to disk: {}{}", e, self.trace());
+            }
+        }
+
+        let now = self.clock.now();
+        if self.checkpoint_interval_ms > 0 && now - self.last_checkpoint >= self.checkpoint_interval_ms * 1_000_000 {
+            if let Err(e) = self.checkpoint() {
+                error!("Unable to checkpoint the memtable: {}{}", e, self.trace());
+            }
+            self.last_checkpoint = now;
+        }
+
+        if self.compaction_frozen {
+            return;
+        }
+
+        // Writes are refused while the disk is nearly full, so merging the
+        // dtables to drop overwritten and deleted data comes first.
+        if self.out_of_space() && self.is_reclaimable() {
+            warn!("Low on disk space, merging disktables to reclaim space.{}", self.trace());
+            if let Err(e) = self.merge_disktables() {
+                error!("Unable to merge disktables: {}{}", e, self.trace());
+            }
+            self.free_space_checked.set(None);
+        }
+
+        if let Err(e) = self.collect_garbage() {
+            error!("Unable to collect garbage: {}{}", e, self.trace());
+        }
+
+        if let Err(e) = self.archive_disktables() {
+            error!("Unable to archive dtables: {}{}", e, self.trace());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use query;
+    use glob::glob;
+    use std::io;
+    use std::fs;
+    use std::io::BufRead;
+    use std::mem;
+    use mtable;
+    use scan;
+    use rand::random;
+    use std::u64;
+    use test;
+    use time;
+    use storage;
+    use storage::{Clock, Storage};
+    use spans;
+    use acl;
+    use idempotency;
+    use rowcache;
+    use tempdir;
+    use serde_json;
+    use std::sync::Arc;
+    use std::collections::HashMap as Map;
+    use std::iter::FromIterator;
+
+    #[test]
+    fn can_merge_disktables() {
+        let mut database = super::Base::new_stub();
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "dtable_one","set": {"status": "alright"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "dtable_z","set": {"status": "working"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+        database.empty_memtable().unwrap();
+
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "dtable_two","set": {"status": "ok"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+        database.empty_memtable().unwrap();
+
+        database.merge_disktables().unwrap();
+
+        assert_eq!(
+            format!("{:?}", database.disktables[0].lookup.get_entries()
+                .iter()
+                .map(|e| e.get_key())
+                .collect::<Vec<_>>()
+            ),
+            r#"["dtable_one", "dtable_two", "dtable_z"]"#
+        );
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "dtable_two","get":["status"]}}"#),
+            r#"Data: ["ok"]"#
+        );
+    }
+
+    #[test]
+    fn splits_merged_dtables_by_key_range() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+
+        for row in &["a", "c", "e"] {
+            database.insert(row, vec![query::MUpdate::new("status", vec![b'x'; 100])], clock.now());
+        }
+        database.empty_memtable().unwrap();
+        for row in &["b", "d"] {
+            database.insert(row, vec![query::MUpdate::new("status", vec![b'x'; 100])], clock.now());
+        }
+        database.empty_memtable().unwrap();
+
+        database.max_dtable_bytes = 200;
+        database.merge_disktables().unwrap();
+
+        let ranges = database.disktables.iter()
+            .map(|d| (d.lookup.get_min_key().to_owned(), d.lookup.get_max_key().to_owned()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![
+            (String::from("a"), String::from("b")),
+            (String::from("c"), String::from("d")),
+            (String::from("e"), String::from("e"))
+        ]);
+        assert_eq!(database.compaction_history().last().unwrap().rows, 5);
+
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.disktables.len(), 3);
+        for row in &["a", "b", "c", "d", "e"] {
+            assert_eq!(format!("{}", reloaded.select(row, &["status"], clock.now())), format!("Data: [\"{}\"]", "x".repeat(100)));
+        }
+    }
+
+    #[test]
+    fn fails_strict_selects_on_read_errors() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+
+        database.insert("a", vec![query::MUpdate::new("status", b"old".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+        database.update("a", vec![query::MUpdate::new("status", b"new".to_vec())], clock.now() + 1);
+        database.empty_memtable().unwrap();
+
+        // The first dtable can't be read, even after retrying, so the
+        // select is answered from the other one.
+        storage.fail_opens(dtable::READ_RETRIES as usize + 1);
+        let result = database.select_tables("a", &["status"], clock.now() + 2, false);
+        assert_eq!(format!("{}", result), r#"Data: ["new"]"#);
+
+        storage.fail_opens(dtable::READ_RETRIES as usize + 1);
+        match database.select_tables("a", &["status"], clock.now() + 2, true) {
+            query::QueryResult::InternalError{error: e} => assert!(e.contains("unable to read row a")),
+            x => panic!("expected an internal error, got {}", x)
+        }
+
+        assert_eq!(format!("{}", database.select_tables("a", &["status"], clock.now() + 2, true)), r#"Data: ["new"]"#);
+    }
+
+    #[test]
+    fn pages_dtable_headers() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.lazy_headers = true;
+        database.header_cache_blocks = 2;
+        database.load().unwrap();
+
+        for i in 0..300 {
+            database.insert(&format!("row{:03}", i), vec![query::MUpdate::new("n", format!("{}", i).into_bytes())], clock.now());
+        }
+        database.empty_memtable().unwrap();
+        database.insert("row150a", vec![query::MUpdate::new("n", b"new".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+        assert!(database.disktables.iter().all(|d| d.is_paged() && d.lookup.get_entries().is_empty()));
+
+        let check = |database: &super::Base| {
+            for &i in &[0, 63, 64, 150, 299] {
+                assert_eq!(format!("{}", database.select(&format!("row{:03}", i), &["n"], clock.now())), format!("Data: [\"{}\"]", i));
+            }
+            assert_eq!(format!("{}", database.select("row150a", &["n"], clock.now())), "Data: [\"new\"]");
+            assert_eq!(format!("{}", database.select("row300", &["n"], clock.now())), "Row not found.");
+            assert_eq!(
+                format!("{}", database.list_keys("row149", 3)),
+                r#"Keys: ["row149", "row150", "row150a"]"#
+            );
+            let rows = database.iter_rows(scan::KeyRange::new("row298", ""), clock.now())
+                .map(|r| r.key().to_owned())
+                .collect::<Vec<_>>();
+            assert_eq!(rows, vec![String::from("row298"), String::from("row299")]);
+        };
+        check(&database);
+
+        database.merge_disktables().unwrap();
+        assert!(database.disktables.iter().all(|d| d.is_paged()));
+        check(&database);
+
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.lazy_headers = true;
+        reloaded.load().unwrap();
+        assert!(reloaded.disktables.iter().all(|d| d.is_paged()));
+        check(&reloaded);
+    }
+
+    #[test]
+    fn restores_from_archived_commit_log() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        database.enable_commit_log_archive("/archive").unwrap();
+
+        // Nothing is archived when there's nothing to flush.
+        database.empty_memtable().unwrap();
+        let first = clock.now();
+        database.insert("a", vec![query::MUpdate::new("status", b"one".to_vec())], first);
+        database.empty_memtable().unwrap();
+        clock.advance(1_000);
+        database.insert("b", vec![query::MUpdate::new("status", b"two".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+        database.insert("c", vec![query::MUpdate::new("status", b"three".to_vec())], clock.now());
+        assert_eq!(database.commit_log_archive.as_ref().unwrap().segments().len(), 2);
+
+        // Writes which haven't been flushed yet aren't in the archive.
+        let mut restored = super::Base::with_storage("/restore", 1 << 20, 10, storage.clone(), clock.clone());
+        restored.load().unwrap();
+        assert_eq!(restored.restore_from_archive("/archive", 0).unwrap(), 2);
+        assert_eq!(format!("{}", restored.select("a", &["status"], clock.now())), "Data: [\"one\"]");
+        assert_eq!(format!("{}", restored.select("b", &["status"], clock.now())), "Data: [\"two\"]");
+        assert_eq!(format!("{}", restored.select("c", &["status"], clock.now())), "Row not found.");
+        assert_eq!(restored.restore_from_archive("/archive", 0).unwrap(), 0);
+
+        // Restoring to a point in time leaves out anything written later.
+        let mut pitr = super::Base::with_storage("/pitr", 1 << 20, 10, storage.clone(), clock.clone());
+        pitr.load().unwrap();
+        assert_eq!(pitr.restore_from_archive("/archive", first).unwrap(), 1);
+        assert_eq!(format!("{}", pitr.select("a", &["status"], clock.now())), "Data: [\"one\"]");
+        assert_eq!(format!("{}", pitr.select("b", &["status"], clock.now())), "Row not found.");
+    }
+
+    #[test]
+    fn bootstraps_replicas_from_snapshots() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut primary = super::Base::with_storage("/primary", 1 << 20, 10, storage.clone(), clock.clone());
+        primary.load().unwrap();
+        primary.insert("a", vec![query::MUpdate::new("status", b"flushed".to_vec())], clock.now());
+        primary.empty_memtable().unwrap();
+        primary.insert("b", vec![query::MUpdate::new("status", b"in memtable".to_vec())], clock.now());
+
+        let mut snapshot = vec![];
+        let position = primary.open_snapshot().unwrap().write_to(&mut snapshot).unwrap();
+        let mut replica = super::Base::with_storage("/replica", 1 << 20, 10, storage.clone(), clock.clone());
+        assert_eq!(replica.install_snapshot(&mut &snapshot[..]).unwrap(), position);
+        replica.load().unwrap();
+        assert_eq!(format!("{}", replica.select("a", &["status"], clock.now())), "Data: [\"flushed\"]");
+        assert_eq!(format!("{}", replica.select("b", &["status"], clock.now())), "Data: [\"in memtable\"]");
+        assert!(replica.install_snapshot(&mut &snapshot[..]).is_err());
+
+        // Then the replica tails the commit log.
+        primary.insert("c", vec![query::MUpdate::new("status", b"tailed".to_vec())], clock.now());
+        let (data, position) = primary.read_commit_log(&position, 1 << 20).unwrap().unwrap();
+        assert_eq!(replica.apply_replicated(&data).unwrap(), 1);
+        assert_eq!(format!("{}", replica.select("c", &["status"], clock.now())), "Data: [\"tailed\"]");
+
+        // Once the commit log is truncated, the rest of it can only be
+        // read from the archive.
+        primary.enable_commit_log_archive("/archive").unwrap();
+        primary.insert("d", vec![query::MUpdate::new("status", b"archived".to_vec())], clock.now());
+        primary.empty_memtable().unwrap();
+        let (data, next) = primary.read_commit_log(&position, 1 << 20).unwrap().unwrap();
+        assert_eq!(next, primary.replication_position().unwrap());
+        assert_eq!(replica.apply_replicated(&data).unwrap(), 1);
+        assert_eq!(format!("{}", replica.select("d", &["status"], clock.now())), "Data: [\"archived\"]");
+        assert_eq!(primary.read_commit_log(&super::replication::Position{generation: 0, offset: 0}, 1 << 20).unwrap(), None);
+    }
+
+    #[test]
+    fn hands_off_missed_entries_to_replicas() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut primary = super::Base::with_storage("/primary", 1 << 20, 10, storage.clone(), clock.clone());
+        primary.hint_directory = String::from("/hints");
+        primary.load().unwrap();
+
+        let mut snapshot = vec![];
+        let position = primary.open_snapshot().unwrap().write_to(&mut snapshot).unwrap();
+        let mut replica = super::Base::with_storage("/replica", 1 << 20, 10, storage.clone(), clock.clone());
+        replica.install_snapshot(&mut &snapshot[..]).unwrap();
+        replica.load().unwrap();
+
+        primary.insert("a", vec![query::MUpdate::new("status", b"tailed".to_vec())], clock.now());
+        let (data, position) = primary.replicate_to("r1", &position, 1 << 20).unwrap().unwrap();
+        assert_eq!(replica.apply_replicated(&data).unwrap(), 1);
+
+        // The replica goes away while the commit log is truncated, so the
+        // entries it missed are kept for it.
+        primary.insert("b", vec![query::MUpdate::new("status", b"hinted".to_vec())], clock.now());
+        primary.empty_memtable().unwrap();
+        primary.insert("c", vec![query::MUpdate::new("status", b"hinted too".to_vec())], clock.now());
+        primary.empty_memtable().unwrap();
+        assert_eq!(primary.read_commit_log(&position, 1 << 20).unwrap(), None);
+
+        let (data, next) = primary.replicate_to("r1", &position, 1 << 20).unwrap().unwrap();
+        assert_eq!(next.generation, primary.replication_position().unwrap().generation);
+        assert_eq!(next.offset, 0);
+
+        // If the response is lost, asking again sends the same hints.
+        let (again, _) = primary.replicate_to("r1", &position, 1 << 20).unwrap().unwrap();
+        assert_eq!(again, data);
+        assert_eq!(replica.apply_replicated(&data).unwrap(), 2);
+        assert_eq!(format!("{}", replica.select("b", &["status"], clock.now())), "Data: [\"hinted\"]");
+        assert_eq!(format!("{}", replica.select("c", &["status"], clock.now())), "Data: [\"hinted too\"]");
+
+        // From then on, it carries on with the commit log.
+        primary.insert("d", vec![query::MUpdate::new("status", b"tailed".to_vec())], clock.now());
+        let (data, _) = primary.replicate_to("r1", &next, 1 << 20).unwrap().unwrap();
+        assert!(replica.apply_replicated(&data).unwrap() >= 1);
+        assert_eq!(format!("{}", replica.select("d", &["status"], clock.now())), "Data: [\"tailed\"]");
+
+        // A replica which the primary hasn't heard from has no hints.
+        assert_eq!(primary.replicate_to("r2", &position, 1 << 20).unwrap(), None);
+    }
+
+    #[test]
+    fn applies_transactions_completely_or_not_at_all() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        database.transforms.add_table(&serde_json::from_str(r#"{
+            "prefix": "accounts/",
+            "transforms": [{"column": "balance", "transform": "max_bytes", "options": {"limit": "3"}}]
+        }"#).unwrap()).unwrap();
+        database.key_rules.reject_empty = true;
+
+        let mut run = |q: &str| {
+            let result = database.query(query::Query::parse(q).unwrap(), clock.now());
+            clock.advance(1);
+            format!("{}", result)
+        };
+        assert_eq!(
+            run(r#"{"transaction": {"updates": {"accounts/a": {"balance": "50"}, "accounts/b": {"balance": "150"}}}}"#),
+            "OK."
+        );
+
+        // If any row's update is refused, none of them are applied.
+        assert!(run(r#"{"transaction": {"updates": {"accounts/a": {"balance": "0"}, "accounts/b": {"balance": "1000"}}}}"#)
+            .starts_with("Schema violation"));
+        assert_eq!(run(r#"{"select": {"row": "accounts/a", "get": ["balance"]}}"#), r#"Data: ["50"]"#);
+        assert_eq!(
+            run(r#"{"transaction": {"updates": {"accounts/a": {"balance": "1"}, "": {"balance": "1"}}}}"#),
+            "Invalid row key."
+        );
+
+        assert_eq!(
+            run(r#"{"transaction": {"updates": {"accounts/a": {"balance": "100"}, "accounts/b": {"balance": "100"}}}}"#),
+            "OK."
+        );
+
+        // The transaction is a single commit log entry, which is replayed
+        // after a restart.
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        for row in &["accounts/a", "accounts/b"] {
+            assert_eq!(format!("{}", reloaded.select(row, &["balance"], clock.now())), r#"Data: ["100"]"#);
+        }
+    }
+
+    #[test]
+    fn caches_select_results() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        database.schemas.add_table(serde_json::from_str(r#"{"prefix": "dashboards/", "result_cache_ttl_ms": 1000}"#).unwrap());
+
+        let select = || query::Query::new_select("dashboards/1", &["status"]);
+        assert_eq!(format!("{}", database.query_now(select())), "Row not found.");
+        database.query_now(query::Query::new_insert("dashboards/1", vec![query::MUpdate::new("status", b"OK".to_vec())]));
+        clock.advance(100);
+        assert_eq!(format!("{}", database.query_now(select())), r#"Data: ["OK"]"#);
+        clock.advance(100);
+        assert_eq!(format!("{}", database.query_now(select())), r#"Data: ["OK"]"#);
+        assert_eq!((database.result_cache.hits, database.result_cache.misses), (1, 2));
+
+        // Rows outside of the table aren't cached.
+        database.query_now(query::Query::new_select("other", &["status"]));
+        assert_eq!(database.result_cache.len(), 1);
+
+        database.query_now(query::Query::new_update("dashboards/1", vec![query::MUpdate::new("status", b"down".to_vec())]));
+        clock.advance(100);
+        assert_eq!(format!("{}", database.query_now(select())), r#"Data: ["down"]"#);
+    }
+
+    #[test]
+    fn can_select_projections() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        database.str_query(r#"{"insert": {"row": "doc","set": {"title": "Notes", "body": "hello, world"}}}"#);
+
+        // Projected values come after the columns, in the order they're
+        // asked for, and missing columns have no projections.
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "doc","get": ["title"],"project": [
+                {"length": "body"},
+                {"substring": {"column": "body", "start": 7, "length": 100}},
+                {"hash": "body"},
+                {"length": "missing"}
+            ]}}"#),
+            r#"Data: ["Notes", "12", "world", "17a1a4f267be633d", None]"#
+        );
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "nobody","get": [],"project": [{"length": "body"}]}}"#),
+            "Row not found."
+        );
+    }
+
+    #[test]
+    fn soft_deletes_and_restores_rows() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.soft_delete_retention_ms = 1000;
+        database.load().unwrap();
+
+        let mut run = |q: &str| {
+            clock.advance(1000);
+            format!("{}", database.query_now(query::Query::parse(q).unwrap()))
+        };
+        assert_eq!(run(r#"{"soft_delete": {"row": "user"}}"#), "Row not found.");
+        run(r#"{"insert": {"row": "user", "set": {"name": "colin"}}}"#);
+        run(r#"{"insert": {"row": "other", "set": {"name": "bob"}}}"#);
+        assert_eq!(run(r#"{"soft_delete": {"row": "user"}}"#), "OK.");
+
+        assert_eq!(run(r#"{"select": {"row": "user", "get": ["name"]}}"#), "Row not found.");
+        assert_eq!(run(r#"{"select": {"row": "user", "get": ["name"], "include_deleted": true}}"#), r#"Data: ["colin"]"#);
+        assert_eq!(run(r#"{"scan": {}}"#), r#"Rows: [other: {name: "bob"}], next: """#);
+
+        assert_eq!(run(r#"{"undelete": {"row": "user"}}"#), "OK.");
+        assert_eq!(run(r#"{"select": {"row": "user", "get": ["name"]}}"#), r#"Data: ["colin"]"#);
+
+        // Once the retention window ends, the row can't be restored, and
+        // compaction drops it.
+        run(r#"{"soft_delete": {"row": "user"}}"#);
+        clock.advance(2_000_000_000);
+        assert_eq!(run(r#"{"undelete": {"row": "user"}}"#), "Row not found.");
+        assert_eq!(run(r#"{"select": {"row": "user", "get": ["name"], "include_deleted": true}}"#), "Row not found.");
+
+        database.empty_memtable().unwrap();
+        database.merge_disktables().unwrap();
+        assert_eq!(database.disktables[0].lookup.get_row_count(), 1);
+    }
+
+    #[test]
+    fn reports_hot_rows() {
+        let mut database = super::Base::new_stub();
+        for _ in 0..3 {
+            database.query_now(query::Query::new_update("busy", vec![query::MUpdate::new("count", b"1".to_vec())]));
+        }
+        database.query_now(query::Query::new_select("quiet", &["count"]));
+
+        // Neither row has been around for a second yet, so the rates are
+        // the same as the counts.
+        assert_eq!(
+            database.str_query(r#"{"top_keys": {"limit": 1}}"#),
+            "Hot keys: [busy: {reads_per_second: 0.0, writes_per_second: 3.0}]"
+        );
+    }
+
+    #[test]
+    fn can_describe_rows() {
+        let mut database = super::Base::new_stub();
+        database.insert("described", vec![
+            query::MUpdate::new("name", b"first".to_vec()),
+            query::MUpdate::new("status", b"ok".to_vec())
+        ], 100);
+        database.empty_memtable().unwrap();
+        database.update("described", vec![query::MUpdate::new("status", b"broken".to_vec())], 110);
+        database.update("described", vec![query::MUpdate::new("status", b"fixed".to_vec())], 120);
+
+        assert_eq!(
+            format!("{}", database.describe("described", 1000)),
+            "Description: [name: {entries: 1, first_timestamp: 100, last_timestamp: 100, bytes: 5}, \
+             status: {entries: 3, first_timestamp: 100, last_timestamp: 120, bytes: 13}]"
+        );
+        assert_eq!(
+            format!("{}", database.describe("described", 110)),
+            "Description: [name: {entries: 1, first_timestamp: 100, last_timestamp: 100, bytes: 5}, \
+             status: {entries: 2, first_timestamp: 100, last_timestamp: 110, bytes: 8}]"
+        );
+        assert_eq!(format!("{}", database.describe("missing", 1000)), "Row not found.");
+
+        database.delete_range("described", "", 130);
+        assert_eq!(format!("{}", database.describe("described", 1000)), "Row not found.");
+        assert_eq!(
+            database.str_query(r#"{"describe": {"row": "described"}}"#),
+            "Row not found."
+        );
+    }
+
+    #[test]
+    fn explains_reads_without_running_them() {
+        let mut database = super::Base::new_stub();
+        database.insert("a", vec![query::MUpdate::new("name", b"alice".to_vec())], 100);
+        database.empty_memtable().unwrap();
+        database.insert("m", vec![query::MUpdate::new("name", b"mallory".to_vec())], 110);
+        database.empty_memtable().unwrap();
+        database.update("m", vec![query::MUpdate::new("name", b"mal".to_vec())], 120);
+
+        fn explain(database: &mut super::Base, q: &str) -> query::Explanation {
+            match database.query_now(query::Query::parse(q).unwrap()) {
+                query::QueryResult::Explanation{explanation: e} => e,
+                x => panic!("unexpected result: {}", x)
+            }
+        }
+
+        let e = explain(&mut database, r#"{"explain": {"select": {"row": "m", "get": ["name"]}}}"#);
+        assert!(e.memtable);
+        assert_eq!((e.dtables.len(), e.skipped_dtables), (1, 1));
+        assert_eq!(e.dtables[0].rows, 1);
+        assert!(e.bytes > 0 && e.bytes == e.dtables[0].bytes);
+
+        let e = explain(&mut database, r#"{"explain": {"select": {"row": "b", "get": ["name"]}}}"#);
+        assert!(!e.memtable);
+        assert_eq!((e.dtables.len(), e.skipped_dtables, e.bytes), (0, 2, 0));
+
+        let e = explain(&mut database, r#"{"explain": {"scan": {"start": "a", "end": "z"}}}"#);
+        assert!(e.memtable);
+        assert_eq!(e.dtables.iter().map(|d| d.rows).collect::<Vec<_>>(), vec![1, 1]);
+        assert_eq!(e.bytes, e.dtables.iter().map(|d| d.bytes).sum::<u64>());
+
+        let e = explain(&mut database, r#"{"explain": {"scan": {"start": "n", "end": ""}}}"#);
+        assert!(!e.memtable);
+        assert_eq!(e.skipped_dtables, 2);
+
+        // Nothing was actually read.
+        assert!(database.disktables.iter().all(|d| d.read_stats() == (0, 0, 0)));
+        assert_eq!(
+            database.str_query(r#"{"explain": {"update": {"row": "m", "set": {"name": "x"}}}}"#),
+            "Internal error: only reads of rows and ranges can be explained"
+        );
+    }
+
+    #[test]
+    fn checksums_latest_values() {
+        // The same latest values, written differently, have the same
+        // checksums.
+        let mut first = super::Base::new_stub();
+        first.insert("a", vec![query::MUpdate::new("name", b"old".to_vec())], 100);
+        first.empty_memtable().unwrap();
+        first.update("a", vec![query::MUpdate::new("name", b"alice".to_vec())], 110);
+        first.insert("b", vec![query::MUpdate::new("name", b"bob".to_vec())], 110);
+
+        let mut second = super::Base::new_stub();
+        second.insert("a", vec![query::MUpdate::new("name", b"alice".to_vec())], 105);
+        second.insert("b", vec![query::MUpdate::new("name", b"bob".to_vec())], 105);
+        second.insert("b0", vec![query::MUpdate::new("name", b"other".to_vec())], 105);
+
+        let hash = |name: &str, value: &str|
+            scan::fnv1a_field(scan::fnv1a_field(scan::FNV_OFFSET, name.as_bytes()), value.as_bytes());
+        assert_eq!(
+            format!("{}", first.checksum("a", 1000)),
+            format!("Checksum: {:016x} (1 rows)", hash("name", "alice"))
+        );
+        assert_eq!(format!("{}", first.checksum("a", 1000)), format!("{}", second.checksum("a", 1000)));
+        assert_eq!(
+            format!("{}", first.checksum("a", 105)),
+            format!("Checksum: {:016x} (1 rows)", hash("name", "old"))
+        );
+        assert_eq!(format!("{}", first.checksum("missing", 1000)), "Row not found.");
+
+        let range = |database: &super::Base| format!("{}", database.checksum_range(scan::KeyRange::new("a", "b0"), 1000));
+        assert_eq!(range(&first), range(&second));
+        assert!(range(&first).ends_with("(2 rows)"));
+
+        second.update("b", vec![query::MUpdate::new("name", b"robert".to_vec())], 120);
+        assert!(range(&first) != range(&second));
+
+        // Soft deleted rows are left out.
+        second.soft_delete("b", 130);
+        assert_eq!(format!("{}", second.checksum("b", 1000)), "Row not found.");
+        assert!(range(&second).ends_with("(1 rows)"));
+        assert_eq!(
+            second.str_query(r#"{"checksum": {"row": "a"}}"#),
+            format!("{}", first.checksum("a", 1000))
+        );
+    }
+
+    #[test]
+    fn diffs_rows_between_timestamps() {
+        let mut database = super::Base::new_stub();
+        database.insert("row", vec![
+            query::MUpdate::new("name", b"alice".to_vec()),
+            query::MUpdate::new("status", b"ok".to_vec())
+        ], 100);
+        database.empty_memtable().unwrap();
+        database.update("row", vec![query::MUpdate::new("status", b"broken".to_vec())], 110);
+        database.update("row", vec![query::MUpdate::new("status", b"ok".to_vec())], 120);
+        database.update("row", vec![query::MUpdate::new("email", b"a@example.com".to_vec())], 130);
+
+        assert_eq!(
+            format!("{}", database.diff("row", 100, 110, 1000)),
+            r#"Changes: [status: "ok" -> "broken"]"#
+        );
+        // The status changed and then changed back.
+        assert_eq!(
+            format!("{}", database.diff("row", 100, 120, 1000)),
+            "Changes: []"
+        );
+        assert_eq!(
+            format!("{}", database.diff("row", 50, 0, 1000)),
+            r#"Changes: [email: None -> "a@example.com", name: None -> "alice", status: None -> "ok"]"#
+        );
+        // The end is capped at the query's timestamp.
+        assert_eq!(
+            format!("{}", database.diff("row", 120, 0, 125)),
+            "Changes: []"
+        );
+        assert_eq!(format!("{}", database.diff("missing", 0, 0, 1000)), "Row not found.");
+
+        // The deleted values are kept in the dtables for older reads.
+        database.empty_memtable().unwrap();
+        database.delete_range("row", "row0", 140);
+        assert_eq!(
+            format!("{}", database.diff("row", 135, 150, 1000)),
+            r#"Changes: [email: "a@example.com" -> None, name: "alice" -> None, status: "ok" -> None]"#
+        );
+        assert_eq!(
+            database.str_query(r#"{"diff": {"row": "row", "from_ts": 100, "to_ts": 110}}"#),
+            r#"Changes: [status: "ok" -> "broken"]"#
+        );
+    }
+
+    #[test]
+    fn reads_through_the_row_cache() {
+        for mode in &[rowcache::CacheMode::Invalidate, rowcache::CacheMode::WriteThrough] {
+            let mut database = super::Base::new_stub();
+            database.row_cache.get_mut().capacity = 10;
+            database.row_cache.get_mut().mode = *mode;
+            database.insert("cached", vec![query::MUpdate::new("status", b"old".to_vec())], 100);
+            database.empty_memtable().unwrap();
+            database.update("cached", vec![query::MUpdate::new("count", b"1".to_vec())], 110);
+
+            let select = |database: &super::Base, timestamp: u64| {
+                format!("{}", database.select("cached", &["status", "count"], timestamp))
+            };
+            assert_eq!(select(&database, 1000), r#"Data: ["old", "1"]"#);
+            assert_eq!(select(&database, 105), r#"Data: ["old", None]"#);
+            assert_eq!(database.row_cache.borrow().len(), 1);
+
+            // Writes are never hidden by the cached copy of the row.
+            database.update("cached", vec![query::MUpdate::new("status", b"new".to_vec())], 120);
+            assert_eq!(select(&database, 1000), r#"Data: ["new", "1"]"#);
+            database.delete_range("cached", "", 130);
+            assert_eq!(select(&database, 1000), "Row not found.");
+            assert_eq!(select(&database, 125), r#"Data: ["new", "1"]"#);
+
+            let (hits, misses) = {
+                let cache = database.row_cache.borrow();
+                (cache.hits, cache.misses)
+            };
+            match *mode {
+                rowcache::CacheMode::Invalidate   => assert_eq!((hits, misses), (2, 3)),
+                rowcache::CacheMode::WriteThrough => assert_eq!((hits, misses), (3, 2))
+            };
+        }
+    }
+
+    #[test]
+    fn applies_family_policies() {
+        let mut database = super::Base::new_stub();
+        database.row_cache.get_mut().capacity = 10;
+        database.families.add_family(super::families::FamilyPolicy{
+            compression: super::families::Codec::Gzip,
+            cache: false,
+            ..super::families::FamilyPolicy::new("data:")
+        });
+        database.families.add_family(super::families::FamilyPolicy{
+            max_versions: 1,
+            ..super::families::FamilyPolicy::new("history:")
+        });
+
+        database.insert("row", vec![
+            query::MUpdate::new("data:body", b"body".to_vec()),
+            query::MUpdate::new("history:status", b"old".to_vec()),
+            query::MUpdate::new("name", b"a".to_vec())
+        ], 100);
+        database.empty_memtable().unwrap();
+        database.update("row", vec![query::MUpdate::new("history:status", b"new".to_vec())], 110);
+        database.empty_memtable().unwrap();
+
+        // Old versions are only dropped once the dtables are merged.
+        let select = |database: &super::Base, cols: &[&str], timestamp: u64| {
+            format!("{}", database.select("row", cols, timestamp))
+        };
+        assert_eq!(select(&database, &["history:status"], 105), r#"Data: ["old"]"#);
+        database.merge_disktables().unwrap();
+        assert_eq!(select(&database, &["history:status"], 105), r#"Data: [None]"#);
+        assert_eq!(select(&database, &["history:status"], 1000), r#"Data: ["new"]"#);
+
+        // The compressed family is read from the dtable, and left out of
+        // the cached row.
+        assert_eq!(select(&database, &["data:body", "name"], 1000), r#"Data: ["body", "a"]"#);
+        assert_eq!(select(&database, &["name"], 1000), r#"Data: ["a"]"#);
+        assert_eq!(select(&database, &["data:body"], 1000), r#"Data: ["body"]"#);
+        let mut cache = database.row_cache.borrow_mut();
+        assert_eq!(cache.get("row").unwrap().get_keys(), &[String::from("history:status"), String::from("name")]);
+    }
+
+    // This function generates 25 random bytes of data to write to the
+    // database.
+    fn random_bytes() -> Vec<u8> {
+        (0..25).map(|_| random::<u8>()).collect::<Vec<_>>()
+    }
+
+    // This function generates a 25 character long ASCII-printable string.
+    fn random_string() -> String {
+        (0..25).map(|_| (0x20u8 + (random::<f32>() * 96.0) as u8) as char).collect()
+    }
+
+    // This method checks that the two methods on dtables which compute
+    // offsets, get_offset_from_index and get_row_offset, match exactly.
+    #[test]
+    fn row_offset_methods_match() {
+        let mut database = super::Base::new_stub();
+        for _ in 0..10 {
+            database.insert(
+                random_string().as_str(),
+                (0..10)
+                    .map(|_| query::MUpdate::new(random_string().as_str(), random_bytes()))
+                    .collect::<Vec<_>>(),
+                random::<u64>()
+            );
+        }
+
+        database.empty_memtable().unwrap();
+
+        let key_list = database.disktables[0].lookup.get_entries()
+            .iter()
+            .map(|e| e.get_key())
+            .collect::<Vec<_>>();
+
+        for (i, k) in key_list.iter().enumerate() {
+            let o1 = database.disktables[0].get_row_offset(k).unwrap().unwrap();
+            let o2 = database.disktables[0].get_offset_from_index(i).unwrap();
+
+            assert_eq!(o1.start, o2.start);
+            assert_eq!(o1.length, o2.length);
+            if o1.length.is_some() {
+                assert_eq!(
+                    o1.length,
+                    Some(670),
+                    "Expected struct length to be exactly 670 bytes.
+                    If you changed the struct, this error might be a false positive."
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn can_multi_merge_disktables() {
+        // In this test, we'll generate a series of DTables with random data
+        // in several rows. The DTables will be merged, and the resulting table
+        // will be checked by a series of queries.
+        let mut database = super::Base::new_stub();
+        let mut max_timestamp = 0;
+        for j in 0..4 {
+            // Write ten rows with random junk data.
+            for i in 0..4 {
+                database.insert(
+                    format!("row{}x{}", j, i).as_str(),
+                    (0..4)
+                        .map(|_| query::MUpdate::new(random_string().as_str(), random_bytes()))
+                        .chain(vec![query::MUpdate::new("canary", format!("ok:{}", i).into_bytes())])
+                        .collect::<Vec<_>>(),
+                    random::<u64>()
+                );
+            }
+
+            let t = random::<u64>();
+            if t > max_timestamp {
+                max_timestamp = t;
+            }
+
+            // Write one row which will overlap in every dtable.
+            database.update(
+                "zcanary_row",
+                vec![query::MUpdate::new("canary", format!("ok:{}", t).into_bytes())],
+                t
+            );
+
+            database.empty_memtable().unwrap();
+        }
+
+        // This will merge all 10 disktables.
+        database.merge_disktables().unwrap();
+
+        println!("{:?}", database.disktables[0].get_row("zcanary_row"));
+        println!("{:?}", database.disktables[0].get_row("row0x0"));
+        println!("{:?}", database.disktables[0].get_row("row0x1"));
+
+        // Now we just need to query to make sure that all of the merged data
+        // follows the expected properties.
+        for i in 0..4 {
+            for j in 0..4 {
+                assert_eq!(
+                    format!("{}", database.query(
+                        query::Query::parse(format!(r#"{{"select": {{"row": "row{}x{}", "get": ["canary"]}}}}"#, i, j).as_str()).unwrap(),
+                        u64::MAX
+                    )),
+                    format!(r#"Data: ["ok:{}"]"#, j),
+                    "expected row{}x{} to contain data: ok:{}", i, j, j
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn can_merge_colliding_disktables() {
+        let mut database = super::Base::new_stub();
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "test_row","set": {"status": "old_status"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+        database.empty_memtable().unwrap();
+
+        assert_eq!(
+            database.str_query(r#"{"update": {"row": "test_row", "set": {"status": "new_status"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+        database.empty_memtable().unwrap();
+
+        database.merge_disktables().unwrap();
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "test_row", "get":["status"]}}"#),
+            r#"Data: ["new_status"]"#
+        );
+    }
+
+    #[test]
+    fn accounts_for_query_costs() {
+        let mut database = super::Base::new_stub();
+        database.str_query(r#"{"insert": {"row": "costed", "set": {"status": "ok"}}}"#);
+        database.empty_memtable().unwrap();
+
+        let context = query::QueryContext::new();
+        let (result, _, cost) = database.query_now_with_cost(query::Query::new_select("costed", &["status"]), &context);
+        assert_eq!(format!("{}", result), r#"Data: ["ok"]"#);
+        assert_eq!((cost.dtables_probed, cost.rows_merged), (1, 1));
+        assert!(cost.bytes_read > 0);
+
+        // The dtable's key range rules it out, so it isn't searched.
+        let (_, _, cost) = database.query_now_with_cost(query::Query::new_select("other", &["status"]), &context);
+        assert_eq!((cost.dtables_probed, cost.rows_merged, cost.bytes_read), (0, 0, 0));
+
+        match database.stats() {
+            query::QueryResult::Stats{stats: s} => {
+                assert_eq!(s.queries, 3);
+                assert_eq!((s.query_dtables_probed, s.query_rows_merged), (1, 1));
+                assert!(s.query_bytes_read > 0);
+            },
+            x => panic!("Expected stats, got: {}", x)
+        }
+    }
+
+    #[test]
+    fn counts_write_stalls() {
+        let mut database = super::Base::new_stub();
+        database.str_query(r#"{"insert": {"row": "stall_one", "set": {"status": "ok"}}}"#);
+
+        // Once the memtable is over its limit, the next write waits for
+        // it to be flushed.
+        database.memtable_size_limit = 1;
+        database.str_query(r#"{"insert": {"row": "stall_two", "set": {"status": "ok"}}}"#);
+
+        match database.stats() {
+            query::QueryResult::Stats{stats: s} => {
+                assert_eq!(s.minor_compactions, 1);
+                assert_eq!(s.write_stalls, 1);
+                assert!(s.last_flush_ns > 0);
+                assert!(s.write_stall_ns >= s.last_flush_ns);
+            },
+            x => panic!("Expected stats, got: {}", x)
+        }
+    }
+
+    #[test]
+    fn can_report_stats() {
+        let mut database = super::Base::new_stub();
+        database.str_query(r#"{"insert": {"row": "stats_one","set": {"status": "alright"}}}"#);
+        database.empty_memtable().unwrap();
+        database.str_query(r#"{"insert": {"row": "stats_two","set": {"status": "ok"}}}"#);
+
+        match database.stats() {
+            query::QueryResult::Stats{stats: s} => {
+                assert_eq!(s.memtable_rows, 1);
+                assert_eq!(s.memtable_size, 8);
+                assert_eq!(s.disktables, 1);
+                assert_eq!(s.disktable_rows, 1);
+                assert!(s.disktable_bytes > 0);
+                assert!(s.commit_log_bytes > 0);
+                assert_eq!(s.minor_compactions, 1);
+                assert_eq!(s.major_compactions, 0);
+            },
+            x => panic!("Expected stats, got: {}", x)
+        }
+
+        let history = database.compaction_history();
+        assert_eq!(history.len(), 1);
+        assert!(!history[0].major);
+        assert_eq!(history[0].rows, 1);
+    }
+
+    #[test]
+    fn only_merges_dtables_which_overlap_enough() {
+        let mut database = super::Base::new_stub();
+        database.disktable_limit = 3;
+        database.compaction_min_overlap = 0.5;
+        for keys in &[vec!["a", "b"], vec!["m", "z"], vec!["n", "p"]] {
+            for key in keys {
+                database.insert(key, vec![query::MUpdate::new("status", b"OK".to_vec())], 1);
+            }
+            database.empty_memtable().unwrap();
+        }
+        assert_eq!(database.disktables[2].overlap(&database.disktables[1]), 1.0);
+        assert_eq!(database.disktables[0].overlap(&database.disktables[1]), 0.0);
+
+        // Only the two dtables which overlap are merged.
+        let first = database.disktables[0].filename().to_owned();
+        database.insert("c", vec![query::MUpdate::new("status", b"OK".to_vec())], 1);
+        database.empty_memtable().unwrap();
+        assert_eq!(database.disktables.len(), 3);
+        assert_eq!(database.disktables[0].filename(), first);
+        assert_eq!(database.disktables[1].len(), 4);
+
+        // None of them overlap now, so they're all kept, past the limit.
+        database.insert("e", vec![query::MUpdate::new("status", b"OK".to_vec())], 1);
+        database.empty_memtable().unwrap();
+        assert_eq!(database.disktables.len(), 4);
+        assert_eq!(database.compaction_history().iter().filter(|c| c.major).count(), 1);
+    }
+
+    #[test]
+    fn pages_through_columns() {
+        let mut database = super::Base::new_stub();
+        database.insert("row", (0..5).map(|i| query::MUpdate::new(&format!("c{}", i), vec![b'0' + i])).collect(), 1);
+        database.empty_memtable().unwrap();
+        database.update("row", vec![
+            query::MUpdate::new("c1", b"x".to_vec()),
+            query::MUpdate::new("c5", b"5".to_vec())
+        ], 2);
+
+        let page = |database: &mut super::Base, get: &[&str], start: &str, limit: u64| {
+            let q = query::Query::Select{
+                row: String::from("row"),
+                get: get.iter().map(|g| g.to_string()).collect(),
+                snapshot: 0,
+                include_deleted: false,
+                project: vec![],
+                strict: false,
+                column_start: start.to_owned(),
+                column_limit: limit
+            };
+            match database.query(q, 3) {
+                query::QueryResult::Rows{rows: r, next: n} => (
+                    r[0].columns.iter().map(|&(ref k, ref v)| format!("{}={}", k, String::from_utf8_lossy(v))).collect::<Vec<_>>(),
+                    n
+                ),
+                x => panic!("expected rows, got {}", x)
+            }
+        };
+
+        // Columns in the memtable and the dtable are merged in key order.
+        assert_eq!(page(&mut database, &[], "", 2), (vec![String::from("c0=0"), String::from("c1=x")], String::from("c2")));
+        assert_eq!(page(&mut database, &[], "c2", 3), (vec![String::from("c2=2"), String::from("c3=3"), String::from("c4=4")], String::from("c5")));
+        assert_eq!(page(&mut database, &[], "c5", 3), (vec![String::from("c5=5")], String::new()));
+
+        // Only the columns asked for are paged through.
+        assert_eq!(page(&mut database, &["c1", "c4"], "", 1), (vec![String::from("c1=x")], String::from("c4")));
+    }
+
+    #[test]
+    fn freezes_compaction_across_restarts() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 2, storage.clone(), clock.clone());
+        database.load().unwrap();
+        assert_eq!(format!("{}", database.query(query::Query::FreezeCompaction, clock.now())), "OK.");
+
+        for row in &["a", "b", "c"] {
+            database.insert(row, vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+            database.empty_memtable().unwrap();
+        }
+        assert_eq!(database.disktables.len(), 3);
+        match database.stats() {
+            query::QueryResult::Stats{stats: s} => assert!(s.compaction_frozen),
+            x => panic!("Expected stats, got: {}", x)
+        }
+
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 2, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        assert!(reloaded.is_compaction_frozen());
+
+        reloaded.query(query::Query::UnfreezeCompaction, clock.now());
+        reloaded.insert("d", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+        reloaded.empty_memtable().unwrap();
+        assert_eq!(reloaded.disktables.len(), 2);
+        assert_eq!(format!("{}", reloaded.select("a", &["status"], clock.now())), r#"Data: ["OK"]"#);
+    }
+
+    #[test]
+    fn merges_overlapping_dtables_first() {
+        let mut database = super::Base::new_stub();
+        database.disktable_limit = 3;
+        for keys in &[vec!["a"], vec!["m", "z"], vec!["n"]] {
+            for key in keys {
+                database.insert(key, vec![query::MUpdate::new("status", b"OK".to_vec())], 1);
+            }
+            database.empty_memtable().unwrap();
+        }
+
+        // The first dtable doesn't overlap any others, and every read of
+        // it finds what it's looking for, so it's left alone.
+        let first = database.disktables[0].filename().to_owned();
+        for _ in 0..5 {
+            database.select("a", &["status"], 2);
+        }
+        assert_eq!(database.disktables[0].read_stats().0, 5);
+
+        database.insert("q", vec![query::MUpdate::new("status", b"OK".to_vec())], 1);
+        database.empty_memtable().unwrap();
+        assert_eq!(database.disktables.len(), 3);
+        assert_eq!(database.disktables[0].filename(), first);
+        assert_eq!(database.disktables[1].len(), 3);
+
+        match database.stats() {
+            query::QueryResult::Stats{stats: s} => {
+                assert_eq!(s.dtables.len(), 3);
+                assert_eq!(s.dtables[0].hits, 5);
+            },
+            x => panic!("Expected stats, got: {}", x)
+        }
+    }
+
+    #[test]
+    fn enforces_key_rules() {
+        let mut database = super::Base::new_stub();
+        database.key_rules.reject_empty = true;
+        database.key_rules.max_length = 10;
+        database.key_rules.set_allowed_characters("a-z_").unwrap();
+        database.key_rules.normalization = super::keys::KeyNormalization::Lowercase;
+
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "","set": {"status": "OK"}}}"#),
+            format!("{}", query::QueryResult::InvalidKey)
+        );
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "a_very_long_row","set": {"status": "OK"}}}"#),
+            format!("{}", query::QueryResult::InvalidKey)
+        );
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "row-1","get": ["status"]}}"#),
+            format!("{}", query::QueryResult::InvalidKey)
+        );
+
+        // Keys are lowercased before they're checked and used.
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "Row_One","set": {"status": "OK"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "ROW_ONE","get": ["status"]}}"#),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn orders_table_keys_case_insensitively() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.key_rules.set_table_order("users/", super::keys::KeyOrder::AsciiCaseInsensitive);
+        database.load().unwrap();
+
+        let run = |database: &mut super::Base, q: &str| {
+            clock.advance(1000);
+            format!("{}", database.query_now(query::Query::parse(q).unwrap()))
+        };
+
+        // Writes to differently cased keys land in the same row, whether
+        // it's in the memtable or a dtable.
+        run(&mut database, r#"{"insert": {"row": "users/Bob@Example.com", "set": {"name": "bob"}}}"#);
+        run(&mut database, r#"{"insert": {"row": "posts/Bob", "set": {"name": "post"}}}"#);
+        database.empty_memtable().unwrap();
+        run(&mut database, r#"{"update": {"row": "USERS/bob@example.COM", "set": {"name": "robert"}}}"#);
+        run(&mut database, r#"{"insert": {"row": "users/alice@example.com", "set": {"name": "alice"}}}"#);
+        database.empty_memtable().unwrap();
+        database.merge_disktables().unwrap();
+
+        assert_eq!(
+            run(&mut database, r#"{"select": {"row": "users/BOB@EXAMPLE.COM", "get": ["name"]}}"#),
+            r#"Data: ["robert"]"#
+        );
+        assert_eq!(run(&mut database, r#"{"select": {"row": "posts/bob", "get": ["name"]}}"#), "Row not found.");
+        assert_eq!(
+            run(&mut database, r#"{"scan": {"start": "Users/A", "end": "users/C", "get": ["name"]}}"#),
+            r#"Rows: [users/alice@example.com: {name: "alice"}, users/bob@example.com: {name: "robert"}], next: """#
+        );
+    }
+
+    #[test]
+    fn salts_keys_of_salted_tables() {
+        let mut database = super::Base::new_stub();
+        database.salting.set_table("events/", 4);
+        database.load().unwrap();
+        for i in 1..6 {
+            database.query_now(query::Query::new_update(&format!("events/{}", i), vec![query::MUpdate::new("n", format!("{}", i).into_bytes())]));
+        }
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::parse(r#"{"select": {"row": "events/3", "get": ["n"]}}"#).unwrap())),
+            r#"Data: ["3"]"#
+        );
+
+        // The rows are stored under their buckets, but scans of the table
+        // see them in order, without the buckets.
+        match database.query_now(query::Query::parse(r#"{"scan": {"start": "events/", "end": "z"}}"#).unwrap()) {
+            query::QueryResult::Rows{rows, ..} => {
+                assert_eq!(rows.len(), 5);
+                assert!(rows.iter().all(|r| r.key.len() == "events/00/1".len()));
+                assert!(rows.iter().any(|r| r.key != rows[0].key && r.key[..10] != rows[0].key[..10]));
+            },
+            x => panic!("expected rows, got {}", x)
+        };
+        let mut scan = |start: &str| format!("{}", database.query_now(query::Query::Scan{
+            start: start.to_owned(),
+            end: String::from("events0"),
+            get: vec![],
+            limit: 3,
+            snapshot: 0
+        }));
+        assert_eq!(scan("events/"), r#"Rows: [events/1: {n: "1"}, events/2: {n: "2"}, events/3: {n: "3"}], next: "events/4""#);
+        assert_eq!(scan("events/4"), r#"Rows: [events/4: {n: "4"}, events/5: {n: "5"}], next: """#);
+    }
+
+    #[test]
+    fn deletes_ranges_of_salted_tables() {
+        let mut database = super::Base::new_stub();
+        database.salting.set_table("events/", 4);
+        database.load().unwrap();
+        for i in 1..6 {
+            database.query_now(query::Query::new_update(&format!("events/{}", i), vec![query::MUpdate::new("n", format!("{}", i).into_bytes())]));
+        }
+
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::DeleteRange{
+                start_row: String::from("events/2"),
+                end_row: String::from("events/4")
+            })),
+            "Done"
+        );
+        for (i, expected) in vec![(1, r#"Data: ["1"]"#), (2, "Row not found."), (3, "Row not found."), (4, r#"Data: ["4"]"#)] {
+            assert_eq!(
+                format!("{}", database.query_now(query::Query::parse(&format!(r#"{{"select": {{"row": "events/{}", "get": ["n"]}}}}"#, i)).unwrap())),
+                expected
+            );
+        }
+
+        // Listing keys and checksums see the unsalted keys as well.
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::ListKeys{start: String::from("events/"), limit: 10})),
+            format!("{}", query::QueryResult::Keys{keys: vec![
+                String::from("events/1"), String::from("events/4"), String::from("events/5")
+            ]})
+        );
+        match database.query_now(query::Query::ChecksumRange{start: String::from("events/"), end: String::from("events0")}) {
+            query::QueryResult::Checksum{rows, ..} => assert_eq!(rows, 3),
+            x => panic!("expected a checksum, got {}", x)
+        };
+    }
+
+    #[test]
+    fn can_run_queries_with_trace_ids() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        assert_eq!(database.trace(), "");
+
+        // Every query counts as slow, so it's written to the slow query log.
+        database.slow_query_ms = 1;
+        let context = query::QueryContext::with_trace_id("abc123");
+        assert_eq!(
+            format!("{}", database.query_now_with_context(
+                query::Query::new_insert("traced", vec![query::MUpdate::new("status", b"OK".to_vec())]),
+                &context
+            )),
+            format!("{}", query::QueryResult::Done)
+        );
+
+        // The trace ID only applies while its query is running.
+        assert_eq!(database.trace(), "");
+        database.trace_id = context.trace_id.clone();
+        assert_eq!(database.trace(), " (trace_id=abc123)");
+    }
+
+    struct TestSink {
+        spans: ::std::sync::Mutex<Vec<spans::Span>>
+    }
+
+    impl spans::SpanSink for TestSink {
+        fn record(&self, span: spans::Span) {
+            self.spans.lock().unwrap().push(span);
+        }
+    }
+
+    #[test]
+    fn records_spans_for_traced_queries() {
+        let sink = Arc::new(TestSink{spans: ::std::sync::Mutex::new(vec![])});
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        let span_sink: Arc<spans::SpanSink> = sink.clone();
+        database.span_sink = Some(span_sink);
+
+        let mut context = query::QueryContext::with_trace_id("trace1");
+        context.span_id = String::from("request1");
+        database.query_now_with_context(
+            query::Query::new_insert("row", vec![query::MUpdate::new("status", b"OK".to_vec())]),
+            &context
+        );
+        database.empty_memtable().unwrap();
+        database.query_now_with_context(query::Query::new_select("row", &["status"]), &context);
+
+        // Queries without a trace ID don't record anything.
+        database.query_now(query::Query::new_select("row", &["status"]));
+
+        let recorded = sink.spans.lock().unwrap();
+        assert_eq!(
+            recorded.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+            vec!["memtable.insert", "commit_log.fsync", "memtable.select", "dtable.read"]
+        );
+        assert!(recorded.iter().all(|s| s.trace_id == "trace1" && s.parent_span_id == "request1"));
+        assert!(recorded.iter().all(|s| s.start <= s.end));
+    }
+
+    #[test]
+    fn deduplicates_repeated_values() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        database.schemas.add_table(serde_json::from_str(r#"{
+            "prefix": "sensors/",
+            "columns": [
+                {"name": "reading", "dedup": true, "touch_column": "seen_at"},
+                {"name": "state", "dedup": true},
+                {"name": "note"}
+            ]
+        }"#).unwrap());
+
+        let versions = |database: &super::Base, column: &str| match database.history("sensors/1", column, 100, u64::MAX) {
+            query::QueryResult::List{entries} => entries.len(),
+            x => panic!("expected a list, got {}", x)
+        };
+
+        database.insert("sensors/1", vec![
+            query::MUpdate::new("reading", b"20".to_vec()),
+            query::MUpdate::new("state", b"ok".to_vec())
+        ], 100);
+        for &(t, reading) in &[(110, "20"), (120, "20"), (130, "21"), (140, "21")] {
+            assert_eq!(
+                format!("{}", database.update("sensors/1", vec![
+                    query::MUpdate::new("reading", reading.as_bytes().to_vec()),
+                    query::MUpdate::new("state", b"ok".to_vec()),
+                    query::MUpdate::new("note", b"same".to_vec())
+                ], t)),
+                "OK."
+            );
+        }
+
+        // Only changes to deduplicated columns are kept, and the last
+        // write which was left out is recorded in the touch column.
+        assert_eq!(versions(&database, "reading"), 2);
+        assert_eq!(versions(&database, "state"), 1);
+        assert_eq!(versions(&database, "note"), 4);
+        assert_eq!(format!("{}", database.select("sensors/1", &["reading", "seen_at"], 1000)), r#"Data: ["21", "140"]"#);
+
+        // A write where everything is left out doesn't write anything.
+        let size = database.memtable.size;
+        database.update("sensors/1", vec![query::MUpdate::new("state", b"ok".to_vec())], 150);
+        assert_eq!(database.memtable.size, size);
+
+        // Transactions are deduplicated too, but appends never are.
+        database.query_now(query::Query::parse(r#"{"transaction": {"updates": {"sensors/1": {"state": "ok"}}}}"#).unwrap());
+        assert_eq!(versions(&database, "state"), 1);
+        database.query_now(query::Query::parse(r#"{"append": {"row": "sensors/1", "set": {"state": "ok"}}}"#).unwrap());
+        assert_eq!(versions(&database, "state"), 2);
+    }
+
+    #[test]
+    fn enforces_table_schemas() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        database.schemas.add_table(super::schema::TableSchema{
+            prefix: String::from("users/"),
+            columns: vec![super::schema::ColumnSchema{
+                name: String::from("name"),
+                column_type: super::schema::ColumnType::String,
+                required: true,
+                default: None,
+                default_timestamp: false,
+                dedup: false,
+                touch_column: None
+            }],
+            result_cache_ttl_ms: 0,
+            transforms: vec![],
+            key_order: super::keys::KeyOrder::Binary,
+            key_parts: vec![],
+            salt_buckets: 0
+        });
+
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "users/1","set": {"nmae": "alice"}}}"#),
+            r#"Schema violation: column "nmae" isn't declared in table "users/""#
+        );
+        assert_eq!(database.str_query(r#"{"select": {"row": "users/1","get": ["nmae"]}}"#), "Row not found.");
+
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "users/1","set": {"name": "alice"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+    }
+
+    #[test]
+    fn transforms_written_values() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        database.transforms.add_table(&serde_json::from_str(r#"{
+            "prefix": "users/",
+            "transforms": [
+                {"column": "email", "transform": "lowercase"},
+                {"column": "bio", "transform": "max_bytes", "options": {"limit": "10"}}
+            ]
+        }"#).unwrap()).unwrap();
+
+        database.str_query(r#"{"insert": {"row": "users/1","set": {"email": "Alice@Example.com"}}}"#);
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "users/1","get": ["email"]}}"#),
+            r#"Data: ["alice@example.com"]"#
+        );
+        database.str_query(r#"{"update": {"row": "users/1","set": {"email": "ALICE@example.com"}}}"#);
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "users/1","get": ["email"]}}"#),
+            r#"Data: ["alice@example.com"]"#
+        );
+
+        // A refused value means that nothing in the write is applied.
+        assert_eq!(
+            database.str_query(r#"{"update": {"row": "users/1","set": {"email": "B@B", "bio": "far too long"}}}"#),
+            r#"Schema violation: column "bio" in table "users/" was refused by transform "max_bytes": value is longer than 10 bytes"#
+        );
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "users/1","get": ["email"]}}"#),
+            r#"Data: ["alice@example.com"]"#
+        );
+    }
+
+    #[test]
+    fn refuses_rows_in_other_shards() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        database.shard_address = String::from("db1:8080");
+        let map = |version: u64, end: &str| super::shards::ShardMap{
+            version: version,
+            shards: vec![
+                super::shards::Shard{start: String::new(), end: end.to_owned(), address: String::from("db1:8080")},
+                super::shards::Shard{start: end.to_owned(), end: String::new(), address: String::from("db2:8080")}
+            ]
+        };
+        assert_eq!(database.update_shard_map(map(1, "m")), Ok(true));
+
+        let insert = |row: &str| query::Query::new_insert(row, vec![query::MUpdate::new("status", b"OK".to_vec())]);
+        assert_eq!(format!("{}", database.query_now(insert("apple"))), "OK.");
+        assert_eq!(format!("{}", database.query_now(insert("zebra"))), "Row belongs to another shard (shard map version 1).");
+
+        // Older maps don't replace newer ones.
+        assert_eq!(database.update_shard_map(map(2, "")), Err(String::from(r#"the shards starting at "" and "" overlap"#)));
+        assert_eq!(database.update_shard_map(map(2, "zz")), Ok(true));
+        assert_eq!(database.update_shard_map(map(1, "m")), Ok(false));
+        assert_eq!(format!("{}", database.query_now(insert("zebra"))), "OK.");
+    }
+
+    #[test]
+    fn executes_prepared_queries() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        database.query_now(query::Query::new_insert("user", vec![
+            query::MUpdate::new("name", b"alice".to_vec()),
+            query::MUpdate::new("email", b"alice@example.com".to_vec())
+        ]));
+
+        let handle = match database.query_now(query::Query::parse(r#"{"prepare": {"get": ["email", "name"]}}"#).unwrap()) {
+            query::QueryResult::Prepared{handle: h} => h,
+            x => panic!("unexpected result: {}", x)
+        };
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::new_execute(handle, "user"))),
+            r#"Data: ["alice@example.com", "alice"]"#
+        );
+        assert_eq!(format!("{}", database.query_now(query::Query::new_execute(handle, "nobody"))), "Row not found.");
+        assert_eq!(format!("{}", database.query_now(query::Query::new_execute(handle + 1, "user"))), "Prepared query not found.");
+    }
+
+    #[test]
+    fn enforces_access_control() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        database.access_control.add_rule(acl::AccessRule{
+            token: String::from("alice"),
+            read: vec![String::from("users/")],
+            write: vec![String::from("users/alice/")]
+        });
+
+        let alice = query::QueryContext{auth_token: String::from("alice"), ..Default::default()};
+        let insert = |row: &str| query::Query::new_insert(row, vec![query::MUpdate::new("status", b"OK".to_vec())]);
+
+        assert_eq!(
+            format!("{}", database.query_now_with_context(insert("users/alice/1"), &alice)),
+            format!("{}", query::QueryResult::Done)
+        );
+        assert_eq!(
+            format!("{}", database.query_now_with_context(insert("users/bob/1"), &alice)),
+            format!("{}", query::QueryResult::PermissionDenied)
+        );
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::new_select("users/alice/1", &["status"]))),
+            format!("{}", query::QueryResult::PermissionDenied)
+        );
+
+        // Key listings only include the keys that the token can read.
+        database.insert("admin/1", vec![query::MUpdate::new("status", b"OK".to_vec())], 1);
+        assert_eq!(
+            format!("{}", database.query_now_with_context(query::Query::ListKeys{start: String::new(), limit: 10}, &alice)),
+            r#"Keys: ["users/alice/1"]"#
+        );
+
+        match database.stats() {
+            query::QueryResult::Stats{stats: s} => assert_eq!(s.permission_denied, 2),
+            _ => panic!("Expected stats.")
+        }
+    }
+
+    #[test]
+    fn records_writes_in_audit_log() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        let directory = tempdir::TempDir::new("audit").unwrap();
+        database.enable_audit_log(directory.path(), 0, 0).unwrap();
+
+        let context = query::QueryContext{
+            auth_token: String::from("secret"),
+            client_address: String::from("10.0.0.1"),
+            ..Default::default()
+        };
+        database.query_now_with_context(
+            query::Query::new_insert("row1", vec![query::MUpdate::new("status", b"OK".to_vec())]),
+            &context
+        );
+        database.query_now_with_context(query::Query::new_select("row1", &["status"]), &context);
+        database.query_now(query::Query::DeleteRange{start_row: String::from("row0"), end_row: String::from("row2")});
+
+        let entries = database.search_audit_log("row1", 10).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].operation, "insert");
+        assert_eq!(entries[0].address, "10.0.0.1");
+        assert_eq!(entries[0].columns, vec![String::from("status")]);
+        assert_eq!(entries[1].operation, "delete_range");
+        assert!(database.search_audit_log("row3", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn can_scan_with_continuation() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        for i in 0..5 {
+            database.insert(&format!("row{}", i), vec![
+                query::MUpdate::new("name", format!("name{}", i).into_bytes()),
+                query::MUpdate::new("other", b"x".to_vec())
+            ], 1);
+        }
+
+        let scan = |start: &str| query::Query::Scan{
+            start: start.to_owned(),
+            end: String::from("row4"),
+            get: vec![String::from("name")],
+            limit: 2,
+            snapshot: 0
+        };
+
+        assert_eq!(
+            format!("{}", database.query_now(scan("row0"))),
+            r#"Rows: [row0: {name: "name0"}, row1: {name: "name1"}], next: "row2""#
+        );
+        assert_eq!(
+            format!("{}", database.query_now(scan("row2"))),
+            r#"Rows: [row2: {name: "name2"}, row3: {name: "name3"}], next: """#
+        );
+    }
+
+    #[test]
+    fn can_list_keys() {
+        let mut database = super::Base::new_stub();
+        database.str_query(r#"{"insert": {"row": "key_b","set": {"status": "ok"}}}"#);
+        database.str_query(r#"{"insert": {"row": "key_d","set": {"status": "ok"}}}"#);
+        database.empty_memtable().unwrap();
+        database.str_query(r#"{"update": {"row": "key_b","set": {"status": "new"}}}"#);
+        database.str_query(r#"{"insert": {"row": "key_c","set": {"status": "ok"}}}"#);
+        database.str_query(r#"{"insert": {"row": "a_key","set": {"status": "ok"}}}"#);
+
+        assert_eq!(
+            database.str_query(r#"{"list_keys": {"start": "key_", "limit": 10}}"#),
+            r#"Keys: ["key_b", "key_c", "key_d"]"#
+        );
+
+        assert_eq!(
+            database.str_query(r#"{"list_keys": {"start": "", "limit": 2}}"#),
+            r#"Keys: ["a_key", "key_b"]"#
+        );
+    }
+
+    #[test]
+    fn can_delete_range() {
+        let mut database = super::Base::new_stub();
+        for row in &["del_a", "del_b", "del_c", "del_d"] {
+            database.insert(row, vec![query::MUpdate::new("status", b"old".to_vec())], 100);
+        }
+        database.empty_memtable().unwrap();
+        database.insert("del_bb", vec![query::MUpdate::new("status", b"old".to_vec())], 110);
+
+        assert_eq!(
+            format!("{}", database.delete_range("del_b", "del_d", 150)),
+            format!("{}", query::QueryResult::Done)
+        );
+
+        // Write to one of the deleted rows after the deletion.
+        database.update("del_c", vec![query::MUpdate::new("status", b"new".to_vec())], 200);
+
+        let select = |database: &super::Base, row: &str| format!("{}", database.select(row, &["status"], 1000));
+        assert_eq!(select(&database, "del_a"), r#"Data: ["old"]"#);
+        assert_eq!(select(&database, "del_b"), "Row not found.");
+        assert_eq!(select(&database, "del_bb"), "Row not found.");
+        assert_eq!(select(&database, "del_c"), r#"Data: ["new"]"#);
+        assert_eq!(select(&database, "del_d"), r#"Data: ["old"]"#);
+
+        // Reading before the deletion still shows the old data.
+        assert_eq!(format!("{}", database.select("del_b", &["status"], 120)), r#"Data: ["old"]"#);
+
+        assert_eq!(
+            database.str_query(r#"{"list_keys": {"start": "", "limit": 10}}"#),
+            r#"Keys: ["del_a", "del_c", "del_d"]"#
+        );
+
+        // The deletion should survive reloading the commit log.
+        mem::replace(&mut database.memtable, mtable::MTable::new());
+        database.load_mtable(false).unwrap();
+        assert_eq!(select(&database, "del_b"), "Row not found.");
+        assert_eq!(select(&database, "del_c"), r#"Data: ["new"]"#);
+
+        // After flushing and merging, the deleted rows are physically removed.
+        database.empty_memtable().unwrap();
+        database.merge_disktables().unwrap();
+        assert_eq!(
+            format!("{:?}", database.disktables[0].lookup.get_entries()
+                .iter()
+                .map(|e| e.get_key())
+                .collect::<Vec<_>>()
+            ),
+            r#"["del_a", "del_c", "del_d"]"#
+        );
+        assert_eq!(database.disktables[0].lookup.get_tombstones().len(), 0);
+        assert_eq!(select(&database, "del_c"), r#"Data: ["new"]"#);
+    }
+
+    #[test]
+    fn can_save_and_reload_dtables() {
+        let directory;
+        {
+            let mut database = super::Base::new_stub();
+            directory = database.directory.to_owned();
+            assert_eq!(
+                database.str_query(r#"{"insert": {"row": "dtable_checker","set": {"status": "alright"}}}"#),
+                format!("{}", query::QueryResult::Done)
+            );
+            // Write to disk.
+            database.empty_memtable().unwrap();
+        }
+
+        // Load up the new database using the old directory, and load in the
+        // dtable files from that run.
+        let mut database = super::Base::new(&directory, 32 * (1<<20), 3);
+        database.load().unwrap();
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "dtable_checker","get": ["status"]}}"#),
+            r#"Data: ["alright"]"#
+        );
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut database = super::Base::new("./data", 32 * (1<<20), 3);
+
+        let done = format!("{}", query::QueryResult::Done);
+        let row_not_found = format!("{}", query::QueryResult::RowNotFound);
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "non-row", "get": []}}"#),
+            row_not_found
+        );
+
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "non-row", "set": {"date": "01-01-1970", "weight": "12 kg"}}}"#),
+            done
+        );
+
+        assert_eq!(
+            database.str_query(r#"{"update": {"row": "non-row", "set": {"weight": "15 kg"}}}"#),
+            done
+        );
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "non-row", "get": ["date", "fate", "weight"]}}"#),
+            r#"Data: ["01-01-1970", None, "15 kg"]"#
+        );
+    }
+
+    #[test]
+    fn can_flush_and_query() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+
+        database.query_now(
+            query::Query::parse(r#"{"insert": {"row": "write_test", "set": {"value": "OK"}}}"#).unwrap()
+        );
+        database.query_now(
+            query::Query::parse(r#"{"insert": {"row": "write_test2", "set": {"value": "OK"}}}"#).unwrap()
+        );
+
+        println!("About to empty memtable.");
+        database.empty_memtable().unwrap();
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "write_test", "get": ["value"]}}"#),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn check_timestamp_select() {
+        // We need to make sure that the system will serve data from
+        // a DTable if it has a newer timestamp than that in the MTable.
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+
+        database.query(
+            query::Query::parse(r#"{"insert": {"row": "timestamp_test", "set": {"clock": "dtable"}}}"#).unwrap(),
+            120
+        );
+        // Flush the memtable to disk.
+        database.empty_memtable().unwrap();
+
+        // Write an older record to the memtable.
+        database.query(
+            query::Query::parse(r#"{"update": {"row": "timestamp_test", "set": {"clock": "memtable", "clock2": "t=100"}}}"#).unwrap(),
+            100
+        );
+
+        // Now when we request the data back, we expect the value from the dtable.
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "timestamp_test", "get": ["clock"]}}"#),
+            r#"Data: ["dtable"]"#
+        );
+
+        assert_eq!(
+            database.disktables[0].len(),
+            1
+        );
+
+        // As an extra trick, write older data to the memtable, and then
+        // query it to see if still returns the most recent value.
+        database.query(
+            query::Query::parse(r#"{"update": {"row": "timestamp_test", "set": {"clock2": "t=90"}}}"#).unwrap(),
+            90
+        );
+        database.query(
+            query::Query::parse(r#"{"update": {"row": "timestamp_test", "set": {"clock2": "t=95"}}}"#).unwrap(),
+            95
+        );
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "timestamp_test", "get": ["clock2"]}}"#),
+            r#"Data: ["t=100"]"#
+        );
+        database.query(
+            query::Query::parse(r#"{"update": {"row": "timestamp_test", "set": {"clock2": "t=110"}}}"#).unwrap(),
+            110
+        );
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "timestamp_test", "get": ["clock2"]}}"#),
+            r#"Data: ["t=110"]"#
+        );
+
+        // When selecting at a specific timestamp, should get an older
+        // snapshot.
+        assert_eq!(
+            format!("{}", database.query(
+                query::Query::parse(r#"{"select": {"row": "timestamp_test", "get": ["clock2"]}}"#).unwrap(),
+                105
+            )),
+            r#"Data: ["t=100"]"#
+        );
+    }
+
+    #[test]
+    fn can_write_and_restore_commit_log() {
+        let mut database = super::Base::new_stub();
+
+        // Write some stuff to the memtable and commit log.
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "my_test_row","set": {"status": "OK"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+
+        // Kill the memtable.
+        mem::replace(&mut database.memtable, mtable::MTable::new());
+
+        // Now the data shouldn't be available.
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "my_test_row","get": ["status"]}}"#),
+            format!("{}", query::QueryResult::RowNotFound)
+        );
+
+        // Load the memtable back up via the commit log.
+        database.load_mtable(false).unwrap();
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "my_test_row","get": ["status"]}}"#),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn skips_repeated_writes_with_idempotency_keys() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        let append = |value: &str| query::Query::Append{
+            row: String::from("user"),
+            set: Map::from_iter(vec![(String::from("events"), value.to_owned().into_bytes())])
+        };
+        let retry = query::QueryContext{idempotency_key: String::from("request-1"), ..Default::default()};
+        let events = || query::Query::SelectList{
+            row: String::from("user"),
+            column: String::from("events"),
+            limit: 0,
+            start: 0,
+            end: 0
+        };
+
+        database.query_now_with_context(append("login"), &retry);
+        database.query_now_with_context(append("login"), &retry);
+        assert_eq!(database.query_now(events()).to_string().matches("login").count(), 1);
+
+        // The keys survive a restart, even after the memtable has been
+        // flushed and the commit log truncated.
+        database.empty_memtable().unwrap();
+        database.idempotency = idempotency::IdempotencyCache::new(10);
+        database.load_mtable(false).unwrap();
+        database.query_now_with_context(append("login"), &retry);
+        assert_eq!(database.query_now(events()).to_string().matches("login").count(), 1);
+
+        // Other keys, or no key at all, are applied as usual.
+        database.query_now_with_context(append("login"), &query::QueryContext{
+            idempotency_key: String::from("request-2"),
+            ..Default::default()
+        });
+        database.query_now(append("login"));
+        assert_eq!(database.query_now(events()).to_string().matches("login").count(), 3);
+    }
+
+    #[test]
+    fn counts_replicas_which_have_applied_writes() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut primary = super::Base::with_storage("/primary", 1 << 20, 10, storage.clone(), clock.clone());
+        primary.load().unwrap();
+
+        // The write is applied here whatever its ack level, and the server
+        // then waits for the replicas to have it (see src/main.rs).
+        let context = query::QueryContext{ack_level: query::AckLevel::LeaderPlusOne, ..Default::default()};
+        let (result, _) = primary.query_now_with_timestamp(query::Query::new_update("user", vec![
+            query::MUpdate::new("name", "alice".as_bytes().to_vec())
+        ]), &context);
+        assert_eq!(format!("{}", result), "OK.");
+        let position = primary.replication_position().unwrap();
+        assert_eq!(primary.replicas_at(&position), (0, 0));
+
+        // Each replica has applied everything before the position it
+        // asks for next.
+        let start = super::replication::Position{generation: position.generation, offset: 0};
+        let (_, next) = primary.replicate_to("r1", &start, 1 << 20).unwrap().unwrap();
+        primary.replicate_to("r2", &start, 1 << 20).unwrap();
+        assert_eq!(primary.replicas_at(&position), (0, 2));
+        primary.replicate_to("r1", &next, 1 << 20).unwrap();
+        assert_eq!(primary.replicas_at(&position), (1, 2));
+    }
+
+    #[test]
+    fn reads_no_earlier_than_min_read_timestamp() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+
+        // A write from a clock which is ahead of this one isn't visible
+        // to ordinary reads yet.
+        let (_, now) = database.query_now_with_timestamp(query::Query::Stats, &query::QueryContext::new());
+        let later = now + 60 * 1_000_000_000;
+        database.query(query::Query::new_update("user", vec![
+            query::MUpdate::new("name", "alice".as_bytes().to_vec())
+        ]), later);
+        let select = || query::Query::new_select("user", &["name"]);
+        assert_eq!(format!("{}", database.query_now(select())), "Data: [None]");
+
+        // But a session which wrote it asks to read at least that far.
+        let session = query::QueryContext{min_read_timestamp: later, ..Default::default()};
+        let (result, timestamp) = database.query_now_with_timestamp(select(), &session);
+        assert_eq!(timestamp, later);
+        assert_eq!(format!("{}", result), r#"Data: ["alice"]"#);
+
+        // Writes always commit at the current time.
+        let (_, timestamp) = database.query_now_with_timestamp(query::Query::new_update("user", vec![
+            query::MUpdate::new("name", "bob".as_bytes().to_vec())
+        ]), &session);
+        assert!(timestamp < later);
+    }
+
+    #[test]
+    fn only_writes_while_leading() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        let update = |name: &str| query::Query::new_update("user", vec![
+            query::MUpdate::new("name", name.as_bytes().to_vec())
+        ]);
+        let not_leader = format!("{}", query::QueryResult::NotLeader);
+
+        // A replica only applies its primary's writes.
+        database.replica = true;
+        assert_eq!(format!("{}", database.query_now(update("alice"))), not_leader);
+        database.replica = false;
+
+        // With an election, writes are accepted until the lease expires.
+        database.lease_expires = Some(clock.now() + 1000);
+        assert_eq!(format!("{}", database.query_now(update("bob"))), "OK.");
+        clock.advance(1000);
+        assert_eq!(format!("{}", database.query_now(update("carol"))), not_leader);
+        assert_eq!(format!("{}", database.query_now(query::Query::new_select("user", &["name"]))), r#"Data: ["bob"]"#);
+    }
+
+    #[test]
+    fn replicas_refuse_reads_until_caught_up() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut primary = super::Base::with_storage("/primary", 1 << 20, 10, storage.clone(), clock.clone());
+        primary.load().unwrap();
+        let mut snapshot = vec![];
+        let position = primary.open_snapshot().unwrap().write_to(&mut snapshot).unwrap();
+        let mut replica = super::Base::with_storage("/replica", 1 << 20, 10, storage.clone(), clock.clone());
+        replica.install_snapshot(&mut &snapshot[..]).unwrap();
+        replica.load().unwrap();
+        replica.replica = true;
+
+        let (_, written) = primary.query_now_with_timestamp(query::Query::new_update("user", vec![
+            query::MUpdate::new("name", "alice".as_bytes().to_vec())
+        ]), &query::QueryContext::new());
+        clock.advance(1000);
+
+        // Until the replica has the session's write, its reads are refused.
+        let session = query::QueryContext{min_read_timestamp: written, ..Default::default()};
+        let select = || query::Query::new_select("user", &["name"]);
+        assert_eq!(
+            format!("{}", replica.query_now_with_context(select(), &session)),
+            format!("{}", query::QueryResult::ReplicaBehind)
+        );
+        assert_eq!(format!("{}", replica.query_now(select())), "Row not found.");
+
+        let (data, next) = primary.read_commit_log(&position, 1 << 20).unwrap().unwrap();
+        replica.apply_replicated(&data).unwrap();
+        assert_eq!(format!("{}", replica.query_now_with_context(select(), &session)), r#"Data: ["alice"]"#);
+
+        // Once it's read the whole commit log, it's caught up to the
+        // primary's clock, even without any newer writes.
+        let now = primary.replicated_through(&next).unwrap().unwrap();
+        assert!(now > written);
+        replica.caught_up(now);
+        let later = query::QueryContext{min_read_timestamp: now, ..Default::default()};
+        assert_eq!(format!("{}", replica.query_now_with_context(select(), &later)), r#"Data: ["alice"]"#);
+    }
+
+    #[test]
+    fn skips_writes_from_deposed_leaders() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+
+        database.fencing_token = 2;
+        database.str_query(r#"{"insert": {"row": "leader","set": {"term": "2"}}}"#);
+
+        // A leader from an older term can still append to the log, but
+        // its writes are thrown away when the log is replayed.
+        database.fencing_token = 1;
+        database.str_query(r#"{"insert": {"row": "deposed","set": {"term": "1"}}}"#);
+
+        mem::replace(&mut database.memtable, mtable::MTable::new());
+        database.fencing_token = 0;
+        database.load_mtable(false).unwrap();
+        assert_eq!(database.fencing_token, 2);
+        assert_eq!(database.str_query(r#"{"select": {"row": "leader","get": ["term"]}}"#), r#"Data: ["2"]"#);
+        assert_eq!(database.str_query(r#"{"select": {"row": "deposed","get": ["term"]}}"#), "Row not found.");
+    }
+
+    #[test]
+    fn can_restore_commit_log_without_fsync() {
+        let mut database = super::Base::new_stub();
+        database.fsync_policy = super::FsyncPolicy::OnFlushOnly;
+
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "unsynced_row","set": {"status": "OK"}}}"#),
+            format!("{}", query::QueryResult::Done)
+        );
+
+        // Even without an fsync, the data should be readable from the
+        // commit log as long as the OS hasn't crashed.
+        mem::replace(&mut database.memtable, mtable::MTable::new());
+        database.load_mtable(false).unwrap();
+
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "unsynced_row","get": ["status"]}}"#),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn syncs_the_last_write_on_a_timer() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.fsync_policy = super::FsyncPolicy::EveryNMs;
+        database.load().unwrap();
+
+        // The first write is synced, but the second one comes before the
+        // interval is up and no write comes after it.
+        for row in &["first_row", "second_row"] {
+            database.insert(row, vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+            clock.advance(1000);
+        }
+        clock.advance(database.fsync_interval_ms * 1_000_000);
+        database.sync_unsynced_writes().unwrap();
+        storage.crash();
+
+        let mut recovered = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        recovered.load().unwrap();
+        for row in &["first_row", "second_row"] {
+            assert_eq!(
+                format!("{}", recovered.select(row, &["status"], clock.now())),
+                r#"Data: ["OK"]"#
+            );
+        }
+    }
+
+    #[test]
+    fn can_spread_dtables_across_directories() {
+        let mut database = super::Base::new_stub();
+        let second = format!("{}/second", database.directory);
+        fs::create_dir_all(&second).unwrap();
+        database.data_directories.push(second.clone());
+
+        database.str_query(r#"{"insert": {"row": "row_one","set": {"status": "one"}}}"#);
+        database.empty_memtable().unwrap();
+        database.str_query(r#"{"insert": {"row": "row_two","set": {"status": "two"}}}"#);
+        database.empty_memtable().unwrap();
+
+        // The dtables should alternate between the two directories.
+        assert_eq!(glob(&format!("{}/*.dtable", database.directory)).unwrap().count(), 1);
+        assert_eq!(glob(&format!("{}/*.dtable", second)).unwrap().count(), 1);
+
+        // Reloading should find both dtables through the manifest.
+        database.disktables = vec![];
+        database.load_dtables().unwrap();
+        assert_eq!(database.disktables.len(), 2);
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "row_one","get": ["status"]}}"#),
+            r#"Data: ["one"]"#
+        );
+
+        // After a merge, the old dtable files should be removed.
+        database.merge_disktables().unwrap();
+        assert_eq!(
+            glob(&format!("{}/*.dtable", database.directory)).unwrap().count() +
+                glob(&format!("{}/*.dtable", second)).unwrap().count(),
+            1
+        );
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "row_two","get": ["status"]}}"#),
+            r#"Data: ["two"]"#
+        );
+    }
+
+    #[test]
+    fn can_place_dtables_in_the_least_used_directory() {
+        let mut database = super::Base::new_stub();
+        let second = format!("{}/second", database.directory);
+        fs::create_dir_all(&second).unwrap();
+        database.data_directories.push(second.clone());
+        database.dtable_placement = super::DTablePlacement::LeastUsed;
+
+        // The first dtable is large, and the next two are small, so both
+        // of them should go in the other directory.
+        for i in 0..100 {
+            database.insert(&format!("large_row_{}", i), vec![query::MUpdate::new("value", vec![0; 1024])], time::precise_time_ns());
+        }
+        database.empty_memtable().unwrap();
+        for row in &["small_row_one", "small_row_two"] {
+            database.insert(row, vec![query::MUpdate::new("value", b"small".to_vec())], time::precise_time_ns());
+            database.empty_memtable().unwrap();
+        }
+
+        assert_eq!(glob(&format!("{}/*.dtable", second)).unwrap().count(), 1);
+        assert_eq!(glob(&format!("{}/*.dtable", database.directory)).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn can_read_from_snapshot() {
+        let mut database = super::Base::new_stub();
+        let now = time::precise_time_ns();
+        database.insert("snap_row", vec![query::MUpdate::new("status", b"old".to_vec())], now);
+        let id = match database.create_snapshot(now + 50) {
+            query::QueryResult::Snapshot{id: i} => i,
+            x => panic!("unexpected result: {}", x)
+        };
+
+        // Writes and deletions after the snapshot shouldn't be visible
+        // through it, even after the data is merged on disk.
+        database.update("snap_row", vec![query::MUpdate::new("status", b"new".to_vec())], now + 100);
+        database.delete_range("snap", "", now + 150);
+        database.empty_memtable().unwrap();
+        database.merge_disktables().unwrap();
+
+        let snapshot_select = query::Query::Select{
+            row: String::from("snap_row"),
+            get: vec![String::from("status")],
+            snapshot: id,
+            include_deleted: false,
+            project: vec![],
+            strict: false,
+            column_start: String::new(),
+            column_limit: 0
+        };
+        assert_eq!(
+            format!("{}", database.query(snapshot_select, now + 200)),
+            r#"Data: ["old"]"#
+        );
+        assert_eq!(
+            format!("{}", database.query(query::Query::new_select("snap_row", &["status"]), now + 200)),
+            format!("{}", query::QueryResult::RowNotFound)
+        );
+
+        // Once the TTL runs out, the snapshot can't be read anymore.
+        let expired_select = query::Query::Select{
+            row: String::from("snap_row"),
+            get: vec![String::from("status")],
+            snapshot: id,
+            include_deleted: false,
+            project: vec![],
+            strict: false,
+            column_start: String::new(),
+            column_limit: 0
+        };
+        assert_eq!(
+            format!("{}", database.query(expired_select, now + 50 + database.snapshot_ttl_ms * 1_000_000)),
+            format!("{}", query::QueryResult::SnapshotNotFound)
+        );
+    }
+
+    #[test]
+    fn can_append_and_select_lists() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        let append = |value: &str| query::Query::Append{
+            row: String::from("user"),
+            set: Map::from_iter(vec![(String::from("events"), value.to_owned().into_bytes())])
+        };
+        let select_list = |limit: u64, start: u64, end: u64| query::Query::SelectList{
+            row: String::from("user"),
+            column: String::from("events"),
+            limit: limit,
+            start: start,
+            end: end
+        };
+
+        database.query(append("login"), 100);
+        database.query(append("view"), 200);
+
+        // Elements are kept across tables, and merged back together.
+        database.empty_memtable().unwrap();
+        database.query(append("logout"), 300);
+
+        assert_eq!(
+            format!("{}", database.query(select_list(0, 0, 0), 400)),
+            r#"List: [100: "login", 200: "view", 300: "logout"]"#
+        );
+        assert_eq!(
+            format!("{}", database.query(select_list(2, 0, 0), 400)),
+            r#"List: [200: "view", 300: "logout"]"#
+        );
+        assert_eq!(
+            format!("{}", database.query(select_list(0, 150, 250), 400)),
+            r#"List: [200: "view"]"#
+        );
+
+        // Elements appended after the read time aren't visible yet.
+        assert_eq!(
+            format!("{}", database.query(select_list(0, 0, 0), 250)),
+            r#"List: [100: "login", 200: "view"]"#
+        );
+
+        // The latest element is still what a regular select sees.
+        assert_eq!(
+            format!("{}", database.query(query::Query::new_select("user", &["events"]), 400)),
+            r#"Data: ["logout"]"#
+        );
+    }
+
+    #[test]
+    fn can_read_column_history() {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+        let set = |value: &str| query::Query::new_update("user", vec![
+            query::MUpdate::new("name", value.to_owned().into_bytes())
+        ]);
+        let history = |column: &str, limit: u64| query::Query::History{
+            row: String::from("user"),
+            column: column.to_owned(),
+            limit: limit
+        };
+
+        database.query(query::Query::new_insert("user", vec![
+            query::MUpdate::new("name", b"alice".to_vec())
+        ]), 100);
+        database.query(set("alicia"), 200);
+        database.empty_memtable().unwrap();
+        database.query(set("ali"), 300);
+
+        // Versions from the memtable and the dtables are merged in order.
+        assert_eq!(
+            format!("{}", database.query(history("name", 0), 400)),
+            r#"List: [100: "alice", 200: "alicia", 300: "ali"]"#
+        );
+        assert_eq!(
+            format!("{}", database.query(history("name", 1), 400)),
+            r#"List: [300: "ali"]"#
+        );
+        assert_eq!(
+            format!("{}", database.query(history("name", 0), 250)),
+            r#"List: [100: "alice", 200: "alicia"]"#
+        );
+        assert_eq!(format!("{}", database.query(history("email", 0), 400)), "List: []");
+        assert_eq!(
+            format!("{}", database.query(query::Query::History{
+                row: String::from("nobody"),
+                column: String::from("name"),
+                limit: 0
+            }, 400)),
+            "Row not found."
+        );
+    }
+
+    // Crash the simulated machine at every point during a flush and merge,
+    // and check that no acknowledged write is lost after recovery.
+    #[test]
+    fn recovers_from_crash_during_compaction() {
+        let policies = [super::FsyncPolicy::Always, super::FsyncPolicy::OnFlushOnly];
+        for policy in policies.iter() {
+            for n in 0..25 {
+                let storage = Arc::new(storage::MemoryStorage::new());
+                let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+
+                let mut database = super::Base::with_storage("/sim", 1 << 20, 2, storage.clone(), clock.clone());
+                database.fsync_policy = *policy;
+                database.load().unwrap();
+
+                let mut rows = vec![];
+                for batch in 0..3 {
+                    if batch > 0 {
+                        database.empty_memtable().unwrap();
+                    }
+                    for i in 0..5 {
+                        let row = format!("row_{}_{}", batch, i);
+                        database.insert(&row, vec![query::MUpdate::new("value", row.clone().into_bytes())], clock.now());
+                        clock.advance(1000);
+                        rows.push(row);
+                    }
+                }
+
+                // Without commit log syncs, the rows which were never
+                // flushed may be lost, but the flushed ones must survive
+                // the merge.
+                if *policy == super::FsyncPolicy::OnFlushOnly {
+                    rows.truncate(10);
+                }
+
+                // The third dtable forces a merge before the flush.
+                storage.fail_after(n);
+                database.empty_memtable().ok();
+                storage.crash();
+
+                let mut recovered = super::Base::with_storage("/sim", 1 << 20, 2, storage.clone(), clock.clone());
+                recovered.load().unwrap();
+                for row in &rows {
+                    assert_eq!(
+                        format!("{}", recovered.select(row, &["value"], clock.now())),
+                        format!(r#"Data: ["{}"]"#, row)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_writes_when_flush_fails() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1, 10, storage.clone(), clock.clone());
+        database.fsync_policy = super::FsyncPolicy::OnFlushOnly;
+        database.load().unwrap();
+
+        // The write is acknowledged, and the flush it triggers fails, so
+        // the row is left in the memtable instead.
+        storage.fail_after(0);
+        database.insert("row_one", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+        assert_eq!(database.disktables.len(), 0);
+        assert_eq!(
+            format!("{}", database.select("row_one", &["status"], clock.now())),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn can_archive_old_dtables() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.archive_after_days = 1;
+        database.load().unwrap();
+
+        database.insert("row_one", vec![query::MUpdate::new("status", b"old".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+
+        // Two days later, the next write moves the dtable into the archive.
+        clock.advance(2 * 24 * 3600 * 1_000_000_000);
+        database.insert("row_two", vec![query::MUpdate::new("status", b"new".to_vec())], clock.now());
+        assert_eq!(database.disktables.len(), 0);
+        assert_eq!(database.archived.len(), 1);
+        assert!(database.archived[0].filename().starts_with("/sim/archive/"));
+
+        // The archive is remembered across restarts.
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.archived.len(), 1);
+
+        // Reading a row which is only in the archive restores its dtable.
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::new_select("row_one", &["status"]))),
+            r#"Data: ["old"]"#
+        );
+        assert_eq!(database.disktables.len(), 1);
+        assert_eq!(database.archived.len(), 0);
+    }
+
+    #[test]
+    fn keeps_archived_rows_from_being_shadowed() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.archive_after_days = 1;
+        database.load().unwrap();
+
+        // The first dtable holds an old value and a range deletion, so it
+        // can't be archived, and the second one holds the newer value.
+        database.insert("row", vec![query::MUpdate::new("status", b"old".to_vec())], clock.now());
+        clock.advance(1000);
+        database.delete_range("a", "b", clock.now());
+        database.empty_memtable().unwrap();
+        clock.advance(1000);
+        database.insert("row", vec![query::MUpdate::new("status", b"new".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+
+        // Two days later, neither dtable is archived, since the older
+        // value would be read instead of the newer one.
+        clock.advance(2 * 24 * 3600 * 1_000_000_000);
+        database.archive_disktables().unwrap();
+        assert_eq!(database.disktables.len(), 2);
+        assert_eq!(database.archived.len(), 0);
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::new_select("row", &["status"]))),
+            r#"Data: ["new"]"#
+        );
+    }
+
+    #[test]
+    fn collects_garbage_hidden_by_range_deletions() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.gc_garbage_ratio = 0.5;
+        database.load().unwrap();
+
+        for row in &["a", "b", "c", "d"] {
+            database.insert(row, vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+        }
+        database.empty_memtable().unwrap();
+        database.insert("z", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+
+        // Only a quarter of the first dtable is hidden, which isn't enough.
+        clock.advance(1000);
+        database.delete_range("a", "b", clock.now());
+        assert_eq!(database.collect_garbage().unwrap(), 0);
+
+        clock.advance(1000);
+        database.delete_range("b", "c", clock.now());
+        assert_eq!(database.collect_garbage().unwrap(), 1);
+        assert_eq!(database.disktables.len(), 2);
+        assert_eq!(database.disktables[0].len(), 2);
+        assert_eq!(database.disktables[1].len(), 1);
+        assert!(database.compaction_history().last().unwrap().garbage_collection);
+
+        // The same deletions aren't applied twice.
+        assert_eq!(database.collect_garbage().unwrap(), 0);
+
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::new_select("b", &["status"]))),
+            r#"Data: [None]"#
+        );
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::new_select("c", &["status"]))),
+            r#"Data: ["OK"]"#
+        );
+
+        // The rewritten dtable is what gets loaded after a restart.
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.disktables[0].len(), 2);
+    }
+
+    #[test]
+    fn can_stub_in_directory() {
+        let directory = tempdir::TempDir::new("stub").unwrap();
+        let mut database = super::Base::new_stub_in(directory.path());
+        database.load().unwrap();
+        database.insert("row", vec![query::MUpdate::new("status", b"OK".to_vec())], 1);
+
+        // The directory belongs to the caller, so it outlives the Base.
+        drop(database);
+        let mut database = super::Base::new_stub_in(directory.path());
+        database.load().unwrap();
+        assert_eq!(
+            format!("{}", database.query_now(query::Query::new_select("row", &["status"]))),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn can_migrate_columns() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+
+        database.insert("users/1", vec![query::MUpdate::new("active", b"y".to_vec())], clock.now());
+        database.insert("orders/1", vec![query::MUpdate::new("active", b"y".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+        database.insert("users/2", vec![query::MUpdate::new("active", b"n".to_vec())], clock.now());
+
+        let migration = serde_json::from_str::<super::migration::ColumnMigration>(r#"{
+            "prefix": "users/",
+            "rename": {"active": "enabled"},
+            "values": {"enabled": {"y": "true", "n": "false"}}
+        }"#).unwrap();
+        assert_eq!(database.migrate_columns(&migration).unwrap(), 2);
+        assert_eq!(database.disktables.len(), 2);
+
+        let select = |database: &mut super::Base, row: &str, col: &str| {
+            format!("{}", database.query_now(query::Query::new_select(row, &[col])))
+        };
+        assert_eq!(select(&mut database, "users/1", "enabled"), r#"Data: ["true"]"#);
+        assert_eq!(select(&mut database, "users/2", "enabled"), r#"Data: ["false"]"#);
+        assert_eq!(select(&mut database, "users/1", "active"), "Data: [None]");
+        assert_eq!(select(&mut database, "orders/1", "active"), r#"Data: ["y"]"#);
+
+        // The migrated dtables are the ones which are loaded after a
+        // restart.
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        assert_eq!(select(&mut reloaded, "users/1", "enabled"), r#"Data: ["true"]"#);
+    }
+
+    #[test]
+    fn checks_files_without_loading_them() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+
+        database.insert("row_one", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+        database.insert("row_two", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+        database.insert("row_three", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+
+        let report = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone()).check();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!((report.dtables, report.rows), (1, 2));
+        assert_eq!((report.commit_log_entries, report.memtable_rows), (1, 1));
+
+        // Lose the end of the data file.
+        let path = database.disktables[0].filename().to_owned();
+        storage.create(&path).unwrap().sync().unwrap();
+
+        let report = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone()).check();
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.commit_log_entries, 1);
+        assert!(format!("{}", report).ends_with("FAILED: 1 problems"));
+    }
+
+    #[test]
+    fn rejects_truncated_dtables() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+
+        database.insert("row_one", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+        database.empty_memtable().unwrap();
+        assert_eq!(database.disktables[0].lookup.get_row_count(), 1);
+        assert_eq!(database.disktables[0].lookup.get_generation(), 1);
+
+        // Lose the end of the data file.
+        let path = database.disktables[0].filename().to_owned();
+        storage.create(&path).unwrap().sync().unwrap();
+
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        assert!(reloaded.load().is_err());
+    }
+
+    #[test]
+    fn splits_large_memtables_into_several_dtables() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.max_dtable_bytes = 200;
+        database.load().unwrap();
+
+        for row in &["a", "b", "c", "d", "e"] {
+            database.insert(row, vec![query::MUpdate::new("status", vec![b'x'; 100])], clock.now());
+        }
+        database.empty_memtable().unwrap();
+
+        // Each dtable covers its own key range.
+        let ranges = database.disktables.iter()
+            .map(|d| (d.lookup.get_min_key().to_owned(), d.lookup.get_max_key().to_owned()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![
+            (String::from("a"), String::from("b")),
+            (String::from("c"), String::from("d")),
+            (String::from("e"), String::from("e"))
+        ]);
+
+        // All of them are recorded in the manifest.
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.disktables.len(), 3);
+        for row in &["a", "b", "c", "d", "e"] {
+            assert_eq!(format!("{}", reloaded.select(row, &["status"], clock.now())), format!("Data: [\"{}\"]", "x".repeat(100)));
+        }
+    }
+
+    #[test]
+    fn replays_commit_logs_in_batches() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 30, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        let count = super::REPLAY_BATCH_ENTRIES * 2 + 10;
+        for i in 0..count {
+            database.insert(&format!("row{:05}", i), vec![query::MUpdate::new("status", format!("{}", i).into_bytes())], clock.now());
+        }
+
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 30, 10, storage.clone(), clock.clone());
+        assert_eq!(reloaded.load_mtable(false).unwrap(), count as u64);
+        assert_eq!(reloaded.memtable.len(), count);
+        for i in &[0, super::REPLAY_BATCH_ENTRIES, count - 1] {
+            assert_eq!(
+                format!("{}", reloaded.select(&format!("row{:05}", i), &["status"], clock.now())),
+                format!("Data: [\"{}\"]", i)
+            );
+        }
+    }
+
+    #[test]
+    fn restarts_from_memtable_checkpoints() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        database.insert("a", vec![query::MUpdate::new("status", b"old".to_vec())], clock.now());
+        database.update("a", vec![query::MUpdate::new("status", b"new".to_vec())], clock.now() + 1);
+        database.insert("b", vec![query::MUpdate::new("status", b"b".to_vec())], clock.now());
+        database.checkpoint().unwrap();
+        database.insert("c", vec![query::MUpdate::new("status", b"c".to_vec())], clock.now());
+
+        // Only the entry written after the checkpoint is replayed.
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        assert_eq!(reloaded.load_mtable(false).unwrap(), 1);
+        assert_eq!(reloaded.memtable.size, database.memtable.size);
+        let select = |database: &super::Base, row: &str, timestamp: u64| {
+            format!("{}", database.select(row, &["status"], timestamp))
+        };
+        assert_eq!(select(&reloaded, "a", clock.now()), r#"Data: ["old"]"#);
+        assert_eq!(select(&reloaded, "a", clock.now() + 1), r#"Data: ["new"]"#);
+        assert_eq!(select(&reloaded, "c", clock.now()), r#"Data: ["c"]"#);
+
+        // Once the memtable is flushed, the checkpoint is gone.
+        database.empty_memtable().unwrap();
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.memtable.len(), 0);
+        assert_eq!(select(&reloaded, "b", clock.now()), r#"Data: ["b"]"#);
+    }
+
+    #[test]
+    fn checks_without_removing_checkpoints() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        database.insert("a", vec![query::MUpdate::new("status", b"a".to_vec())], clock.now());
+        database.checkpoint().unwrap();
+
+        // Lose the commit log, so that the checkpoint covers more than it.
+        let commit_log_path = database.commit_log_path();
+        storage.create(&commit_log_path).unwrap().sync().unwrap();
+
+        let report = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone()).check();
+        assert!(report.is_ok(), "{}", report);
+        assert!(storage.open(&format!("{}.meta", database.checkpoint_path())).is_ok());
+    }
+
+    #[test]
+    fn reports_where_the_commit_log_is_corrupted() {
+        use std::io::Write;
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let clock = Arc::new(storage::SimulatedClock::new(1_000_000_000));
+        let mut database = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        database.load().unwrap();
+        database.insert("row_one", vec![query::MUpdate::new("status", b"OK".to_vec())], clock.now());
+
+        // Append the size of an entry which was never written.
+        let path = database.commit_log_path();
+        let valid = storage.open(&path).unwrap().len().unwrap();
+        let mut f = storage.append(&path).unwrap();
+        f.write_all(&[0xff, 0xff, 0, 0]).unwrap();
+        f.sync().unwrap();
+
+        let mut reloaded = super::Base::with_storage("/sim", 1 << 20, 10, storage.clone(), clock.clone());
+        match reloaded.load() {
+            Err(super::BaseError::Corrupted{path: p, offset: o, ..}) => {
+                assert_eq!(p, path);
+                assert_eq!(o, valid);
+            },
+            x => panic!("expected a corrupted commit log, got {:?}", x.err().map(|e| format!("{}", e)))
+        };
+    }
+
+    #[test]
+    fn rejects_writes_when_out_of_space() {
+        let mut database = super::Base::new_stub();
+        database.str_query(r#"{"insert": {"row": "row_one","set": {"status": "OK"}}}"#);
+
+        // No disk has this much free space, so every write is refused.
+        database.min_free_bytes = u64::MAX;
+        assert!(database.out_of_space());
+        assert_eq!(
+            database.str_query(r#"{"insert": {"row": "row_two","set": {"status": "OK"}}}"#),
+            format!("{}", query::QueryResult::OutOfSpace)
+        );
+        assert_eq!(
+            database.str_query(r#"{"update": {"row": "row_one","set": {"status": "changed"}}}"#),
+            format!("{}", query::QueryResult::OutOfSpace)
+        );
+
+        // Reads should continue to work.
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "row_one","get": ["status"]}}"#),
+            r#"Data: ["OK"]"#
+        );
+
+        match database.stats() {
+            query::QueryResult::Stats{stats: s} => assert!(s.out_of_space),
+            x => panic!("unexpected result: {}", x)
+        };
+    }
+
+    #[test]
+    fn reclaims_space_when_out_of_space() {
+        let mut database = super::Base::new_stub();
+        database.str_query(r#"{"insert": {"row": "row_one","set": {"status": "old"}}}"#);
+        database.empty_memtable().unwrap();
+        database.str_query(r#"{"update": {"row": "row_one","set": {"status": "new"}}}"#);
+        database.empty_memtable().unwrap();
+        assert_eq!(database.disktables.len(), 2);
+
+        // Running out of space merges the dtables, so that the overwritten
+        // value is dropped.
+        database.min_free_bytes = u64::MAX;
+        database.check_size_limits();
+        assert_eq!(database.disktables.len(), 1);
+        assert_eq!(
+            database.str_query(r#"{"select": {"row": "row_one","get": ["status"]}}"#),
+            r#"Data: ["new"]"#
+        );
+    }
+
+    // This function tests automatic minor compaction by setting a low
+    // memtable memory limit, then overflowing it by writing a bunch of
+    // data. If successful, it'll cause the server to write the memtable
+    // to disk.
+    #[test]
+    fn automatic_minor_compaction() {
+        let mut database = super::Base::new_stub();
+        database.disktable_limit = 2;
+        database.memtable_size_limit = 5120; // max memory: 5 KiB.
+
+        // Now we'll compose a query which doesn't overflow the data.
+        database.query_now(query::Query::new_insert(
+            "nonexistant_row",
+            vec![query::MUpdate::new("data", vec![0; 1024])]
+        ));
+
+        assert_eq!(database.memtable.size, 1028);
+
+        // Now we'll overflow it, forcing a disktable write. That'll
+        // leave us with an empty memtable and one disktable.
+        database.query_now(query::Query::new_insert(
+            "yet another row",
+            vec![query::MUpdate::new("data", vec![0; 5120])]
+        ));
+
+        assert_eq!(database.memtable.size, 0);
+        assert_eq!(database.disktables.len(), 1);
+    }
+
+    #[test]
+    fn automatic_major_compaction() {
+        let mut database = super::Base::new_stub();
+        database.disktable_limit = 2;
+        database.memtable_size_limit = 5120; // max memory: 5 KiB.
+
+        for _ in 0..10 {
+            // Overflow the memtable and force a write to the disktable.
+            database.query_now(query::Query::new_insert(
+                "yet another row",
+                vec![query::MUpdate::new("data", vec![0; 5120])]
+            ));
+
+            assert!(database.disktables.len() <= 2, "Disktable limit exceeded.");
+            assert!(database.memtable.size <= 5120, "Memtable size limit exceeded.");
+        }
+    }
+
+    #[test]
+    fn test_cases() {
+        let mut database = super::Base::new_stub();
+
+        let entries = glob(&format!("./src/testcases/*.txt")).unwrap();
+        for entry in entries {
+            let data_path = entry.unwrap();
+            let mut f = io::BufReader::new(fs::File::open(&data_path.to_str().unwrap()).unwrap());
+
+            loop {
+                let mut command = String::new();
+                let mut result  = String::new();
+                match f.read_line(&mut command) {
+                    Ok(s)   => s,
+                    Err(_)  => break
+                };
+
+                // In case we leave some blank lines, don't fail.
+                if command.trim() == "" {
+                    break;
+                }
+
+                f.read_line(&mut result).unwrap();
+
+                assert_eq!(
+                    database.str_query(command.as_str().trim()),
+                    result.trim()
+                );
+            }
+        }
+
+    }
+
+    fn create_default_database() -> super::Base {
+        let mut database = super::Base::new_stub();
+        database.load().unwrap();
+
+        for _ in 0..100 {
+            database.query_now(
+                query::Query::new_insert(random_string().as_str(), (0..100).map(|_| {
+                    query::MUpdate::new(random_string().as_str(), random_bytes())
+                }).collect::<Vec<_>>())
+            );
+        }
+
+        database.query_now(
+            query::Query::new_insert("test_row", (0..10).map(|_| {
+                query::MUpdate::new(random_string().as_str(), random_bytes())
+            }).collect::<Vec<_>>())
+        );
+
+        database.query_now(
+            query::Query::new_update("test_row", (0..10).map(|_| {
+                query::MUpdate::new("test_column", random_bytes())
+            }).collect::<Vec<_>>())
+        );
+
+        database.empty_memtable();
+
+        for _ in 0..100 {
+            database.query_now(
+                query::Query::new_insert(random_string().as_str(), (0..100).map(|_| {
+                    query::MUpdate::new(random_string().as_str(), random_bytes())
+                }).collect::<Vec<_>>())
+            );
+        }
+
+        database.query_now(
+            query::Query::new_insert("test_row", (0..10).map(|_| {
+                query::MUpdate::new(random_string().as_str(), random_bytes())
+            }).collect::<Vec<_>>())
+        );
+
+        database.query_now(
+            query::Query::new_update("test_row", (0..10).map(|_| {
+                query::MUpdate::new("test_column", random_bytes())
+            }).collect::<Vec<_>>())
+        );
+
+        database.empty_memtable();
+
+        for _ in 0..100 {
+            database.query_now(
+                query::Query::new_insert(random_string().as_str(), (0..100).map(|_| {
+                    query::MUpdate::new(random_string().as_str(), random_bytes())
+                }).collect::<Vec<_>>())
+            );
+        }
+
+        database.query_now(
+            query::Query::new_insert("test_row", (0..10).map(|_| {
+                query::MUpdate::new(random_string().as_str(), random_bytes())
+            }).collect::<Vec<_>>())
+        );
+
+        database.query_now(
+            query::Query::new_update("test_row", (0..10).map(|_| {
+                query::MUpdate::new("test_column", random_bytes())
+            }).collect::<Vec<_>>())
+        );
+
+        database
+    }
+
+    #[bench]
+    fn select(b: &mut test::Bencher) {
+        let mut database = create_default_database();
+
+        b.iter(|| {
+            database.query_now(
+                query::Query::new_select("test_row", &["test_column"])
+            );
+        });
+    }
+
+    #[bench]
+    fn insert(b: &mut test::Bencher) {
+        let mut database = create_default_database();
+
+        b.iter(|| {
+            database.query_now(
+                query::Query::new_insert(
+                    random_string().as_str(),
+                    vec![query::MUpdate::new(
+                        random_string().as_str(),
+                        random_bytes()
+                    )]
+                )
+            );
+        });
+    }
+
+    #[bench]
+    fn update(b: &mut test::Bencher) {
+        let mut database = create_default_database();
+
+        b.iter(|| {
+            database.query_now(
+                query::Query::new_update(
+                    random_string().as_str(),
+                    vec![query::MUpdate::new(
+                        random_string().as_str(),
+                        random_bytes()
+                    )]
+                )
+            );
+        });
+    }
+}
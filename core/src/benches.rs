@@ -0,0 +1,203 @@
+/*
+    benches.rs
+
+    Benchmarks for the storage engine's building blocks: the memtable,
+    dtable lookups and scans, parsing and merging rows, and compaction. The dtables
+    are kept in memory, so these measure the engine rather than the disk,
+    and (unlike benches/network.rs) don't need a server.
+*/
+
+use std::sync::Arc;
+
+use rand;
+use protobuf;
+use protobuf::Message;
+use test;
+
+use dtable;
+use families;
+use lazyrow;
+use mtable;
+use query;
+use storage;
+use storage::Storage;
+use generated::dtable::*;
+
+const ROWS: usize = 10000;
+
+fn row_key(index: usize) -> String {
+    format!("row{:08}", index)
+}
+
+fn random_bytes() -> Vec<u8> {
+    (0..25).map(|_| rand::random::<u8>()).collect()
+}
+
+fn updates(columns: usize) -> Vec<query::MUpdate> {
+    (0..columns).map(|c| query::MUpdate::new(&format!("column{}", c), random_bytes())).collect()
+}
+
+// A memtable with `rows` rows, spread out over the keyspace by `stride`,
+// so that several of them can be merged together.
+fn memtable(rows: usize, stride: usize, offset: usize) -> mtable::MTable {
+    let mut m = mtable::MTable::new();
+    for i in 0..rows {
+        m.insert(&row_key(i * stride + offset), &updates(4), (i + 1) as u64).unwrap();
+    }
+    m
+}
+
+fn write_dtable(storage: &Arc<storage::MemoryStorage>, filename: &str, m: &mtable::MTable) -> dtable::DTable {
+    let mut f = storage.create(filename).unwrap();
+    let mut h = storage.create(&format!("{}.header", filename)).unwrap();
+    let header = m.write_to_writer(&mut f, &mut h, 1, 1).unwrap();
+    dtable::DTable::from_dtableheader(storage.clone(), filename.to_owned(), header)
+}
+
+fn column(entries: usize) -> DColumn {
+    let mut timestamps = (0..entries).map(|_| rand::random::<u32>() as u64).collect::<Vec<_>>();
+    timestamps.sort();
+
+    let mut c = DColumn::new();
+    c.set_entries(protobuf::RepeatedField::from_vec(timestamps.into_iter().map(|t| {
+        let mut e = DEntry::new();
+        e.set_timestamp(t);
+        e.set_value(random_bytes());
+        e
+    }).collect()));
+    c
+}
+
+#[bench]
+fn memtable_insert(b: &mut test::Bencher) {
+    let mut m = mtable::MTable::new();
+    let mut i = 0;
+    b.iter(|| {
+        i += 1;
+        m.insert(&row_key(i), &updates(4), i as u64).unwrap();
+    });
+}
+
+#[bench]
+fn memtable_update(b: &mut test::Bencher) {
+    let mut m = memtable(ROWS, 1, 0);
+    let mut i = ROWS;
+    b.iter(|| {
+        i += 1;
+        m.update(&row_key(i % ROWS), &updates(1), i as u64).unwrap();
+    });
+}
+
+#[bench]
+fn dtable_binary_search(b: &mut test::Bencher) {
+    let storage = Arc::new(storage::MemoryStorage::new());
+    let d = write_dtable(&storage, "/bench/search.dtable", &memtable(ROWS, 1, 0));
+    let keys = (0..1000).map(|_| row_key(rand::random::<usize>() % ROWS)).collect::<Vec<_>>();
+
+    b.iter(|| {
+        for key in &keys {
+            test::black_box(d.get_row_offset(key));
+        }
+    });
+}
+
+fn encoded_row() -> Vec<u8> {
+    let mut row = DRow::new();
+    row.set_keys(protobuf::RepeatedField::from_vec((0..10).map(|c| format!("column{}", c)).collect()));
+    row.set_columns(protobuf::RepeatedField::from_vec((0..10).map(|_| column(10)).collect()));
+    row.write_to_bytes().unwrap()
+}
+
+#[bench]
+fn drow_parse(b: &mut test::Bencher) {
+    let bytes = encoded_row();
+    b.iter(|| {
+        test::black_box(protobuf::parse_from_bytes::<DRow>(&bytes).unwrap());
+    });
+}
+
+// Selecting two columns without parsing the rest of the row, as a
+// dtable select does.
+#[bench]
+fn drow_lazy_select(b: &mut test::Bencher) {
+    let bytes = encoded_row();
+    b.iter(|| {
+        let row = lazyrow::LazyRow::parse(&bytes).unwrap();
+        test::black_box(row.select(&["column2", "column7"], u64::max_value()).unwrap());
+    });
+}
+
+#[bench]
+fn dcolumn_merge(b: &mut test::Bencher) {
+    let columns = (0..10).map(|_| column(100)).collect::<Vec<_>>();
+    let merging = columns.iter().collect::<Vec<_>>();
+
+    b.iter(|| {
+        test::black_box(DColumn::from_vec(&merging));
+    });
+}
+
+// Scans read every row of a dtable in order. With readahead, most rows
+// come out of a buffer instead of a seek and read of their own.
+fn scan_dtable(b: &mut test::Bencher, readahead_bytes: u64) {
+    let storage = Arc::new(storage::MemoryStorage::new());
+    let d = write_dtable(&storage, "/bench/scan.dtable", &memtable(ROWS, 1, 0));
+
+    b.iter(|| {
+        let mut reader = d.read_ahead(readahead_bytes);
+        for i in 0..d.len() {
+            test::black_box(reader.get_row(i).unwrap());
+        }
+    });
+}
+
+#[bench]
+fn scan_row_by_row(b: &mut test::Bencher) {
+    scan_dtable(b, 0);
+}
+
+#[bench]
+fn scan_with_readahead(b: &mut test::Bencher) {
+    scan_dtable(b, 64 * 1024);
+}
+
+// Merges dtables with interleaved keys, as well as a row which appears
+// in all of them and has to be merged.
+fn compact(b: &mut test::Bencher, inputs: usize) {
+    let storage = Arc::new(storage::MemoryStorage::new());
+    let tables = (0..inputs).map(|i| {
+        let mut m = memtable(ROWS / inputs, inputs, i);
+        m.update(&row_key(0), &updates(4), 100 + i as u64).unwrap();
+        write_dtable(&storage, &format!("/bench/{}.dtable", i), &m)
+    }).collect::<Vec<_>>();
+
+    b.iter(|| {
+        test::black_box(dtable::DTable::from_vec(
+            storage.clone(),
+            "/bench/merged.dtable",
+            &tables,
+            &[],
+            &families::Families::new(),
+            dtable::CompactionOptions{
+                sync: false,
+                bytes_per_second: 0,
+                gc_before: 0,
+                created: 1,
+                max_bytes: 0,
+                drop_cache: false,
+                soft_deleted_before: 0,
+                max_fan_in: 0
+            }
+        ).unwrap());
+    });
+}
+
+#[bench]
+fn compaction(b: &mut test::Bencher) {
+    compact(b, 4);
+}
+
+#[bench]
+fn compaction_many_inputs(b: &mut test::Bencher) {
+    compact(b, 64);
+}
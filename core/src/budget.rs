@@ -0,0 +1,162 @@
+/*
+    budget.rs
+
+    Memory budgets for queries. Before a query runs, the memory it needs
+    is estimated from what it asks for: the values that it writes, or the
+    number of values it could read. A query whose estimate is over
+    max_query_bytes is refused with ResourceExhausted, and so is one which
+    would take the estimates of all the queries running at once over
+    max_total_bytes. The second kind can be retried once other queries
+    finish.
+
+    The size of a value isn't known until it's read, so each value a read
+    could return counts as value_bytes.
+*/
+
+use std::cmp;
+use std::collections::HashMap as Map;
+use std::sync::{Arc, Mutex};
+
+use base;
+use query;
+
+// Reads which don't say how many entries or columns they want are
+// estimated as this many.
+const UNLIMITED_ENTRIES: u64 = 1000;
+const COLUMNS_PER_ROW: u64 = 8;
+
+// Row keys are estimated as this many bytes.
+const KEY_BYTES: u64 = 64;
+
+#[derive(Clone)]
+pub struct MemoryBudget {
+    // The limits, or 0 for no limit.
+    pub max_query_bytes: u64,
+    pub max_total_bytes: u64,
+
+    pub value_bytes: u64,
+    in_use: Arc<Mutex<u64>>
+}
+
+// A Reservation holds a query's share of the budget, and gives it back
+// when it's dropped.
+pub struct Reservation {
+    in_use: Arc<Mutex<u64>>,
+    bytes: u64
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        *in_use -= self.bytes;
+    }
+}
+
+fn values_bytes(set: &Map<String, Vec<u8>>) -> u64 {
+    set.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum()
+}
+
+impl MemoryBudget {
+    pub fn new(max_query_bytes: u64, max_total_bytes: u64, value_bytes: u64) -> MemoryBudget {
+        MemoryBudget{
+            max_query_bytes: max_query_bytes,
+            max_total_bytes: max_total_bytes,
+            value_bytes: value_bytes,
+            in_use: Arc::new(Mutex::new(0))
+        }
+    }
+
+    pub fn estimate(&self, q: &query::Query) -> u64 {
+        let entries = |limit: u64| match limit {
+            0 => UNLIMITED_ENTRIES,
+            l => l
+        };
+        match *q {
+            // A page of columns comes back with their names.
+            query::Query::Select{ref column_start, column_limit: l, ..} if l > 0 || !column_start.is_empty() =>
+                cmp::min(entries(l), base::MAX_PAGE_COLUMNS as u64) * (KEY_BYTES + self.value_bytes),
+            query::Query::Select{ref get, ref project, ..} =>
+                (get.len() + project.len()) as u64 * self.value_bytes,
+            // The prepared columns aren't known here.
+            query::Query::Execute{..} => COLUMNS_PER_ROW * self.value_bytes,
+            // Each changed column comes back with two values.
+            query::Query::Diff{..} => 2 * COLUMNS_PER_ROW * self.value_bytes,
+            query::Query::Insert{ref row, ref set} |
+            query::Query::Update{ref row, ref set} |
+            query::Query::Append{ref row, ref set} =>
+                row.len() as u64 + values_bytes(set),
+            query::Query::Transaction{ref updates} =>
+                updates.iter().map(|(row, set)| row.len() as u64 + values_bytes(set)).sum(),
+            query::Query::SelectList{limit: l, ..} |
+            query::Query::History{limit: l, ..} =>
+                entries(l) * self.value_bytes,
+            query::Query::Scan{ref get, limit: l, ..} => {
+                let rows = cmp::min(entries(l), base::MAX_SCAN_ROWS as u64);
+                let columns = match get.len() {
+                    0 => COLUMNS_PER_ROW,
+                    n => n as u64
+                };
+                rows * (KEY_BYTES + columns * self.value_bytes)
+            },
+            query::Query::ListKeys{limit: l, ..} => entries(l) * KEY_BYTES,
+            _ => 0
+        }
+    }
+
+    // The bytes reserved by the queries which are running.
+    pub fn in_use(&self) -> u64 {
+        *self.in_use.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Reserve the query's estimate, or return the reason that it's over
+    // the budget.
+    pub fn reserve(&self, q: &query::Query) -> Result<Reservation, String> {
+        let bytes = self.estimate(q);
+        if self.max_query_bytes > 0 && bytes > self.max_query_bytes {
+            return Err(format!("query needs about {} bytes, over the limit of {}", bytes, self.max_query_bytes));
+        }
+
+        let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        if self.max_total_bytes > 0 && *in_use + bytes > self.max_total_bytes {
+            return Err(format!("{} bytes are in use by other queries, and this one needs about {} more", *in_use, bytes));
+        }
+        *in_use += bytes;
+        Ok(Reservation{
+            in_use: self.in_use.clone(),
+            bytes: bytes
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use query;
+
+    #[test]
+    fn estimates_queries() {
+        let budget = super::MemoryBudget::new(0, 0, 100);
+        assert_eq!(budget.estimate(&query::Query::new_select("row", &["a", "b"])), 200);
+        assert_eq!(budget.estimate(&query::Query::new_insert("row", vec![query::MUpdate::new("a", vec![0; 10])])), 14);
+        assert_eq!(budget.estimate(&query::Query::parse(r#"{"scan": {"start": "a", "end": "b", "get": ["x"], "limit": 10}}"#).unwrap()), 10 * (64 + 100));
+        assert_eq!(budget.estimate(&query::Query::Stats), 0);
+    }
+
+    #[test]
+    fn refuses_queries_over_budget() {
+        let budget = super::MemoryBudget::new(300, 500, 100);
+        let select = |columns: &[&str]| query::Query::new_select("row", columns);
+        assert!(budget.reserve(&select(&["a", "b", "c", "d"])).is_err());
+
+        let first = budget.reserve(&select(&["a", "b", "c"])).unwrap();
+        assert_eq!(budget.in_use(), 300);
+        assert_eq!(
+            budget.reserve(&select(&["a", "b", "c"])).err(),
+            Some(String::from("300 bytes are in use by other queries, and this one needs about 300 more"))
+        );
+
+        // Once the first query finishes, there's room again.
+        drop(first);
+        assert_eq!(budget.in_use(), 0);
+        assert!(budget.reserve(&select(&["a", "b", "c"])).is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+/*
+    compositekey.rs
+
+    Composite row keys are made up of several parts, e.g. a user, a
+    timestamp and an event name, encoded so that the keys sort in the
+    same order as the tuples of parts do. That way a range scan over a
+    prefix of the parts finds exactly the matching rows.
+
+    Strings end with "\0\x01", and any NUL character inside them is
+    written as "\0\x02", so a string always sorts before the strings
+    which it's a prefix of. Numbers are written as sixteen lowercase hex
+    digits, so they all have the same length.
+*/
+
+use std::fmt;
+
+use keys;
+
+const STRING_END: &'static str = "\0\x01";
+const ESCAPED_NUL: &'static str = "\0\x02";
+const U64_DIGITS: usize = 16;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum KeyPartType {
+    #[serde(rename = "string")]
+    String,
+    #[serde(rename = "u64")]
+    U64
+}
+
+impl fmt::Display for KeyPartType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                KeyPartType::String => "string",
+                KeyPartType::U64    => "u64"
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyPart {
+    String(String),
+    U64(u64)
+}
+
+// Keys are built up a part at a time, e.g.
+//
+//   Key::builder().prefix("events/").string("colin").u64(ts).build()
+//
+// The prefix isn't encoded, so it can be the prefix of a table.
+#[derive(Debug, Clone, Default)]
+pub struct Key {
+    encoded: String
+}
+
+impl Key {
+    pub fn builder() -> Key {
+        Key::default()
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> Key {
+        self.encoded.push_str(prefix);
+        self
+    }
+
+    pub fn string(mut self, part: &str) -> Key {
+        self.encoded.push_str(&part.replace("\0", ESCAPED_NUL));
+        self.encoded.push_str(STRING_END);
+        self
+    }
+
+    pub fn u64(mut self, part: u64) -> Key {
+        self.encoded.push_str(&format!("{:016x}", part));
+        self
+    }
+
+    pub fn build(&self) -> String {
+        self.encoded.clone()
+    }
+
+    // The [start, end) range of every key which starts with the parts
+    // so far. An empty end means that the range has no upper bound.
+    pub fn range(&self) -> (String, String) {
+        let end = keys::prefix_end(&self.encoded).unwrap_or_default();
+        (self.encoded.clone(), end)
+    }
+}
+
+// Split an encoded key, without its prefix, into parts of the given
+// types. The whole key has to be used up.
+pub fn decode(mut key: &str, types: &[KeyPartType]) -> Result<Vec<KeyPart>, String> {
+    let mut parts = vec![];
+    for (index, part_type) in types.iter().enumerate() {
+        match *part_type {
+            KeyPartType::String => {
+                let mut part = String::new();
+                loop {
+                    let nul = match key.find('\0') {
+                        Some(n) => n,
+                        None    => return Err(format!("part {} is a string without an end", index))
+                    };
+                    part.push_str(&key[..nul]);
+                    let rest = &key[nul..];
+                    if rest.starts_with(STRING_END) {
+                        key = &rest[STRING_END.len()..];
+                        break;
+                    } else if rest.starts_with(ESCAPED_NUL) {
+                        part.push('\0');
+                        key = &rest[ESCAPED_NUL.len()..];
+                    } else {
+                        return Err(format!("part {} has an unescaped NUL character", index));
+                    }
+                }
+                parts.push(KeyPart::String(part));
+            },
+            KeyPartType::U64 => {
+                let digits = match key.get(..U64_DIGITS) {
+                    Some(d) if d.bytes().all(|b| (b >= b'0' && b <= b'9') || (b >= b'a' && b <= b'f')) => d,
+                    _ => return Err(format!("part {} isn't a u64", index))
+                };
+                parts.push(KeyPart::U64(u64::from_str_radix(digits, 16).unwrap()));
+                key = &key[U64_DIGITS..];
+            }
+        }
+    }
+
+    if !key.is_empty() {
+        return Err(format!("the key has more than {} parts", types.len()));
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Key, KeyPart, KeyPartType};
+
+    #[test]
+    fn composite_keys_sort_like_their_parts() {
+        let tuples = vec![
+            ("", 0, ""),
+            ("", 7, "a"),
+            ("a", 0, ""),
+            ("a", 1, "b"),
+            ("a", 256, ""),
+            ("a\0", 0, ""),
+            ("a\0b", 0, ""),
+            ("ab", 0, ""),
+            ("b", 0, "")
+        ];
+        let keys = tuples.iter()
+            .map(|&(a, n, b)| Key::builder().prefix("t/").string(a).u64(n).string(b).build())
+            .collect::<Vec<_>>();
+
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+
+        for (key, &(a, n, b)) in keys.iter().zip(tuples.iter()) {
+            assert_eq!(
+                super::decode(&key[2..], &[KeyPartType::String, KeyPartType::U64, KeyPartType::String]),
+                Ok(vec![KeyPart::String(a.to_owned()), KeyPart::U64(n), KeyPart::String(b.to_owned())])
+            );
+        }
+    }
+
+    #[test]
+    fn composite_key_ranges_only_cover_their_prefix() {
+        let (start, end) = Key::builder().prefix("t/").string("a").range();
+        let inside = Key::builder().prefix("t/").string("a").u64(5).build();
+        assert!(start <= inside && inside < end);
+        for outside in &["", "a\0", "ab", "b"] {
+            let key = Key::builder().prefix("t/").string(outside).u64(5).build();
+            assert!(key < start || key >= end);
+        }
+
+        let (start, end) = Key::builder().string("a").u64(0xff).range();
+        assert!(Key::builder().string("a").u64(0xff).string("x").build() < end);
+        assert!(Key::builder().string("a").u64(0x100).build() >= end);
+        assert!(start < end);
+    }
+
+    #[test]
+    fn can_reject_malformed_composite_keys() {
+        let types = [KeyPartType::String, KeyPartType::U64];
+        assert!(super::decode("a\0\x010000000000000001", &types).is_ok());
+        assert!(super::decode("a|1", &types).is_err());
+        assert!(super::decode("a\0\x01000000000000001", &types).is_err());
+        assert!(super::decode("a\0\x010000000000000001x", &types).is_err());
+        assert!(super::decode("a\0\x03\0\x010000000000000001", &types).is_err());
+        assert!(super::decode("a\0\x01000000000000000G", &types).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+/*
+    database.rs
+
+    The Database is the entry point for applications which embed the
+    storage engine. It wraps a Base in a lock, so that it can be shared
+    between threads.
+*/
+
+use std;
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use time;
+
+use base;
+use query;
+
+pub struct Database {
+    base: Mutex<base::Base>
+}
+
+impl Database {
+    // Open the database stored in the directory, creating it if it doesn't
+    // exist yet, and load its state from disk.
+    pub fn open(directory: &str) -> Result<Database, base::BaseError> {
+        std::fs::create_dir_all(directory).map_err(|e| base::BaseError::Io{
+            path: directory.to_owned(),
+            error: e
+        })?;
+
+        let mut b = base::Base::new(directory, 32 * (1 << 20), 2);
+        b.load()?;
+        Ok(Database::from_base(b))
+    }
+
+    // Wrap a Base which has already been configured and loaded.
+    pub fn from_base(b: base::Base) -> Database {
+        Database{
+            base: Mutex::new(b)
+        }
+    }
+
+    // Start a thread which syncs the commit log every fsync_interval_ms
+    // under the EveryNMs policy, so that a write is never left unsynced
+    // for much longer than that just because no write came after it. The
+    // thread stops once the database is dropped.
+    pub fn sync_in_background(database: &Arc<Database>) {
+        let database = Arc::downgrade(database);
+        thread::spawn(move || loop {
+            let interval_ms = match database.upgrade() {
+                Some(d) => {
+                    let mut base = d.lock();
+                    if let Err(e) = base.sync_unsynced_writes() {
+                        error!("Unable to sync the commit log: {}", e);
+                    }
+                    std::cmp::max(base.fsync_interval_ms, 1)
+                },
+                None => return
+            };
+            thread::sleep(Duration::from_millis(interval_ms));
+        });
+    }
+
+    // Run a query with timestamp set to now.
+    pub fn query(&self, q: query::Query) -> query::QueryResult {
+        self.lock().query_now(q)
+    }
+
+    // Run a query with timestamp set to now, as part of the request
+    // described by the context.
+    pub fn query_with_context(&self, q: query::Query, context: &query::QueryContext) -> query::QueryResult {
+        self.lock().query_now_with_context(q, context)
+    }
+
+    // Like query_with_context, but also returns the timestamp that the
+    // query ran at.
+    pub fn query_with_timestamp(&self, q: query::Query, context: &query::QueryContext) -> (query::QueryResult, u64) {
+        self.lock().query_now_with_timestamp(q, context)
+    }
+
+    // Like query_with_timestamp, but also returns what the query cost to
+    // run, including how long it waited for the lock.
+    pub fn query_with_cost(&self, q: query::Query, context: &query::QueryContext) -> (query::QueryResult, u64, query::QueryCost) {
+        let started = time::precise_time_ns();
+        let mut base = self.lock();
+        let lock_ns = time::precise_time_ns() - started;
+        let (result, timestamp, mut cost) = base.query_now_with_cost(q, context);
+        cost.lock_ns = lock_ns;
+        (result, timestamp, cost)
+    }
+
+    // Get direct access to the underlying Base, e.g. to change its
+    // configuration or check on its health. If a thread panicked while
+    // holding the lock, the database keeps serving instead of failing
+    // every query after it, although the query which panicked may have
+    // only been partly applied.
+    pub fn lock(&self) -> MutexGuard<base::Base> {
+        self.base.lock().unwrap_or_else(|e| {
+            warn!("recovering the database lock after a panic");
+            e.into_inner()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::sync::Arc;
+    use base;
+    use query;
+    use tempdir;
+
+    #[test]
+    fn can_embed_database() {
+        let directory = tempdir::TempDir::new("embedded").unwrap();
+        let database = super::Database::open(directory.path()).unwrap();
+
+        assert_eq!(
+            format!("{}", database.query(query::Query::new_insert(
+                "embedded_row",
+                vec![query::MUpdate::new("status", b"OK".to_vec())]
+            ))),
+            format!("{}", query::QueryResult::Done)
+        );
+
+        // Reopening the database should recover the row from the commit log.
+        drop(database);
+        let database = super::Database::open(directory.path()).unwrap();
+        assert_eq!(
+            format!("{}", database.query(query::Query::new_select("embedded_row", &["status"]))),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn survives_a_poisoned_lock() {
+        let mut b = base::Base::new_stub();
+        b.load().unwrap();
+        let database = Arc::new(super::Database::from_base(b));
+
+        let d = database.clone();
+        assert!(thread::spawn(move || {
+            let _guard = d.lock();
+            panic!("panicked while holding the lock");
+        }).join().is_err());
+
+        assert_eq!(
+            format!("{}", database.query(query::Query::new_insert(
+                "row",
+                vec![query::MUpdate::new("status", b"OK".to_vec())]
+            ))),
+            format!("{}", query::QueryResult::Done)
+        );
+    }
+}
@@ -0,0 +1,1758 @@
+use std::io;
+use std::io::Seek;
+use std::io::Read;
+use std::io::Write;
+use std;
+use std::fmt;
+use std::iter::FromIterator;
+use std::thread;
+use std::time::Duration;
+use std::sync::Arc;
+use std::cell::Cell;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use time;
+
+use protobuf;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use mtable;
+use lazyrow;
+use families;
+use keyindex;
+use kmerge::{KMerge, Mergeable};
+use storage::{Storage, StorageFile};
+use generated::dtable::*;
+
+// A ReadAhead reads rows out of a dtable in key order, as a scan does.
+// Rather than seeking to each row and reading it on its own, it reads at
+// least readahead_bytes at a time into a buffer, so that the rows after
+// it come from memory. A readahead_bytes of zero reads each row alone.
+pub struct ReadAhead<'a> {
+    table: &'a DTable,
+    file: Option<Box<StorageFile>>,
+    readahead_bytes: u64,
+
+    // The buffer holds the data starting at this offset in the file.
+    buffer: Vec<u8>,
+    buffer_start: u64
+}
+
+pub struct DTable {
+    filename: String,
+    storage: Arc<Storage>,
+    pub lookup: DTableHeader,
+    index: keyindex::FenceIndex,
+
+    // If set, the entries aren't kept in the lookup, and are read from
+    // the header file as they're needed.
+    paged: Option<keyindex::PagedIndex>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    bytes_read: Cell<u64>,
+
+    // How many reads have failed, in total and since the last one which
+    // succeeded.
+    read_errors: Cell<u64>,
+    failed_reads: Cell<u64>
+}
+
+#[derive(Debug)]
+pub enum TError {
+    // The file couldn't be read or written.
+    Io(io::Error),
+
+    // The data at the offset in the file doesn't make sense.
+    Corrupted{offset: u64, reason: String},
+    NotFound,
+    AlreadyExists
+}
+
+impl fmt::Display for TError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TError::Io(ref e)                           => write!(f, "{}", e),
+            TError::Corrupted{offset, ref reason}       => write!(f, "{} at offset {}", reason, offset),
+            TError::NotFound                            => write!(f, "not found"),
+            TError::AlreadyExists                       => write!(f, "already exists")
+        }
+    }
+}
+
+// Data is copied between dtables in chunks of this size, so that the
+// throttle gets a chance to pause the compaction regularly.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+// Rows are written to new dtables through a buffer of this size, rather
+// than with a write call for each row.
+const WRITE_BUFFER_SIZE: usize = 1 << 20;
+
+// Reads which fail with an I/O error are retried this many times, after
+// waiting READ_RETRY_BACKOFF_MS, and then twice as long again before each
+// retry after that. Reads of a degraded dtable aren't retried, since the
+// backoff is spent holding the database lock.
+pub const READ_RETRIES: u32 = 3;
+const READ_RETRY_BACKOFF_MS: u64 = 10;
+
+// Once this many reads of a dtable in a row have failed, it's degraded.
+const DEGRADED_AFTER_FAILED_READS: u64 = 3;
+
+// When a compaction is asked to keep its files out of the page cache, it
+// drops them from the cache each time it writes this many bytes.
+const DROP_CACHE_INTERVAL: u64 = 8 * (1 << 20);
+
+// CompactionOptions controls how a set of dtables is merged. If sync is
+// set, the new files are flushed to disk before returning. If
+// bytes_per_second is non-zero, writes are slowed down to that rate so
+// that the compaction doesn't starve foreground reads of disk bandwidth.
+// Tombstones newer than gc_before are kept rather than applied, since an
+// open snapshot may still need to read the data that they hide. The
+// created timestamp is recorded in the merged dtable's header. If
+// max_bytes is non-zero, the output is split into dtables of about
+// that size. If drop_cache is set, the files being read and written are
+// regularly dropped from the page cache, so that the compaction doesn't
+// evict the data which reads actually need. Rows which were soft deleted
+// before soft_deleted_before are dropped, unless it's zero. A row found
+// in more than max_fan_in dtables is merged a batch of that many at a
+// time, unless it's zero.
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionOptions {
+    pub sync: bool,
+    pub bytes_per_second: u64,
+    pub gc_before: u64,
+    pub created: u64,
+    pub max_bytes: u64,
+    pub drop_cache: bool,
+    pub soft_deleted_before: u64,
+    pub max_fan_in: usize
+}
+
+// What a merge ran into: the largest number of dtables that a single row
+// was merged from, and how many rows had to be merged in batches because
+// they were in more than max_fan_in dtables.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MergeStats {
+    pub max_fan_in: u64,
+    pub batched_rows: u64
+}
+
+// The Throttle keeps track of how much data has been written and sleeps
+// whenever the writer gets ahead of the configured rate.
+struct Throttle {
+    bytes_per_second: u64,
+    started: u64,
+    written: u64
+}
+
+impl Throttle {
+    fn new(bytes_per_second: u64) -> Throttle {
+        Throttle{
+            bytes_per_second: bytes_per_second,
+            started: time::precise_time_ns(),
+            written: 0
+        }
+    }
+
+    fn consume(&mut self, bytes: u64) {
+        if self.bytes_per_second == 0 {
+            return;
+        }
+
+        self.written += bytes;
+        let expected = (self.written as f64 / self.bytes_per_second as f64 * 1e9) as u64;
+        let elapsed = time::precise_time_ns() - self.started;
+        if expected > elapsed {
+            let wait = expected - elapsed;
+            thread::sleep(Duration::new(wait / 1_000_000_000, (wait % 1_000_000_000) as u32));
+        }
+    }
+}
+
+// Copy everything from the reader to the writer, one chunk at a time,
+// returning the number of bytes copied.
+fn copy_chunked<R: Read, W: Write>(reader: &mut R, writer: &mut W, buf: &mut [u8], throttle: &mut Throttle) -> Result<u64, io::Error> {
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(buf) {
+            Ok(0)   => return Ok(copied),
+            Ok(n)   => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e)  => return Err(e)
+        };
+
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+        throttle.consume(n as u64);
+    }
+}
+
+// Protobuf messages can only fail to be written if the file can't be.
+fn write_error(e: protobuf::ProtobufError) -> TError {
+    match e {
+        protobuf::ProtobufError::IoError(e) => TError::Io(e),
+        e => TError::Io(io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+    }
+}
+
+// Check that a row read from the dtable at the offset parsed, and is
+// usable.
+fn check_row(row: protobuf::ProtobufResult<DRow>, filename: &str, offset: u64) -> Result<DRow, TError> {
+    // Compressed family blocks are expanded back into columns first.
+    match row.map_err(io::Error::from).and_then(families::expand) {
+        Ok(ref r) if !r.is_valid() => Err(TError::Corrupted{
+            offset: offset,
+            reason: format!("row columns are out of order in {}", filename)
+        }),
+        Ok(r)   => Ok(r),
+        Err(e)  => Err(TError::Corrupted{
+            offset: offset,
+            reason: format!("unable to parse row in {}: {}", filename, e)
+        })
+    }
+}
+
+impl std::convert::From<std::io::Error> for TError {
+    fn from(e: std::io::Error) -> Self {
+        TError::Io(e)
+    }
+}
+
+impl<'a> ReadAhead<'a> {
+    // Read the row at the index in the dtable's header.
+    pub fn get_row(&mut self, index: usize) -> Result<DRow, TError> {
+        let result = self.read_row(index);
+        self.table.record_read(&result);
+        result
+    }
+
+    fn read_row(&mut self, index: usize) -> Result<DRow, TError> {
+        let table = self.table;
+        let region = table.get_offset_from_index(index)?;
+        let end = match region.length {
+            Some(n) => region.start + n,
+            None    => table.lookup.get_total_bytes()
+        };
+        table.hits.set(table.hits.get() + 1);
+        table.bytes_read.set(table.bytes_read.get() + end.saturating_sub(region.start));
+
+        // Headers written before the total size was recorded don't say
+        // where the last row ends, so it's read straight from the file.
+        if end <= region.start {
+            let mut file = table.get_reader()?;
+            file.seek(io::SeekFrom::Start(region.start))?;
+            return check_row(protobuf::parse_from_reader::<DRow>(&mut file), &table.filename, region.start);
+        }
+
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if region.start < self.buffer_start || end > buffer_end {
+            self.fill(region.start, end)?;
+        }
+
+        let from = (region.start - self.buffer_start) as usize;
+        let to = (end - self.buffer_start) as usize;
+        check_row(protobuf::parse_from_bytes::<DRow>(&self.buffer[from..to]), &table.filename, region.start)
+    }
+
+    // Read [start, end) into the buffer, along with as much of the data
+    // after it as fits in readahead_bytes.
+    fn fill(&mut self, start: u64, end: u64) -> Result<(), TError> {
+        if self.file.is_none() {
+            self.file = Some(self.table.get_reader()?);
+        }
+        let file = self.file.as_mut().unwrap();
+
+        let available = std::cmp::max(self.table.lookup.get_total_bytes(), end) - start;
+        let length = std::cmp::min(std::cmp::max(end - start, self.readahead_bytes), available);
+        self.buffer.clear();
+        self.buffer.resize(length as usize, 0);
+        self.buffer_start = start;
+        file.seek(io::SeekFrom::Start(start))?;
+        file.read_exact(&mut self.buffer)?;
+        Ok(())
+    }
+}
+
+impl RangeTombstone {
+    // Check whether a row key falls inside of the deleted range. An empty
+    // end key means that the range has no upper bound.
+    pub fn covers(&self, key: &str) -> bool {
+        key >= self.get_start() && (self.get_end().is_empty() || key < self.get_end())
+    }
+}
+
+// Find the most recent of the tombstones covering the row, as seen at the
+// provided timestamp. Returns zero if the row was never deleted.
+// Fill in the summary fields of a header from its entries, so that the
+// size and key range of a dtable are known without reading its data.
+pub fn summarize(header: &mut DTableHeader, total_bytes: u64, created: u64, generation: u64) {
+    let (min_key, max_key) = match (header.get_entries().first(), header.get_entries().last()) {
+        (Some(first), Some(last)) => (first.get_key().to_owned(), last.get_key().to_owned()),
+        _ => (String::new(), String::new())
+    };
+    let row_count = header.get_entries().len() as u64;
+
+    header.set_row_count(row_count);
+    header.set_min_key(min_key);
+    header.set_max_key(max_key);
+    header.set_total_bytes(total_bytes);
+    header.set_created(created);
+    header.set_generation(generation);
+}
+
+// A new dtable's data file, written through a large buffer.
+type Output = io::BufWriter<Box<StorageFile>>;
+
+// Create a new dtable's data file, with space reserved on disk for about
+// the number of bytes expected to be written to it, so that it's laid
+// out in one piece rather than growing a write at a time. Reserving
+// space is only advice to the filesystem, so failures are just logged.
+fn create_output(storage: &Arc<Storage>, filename: &str, expected_bytes: u64) -> Result<Output, io::Error> {
+    let f = storage.create(filename)?;
+    if expected_bytes > 0 {
+        if let Err(e) = f.allocate(expected_bytes) {
+            debug!("Unable to reserve {} bytes for {}: {}", expected_bytes, filename, e);
+        }
+    }
+    Ok(io::BufWriter::with_capacity(WRITE_BUFFER_SIZE, f))
+}
+
+// Write out whatever is buffered, and give back the space that was
+// reserved but not used.
+fn flush_output(f_out: &mut Output) -> Result<(), io::Error> {
+    f_out.flush()?;
+    f_out.get_ref().trim()
+}
+
+// Write the header of a merged dtable once its rows have been written,
+// and flush both files to disk if the options ask for it. The retained
+// tombstones only go into the first of the merged dtables.
+fn finish_output(storage: &Arc<Storage>, output: &mut DTable, f_out: &mut Output, total_bytes: u64, tombstones: &[&RangeTombstone], generation: u64, options: CompactionOptions) -> Result<(), TError> {
+    flush_output(f_out)?;
+    output.lookup.set_tombstones(protobuf::RepeatedField::from_iter(
+        tombstones.iter().map(|&t| t.clone())
+    ));
+    summarize(&mut output.lookup, total_bytes, options.created, generation);
+    output.reindex();
+    let mut header_file = storage.create(&format!("{}.header", output.filename()))?;
+    keyindex::write_header(&output.lookup, &mut header_file).map_err(write_error)?;
+
+    if options.sync {
+        header_file.sync()?;
+        f_out.get_ref().sync()?;
+    }
+    if options.drop_cache {
+        drop_caches(&[], Some(&**f_out.get_ref()));
+    }
+    Ok(())
+}
+
+// Drop the files used by a compaction from the page cache. This is only
+// advice to the operating system, so failures are just logged.
+fn drop_caches(inputs: &[Box<StorageFile>], output: Option<&StorageFile>) {
+    for f in inputs.iter().map(|f| &**f).chain(output) {
+        if let Err(e) = f.drop_cache() {
+            debug!("Unable to drop compaction files from the page cache: {}", e);
+        }
+    }
+}
+
+// Check that the summary in a header agrees with its entries. Headers
+// written before the summary existed have no row count, and are
+// accepted as they are.
+fn summary_is_valid(header: &DTableHeader) -> bool {
+    let entries = header.get_entries();
+    if header.get_row_count() == 0 {
+        return true;
+    }
+
+    header.get_row_count() == entries.len() as u64 &&
+        entries.first().map(|e| e.get_key()) == Some(header.get_min_key()) &&
+        entries.last().map(|e| e.get_key()) == Some(header.get_max_key()) &&
+        entries.last().map(|e| e.get_offset() < header.get_total_bytes()).unwrap_or(false)
+}
+
+// Rows are soft deleted by writing the time that they were deleted to
+// this column, and restored by writing an empty value to it.
+pub const SOFT_DELETE_COLUMN: &'static str = "_deleted";
+
+// Returns the time that a row was soft deleted, given the value of its
+// soft delete column, or None if it isn't deleted.
+pub fn soft_deleted_at(marker: &[u8]) -> Option<u64> {
+    std::str::from_utf8(marker).ok().and_then(|s| s.parse().ok())
+}
+
+pub fn deleted_at<'a, I>(tombstones: I, row: &str, timestamp: u64) -> u64
+    where I: Iterator<Item=&'a RangeTombstone>
+{
+    tombstones
+        .filter(|t| t.get_timestamp() <= timestamp && t.covers(row))
+        .map(|t| t.get_timestamp())
+        .max()
+        .unwrap_or(0)
+}
+
+impl DColumn {
+    pub fn get_latest_value(&self) -> Result<DEntry, TError> {
+        self.get_value(std::u64::MAX)
+    }
+
+    pub fn get_value(&self, timestamp: u64) -> Result<DEntry, TError> {
+        let entries = self.get_entries();
+        match entries.len() {
+            0 => Err(TError::NotFound),
+            n => {
+                let mut index = n-1;
+                for i in (0..n).rev() {
+                    if entries[i].get_timestamp() <= timestamp {
+                        index = i;
+                        break;
+                    }
+                }
+                Ok(entries[index].clone())
+            }
+        }
+    }
+
+    // This function merges together a series of DColumns into a single one.
+    pub fn from_vec(cols: &[&DColumn]) -> DColumn {
+        DColumn::merge(cols.iter().map(|&c| c.clone()).collect())
+    }
+
+    // Merges DColumns which are no longer needed into a single one. The
+    // entries are moved rather than copied, so merging doesn't need any
+    // more memory than the columns already take up.
+    pub fn merge(cols: Vec<DColumn>) -> DColumn {
+        let output = KMerge::new(
+            cols.into_iter()
+                .map(|mut c| c.take_entries().into_vec().into_iter())
+                .collect::<Vec<_>>()
+        ).map(|(_, e)| e).collect::<Vec<_>>();
+
+        let mut d = DColumn::new();
+        d.set_entries(protobuf::RepeatedField::from_vec(output));
+        d
+    }
+}
+
+impl DRow {
+    // A DRow read from disk is only usable if it has a column for every
+    // key, and the keys are sorted so that they can be binary searched.
+    pub fn is_valid(&self) -> bool {
+        self.get_keys().len() == self.get_columns().len() &&
+            self.get_keys().windows(2).all(|w| w[0] < w[1])
+    }
+
+    pub fn get_column(&self, key: &str) -> Result<&DColumn, TError> {
+        let keys = self.get_keys();
+        let mut l: i32 = 0;
+        let mut r: i32 = keys.len() as i32 - 1;
+
+        while l <= r {
+            let index = (l + r) >> 1;
+            match &keys[index as usize] {
+                k if key == k => return Ok(&self.get_columns()[index as usize]),
+                k if key > k  => l = index + 1,
+                _             => r = index - 1
+            }
+        }
+        Err(TError::NotFound)
+    }
+
+    pub fn get_latest_value(&self, key: &str) -> Result<DEntry, TError> {
+        self.get_column(key)?.get_latest_value()
+    }
+
+    pub fn get_value(&self, key: &str, timestamp: u64) -> Result<DEntry, TError> {
+        self.get_column(key)?.get_value(timestamp)
+    }
+
+    // Returns the time that the row was soft deleted, if it currently is.
+    pub fn soft_deleted_at(&self) -> Option<u64> {
+        self.get_latest_value(SOFT_DELETE_COLUMN).ok()
+            .and_then(|e| soft_deleted_at(e.get_value()))
+    }
+
+    pub fn select(&self, cols: &[&str], timestamp: u64) -> Vec<Option<DEntry>> {
+        cols.iter().map(|col| {
+            match self.get_value(col, timestamp) {
+                Ok(v)   => Some(v),
+                Err(_)  => None
+            }
+        }).collect::<Vec<_>>()
+    }
+
+    // Create a copy of the DRow without any entries written at or before
+    // the timestamp. Columns which end up empty are dropped.
+    pub fn purge(&self, timestamp: u64) -> DRow {
+        let mut keys = vec![];
+        let mut cols = vec![];
+        for (key, col) in self.get_keys().iter().zip(self.get_columns().iter()) {
+            let entries = col.get_entries()
+                .iter()
+                .filter(|e| e.get_timestamp() > timestamp)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if entries.is_empty() {
+                continue;
+            }
+
+            let mut c = DColumn::new();
+            c.set_entries(protobuf::RepeatedField::from_vec(entries));
+            keys.push(key.to_owned());
+            cols.push(c);
+        }
+
+        let mut d = DRow::new();
+        d.set_columns(protobuf::RepeatedField::from_vec(cols));
+        d.set_keys(protobuf::RepeatedField::from_vec(keys));
+        d
+    }
+
+    // Merge a list of DRows with the same key together into a new DRow
+    // with the same key
+    pub fn from_vec(rows: &[DRow]) -> DRow {
+        DRow::merge(rows.to_vec())
+    }
+
+    // Merges DRows with the same key which are no longer needed into a
+    // new DRow. Like DColumn::merge, the columns are moved into the new
+    // row instead of being copied.
+    pub fn merge(rows: Vec<DRow>) -> DRow {
+        let mut columns = KMerge::new(
+            rows.into_iter()
+                .map(|mut r| {
+                    let keys = r.take_keys().into_vec();
+                    let cols = r.take_columns().into_vec();
+                    keys.into_iter().zip(cols.into_iter())
+                })
+                .collect::<Vec<_>>()
+        );
+
+        let mut output_keys = vec![];
+        let mut output_cols = vec![];
+
+        // Several DRows may share the same column, in which case those
+        // columns have to be merged. Otherwise the column is just moved
+        // into the new row.
+        while let Some(group) = columns.next_group() {
+            let mut key = String::new();
+            let mut cols = Vec::with_capacity(group.len());
+            for (_, (k, col)) in group {
+                key = k;
+                cols.push(col);
+            }
+            output_keys.push(key);
+            output_cols.push(match cols.len() {
+                1 => cols.pop().unwrap(),
+                _ => DColumn::merge(cols)
+            });
+        }
+
+        let mut d = DRow::new();
+        d.set_columns(protobuf::RepeatedField::from_vec(output_cols));
+        d.set_keys(protobuf::RepeatedField::from_vec(output_keys));
+        d
+    }
+}
+
+// When merging, a column's entries are ordered by timestamp, and the
+// columns of rows and the rows of dtables by key.
+impl Mergeable for DEntry {
+    fn merge_cmp(&self, other: &DEntry) -> Ordering {
+        self.get_timestamp().cmp(&other.get_timestamp())
+    }
+}
+
+impl Mergeable for (String, DColumn) {
+    fn merge_cmp(&self, other: &(String, DColumn)) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<'a> Mergeable for (usize, &'a DTableHeaderEntry) {
+    fn merge_cmp(&self, other: &(usize, &'a DTableHeaderEntry)) -> Ordering {
+        self.1.get_key().cmp(other.1.get_key())
+    }
+}
+
+impl std::fmt::Display for DRow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DRow: {{ {} }}",
+                self.get_keys()
+                .iter()
+                .map(|s| format!("{}: {:?}", s, self.get_latest_value(s).unwrap().get_value()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+pub struct DataRegion {
+    pub start: u64,
+    pub length: Option<u64>,
+    pub families: Vec<FamilyBlock>
+}
+
+// The region of the row at the index in the header entries. The last
+// row's length isn't known from the entries alone.
+fn region_at(entries: &[DTableHeaderEntry], index: usize) -> DataRegion {
+    let offset = entries[index].get_offset();
+    DataRegion{
+        start:  offset,
+        length: entries.get(index + 1).map(|e| e.get_offset() - offset),
+        families: entries[index].get_families().to_vec()
+    }
+}
+
+impl DTable {
+    // Load a DTable, reading its header into memory.
+    pub fn new(storage: Arc<Storage>, filename: String) -> Result<DTable, io::Error> {
+        let mut header = storage.open(&format!("{}.header", filename))?;
+        let mut lookup = protobuf::parse_from_reader::<DTableHeader>(&mut header)?;
+        keyindex::expand(&mut lookup).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Lookups binary search the keys, and row lengths are computed from
+        // the gaps between offsets, so a header where either is out of order
+        // can't be used.
+        let entries = lookup.get_entries();
+        if entries.windows(2).any(|w| w[0].get_key() >= w[1].get_key() || w[0].get_offset() > w[1].get_offset()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "dtable header is not in order"));
+        }
+        if !summary_is_valid(&lookup) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "dtable header summary doesn't match its entries"));
+        }
+
+        Ok(DTable::from_dtableheader(storage, filename, lookup))
+    }
+
+    pub fn from_dtableheader(storage: Arc<Storage>, filename: String, header: DTableHeader) -> DTable {
+        DTable{
+            filename: filename,
+            storage: storage,
+            index: keyindex::FenceIndex::new(header.get_entries()),
+            paged: None,
+            lookup: header,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            bytes_read: Cell::new(0),
+            read_errors: Cell::new(0),
+            failed_reads: Cell::new(0)
+        }
+    }
+
+    // Load a DTable, only keeping the fences of its header in memory
+    // (see keyindex.rs). Up to cache_blocks blocks of the header are
+    // cached once they've been read.
+    pub fn open_paged(storage: Arc<Storage>, filename: String, cache_blocks: usize) -> Result<DTable, io::Error> {
+        let mut d = DTable::from_dtableheader(storage, filename, DTableHeader::new());
+        d.page_out(cache_blocks)?;
+        Ok(d)
+    }
+
+    // Drop the entries of the header from memory, reading them from the
+    // header file as they're needed from now on.
+    pub fn page_out(&mut self, cache_blocks: usize) -> Result<(), io::Error> {
+        let (lookup, paged) = keyindex::PagedIndex::load(
+            self.storage.clone(),
+            &format!("{}.header", self.filename),
+            cache_blocks
+        )?;
+        self.lookup = lookup;
+        self.reindex();
+        self.paged = Some(paged);
+        Ok(())
+    }
+
+    pub fn is_paged(&self) -> bool {
+        self.paged.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        match self.paged {
+            Some(ref p) => p.len(),
+            None        => self.lookup.get_entries().len()
+        }
+    }
+
+    // Rebuild the index of the header entries. This has to be done after
+    // the entries are changed, e.g. once a merge has added them all.
+    fn reindex(&mut self) {
+        self.index = keyindex::FenceIndex::new(self.lookup.get_entries());
+    }
+
+    // Returns the index of the first row key which is greater than or
+    // equal to the provided key.
+    pub fn lower_bound(&self, key: &str) -> Result<usize, io::Error> {
+        match self.paged {
+            Some(ref p) => p.lower_bound(key),
+            None        => Ok(self.index.lower_bound(self.lookup.get_entries(), key))
+        }
+    }
+
+    // Returns the header entries, in order, starting at the index. A
+    // paged header has to read them from disk.
+    pub fn entries_after(&self, index: usize) -> Result<Cow<[DTableHeaderEntry]>, io::Error> {
+        match self.paged {
+            Some(ref p) => p.entries_from(index).map(Cow::Owned),
+            None        => Ok(Cow::Borrowed(&self.lookup.get_entries()[index..]))
+        }
+    }
+
+    // Returns all of the header entries, in order.
+    pub fn entries(&self) -> Result<Cow<[DTableHeaderEntry]>, io::Error> {
+        self.entries_after(0)
+    }
+
+    // Returns the header entries, in order, starting at the provided key.
+    pub fn entries_from(&self, start: &str) -> Result<Cow<[DTableHeaderEntry]>, io::Error> {
+        let index = self.lower_bound(start)?;
+        self.entries_after(index)
+    }
+
+    // Returns the header with all of its entries.
+    fn full_header(&self) -> Result<DTableHeader, io::Error> {
+        let mut header = self.lookup.clone();
+        if self.paged.is_some() {
+            header.set_entries(protobuf::RepeatedField::from_vec(self.entries()?.into_owned()));
+        }
+        Ok(header)
+    }
+
+    // Returns false if the key is outside of the range of keys that the
+    // header says this DTable holds. A header without a summary can't
+    // rule anything out.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.lookup.get_row_count() == 0 ||
+            (key >= self.lookup.get_min_key() && key <= self.lookup.get_max_key())
+    }
+
+    // Returns false if none of the keys in [start, end) can be in this
+    // DTable. An empty end key means the range has no upper bound.
+    pub fn may_overlap(&self, start: &str, end: &str) -> bool {
+        self.lookup.get_row_count() == 0 ||
+            (self.lookup.get_max_key() >= start && (end.is_empty() || self.lookup.get_min_key() < end))
+    }
+
+    // Returns false if none of this DTable's keys can be in the other
+    // DTable's key range. Without a summary, they're assumed to overlap.
+    pub fn may_overlap_keys(&self, other: &DTable) -> bool {
+        self.lookup.get_row_count() == 0 || other.lookup.get_row_count() == 0 ||
+            (self.lookup.get_min_key() <= other.lookup.get_max_key() &&
+                other.lookup.get_min_key() <= self.lookup.get_max_key())
+    }
+
+    // The fraction of this DTable's rows whose keys are in the other
+    // DTable's key range (roughly: a row equal to the other's max key
+    // isn't counted). If either header has no summary, or this one's
+    // can't be searched, they're assumed to overlap completely.
+    pub fn overlap(&self, other: &DTable) -> f64 {
+        if self.lookup.get_row_count() == 0 || other.lookup.get_row_count() == 0 {
+            return 1.0;
+        }
+
+        let range = self.lower_bound(other.lookup.get_min_key())
+            .and_then(|start| self.lower_bound(other.lookup.get_max_key()).map(|end| (start, end)));
+        match range {
+            Ok((start, end)) => end.saturating_sub(start) as f64 / self.len() as f64,
+            Err(_)           => 1.0
+        }
+    }
+
+    // Returns the read statistics for this DTable: how many lookups found
+    // the row they were looking for, how many didn't, and how many bytes
+    // of rows have been read.
+    pub fn read_stats(&self) -> (u64, u64, u64) {
+        (self.hits.get(), self.misses.get(), self.bytes_read.get())
+    }
+
+    // The number of reads of this DTable which have failed.
+    pub fn read_errors(&self) -> u64 {
+        self.read_errors.get()
+    }
+
+    // A DTable is degraded once its most recent reads have all failed, so
+    // that rows in it are probably being reported as missing.
+    pub fn is_degraded(&self) -> bool {
+        self.failed_reads.get() >= DEGRADED_AFTER_FAILED_READS
+    }
+
+    fn record_read<T>(&self, result: &Result<T, TError>) {
+        match *result {
+            Ok(_) => self.failed_reads.set(0),
+            Err(TError::NotFound) => (),
+            Err(_) => {
+                self.read_errors.set(self.read_errors.get() + 1);
+                self.failed_reads.set(self.failed_reads.get() + 1);
+                if self.failed_reads.get() == DEGRADED_AFTER_FAILED_READS {
+                    error!("{} is degraded: the last {} reads of it failed", self.filename, DEGRADED_AFTER_FAILED_READS);
+                }
+            }
+        }
+    }
+
+    // Returns the size of the data file backing this DTable, in bytes.
+    pub fn size_on_disk(&self) -> u64 {
+        self.get_reader().and_then(|f| f.len()).unwrap_or(0)
+    }
+
+    // Returns the size of the data in this DTable, in bytes. This comes
+    // from the header when it's available, to avoid touching the disk.
+    pub fn total_bytes(&self) -> u64 {
+        match self.lookup.get_total_bytes() {
+            0 => self.size_on_disk(),
+            n => n
+        }
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    // Deletes the data and header files backing this DTable. This should
+    // only be done once the DTable is no longer listed in the manifest.
+    pub fn remove_files(&self) -> Result<(), io::Error> {
+        self.storage.remove(&self.filename)?;
+        self.storage.remove(&format!("{}.header", self.filename))
+    }
+
+    pub fn get_offset_from_index(&self, index: usize) -> Result<DataRegion, io::Error> {
+        let (mut entry, next) = match self.paged {
+            Some(ref p) => p.entry(index)?,
+            None        => return Ok(region_at(self.lookup.get_entries(), index))
+        };
+
+        let offset = entry.get_offset();
+        Ok(DataRegion{
+            start:  offset,
+            length: next.map(|n| n - offset),
+            families: entry.take_families().into_vec()
+        })
+    }
+
+    pub fn get_row_offset(&self, key: &str) -> Result<Option<DataRegion>, io::Error> {
+        let index = match self.paged {
+            Some(ref p) => p.find(key)?,
+            None        => self.index.find(self.lookup.get_entries(), key)
+        };
+        match index {
+            Some(i) => self.get_offset_from_index(i).map(Some),
+            None    => Ok(None)
+        }
+    }
+
+    // The number of bytes which reading the row would take, worked out
+    // from the header alone, or None if the row isn't in this DTable. If
+    // cols is provided, only the blocks of their families are counted.
+    pub fn estimate_read(&self, key: &str, cols: Option<&[&str]>) -> Result<Option<u64>, io::Error> {
+        let offset = match self.get_row_offset(key)? {
+            Some(o) => o,
+            None    => return Ok(None)
+        };
+        let length = offset.length
+            .unwrap_or(self.lookup.get_total_bytes().saturating_sub(offset.start));
+        Ok(Some(match cols.and_then(|c| families::ranges(&offset.families, length, c)) {
+            Some(ranges) => ranges.iter().map(|&(start, end)| end - start).sum(),
+            None         => length
+        }))
+    }
+
+    // The number of rows with keys in [start, end), and the number of
+    // bytes that they take up, worked out from the header alone. An empty
+    // end key means the range has no upper bound.
+    pub fn estimate_range(&self, start: &str, end: &str) -> Result<(u64, u64), io::Error> {
+        let first = self.lower_bound(start)?;
+        let last = match end {
+            "" => self.len(),
+            e  => self.lower_bound(e)?
+        };
+        if last <= first {
+            return Ok((0, 0));
+        }
+
+        let offset = |index: usize| -> Result<u64, io::Error> {
+            match index < self.len() {
+                true  => self.get_offset_from_index(index).map(|r| r.start),
+                false => Ok(self.total_bytes())
+            }
+        };
+        Ok(((last - first) as u64, offset(last)?.saturating_sub(offset(first)?)))
+    }
+
+    fn get_reader(&self) -> Result<Box<StorageFile>, io::Error> {
+        self.storage.open(&self.filename)
+    }
+
+    fn write_header(&self) -> Result<(), io::Error> {
+        let mut header = self.storage.create(&format!("{}.header", self.filename))?;
+        keyindex::write_header(&self.lookup, &mut header)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "unable to write dtable header"))?;
+        header.sync()
+    }
+
+    // Compress the dtable's data into the provided filename. The header
+    // is written uncompressed next to it, so that the archived dtable can
+    // still be searched without reading the data.
+    pub fn archive(&self, filename: &str) -> Result<DTable, io::Error> {
+        let mut reader = self.get_reader()?;
+        let mut data = self.storage.create(filename)?;
+        {
+            let mut encoder = GzEncoder::new(&mut data, Compression::Default);
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?;
+        }
+        data.sync()?;
+
+        let archived = DTable::from_dtableheader(
+            self.storage.clone(),
+            filename.to_owned(),
+            self.full_header()?
+        );
+        archived.write_header()?;
+        Ok(archived)
+    }
+
+    // Decompress an archived dtable back into the provided filename, so
+    // that it can be read from again. Its created timestamp is reset,
+    // so that it doesn't get archived again straight away.
+    pub fn rehydrate(&self, filename: &str, created: u64) -> Result<DTable, io::Error> {
+        let mut decoder = GzDecoder::new(self.get_reader()?)?;
+        let mut data = self.storage.create(filename)?;
+        io::copy(&mut decoder, &mut data)?;
+        data.sync()?;
+
+        let mut restored = DTable::from_dtableheader(
+            self.storage.clone(),
+            filename.to_owned(),
+            self.full_header()?
+        );
+        restored.lookup.set_created(created);
+        restored.write_header()?;
+        Ok(restored)
+    }
+
+    #[cfg(test)]
+    pub fn select_one(&self, row: &str, col: &str) -> Option<Vec<u8>> {
+        match self.select(row, &[col], std::u64::MAX) {
+            Some(ref result) => match result[0] {
+                Some(ref value) => Some(value.get_value().to_owned()),
+                None        => None
+            },
+            None => None
+        }
+    }
+
+    // Errors are logged, and the row is treated as though it isn't in the
+    // dtable.
+    pub fn select(&self, row: &str, cols: &[&str], timestamp: u64) -> mtable::TOption {
+        match self.try_select(row, cols, timestamp) {
+            Ok(r)   => r,
+            Err(e)  => {
+                error!("Unable to read row {} from {}: {}", row, self.filename, e);
+                None
+            }
+        }
+    }
+
+    // Only the selected columns are copied out of the row (see
+    // lazyrow.rs), rather than parsing all of it. If the row is split
+    // into column families, only their blocks are read (see families.rs).
+    pub fn try_select(&self, row: &str, cols: &[&str], timestamp: u64) -> Result<mtable::TOption, TError> {
+        let result = self.read_row_bytes(row, Some(cols)).and_then(|(offset, data)| {
+            let corrupted = |reason: String| TError::Corrupted{
+                offset: offset,
+                reason: format!("unable to parse row in {}: {}", self.filename, reason)
+            };
+            let lazy = lazyrow::LazyRow::parse(&data).map_err(&corrupted)?;
+            if lazy.is_compressed() {
+                let row = check_row(protobuf::parse_from_bytes::<DRow>(&data), &self.filename, offset)?;
+                return Ok(row.select(cols, timestamp));
+            }
+            if !lazy.is_valid() {
+                return Err(corrupted(String::from("row columns are out of order")));
+            }
+            lazy.select(cols, timestamp).map_err(&corrupted)
+        });
+        self.record_read(&result);
+
+        match result {
+            Ok(r)   => Ok(Some(r)),
+            Err(TError::NotFound) => Ok(None),
+            Err(e)  => Err(e)
+        }
+    }
+
+    pub fn get_row(&self, key: &str) -> Result<DRow, TError> {
+        let result = self.read_row_bytes(key, None).and_then(|(offset, data)| {
+            check_row(protobuf::parse_from_bytes::<DRow>(&data), &self.filename, offset)
+        });
+        self.record_read(&result);
+        result
+    }
+
+    // Read the encoded row, and return it along with its offset in the
+    // file. If cols is provided, the blocks of the row's other column
+    // families are left out. I/O errors are retried, in case they're
+    // transient, unless the dtable is already degraded.
+    fn read_row_bytes(&self, key: &str, cols: Option<&[&str]>) -> Result<(u64, Vec<u8>), TError> {
+        let mut retries = 0;
+        loop {
+            match self.try_read_row_bytes(key, cols) {
+                Err(TError::Io(ref e)) if retries < READ_RETRIES && !self.is_degraded() => {
+                    warn!("Retrying read of row {} from {}: {}", key, self.filename, e);
+                    thread::sleep(Duration::from_millis(READ_RETRY_BACKOFF_MS << retries));
+                    retries += 1;
+                },
+                result => return result
+            }
+        }
+    }
+
+    fn try_read_row_bytes(&self, key: &str, cols: Option<&[&str]>) -> Result<(u64, Vec<u8>), TError> {
+        let offset = match self.get_row_offset(key)? {
+            Some(n) => n,
+            None    => {
+                self.misses.set(self.misses.get() + 1);
+                return Err(TError::NotFound)
+            }
+        };
+
+        // The last row runs to the end of the file, so its length comes
+        // from the size recorded in the header.
+        self.hits.set(self.hits.get() + 1);
+        let length = offset.length
+            .unwrap_or(self.lookup.get_total_bytes().saturating_sub(offset.start));
+
+        let mut file = self.get_reader()?;
+
+        if let Some(ranges) = cols.and_then(|c| families::ranges(&offset.families, length, c)) {
+            let mut data = vec![];
+            for (start, end) in ranges {
+                self.bytes_read.set(self.bytes_read.get() + end - start);
+                file.seek(io::SeekFrom::Start(offset.start + start))?;
+                (&mut file).take(end - start).read_to_end(&mut data)?;
+            }
+            return Ok((offset.start, data));
+        }
+
+        self.bytes_read.set(self.bytes_read.get() + length);
+        file.seek(io::SeekFrom::Start(offset.start))?;
+
+        let mut data = Vec::with_capacity(length as usize);
+        match offset.length {
+            Some(n) => file.take(n).read_to_end(&mut data)?,
+            None    => file.read_to_end(&mut data)?
+        };
+        Ok((offset.start, data))
+    }
+
+    pub fn read_ahead(&self, readahead_bytes: u64) -> ReadAhead {
+        ReadAhead{
+            table: self,
+            file: None,
+            readahead_bytes: readahead_bytes,
+            buffer: vec![],
+            buffer_start: 0
+        }
+    }
+
+    // from_vec takes a list of dtables and merges them into a single
+    // dtable.
+    pub fn from_vec(storage: Arc<Storage>, filename: &str, tables: &[DTable], tombstones: &[RangeTombstone], policies: &families::Families, options: CompactionOptions) -> Result<DTable, TError> {
+        let options = CompactionOptions{max_bytes: 0, ..options};
+        DTable::merge_into(storage, &mut || filename.to_owned(), tables, tombstones, policies, options)
+            .map(|(mut outputs, _)| outputs.remove(0))
+    }
+
+    // merge_into takes a list of dtables and merges them into new dtables,
+    // named by calling filenames. This is a bit of a complicated function.
+    // Essentially, it runs sequentially through the rows of each dtable and
+    // merges them together in order. The tombstones are applied to the
+    // merged rows and then dropped, unless they're too recent to be garbage
+    // collected. If options.max_bytes is set, a new dtable is started once
+    // the current one reaches that size, so the outputs cover consecutive
+    // key ranges. Rows are written with the policies of their column
+    // families, and versions that the policies no longer keep are dropped.
+    pub fn merge_into(storage: Arc<Storage>, filenames: &mut FnMut() -> String, tables: &[DTable], tombstones: &[RangeTombstone], policies: &families::Families, options: CompactionOptions) -> Result<(Vec<DTable>, MergeStats), TError> {
+        // Each output is expected to hold whatever is left of the input,
+        // up to max_bytes.
+        let input_bytes = tables.iter().map(|t| t.total_bytes()).sum::<u64>();
+        let expected_bytes = |written: u64| match options.max_bytes {
+            0 => input_bytes.saturating_sub(written),
+            n => std::cmp::min(input_bytes.saturating_sub(written), n)
+        };
+        let mut written = 0;
+
+        let filename = filenames();
+        let mut f_out = create_output(&storage, &filename, expected_bytes(written))?;
+        let mut throttle = Throttle::new(options.bytes_per_second);
+        let (applied, retained): (Vec<&RangeTombstone>, Vec<&RangeTombstone>) = tombstones.iter()
+            .partition(|t| t.get_timestamp() <= options.gc_before);
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        let mut files = tables.iter()
+            .map(|t| t.get_reader())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The offset tracks how many bytes we've written to the dtable.
+        let mut offset = 0;
+
+        // Paged headers are read into memory for the length of the merge.
+        let entries = tables.iter()
+            .map(|t| t.entries())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut merge = KMerge::new(
+            entries.iter()
+                .map(|e| e.iter().enumerate())
+                .collect::<Vec<_>>()
+        );
+
+        // The output is the DTable that we'll return, which corresponds
+        // to the merged data.
+        let mut output = DTable::from_dtableheader(
+            storage.clone(),
+            filename,
+            DTableHeader::new()
+        );
+        let mut outputs = vec![];
+        let mut stats = MergeStats::default();
+        let mut dropped_at = 0;
+        let generation = tables.iter()
+            .map(|t| t.lookup.get_generation())
+            .max()
+            .unwrap_or(0);
+
+        // Each step takes the next key from the dtables, along with the
+        // dtables which have a row for it and where it is in each of them.
+        while let Some(group) = merge.next_group() {
+            let next_key = (group[0].1).1.get_key();
+            let rows_to_write = group.iter()
+                .map(|&(index, (position, _))| (index, position))
+                .collect::<Vec<_>>();
+
+            // Since the tombstones are dropped during the merge, any data that
+            // they cover needs to be removed from the row before it's written.
+            let deleted_at = applied.iter()
+                .filter(|t| t.covers(next_key))
+                .map(|t| t.get_timestamp())
+                .max()
+                .unwrap_or(0);
+
+            // Once the output is big enough, the rest of the rows go into
+            // a new dtable.
+            if options.max_bytes > 0 && offset >= options.max_bytes {
+                let kept: &[&RangeTombstone] = if outputs.is_empty() { &retained } else { &[] };
+                finish_output(&storage, &mut output, &mut f_out, offset, kept, generation, options)?;
+                written += offset;
+                let filename = filenames();
+                f_out = create_output(&storage, &filename, expected_bytes(written))?;
+                outputs.push(std::mem::replace(
+                    &mut output,
+                    DTable::from_dtableheader(storage.clone(), filename, DTableHeader::new())
+                ));
+                offset = 0;
+                dropped_at = 0;
+            }
+
+            if options.drop_cache && offset >= dropped_at + DROP_CACHE_INTERVAL {
+                f_out.flush()?;
+                drop_caches(&files, Some(&**f_out.get_ref()));
+                dropped_at = offset;
+            }
+
+            // There are two possibilities here. One: we have a single key that needs
+            // to be directly copied from the source file to the destination, or two,
+            // we have a number of identical keys which need to be merged, then written.
+            match (rows_to_write.len(), deleted_at) {
+                (0, _) => panic!("It should not be possible to reach this statement."),
+
+                // Okay, there's only one key which is to be written. In that case,
+                // we'll directly copy the data from the source file to the destination,
+                // unless it needs to be checked for an expired soft deletion or for
+                // versions which its column families don't keep.
+                (1, 0) if options.soft_deleted_before == 0 && !policies.has_retention() => {
+                    let (index, position) = rows_to_write[0];
+                    // Let's figure out which part of the files to copy into the new record.
+                    let region = region_at(&entries[index], position);
+
+                    // Now seek the file to the start of the location we wish to copy, and
+                    // copy the data from the source dtable to the new dtable.
+                    let origin = &mut files[index];
+                    origin.seek(io::SeekFrom::Start(region.start))?;
+                    let length = match region.length {
+                        Some(n) => copy_chunked(&mut origin.take(n), &mut f_out, &mut buf, &mut throttle),
+                        None    => copy_chunked(&mut origin, &mut f_out, &mut buf, &mut throttle)
+                    }?;
+
+                    // The row's column families are copied along with it.
+                    let mut hentry = DTableHeaderEntry::new();
+                    hentry.set_key(next_key.to_owned());
+                    hentry.set_offset(offset.to_owned());
+                    hentry.set_families(protobuf::RepeatedField::from_vec(region.families));
+                    offset += length;
+
+                    output.lookup.mut_entries().push(hentry);
+                },
+
+                // Okay, we have multiple rows which need to be merged (or a row which
+                // needs to be purged) before being written.
+                _ => {
+                    // A row which is in a lot of dtables is read and merged
+                    // a batch at a time, with the rows merged so far, so
+                    // that only max_fan_in of them are in memory at once.
+                    let fan_in = rows_to_write.len();
+                    let batch_size = match options.max_fan_in {
+                        0 => fan_in,
+                        n => n
+                    };
+                    stats.max_fan_in = std::cmp::max(stats.max_fan_in, fan_in as u64);
+                    if fan_in > batch_size {
+                        stats.batched_rows += 1;
+                    }
+
+                    let mut merged = None;
+                    for batch in rows_to_write.chunks(batch_size) {
+                        let mut rows = Vec::with_capacity(batch.len() + 1);
+                        rows.extend(merged.take());
+                        for &(ix, position) in batch {
+                            let origin = &mut files[ix];
+                            let region = region_at(&entries[ix], position);
+                            origin.seek(io::SeekFrom::Start(region.start))?;
+
+                            let row = match region.length {
+                                Some(n) => protobuf::parse_from_reader::<DRow>(&mut origin.take(n)),
+                                None    => protobuf::parse_from_reader::<DRow>(origin)
+                            };
+                            rows.push(check_row(row, &tables[ix].filename, region.start)?);
+                        }
+                        merged = Some(DRow::merge(rows));
+                    }
+
+                    // Write the merged row to the output file, unless
+                    // everything in it has been deleted.
+                    let mut row = merged.unwrap();
+                    let mut purged = false;
+                    if deleted_at > 0 {
+                        row = row.purge(deleted_at);
+                        purged = true;
+                    }
+                    if policies.has_retention() {
+                        row = policies.retain(&row, options.created, options.gc_before);
+                        purged = true;
+                    }
+                    let expired = row.soft_deleted_at()
+                        .map_or(false, |t| t < options.soft_deleted_before);
+
+                    if !expired && (!purged || !row.get_keys().is_empty()) {
+                        let (length, blocks) = families::write_row(&row, policies, &mut f_out).map_err(write_error)?;
+
+                        let mut hentry = DTableHeaderEntry::new();
+                        hentry.set_key(next_key.to_owned());
+                        hentry.set_offset(offset);
+                        hentry.set_families(protobuf::RepeatedField::from_vec(blocks));
+                        offset += length;
+                        throttle.consume(length);
+
+                        output.lookup.mut_entries().push(hentry);
+                    }
+                }
+            };
+        }
+
+        // Finally, write the last header. If every remaining row was
+        // deleted after a new dtable was started, the new one is left out.
+        if !outputs.is_empty() && offset == 0 {
+            drop(f_out);
+            storage.remove(output.filename())?;
+        } else {
+            let kept: &[&RangeTombstone] = if outputs.is_empty() { &retained } else { &[] };
+            finish_output(&storage, &mut output, &mut f_out, offset, kept, generation, options)?;
+            outputs.push(output);
+        }
+
+        if options.drop_cache {
+            drop_caches(&files, None);
+        }
+
+        if stats.batched_rows > 0 {
+            warn!(
+                "Merged {} rows in batches, since they were in more than {} dtables (up to {}).",
+                stats.batched_rows,
+                options.max_fan_in,
+                stats.max_fan_in
+            );
+        }
+
+        Ok((outputs, stats))
+    }
+
+    // Write a copy of the dtable to the filename, passing each row which
+    // the filter selects through the rewrite function. The copy keeps
+    // the dtable's range deletions, created time and generation. Rows
+    // which are left without any columns are dropped.
+    pub fn rewrite<P, F>(&self, filename: &str, sync: bool, policies: &families::Families, filter: P, rewrite: F) -> Result<DTable, TError>
+        where P: Fn(&str) -> bool, F: FnMut(&str, &DRow) -> DRow
+    {
+        self.rewrite_rows(filename, sync, self.lookup.get_collected(), policies, filter, rewrite)
+    }
+
+    // Write a copy of the dtable to the filename without the entries that
+    // the tombstones hide. Unlike a merge, the tombstones are left in
+    // place, so they can still hide data in the other dtables. The newest
+    // tombstone applied is remembered in the header, so that the same
+    // garbage isn't counted again.
+    pub fn drop_obsolete(&self, filename: &str, sync: bool, policies: &families::Families, tombstones: &[&RangeTombstone]) -> Result<DTable, TError> {
+        let collected = tombstones.iter()
+            .map(|t| t.get_timestamp())
+            .fold(self.lookup.get_collected(), std::cmp::max);
+
+        self.rewrite_rows(
+            filename,
+            sync,
+            collected,
+            policies,
+            |key| tombstones.iter().any(|t| t.covers(key)),
+            |key, row| row.purge(deleted_at(tombstones.iter().cloned(), key, std::u64::MAX))
+        )
+    }
+
+    // Estimate how much of the dtable the tombstones hide, as the fraction
+    // of its rows which fall inside of them. This only reads the header,
+    // and since a row may also hold entries newer than the tombstone, it
+    // can overestimate.
+    pub fn garbage_ratio(&self, tombstones: &[&RangeTombstone]) -> f64 {
+        if self.len() == 0 {
+            return 0.0;
+        }
+
+        // Overlapping tombstones are joined together first, so that no
+        // row is counted twice.
+        let mut ranges = tombstones.iter()
+            .map(|t| (t.get_start(), t.get_end()))
+            .collect::<Vec<_>>();
+        ranges.sort();
+
+        // If a paged header can't be read, the dtable is treated as though
+        // nothing is hidden, so that it's left alone.
+        let lower_bound = |key: &str| self.lower_bound(key).unwrap_or_else(|e| {
+            error!("Unable to read the header of {}: {}", self.filename, e);
+            0
+        });
+
+        let mut covered = 0;
+        let mut counted = 0;
+        for (start, end) in ranges {
+            let first = std::cmp::max(lower_bound(start), counted);
+            let last = match end {
+                "" => self.len(),
+                e  => lower_bound(e)
+            };
+            if last > first {
+                covered += last - first;
+                counted = last;
+            }
+        }
+
+        covered as f64 / self.len() as f64
+    }
+
+    fn rewrite_rows<P, F>(&self, filename: &str, sync: bool, collected: u64, policies: &families::Families, filter: P, mut rewrite: F) -> Result<DTable, TError>
+        where P: Fn(&str) -> bool, F: FnMut(&str, &DRow) -> DRow
+    {
+        let mut f_in = self.get_reader()?;
+        let mut f_out = create_output(&self.storage, filename, self.total_bytes())?;
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        let mut throttle = Throttle::new(0);
+        let mut output = DTable::from_dtableheader(
+            self.storage.clone(),
+            filename.to_owned(),
+            DTableHeader::new()
+        );
+
+        let mut offset = 0;
+        let entries = self.entries()?;
+        for (index, entry) in entries.iter().enumerate() {
+            let region = region_at(&entries, index);
+            f_in.seek(io::SeekFrom::Start(region.start))?;
+
+            let (length, blocks) = if filter(entry.get_key()) {
+                let row = match region.length {
+                    Some(n) => protobuf::parse_from_reader::<DRow>(&mut (&mut f_in).take(n)),
+                    None    => protobuf::parse_from_reader::<DRow>(&mut f_in)
+                };
+                let row = rewrite(entry.get_key(), &check_row(row, &self.filename, region.start)?);
+                if row.get_keys().is_empty() {
+                    continue;
+                }
+                families::write_row(&row, policies, &mut f_out).map_err(write_error)?
+            } else {
+                let length = match region.length {
+                    Some(n) => copy_chunked(&mut (&mut f_in).take(n), &mut f_out, &mut buf, &mut throttle),
+                    None    => copy_chunked(&mut f_in, &mut f_out, &mut buf, &mut throttle)
+                }?;
+                (length, region.families)
+            };
+
+            let mut hentry = DTableHeaderEntry::new();
+            hentry.set_key(entry.get_key().to_owned());
+            hentry.set_offset(offset);
+            hentry.set_families(protobuf::RepeatedField::from_vec(blocks));
+            offset += length;
+            output.lookup.mut_entries().push(hentry);
+        }
+
+        output.lookup.set_tombstones(protobuf::RepeatedField::from_iter(
+            self.lookup.get_tombstones().iter().cloned()
+        ));
+        flush_output(&mut f_out)?;
+        summarize(&mut output.lookup, offset, self.lookup.get_created(), self.lookup.get_generation());
+        output.lookup.set_collected(collected);
+        output.reindex();
+        let mut header_file = self.storage.create(&format!("{}.header", filename))?;
+        keyindex::write_header(&output.lookup, &mut header_file).map_err(write_error)?;
+
+        if sync {
+            header_file.sync()?;
+            f_out.get_ref().sync()?;
+        }
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand;
+    use time;
+    use protobuf;
+    use storage;
+    use families;
+    use std::sync::Arc;
+
+    #[test]
+    fn can_merge_columns() {
+        let data = vec![230, 210, 40, 30, 22];
+
+        // Generate columns with valid (ordered, but random) timestamps.
+        let cols = (0..10).map(|_| {
+                let mut c = super::DColumn::new();
+                let mut entries = (0..100).map(|_| {
+                        let mut e = super::DEntry::new();
+                        e.set_timestamp(rand::random::<u32>() as u64);
+                        e.set_value(data.clone());
+                        return e;
+                    }).collect::<Vec<_>>();
+                entries.sort_by_key(|e| (e.get_timestamp() as i64));
+                c.set_entries(protobuf::RepeatedField::from_vec(entries));
+                return c;
+            }).collect::<Vec<_>>();
+
+        // Merge the columns together. It should still be ordered after the
+        // merge, and have exactly 1000 entries.
+        let merged = super::DColumn::from_vec(cols.iter().collect::<Vec<_>>().as_slice());
+
+        let entries = merged.get_entries();
+        assert_eq!(entries.len(), 1000);
+
+        let mut minimum = 0;
+        for e in entries {
+            let t = e.get_timestamp();
+            assert!(t >= minimum, "t({}) < minimum({})", t, minimum);
+            minimum = t;
+        }
+    }
+
+    #[test]
+    fn can_merge_rows() {
+        let rows = (0..20).map(|index| {
+            let mut e = super::DEntry::new();
+            e.set_timestamp(100);
+            e.set_value(vec![]);
+
+            let mut c = super::DColumn::new();
+            c.set_entries(protobuf::RepeatedField::from_vec(vec![e]));
+
+            let mut r = super::DRow::new();
+            if index == 0 {
+                r.set_keys(protobuf::RepeatedField::from_vec(vec!["canary_field".to_string()]))
+            } else {
+                r.set_keys(protobuf::RepeatedField::from_vec(vec![format!("hello{}", index%3)]));
+            }
+            r.set_columns(protobuf::RepeatedField::from_vec(vec![c]));
+
+            return r;
+        }).collect::<Vec<_>>();
+
+        let new_row = super::DRow::from_vec(rows.as_slice());
+        new_row.get_column("hello0").unwrap();
+        new_row.get_column("hello1").unwrap();
+        new_row.get_column("hello2").unwrap();
+    }
+
+    #[test]
+    fn can_prune_by_key_range() {
+        let mut header = super::DTableHeader::new();
+        header.set_entries(protobuf::RepeatedField::from_vec(
+            ["carrot", "date", "fig"].iter().enumerate().map(|(i, k)| {
+                let mut e = super::DTableHeaderEntry::new();
+                e.set_key(k.to_string());
+                e.set_offset(i as u64 * 10);
+                e
+            }).collect()
+        ));
+        super::summarize(&mut header, 30, 0, 1);
+        let d = super::DTable::from_dtableheader(
+            Arc::new(storage::MemoryStorage::new()),
+            String::from("/sim/1.dtable"),
+            header
+        );
+
+        assert!(!d.may_contain("apple"));
+        assert!(d.may_contain("carrot"));
+        assert!(d.may_contain("egg"));
+        assert!(d.may_contain("fig"));
+        assert!(!d.may_contain("grape"));
+
+        assert!(!d.may_overlap("a", "carrot"));
+        assert!(d.may_overlap("a", "carrots"));
+        assert!(d.may_overlap("fig", ""));
+        assert!(!d.may_overlap("figs", ""));
+    }
+
+    #[test]
+    fn range_tombstone_covers_keys() {
+        let mut t = super::RangeTombstone::new();
+        t.set_start(String::from("b"));
+        t.set_end(String::from("d"));
+        assert!(!t.covers("a"));
+        assert!(t.covers("b"));
+        assert!(t.covers("cat"));
+        assert!(!t.covers("d"));
+
+        // An empty end key means the range is unbounded.
+        t.set_end(String::new());
+        assert!(t.covers("zebra"));
+    }
+
+    #[test]
+    fn can_copy_with_throttle() {
+        let data = vec![7; 3 * super::COPY_CHUNK_SIZE + 10];
+        let mut output = vec![];
+        let mut buf = vec![0; super::COPY_CHUNK_SIZE];
+
+        // At 1 MiB/s, copying roughly 200 KiB should take about 190ms.
+        let started = time::precise_time_ns();
+        let mut throttle = super::Throttle::new(1 << 20);
+        let copied = super::copy_chunked(&mut data.as_slice(), &mut output, &mut buf, &mut throttle).unwrap();
+
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(output, data);
+        assert!(time::precise_time_ns() - started >= 150_000_000);
+    }
+
+    #[test]
+    fn loads_prefix_compressed_headers() {
+        use mtable;
+        use query;
+        use storage::Storage;
+
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut m = mtable::MTable::new();
+        for i in 0..200 {
+            m.insert(&format!("user/{:05}", i * 2), &[query::MUpdate::new("value", format!("{}", i).into_bytes())], 1).unwrap();
+        }
+        let mut f = storage.create("/test/1.dtable").unwrap();
+        let mut h = storage.create("/test/1.dtable.header").unwrap();
+        let header = m.write_to_writer(&mut f, &mut h, 1, 1).unwrap();
+
+        let d = super::DTable::new(storage.clone(), String::from("/test/1.dtable")).unwrap();
+        assert_eq!(d.lookup.get_entries(), header.get_entries());
+        for i in 0..200 {
+            let row = d.get_row(&format!("user/{:05}", i * 2)).unwrap();
+            assert_eq!(row.get_latest_value("value").unwrap().get_value(), format!("{}", i).as_bytes());
+            assert!(d.get_row_offset(&format!("user/{:05}", i * 2 + 1)).unwrap().is_none());
+        }
+        assert_eq!(d.entries_from("user/00101").unwrap()[0].get_key(), "user/00102");
+    }
+
+    #[test]
+    fn retries_failed_reads() {
+        use mtable;
+        use query;
+        use storage::Storage;
+
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut m = mtable::MTable::new();
+        m.insert("a", &[query::MUpdate::new("value", b"1".to_vec())], 1).unwrap();
+        {
+            let mut f = storage.create("/test/1.dtable").unwrap();
+            let mut h = storage.create("/test/1.dtable.header").unwrap();
+            m.write_to_writer(&mut f, &mut h, 1, 1).unwrap();
+        }
+        let d = super::DTable::new(storage.clone(), String::from("/test/1.dtable")).unwrap();
+
+        // Transient errors are retried.
+        storage.fail_opens(2);
+        assert_eq!(d.select_one("a", "value"), Some(b"1".to_vec()));
+        assert_eq!(d.read_errors(), 0);
+
+        // Errors which outlast the retries are counted, and once enough
+        // reads in a row have failed, the dtable is degraded.
+        for _ in 0..super::DEGRADED_AFTER_FAILED_READS {
+            assert!(!d.is_degraded());
+            storage.fail_opens(super::READ_RETRIES as usize + 1);
+            assert_eq!(d.select_one("a", "value"), None);
+        }
+        assert!(d.is_degraded());
+        assert_eq!(d.read_errors(), super::DEGRADED_AFTER_FAILED_READS);
+
+        // Once degraded, failed reads aren't retried.
+        storage.fail_opens(1);
+        assert_eq!(d.select_one("a", "value"), None);
+        assert_eq!(d.read_errors(), super::DEGRADED_AFTER_FAILED_READS + 1);
+
+        // It recovers as soon as a read succeeds.
+        assert!(d.get_row("a").is_ok());
+        assert!(!d.is_degraded());
+    }
+
+    #[test]
+    fn reads_only_the_selected_families() {
+        use mtable;
+        use query;
+        use storage::Storage;
+
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let write = |filename: &str, timestamp: u64, rows: &[(&str, Vec<(&str, Vec<u8>)>)]| {
+            let mut m = mtable::MTable::new();
+            for &(key, ref columns) in rows {
+                let updates = columns.iter().map(|&(k, ref v)| query::MUpdate::new(k, v.clone())).collect::<Vec<_>>();
+                m.insert(key, &updates, timestamp).unwrap();
+            }
+            let mut f = storage.create(filename).unwrap();
+            let mut h = storage.create(&format!("{}.header", filename)).unwrap();
+            m.write_to_writer(&mut f, &mut h, 1, 1).unwrap();
+            super::DTable::new(storage.clone(), filename.to_owned()).unwrap()
+        };
+
+        let body = vec![b'x'; 10000];
+        let first = write("/test/1.dtable", 1, &[
+            ("a", vec![("data:body", body.clone()), ("meta:owner", b"alice".to_vec()), ("name", b"a".to_vec())]),
+            ("c", vec![("meta:owner", b"carol".to_vec())])
+        ]);
+        let second = write("/test/2.dtable", 2, &[
+            ("a", vec![("meta:owner", b"bob".to_vec())]),
+            ("b", vec![("data:body", body.clone()), ("meta:owner", b"bert".to_vec())])
+        ]);
+        assert_eq!(first.get_row_offset("a").unwrap().unwrap().families.len(), 3);
+
+        // Both merged rows and copied rows keep their families.
+        let merged = super::DTable::from_vec(storage.clone(), "/test/3.dtable", &[first, second], &[], &families::Families::new(), super::CompactionOptions{
+            sync: false,
+            bytes_per_second: 0,
+            gc_before: 0,
+            created: 1,
+            max_bytes: 0,
+            drop_cache: false,
+            soft_deleted_before: 0,
+            max_fan_in: 0
+        }).unwrap();
+
+        for &(key, owner) in &[("a", "bob"), ("b", "bert")] {
+            let (_, _, before) = merged.read_stats();
+            assert_eq!(merged.select_one(key, "meta:owner").unwrap(), owner.as_bytes());
+            let (_, _, after) = merged.read_stats();
+            assert!(after - before < 100, "read {} bytes of {}", after - before, key);
+            assert_eq!(merged.select_one(key, "data:body").unwrap(), &body[..]);
+            assert_eq!(merged.get_row(key).unwrap().get_latest_value("data:body").unwrap().get_value(), &body[..]);
+        }
+        assert_eq!(merged.select_one("a", "name").unwrap(), b"a");
+        assert_eq!(merged.select_one("b", "name"), None);
+        assert_eq!(merged.select_one("c", "meta:owner").unwrap(), b"carol");
+        assert!(merged.get_row_offset("c").unwrap().unwrap().families.is_empty());
+
+        // Paged headers have the families too.
+        let paged = super::DTable::open_paged(storage.clone(), String::from("/test/3.dtable"), 1).unwrap();
+        assert_eq!(paged.get_row_offset("b").unwrap().unwrap().families.len(), 2);
+        assert_eq!(paged.select_one("b", "meta:owner").unwrap(), b"bert");
+    }
+
+    #[test]
+    fn merges_rows_in_batches_of_max_fan_in() {
+        use mtable;
+        use query;
+        use storage::Storage;
+
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let tables = (1..6).map(|i| {
+            let mut m = mtable::MTable::new();
+            m.insert("a", &[query::MUpdate::new("value", format!("{}", i).into_bytes()), query::MUpdate::new(&format!("col{}", i), vec![])], i).unwrap();
+            m.insert(&format!("b{}", i), &[query::MUpdate::new("value", vec![])], i).unwrap();
+            let filename = format!("/test/{}.dtable", i);
+            let mut f = storage.create(&filename).unwrap();
+            let mut h = storage.create(&format!("{}.header", filename)).unwrap();
+            m.write_to_writer(&mut f, &mut h, 1, 1).unwrap();
+            super::DTable::new(storage.clone(), filename).unwrap()
+        }).collect::<Vec<_>>();
+
+        let merge = |filename: &str, max_fan_in: usize| {
+            let options = super::CompactionOptions{
+                sync: false,
+                bytes_per_second: 0,
+                gc_before: 0,
+                created: 1,
+                max_bytes: 0,
+                drop_cache: false,
+                soft_deleted_before: 0,
+                max_fan_in: max_fan_in
+            };
+            let (mut outputs, stats) = super::DTable::merge_into(storage.clone(), &mut || filename.to_owned(), &tables, &[], &families::Families::new(), options).unwrap();
+            (outputs.remove(0), stats)
+        };
+
+        let (batched, stats) = merge("/test/batched.dtable", 2);
+        assert_eq!(stats, super::MergeStats{max_fan_in: 5, batched_rows: 1});
+        let (unbatched, stats) = merge("/test/unbatched.dtable", 0);
+        assert_eq!(stats, super::MergeStats{max_fan_in: 5, batched_rows: 0});
+
+        // Merging in batches gives the same row as merging all at once.
+        let row = batched.get_row("a").unwrap();
+        assert_eq!(row, unbatched.get_row("a").unwrap());
+        assert_eq!(row.get_keys().len(), 6);
+        assert_eq!(row.get_column("value").unwrap().get_entries().len(), 5);
+        assert_eq!(row.get_latest_value("value").unwrap().get_value(), b"5");
+        assert_eq!(batched.len(), 6);
+    }
+
+    #[test]
+    fn reads_ahead_in_order() {
+        use mtable;
+        use query;
+        use storage::Storage;
+
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut m = mtable::MTable::new();
+        for i in 0..100 {
+            m.insert(&format!("row{:03}", i), &[query::MUpdate::new("value", format!("{}", i).into_bytes())], 1).unwrap();
+        }
+        let mut f = storage.create("/test/1.dtable").unwrap();
+        let mut h = storage.create("/test/1.dtable.header").unwrap();
+        let header = m.write_to_writer(&mut f, &mut h, 1, 1).unwrap();
+        let d = super::DTable::from_dtableheader(storage.clone(), String::from("/test/1.dtable"), header);
+
+        // Whether the rows are read one at a time, a few at a time, or
+        // all at once, they come out the same.
+        for &readahead_bytes in &[0, 64, 1 << 20] {
+            let mut reader = d.read_ahead(readahead_bytes);
+            for i in 0..100 {
+                let row = reader.get_row(i).unwrap();
+                assert_eq!(row.get_latest_value("value").unwrap().get_value(), format!("{}", i).as_bytes());
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+/*
+    election.rs
+
+    Lease-based leader election for a shard. A replica becomes leader by
+    taking out a lease for a new term, and stays leader for as long as
+    it keeps renewing the lease before it expires. If the leader dies,
+    its lease runs out and another replica can take over with a higher
+    term.
+
+    The term doubles as a fencing token: the leader stamps it onto every
+    commit log entry, and entries carrying an older term than one which
+    has already been seen come from a deposed leader, so they're
+    rejected. The primary announces its lease to the replicas which tail
+    its commit log, and a replica campaigns once the primary stops
+    answering (see src/replica.rs); this only decides what to do with
+    the leases.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub holder: String,
+    pub term: u64,
+
+    // The lease is valid until this time, in nanoseconds.
+    pub expires: u64
+}
+
+pub struct Election {
+    pub node_id: String,
+
+    // How long a lease lasts after it's taken out or renewed. The leader
+    // should renew well before then, e.g. every third of it.
+    pub lease_ms: u64,
+
+    // The newest lease that this replica knows about.
+    lease: Option<Lease>
+}
+
+impl Election {
+    pub fn new(node_id: &str, lease_ms: u64) -> Election {
+        Election{
+            node_id: node_id.to_owned(),
+            lease_ms: lease_ms,
+            lease: None
+        }
+    }
+
+    // Take note of a lease announced by another replica. Leases from
+    // older terms are ignored. Returns whether the lease was accepted.
+    pub fn observe(&mut self, lease: Lease) -> bool {
+        let accept = match self.lease {
+            Some(ref current) => lease.term > current.term ||
+                (lease.term == current.term && lease.holder == current.holder && lease.expires > current.expires),
+            None => true
+        };
+
+        if accept {
+            self.lease = Some(lease);
+        }
+        accept
+    }
+
+    // Try to become (or stay) leader at time `now`. The leader renews its
+    // lease for the same term. Anyone else can only take over once the
+    // current lease has expired, and then does so with the next term.
+    // A leader which let its own lease run out can't, since a replica may
+    // already have taken that term without it hearing. Returns the lease
+    // to announce to the other replicas, if there is one.
+    pub fn campaign(&mut self, now: u64) -> Option<Lease> {
+        let expires = now + self.lease_ms * 1_000_000;
+        let lease = match self.lease {
+            Some(ref l) if l.holder == self.node_id && l.expires > now => Lease{
+                holder: l.holder.clone(),
+                term: l.term,
+                expires: expires
+            },
+            Some(ref l) if l.expires > now => return None,
+            Some(ref l) if l.holder == self.node_id => return None,
+            Some(ref l) => Lease{
+                holder: self.node_id.clone(),
+                term: l.term + 1,
+                expires: expires
+            },
+            None => Lease{
+                holder: self.node_id.clone(),
+                term: 1,
+                expires: expires
+            }
+        };
+
+        self.lease = Some(lease.clone());
+        Some(lease)
+    }
+
+    // The newest lease that this replica knows about, if any.
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    pub fn is_leader(&self, now: u64) -> bool {
+        match self.lease {
+            Some(ref l) => l.holder == self.node_id && l.expires > now,
+            None        => false
+        }
+    }
+
+    // The fencing token to stamp on writes, if this replica is the leader.
+    pub fn fencing_token(&self, now: u64) -> Option<u64> {
+        match self.lease {
+            Some(ref l) if self.is_leader(now) => Some(l.term),
+            _ => None
+        }
+    }
+
+    // The current leader, if its lease hasn't expired.
+    pub fn leader(&self, now: u64) -> Option<&str> {
+        match self.lease {
+            Some(ref l) if l.expires > now => Some(&l.holder),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn fails_over_when_lease_expires() {
+        let mut a = super::Election::new("a", 100);
+        let mut b = super::Election::new("b", 100);
+
+        let lease = a.campaign(0).unwrap();
+        assert_eq!(lease.term, 1);
+        assert!(b.observe(lease));
+        assert!(a.is_leader(50 * MS));
+        assert_eq!(b.leader(50 * MS), Some("a"));
+
+        // While a's lease is valid, b can't take over, and a can renew.
+        assert_eq!(b.campaign(50 * MS), None);
+        assert!(b.observe(a.campaign(90 * MS).unwrap()));
+        assert_eq!(b.campaign(150 * MS), None);
+
+        // a dies, and once its lease runs out b takes over.
+        let lease = b.campaign(200 * MS).unwrap();
+        assert_eq!(lease.term, 2);
+        assert_eq!(b.fencing_token(200 * MS), Some(2));
+        assert!(!a.is_leader(200 * MS));
+        assert_eq!(a.fencing_token(200 * MS), None);
+        assert_eq!(a.campaign(200 * MS), None);
+
+        // When a comes back, it learns about the new term, and it can't
+        // be talked back into the old one.
+        assert!(a.observe(lease));
+        assert!(!a.observe(super::Lease{holder: String::from("a"), term: 1, expires: 1000 * MS}));
+        assert_eq!(a.leader(250 * MS), Some("b"));
+    }
+}
@@ -0,0 +1,147 @@
+/*
+    export.rs
+
+    Exports rows as a SQL script, for analytics which shouldn't run
+    against the live database. The script loads straight into SQLite
+    (e.g. `sqlite3 export.db < export.sql`), with one row for each
+    column's value:
+
+        CREATE TABLE largetable (row_key TEXT, column_name TEXT, timestamp INTEGER, value BLOB);
+
+    Timestamps are in nanoseconds, as they're stored. Values which are
+    valid UTF-8 are written as text, and anything else as a blob. Rows are
+    written as they come out of the iterator, so only one is held in
+    memory at a time.
+*/
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str;
+
+use scan;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ExportStats {
+    pub rows: u64,
+    pub values: u64
+}
+
+impl fmt::Display for ExportStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} rows, {} values", self.rows, self.values)
+    }
+}
+
+// Table names are written into the script as they are, so they're kept
+// to ones which never need quoting.
+fn valid_table_name(name: &str) -> bool {
+    let letter = |c: char| (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_';
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if letter(c) => (),
+        _ => return false
+    };
+    chars.all(|c| letter(c) || (c >= '0' && c <= '9'))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A SQL literal for the text. Text with NUL characters can't be quoted,
+// so it's cast from a blob instead.
+fn text_literal(text: &str) -> String {
+    match text.contains('\0') {
+        true    => format!("CAST(X'{}' AS TEXT)", hex(text.as_bytes())),
+        false   => format!("'{}'", text.replace("'", "''"))
+    }
+}
+
+fn value_literal(value: &[u8]) -> String {
+    match str::from_utf8(value) {
+        Ok(text)    => text_literal(text),
+        Err(_)      => format!("X'{}'", hex(value))
+    }
+}
+
+// Write every row from the iterator into the table, which is created by
+// the script. The inserts are wrapped in a single transaction, which
+// makes loading them into SQLite much faster.
+pub fn export_sql<I, W>(rows: I, table: &str, out: &mut W) -> io::Result<ExportStats>
+    where I: Iterator<Item=scan::RowView>, W: Write
+{
+    if !valid_table_name(table) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid table name: {}", table)));
+    }
+
+    writeln!(out, "BEGIN TRANSACTION;")?;
+    writeln!(out, "CREATE TABLE {} (row_key TEXT, column_name TEXT, timestamp INTEGER, value BLOB);", table)?;
+
+    let mut stats = ExportStats::default();
+    for row in rows {
+        let key = text_literal(row.key());
+        for (column, timestamp, value) in row.iter_timestamped_columns() {
+            writeln!(
+                out,
+                "INSERT INTO {} VALUES ({}, {}, {}, {});",
+                table,
+                key,
+                text_literal(column),
+                timestamp,
+                value_literal(value)
+            )?;
+            stats.values += 1;
+        }
+        stats.rows += 1;
+    }
+
+    writeln!(out, "COMMIT;")?;
+    out.flush()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use base;
+    use query;
+    use scan;
+
+    #[test]
+    fn exports_rows_as_sql() {
+        let mut database = base::Base::new_stub();
+        database.insert("a", vec![query::MUpdate::new("name", b"O'Brien".to_vec())], 100);
+        database.empty_memtable().unwrap();
+        database.insert("b", vec![
+            query::MUpdate::new("data", vec![0xff, 0x00]),
+            query::MUpdate::new("nul", b"x\0y".to_vec())
+        ], 200);
+        database.update("a", vec![query::MUpdate::new("age", b"30".to_vec())], 300);
+
+        let mut out = vec![];
+        let stats = super::export_sql(database.iter_rows(scan::KeyRange::all(), 250), "people", &mut out).unwrap();
+        assert_eq!(stats, super::ExportStats{rows: 2, values: 3});
+
+        // Writes after the export's timestamp aren't included.
+        let script = String::from_utf8(out).unwrap();
+        let mut lines = script.lines().collect::<Vec<_>>();
+        lines[3..5].sort();
+        assert_eq!(lines, vec![
+            "BEGIN TRANSACTION;",
+            "CREATE TABLE people (row_key TEXT, column_name TEXT, timestamp INTEGER, value BLOB);",
+            "INSERT INTO people VALUES ('a', 'name', 100, 'O''Brien');",
+            "INSERT INTO people VALUES ('b', 'data', 200, X'ff00');",
+            "INSERT INTO people VALUES ('b', 'nul', 200, CAST(X'780079' AS TEXT));",
+            "COMMIT;"
+        ]);
+    }
+
+    #[test]
+    fn rejects_table_names_which_need_quoting() {
+        let database = base::Base::new_stub();
+        for name in &["", "1table", "drop table x; --", "a-b"] {
+            assert!(super::export_sql(database.iter_rows(scan::KeyRange::all(), 0), name, &mut Vec::<u8>::new()).is_err());
+        }
+        assert!(super::export_sql(database.iter_rows(scan::KeyRange::all(), 0), "_export_2", &mut Vec::<u8>::new()).is_ok());
+    }
+}
@@ -0,0 +1,450 @@
+/*
+    families.rs
+
+    Columns are grouped into families by naming them with a prefix that
+    ends in a colon, e.g. "meta:owner" and "data:body". Columns without
+    a colon are in the default family, "".
+
+    In a dtable, a row with columns in more than one family is written
+    as a series of DRows, one for each run of columns in the same family,
+    in column order. Repeated fields are concatenated when protobufs are
+    merged, so the series still parses as the whole row. The row's header
+    entry records where each of these blocks starts, so that a select
+    only reads the blocks which hold the columns it asks for, rather than
+    reading past large values in the other families.
+
+    Each family can also be given a policy in the config (see README.md).
+    Its blocks can be compressed, in which case they're written as a DRow
+    holding only the compressed encoding of the block, and expanded again
+    when the row is parsed. Its columns can be left out of the row cache,
+    and old versions of them can be dropped once they're past a TTL or
+    beyond a number of versions, when dtables are merged.
+*/
+
+use std::io;
+
+use protobuf;
+use protobuf::Message;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use dtable::SOFT_DELETE_COLUMN;
+use generated::dtable::*;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Codec {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "gzip")]
+    Gzip
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FamilyPolicy {
+    // The family's name, including the colon, e.g. "data:".
+    pub family: String,
+    #[serde(default="default_compression")]
+    pub compression: Codec,
+
+    // Whether the family's columns are kept in the row cache.
+    #[serde(default="default_cache")]
+    pub cache: bool,
+
+    // Versions older than this are dropped, unless it's zero.
+    #[serde(default)]
+    pub ttl_ms: u64,
+
+    // Only the newest max_versions versions are kept, unless it's zero.
+    #[serde(default)]
+    pub max_versions: usize
+}
+
+fn default_compression() -> Codec { Codec::None }
+fn default_cache() -> bool { true }
+
+impl FamilyPolicy {
+    pub fn new(family: &str) -> FamilyPolicy {
+        FamilyPolicy{
+            family: family.to_owned(),
+            compression: default_compression(),
+            cache: default_cache(),
+            ttl_ms: 0,
+            max_versions: 0
+        }
+    }
+
+    fn has_retention(&self) -> bool {
+        self.ttl_ms > 0 || self.max_versions > 0
+    }
+}
+
+// The policies of the families which have one. Families without a
+// policy are uncompressed, cached and keep every version.
+#[derive(Clone)]
+pub struct Families {
+    policies: Vec<FamilyPolicy>
+}
+
+impl Families {
+    pub fn new() -> Families {
+        Families{
+            policies: vec![]
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.policies.is_empty()
+    }
+
+    // Add the policy, replacing any which the family already has.
+    pub fn add_family(&mut self, policy: FamilyPolicy) {
+        self.policies.retain(|p| p.family != policy.family);
+        self.policies.push(policy);
+    }
+
+    pub fn get(&self, family: &str) -> Option<&FamilyPolicy> {
+        self.policies.iter().find(|p| p.family == family)
+    }
+
+    fn is_compressed(&self, family: &str) -> bool {
+        self.get(family).map_or(false, |p| p.compression != Codec::None)
+    }
+
+    pub fn is_cached(&self, column: &str) -> bool {
+        self.get(family(column)).map_or(true, |p| p.cache)
+    }
+
+    // Whether merges have to look at every row to drop old versions.
+    pub fn has_retention(&self) -> bool {
+        self.policies.iter().any(|p| p.has_retention())
+    }
+
+    // Create a copy of the row with only the columns which may be cached.
+    pub fn cached_columns(&self, row: &DRow) -> DRow {
+        let (keys, cols): (Vec<_>, Vec<_>) = row.get_keys().iter()
+            .zip(row.get_columns().iter())
+            .filter(|&(k, _)| self.is_cached(k))
+            .map(|(k, c)| (k.to_owned(), c.clone()))
+            .unzip();
+
+        let mut d = DRow::new();
+        d.set_keys(protobuf::RepeatedField::from_vec(keys));
+        d.set_columns(protobuf::RepeatedField::from_vec(cols));
+        d
+    }
+
+    // Create a copy of the row without the versions which are past their
+    // family's TTL as of now, or beyond its number of versions. Versions
+    // written after gc_before are kept, since an open snapshot may still
+    // need them. Columns which end up empty are dropped.
+    pub fn retain(&self, row: &DRow, now: u64, gc_before: u64) -> DRow {
+        let mut keys = vec![];
+        let mut cols = vec![];
+        for (key, col) in row.get_keys().iter().zip(row.get_columns().iter()) {
+            let policy = match self.get(family(key)) {
+                Some(p) if p.has_retention() && key.as_str() != SOFT_DELETE_COLUMN => p,
+                _ => {
+                    keys.push(key.to_owned());
+                    cols.push(col.clone());
+                    continue;
+                }
+            };
+
+            // Entries are in timestamp order, so the newest are at the end.
+            let expires_before = now.saturating_sub(policy.ttl_ms * 1_000_000);
+            let count = col.get_entries().len();
+            let entries = col.get_entries()
+                .iter()
+                .enumerate()
+                .filter(|&(i, e)| {
+                    let expired = policy.ttl_ms > 0 && e.get_timestamp() < expires_before;
+                    let extra = policy.max_versions > 0 && count - i > policy.max_versions;
+                    e.get_timestamp() > gc_before || !(expired || extra)
+                })
+                .map(|(_, e)| e.clone())
+                .collect::<Vec<_>>();
+
+            if entries.is_empty() {
+                continue;
+            }
+
+            let mut c = DColumn::new();
+            c.set_entries(protobuf::RepeatedField::from_vec(entries));
+            keys.push(key.to_owned());
+            cols.push(c);
+        }
+
+        let mut d = DRow::new();
+        d.set_columns(protobuf::RepeatedField::from_vec(cols));
+        d.set_keys(protobuf::RepeatedField::from_vec(keys));
+        d
+    }
+}
+
+// The family of the column: its name up to and including the first
+// colon, if it has one.
+pub fn family(column: &str) -> &str {
+    match column.find(':') {
+        Some(i) => &column[..i + 1],
+        None    => ""
+    }
+}
+
+// Write the row as one block per run of columns in the same family, and
+// return the number of bytes written, along with where each block starts
+// relative to the start of the row. A row with only one uncompressed
+// block is written as it is, and doesn't need any blocks in its header
+// entry.
+pub fn write_row(row: &DRow, policies: &Families, w: &mut io::Write) -> protobuf::ProtobufResult<(u64, Vec<FamilyBlock>)> {
+    let keys = row.get_keys();
+    let mut starts = vec![0];
+    for i in 1..keys.len() {
+        if family(&keys[i]) != family(&keys[i - 1]) {
+            starts.push(i);
+        }
+    }
+
+    let compressed = keys.first().map_or(false, |k| policies.is_compressed(family(k)));
+    if starts.len() == 1 && !compressed {
+        row.write_to_writer(w)?;
+        return Ok((row.get_cached_size() as u64, vec![]));
+    }
+
+    let mut offset = 0;
+    let mut blocks = vec![];
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).cloned().unwrap_or(keys.len());
+        let mut part = DRow::new();
+        part.set_keys(protobuf::RepeatedField::from_vec(keys[start..end].to_vec()));
+        part.set_columns(protobuf::RepeatedField::from_vec(row.get_columns()[start..end].to_vec()));
+        if policies.is_compressed(family(&keys[start])) {
+            part = compress(&part)?;
+        }
+        part.write_to_writer(w)?;
+
+        let mut block = FamilyBlock::new();
+        block.set_family(family(&keys[start]).to_owned());
+        block.set_offset(offset);
+        blocks.push(block);
+        offset += part.get_cached_size() as u64;
+    }
+    Ok((offset, blocks))
+}
+
+fn compress(part: &DRow) -> protobuf::ProtobufResult<DRow> {
+    let mut encoder = GzEncoder::new(vec![], Compression::Default);
+    part.write_to_writer(&mut encoder)?;
+    let mut d = DRow::new();
+    d.set_compressed(protobuf::RepeatedField::from_vec(vec![encoder.finish()?]));
+    Ok(d)
+}
+
+// Replace the row's compressed blocks with the columns in them, keeping
+// the columns in order.
+pub fn expand(mut row: DRow) -> io::Result<DRow> {
+    if row.get_compressed().is_empty() {
+        return Ok(row);
+    }
+
+    let mut pairs = row.take_keys().into_vec()
+        .into_iter()
+        .zip(row.take_columns().into_vec())
+        .collect::<Vec<_>>();
+    for data in row.take_compressed().into_vec() {
+        let mut decoder = GzDecoder::new(&data[..])?;
+        let mut part = protobuf::parse_from_reader::<DRow>(&mut decoder)?;
+        pairs.extend(part.take_keys().into_vec().into_iter().zip(part.take_columns().into_vec()));
+    }
+    pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let (keys, cols): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+    row.set_keys(protobuf::RepeatedField::from_vec(keys));
+    row.set_columns(protobuf::RepeatedField::from_vec(cols));
+    Ok(row)
+}
+
+// The byte ranges of a row of the given length, relative to its start,
+// which hold the blocks of the families of the columns. Neighbouring
+// blocks are joined into one range. Returns None if the whole row has
+// to be read anyway, e.g. because it isn't split into blocks.
+pub fn ranges(blocks: &[FamilyBlock], length: u64, cols: &[&str]) -> Option<Vec<(u64, u64)>> {
+    if blocks.is_empty() {
+        return None;
+    }
+
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    let mut skipped = false;
+    for (i, block) in blocks.iter().enumerate() {
+        if !cols.iter().any(|c| family(c) == block.get_family()) {
+            skipped = true;
+            continue;
+        }
+
+        let end = blocks.get(i + 1).map(|b| b.get_offset()).unwrap_or(length);
+        if let Some(last) = ranges.last_mut() {
+            if last.1 == block.get_offset() {
+                last.1 = end;
+                continue;
+            }
+        }
+        ranges.push((block.get_offset(), end));
+    }
+
+    match skipped {
+        true  => Some(ranges),
+        false => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf;
+    use generated::dtable::*;
+
+    fn row(columns: &[(&str, &str)]) -> DRow {
+        versioned_row(&columns.iter().map(|&(k, v)| (k, vec![(10, v)])).collect::<Vec<_>>())
+    }
+
+    fn versioned_row(columns: &[(&str, Vec<(u64, &str)>)]) -> DRow {
+        let mut r = DRow::new();
+        r.set_keys(protobuf::RepeatedField::from_vec(columns.iter().map(|&(k, _)| k.to_owned()).collect()));
+        r.set_columns(protobuf::RepeatedField::from_vec(columns.iter().map(|&(_, ref entries)| {
+            let mut c = DColumn::new();
+            c.set_entries(protobuf::RepeatedField::from_vec(entries.iter().map(|&(t, v)| {
+                let mut e = DEntry::new();
+                e.set_timestamp(t);
+                e.set_value(v.as_bytes().to_vec());
+                e
+            }).collect()));
+            c
+        }).collect()));
+        r
+    }
+
+    #[test]
+    fn finds_families() {
+        assert_eq!(super::family("meta:owner"), "meta:");
+        assert_eq!(super::family("data:a:b"), "data:");
+        assert_eq!(super::family("name"), "");
+        assert_eq!(super::family(":x"), ":");
+    }
+
+    #[test]
+    fn writes_rows_in_blocks() {
+        let r = row(&[("a", "1"), ("data:body", "a large value"), ("meta:owner", "alice"), ("z", "2")]);
+        let mut data = vec![];
+        let (length, blocks) = super::write_row(&r, &super::Families::new(), &mut data).unwrap();
+        assert_eq!(length, data.len() as u64);
+        assert_eq!(
+            blocks.iter().map(|b| b.get_family()).collect::<Vec<_>>(),
+            vec!["", "data:", "meta:", ""]
+        );
+
+        // The blocks parse as the whole row.
+        let parsed = protobuf::parse_from_bytes::<DRow>(&data).unwrap();
+        assert_eq!(format!("{}", parsed), format!("{}", r));
+
+        // Only the meta: block is read for meta: columns, and both of the
+        // default family's blocks are read for ungrouped columns.
+        let meta = super::ranges(&blocks, length, &["meta:owner"]).unwrap();
+        assert_eq!(meta, vec![(blocks[2].get_offset(), blocks[3].get_offset())]);
+        let parsed = protobuf::parse_from_bytes::<DRow>(&data[meta[0].0 as usize..meta[0].1 as usize]).unwrap();
+        assert_eq!(parsed.get_keys(), &[String::from("meta:owner")]);
+        assert_eq!(
+            super::ranges(&blocks, length, &["z"]).unwrap(),
+            vec![(0, blocks[1].get_offset()), (blocks[3].get_offset(), length)]
+        );
+        assert_eq!(
+            super::ranges(&blocks, length, &["meta:owner", "z"]).unwrap(),
+            vec![(0, blocks[1].get_offset()), (blocks[2].get_offset(), length)]
+        );
+        assert_eq!(super::ranges(&blocks, length, &["other:x"]).unwrap(), vec![]);
+        assert_eq!(super::ranges(&blocks, length, &["a", "data:body", "meta:owner"]), None);
+    }
+
+    #[test]
+    fn writes_rows_with_one_family_as_they_are() {
+        let r = row(&[("a", "1"), ("b", "2")]);
+        let mut data = vec![];
+        let (length, blocks) = super::write_row(&r, &super::Families::new(), &mut data).unwrap();
+        assert_eq!(length, data.len() as u64);
+        assert!(blocks.is_empty());
+        assert_eq!(super::ranges(&blocks, length, &["a"]), None);
+    }
+
+    #[test]
+    fn compresses_blocks() {
+        let mut policies = super::Families::new();
+        policies.add_family(super::FamilyPolicy{
+            compression: super::Codec::Gzip,
+            ..super::FamilyPolicy::new("data:")
+        });
+
+        let body = "a large value which compresses well ".repeat(100);
+        let r = row(&[("a", "1"), ("data:body", body.as_str()), ("meta:owner", "alice")]);
+        let mut data = vec![];
+        let (length, blocks) = super::write_row(&r, &policies, &mut data).unwrap();
+        assert_eq!(length, data.len() as u64);
+        assert!(length < body.len() as u64);
+
+        // The compressed block only shows up once the row is expanded.
+        let parsed = protobuf::parse_from_bytes::<DRow>(&data).unwrap();
+        assert_eq!(parsed.get_keys(), &[String::from("a"), String::from("meta:owner")]);
+        assert_eq!(parsed.get_compressed().len(), 1);
+        let expanded = super::expand(parsed).unwrap();
+        assert!(expanded.get_compressed().is_empty());
+        assert_eq!(format!("{}", expanded), format!("{}", r));
+
+        // A row in only a compressed family is still split into a block.
+        let r = row(&[("data:body", body.as_str())]);
+        let mut data = vec![];
+        let (_, blocks) = super::write_row(&r, &policies, &mut data).unwrap();
+        assert_eq!(blocks.len(), 1);
+        let parsed = protobuf::parse_from_bytes::<DRow>(&data).unwrap();
+        assert_eq!(format!("{}", super::expand(parsed).unwrap()), format!("{}", r));
+    }
+
+    #[test]
+    fn retains_versions_by_policy() {
+        let mut policies = super::Families::new();
+        policies.add_family(super::FamilyPolicy{
+            max_versions: 2,
+            ..super::FamilyPolicy::new("history:")
+        });
+        policies.add_family(super::FamilyPolicy{
+            ttl_ms: 1,
+            cache: false,
+            ..super::FamilyPolicy::new("session:")
+        });
+        assert!(policies.has_retention());
+        assert!(policies.is_cached("history:a"));
+        assert!(!policies.is_cached("session:token"));
+
+        let r = versioned_row(&[
+            ("history:a", vec![(1, "a"), (2, "b"), (3, "c")]),
+            ("name", vec![(1, "x"), (2, "y"), (3, "z")]),
+            ("session:token", vec![(1_000_000, "old"), (5_000_000, "new")])
+        ]);
+        let now = 5_500_000;
+        let retained = policies.retain(&r, now, u64::max_value());
+        assert_eq!(format!("{}", retained), format!("{}", versioned_row(&[
+            ("history:a", vec![(2, "b"), (3, "c")]),
+            ("name", vec![(1, "x"), (2, "y"), (3, "z")]),
+            ("session:token", vec![(5_000_000, "new")])
+        ])));
+
+        // Versions newer than gc_before are kept for open snapshots.
+        let retained = policies.retain(&r, now, 1);
+        assert_eq!(format!("{}", retained), format!("{}", versioned_row(&[
+            ("history:a", vec![(2, "b"), (3, "c")]),
+            ("name", vec![(1, "x"), (2, "y"), (3, "z")]),
+            ("session:token", vec![(1_000_000, "old"), (5_000_000, "new")])
+        ])));
+
+        // Expired columns are dropped, and so are uncached ones from cached rows.
+        let retained = policies.retain(&r, 100_000_000, u64::max_value());
+        assert_eq!(retained.get_keys(), &[String::from("history:a"), String::from("name")]);
+        assert_eq!(policies.cached_columns(&r).get_keys(), &[String::from("history:a"), String::from("name")]);
+    }
+}
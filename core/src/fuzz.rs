@@ -0,0 +1,233 @@
+/*
+    fuzz.rs
+
+    This file contains randomized tests which feed corrupted data to
+    the parsers for dtables and the commit log, and check that merges
+    never lose any data.
+*/
+
+use std;
+use std::io::{Read, Write};
+use std::iter::FromIterator;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rand;
+use rand::Rng;
+use time;
+use protobuf;
+use protobuf::Message;
+
+use base;
+use dtable;
+use families;
+use keyindex;
+use mtable;
+use query;
+use storage;
+use generated::dtable::*;
+
+const ITERATIONS: usize = 200;
+
+fn fuzz_directory() -> String {
+    let directory = format!("/tmp/largetable/fuzz-{}", time::precise_time_ns());
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn random_string(rng: &mut rand::ThreadRng) -> String {
+    let length = rng.gen_range(1, 8);
+    (0..length).map(|_| rng.gen_range(b'a', b'f') as char).collect()
+}
+
+// Corrupt the data by flipping, removing or inserting a few random bytes.
+fn mutate(rng: &mut rand::ThreadRng, data: &[u8]) -> Vec<u8> {
+    let mut output = data.to_vec();
+    for _ in 0..rng.gen_range(1, 5) {
+        match rng.gen_range(0, 3) {
+            0 if !output.is_empty() => {
+                let i = rng.gen_range(0, output.len());
+                output[i] = rng.gen();
+            },
+            1 if !output.is_empty() => {
+                let i = rng.gen_range(0, output.len());
+                output.truncate(i);
+            },
+            _ => {
+                let i = rng.gen_range(0, output.len() + 1);
+                output.insert(i, rng.gen());
+            }
+        }
+    }
+    output
+}
+
+// Build a memtable full of random rows, some of which have several
+// versions of the same column.
+fn random_memtable(rng: &mut rand::ThreadRng) -> mtable::MTable {
+    let mut m = mtable::MTable::new();
+    for _ in 0..rng.gen_range(1, 30) {
+        let updates = (0..rng.gen_range(1, 4))
+            .map(|_| query::MUpdate::new(&random_string(rng), random_string(rng).into_bytes()))
+            .collect::<Vec<_>>();
+        m.update(&random_string(rng), &updates, rng.gen_range(1, 1000)).unwrap();
+    }
+    m
+}
+
+fn write_dtable(directory: &str, name: &str, m: &mtable::MTable) -> dtable::DTable {
+    let filename = format!("{}/{}.dtable", directory, name);
+    let mut f = std::fs::File::create(&filename).unwrap();
+    let mut h = std::fs::File::create(format!("{}.header", filename)).unwrap();
+    let header = m.write_to_writer(&mut f, &mut h, time::precise_time_ns(), 1).unwrap();
+    dtable::DTable::from_dtableheader(Arc::new(storage::DiskStorage), filename, header)
+}
+
+// Count every entry stored in the dtable, grouped by row and column.
+fn count_entries(d: &dtable::DTable) -> HashMap<(String, String), usize> {
+    let mut counts = HashMap::new();
+    for entry in d.lookup.get_entries() {
+        let row = d.get_row(entry.get_key()).unwrap();
+        for (key, column) in row.get_keys().iter().zip(row.get_columns().iter()) {
+            *counts.entry((entry.get_key().to_owned(), key.to_owned())).or_insert(0) += column.get_entries().len();
+        }
+    }
+    counts
+}
+
+#[test]
+fn corrupted_dtable_headers_are_rejected() {
+    let mut rng = rand::thread_rng();
+    let directory = fuzz_directory();
+
+    for i in 0..ITERATIONS {
+        let m = random_memtable(&mut rng);
+        let d = write_dtable(&directory, &format!("{}", i), &m);
+        let header = keyindex::compress(&d.lookup).write_to_bytes().unwrap();
+
+        let filename = format!("{}/{}.dtable.header", directory, i);
+        std::fs::File::create(&filename).unwrap().write_all(&mutate(&mut rng, &header)).unwrap();
+
+        // Either the header is rejected, or every lookup in it should
+        // fail cleanly rather than panicking.
+        if let Ok(d) = dtable::DTable::new(Arc::new(storage::DiskStorage), format!("{}/{}.dtable", directory, i)) {
+            for entry in d.lookup.get_entries() {
+                d.get_row(entry.get_key()).ok();
+            }
+            d.get_row(&random_string(&mut rng)).ok();
+        }
+
+        // The same goes for a paged header, which is checked as it's read.
+        if let Ok(d) = dtable::DTable::open_paged(Arc::new(storage::DiskStorage), format!("{}/{}.dtable", directory, i), 2) {
+            for key in m.keys_from("", usize::max_value()) {
+                d.get_row(&key).ok();
+            }
+            d.get_row(&random_string(&mut rng)).ok();
+            d.entries().ok();
+        }
+    }
+}
+
+#[test]
+fn corrupted_rows_are_rejected() {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..ITERATIONS {
+        let mut r = DRow::new();
+        let mut keys = (0..rng.gen_range(1, 5)).map(|_| random_string(&mut rng)).collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        r.set_columns(protobuf::RepeatedField::from_iter(keys.iter().map(|_| {
+            let mut e = DEntry::new();
+            e.set_timestamp(rng.gen());
+            e.set_value(random_string(&mut rng).into_bytes());
+            let mut c = DColumn::new();
+            c.set_entries(protobuf::RepeatedField::from_vec(vec![e]));
+            c
+        })));
+        r.set_keys(protobuf::RepeatedField::from_vec(keys));
+
+        let bytes = mutate(&mut rng, &r.write_to_bytes().unwrap());
+        if let Ok(row) = protobuf::parse_from_bytes::<DRow>(&bytes) {
+            if row.is_valid() {
+                for key in row.get_keys() {
+                    row.get_latest_value(key).ok();
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn corrupted_commit_logs_are_rejected() {
+    let mut rng = rand::thread_rng();
+
+    let directory = fuzz_directory();
+    let mut database = base::Base::new(&directory, 1 << 20, 10);
+    for _ in 0..20 {
+        database.update(
+            &random_string(&mut rng),
+            vec![query::MUpdate::new(&random_string(&mut rng), random_string(&mut rng).into_bytes())],
+            rng.gen_range(1, 1000)
+        );
+    }
+    database.delete_range("b", "c", 500);
+
+    let mut log = vec![];
+    std::fs::File::open(format!("{}/commit.log", directory))
+        .unwrap()
+        .read_to_end(&mut log)
+        .unwrap();
+
+    for i in 0..ITERATIONS {
+        let replay_directory = format!("{}/{}", directory, i);
+        std::fs::create_dir_all(&replay_directory).unwrap();
+        std::fs::File::create(format!("{}/commit.log", replay_directory))
+            .unwrap()
+            .write_all(&mutate(&mut rng, &log))
+            .unwrap();
+
+        // Replaying a corrupted commit log may fail, but it mustn't panic.
+        let mut replay = base::Base::new(&replay_directory, 1 << 20, 10);
+        replay.load().ok();
+    }
+}
+
+#[test]
+fn merges_preserve_entries() {
+    let mut rng = rand::thread_rng();
+    let directory = fuzz_directory();
+
+    for i in 0..ITERATIONS / 10 {
+        let tables = (0..rng.gen_range(2, 5))
+            .map(|j| write_dtable(&directory, &format!("{}-{}", i, j), &random_memtable(&mut rng)))
+            .collect::<Vec<_>>();
+
+        let mut expected = HashMap::new();
+        for d in &tables {
+            for (key, count) in count_entries(d) {
+                *expected.entry(key).or_insert(0) += count;
+            }
+        }
+
+        let merged = dtable::DTable::from_vec(
+            Arc::new(storage::DiskStorage),
+            &format!("{}/{}-merged.dtable", directory, i),
+            &tables,
+            &[],
+            &families::Families::new(),
+            dtable::CompactionOptions{
+                sync: false,
+                bytes_per_second: 0,
+                gc_before: std::u64::MAX,
+                created: time::precise_time_ns(),
+                max_bytes: 0,
+                drop_cache: false,
+                soft_deleted_before: 0,
+                max_fan_in: 0
+            }
+        ).unwrap();
+
+        assert_eq!(count_entries(&merged), expected);
+    }
+}
@@ -0,0 +1,213 @@
+/*
+    hints.rs
+
+    Hinted handoff: while a replica is down, the commit log entries that
+    it misses are queued up in a hint file for that peer, and replayed to
+    it when it comes back, instead of leaving it stale until the next
+    full repair. Hint files are bounded by size and by the age of the
+    hints in them. Hints which don't fit, or which expire, are dropped
+    and counted, since the peer then needs a full repair after all.
+
+    Each hint is stored as the time it was queued (8 bytes), the size of
+    the entry (4 bytes), and then the CommitLogEntry itself.
+*/
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use protobuf;
+use protobuf::Message;
+
+use generated::dtable::CommitLogEntry;
+use storage;
+
+pub struct HintLog {
+    storage: Arc<storage::Storage>,
+    path: String,
+    file: Box<storage::StorageFile>,
+    bytes: u64,
+
+    // The most bytes of hints to keep for the peer, and how long to keep
+    // each hint for, or 0 for no limit.
+    pub max_bytes: u64,
+    pub max_age_ms: u64,
+
+    // Hints which were dropped, because the log was full or they expired.
+    pub dropped: u64
+}
+
+impl HintLog {
+    // Open the hint file for the peer, keeping any hints which were
+    // queued before a restart.
+    pub fn open(storage: Arc<storage::Storage>, directory: &str, peer: &str) -> io::Result<HintLog> {
+        storage.create_dir_all(directory)?;
+        let path = format!("{}/{}.hints", directory, peer);
+        let file = storage.append(&path)?;
+        let bytes = file.len()?;
+
+        Ok(HintLog{
+            storage: storage,
+            path: path,
+            file: file,
+            bytes: bytes,
+            max_bytes: 0,
+            max_age_ms: 0,
+            dropped: 0
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0
+    }
+
+    // Queue the entry for the peer at time `now`. Returns false if the
+    // hint log is full, in which case the entry is dropped.
+    pub fn add(&mut self, entry: &CommitLogEntry, now: u64) -> io::Result<bool> {
+        let size = entry.compute_size();
+        let length = 12 + size as u64;
+        if self.max_bytes > 0 && self.bytes + length > self.max_bytes {
+            self.dropped += 1;
+            return Ok(false);
+        }
+
+        let mut record = Vec::with_capacity(length as usize);
+        record.write_u64::<LittleEndian>(now)?;
+        record.write_u32::<LittleEndian>(size)?;
+        entry.write_to_writer(&mut record)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to encode hint"))?;
+
+        self.file.write_all(&record)?;
+        self.file.sync()?;
+        self.bytes += length;
+        Ok(true)
+    }
+
+    // Hand the queued entries to `send`, oldest first, skipping any which
+    // have expired by `now`. If `send` fails (e.g. the peer went down
+    // again), the remaining hints are kept for next time. Returns the
+    // number of entries which were sent.
+    pub fn replay<F>(&mut self, now: u64, mut send: F) -> io::Result<usize>
+        where F: FnMut(&CommitLogEntry) -> bool
+    {
+        let mut hints = vec![];
+        let mut file = self.storage.open(&self.path)?;
+        // A hint which was only partly written before a crash is ignored.
+        loop {
+            let (queued, size) = match (file.read_u64::<LittleEndian>(), file.read_u32::<LittleEndian>()) {
+                (Ok(t), Ok(n))  => (t, n),
+                _               => break
+            };
+            let mut buf = vec![0; size as usize];
+            if file.read_exact(&mut buf).is_err() {
+                break;
+            }
+            hints.push((queued, buf));
+        }
+
+        let max_age = self.max_age_ms * 1_000_000;
+        let mut sent = 0;
+        let mut remaining = vec![];
+        for (queued, buf) in hints {
+            if !remaining.is_empty() {
+                remaining.push((queued, buf));
+                continue;
+            }
+
+            if max_age > 0 && queued + max_age < now {
+                self.dropped += 1;
+                continue;
+            }
+
+            let entry = protobuf::parse_from_bytes::<CommitLogEntry>(&buf)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupted hint"))?;
+            if send(&entry) {
+                sent += 1;
+            } else {
+                remaining.push((queued, buf));
+            }
+        }
+
+        // Whatever wasn't sent is written to a new file, which replaces
+        // the old one.
+        let temporary = format!("{}.tmp", self.path);
+        let mut bytes = 0;
+        {
+            let mut f = self.storage.create(&temporary)?;
+            for &(queued, ref buf) in &remaining {
+                f.write_u64::<LittleEndian>(queued)?;
+                f.write_u32::<LittleEndian>(buf.len() as u32)?;
+                f.write_all(buf)?;
+                bytes += 12 + buf.len() as u64;
+            }
+            f.sync()?;
+        }
+        self.storage.rename(&temporary, &self.path)?;
+        self.file = self.storage.append(&self.path)?;
+        self.bytes = bytes;
+
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use generated::dtable::CommitLogEntry;
+    use storage;
+
+    const MS: u64 = 1_000_000;
+
+    fn entry(row: &str) -> CommitLogEntry {
+        let mut c = CommitLogEntry::new();
+        c.set_key(row.to_owned());
+        c.set_timestamp(1);
+        c
+    }
+
+    #[test]
+    fn replays_hints_when_peer_returns() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut hints = super::HintLog::open(storage.clone(), "/hints", "peer1").unwrap();
+        assert!(hints.is_empty());
+
+        for row in &["a", "b", "c"] {
+            assert!(hints.add(&entry(row), 0).unwrap());
+        }
+
+        // The peer fails partway through, so the rest are kept, even
+        // across a restart.
+        let mut received = vec![];
+        let sent = hints.replay(0, |e| {
+            received.push(e.get_key().to_owned());
+            received.len() < 2
+        }).unwrap();
+        assert_eq!(sent, 1);
+
+        let mut hints = super::HintLog::open(storage.clone(), "/hints", "peer1").unwrap();
+        let mut received = vec![];
+        hints.replay(0, |e| { received.push(e.get_key().to_owned()); true }).unwrap();
+        assert_eq!(received, vec![String::from("b"), String::from("c")]);
+        assert!(hints.is_empty());
+    }
+
+    #[test]
+    fn drops_hints_beyond_limits() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut hints = super::HintLog::open(storage, "/hints", "peer1").unwrap();
+        hints.max_bytes = 50;
+        hints.max_age_ms = 100;
+
+        assert!(hints.add(&entry("a"), 0).unwrap());
+        assert!(hints.add(&entry("b"), 50 * MS).unwrap());
+        assert!(!hints.add(&entry("c"), 50 * MS).unwrap());
+        assert_eq!(hints.dropped, 1);
+
+        // By now, the first hint has expired.
+        let mut received = vec![];
+        hints.replay(120 * MS, |e| { received.push(e.get_key().to_owned()); true }).unwrap();
+        assert_eq!(received, vec![String::from("b")]);
+        assert_eq!(hints.dropped, 2);
+    }
+}
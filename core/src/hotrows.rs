@@ -0,0 +1,257 @@
+/*
+    hotrows.rs
+
+    Every query goes through the same lock, so a single row which is
+    read or written far more than the others slows down everyone. The
+    HotRows tracker counts reads and writes per row in count-min
+    sketches, which take a fixed amount of memory no matter how many rows
+    there are, and remembers the handful of rows with the highest counts.
+    Counts are kept for a window of a few seconds at a time, so that they
+    can be turned into rates.
+*/
+
+use std::cmp;
+use std::collections::HashMap;
+
+use query::HotKey;
+
+// Each window of counts covers this long. The previous window is kept
+// as well, so that rates don't drop to nothing whenever a window ends.
+const WINDOW_NS: u64 = 10 * 1_000_000_000;
+
+// The dimensions of each sketch. A row's count is overestimated by at
+// most about 2/WIDTH of the total count, with high probability.
+const WIDTH: usize = 2048;
+const DEPTH: usize = 4;
+
+// The number of rows which are tracked by default.
+pub const DEFAULT_CAPACITY: usize = 32;
+
+fn fnv1a(seed: u64, data: &[u8]) -> u64 {
+    data.iter().fold(seed, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+// A CountMinSketch counts how many times it has seen each key. A count
+// is never underestimated, but collisions can make it too high.
+pub struct CountMinSketch {
+    counts: Vec<u32>
+}
+
+impl CountMinSketch {
+    pub fn new() -> CountMinSketch {
+        CountMinSketch{
+            counts: vec![0; WIDTH * DEPTH]
+        }
+    }
+
+    fn cells(key: &str) -> Vec<usize> {
+        (0..DEPTH)
+            .map(|i| i * WIDTH + (fnv1a(0xcbf29ce484222325 ^ i as u64, key.as_bytes()) as usize % WIDTH))
+            .collect()
+    }
+
+    // Count the key once more, and return its new count.
+    pub fn increment(&mut self, key: &str) -> u64 {
+        let mut count = u32::max_value();
+        for cell in CountMinSketch::cells(key) {
+            self.counts[cell] = self.counts[cell].saturating_add(1);
+            count = cmp::min(count, self.counts[cell]);
+        }
+        count as u64
+    }
+
+    pub fn estimate(&self, key: &str) -> u64 {
+        CountMinSketch::cells(key).into_iter()
+            .map(|cell| self.counts[cell])
+            .min()
+            .unwrap_or(0) as u64
+    }
+
+    pub fn clear(&mut self) {
+        for c in self.counts.iter_mut() {
+            *c = 0;
+        }
+    }
+}
+
+// The counts for one of the rows being tracked in the current window.
+#[derive(Clone, Debug, Default)]
+struct Tracked {
+    reads: u64,
+    writes: u64,
+    warned: bool
+}
+
+// A HotRows tracker remembers up to capacity rows. Once a row's reads or
+// writes go over qps_limit per second, it's reported as hot, once per
+// window. A qps_limit of zero means that rows are never reported.
+pub struct HotRows {
+    pub capacity: usize,
+    pub qps_limit: u64,
+
+    reads: CountMinSketch,
+    writes: CountMinSketch,
+    top: HashMap<String, Tracked>,
+    previous: HashMap<String, Tracked>,
+    window_start: u64,
+    previous_start: Option<u64>
+}
+
+impl HotRows {
+    pub fn new(capacity: usize, now: u64) -> HotRows {
+        HotRows{
+            capacity: capacity,
+            qps_limit: 0,
+            reads: CountMinSketch::new(),
+            writes: CountMinSketch::new(),
+            top: HashMap::new(),
+            previous: HashMap::new(),
+            window_start: now,
+            previous_start: None
+        }
+    }
+
+    // Start a new window, if the current one has run out.
+    fn advance(&mut self, now: u64) {
+        if now < self.window_start + WINDOW_NS {
+            return;
+        }
+
+        // If the current window ended long ago, there's nothing worth
+        // keeping from it either.
+        self.previous = if now < self.window_start + 2 * WINDOW_NS {
+            self.previous_start = Some(self.window_start);
+            self.top.drain().collect()
+        } else {
+            self.previous_start = None;
+            self.top.clear();
+            HashMap::new()
+        };
+        self.reads.clear();
+        self.writes.clear();
+        self.window_start = now;
+    }
+
+    // The number of seconds that the counts cover, which is at least one,
+    // so that a burst at the start of a window isn't blown out of
+    // proportion.
+    fn seconds(&self, now: u64, include_previous: bool) -> f64 {
+        let start = match (include_previous, self.previous_start) {
+            (true, Some(s)) => s,
+            _               => self.window_start
+        };
+        let elapsed = now.saturating_sub(start) as f64 / 1_000_000_000.0;
+        if elapsed < 1.0 { 1.0 } else { elapsed }
+    }
+
+    // Count a read or write of the row. If it makes the row go over the
+    // QPS limit for the first time this window, returns its rate.
+    pub fn record(&mut self, row: &str, write: bool, now: u64) -> Option<f64> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        self.advance(now);
+        let (reads, writes) = match write {
+            true  => (self.reads.estimate(row), self.writes.increment(row)),
+            false => (self.reads.increment(row), self.writes.estimate(row))
+        };
+
+        // The row replaces the coldest tracked row if it's hotter.
+        if !self.top.contains_key(row) {
+            if self.top.len() >= self.capacity {
+                let coldest = self.top.iter()
+                    .min_by_key(|&(_, t)| t.reads + t.writes)
+                    .map(|(k, t)| (k.clone(), t.reads + t.writes));
+                match coldest {
+                    Some((ref k, count)) if count < reads + writes => { self.top.remove(k); },
+                    _ => return None
+                }
+            }
+            self.top.insert(row.to_owned(), Tracked::default());
+        }
+
+        let seconds = self.seconds(now, false);
+        let qps_limit = self.qps_limit;
+        let tracked = self.top.get_mut(row).unwrap();
+        tracked.reads = reads;
+        tracked.writes = writes;
+
+        let rate = cmp::max(reads, writes) as f64 / seconds;
+        if qps_limit == 0 || tracked.warned || rate <= qps_limit as f64 {
+            return None;
+        }
+        tracked.warned = true;
+        Some(rate)
+    }
+
+    // Returns up to limit of the hottest rows, hottest first, with their
+    // rates over the current and previous windows.
+    pub fn top_keys(&self, limit: usize, now: u64) -> Vec<HotKey> {
+        let mut counts: HashMap<&str, (u64, u64)> = HashMap::new();
+        for (k, t) in self.previous.iter().chain(self.top.iter()) {
+            let c = counts.entry(k.as_str()).or_insert((0, 0));
+            c.0 += t.reads;
+            c.1 += t.writes;
+        }
+
+        let seconds = self.seconds(now, true);
+        let mut keys = counts.into_iter()
+            .map(|(k, (reads, writes))| HotKey{
+                key: k.to_owned(),
+                reads_per_second: reads as f64 / seconds,
+                writes_per_second: writes as f64 / seconds
+            })
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.key.cmp(&b.key));
+        keys.sort_by(|a, b| {
+            (b.reads_per_second + b.writes_per_second)
+                .partial_cmp(&(a.reads_per_second + a.writes_per_second))
+                .unwrap_or(cmp::Ordering::Equal)
+        });
+        keys.truncate(limit);
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn sketch_never_undercounts() {
+        let mut sketch = super::CountMinSketch::new();
+        for i in 0..10000 {
+            sketch.increment(&format!("row{}", i % 100));
+        }
+        for i in 0..100 {
+            assert!(sketch.estimate(&format!("row{}", i)) >= 100);
+        }
+        assert!(sketch.estimate("missing") < 100);
+    }
+
+    #[test]
+    fn reports_hot_rows() {
+        let mut hot = super::HotRows::new(2, 0);
+        hot.qps_limit = 100;
+
+        let mut reported = vec![];
+        for i in 0..1000 {
+            if let Some(rate) = hot.record("hot", i % 2 == 0, i) {
+                reported.push(rate);
+            }
+            hot.record(&format!("cold{}", i % 3), false, i);
+        }
+
+        // The row is only reported once per window.
+        assert_eq!(reported, vec![101.0]);
+
+        let top = hot.top_keys(10, 1000);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].key, "hot");
+        assert_eq!((top[0].reads_per_second, top[0].writes_per_second), (500.0, 500.0));
+
+        // A later window still remembers the previous one.
+        let later = super::WINDOW_NS + 1000;
+        hot.record("other", false, later);
+        assert_eq!(hot.top_keys(1, later)[0].key, "hot");
+    }
+}
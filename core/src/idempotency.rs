@@ -0,0 +1,90 @@
+/*
+    idempotency.rs
+
+    Clients can attach an idempotency key to a write, so that retrying
+    it after a timeout doesn't apply it twice. The database remembers the
+    keys of recent writes which succeeded, and a retry with one of those
+    keys is answered without being run again.
+*/
+
+use std::collections::{HashSet, VecDeque};
+
+// The number of keys remembered by default.
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+// An IdempotencyCache holds up to capacity keys. When it's full, the
+// least recently used key is forgotten. A capacity of zero disables it.
+pub struct IdempotencyCache {
+    pub capacity: usize,
+    keys: HashSet<String>,
+    order: VecDeque<String>
+}
+
+impl IdempotencyCache {
+    pub fn new(capacity: usize) -> IdempotencyCache {
+        IdempotencyCache{
+            capacity: capacity,
+            keys: HashSet::new(),
+            order: VecDeque::new()
+        }
+    }
+
+    // Check whether the key has been seen, and if it has, mark it as
+    // recently used.
+    pub fn check(&mut self, key: &str) -> bool {
+        if !self.keys.contains(key) {
+            return false;
+        }
+
+        if let Some(position) = self.order.iter().position(|k| k == key) {
+            let k = self.order.remove(position).unwrap();
+            self.order.push_back(k);
+        }
+        true
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        if self.capacity == 0 || self.check(key) {
+            return;
+        }
+
+        while self.order.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(k) => self.keys.remove(&k),
+                None    => break
+            };
+        }
+        self.keys.insert(key.to_owned());
+        self.order.push_back(key.to_owned());
+    }
+
+    // The remembered keys, least recently used first.
+    pub fn keys(&self) -> Vec<String> {
+        self.order.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn forgets_least_recently_used_keys() {
+        let mut cache = super::IdempotencyCache::new(2);
+        cache.insert("a");
+        cache.insert("b");
+        assert!(cache.check("a"));
+
+        // "b" is now the least recently used.
+        cache.insert("c");
+        assert!(cache.check("a"));
+        assert!(!cache.check("b"));
+        assert!(cache.check("c"));
+        assert_eq!(cache.keys(), vec![String::from("a"), String::from("c")]);
+    }
+
+    #[test]
+    fn can_be_disabled() {
+        let mut cache = super::IdempotencyCache::new(0);
+        cache.insert("a");
+        assert!(!cache.check("a"));
+    }
+}
@@ -0,0 +1,561 @@
+/*
+    keyindex.rs
+
+    The header of a dtable lists every row key, and for dtables with
+    millions of rows, most of the header is keys which share a long
+    prefix with the key before them. When a header is written, each key
+    only stores the part after the prefix it shares with the previous
+    key, along with the length of that prefix. Every RESTART_INTERVAL
+    entries the whole key is stored, so the header can be expanded
+    starting from any restart point. Headers without prefix_compressed
+    set store every key whole, and are read as they are.
+
+    Once loaded, lookups go through a FenceIndex, which keeps every
+    FENCE_INTERVAL'th key in a small array of its own. A lookup binary
+    searches the fences, which stay in cache, and then only the block of
+    entries between two fences, rather than jumping around the whole
+    header.
+
+    A PagedIndex goes further, for when the headers of every dtable
+    don't fit in memory. It only keeps the fences, and where each block
+    of entries is in the header file. Blocks are read from the file as
+    lookups need them, and the most recently read are cached. Since
+    FENCE_INTERVAL is a multiple of RESTART_INTERVAL, every block starts
+    at a restart point and can be expanded on its own.
+*/
+
+use std::io;
+use std::io::{Read, Seek};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use protobuf;
+use protobuf::Message;
+
+use storage::Storage;
+use generated::dtable::{DTableHeader, DTableHeaderEntry};
+
+pub const RESTART_INTERVAL: usize = 16;
+pub const FENCE_INTERVAL: usize = 64;
+
+// The length in bytes of the prefix shared by the two keys, backed off
+// so that it ends on a character boundary.
+fn shared_prefix(a: &str, b: &str) -> usize {
+    let mut shared = a.bytes().zip(b.bytes()).take_while(|&(x, y)| x == y).count();
+    while !b.is_char_boundary(shared) {
+        shared -= 1;
+    }
+    shared
+}
+
+// Returns a copy of the header with its keys prefix compressed.
+pub fn compress(header: &DTableHeader) -> DTableHeader {
+    let mut compressed = header.clone();
+    let mut previous = "";
+    for (i, (entry, original)) in compressed.mut_entries().iter_mut()
+        .zip(header.get_entries().iter())
+        .enumerate()
+    {
+        let key = original.get_key();
+        let shared = match i % RESTART_INTERVAL {
+            0 => 0,
+            _ => shared_prefix(previous, key)
+        };
+        entry.set_shared(shared as u32);
+        entry.set_key(key[shared..].to_owned());
+        previous = key;
+    }
+    compressed.set_prefix_compressed(true);
+    compressed
+}
+
+// Expand the keys of a prefix compressed header in place. Fails if the
+// prefixes don't fit the keys before them.
+pub fn expand(header: &mut DTableHeader) -> Result<(), String> {
+    if !header.get_prefix_compressed() {
+        return Ok(());
+    }
+
+    let mut previous = String::new();
+    for (i, entry) in header.mut_entries().iter_mut().enumerate() {
+        let shared = entry.get_shared() as usize;
+        if shared > 0 && i % RESTART_INTERVAL == 0 {
+            return Err(format!("entry {} is a restart point but shares a prefix", i));
+        }
+        if shared > previous.len() || !previous.is_char_boundary(shared) {
+            return Err(format!("entry {} shares more than the previous key", i));
+        }
+        let mut key = previous[..shared].to_owned();
+        key.push_str(entry.get_key());
+        entry.set_key(key.clone());
+        entry.set_shared(0);
+        previous = key;
+    }
+    header.set_prefix_compressed(false);
+    Ok(())
+}
+
+// Write the header with its keys prefix compressed.
+pub fn write_header(header: &DTableHeader, w: &mut io::Write) -> protobuf::ProtobufResult<()> {
+    compress(header).write_to_writer(w)
+}
+
+pub struct FenceIndex {
+    fences: Vec<String>
+}
+
+impl FenceIndex {
+    pub fn new(entries: &[DTableHeaderEntry]) -> FenceIndex {
+        FenceIndex{
+            fences: entries.chunks(FENCE_INTERVAL)
+                .map(|block| block[0].get_key().to_owned())
+                .collect()
+        }
+    }
+
+    // Returns the index of the first entry whose key is greater than or
+    // equal to the provided key. The entries must be the ones the index
+    // was built from.
+    pub fn lower_bound(&self, entries: &[DTableHeaderEntry], key: &str) -> usize {
+        let block = match self.fences.binary_search_by(|f| f.as_str().cmp(key)) {
+            Ok(i)   => return i * FENCE_INTERVAL,
+            Err(0)  => return 0,
+            Err(i)  => i - 1
+        };
+
+        let start = block * FENCE_INTERVAL;
+        let end = ::std::cmp::min(start + FENCE_INTERVAL, entries.len());
+        match entries[start..end].binary_search_by(|e| e.get_key().cmp(key)) {
+            Ok(i) | Err(i) => start + i
+        }
+    }
+
+    // Returns the index of the entry with exactly the provided key.
+    pub fn find(&self, entries: &[DTableHeaderEntry], key: &str) -> Option<usize> {
+        let index = self.lower_bound(entries, key);
+        match entries.get(index) {
+            Some(e) if e.get_key() == key => Some(index),
+            _                               => None
+        }
+    }
+}
+
+// A block of up to FENCE_INTERVAL entries in a header file.
+struct Block {
+    // The key and data offset of the first entry in the block.
+    fence: String,
+    offset: u64,
+
+    // Where the block's entries are in the header file.
+    start: u64,
+    end: u64
+}
+
+struct BlockCache {
+    blocks: HashMap<usize, Arc<Vec<DTableHeaderEntry>>>,
+
+    // Blocks in the order they were read, oldest first.
+    order: VecDeque<usize>
+}
+
+pub struct PagedIndex {
+    storage: Arc<Storage>,
+    filename: String,
+    len: usize,
+    blocks: Vec<Block>,
+    cache_blocks: usize,
+    cache: RefCell<BlockCache>
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// Reads protobuf fields one at a time out of a header file, keeping
+// track of where it is in the file.
+struct Stream<R> {
+    inner: R,
+    position: u64
+}
+
+impl<R: Read> Stream<R> {
+    // Returns None at the end of the file.
+    fn varint(&mut self) -> io::Result<Option<u64>> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let mut b = [0];
+            if self.inner.read(&mut b)? == 0 {
+                return match i {
+                    0 => Ok(None),
+                    _ => Err(invalid_data("dtable header ends in a varint"))
+                };
+            }
+            self.position += 1;
+            value |= ((b[0] & 0x7f) as u64) << (7 * i);
+            if b[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+        }
+        Err(invalid_data("dtable header has a varint which is too long"))
+    }
+
+    fn required_varint(&mut self) -> io::Result<u64> {
+        self.varint()?.ok_or_else(|| invalid_data("dtable header ends in a field"))
+    }
+
+    fn bytes(&mut self, length: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        (&mut self.inner).take(length).read_to_end(&mut data)?;
+        if (data.len() as u64) < length {
+            return Err(invalid_data("dtable header ends in a field"));
+        }
+        self.position += length;
+        Ok(data)
+    }
+}
+
+fn write_varint(w: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        w.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    w.push(value as u8);
+}
+
+impl PagedIndex {
+    // Read through the header file, finding where each block of entries
+    // starts, and keeping every field except the entries. The entries are
+    // checked as they go by, in the same way as DTable::new checks them.
+    pub fn load(storage: Arc<Storage>, filename: &str, cache_blocks: usize) -> io::Result<(DTableHeader, PagedIndex)> {
+        let mut stream = Stream{
+            inner: io::BufReader::new(storage.open(filename)?),
+            position: 0
+        };
+        let mut rest = vec![];
+        let mut blocks: Vec<Block> = vec![];
+        let mut previous = String::new();
+        let mut previous_offset = 0;
+        let mut len = 0;
+
+        loop {
+            let start = stream.position;
+            let tag = match stream.varint()? {
+                Some(t) => t,
+                None    => break
+            };
+
+            if tag >> 3 != 1 || tag & 7 != 2 {
+                write_varint(&mut rest, tag);
+                match tag & 7 {
+                    0 => {
+                        let value = stream.required_varint()?;
+                        write_varint(&mut rest, value);
+                    },
+                    1 => rest.extend(stream.bytes(8)?),
+                    2 => {
+                        let length = stream.required_varint()?;
+                        write_varint(&mut rest, length);
+                        rest.extend(stream.bytes(length)?);
+                    },
+                    5 => rest.extend(stream.bytes(4)?),
+                    _ => return Err(invalid_data("dtable header has an unsupported wire type"))
+                };
+                continue;
+            }
+
+            let length = stream.required_varint()?;
+            let entry = protobuf::parse_from_bytes::<DTableHeaderEntry>(&stream.bytes(length)?)?;
+            let shared = entry.get_shared() as usize;
+            if (shared > 0 && len % RESTART_INTERVAL == 0) || shared > previous.len() || !previous.is_char_boundary(shared) {
+                return Err(invalid_data("dtable header has a bad key prefix"));
+            }
+            let mut key = previous[..shared].to_owned();
+            key.push_str(entry.get_key());
+            if len > 0 && (key <= previous || entry.get_offset() < previous_offset) {
+                return Err(invalid_data("dtable header is not in order"));
+            }
+
+            if len % FENCE_INTERVAL == 0 {
+                blocks.push(Block{
+                    fence: key.clone(),
+                    offset: entry.get_offset(),
+                    start: start,
+                    end: 0
+                });
+            }
+            blocks.last_mut().unwrap().end = stream.position;
+            previous = key;
+            previous_offset = entry.get_offset();
+            len += 1;
+        }
+
+        let header = protobuf::parse_from_bytes::<DTableHeader>(&rest)?;
+        let summary_is_valid = header.get_row_count() == 0 || (
+            header.get_row_count() == len as u64 &&
+                blocks.first().map(|b| b.fence.as_str()) == Some(header.get_min_key()) &&
+                previous == header.get_max_key()
+        );
+        if !summary_is_valid {
+            return Err(invalid_data("dtable header summary doesn't match its entries"));
+        }
+
+        Ok((header, PagedIndex{
+            storage: storage,
+            filename: filename.to_owned(),
+            len: len,
+            blocks: blocks,
+            cache_blocks: cache_blocks,
+            cache: RefCell::new(BlockCache{
+                blocks: HashMap::new(),
+                order: VecDeque::new()
+            })
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // The number of blocks which are cached.
+    #[cfg(test)]
+    pub fn cached_blocks(&self) -> usize {
+        self.cache.borrow().blocks.len()
+    }
+
+    // Read and expand the entries in the blocks [first, last).
+    fn read_blocks(&self, first: usize, last: usize) -> io::Result<Vec<DTableHeaderEntry>> {
+        if first >= last {
+            return Ok(vec![]);
+        }
+
+        let start = self.blocks[first].start;
+        let mut file = self.storage.open(&self.filename)?;
+        file.seek(io::SeekFrom::Start(start))?;
+        let mut data = vec![];
+        (&mut file).take(self.blocks[last - 1].end - start).read_to_end(&mut data)?;
+
+        let mut header = protobuf::parse_from_bytes::<DTableHeader>(&data)?;
+        header.set_prefix_compressed(true);
+        expand(&mut header).map_err(|e| invalid_data(&e))?;
+
+        let expected = ::std::cmp::min(last * FENCE_INTERVAL, self.len) - first * FENCE_INTERVAL;
+        let entries = header.take_entries().into_vec();
+        if entries.len() != expected || entries[0].get_key() != self.blocks[first].fence {
+            return Err(invalid_data("dtable header has changed since it was loaded"));
+        }
+        Ok(entries)
+    }
+
+    fn block(&self, index: usize) -> io::Result<Arc<Vec<DTableHeaderEntry>>> {
+        let cached = self.cache.borrow().blocks.get(&index).cloned();
+        if let Some(entries) = cached {
+            return Ok(entries);
+        }
+
+        let entries = Arc::new(self.read_blocks(index, index + 1)?);
+        if self.cache_blocks > 0 {
+            let mut cache = self.cache.borrow_mut();
+            while cache.blocks.len() >= self.cache_blocks {
+                match cache.order.pop_front() {
+                    Some(b) => cache.blocks.remove(&b),
+                    None    => break
+                };
+            }
+            cache.blocks.insert(index, entries.clone());
+            cache.order.push_back(index);
+        }
+        Ok(entries)
+    }
+
+    // Find the block which the key would be in, returning the index of
+    // its first entry, and the result of searching for the key in it.
+    fn search(&self, key: &str) -> io::Result<(usize, Result<usize, usize>)> {
+        let block = match self.blocks.binary_search_by(|b| b.fence.as_str().cmp(key)) {
+            Ok(i)   => return Ok((i * FENCE_INTERVAL, Ok(0))),
+            Err(0)  => return Ok((0, Err(0))),
+            Err(i)  => i - 1
+        };
+        let entries = self.block(block)?;
+        Ok((block * FENCE_INTERVAL, entries.binary_search_by(|e| e.get_key().cmp(key))))
+    }
+
+    // The same as FenceIndex::lower_bound, reading a block if needed.
+    pub fn lower_bound(&self, key: &str) -> io::Result<usize> {
+        self.search(key).map(|(start, result)| match result {
+            Ok(i) | Err(i) => start + i
+        })
+    }
+
+    // The same as FenceIndex::find, reading a block if needed.
+    pub fn find(&self, key: &str) -> io::Result<Option<usize>> {
+        self.search(key).map(|(start, result)| result.ok().map(|i| start + i))
+    }
+
+    // The entry, and the data offset of the one after it, if any.
+    pub fn entry(&self, index: usize) -> io::Result<(DTableHeaderEntry, Option<u64>)> {
+        let block = index / FENCE_INTERVAL;
+        let i = index % FENCE_INTERVAL;
+        let entries = self.block(block)?;
+        let next = match entries.get(i + 1) {
+            Some(e) => Some(e.get_offset()),
+            None    => self.blocks.get(block + 1).map(|b| b.offset)
+        };
+        Ok((entries[i].clone(), next))
+    }
+
+    // Read every entry from the index onward. This reads straight from
+    // the file, so that a scan doesn't push everything out of the cache.
+    pub fn entries_from(&self, index: usize) -> io::Result<Vec<DTableHeaderEntry>> {
+        let first = index / FENCE_INTERVAL;
+        let mut entries = self.read_blocks(first, self.blocks.len())?;
+        entries.drain(..index - first * FENCE_INTERVAL);
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use protobuf;
+    use protobuf::Message;
+    use storage;
+    use generated::dtable::*;
+
+    fn header(keys: &[String]) -> DTableHeader {
+        let mut header = DTableHeader::new();
+        header.set_entries(protobuf::RepeatedField::from_vec(
+            keys.iter().enumerate().map(|(i, k)| {
+                let mut e = DTableHeaderEntry::new();
+                e.set_key(k.to_owned());
+                e.set_offset(i as u64 * 10);
+                e
+            }).collect()
+        ));
+        header
+    }
+
+    fn keys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("user/{:06}/profé", i * 3)).collect()
+    }
+
+    #[test]
+    fn compresses_and_expands_keys() {
+        let original = header(&keys(100));
+        let compressed = super::compress(&original);
+        assert!(compressed.get_prefix_compressed());
+        assert_eq!(compressed.get_entries()[0].get_key(), "user/000000/profé");
+        assert_eq!(compressed.get_entries()[1].get_key(), "3/profé");
+        assert_eq!(compressed.get_entries()[1].get_shared(), 10);
+        assert_eq!(compressed.get_entries()[16].get_shared(), 0);
+        assert!(compressed.write_to_bytes().unwrap().len() < original.write_to_bytes().unwrap().len());
+
+        let mut expanded = protobuf::parse_from_bytes::<DTableHeader>(
+            &compressed.write_to_bytes().unwrap()
+        ).unwrap();
+        super::expand(&mut expanded).unwrap();
+        assert_eq!(expanded, original);
+
+        // Uncompressed headers are left alone.
+        let mut unchanged = original.clone();
+        super::expand(&mut unchanged).unwrap();
+        assert_eq!(unchanged, original);
+    }
+
+    #[test]
+    fn refuses_bad_prefixes() {
+        let mut compressed = super::compress(&header(&keys(20)));
+        compressed.mut_entries()[1].set_shared(100);
+        assert!(super::expand(&mut compressed.clone()).is_err());
+
+        compressed.mut_entries()[1].set_shared(0);
+        compressed.mut_entries()[16].set_shared(1);
+        assert!(super::expand(&mut compressed).is_err());
+    }
+
+    fn paged(keys: &[String], cache_blocks: usize) -> super::PagedIndex {
+        use storage::Storage;
+
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut header = header(keys);
+        header.set_row_count(keys.len() as u64);
+        header.set_min_key(keys.first().cloned().unwrap_or_default());
+        header.set_max_key(keys.last().cloned().unwrap_or_default());
+        let mut f = storage.create("/test/1.dtable.header").unwrap();
+        super::write_header(&header, &mut f).unwrap();
+
+        let (loaded, paged) = super::PagedIndex::load(storage.clone(), "/test/1.dtable.header", cache_blocks).unwrap();
+        assert!(loaded.get_entries().is_empty());
+        assert_eq!(loaded.get_row_count(), keys.len() as u64);
+        paged
+    }
+
+    #[test]
+    fn pages_in_blocks() {
+        for &n in &[0, 1, 64, 65, 200] {
+            let keys = keys(n);
+            let header = header(&keys);
+            let entries = header.get_entries();
+            let paged = paged(&keys, 2);
+            assert_eq!(paged.len(), n);
+
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(paged.find(key).unwrap(), Some(i));
+                let after = format!("{}!", key);
+                assert_eq!(paged.lower_bound(&after).unwrap(), i + 1);
+                assert_eq!(paged.find(&after).unwrap(), None);
+                assert_eq!(
+                    paged.entry(i).unwrap(),
+                    (entries[i].clone(), entries.get(i + 1).map(|e| e.get_offset()))
+                );
+                assert!(paged.cached_blocks() <= 2);
+            }
+            assert_eq!(paged.lower_bound("").unwrap(), 0);
+            assert_eq!(paged.lower_bound("zzz").unwrap(), n);
+            for &from in &[0, n / 2, n] {
+                assert_eq!(&paged.entries_from(from).unwrap()[..], &entries[from..]);
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_bad_paged_headers() {
+        use storage::Storage;
+
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut keys = keys(100);
+        keys.swap(70, 71);
+        let mut f = storage.create("/test/1.dtable.header").unwrap();
+        super::write_header(&header(&keys), &mut f).unwrap();
+        assert!(super::PagedIndex::load(storage.clone(), "/test/1.dtable.header", 2).is_err());
+
+        let mut summary = header(&keys[..10]);
+        summary.set_row_count(11);
+        let mut f = storage.create("/test/2.dtable.header").unwrap();
+        super::write_header(&summary, &mut f).unwrap();
+        assert!(super::PagedIndex::load(storage.clone(), "/test/2.dtable.header", 2).is_err());
+    }
+
+    #[test]
+    fn finds_keys_through_fences() {
+        for &n in &[0, 1, 63, 64, 65, 200] {
+            let keys = keys(n);
+            let header = header(&keys);
+            let entries = header.get_entries();
+            let index = super::FenceIndex::new(entries);
+
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(index.find(entries, key), Some(i));
+
+                // Just after each key is the same as a search of the whole
+                // header, i.e. the next entry.
+                let after = format!("{}!", key);
+                assert_eq!(index.lower_bound(entries, &after), i + 1);
+                assert_eq!(index.find(entries, &after), None);
+            }
+            assert_eq!(index.lower_bound(entries, ""), 0);
+            assert_eq!(index.lower_bound(entries, "zzz"), n);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+/*
+    keys.rs
+
+    This file contains the rules which row keys have to follow, and the
+    normalization which is applied to row keys before they're used.
+
+    Tables can also order their keys case-insensitively. Rather than
+    threading a comparator through the memtable, dtables and merges, the
+    keys of those tables are folded to a canonical form on the way in,
+    so that binary ordering of the stored keys is the table's ordering.
+*/
+
+use std::fmt;
+use std::collections::HashMap as Map;
+use regex;
+use regex::Regex;
+
+use query;
+
+// The KeyNormalization is applied to every row key on the way in, for
+// reads and writes alike, so that they always agree on the key.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum KeyNormalization {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "lowercase")]
+    Lowercase
+}
+
+impl fmt::Display for KeyNormalization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                KeyNormalization::None      => "none",
+                KeyNormalization::Lowercase => "lowercase"
+            }
+        )
+    }
+}
+
+// The order of the row keys in a table. Keys in an
+// ascii_case_insensitive table are stored with their ASCII letters
+// lowercased, after the table's prefix.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum KeyOrder {
+    #[serde(rename = "binary")]
+    Binary,
+    #[serde(rename = "ascii_case_insensitive")]
+    AsciiCaseInsensitive
+}
+
+impl Default for KeyOrder {
+    fn default() -> KeyOrder {
+        KeyOrder::Binary
+    }
+}
+
+impl fmt::Display for KeyOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                KeyOrder::Binary                => "binary",
+                KeyOrder::AsciiCaseInsensitive  => "ascii_case_insensitive"
+            }
+        )
+    }
+}
+
+// The smallest key which is greater than every key starting with the
+// prefix, or None if there isn't one.
+pub fn prefix_end(prefix: &str) -> Option<String> {
+    let mut bytes = prefix.as_bytes().to_vec();
+    while let Some(last) = bytes.pop() {
+        if last < 0x7f {
+            bytes.push(last + 1);
+            return String::from_utf8(bytes).ok();
+        }
+    }
+    None
+}
+
+// KeyRules constrain which row keys can be read and written. A
+// max_length of zero means that keys can be any length.
+pub struct KeyRules {
+    pub max_length: usize,
+    pub reject_empty: bool,
+    pub normalization: KeyNormalization,
+    allowed_characters: Option<Regex>,
+    table_orders: Vec<(String, KeyOrder)>
+}
+
+impl KeyRules {
+    // The default rules accept any key, and leave it as it is.
+    pub fn new() -> KeyRules {
+        KeyRules{
+            max_length: 0,
+            reject_empty: false,
+            normalization: KeyNormalization::None,
+            allowed_characters: None,
+            table_orders: Vec::new()
+        }
+    }
+
+    // Only allow the characters in the provided regex character class,
+    // e.g. "a-z0-9_". An empty class allows any character.
+    pub fn set_allowed_characters(&mut self, class: &str) -> Result<(), regex::Error> {
+        self.allowed_characters = match class {
+            "" => None,
+            c  => Some(Regex::new(&format!("^[{}]*$", c))?)
+        };
+        Ok(())
+    }
+
+    // Order the keys of the table with the given prefix.
+    pub fn set_table_order(&mut self, prefix: &str, order: KeyOrder) {
+        self.table_orders.retain(|&(ref p, _)| p != prefix);
+        self.table_orders.push((prefix.to_owned(), order));
+    }
+
+    pub fn normalize(&self, key: &str) -> String {
+        let key = match self.normalization {
+            KeyNormalization::None      => key.to_owned(),
+            KeyNormalization::Lowercase => key.to_lowercase()
+        };
+
+        // Case-insensitive tables match their prefix case-insensitively
+        // too, but it's written back as it was declared, so that the key
+        // still belongs to the table. The longest matching prefix wins.
+        let table = self.table_orders.iter()
+            .filter(|&&(ref p, order)| match order {
+                KeyOrder::Binary                => key.starts_with(p.as_str()),
+                KeyOrder::AsciiCaseInsensitive  =>
+                    key.len() >= p.len() && key.as_bytes()[..p.len()].eq_ignore_ascii_case(p.as_bytes())
+            })
+            .max_by_key(|&&(ref p, _)| p.len());
+
+        match table {
+            Some(&(ref p, KeyOrder::AsciiCaseInsensitive)) =>
+                format!("{}{}", p, key[p.len()..].to_ascii_lowercase()),
+            _ => key
+        }
+    }
+
+    // Check a row key, which should already be normalized, against the
+    // rules.
+    pub fn is_valid(&self, key: &str) -> bool {
+        if self.reject_empty && key.is_empty() {
+            return false;
+        }
+
+        if self.max_length > 0 && key.len() > self.max_length {
+            return false;
+        }
+
+        match self.allowed_characters {
+            Some(ref r) => r.is_match(key),
+            None        => true
+        }
+    }
+
+    // Normalize every row key in the query, including the bounds of
+    // key ranges.
+    pub fn normalize_query(&self, q: query::Query) -> query::Query {
+        match q {
+            query::Query::Select{row: r, get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl} =>
+                query::Query::Select{row: self.normalize(&r), get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl},
+            query::Query::Insert{row: r, set: s} =>
+                query::Query::Insert{row: self.normalize(&r), set: s},
+            query::Query::Update{row: r, set: s} =>
+                query::Query::Update{row: self.normalize(&r), set: s},
+            query::Query::Append{row: r, set: s} =>
+                query::Query::Append{row: self.normalize(&r), set: s},
+            query::Query::SelectList{row: r, column: c, limit: l, start: s, end: e} =>
+                query::Query::SelectList{row: self.normalize(&r), column: c, limit: l, start: s, end: e},
+            // Rows which normalize to the same key are merged together.
+            query::Query::Transaction{updates: u} => {
+                let mut updates = Map::new();
+                for (row, set) in u {
+                    updates.entry(self.normalize(&row)).or_insert_with(Map::new).extend(set);
+                }
+                query::Query::Transaction{updates: updates}
+            },
+            query::Query::History{row: r, column: c, limit: l} =>
+                query::Query::History{row: self.normalize(&r), column: c, limit: l},
+            query::Query::Describe{row: r} =>
+                query::Query::Describe{row: self.normalize(&r)},
+            query::Query::Execute{handle: h, row: r} =>
+                query::Query::Execute{handle: h, row: self.normalize(&r)},
+            query::Query::Checksum{row: r} =>
+                query::Query::Checksum{row: self.normalize(&r)},
+            query::Query::Diff{row: r, from_ts: f, to_ts: t} =>
+                query::Query::Diff{row: self.normalize(&r), from_ts: f, to_ts: t},
+            query::Query::ChecksumRange{start: s, end: e} =>
+                query::Query::ChecksumRange{start: self.normalize(&s), end: self.normalize(&e)},
+            query::Query::SoftDelete{row: r} =>
+                query::Query::SoftDelete{row: self.normalize(&r)},
+            query::Query::Undelete{row: r} =>
+                query::Query::Undelete{row: self.normalize(&r)},
+            query::Query::ListKeys{start: s, limit: l} =>
+                query::Query::ListKeys{start: self.normalize(&s), limit: l},
+            query::Query::DeleteRange{start_row: s, end_row: e} =>
+                query::Query::DeleteRange{start_row: self.normalize(&s), end_row: self.normalize(&e)},
+            query::Query::Scan{start: s, end: e, get: g, limit: l, snapshot: n} =>
+                query::Query::Scan{start: self.normalize(&s), end: self.normalize(&e), get: g, limit: l, snapshot: n},
+            query::Query::Explain(q) =>
+                query::Query::Explain(Box::new(self.normalize_query(*q))),
+            x => x
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn can_validate_keys() {
+        let mut rules = super::KeyRules::new();
+        assert!(rules.is_valid(""));
+        assert!(rules.is_valid("Any Key!"));
+
+        rules.reject_empty = true;
+        rules.max_length = 8;
+        rules.set_allowed_characters("a-z0-9_").unwrap();
+        assert!(!rules.is_valid(""));
+        assert!(rules.is_valid("row_1"));
+        assert!(!rules.is_valid("Row_1"));
+        assert!(!rules.is_valid("row 1"));
+        assert!(!rules.is_valid("row_123456"));
+    }
+
+    #[test]
+    fn can_normalize_keys() {
+        let mut rules = super::KeyRules::new();
+        assert_eq!(rules.normalize("Row"), "Row");
+
+        rules.normalization = super::KeyNormalization::Lowercase;
+        assert_eq!(rules.normalize("Row"), "row");
+    }
+
+    #[test]
+    fn can_order_tables_case_insensitively() {
+        let mut rules = super::KeyRules::new();
+        rules.set_table_order("Users/", super::KeyOrder::AsciiCaseInsensitive);
+        assert_eq!(rules.normalize("Users/Bob@Example.com"), "Users/bob@example.com");
+        assert_eq!(rules.normalize("USERS/Bob"), "Users/bob");
+        assert_eq!(rules.normalize("Users/\u{c9}mile"), "Users/\u{c9}mile");
+        assert_eq!(rules.normalize("Posts/Bob"), "Posts/Bob");
+
+        // Longer prefixes take priority over the tables they're inside.
+        rules.set_table_order("Users/Admin/", super::KeyOrder::Binary);
+        assert_eq!(rules.normalize("Users/Admin/Bob"), "Users/Admin/Bob");
+        assert_eq!(rules.normalize("users/Admin/Bob"), "Users/admin/bob");
+        assert_eq!(rules.normalize("Users/Ann"), "Users/ann");
+        rules.set_table_order("Users/", super::KeyOrder::Binary);
+        assert_eq!(rules.normalize("Users/Bob"), "Users/Bob");
+    }
+}
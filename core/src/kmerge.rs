@@ -0,0 +1,145 @@
+/*
+    kmerge.rs
+
+    KMerge merges several sorted iterators into a single sorted one. The
+    next item of each iterator is kept in a binary heap, so that finding
+    the smallest takes O(log k) comparisons for k iterators rather than
+    looking at every one of them. It's used to merge the entries of
+    columns, the columns of rows and the rows of dtables.
+*/
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// Mergeable items are merged in the order given by merge_cmp. Items which
+// compare equal come out in the order of the iterators they came from.
+pub trait Mergeable {
+    fn merge_cmp(&self, other: &Self) -> Ordering;
+}
+
+struct Head<T> {
+    item: T,
+    source: usize
+}
+
+// BinaryHeap pops the largest item first, so the order is reversed to
+// have it pop the smallest.
+impl<T: Mergeable> Ord for Head<T> {
+    fn cmp(&self, other: &Head<T>) -> Ordering {
+        other.item.merge_cmp(&self.item)
+            .then(other.source.cmp(&self.source))
+    }
+}
+
+impl<T: Mergeable> PartialOrd for Head<T> {
+    fn partial_cmp(&self, other: &Head<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Mergeable> PartialEq for Head<T> {
+    fn eq(&self, other: &Head<T>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Mergeable> Eq for Head<T> {}
+
+pub struct KMerge<I: Iterator> {
+    iterators: Vec<I>,
+    heap: BinaryHeap<Head<I::Item>>
+}
+
+impl<I: Iterator> KMerge<I> where I::Item: Mergeable {
+    pub fn new(iterators: Vec<I>) -> KMerge<I> {
+        let mut merge = KMerge{
+            heap: BinaryHeap::with_capacity(iterators.len()),
+            iterators: iterators
+        };
+        for source in 0..merge.iterators.len() {
+            merge.advance(source);
+        }
+        merge
+    }
+
+    fn advance(&mut self, source: usize) {
+        if let Some(item) = self.iterators[source].next() {
+            self.heap.push(Head{item: item, source: source});
+        }
+    }
+
+    // Returns the smallest item along with every other item equal to it,
+    // each with the index of the iterator it came from, in order.
+    pub fn next_group(&mut self) -> Option<Vec<(usize, I::Item)>> {
+        let first = match self.next() {
+            Some(x) => x,
+            None    => return None
+        };
+        let mut group = vec![first];
+        loop {
+            match self.heap.peek() {
+                Some(h) if h.item.merge_cmp(&group[0].1) == Ordering::Equal => (),
+                _ => break
+            }
+            let next = self.next().unwrap();
+            group.push(next);
+        }
+        Some(group)
+    }
+}
+
+impl<I: Iterator> Iterator for KMerge<I> where I::Item: Mergeable {
+    type Item = (usize, I::Item);
+
+    fn next(&mut self) -> Option<(usize, I::Item)> {
+        let head = match self.heap.pop() {
+            Some(h) => h,
+            None    => return None
+        };
+        self.advance(head.source);
+        Some((head.source, head.item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    impl super::Mergeable for (u64, &'static str) {
+        fn merge_cmp(&self, other: &(u64, &'static str)) -> Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn merges_in_order() {
+        let merge = super::KMerge::new(vec![
+            vec![(1u64, "a"), (4, "a"), (5, "a")].into_iter(),
+            vec![].into_iter(),
+            vec![(2, "c"), (4, "c")].into_iter(),
+            vec![(1, "d"), (3, "d")].into_iter()
+        ]);
+        assert_eq!(merge.collect::<Vec<_>>(), vec![
+            (0, (1, "a")),
+            (3, (1, "d")),
+            (2, (2, "c")),
+            (3, (3, "d")),
+            (0, (4, "a")),
+            (2, (4, "c")),
+            (0, (5, "a"))
+        ]);
+    }
+
+    #[test]
+    fn groups_equal_items() {
+        let mut merge = super::KMerge::new(vec![
+            vec![(1u64, "a"), (2, "a")].into_iter(),
+            vec![(2, "b")].into_iter(),
+            vec![(1, "c"), (3, "c")].into_iter()
+        ]);
+        assert_eq!(merge.next_group(), Some(vec![(0, (1, "a")), (2, (1, "c"))]));
+        assert_eq!(merge.next_group(), Some(vec![(0, (2, "a")), (1, (2, "b"))]));
+        assert_eq!(merge.next_group(), Some(vec![(2, (3, "c"))]));
+        assert_eq!(merge.next_group(), None);
+    }
+}
@@ -0,0 +1,245 @@
+/*
+    lazyrow.rs
+
+    Reads columns straight out of an encoded DRow. Parsing the whole
+    DRow copies out every version of every column, which is most of the
+    cost of a select, even though a select usually wants one version of
+    a few columns. A LazyRow only finds where each column is in the
+    encoded row, and copies out the entries which are asked for.
+
+    This follows the protobuf wire format for DRow (see dtable.proto):
+    columns are field 1 and keys are field 2, and in each DColumn, the
+    DEntry messages are field 1. In a DEntry, the timestamp is field 1
+    and the value is field 2. Unknown fields are skipped. Compressed
+    blocks (field 3) can't be read in place, so a row which has any has
+    to be parsed in full instead.
+*/
+
+use byteorder::{LittleEndian, ByteOrder};
+
+use generated::dtable::DEntry;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader{
+            data: data,
+            position: 0
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if length > self.data.len() - self.position {
+            return Err(String::from("unexpected end of row"));
+        }
+        let data = self.data;
+        self.position += length;
+        Ok(&data[self.position - length..self.position])
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let b = self.take(1)?[0];
+            value |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("varint is too long"))
+    }
+
+    // Returns the field number and wire type.
+    fn tag(&mut self) -> Result<(u64, u64), String> {
+        let tag = self.varint()?;
+        Ok((tag >> 3, tag & 7))
+    }
+
+    fn length_delimited(&mut self) -> Result<&'a [u8], String> {
+        let length = self.varint()?;
+        if length > (self.data.len() - self.position) as u64 {
+            return Err(String::from("unexpected end of row"));
+        }
+        self.take(length as usize)
+    }
+
+    fn skip(&mut self, wire_type: u64) -> Result<(), String> {
+        match wire_type {
+            WIRE_VARINT             => self.varint().map(|_| ()),
+            WIRE_FIXED64            => self.take(8).map(|_| ()),
+            WIRE_LENGTH_DELIMITED   => self.length_delimited().map(|_| ()),
+            WIRE_FIXED32            => self.take(4).map(|_| ()),
+            t                       => Err(format!("unsupported wire type {}", t))
+        }
+    }
+}
+
+pub struct LazyRow<'a> {
+    keys: Vec<&'a str>,
+    columns: Vec<&'a [u8]>,
+    compressed: bool
+}
+
+impl<'a> LazyRow<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<LazyRow<'a>, String> {
+        let mut row = LazyRow{
+            keys: vec![],
+            columns: vec![],
+            compressed: false
+        };
+        let mut reader = Reader::new(data);
+        while !reader.is_done() {
+            match reader.tag()? {
+                (1, WIRE_LENGTH_DELIMITED) => row.columns.push(reader.length_delimited()?),
+                (2, WIRE_LENGTH_DELIMITED) => {
+                    let key = ::std::str::from_utf8(reader.length_delimited()?)
+                        .map_err(|_| String::from("column key isn't valid UTF-8"))?;
+                    row.keys.push(key);
+                },
+                (3, WIRE_LENGTH_DELIMITED) => {
+                    reader.length_delimited()?;
+                    row.compressed = true;
+                },
+                (_, wire_type) => reader.skip(wire_type)?
+            };
+        }
+        Ok(row)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    // The same check as DRow::is_valid.
+    pub fn is_valid(&self) -> bool {
+        self.keys.len() == self.columns.len() &&
+            self.keys.windows(2).all(|w| w[0] < w[1])
+    }
+
+    // Find the version of the column as of the timestamp, the same way
+    // as DColumn::get_value: the last entry written at or before it, or
+    // else the last entry.
+    pub fn get_value(&self, key: &str, timestamp: u64) -> Result<Option<DEntry>, String> {
+        let column = match self.keys.binary_search_by(|k| (*k).cmp(key)) {
+            Ok(index)   => self.columns[index],
+            Err(_)      => return Ok(None)
+        };
+
+        let mut latest = None;
+        let mut found = None;
+        let mut reader = Reader::new(column);
+        while !reader.is_done() {
+            match reader.tag()? {
+                (1, WIRE_LENGTH_DELIMITED) => {
+                    let entry = parse_entry(reader.length_delimited()?)?;
+                    if entry.0 <= timestamp {
+                        found = Some(entry);
+                    }
+                    latest = Some(entry);
+                },
+                (_, wire_type) => reader.skip(wire_type)?
+            };
+        }
+
+        Ok(found.or(latest).map(|(t, v)| {
+            let mut entry = DEntry::new();
+            entry.set_timestamp(t);
+            entry.set_value(v.to_vec());
+            entry
+        }))
+    }
+
+    pub fn select(&self, cols: &[&str], timestamp: u64) -> Result<Vec<Option<DEntry>>, String> {
+        cols.iter().map(|col| self.get_value(col, timestamp)).collect()
+    }
+}
+
+// The timestamp and value of an encoded DEntry, without copying it.
+fn parse_entry(data: &[u8]) -> Result<(u64, &[u8]), String> {
+    let mut timestamp = 0;
+    let mut value: &[u8] = &[];
+    let mut reader = Reader::new(data);
+    while !reader.is_done() {
+        match reader.tag()? {
+            (1, WIRE_FIXED64)           => timestamp = LittleEndian::read_u64(reader.take(8)?),
+            (2, WIRE_LENGTH_DELIMITED)  => value = reader.length_delimited()?,
+            (_, wire_type)              => reader.skip(wire_type)?
+        };
+    }
+    Ok((timestamp, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf;
+    use protobuf::Message;
+    use generated::dtable::*;
+
+    fn row() -> DRow {
+        let column = |entries: &[(u64, &str)]| {
+            let mut c = DColumn::new();
+            c.set_entries(protobuf::RepeatedField::from_vec(entries.iter().map(|&(t, v)| {
+                let mut e = DEntry::new();
+                e.set_timestamp(t);
+                e.set_value(v.as_bytes().to_vec());
+                e
+            }).collect()));
+            c
+        };
+
+        let mut r = DRow::new();
+        r.set_keys(protobuf::RepeatedField::from_vec(vec![String::from("email"), String::from("name")]));
+        r.set_columns(protobuf::RepeatedField::from_vec(vec![
+            column(&[(10, "a@example.com")]),
+            column(&[(0, ""), (20, "alice"), (30, "alicia")])
+        ]));
+        r
+    }
+
+    #[test]
+    fn reads_the_same_values_as_drow() {
+        let row = row();
+        let encoded = row.write_to_bytes().unwrap();
+        let lazy = super::LazyRow::parse(&encoded).unwrap();
+        assert!(lazy.is_valid());
+
+        let cols = ["email", "missing", "name"];
+        for &t in [0, 5, 10, 20, 25, 30, 100].iter() {
+            assert_eq!(
+                format!("{:?}", lazy.select(&cols, t).unwrap()),
+                format!("{:?}", row.select(&cols, t)),
+                "at timestamp {}", t
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_rows() {
+        let encoded = row().write_to_bytes().unwrap();
+        assert!(super::LazyRow::parse(&encoded[..encoded.len() - 3]).is_err());
+
+        // Columns are only parsed when they're read.
+        let mut column = DColumn::new();
+        column.set_entries(protobuf::RepeatedField::from_vec(vec![DEntry::new()]));
+        let mut encoded_column = column.write_to_bytes().unwrap();
+        encoded_column.push(0x0a);
+        let mut broken = vec![0x0a, encoded_column.len() as u8];
+        broken.extend(encoded_column);
+        broken.extend(&[0x12, 1, b'x']);
+        let lazy = super::LazyRow::parse(&broken).unwrap();
+        assert!(lazy.get_value("x", 0).is_err());
+    }
+}
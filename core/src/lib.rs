@@ -0,0 +1,69 @@
+/*
+    lib.rs
+
+    This is the storage engine behind largetable, packaged so that it
+    can be embedded in other applications without running the server.
+*/
+#![feature(test)]
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate serde_derive;
+
+#[cfg(test)]
+extern crate test;
+
+extern crate protobuf;
+extern crate serde_json;
+extern crate rand;
+extern crate time;
+extern crate regex;
+extern crate glob;
+extern crate byteorder;
+extern crate libc;
+extern crate flate2;
+
+pub mod base;
+pub mod query;
+pub mod scan;
+pub mod export;
+pub mod keys;
+pub mod compositekey;
+pub mod acl;
+pub mod schema;
+pub mod salting;
+pub mod transform;
+pub mod migration;
+pub mod audit;
+pub mod logarchive;
+pub mod replication;
+pub mod shards;
+pub mod budget;
+pub mod idempotency;
+pub mod prepared;
+pub mod rowcache;
+pub mod resultcache;
+pub mod hotrows;
+pub mod election;
+pub mod hints;
+pub mod storage;
+pub mod tempdir;
+pub mod spans;
+pub mod families;
+pub mod generated;
+mod mtable;
+mod dtable;
+mod keyindex;
+mod lazyrow;
+mod kmerge;
+mod database;
+
+#[cfg(test)]
+mod fuzz;
+
+#[cfg(test)]
+mod benches;
+
+pub use database::Database;
@@ -0,0 +1,222 @@
+/*
+    logarchive.rs
+
+    Continuous archiving of the commit log, for point in time recovery.
+    The commit log is truncated every time the memtable is flushed, so
+    just before that happens, its contents are copied into the archive
+    directory as a sealed segment. Segments are named by sequence number,
+    like 00000000000000000001.log, and a line is added to the archive's
+    manifest (segments.json) for each one, recording the range of
+    timestamps that it covers and the generation of the dtables that its
+    writes were flushed into.
+
+    To recover, restore a backup of the data directory, and then replay
+    the segments with a newer generation than any of the backup's dtables
+    (see Base::restore_from_archive). Writes which were still in the
+    commit log when the server was lost haven't been archived yet.
+*/
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ByteOrder};
+use protobuf;
+use serde_json;
+
+use generated::dtable::CommitLogEntry;
+use storage;
+
+const MANIFEST_FILE: &'static str = "segments.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub sequence: u64,
+    pub generation: u64,
+
+    // The range of timestamps of the writes in the segment, which are
+    // both 0 if it only carries idempotency keys.
+    pub first_timestamp: u64,
+    pub last_timestamp: u64,
+    pub entries: u64,
+    pub bytes: u64
+}
+
+// Split the contents of a commit log into its entries.
+pub fn parse_entries(data: &[u8]) -> Result<Vec<CommitLogEntry>, String> {
+    let mut entries = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        if offset + 4 > data.len() {
+            return Err(format!("entry size at offset {} is cut short", offset));
+        }
+        let size = LittleEndian::read_u32(&data[offset..offset + 4]) as usize;
+        let start = offset + 4;
+        if size > data.len() - start {
+            return Err(format!("entry of {} bytes at offset {} runs past the end", size, offset));
+        }
+        let entry = protobuf::parse_from_bytes::<CommitLogEntry>(&data[start..start + size])
+            .map_err(|e| format!("unable to parse entry at offset {}: {}", offset, e))?;
+        entries.push(entry);
+        offset = start + size;
+    }
+    Ok(entries)
+}
+
+pub struct LogArchive {
+    storage: Arc<storage::Storage>,
+    directory: String,
+    segments: Vec<Segment>
+}
+
+impl LogArchive {
+    pub fn open(storage: Arc<storage::Storage>, directory: &str) -> io::Result<LogArchive> {
+        storage.create_dir_all(directory)?;
+        let mut archive = LogArchive{
+            storage: storage,
+            directory: directory.to_owned(),
+            segments: vec![]
+        };
+        archive.segments = archive.read_manifest()?;
+        Ok(archive)
+    }
+
+    fn manifest_path(&self) -> String {
+        format!("{}/{}", self.directory, MANIFEST_FILE)
+    }
+
+    fn segment_path(&self, sequence: u64) -> String {
+        format!("{}/{:020}.log", self.directory, sequence)
+    }
+
+    // A line that doesn't parse was probably cut short by a crash while
+    // it was being added, before its segment was finished, so it's
+    // skipped.
+    fn read_manifest(&self) -> io::Result<Vec<Segment>> {
+        let mut contents = String::new();
+        match self.storage.open(&self.manifest_path()) {
+            Ok(mut f)   => f.read_to_string(&mut contents)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e)      => return Err(e)
+        };
+        Ok(contents.lines()
+            .filter_map(|line| serde_json::from_str::<Segment>(line).ok())
+            .collect())
+    }
+
+    // The archived segments, oldest first.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    // The generation of the newest segment, or 0 if there aren't any.
+    pub fn last_generation(&self) -> u64 {
+        self.segments.last().map(|s| s.generation).unwrap_or(0)
+    }
+
+    // Seal the contents of the commit log into a new segment. The segment
+    // is synced before it's added to the manifest, so that the manifest
+    // never lists a segment which isn't complete.
+    pub fn archive(&mut self, data: &[u8], generation: u64) -> io::Result<Segment> {
+        let entries = parse_entries(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let timestamps = entries.iter()
+            .map(|e| e.get_timestamp())
+            .filter(|&t| t > 0)
+            .collect::<Vec<_>>();
+
+        let segment = Segment{
+            sequence: self.segments.last().map(|s| s.sequence).unwrap_or(0) + 1,
+            generation: generation,
+            first_timestamp: timestamps.iter().cloned().min().unwrap_or(0),
+            last_timestamp: timestamps.iter().cloned().max().unwrap_or(0),
+            entries: entries.len() as u64,
+            bytes: data.len() as u64
+        };
+
+        let path = self.segment_path(segment.sequence);
+        let temporary = format!("{}.tmp", path);
+        {
+            let mut f = self.storage.create(&temporary)?;
+            f.write_all(data)?;
+            f.sync()?;
+        }
+        self.storage.rename(&temporary, &path)?;
+
+        let mut line = serde_json::to_string(&segment)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to encode segment"))?;
+        line.push('\n');
+        let mut manifest = self.storage.append(&self.manifest_path())?;
+        manifest.write_all(line.as_bytes())?;
+        manifest.sync()?;
+
+        self.segments.push(segment.clone());
+        Ok(segment)
+    }
+
+    pub fn read_segment(&self, segment: &Segment) -> io::Result<Vec<CommitLogEntry>> {
+        let data = self.read_segment_data(segment)?;
+        parse_entries(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // The segment as it was in the commit log.
+    pub fn read_segment_data(&self, segment: &Segment) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        self.open_segment(segment)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    // Open the segment, to read part of it as it was in the commit log.
+    pub fn open_segment(&self, segment: &Segment) -> io::Result<Box<storage::StorageFile>> {
+        self.storage.open(&self.segment_path(segment.sequence))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use protobuf::Message;
+    use generated::dtable::CommitLogEntry;
+    use storage;
+
+    fn log(timestamps: &[u64]) -> Vec<u8> {
+        let mut data = vec![];
+        for &t in timestamps {
+            let mut c = CommitLogEntry::new();
+            c.set_key(format!("row{}", t));
+            c.set_timestamp(t);
+            data.write_u32::<LittleEndian>(c.compute_size()).unwrap();
+            c.write_to_writer(&mut data).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn archives_segments() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut archive = super::LogArchive::open(storage.clone(), "/archive").unwrap();
+        archive.archive(&log(&[100, 200]), 1).unwrap();
+        archive.archive(&log(&[300]), 2).unwrap();
+
+        // The manifest is read back when the archive is opened again.
+        let reopened = super::LogArchive::open(storage.clone(), "/archive").unwrap();
+        assert_eq!(
+            reopened.segments().iter().map(|s| (s.sequence, s.generation, s.first_timestamp, s.last_timestamp, s.entries)).collect::<Vec<_>>(),
+            vec![(1, 1, 100, 200, 2), (2, 2, 300, 300, 1)]
+        );
+        assert_eq!(reopened.last_generation(), 2);
+        let entries = reopened.read_segment(&reopened.segments()[0]).unwrap();
+        assert_eq!(entries.iter().map(|e| e.get_key()).collect::<Vec<_>>(), vec!["row100", "row200"]);
+    }
+
+    #[test]
+    fn refuses_corrupted_logs() {
+        let storage = Arc::new(storage::MemoryStorage::new());
+        let mut archive = super::LogArchive::open(storage, "/archive").unwrap();
+        let mut data = log(&[100]);
+        data.pop();
+        assert!(archive.archive(&data, 1).is_err());
+        assert!(archive.segments().is_empty());
+    }
+}
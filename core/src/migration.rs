@@ -0,0 +1,135 @@
+/*
+    migration.rs
+
+    A ColumnMigration rewrites the columns of existing rows: columns can
+    be renamed, and the values of a column can be replaced using a
+    mapping from old values to new ones, e.g. to turn "y"/"n" into
+    "true"/"false" before declaring the column as a bool. Migrations are
+    applied to whole dtables at a time, which are then swapped in for the
+    old ones (see Base::migrate_columns).
+
+    Values are mapped as text, so binary values are left alone, and so
+    are empty values, which mark deletions.
+*/
+
+use std::str;
+use std::collections::{BTreeMap, HashMap};
+
+use protobuf;
+
+use generated::dtable::{DRow, DColumn};
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ColumnMigration {
+    // Only rows whose keys start with the prefix are migrated.
+    #[serde(default)]
+    pub prefix: String,
+
+    // Old column name -> new column name.
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+
+    // Column name (after renaming) -> old value -> new value.
+    #[serde(default)]
+    pub values: HashMap<String, HashMap<String, String>>
+}
+
+impl ColumnMigration {
+    pub fn applies_to(&self, row: &str) -> bool {
+        row.starts_with(self.prefix.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rename.is_empty() && self.values.is_empty()
+    }
+
+    // Create the migrated copy of the row. If a column is renamed to one
+    // which already exists, their entries are merged by timestamp.
+    pub fn rewrite(&self, row: &DRow) -> DRow {
+        let mut columns: BTreeMap<String, Vec<DColumn>> = BTreeMap::new();
+        for (key, col) in row.get_keys().iter().zip(row.get_columns().iter()) {
+            let name = self.rename.get(key).cloned().unwrap_or_else(|| key.to_owned());
+            let mut col = col.clone();
+            if let Some(mapping) = self.values.get(&name) {
+                for e in col.mut_entries().iter_mut() {
+                    let mapped = match str::from_utf8(e.get_value()) {
+                        Ok(v) if !v.is_empty() => mapping.get(v).cloned(),
+                        _ => None
+                    };
+                    if let Some(v) = mapped {
+                        e.set_value(v.into_bytes());
+                    }
+                }
+            }
+            columns.entry(name).or_insert_with(Vec::new).push(col);
+        }
+
+        let mut keys = vec![];
+        let mut cols = vec![];
+        for (name, mut merging) in columns {
+            keys.push(name);
+            cols.push(match merging.len() {
+                1 => merging.pop().unwrap(),
+                _ => DColumn::from_vec(&merging.iter().collect::<Vec<_>>())
+            });
+        }
+
+        let mut d = DRow::new();
+        d.set_keys(protobuf::RepeatedField::from_vec(keys));
+        d.set_columns(protobuf::RepeatedField::from_vec(cols));
+        d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+    use protobuf;
+    use serde_json;
+    use generated::dtable::{DRow, DColumn, DEntry};
+
+    fn column(entries: &[(u64, &str)]) -> DColumn {
+        let mut c = DColumn::new();
+        c.set_entries(protobuf::RepeatedField::from_iter(entries.iter().map(|&(t, v)| {
+            let mut e = DEntry::new();
+            e.set_timestamp(t);
+            e.set_value(v.as_bytes().to_vec());
+            e
+        })));
+        c
+    }
+
+    #[test]
+    fn renames_and_maps_columns() {
+        let migration: super::ColumnMigration = serde_json::from_str(r#"{
+            "prefix": "users/",
+            "rename": {"active": "enabled", "mail": "email"},
+            "values": {"enabled": {"y": "true", "n": "false"}}
+        }"#).unwrap();
+        assert!(migration.applies_to("users/1"));
+        assert!(!migration.applies_to("orders/1"));
+
+        let mut row = DRow::new();
+        row.set_keys(protobuf::RepeatedField::from_vec(vec![
+            String::from("active"), String::from("email"), String::from("mail"), String::from("name")
+        ]));
+        row.set_columns(protobuf::RepeatedField::from_vec(vec![
+            column(&[(1, "y"), (2, "n"), (3, "")]),
+            column(&[(5, "new@example.com")]),
+            column(&[(4, "old@example.com")]),
+            column(&[(1, "n")])
+        ]));
+
+        let migrated = migration.rewrite(&row);
+        assert!(migrated.is_valid());
+        assert_eq!(migrated.get_keys(), &[String::from("email"), String::from("enabled"), String::from("name")]);
+
+        // The deletion marker and the other columns are left alone.
+        let values = |key: &str| migrated.get_column(key).unwrap().get_entries().iter()
+            .map(|e| (e.get_timestamp(), String::from_utf8(e.get_value().to_vec()).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(values("enabled"), vec![(1, String::from("true")), (2, String::from("false")), (3, String::new())]);
+        assert_eq!(values("email"), vec![(4, String::from("old@example.com")), (5, String::from("new@example.com"))]);
+        assert_eq!(values("name"), vec![(1, String::from("n"))]);
+    }
+}
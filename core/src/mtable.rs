@@ -9,13 +9,15 @@ use std::fmt;
 use std::str::FromStr;
 use std::u64;
 use std::collections::BTreeMap;
+use std::collections::btree_map;
 use std::iter::FromIterator;
 
 use protobuf;
-use protobuf::Message;
 
 use generated::dtable::*;
 use dtable;
+use keyindex;
+use families;
 use query::MUpdate;
 
 pub type TOption = Option<Vec<Option<DEntry>>>;
@@ -27,14 +29,40 @@ pub struct MRow {
 pub struct MTable {
     rows: BTreeMap<String, MRow>,
 
+    // tombstones: the range deletions applied to this MTable. They
+    // are kept so that they can hide older data in the dtables.
+    tombstones: Vec<RangeTombstone>,
+
     // size: represents the approximate size of the MTable, in bytes.
     pub size: usize
 }
 
 impl MRow {
-    fn write_to_writer(&self, w: &mut io::Write) -> Result<u64, io::Error> {
-        // First, construct a DRow using this MRow, then
-        // write out that DRow using write_to_writer.
+    // Returns the number of bytes written, and the blocks of the row's
+    // column families, if it has more than one.
+    fn write_to_writer(&self, policies: &families::Families, w: &mut io::Write) -> Result<(u64, Vec<FamilyBlock>), io::Error> {
+        // First, construct a DRow using this MRow, then write out that
+        // DRow, split up by column family.
+        let drow = self.to_drow();
+        Ok(families::write_row(&drow, policies, w)?)
+    }
+
+    // The names of up to limit of the columns which match the filter, from
+    // start onward, in key order.
+    pub fn column_keys<F: Fn(&str) -> bool>(&self, start: &str, limit: usize, filter: F) -> Vec<String> {
+        self.columns.range(start.to_owned()..)
+            .map(|(k, _)| k)
+            .filter(|k| filter(k.as_str()))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_column(&self, key: &str) -> Option<&DColumn> {
+        self.columns.get(key)
+    }
+
+    pub fn to_drow(&self) -> DRow {
         let mut drow = DRow::new();
         drow.set_columns(protobuf::RepeatedField::from_iter(
             self.columns.iter().map(|(_, value)| value.clone())
@@ -47,9 +75,7 @@ impl MRow {
             self.columns.iter().map(|(key, _)| String::from_str(key).unwrap())
         ));
 
-        drow.write_to_writer(w)?;
-
-        Ok(drow.get_cached_size() as u64)
+        drow
     }
 }
 
@@ -69,7 +95,7 @@ impl fmt::Display for MRow {
 
 impl MTable {
     pub fn new() -> MTable {
-        MTable{rows: BTreeMap::new(), size: 0}
+        MTable{rows: BTreeMap::new(), tombstones: vec![], size: 0}
     }
 
     pub fn update(&mut self, row: &str, updates: &[MUpdate], timestamp: u64) -> Result<(), dtable::TError>{
@@ -82,6 +108,64 @@ impl MTable {
         self.insert(row, updates, timestamp)
     }
 
+    // Delete every row in the range [start, end) which was written at or
+    // before the timestamp. An empty end key means the range has no upper
+    // bound. Unless purge is set, the data is only hidden by the tombstone
+    // rather than removed, so that older snapshots can still read it.
+    pub fn delete_range(&mut self, start: &str, end: &str, timestamp: u64, purge: bool) {
+        let mut tombstone = RangeTombstone::new();
+        tombstone.set_start(start.to_owned());
+        tombstone.set_end(end.to_owned());
+        tombstone.set_timestamp(timestamp);
+
+        if !purge {
+            self.tombstones.push(tombstone);
+            return;
+        }
+
+        let mut removed = 0;
+        let mut empty_rows = vec![];
+        for (key, row) in self.rows.range_mut(start.to_owned()..) {
+            if !tombstone.covers(key) {
+                break;
+            }
+
+            removed += row.purge(timestamp);
+            if row.columns.is_empty() {
+                empty_rows.push(key.to_owned());
+            }
+        }
+
+        for key in empty_rows {
+            self.rows.remove(&key);
+        }
+
+        self.size = self.size.saturating_sub(removed);
+        self.tombstones.push(tombstone);
+    }
+
+    pub fn tombstones(&self) -> &[RangeTombstone] {
+        &self.tombstones
+    }
+
+    // Returns the number of rows in the MTable.
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    // Returns up to limit row keys, in order, starting at the provided key.
+    pub fn keys_from(&self, start: &str, limit: usize) -> Vec<String> {
+        self.rows.range(start.to_owned()..)
+            .take(limit)
+            .map(|(k, _)| k.to_owned())
+            .collect()
+    }
+
+    // Returns the rows, in order, starting at the provided key.
+    pub fn range_from(&self, start: &str) -> btree_map::Range<String, MRow> {
+        self.rows.range(start.to_owned()..)
+    }
+
     pub fn get_row(&self, row: &str) -> Option<&MRow> {
         self.rows.get(row)
     }
@@ -111,6 +195,24 @@ impl MTable {
         Ok(())
     }
 
+    // Add a row with every version of its columns, e.g. one read back
+    // from a checkpoint of the MTable.
+    pub fn insert_row(&mut self, row: &str, mut drow: DRow) -> Result<(), dtable::TError> {
+        if self.rows.get(row).is_some() {
+            return Err(dtable::TError::AlreadyExists);
+        }
+
+        let columns = drow.take_keys().into_iter()
+            .zip(drow.take_columns().into_iter())
+            .collect::<BTreeMap<_, _>>();
+        let size = columns.iter()
+            .map(|(k, c)| c.get_entries().iter().map(|e| k.len() + e.get_value().len()).sum::<usize>())
+            .sum::<usize>();
+        self.size += size;
+        self.rows.insert(row.to_string(), MRow{columns: columns});
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn select_one(&self, row: &str, col: &str) -> Option<DEntry> {
         match self.select(row, &[col], ::std::u64::MAX) {
@@ -138,24 +240,46 @@ impl MTable {
         )
     }
 
-    pub fn write_to_writer(&self, data: &mut io::Write, header: &mut io::Write) -> Result<DTableHeader, io::Error> {
+    pub fn write_to_writer(&self, data: &mut io::Write, header: &mut io::Write, created: u64, generation: u64) -> Result<DTableHeader, io::Error> {
+        self.write_part_to_writer("", 0, &families::Families::new(), data, header, created, generation)
+            .map(|(table_header, _)| table_header)
+    }
+
+    // Writes the rows from start onward, stopping once at least max_bytes
+    // of rows have been written (zero means there's no limit). Returns
+    // the header, and the key to continue from if any rows were left
+    // out. The range deletions go along with the part starting from the
+    // beginning. Rows are written with the policies of their column
+    // families.
+    pub fn write_part_to_writer(&self, start: &str, max_bytes: u64, policies: &families::Families, data: &mut io::Write, header: &mut io::Write, created: u64, generation: u64) -> Result<(DTableHeader, Option<String>), io::Error> {
         let mut headers = vec![];
         let mut offset = 0;
-        for (key, row) in &self.rows {
-            let length = row.write_to_writer(data)?;
+        let mut next = None;
+        for (key, row) in self.range_from(start) {
+            if max_bytes > 0 && offset >= max_bytes {
+                next = Some(key.to_owned());
+                break;
+            }
+
+            let (length, blocks) = row.write_to_writer(policies, data)?;
             let mut h = DTableHeaderEntry::new();
             h.set_offset(offset);
             h.set_key(String::from_str(key).unwrap());
+            h.set_families(protobuf::RepeatedField::from_vec(blocks));
             headers.push(h);
             offset += length;
         }
 
         let mut table_header = DTableHeader::new();
         table_header.set_entries(protobuf::RepeatedField::from_vec(headers));
+        if start.is_empty() {
+            table_header.set_tombstones(protobuf::RepeatedField::from_vec(self.tombstones.clone()));
+        }
+        dtable::summarize(&mut table_header, offset, created, generation);
 
-        table_header.write_to_writer(header)?;
+        keyindex::write_header(&table_header, header)?;
 
-        Ok(table_header)
+        Ok((table_header, next))
     }
 }
 
@@ -193,6 +317,30 @@ impl MRow {
             self.columns.insert(update.key.clone(), c);
         }
     }
+
+    // Remove every entry written at or before the timestamp, and drop any
+    // columns which end up empty. Returns the number of bytes removed.
+    fn purge(&mut self, timestamp: u64) -> usize {
+        let mut removed = 0;
+        let mut empty_columns = vec![];
+        for (key, col) in self.columns.iter_mut() {
+            let (kept, dropped): (Vec<_>, Vec<_>) = col.take_entries()
+                .into_iter()
+                .partition(|e| e.get_timestamp() > timestamp);
+
+            removed += dropped.iter().map(|e| e.get_value().len() + key.len()).sum::<usize>();
+            if kept.is_empty() {
+                empty_columns.push(key.to_owned());
+            }
+            col.set_entries(protobuf::RepeatedField::from_vec(kept));
+        }
+
+        for key in empty_columns {
+            self.columns.remove(&key);
+        }
+
+        removed
+    }
 }
 
 #[cfg(test)]
@@ -201,6 +349,8 @@ mod tests {
     use protobuf;
     use generated::dtable::DRow as DRow;
     use dtable;
+    use families;
+    use storage;
     use time;
 
     #[test]
@@ -240,6 +390,52 @@ mod tests {
         assert!(m.select_one("colin", "fake").is_none());
     }
 
+    #[test]
+    fn can_delete_range() {
+        let mut m = super::MTable::new();
+        for row in &["apple", "banana", "cherry", "date"] {
+            m.insert(row, &[super::MUpdate::new("fruit", vec![1])], 100).unwrap();
+        }
+        m.update("cherry", &[super::MUpdate::new("fruit", vec![2])], 200).unwrap();
+
+        m.delete_range("banana", "date", 150, true);
+
+        assert!(m.select_one("apple", "fruit").is_some());
+        assert!(m.get_row("banana").is_none());
+        assert_eq!(m.select_one("cherry", "fruit").unwrap().get_value(), &[2]);
+        assert!(m.select_one("date", "fruit").is_some());
+        assert_eq!(m.tombstones().len(), 1);
+        assert_eq!(m.size, 18);
+    }
+
+    #[test]
+    fn can_write_in_parts() {
+        let mut m = super::MTable::new();
+        for row in &["apple", "banana", "cherry", "date", "elderberry"] {
+            m.insert(row, &[super::MUpdate::new("fruit", vec![1; 100])], 100).unwrap();
+        }
+        m.delete_range("fig", "grape", 150, true);
+
+        let mut parts = vec![];
+        let mut start = String::new();
+        loop {
+            let (header, next) = m.write_part_to_writer(&start, 200, &families::Families::new(), &mut Vec::<u8>::new(), &mut Vec::<u8>::new(), 0, 1).unwrap();
+            parts.push(header);
+            match next {
+                Some(k) => start = k,
+                None    => break
+            }
+        }
+
+        // Each part stops once it has gone over the limit, and the parts
+        // don't overlap.
+        assert_eq!(parts.len(), 3);
+        assert_eq!((parts[0].get_min_key(), parts[0].get_max_key()), ("apple", "banana"));
+        assert_eq!((parts[1].get_min_key(), parts[1].get_max_key()), ("cherry", "date"));
+        assert_eq!(parts[2].get_row_count(), 1);
+        assert_eq!(parts.iter().map(|p| p.get_tombstones().len()).collect::<Vec<_>>(), vec![1, 0, 0]);
+    }
+
     #[test]
     fn can_read_and_write_mrow() {
         let mut m = super::MTable::new();
@@ -266,7 +462,7 @@ mod tests {
 
         // Write the MRow to a file.
         let mut f = std::fs::File::create("./data/state.bin").unwrap();
-        m.get_row("colin").unwrap().write_to_writer(&mut f).unwrap();
+        m.get_row("colin").unwrap().write_to_writer(&families::Families::new(), &mut f).unwrap();
 
         // Read the MRow back from the file.
         let mut g = std::fs::File::open("./data/state.bin").unwrap();
@@ -321,13 +517,16 @@ mod tests {
         // Now write the MTable to a file.
         let mut data = std::fs::File::create("./data/0.dtable").unwrap();
         let mut head = std::fs::File::create("./data/0.dtable.header").unwrap();
-        m.write_to_writer(&mut data, &mut head).unwrap();
+        let header = m.write_to_writer(&mut data, &mut head, time::precise_time_ns(), 1).unwrap();
+        assert_eq!(header.get_row_count(), 2);
+        assert_eq!(header.get_min_key(), "row1");
+        assert_eq!(header.get_max_key(), "row2");
+        assert_eq!(header.get_generation(), 1);
 
         // Now construct a DTable from the MTable and query it.
-        let header = std::fs::File::open("./data/0.dtable.header").unwrap();
         let d = dtable::DTable::new(
-            String::from("./data/0.dtable"),
-            header
+            std::sync::Arc::new(storage::DiskStorage),
+            String::from("./data/0.dtable")
         ).unwrap();
 
         // Check for existence of columns and correct values.
@@ -0,0 +1,140 @@
+/*
+    prepared.rs
+
+    Prepared queries, for clients which run the same select on lots of
+    different rows. A prepare query registers the columns to select, and
+    returns a handle for them. From then on, the client executes the
+    handle on each row, which is a much smaller query to send and parse
+    than the whole select.
+
+    A handle is a hash of the columns, so preparing the same columns
+    again, or on another server, gives the same handle. Handles aren't
+    kept across restarts, and the oldest are forgotten once there are
+    more than the capacity, so executing a handle can fail with
+    NotPrepared. The client then prepares the columns again and retries.
+*/
+
+use std::collections::{HashMap, VecDeque};
+
+// The number of prepared queries which are kept by default.
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+pub struct PreparedQueries {
+    pub capacity: usize,
+
+    // How many executed handles were found, and how many weren't.
+    pub hits: u64,
+    pub misses: u64,
+
+    columns: HashMap<u64, Vec<String>>,
+
+    // Handles in the order they were prepared, oldest first.
+    order: VecDeque<u64>
+}
+
+// Hash the columns (FNV-1a), separating them with a zero byte so that
+// ["ab"] and ["a", "b"] are different. Handle 0 is never used.
+fn hash_columns(get: &[String]) -> u64 {
+    let hash = get.iter().fold(0xcbf29ce484222325u64, |hash, column| {
+        column.as_bytes().iter().chain(&[0])
+            .fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+    });
+    match hash {
+        0 => 1,
+        h => h
+    }
+}
+
+impl PreparedQueries {
+    pub fn new(capacity: usize) -> PreparedQueries {
+        PreparedQueries{
+            capacity: capacity,
+            hits: 0,
+            misses: 0,
+            columns: HashMap::new(),
+            order: VecDeque::new()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    // Register the columns, and return their handle. If the hash
+    // collides with different columns, the next free handle is used
+    // instead.
+    pub fn prepare(&mut self, get: Vec<String>) -> u64 {
+        let mut handle = hash_columns(&get);
+        loop {
+            match self.columns.get(&handle) {
+                Some(c) if *c == get => return handle,
+                Some(_) => handle = handle.wrapping_add(1).max(1),
+                None    => break
+            }
+        }
+
+        while self.capacity > 0 && self.columns.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(h) => self.columns.remove(&h),
+                None    => break
+            };
+        }
+        self.columns.insert(handle, get);
+        self.order.push_back(handle);
+        handle
+    }
+
+    // The columns registered for the handle.
+    pub fn get(&self, handle: u64) -> Option<&Vec<String>> {
+        self.columns.get(&handle)
+    }
+
+    // Like get, but counts whether the handle was found, for when it's
+    // being executed.
+    pub fn execute(&mut self, handle: u64) -> Option<&Vec<String>> {
+        match self.columns.get(&handle) {
+            Some(c) => {
+                self.hits += 1;
+                Some(c)
+            },
+            None    => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn columns(c: &[&str]) -> Vec<String> {
+        c.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn prepares_queries() {
+        let mut prepared = super::PreparedQueries::new(10);
+        let handle = prepared.prepare(columns(&["name", "email"]));
+        assert_eq!(prepared.prepare(columns(&["name", "email"])), handle);
+        assert!(prepared.prepare(columns(&["name"])) != handle);
+        assert!(prepared.prepare(columns(&["nameemail"])) != handle);
+        assert_eq!(prepared.get(handle), Some(&columns(&["name", "email"])));
+        assert_eq!(prepared.get(handle + 1000), None);
+
+        assert!(prepared.execute(handle).is_some());
+        assert!(prepared.execute(handle + 1000).is_none());
+        assert_eq!((prepared.hits, prepared.misses), (1, 1));
+    }
+
+    #[test]
+    fn forgets_the_oldest_queries() {
+        let mut prepared = super::PreparedQueries::new(2);
+        let first = prepared.prepare(columns(&["a"]));
+        let second = prepared.prepare(columns(&["b"]));
+        let third = prepared.prepare(columns(&["c"]));
+        assert_eq!(prepared.len(), 2);
+        assert_eq!(prepared.get(first), None);
+        assert!(prepared.get(second).is_some());
+        assert!(prepared.get(third).is_some());
+    }
+}
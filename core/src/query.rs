@@ -0,0 +1,2025 @@
+/*
+    query.rs
+
+    This library parses queries from strings and creates
+    query objects.
+*/
+
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::collections::HashMap as Map;
+use std::iter::FromIterator;
+
+use serde_json;
+use protobuf;
+use protobuf::Message;
+
+use generated;
+
+// The version of the protobuf wire protocol, which is served under /v1/.
+// Within a version, fields and enum values in query.proto are only ever
+// added, never renumbered or reused, so older clients keep working.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum QError {
+    ParseError
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MUpdate {
+    pub value: Vec<u8>,
+    pub key: String
+}
+
+impl MUpdate {
+    pub fn new(key: &str, value: Vec<u8>) -> MUpdate {
+        MUpdate{
+            key: key.to_string(),
+            value: value
+        }
+    }
+
+    // Calculate the size of an update.
+    pub fn size(&self) -> usize {
+        self.value.len() + self.key.len()
+    }
+}
+
+// In order to support JSON parsing of queries, this struct is created
+// which has Strings instead of Vec<u8> in the value of the HashMap.
+// In order to be applied to the database, these QueryStrings must be
+// converted into regular Queries using .into_query().
+#[derive(Serialize, Deserialize, Debug)]
+pub enum QueryString {
+    // If a snapshot is given, the row is read as of the time that the
+    // snapshot was created. Soft deleted rows are only found if
+    // include_deleted is set. If strict is set, the select fails if any
+    // dtable can't be read, rather than reading the row from the rest.
+    // Setting column_limit (or column_start) pages through the row's
+    // columns in key order instead, starting from column_start (see
+    // Base::select_page).
+    #[serde(rename = "select")]
+    Select {
+        row: String,
+        get: Vec<String>,
+        #[serde(default, skip_serializing_if="is_zero")]
+        snapshot: u64,
+        #[serde(default, skip_serializing_if="is_false")]
+        include_deleted: bool,
+        #[serde(default, skip_serializing_if="Vec::is_empty")]
+        project: Vec<Projection>,
+        #[serde(default, skip_serializing_if="is_false")]
+        strict: bool,
+        #[serde(default, skip_serializing_if="String::is_empty")]
+        column_start: String,
+        #[serde(default, skip_serializing_if="is_zero")]
+        column_limit: u64
+    },
+    #[serde(rename = "update")]
+    Update { row: String, set: Map<String, String> },
+    #[serde(rename = "insert")]
+    Insert { row: String, set: Map<String, String> },
+    #[serde(rename = "stats")]
+    Stats {},
+    #[serde(rename = "list_keys")]
+    ListKeys {
+        #[serde(default)]
+        start: String,
+        #[serde(default="default_list_limit")]
+        limit: u64
+    },
+    // Deletes every row in [start_row, end_row). An empty end_row
+    // means that the range has no upper bound.
+    #[serde(rename = "delete_range")]
+    DeleteRange { start_row: String, end_row: String },
+    #[serde(rename = "create_snapshot")]
+    CreateSnapshot {},
+    // Each append adds a new element to the end of the list stored in
+    // every column, rather than replacing the column's value.
+    #[serde(rename = "append")]
+    Append { row: String, set: Map<String, String> },
+    // Reads the elements of a list column which were appended within
+    // [start, end], oldest first. A non-zero limit only returns the most
+    // recent elements, and an end of zero means there's no upper bound.
+    #[serde(rename = "select_list")]
+    SelectList {
+        row: String,
+        column: String,
+        #[serde(default, skip_serializing_if="is_zero")]
+        limit: u64,
+        #[serde(default, skip_serializing_if="is_zero")]
+        start: u64,
+        #[serde(default, skip_serializing_if="is_zero")]
+        end: u64
+    },
+    // Reads up to limit rows in [start, end), in order. An empty get
+    // returns every column. If there are more rows in the range, the
+    // result's next key continues the scan from where it left off.
+    #[serde(rename = "scan")]
+    Scan {
+        #[serde(default)]
+        start: String,
+        #[serde(default)]
+        end: String,
+        #[serde(default)]
+        get: Vec<String>,
+        #[serde(default="default_list_limit")]
+        limit: u64,
+        #[serde(default, skip_serializing_if="is_zero")]
+        snapshot: u64
+    },
+    // Describes the row's columns, without reading back their values.
+    #[serde(rename = "describe")]
+    Describe { row: String },
+    // Lists the rows which have been read or written the most recently.
+    #[serde(rename = "top_keys")]
+    TopKeys {
+        #[serde(default="default_top_keys_limit")]
+        limit: u64
+    },
+    // Hides the row from selects and scans, but keeps its data so that
+    // it can be restored by an undelete until the retention window ends.
+    #[serde(rename = "soft_delete")]
+    SoftDelete { row: String },
+    #[serde(rename = "undelete")]
+    Undelete { row: String },
+    // Reads every version of the column which is still stored, oldest
+    // first, with its timestamp. A non-zero limit only returns the most
+    // recent versions.
+    #[serde(rename = "history")]
+    History {
+        row: String,
+        column: String,
+        #[serde(default, skip_serializing_if="is_zero")]
+        limit: u64
+    },
+    // Updates several rows, keyed by row, as a single write: either every
+    // update is applied or none are, and reads never see some of them
+    // without the others.
+    #[serde(rename = "transaction")]
+    Transaction { updates: Map<String, Map<String, String>> },
+    // Registers the columns of a select which is run over and over, and
+    // returns a handle for it. Executing the handle on a row is the same
+    // as selecting those columns from it, but the query is much smaller.
+    #[serde(rename = "prepare")]
+    Prepare { get: Vec<String> },
+    #[serde(rename = "execute")]
+    Execute { handle: u64, row: String },
+    // Returns a hash over the latest values of the row's columns, so that
+    // another system holding a copy of the row can check that it's in
+    // sync without reading the values back. The range form combines the
+    // checksums of every row from start up to (but not including) end.
+    #[serde(rename = "checksum")]
+    Checksum { row: String },
+    #[serde(rename = "checksum_range")]
+    ChecksumRange { start: String, end: String },
+    // Returns the columns whose latest value at to_ts is different from
+    // their latest value at from_ts, with both values. A to_ts of zero
+    // means the current time.
+    #[serde(rename = "diff")]
+    Diff {
+        row: String,
+        from_ts: u64,
+        #[serde(default, skip_serializing_if="is_zero")]
+        to_ts: u64
+    },
+    // Pauses background compaction until it's unfrozen again, e.g.
+    // during peak traffic or disk maintenance. The memtable is still
+    // written to disk when it's full.
+    #[serde(rename = "freeze_compaction")]
+    FreezeCompaction {},
+    #[serde(rename = "unfreeze_compaction")]
+    UnfreezeCompaction {},
+    // Says which tables a read of a row or range would search, and
+    // roughly how many bytes it would read from them, without running it.
+    #[serde(rename = "explain")]
+    Explain(Box<QueryString>),
+}
+
+fn default_list_limit() -> u64 { 100 }
+fn default_top_keys_limit() -> u64 { 10 }
+fn is_zero(x: &u64) -> bool { *x == 0 }
+fn is_false(x: &bool) -> bool { !*x }
+
+impl QueryString {
+    fn into_query(self) -> Query {
+        fn convert_map(input: Map<String, String>) -> Map<String, Vec<u8>> {
+            Map::from_iter(
+                input.into_iter().map(|(k, v)| (k, v.into_bytes()))
+            )
+        }
+        match self {
+            QueryString::Select{row: r, get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl} =>
+                Query::Select{row: r, get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl},
+            QueryString::Update{row: r, set: s} => Query::Update{row: r, set: convert_map(s)},
+            QueryString::Insert{row: r, set: s} => Query::Insert{row: r, set: convert_map(s)},
+            QueryString::Stats{} => Query::Stats,
+            QueryString::ListKeys{start: s, limit: l} => Query::ListKeys{start: s, limit: l},
+            QueryString::DeleteRange{start_row: s, end_row: e} => Query::DeleteRange{start_row: s, end_row: e},
+            QueryString::CreateSnapshot{} => Query::CreateSnapshot,
+            QueryString::Append{row: r, set: s} => Query::Append{row: r, set: convert_map(s)},
+            QueryString::SelectList{row: r, column: c, limit: l, start: s, end: e} =>
+                Query::SelectList{row: r, column: c, limit: l, start: s, end: e},
+            QueryString::Scan{start: s, end: e, get: g, limit: l, snapshot: n} =>
+                Query::Scan{start: s, end: e, get: g, limit: l, snapshot: n},
+            QueryString::Describe{row: r} => Query::Describe{row: r},
+            QueryString::TopKeys{limit: l} => Query::TopKeys{limit: l},
+            QueryString::SoftDelete{row: r} => Query::SoftDelete{row: r},
+            QueryString::Undelete{row: r} => Query::Undelete{row: r},
+            QueryString::History{row: r, column: c, limit: l} => Query::History{row: r, column: c, limit: l},
+            QueryString::Transaction{updates: u} => Query::Transaction{
+                updates: u.into_iter().map(|(row, set)| (row, convert_map(set))).collect()
+            },
+            QueryString::Prepare{get: g} => Query::Prepare{get: g},
+            QueryString::Execute{handle: h, row: r} => Query::Execute{handle: h, row: r},
+            QueryString::Checksum{row: r} => Query::Checksum{row: r},
+            QueryString::ChecksumRange{start: s, end: e} => Query::ChecksumRange{start: s, end: e},
+            QueryString::Diff{row: r, from_ts: f, to_ts: t} => Query::Diff{row: r, from_ts: f, to_ts: t},
+            QueryString::FreezeCompaction{} => Query::FreezeCompaction,
+            QueryString::UnfreezeCompaction{} => Query::UnfreezeCompaction,
+            QueryString::Explain(q) => Query::Explain(Box::new(q.into_query()))
+        }
+    }
+}
+
+// A Projection is a value which the server derives from a column, and
+// returns in place of the column's value, e.g. to check whether a large
+// value has changed without reading it. Lengths and offsets are in bytes.
+// Hashes are 64 bit FNV-1a, which is fast but not cryptographic. Lengths
+// and hashes are returned as text.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Projection {
+    #[serde(rename = "length")]
+    Length(String),
+    #[serde(rename = "hash")]
+    Hash(String),
+    #[serde(rename = "substring")]
+    Substring { column: String, start: u64, length: u64 }
+}
+
+impl Projection {
+    pub fn column(&self) -> &str {
+        match *self {
+            Projection::Length(ref c) |
+            Projection::Hash(ref c) |
+            Projection::Substring{column: ref c, ..} => c
+        }
+    }
+
+    pub fn evaluate(&self, value: &[u8]) -> Vec<u8> {
+        match *self {
+            Projection::Length(_) => format!("{}", value.len()).into_bytes(),
+            Projection::Hash(_) => {
+                let hash = value.iter().fold(0xcbf29ce484222325, |h: u64, &b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+                format!("{:016x}", hash).into_bytes()
+            },
+            Projection::Substring{start: s, length: l, ..} => {
+                let start = cmp::min(s, value.len() as u64) as usize;
+                let end = cmp::min(s.saturating_add(l), value.len() as u64) as usize;
+                value[start..end].to_vec()
+            }
+        }
+    }
+
+    fn from_generated(p: &mut generated::query::Projection) -> Projection {
+        match p.get_field_type() {
+            generated::query::ProjectionType::LENGTH => Projection::Length(p.take_column()),
+            generated::query::ProjectionType::HASH => Projection::Hash(p.take_column()),
+            generated::query::ProjectionType::SUBSTRING => Projection::Substring{
+                column: p.take_column(),
+                start: p.get_start(),
+                length: p.get_length()
+            }
+        }
+    }
+
+    fn into_generated(self) -> generated::query::Projection {
+        let mut p = generated::query::Projection::new();
+        match self {
+            Projection::Length(c) => {
+                p.set_field_type(generated::query::ProjectionType::LENGTH);
+                p.set_column(c);
+            },
+            Projection::Hash(c) => {
+                p.set_field_type(generated::query::ProjectionType::HASH);
+                p.set_column(c);
+            },
+            Projection::Substring{column: c, start: s, length: l} => {
+                p.set_field_type(generated::query::ProjectionType::SUBSTRING);
+                p.set_column(c);
+                p.set_start(s);
+                p.set_length(l);
+            }
+        }
+        p
+    }
+}
+
+#[derive(Clone)]
+pub enum Query {
+    Select {
+        row: String,
+        get: Vec<String>,
+        snapshot: u64,
+        include_deleted: bool,
+        project: Vec<Projection>,
+        strict: bool,
+        column_start: String,
+        column_limit: u64
+    },
+    Update { row: String, set: Map<String, Vec<u8>> },
+    Insert { row: String, set: Map<String, Vec<u8>> },
+    Stats,
+    ListKeys { start: String, limit: u64 },
+    DeleteRange { start_row: String, end_row: String },
+    CreateSnapshot,
+    Append { row: String, set: Map<String, Vec<u8>> },
+    SelectList { row: String, column: String, limit: u64, start: u64, end: u64 },
+    Scan { start: String, end: String, get: Vec<String>, limit: u64, snapshot: u64 },
+    Describe { row: String },
+    TopKeys { limit: u64 },
+    SoftDelete { row: String },
+    Undelete { row: String },
+    History { row: String, column: String, limit: u64 },
+    Transaction { updates: Map<String, Map<String, Vec<u8>>> },
+    Prepare { get: Vec<String> },
+    Execute { handle: u64, row: String },
+    Checksum { row: String },
+    ChecksumRange { start: String, end: String },
+    Diff { row: String, from_ts: u64, to_ts: u64 },
+    FreezeCompaction,
+    UnfreezeCompaction,
+    Explain(Box<Query>),
+}
+
+// The QueryContext carries information about the request that a query
+// came from, which isn't part of the query itself. If the request is
+// being traced, span_id is the span which covers the whole request.
+// client_address is the IP address that the request came from. If a
+// write has an idempotency_key, retrying it won't apply it twice. A read
+// with a min_read_timestamp sees every write committed at or before it,
+// or on a replica which hasn't caught up that far, is refused.
+// A write is only reported as done once ack_level is met.
+#[derive(Debug, Clone, Default)]
+pub struct QueryContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub auth_token: String,
+    pub client_address: String,
+    pub idempotency_key: String,
+    pub min_read_timestamp: u64,
+    pub ack_level: AckLevel
+}
+
+// The AckLevel says which copies of a write must be stored before it's
+// acknowledged: just the leader's, the leader's and at least one
+// follower's, or every replica's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AckLevel {
+    Leader,
+    LeaderPlusOne,
+    All
+}
+
+impl Default for AckLevel {
+    fn default() -> AckLevel {
+        AckLevel::Leader
+    }
+}
+
+impl AckLevel {
+    pub fn parse(level: &str) -> Option<AckLevel> {
+        match level {
+            "leader"    => Some(AckLevel::Leader),
+            "leader+1"  => Some(AckLevel::LeaderPlusOne),
+            "all"       => Some(AckLevel::All),
+            _           => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AckLevel::Leader        => "leader",
+            AckLevel::LeaderPlusOne => "leader+1",
+            AckLevel::All           => "all"
+        }
+    }
+
+    // Whether a write can be acknowledged at this level, when `in_sync`
+    // of the leader's `followers` have stored it. Every replica includes
+    // at least one follower, so All is never easier to meet than
+    // LeaderPlusOne.
+    pub fn is_met(&self, in_sync: usize, followers: usize) -> bool {
+        match *self {
+            AckLevel::Leader        => true,
+            AckLevel::LeaderPlusOne => in_sync >= 1,
+            AckLevel::All           => in_sync >= cmp::max(followers, 1)
+        }
+    }
+}
+
+impl QueryContext {
+    pub fn new() -> QueryContext {
+        QueryContext::default()
+    }
+
+    pub fn with_trace_id(trace_id: &str) -> QueryContext {
+        QueryContext{
+            trace_id: trace_id.to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+// Stats contains a summary of the internal state of the database
+// engine, returned by a stats query.
+#[derive(Serialize, Debug, Default)]
+pub struct Stats {
+    pub memtable_size: u64,
+    pub memtable_rows: u64,
+    pub disktables: u64,
+    pub disktable_rows: u64,
+    pub disktable_bytes: u64,
+    pub commit_log_bytes: u64,
+    pub uptime_seconds: u64,
+    pub minor_compactions: u64,
+    pub major_compactions: u64,
+
+    // The most dtables that a row has been merged from by a major
+    // compaction, and how many rows were merged in batches because they
+    // were in more than max_merge_fan_in dtables.
+    pub merge_max_fan_in: u64,
+    pub merge_batched_rows: u64,
+
+    // How many writes waited for a flush or compaction, how long they
+    // waited in total, and how long the last flush of the memtable took.
+    pub write_stalls: u64,
+    pub write_stall_ns: u64,
+    pub last_flush_ns: u64,
+    pub free_bytes: u64,
+    pub out_of_space: bool,
+    pub permission_denied: u64,
+    pub row_cache_rows: u64,
+    pub row_cache_hits: u64,
+    pub row_cache_misses: u64,
+    pub result_cache_results: u64,
+    pub result_cache_hits: u64,
+    pub result_cache_misses: u64,
+
+    // The costs of every query run so far, added up (see QueryCost).
+    pub queries: u64,
+    pub query_dtables_probed: u64,
+    pub query_bytes_read: u64,
+    pub query_rows_merged: u64,
+    pub query_execute_ns: u64,
+    pub prepared_handles: u64,
+    pub prepared_hits: u64,
+    pub prepared_misses: u64,
+
+    // Set if any dtable is degraded (see DTableStats).
+    pub degraded: bool,
+    pub compaction_frozen: bool,
+    pub dtables: Vec<DTableStats>
+}
+
+// A QueryCost is what running a query took: how many times a dtable was
+// searched for a row, how many bytes were read from dtables, and how
+// many versions of rows were read from them and merged into the result.
+// The times are how long the query waited in the worker pool's queue,
+// waited for the database lock, and ran for, in nanoseconds.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct QueryCost {
+    pub dtables_probed: u64,
+    pub bytes_read: u64,
+    pub rows_merged: u64,
+    pub queue_ns: u64,
+    pub lock_ns: u64,
+    pub execute_ns: u64
+}
+
+impl QueryCost {
+    pub fn add(&mut self, other: &QueryCost) {
+        self.dtables_probed += other.dtables_probed;
+        self.bytes_read += other.bytes_read;
+        self.rows_merged += other.rows_merged;
+        self.queue_ns += other.queue_ns;
+        self.lock_ns += other.lock_ns;
+        self.execute_ns += other.execute_ns;
+    }
+}
+
+// DTableStats describes a single dtable, and how often reads have
+// searched it. A dtable is degraded if its most recent reads have all
+// failed, even after retrying them.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct DTableStats {
+    pub filename: String,
+    pub rows: u64,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub bytes_read: u64,
+    pub read_errors: u64,
+    pub degraded: bool
+}
+
+// A ListEntry is one element of a list column, along with the time
+// that it was appended.
+#[derive(Serialize, Debug, Clone)]
+pub struct ListEntry {
+    pub timestamp: u64,
+    pub value: Vec<u8>
+}
+
+// A ColumnDescription summarizes one column of a row: how many versions
+// of it are stored, when the oldest and newest were written, and how big
+// its values add up to.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ColumnDescription {
+    pub name: String,
+    pub entries: u64,
+    pub first_timestamp: u64,
+    pub last_timestamp: u64,
+    pub bytes: u64
+}
+
+// A ColumnChange is a column whose latest value is different at the two
+// timestamps of a diff. The value is None at a timestamp where the column
+// hadn't been written yet, or was deleted.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ColumnChange {
+    pub column: String,
+    pub before: Option<Vec<u8>>,
+    pub after: Option<Vec<u8>>
+}
+
+// A HotKey is one of the busiest rows, along with roughly how often it
+// has been read and written lately.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HotKey {
+    pub key: String,
+    pub reads_per_second: f64,
+    pub writes_per_second: f64
+}
+
+// A ScanRow is one row returned by a scan, with the value of each of its
+// columns.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ScanRow {
+    pub key: String,
+    pub columns: Vec<(String, Vec<u8>)>
+}
+
+// An Explanation says what reading a row or range would involve: whether
+// the memtable has any of it, which dtables would be searched, and how
+// many bytes would be read from them. skipped_dtables is the number of
+// dtables whose key ranges rule them out without searching them.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Explanation {
+    pub memtable: bool,
+    pub dtables: Vec<DTableRead>,
+    pub skipped_dtables: u64,
+    pub bytes: u64
+}
+
+// A DTableRead is one dtable which a read would search, along with how
+// many of the rows it's after are in there, and their size in bytes.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct DTableRead {
+    pub filename: String,
+    pub rows: u64,
+    pub bytes: u64
+}
+
+#[derive(Serialize, Debug)]
+pub enum QueryResult {
+    NotImplemented,
+    RowNotFound,
+    RowAlreadyExists,
+    InternalError{ error: String },
+    Done,
+    PartialCommit{ error: String },
+    NetworkError,
+    OutOfSpace,
+    SnapshotNotFound,
+    InvalidKey,
+    Busy,
+    NotAllowed,
+    PermissionDenied,
+    InsufficientReplicas,
+    ReplicaBehind,
+    NotLeader,
+    WrongShard{ version: u64 },
+    ResourceExhausted{ reason: String },
+    NotPrepared,
+    SchemaViolation{ reason: String },
+    Snapshot{ id: u64 },
+    Prepared{ handle: u64 },
+    Checksum{ checksum: u64, rows: u64 },
+    Data{ columns: Vec<Option<Vec<u8>>> },
+    Stats{ stats: Stats },
+    Keys{ keys: Vec<String> },
+    List{ entries: Vec<ListEntry> },
+    Rows{ rows: Vec<ScanRow>, next: String },
+    Description{ columns: Vec<ColumnDescription> },
+    Changes{ changes: Vec<ColumnChange> },
+    HotKeys{ keys: Vec<HotKey> },
+    Explanation{ explanation: Explanation }
+}
+
+impl Query {
+    pub fn new_select(row: &str, get: &[&str]) -> Query {
+        Query::Select{
+            row: row.to_string(),
+            get: get.iter().map(|s| s.to_string()).collect(),
+            snapshot: 0,
+            include_deleted: false,
+            project: vec![],
+            strict: false,
+            column_start: String::new(),
+            column_limit: 0
+        }
+    }
+
+    pub fn as_query_string(&self) -> QueryString {
+        fn convert_map(input: &Map<String, Vec<u8>>) -> Map<String, String> {
+            Map::from_iter(
+                input.iter().map(|(k, v)| (k.clone(), String::from_utf8(v.to_vec()).unwrap()))
+            )
+        }
+
+        match *self {
+            Query::Select{row: ref r, get: ref g, snapshot: s, include_deleted: d, project: ref p, strict: t, column_start: ref cs, column_limit: cl} =>
+                QueryString::Select{row: r.clone(), get: g.clone(), snapshot: s, include_deleted: d, project: p.clone(), strict: t, column_start: cs.clone(), column_limit: cl},
+            Query::Update{row: ref r, set: ref s} => QueryString::Update{row: r.clone(), set: convert_map(s)},
+            Query::Insert{row: ref r, set: ref s} => QueryString::Insert{row: r.clone(), set: convert_map(s)},
+            Query::Stats => QueryString::Stats{},
+            Query::ListKeys{start: ref s, limit: l} => QueryString::ListKeys{start: s.clone(), limit: l},
+            Query::DeleteRange{start_row: ref s, end_row: ref e} => QueryString::DeleteRange{start_row: s.clone(), end_row: e.clone()},
+            Query::CreateSnapshot => QueryString::CreateSnapshot{},
+            Query::Append{row: ref r, set: ref s} => QueryString::Append{row: r.clone(), set: convert_map(s)},
+            Query::SelectList{row: ref r, column: ref c, limit: l, start: s, end: e} =>
+                QueryString::SelectList{row: r.clone(), column: c.clone(), limit: l, start: s, end: e},
+            Query::Scan{start: ref s, end: ref e, get: ref g, limit: l, snapshot: n} =>
+                QueryString::Scan{start: s.clone(), end: e.clone(), get: g.clone(), limit: l, snapshot: n},
+            Query::Describe{row: ref r} => QueryString::Describe{row: r.clone()},
+            Query::TopKeys{limit: l} => QueryString::TopKeys{limit: l},
+            Query::SoftDelete{row: ref r} => QueryString::SoftDelete{row: r.clone()},
+            Query::Undelete{row: ref r} => QueryString::Undelete{row: r.clone()},
+            Query::History{row: ref r, column: ref c, limit: l} =>
+                QueryString::History{row: r.clone(), column: c.clone(), limit: l},
+            Query::Transaction{updates: ref u} => QueryString::Transaction{
+                updates: u.iter().map(|(row, set)| (row.clone(), convert_map(set))).collect()
+            },
+            Query::Prepare{get: ref g} => QueryString::Prepare{get: g.clone()},
+            Query::Execute{handle: h, row: ref r} => QueryString::Execute{handle: h, row: r.clone()},
+            Query::Checksum{row: ref r} => QueryString::Checksum{row: r.clone()},
+            Query::ChecksumRange{start: ref s, end: ref e} => QueryString::ChecksumRange{start: s.clone(), end: e.clone()},
+            Query::Diff{row: ref r, from_ts: f, to_ts: t} => QueryString::Diff{row: r.clone(), from_ts: f, to_ts: t},
+            Query::FreezeCompaction => QueryString::FreezeCompaction{},
+            Query::UnfreezeCompaction => QueryString::UnfreezeCompaction{},
+            Query::Explain(ref q) => QueryString::Explain(Box::new(q.as_query_string()))
+        }
+    }
+
+    // Returns true if the query changes the data in the database, or any
+    // other state which is saved along with it.
+    pub fn is_write(&self) -> bool {
+        match *self {
+            Query::Update{..} | Query::Insert{..} | Query::Append{..} | Query::DeleteRange{..} |
+            Query::SoftDelete{..} | Query::Undelete{..} | Query::Transaction{..} |
+            Query::FreezeCompaction | Query::UnfreezeCompaction => true,
+            Query::Select{..} | Query::SelectList{..} | Query::Stats | Query::ListKeys{..} |
+            Query::CreateSnapshot | Query::Scan{..} | Query::Describe{..} | Query::TopKeys{..} |
+            Query::History{..} | Query::Prepare{..} | Query::Execute{..} | Query::Checksum{..} |
+            Query::ChecksumRange{..} | Query::Diff{..} | Query::Explain(_) => false
+        }
+    }
+
+    pub fn new_execute(handle: u64, row: &str) -> Query {
+        Query::Execute{
+            handle: handle,
+            row: row.to_string()
+        }
+    }
+
+    pub fn new_update(row: &str, set: Vec<MUpdate>) -> Query {
+        Query::Update{
+            row: row.to_string(),
+            set: set.into_iter().map(|u| (u.key, u.value)).collect()
+        }
+    }
+
+    pub fn new_insert(row: &str, set: Vec<MUpdate>) -> Query {
+        Query::Insert{
+            row: row.to_string(),
+            set: set.into_iter().map(|u| (u.key, u.value)).collect()
+        }
+    }
+
+    // Create a query from a protobuf query.
+    pub fn from_bytes(mut reader: &mut io::Read) -> Result<Query, QError> {
+        let q = protobuf::parse_from_reader::<generated::query::Query>(&mut reader).map_err(|_| QError::ParseError)?;
+        Query::from_generated(q)
+    }
+
+    fn from_generated(mut q: generated::query::Query) -> Result<Query, QError> {
+        match q.get_field_type() {
+            generated::query::QueryType::SELECT => Ok(Query::Select{
+                row: q.take_row(),
+                get: q.take_columns().into_vec(),
+                snapshot: q.get_snapshot(),
+                include_deleted: q.get_include_deleted(),
+                project: q.take_project().into_vec()
+                    .into_iter()
+                    .map(|mut p| Projection::from_generated(&mut p))
+                    .collect(),
+                strict: q.get_strict(),
+                column_start: q.take_column_start(),
+                column_limit: q.get_limit()
+            }),
+            generated::query::QueryType::INSERT => Ok(Query::Insert{
+                row: q.take_row(),
+                set: q.take_values()
+            }),
+            generated::query::QueryType::UPDATE => Ok(Query::Update{
+                row: q.take_row(),
+                set: q.take_values()
+            }),
+            generated::query::QueryType::STATS => Ok(Query::Stats),
+            generated::query::QueryType::LIST_KEYS => Ok(Query::ListKeys{
+                start: q.take_row(),
+                limit: q.get_limit()
+            }),
+            generated::query::QueryType::DELETE_RANGE => Ok(Query::DeleteRange{
+                start_row: q.take_row(),
+                end_row: q.take_end_row()
+            }),
+            generated::query::QueryType::CREATE_SNAPSHOT => Ok(Query::CreateSnapshot),
+            generated::query::QueryType::APPEND => Ok(Query::Append{
+                row: q.take_row(),
+                set: q.take_values()
+            }),
+            generated::query::QueryType::SELECT_LIST => Ok(Query::SelectList{
+                row: q.take_row(),
+                column: match q.take_columns().into_vec().pop() {
+                    Some(c) => c,
+                    None    => return Err(QError::ParseError)
+                },
+                limit: q.get_limit(),
+                start: q.get_start_timestamp(),
+                end: q.get_end_timestamp()
+            }),
+            generated::query::QueryType::SCAN => Ok(Query::Scan{
+                start: q.take_row(),
+                end: q.take_end_row(),
+                get: q.take_columns().into_vec(),
+                limit: q.get_limit(),
+                snapshot: q.get_snapshot()
+            }),
+            generated::query::QueryType::DESCRIBE => Ok(Query::Describe{
+                row: q.take_row()
+            }),
+            generated::query::QueryType::TOP_KEYS => Ok(Query::TopKeys{
+                limit: q.get_limit()
+            }),
+            generated::query::QueryType::SOFT_DELETE => Ok(Query::SoftDelete{
+                row: q.take_row()
+            }),
+            generated::query::QueryType::UNDELETE => Ok(Query::Undelete{
+                row: q.take_row()
+            }),
+            generated::query::QueryType::HISTORY => Ok(Query::History{
+                row: q.take_row(),
+                column: match q.take_columns().into_vec().pop() {
+                    Some(c) => c,
+                    None    => return Err(QError::ParseError)
+                },
+                limit: q.get_limit()
+            }),
+            generated::query::QueryType::TRANSACTION => Ok(Query::Transaction{
+                updates: q.take_writes().into_vec()
+                    .into_iter()
+                    .map(|mut w| (w.take_row(), w.take_values()))
+                    .collect()
+            }),
+            generated::query::QueryType::PREPARE => Ok(Query::Prepare{
+                get: q.take_columns().into_vec()
+            }),
+            generated::query::QueryType::EXECUTE => Ok(Query::Execute{
+                handle: q.get_handle(),
+                row: q.take_row()
+            }),
+            generated::query::QueryType::CHECKSUM => Ok(Query::Checksum{
+                row: q.take_row()
+            }),
+            generated::query::QueryType::CHECKSUM_RANGE => Ok(Query::ChecksumRange{
+                start: q.take_row(),
+                end: q.take_end_row()
+            }),
+            generated::query::QueryType::DIFF => Ok(Query::Diff{
+                row: q.take_row(),
+                from_ts: q.get_start_timestamp(),
+                to_ts: q.get_end_timestamp()
+            }),
+            generated::query::QueryType::FREEZE_COMPACTION => Ok(Query::FreezeCompaction),
+            generated::query::QueryType::UNFREEZE_COMPACTION => Ok(Query::UnfreezeCompaction),
+            generated::query::QueryType::EXPLAIN => match q.has_explain() {
+                true  => Ok(Query::Explain(Box::new(Query::from_generated(q.take_explain())?))),
+                false => Err(QError::ParseError)
+            }
+        }
+    }
+
+    // Turn the query into a protobuf, and then write it to a writer.
+    pub fn write_to_writer(self, mut writer: &mut io::Write) -> Result<(), QError> {
+        self.into_generated().write_to_writer(writer).map_err(|_| QError::ParseError)
+    }
+
+    fn into_generated(self) -> generated::query::Query {
+        let mut q = generated::query::Query::new();
+        match self {
+            Query::Select{row: r, get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl} => {
+                q.set_field_type(generated::query::QueryType::SELECT);
+                q.set_row(r);
+                q.set_columns(protobuf::RepeatedField::from_vec(g));
+                q.set_snapshot(s);
+                q.set_include_deleted(d);
+                q.set_project(protobuf::RepeatedField::from_vec(
+                    p.into_iter().map(|p| p.into_generated()).collect()
+                ));
+                q.set_strict(t);
+                q.set_column_start(cs);
+                q.set_limit(cl);
+            },
+            Query::Insert{row: r, set: s} => {
+                q.set_field_type(generated::query::QueryType::INSERT);
+                q.set_row(r);
+                q.set_values(s);
+            },
+            Query::Update{row: r, set: s} => {
+                q.set_field_type(generated::query::QueryType::UPDATE);
+                q.set_row(r);
+                q.set_values(s);
+            },
+            Query::Stats => {
+                q.set_field_type(generated::query::QueryType::STATS);
+            },
+            Query::ListKeys{start: s, limit: l} => {
+                q.set_field_type(generated::query::QueryType::LIST_KEYS);
+                q.set_row(s);
+                q.set_limit(l);
+            },
+            Query::DeleteRange{start_row: s, end_row: e} => {
+                q.set_field_type(generated::query::QueryType::DELETE_RANGE);
+                q.set_row(s);
+                q.set_end_row(e);
+            },
+            Query::CreateSnapshot => {
+                q.set_field_type(generated::query::QueryType::CREATE_SNAPSHOT);
+            },
+            Query::Append{row: r, set: s} => {
+                q.set_field_type(generated::query::QueryType::APPEND);
+                q.set_row(r);
+                q.set_values(s);
+            },
+            Query::SelectList{row: r, column: c, limit: l, start: s, end: e} => {
+                q.set_field_type(generated::query::QueryType::SELECT_LIST);
+                q.set_row(r);
+                q.set_columns(protobuf::RepeatedField::from_vec(vec![c]));
+                q.set_limit(l);
+                q.set_start_timestamp(s);
+                q.set_end_timestamp(e);
+            },
+            Query::Scan{start: s, end: e, get: g, limit: l, snapshot: n} => {
+                q.set_field_type(generated::query::QueryType::SCAN);
+                q.set_row(s);
+                q.set_end_row(e);
+                q.set_columns(protobuf::RepeatedField::from_vec(g));
+                q.set_limit(l);
+                q.set_snapshot(n);
+            },
+            Query::Describe{row: r} => {
+                q.set_field_type(generated::query::QueryType::DESCRIBE);
+                q.set_row(r);
+            },
+            Query::TopKeys{limit: l} => {
+                q.set_field_type(generated::query::QueryType::TOP_KEYS);
+                q.set_limit(l);
+            },
+            Query::SoftDelete{row: r} => {
+                q.set_field_type(generated::query::QueryType::SOFT_DELETE);
+                q.set_row(r);
+            },
+            Query::Undelete{row: r} => {
+                q.set_field_type(generated::query::QueryType::UNDELETE);
+                q.set_row(r);
+            },
+            Query::History{row: r, column: c, limit: l} => {
+                q.set_field_type(generated::query::QueryType::HISTORY);
+                q.set_row(r);
+                q.set_columns(protobuf::RepeatedField::from_vec(vec![c]));
+                q.set_limit(l);
+            },
+            Query::Transaction{updates: u} => {
+                q.set_field_type(generated::query::QueryType::TRANSACTION);
+                let mut writes = u.into_iter()
+                    .map(|(row, set)| {
+                        let mut w = generated::query::TransactionWrite::new();
+                        w.set_row(row);
+                        w.set_values(set);
+                        w
+                    })
+                    .collect::<Vec<_>>();
+                writes.sort_by(|a, b| a.get_row().cmp(b.get_row()));
+                q.set_writes(protobuf::RepeatedField::from_vec(writes));
+            },
+            Query::Prepare{get: g} => {
+                q.set_field_type(generated::query::QueryType::PREPARE);
+                q.set_columns(protobuf::RepeatedField::from_vec(g));
+            },
+            Query::Execute{handle: h, row: r} => {
+                q.set_field_type(generated::query::QueryType::EXECUTE);
+                q.set_handle(h);
+                q.set_row(r);
+            },
+            Query::Checksum{row: r} => {
+                q.set_field_type(generated::query::QueryType::CHECKSUM);
+                q.set_row(r);
+            },
+            Query::ChecksumRange{start: s, end: e} => {
+                q.set_field_type(generated::query::QueryType::CHECKSUM_RANGE);
+                q.set_row(s);
+                q.set_end_row(e);
+            },
+            Query::Diff{row: r, from_ts: f, to_ts: t} => {
+                q.set_field_type(generated::query::QueryType::DIFF);
+                q.set_row(r);
+                q.set_start_timestamp(f);
+                q.set_end_timestamp(t);
+            },
+            Query::FreezeCompaction => {
+                q.set_field_type(generated::query::QueryType::FREEZE_COMPACTION);
+            },
+            Query::UnfreezeCompaction => {
+                q.set_field_type(generated::query::QueryType::UNFREEZE_COMPACTION);
+            },
+            Query::Explain(e) => {
+                q.set_field_type(generated::query::QueryType::EXPLAIN);
+                q.set_explain(e.into_generated());
+            }
+        };
+        q
+    }
+
+    // This function parses an arbitrary string and returns
+    // a query or an error.
+    pub fn parse(input: &str) -> Result<Query, QError> {
+        let qs: QueryString = serde_json::from_str(input).map_err(|_| QError::ParseError)?;
+        Ok(qs.into_query())
+    }
+
+    // Like parse, but the query may have "debug": true next to it, e.g.
+    // {"select": {...}, "debug": true}, which asks for its cost to be
+    // returned along with the result. Returns the query and the flag.
+    pub fn parse_request(input: &str) -> Result<(Query, bool), QError> {
+        let mut value: serde_json::Value = serde_json::from_str(input).map_err(|_| QError::ParseError)?;
+        let debug = match value.as_object_mut().and_then(|o| o.remove("debug")) {
+            Some(serde_json::Value::Bool(d)) => d,
+            Some(_) => return Err(QError::ParseError),
+            None    => false
+        };
+        let qs: QueryString = serde_json::from_value(value).map_err(|_| QError::ParseError)?;
+        Ok((qs.into_query(), debug))
+    }
+
+    // Return the query as a JSON object.
+    pub fn as_json(&self) -> Result<String, QError> {
+        serde_json::to_string(&self.as_query_string()).map_err(|_| QError::ParseError)
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.as_json() {
+            Ok(s)   => write!(f, "{}", s),
+            Err(_)  => write!(f, "<Unable to parse query>")
+        }
+    }
+}
+
+impl Stats {
+    pub fn from_generated(s: &generated::query::Stats) -> Stats {
+        Stats{
+            memtable_size: s.get_memtable_size(),
+            memtable_rows: s.get_memtable_rows(),
+            disktables: s.get_disktables(),
+            disktable_rows: s.get_disktable_rows(),
+            disktable_bytes: s.get_disktable_bytes(),
+            commit_log_bytes: s.get_commit_log_bytes(),
+            uptime_seconds: s.get_uptime_seconds(),
+            minor_compactions: s.get_minor_compactions(),
+            major_compactions: s.get_major_compactions(),
+            merge_max_fan_in: s.get_merge_max_fan_in(),
+            merge_batched_rows: s.get_merge_batched_rows(),
+            write_stalls: s.get_write_stalls(),
+            write_stall_ns: s.get_write_stall_ns(),
+            last_flush_ns: s.get_last_flush_ns(),
+            free_bytes: s.get_free_bytes(),
+            out_of_space: s.get_out_of_space(),
+            permission_denied: s.get_permission_denied(),
+            row_cache_rows: s.get_row_cache_rows(),
+            row_cache_hits: s.get_row_cache_hits(),
+            row_cache_misses: s.get_row_cache_misses(),
+            result_cache_results: s.get_result_cache_results(),
+            result_cache_hits: s.get_result_cache_hits(),
+            result_cache_misses: s.get_result_cache_misses(),
+            queries: s.get_queries(),
+            query_dtables_probed: s.get_query_dtables_probed(),
+            query_bytes_read: s.get_query_bytes_read(),
+            query_rows_merged: s.get_query_rows_merged(),
+            query_execute_ns: s.get_query_execute_ns(),
+            prepared_handles: s.get_prepared_handles(),
+            prepared_hits: s.get_prepared_hits(),
+            prepared_misses: s.get_prepared_misses(),
+            degraded: s.get_degraded(),
+            compaction_frozen: s.get_compaction_frozen(),
+            dtables: s.get_dtables().iter().map(|d| DTableStats::from_generated(d)).collect()
+        }
+    }
+
+    pub fn into_generated(self) -> generated::query::Stats {
+        let mut s = generated::query::Stats::new();
+        s.set_memtable_size(self.memtable_size);
+        s.set_memtable_rows(self.memtable_rows);
+        s.set_disktables(self.disktables);
+        s.set_disktable_rows(self.disktable_rows);
+        s.set_disktable_bytes(self.disktable_bytes);
+        s.set_commit_log_bytes(self.commit_log_bytes);
+        s.set_uptime_seconds(self.uptime_seconds);
+        s.set_minor_compactions(self.minor_compactions);
+        s.set_major_compactions(self.major_compactions);
+        s.set_merge_max_fan_in(self.merge_max_fan_in);
+        s.set_merge_batched_rows(self.merge_batched_rows);
+        s.set_write_stalls(self.write_stalls);
+        s.set_write_stall_ns(self.write_stall_ns);
+        s.set_last_flush_ns(self.last_flush_ns);
+        s.set_free_bytes(self.free_bytes);
+        s.set_out_of_space(self.out_of_space);
+        s.set_permission_denied(self.permission_denied);
+        s.set_row_cache_rows(self.row_cache_rows);
+        s.set_row_cache_hits(self.row_cache_hits);
+        s.set_row_cache_misses(self.row_cache_misses);
+        s.set_result_cache_results(self.result_cache_results);
+        s.set_result_cache_hits(self.result_cache_hits);
+        s.set_result_cache_misses(self.result_cache_misses);
+        s.set_queries(self.queries);
+        s.set_query_dtables_probed(self.query_dtables_probed);
+        s.set_query_bytes_read(self.query_bytes_read);
+        s.set_query_rows_merged(self.query_rows_merged);
+        s.set_query_execute_ns(self.query_execute_ns);
+        s.set_prepared_handles(self.prepared_handles);
+        s.set_prepared_hits(self.prepared_hits);
+        s.set_prepared_misses(self.prepared_misses);
+        s.set_degraded(self.degraded);
+        s.set_compaction_frozen(self.compaction_frozen);
+        s.set_dtables(protobuf::RepeatedField::from_iter(
+            self.dtables.into_iter().map(|d| d.into_generated())
+        ));
+        s
+    }
+}
+
+impl DTableStats {
+    pub fn from_generated(d: &generated::query::DTableStats) -> DTableStats {
+        DTableStats{
+            filename: d.get_filename().to_owned(),
+            rows: d.get_rows(),
+            bytes: d.get_bytes(),
+            hits: d.get_hits(),
+            misses: d.get_misses(),
+            bytes_read: d.get_bytes_read(),
+            read_errors: d.get_read_errors(),
+            degraded: d.get_degraded()
+        }
+    }
+
+    pub fn into_generated(self) -> generated::query::DTableStats {
+        let mut d = generated::query::DTableStats::new();
+        d.set_filename(self.filename);
+        d.set_rows(self.rows);
+        d.set_bytes(self.bytes);
+        d.set_hits(self.hits);
+        d.set_misses(self.misses);
+        d.set_bytes_read(self.bytes_read);
+        d.set_read_errors(self.read_errors);
+        d.set_degraded(self.degraded);
+        d
+    }
+}
+
+impl fmt::Display for DTableStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{ filename: {}, rows: {}, bytes: {}, hits: {}, misses: {}, bytes_read: {}, read_errors: {}, degraded: {} }}",
+            self.filename,
+            self.rows,
+            self.bytes,
+            self.hits,
+            self.misses,
+            self.bytes_read,
+            self.read_errors,
+            self.degraded
+        )
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{ memtable_size: {}, memtable_rows: {}, disktables: {}, disktable_rows: {}, disktable_bytes: {}, commit_log_bytes: {}, uptime_seconds: {}, minor_compactions: {}, major_compactions: {}, merge_max_fan_in: {}, merge_batched_rows: {}, write_stalls: {}, write_stall_ns: {}, last_flush_ns: {}, free_bytes: {}, out_of_space: {}, permission_denied: {}, row_cache_rows: {}, row_cache_hits: {}, row_cache_misses: {}, result_cache_results: {}, result_cache_hits: {}, result_cache_misses: {}, queries: {}, query_dtables_probed: {}, query_bytes_read: {}, query_rows_merged: {}, query_execute_ns: {}, prepared_handles: {}, prepared_hits: {}, prepared_misses: {}, degraded: {}, compaction_frozen: {}, dtables: [{}] }}",
+            self.memtable_size,
+            self.memtable_rows,
+            self.disktables,
+            self.disktable_rows,
+            self.disktable_bytes,
+            self.commit_log_bytes,
+            self.uptime_seconds,
+            self.minor_compactions,
+            self.major_compactions,
+            self.merge_max_fan_in,
+            self.merge_batched_rows,
+            self.write_stalls,
+            self.write_stall_ns,
+            self.last_flush_ns,
+            self.free_bytes,
+            self.out_of_space,
+            self.permission_denied,
+            self.row_cache_rows,
+            self.row_cache_hits,
+            self.row_cache_misses,
+            self.result_cache_results,
+            self.result_cache_hits,
+            self.result_cache_misses,
+            self.queries,
+            self.query_dtables_probed,
+            self.query_bytes_read,
+            self.query_rows_merged,
+            self.query_execute_ns,
+            self.prepared_handles,
+            self.prepared_hits,
+            self.prepared_misses,
+            self.degraded,
+            self.compaction_frozen,
+            self.dtables.iter().map(|d| format!("{}", d)).collect::<Vec<_>>().join(", ")
+        )
+    }
+}
+
+fn result_column_from_generated(mut c: generated::query::ResultColumn) -> Option<Vec<u8>> {
+    match c.get_has_data() {
+        true  => Some(c.take_data()),
+        false => None
+    }
+}
+
+fn result_column_into_generated(c: Option<Vec<u8>>) -> generated::query::ResultColumn {
+    let mut x = generated::query::ResultColumn::new();
+    x.set_has_data(c.is_some());
+    if let Some(data) = c {
+        x.set_data(data);
+    }
+    x
+}
+
+impl QueryResult {
+    // Return the result as a JSON object.
+    pub fn as_json(&self) -> Result<String, QError> {
+        serde_json::to_string(self).map_err(|_| QError::ParseError)
+    }
+
+    pub fn from_generated(mut q: generated::query::QueryResult) -> QueryResult {
+        let field_type = q.get_field_type();
+        match field_type {
+            generated::query::QueryResultType::OK => QueryResult::Done,
+            generated::query::QueryResultType::ROW_NOT_FOUND => QueryResult::RowNotFound,
+            generated::query::QueryResultType::ROW_ALREADY_EXISTS => QueryResult::RowAlreadyExists,
+            generated::query::QueryResultType::PARTIAL_COMMIT =>
+                QueryResult::PartialCommit{ error: q.take_error() },
+            generated::query::QueryResultType::INTERNAL_ERROR =>
+                QueryResult::InternalError{ error: q.take_error() },
+            generated::query::QueryResultType::NOT_IMPLEMENTED => QueryResult::NotImplemented,
+            generated::query::QueryResultType::NETWORK_ERROR => QueryResult::NetworkError,
+            generated::query::QueryResultType::OUT_OF_SPACE => QueryResult::OutOfSpace,
+            generated::query::QueryResultType::SNAPSHOT_NOT_FOUND => QueryResult::SnapshotNotFound,
+            generated::query::QueryResultType::INVALID_KEY => QueryResult::InvalidKey,
+            generated::query::QueryResultType::BUSY => QueryResult::Busy,
+            generated::query::QueryResultType::NOT_ALLOWED => QueryResult::NotAllowed,
+            generated::query::QueryResultType::PERMISSION_DENIED => QueryResult::PermissionDenied,
+            generated::query::QueryResultType::INSUFFICIENT_REPLICAS => QueryResult::InsufficientReplicas,
+            generated::query::QueryResultType::REPLICA_BEHIND => QueryResult::ReplicaBehind,
+            generated::query::QueryResultType::NOT_LEADER => QueryResult::NotLeader,
+            generated::query::QueryResultType::RESOURCE_EXHAUSTED =>
+                QueryResult::ResourceExhausted{ reason: q.take_error() },
+            generated::query::QueryResultType::NOT_PREPARED => QueryResult::NotPrepared,
+            generated::query::QueryResultType::PREPARED =>
+                QueryResult::Prepared{ handle: q.get_handle() },
+            generated::query::QueryResultType::CHECKSUM_VALUE =>
+                QueryResult::Checksum{ checksum: q.get_checksum(), rows: q.get_checksum_rows() },
+            generated::query::QueryResultType::WRONG_SHARD =>
+                QueryResult::WrongShard{ version: q.get_shard_map_version() },
+            generated::query::QueryResultType::SCHEMA_VIOLATION =>
+                QueryResult::SchemaViolation{ reason: q.take_error() },
+            generated::query::QueryResultType::SNAPSHOT_CREATED =>
+                QueryResult::Snapshot{ id: q.get_snapshot() },
+            generated::query::QueryResultType::ENGINE_STATS =>
+                QueryResult::Stats{ stats: Stats::from_generated(q.get_stats()) },
+            generated::query::QueryResultType::KEYS =>
+                QueryResult::Keys{ keys: q.take_keys().into_vec() },
+            generated::query::QueryResultType::LIST =>
+                QueryResult::List{
+                    entries: q.take_entries().into_iter()
+                        .map(|mut e| ListEntry{
+                            timestamp: e.get_timestamp(),
+                            value: e.take_value()
+                        }).collect::<Vec<_>>()
+                },
+            generated::query::QueryResultType::ROWS =>
+                QueryResult::Rows{
+                    rows: q.take_rows().into_iter()
+                        .map(|mut r| ScanRow{
+                            key: r.take_key(),
+                            columns: r.take_names().into_iter()
+                                .zip(r.take_values().into_iter())
+                                .collect()
+                        }).collect::<Vec<_>>(),
+                    next: q.take_next()
+                },
+            generated::query::QueryResultType::DESCRIPTION =>
+                QueryResult::Description{
+                    columns: q.take_description().into_iter()
+                        .map(|mut c| ColumnDescription{
+                            name: c.take_name(),
+                            entries: c.get_entries(),
+                            first_timestamp: c.get_first_timestamp(),
+                            last_timestamp: c.get_last_timestamp(),
+                            bytes: c.get_bytes()
+                        }).collect::<Vec<_>>()
+                },
+            generated::query::QueryResultType::CHANGES =>
+                QueryResult::Changes{
+                    changes: q.take_changes().into_iter()
+                        .map(|mut c| ColumnChange{
+                            column: c.take_column(),
+                            before: result_column_from_generated(c.take_before()),
+                            after: result_column_from_generated(c.take_after())
+                        }).collect::<Vec<_>>()
+                },
+            generated::query::QueryResultType::HOT_KEYS =>
+                QueryResult::HotKeys{
+                    keys: q.take_hot_keys().into_iter()
+                        .map(|mut k| HotKey{
+                            key: k.take_key(),
+                            reads_per_second: k.get_reads_per_second(),
+                            writes_per_second: k.get_writes_per_second()
+                        }).collect::<Vec<_>>()
+                },
+            generated::query::QueryResultType::EXPLANATION => {
+                let mut e = q.take_explanation();
+                QueryResult::Explanation{
+                    explanation: Explanation{
+                        memtable: e.get_memtable(),
+                        dtables: e.take_dtables().into_iter()
+                            .map(|mut d| DTableRead{
+                                filename: d.take_filename(),
+                                rows: d.get_rows(),
+                                bytes: d.get_bytes()
+                            }).collect::<Vec<_>>(),
+                        skipped_dtables: e.get_skipped_dtables(),
+                        bytes: e.get_bytes()
+                    }
+                }
+            },
+            generated::query::QueryResultType::DATA =>
+                QueryResult::Data{
+                    columns: q.take_columns().into_iter()
+                        .map(result_column_from_generated)
+                        .collect::<Vec<_>>()
+                },
+        }
+    }
+
+    pub fn into_generated(self) -> generated::query::QueryResult {
+        let mut output = generated::query::QueryResult::new();
+        match self {
+            QueryResult::Done               => output.set_field_type(generated::query::QueryResultType::OK),
+            QueryResult::RowNotFound        => output.set_field_type(generated::query::QueryResultType::ROW_NOT_FOUND),
+            QueryResult::RowAlreadyExists   => output.set_field_type(generated::query::QueryResultType::ROW_ALREADY_EXISTS),
+            QueryResult::PartialCommit{error: e} => {
+                output.set_error(e);
+                output.set_field_type(generated::query::QueryResultType::PARTIAL_COMMIT);
+            },
+            QueryResult::NotImplemented     => output.set_field_type(generated::query::QueryResultType::NOT_IMPLEMENTED),
+            QueryResult::NetworkError       => output.set_field_type(generated::query::QueryResultType::NETWORK_ERROR),
+            QueryResult::InternalError{error: e} => {
+                output.set_error(e);
+                output.set_field_type(generated::query::QueryResultType::INTERNAL_ERROR);
+            },
+            QueryResult::OutOfSpace         => output.set_field_type(generated::query::QueryResultType::OUT_OF_SPACE),
+            QueryResult::SnapshotNotFound   => output.set_field_type(generated::query::QueryResultType::SNAPSHOT_NOT_FOUND),
+            QueryResult::InvalidKey         => output.set_field_type(generated::query::QueryResultType::INVALID_KEY),
+            QueryResult::Busy               => output.set_field_type(generated::query::QueryResultType::BUSY),
+            QueryResult::NotAllowed         => output.set_field_type(generated::query::QueryResultType::NOT_ALLOWED),
+            QueryResult::PermissionDenied   => output.set_field_type(generated::query::QueryResultType::PERMISSION_DENIED),
+            QueryResult::InsufficientReplicas => output.set_field_type(generated::query::QueryResultType::INSUFFICIENT_REPLICAS),
+            QueryResult::ReplicaBehind      => output.set_field_type(generated::query::QueryResultType::REPLICA_BEHIND),
+            QueryResult::NotLeader          => output.set_field_type(generated::query::QueryResultType::NOT_LEADER),
+            QueryResult::ResourceExhausted{reason: r} => {
+                output.set_error(r);
+                output.set_field_type(generated::query::QueryResultType::RESOURCE_EXHAUSTED);
+            },
+            QueryResult::NotPrepared        => output.set_field_type(generated::query::QueryResultType::NOT_PREPARED),
+            QueryResult::Prepared{handle: h} => {
+                output.set_handle(h);
+                output.set_field_type(generated::query::QueryResultType::PREPARED);
+            },
+            QueryResult::Checksum{checksum: c, rows: r} => {
+                output.set_checksum(c);
+                output.set_checksum_rows(r);
+                output.set_field_type(generated::query::QueryResultType::CHECKSUM_VALUE);
+            },
+            QueryResult::WrongShard{version: v} => {
+                output.set_shard_map_version(v);
+                output.set_field_type(generated::query::QueryResultType::WRONG_SHARD);
+            },
+            QueryResult::Snapshot{id: i}    => {
+                output.set_snapshot(i);
+                output.set_field_type(generated::query::QueryResultType::SNAPSHOT_CREATED);
+            },
+            QueryResult::Data{columns: c}   => {
+                output.set_columns(protobuf::RepeatedField::from_iter(
+                    c.into_iter().map(result_column_into_generated)
+                ));
+                output.set_field_type(generated::query::QueryResultType::DATA);
+            },
+            QueryResult::Stats{stats: s}    => {
+                output.set_stats(s.into_generated());
+                output.set_field_type(generated::query::QueryResultType::ENGINE_STATS);
+            },
+            QueryResult::Keys{keys: k}      => {
+                output.set_keys(protobuf::RepeatedField::from_vec(k));
+                output.set_field_type(generated::query::QueryResultType::KEYS);
+            },
+            QueryResult::SchemaViolation{reason: r} => {
+                output.set_error(r);
+                output.set_field_type(generated::query::QueryResultType::SCHEMA_VIOLATION);
+            },
+            QueryResult::List{entries: e}   => {
+                output.set_entries(protobuf::RepeatedField::from_iter(
+                    e.into_iter()
+                        .map(|e| {
+                            let mut x = generated::query::ListEntry::new();
+                            x.set_timestamp(e.timestamp);
+                            x.set_value(e.value);
+                            x
+                        }
+                )));
+                output.set_field_type(generated::query::QueryResultType::LIST);
+            },
+            QueryResult::Rows{rows: r, next: n} => {
+                output.set_rows(protobuf::RepeatedField::from_iter(
+                    r.into_iter()
+                        .map(|r| {
+                            let mut x = generated::query::ScanRow::new();
+                            let (names, values): (Vec<_>, Vec<_>) = r.columns.into_iter().unzip();
+                            x.set_key(r.key);
+                            x.set_names(protobuf::RepeatedField::from_vec(names));
+                            x.set_values(protobuf::RepeatedField::from_vec(values));
+                            x
+                        }
+                )));
+                output.set_next(n);
+                output.set_field_type(generated::query::QueryResultType::ROWS);
+            },
+            QueryResult::Description{columns: c} => {
+                output.set_description(protobuf::RepeatedField::from_iter(
+                    c.into_iter()
+                        .map(|c| {
+                            let mut x = generated::query::ColumnDescription::new();
+                            x.set_name(c.name);
+                            x.set_entries(c.entries);
+                            x.set_first_timestamp(c.first_timestamp);
+                            x.set_last_timestamp(c.last_timestamp);
+                            x.set_bytes(c.bytes);
+                            x
+                        }
+                )));
+                output.set_field_type(generated::query::QueryResultType::DESCRIPTION);
+            },
+            QueryResult::Changes{changes: c} => {
+                output.set_changes(protobuf::RepeatedField::from_iter(
+                    c.into_iter()
+                        .map(|c| {
+                            let mut x = generated::query::ColumnChange::new();
+                            x.set_column(c.column);
+                            x.set_before(result_column_into_generated(c.before));
+                            x.set_after(result_column_into_generated(c.after));
+                            x
+                        }
+                )));
+                output.set_field_type(generated::query::QueryResultType::CHANGES);
+            },
+            QueryResult::HotKeys{keys: k} => {
+                output.set_hot_keys(protobuf::RepeatedField::from_iter(
+                    k.into_iter()
+                        .map(|k| {
+                            let mut x = generated::query::HotKey::new();
+                            x.set_key(k.key);
+                            x.set_reads_per_second(k.reads_per_second);
+                            x.set_writes_per_second(k.writes_per_second);
+                            x
+                        }
+                )));
+                output.set_field_type(generated::query::QueryResultType::HOT_KEYS);
+            },
+            QueryResult::Explanation{explanation: e} => {
+                let mut x = generated::query::Explanation::new();
+                x.set_memtable(e.memtable);
+                x.set_dtables(protobuf::RepeatedField::from_iter(
+                    e.dtables.into_iter()
+                        .map(|d| {
+                            let mut r = generated::query::DTableRead::new();
+                            r.set_filename(d.filename);
+                            r.set_rows(d.rows);
+                            r.set_bytes(d.bytes);
+                            r
+                        }
+                )));
+                x.set_skipped_dtables(e.skipped_dtables);
+                x.set_bytes(e.bytes);
+                output.set_explanation(x);
+                output.set_field_type(generated::query::QueryResultType::EXPLANATION);
+            }
+        }
+        output
+    }
+}
+
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryResult::Done             => write!(f, "OK."),
+            QueryResult::RowNotFound      => write!(f, "Row not found."),
+            QueryResult::RowAlreadyExists => write!(f, "Row already exists."),
+            QueryResult::InternalError{error: ref e} if e.is_empty() => write!(f, "Internal error."),
+            QueryResult::InternalError{error: ref e} => write!(f, "Internal error: {}", e),
+            QueryResult::NotImplemented   => write!(f, "Not implemented."),
+            QueryResult::NetworkError     => write!(f, "Network error."),
+            QueryResult::PartialCommit{error: ref e} if e.is_empty() => write!(f, "Partial commit (!)"),
+            QueryResult::PartialCommit{error: ref e} => write!(f, "Partial commit (!): {}", e),
+            QueryResult::OutOfSpace       => write!(f, "Out of disk space."),
+            QueryResult::SnapshotNotFound => write!(f, "Snapshot not found."),
+            QueryResult::InvalidKey       => write!(f, "Invalid row key."),
+            QueryResult::Busy             => write!(f, "Server busy."),
+            QueryResult::NotAllowed       => write!(f, "Query not allowed here."),
+            QueryResult::PermissionDenied => write!(f, "Permission denied."),
+            QueryResult::InsufficientReplicas => write!(f, "Not enough replicas to acknowledge the write."),
+            QueryResult::ReplicaBehind    => write!(f, "Replica hasn't caught up to the read timestamp yet."),
+            QueryResult::NotLeader        => write!(f, "Writes are only accepted by the leader."),
+            QueryResult::WrongShard{version: v} => write!(f, "Row belongs to another shard (shard map version {}).", v),
+            QueryResult::ResourceExhausted{reason: ref r} => write!(f, "Resource exhausted: {}", r),
+            QueryResult::SchemaViolation{reason: ref r} => write!(f, "Schema violation: {}", r),
+            QueryResult::NotPrepared      => write!(f, "Prepared query not found."),
+            QueryResult::Snapshot{id: i}  => write!(f, "Snapshot: {}", i),
+            QueryResult::Prepared{handle: h} => write!(f, "Prepared: {}", h),
+            QueryResult::Checksum{checksum: c, rows: r} => write!(f, "Checksum: {:016x} ({} rows)", c, r),
+            QueryResult::Data{columns: ref c} => {
+                write!(f, "Data: [{}]", c.iter().map(|s| match *s {
+                    Some(ref x) => {
+                        format!(
+                            "\"{}\"",
+                            String::from_utf8(x.clone())
+                            .unwrap_or(String::from("Err"))
+                        )
+                    },
+                    None        => String::from("None")
+                }).collect::<Vec<_>>().join(", "))
+            },
+            QueryResult::Stats{stats: ref s} => write!(f, "Stats: {}", s),
+            QueryResult::Keys{keys: ref k} => {
+                write!(f, "Keys: [{}]", k.iter()
+                    .map(|s| format!("\"{}\"", s))
+                    .collect::<Vec<_>>()
+                    .join(", "))
+            },
+            QueryResult::List{entries: ref e} => {
+                write!(f, "List: [{}]", e.iter()
+                    .map(|x| format!(
+                        "{}: \"{}\"",
+                        x.timestamp,
+                        String::from_utf8(x.value.clone()).unwrap_or(String::from("Err"))
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "))
+            },
+            QueryResult::Rows{rows: ref r, next: ref n} => {
+                write!(f, "Rows: [{}], next: \"{}\"", r.iter()
+                    .map(|row| format!(
+                        "{}: {{{}}}",
+                        row.key,
+                        row.columns.iter()
+                            .map(|&(ref k, ref v)| format!(
+                                "{}: \"{}\"",
+                                k,
+                                String::from_utf8(v.clone()).unwrap_or(String::from("Err"))
+                            ))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "), n)
+            },
+            QueryResult::Description{columns: ref c} => {
+                write!(f, "Description: [{}]", c.iter()
+                    .map(|x| format!(
+                        "{}: {{entries: {}, first_timestamp: {}, last_timestamp: {}, bytes: {}}}",
+                        x.name,
+                        x.entries,
+                        x.first_timestamp,
+                        x.last_timestamp,
+                        x.bytes
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "))
+            },
+            QueryResult::Changes{changes: ref c} => {
+                let value = |v: &Option<Vec<u8>>| match *v {
+                    Some(ref x) => format!("\"{}\"", String::from_utf8(x.clone()).unwrap_or(String::from("Err"))),
+                    None        => String::from("None")
+                };
+                write!(f, "Changes: [{}]", c.iter()
+                    .map(|x| format!("{}: {} -> {}", x.column, value(&x.before), value(&x.after)))
+                    .collect::<Vec<_>>()
+                    .join(", "))
+            },
+            QueryResult::HotKeys{keys: ref k} => {
+                write!(f, "Hot keys: [{}]", k.iter()
+                    .map(|x| format!(
+                        "{}: {{reads_per_second: {:.1}, writes_per_second: {:.1}}}",
+                        x.key,
+                        x.reads_per_second,
+                        x.writes_per_second
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "))
+            },
+            QueryResult::Explanation{explanation: ref e} => {
+                write!(f, "Explanation: memtable: {}, dtables: [{}], skipped_dtables: {}, bytes: {}", e.memtable, e.dtables.iter()
+                    .map(|d| format!("{}: {{rows: {}, bytes: {}}}", d.filename, d.rows, d.bytes))
+                    .collect::<Vec<_>>()
+                    .join(", "), e.skipped_dtables, e.bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap as Map;
+    use std::iter::FromIterator;
+    use protobuf;
+    use protobuf::Message;
+    use generated;
+
+    use test;
+
+    #[test]
+    fn can_print_select() {
+        let q = super::Query::new_select(
+            "row1",
+            &["test", "column2", "col3"]
+        );
+
+        assert_eq!(
+            format!("{}", q),
+            r#"{"select":{"row":"row1","get":["test","column2","col3"]}}"#
+        )
+    }
+
+    // This function takes a query, converts it back and forth to a
+    // protobuf-compatible query, and checks that it is still the same.
+    fn query_conversion_is_valid(q: super::Query) {
+        let repr = format!("{}", q);
+        let mut bytes = vec![];
+        q.write_to_writer(&mut bytes).unwrap();
+        let recovered = super::Query::from_bytes(&mut bytes.as_slice()).unwrap();
+        assert_eq!(
+            repr,
+            format!("{}", recovered)
+        );
+        let mut bytes2 = vec![];
+        recovered.write_to_writer(&mut bytes2).unwrap();
+        assert_eq!(bytes, bytes2);
+    }
+
+    fn queryresult_conversion_is_valid(q: super::QueryResult) {
+        let repr = format!("{}", q);
+        let mut bytes = vec![];
+        q.into_generated().write_to_writer(&mut bytes).unwrap();
+        let converted_generated = protobuf::parse_from_bytes::<generated::query::QueryResult>(&mut bytes.as_slice()).unwrap();
+        let recovered = super::QueryResult::from_generated(converted_generated);
+        assert_eq!(
+            repr,
+            format!("{}", recovered)
+        );
+        let mut bytes2 = vec![];
+        recovered.into_generated().write_to_writer(&mut bytes2).unwrap();
+        assert_eq!(bytes, bytes2);
+    }
+
+    #[test]
+    fn can_convert_queryresult_to_json() {
+        assert_eq!(super::QueryResult::Done.as_json().unwrap(), r#""Done""#);
+        assert_eq!(
+            super::QueryResult::Snapshot{id: 42}.as_json().unwrap(),
+            r#"{"Snapshot":{"id":42}}"#
+        );
+        assert_eq!(
+            super::QueryResult::Keys{keys: vec![String::from("row1")]}.as_json().unwrap(),
+            r#"{"Keys":{"keys":["row1"]}}"#
+        );
+    }
+
+    #[test]
+    fn can_convert_queryresult_to_bytes() {
+        queryresult_conversion_is_valid(super::QueryResult::Done);
+        queryresult_conversion_is_valid(super::QueryResult::RowNotFound);
+        queryresult_conversion_is_valid(super::QueryResult::RowAlreadyExists);
+        queryresult_conversion_is_valid(super::QueryResult::NetworkError);
+        queryresult_conversion_is_valid(super::QueryResult::InternalError{ error: String::from("disk failure") });
+        queryresult_conversion_is_valid(super::QueryResult::NotImplemented);
+        queryresult_conversion_is_valid(super::QueryResult::PartialCommit{ error: String::new() });
+        queryresult_conversion_is_valid(super::QueryResult::OutOfSpace);
+        queryresult_conversion_is_valid(super::QueryResult::SnapshotNotFound);
+        queryresult_conversion_is_valid(super::QueryResult::InvalidKey);
+        queryresult_conversion_is_valid(super::QueryResult::Busy);
+        queryresult_conversion_is_valid(super::QueryResult::NotAllowed);
+        queryresult_conversion_is_valid(super::QueryResult::PermissionDenied);
+        queryresult_conversion_is_valid(super::QueryResult::InsufficientReplicas);
+        queryresult_conversion_is_valid(super::QueryResult::ReplicaBehind);
+        queryresult_conversion_is_valid(super::QueryResult::NotLeader);
+        queryresult_conversion_is_valid(super::QueryResult::WrongShard{version: 3});
+        queryresult_conversion_is_valid(super::QueryResult::ResourceExhausted{reason: String::from("query is too big")});
+        queryresult_conversion_is_valid(super::QueryResult::SchemaViolation{reason: String::from("column \"x\" isn't declared")});
+        queryresult_conversion_is_valid(super::QueryResult::Snapshot{id: 42});
+        queryresult_conversion_is_valid(super::QueryResult::Prepared{handle: 7});
+        queryresult_conversion_is_valid(super::QueryResult::NotPrepared);
+        queryresult_conversion_is_valid(super::QueryResult::Checksum{checksum: 0xfeedface, rows: 3});
+        queryresult_conversion_is_valid(super::QueryResult::Data{columns: vec![Some(String::from("this is a test").into_bytes())]});
+        queryresult_conversion_is_valid(super::QueryResult::Data{columns: vec![None]});
+        queryresult_conversion_is_valid(super::QueryResult::Stats{stats: super::Stats{
+            memtable_size: 1028,
+            disktables: 2,
+            minor_compactions: 3,
+            permission_denied: 5,
+            row_cache_hits: 6,
+            result_cache_misses: 7,
+            degraded: true,
+            compaction_frozen: true,
+            dtables: vec![super::DTableStats{
+                filename: String::from("./data/1.dtable"),
+                rows: 10,
+                hits: 4,
+                misses: 2,
+                read_errors: 3,
+                degraded: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }});
+        queryresult_conversion_is_valid(super::QueryResult::Keys{keys: vec![String::from("row1"), String::from("row2")]});
+        queryresult_conversion_is_valid(super::QueryResult::Keys{keys: vec![]});
+        queryresult_conversion_is_valid(super::QueryResult::List{entries: vec![
+            super::ListEntry{timestamp: 10, value: String::from("first").into_bytes()},
+            super::ListEntry{timestamp: 20, value: String::from("second").into_bytes()}
+        ]});
+        queryresult_conversion_is_valid(super::QueryResult::Rows{
+            rows: vec![super::ScanRow{
+                key: String::from("row1"),
+                columns: vec![
+                    (String::from("name"), String::from("alice").into_bytes()),
+                    (String::from("email"), String::from("alice@example.com").into_bytes())
+                ]
+            }],
+            next: String::from("row2")
+        });
+        queryresult_conversion_is_valid(super::QueryResult::Rows{rows: vec![], next: String::new()});
+        queryresult_conversion_is_valid(super::QueryResult::Description{columns: vec![
+            super::ColumnDescription{
+                name: String::from("status"),
+                entries: 3,
+                first_timestamp: 10,
+                last_timestamp: 30,
+                bytes: 12
+            }
+        ]});
+        queryresult_conversion_is_valid(super::QueryResult::Changes{changes: vec![
+            super::ColumnChange{
+                column: String::from("status"),
+                before: Some(String::from("ok").into_bytes()),
+                after: None
+            },
+            super::ColumnChange{
+                column: String::from("name"),
+                before: None,
+                after: Some(String::from("alice").into_bytes())
+            }
+        ]});
+        queryresult_conversion_is_valid(super::QueryResult::Changes{changes: vec![]});
+        queryresult_conversion_is_valid(super::QueryResult::HotKeys{keys: vec![
+            super::HotKey{
+                key: String::from("row1"),
+                reads_per_second: 1200.5,
+                writes_per_second: 3.0
+            }
+        ]});
+        queryresult_conversion_is_valid(super::QueryResult::Explanation{explanation: super::Explanation{
+            memtable: true,
+            dtables: vec![super::DTableRead{filename: String::from("/data/2.dtable"), rows: 1, bytes: 120}],
+            skipped_dtables: 3,
+            bytes: 120
+        }});
+        queryresult_conversion_is_valid(super::QueryResult::Explanation{explanation: Default::default()});
+    }
+
+    // Clients in other languages are generated from query.proto, so the
+    // encoding of queries and results has to stay the same.
+    #[test]
+    fn wire_format_is_stable() {
+        let mut bytes = vec![];
+        super::Query::new_select("r", &["c"]).write_to_writer(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![0x12, 1, b'r', 0x1a, 1, b'c']);
+
+        let mut bytes = vec![];
+        super::Query::Stats.write_to_writer(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![0x08, 3]);
+
+        let bytes = super::QueryResult::Keys{keys: vec![String::from("a")]}
+            .into_generated()
+            .write_to_bytes()
+            .unwrap();
+        assert_eq!(bytes, vec![0x08, 9, 0x22, 1, b'a']);
+
+        let recovered = super::Query::from_bytes(&mut &[0x08, 4, 0x12, 1, b'k', 0x28, 10][..]).unwrap();
+        assert_eq!(format!("{}", recovered), r#"{"list_keys":{"start":"k","limit":10}}"#);
+    }
+
+    #[test]
+    fn can_convert_query_to_bytes() {
+        query_conversion_is_valid(super::Query::Insert{row: String::from("test"), set: Map::new()});
+
+        let data = vec![
+            ("c@#$%^&*()".to_string(),  String::from("caDS{").into_bytes())
+        ];
+        let set = Map::<String, Vec<u8>>::from_iter(data);
+        query_conversion_is_valid(super::Query::Insert{row: String::from("QW_#F)A"), set: set.clone()});
+        query_conversion_is_valid(super::Query::Update{row: String::from("!@)#!!D"), set: set.clone()});
+        query_conversion_is_valid(super::Query::new_select("!@)#!!D", &["abcdef"]));
+        query_conversion_is_valid(super::Query::Select{
+            row: String::from("row"),
+            get: vec![],
+            snapshot: 7,
+            include_deleted: true,
+            project: vec![],
+            strict: true,
+            column_start: String::from("col5"),
+            column_limit: 100
+        });
+        query_conversion_is_valid(super::Query::Select{
+            row: String::from("row"),
+            get: vec![String::from("name")],
+            snapshot: 0,
+            include_deleted: false,
+            project: vec![
+                super::Projection::Length(String::from("body")),
+                super::Projection::Hash(String::from("body")),
+                super::Projection::Substring{column: String::from("body"), start: 4, length: 10}
+            ],
+            strict: false,
+            column_start: String::new(),
+            column_limit: 0
+        });
+        query_conversion_is_valid(super::Query::Stats);
+        query_conversion_is_valid(super::Query::ListKeys{start: String::from("row"), limit: 25});
+        query_conversion_is_valid(super::Query::DeleteRange{start_row: String::from("a"), end_row: String::from("b")});
+        query_conversion_is_valid(super::Query::CreateSnapshot);
+        query_conversion_is_valid(super::Query::Append{row: String::from("row"), set: set.clone()});
+        query_conversion_is_valid(super::Query::SelectList{
+            row: String::from("row"),
+            column: String::from("events"),
+            limit: 5,
+            start: 100,
+            end: 0
+        });
+        query_conversion_is_valid(super::Query::Scan{
+            start: String::from("a"),
+            end: String::from("m"),
+            get: vec![String::from("name")],
+            limit: 50,
+            snapshot: 3
+        });
+        query_conversion_is_valid(super::Query::Describe{row: String::from("row")});
+        query_conversion_is_valid(super::Query::TopKeys{limit: 5});
+        query_conversion_is_valid(super::Query::SoftDelete{row: String::from("row")});
+        query_conversion_is_valid(super::Query::Undelete{row: String::from("row")});
+        query_conversion_is_valid(super::Query::History{
+            row: String::from("row"),
+            column: String::from("name"),
+            limit: 3
+        });
+        query_conversion_is_valid(super::Query::Transaction{
+            updates: Map::from_iter(vec![(String::from("row"), set.clone())])
+        });
+        query_conversion_is_valid(super::Query::Prepare{
+            get: vec![String::from("name"), String::from("email")]
+        });
+        query_conversion_is_valid(super::Query::new_execute(7, "row"));
+        query_conversion_is_valid(super::Query::Checksum{row: String::from("row")});
+        query_conversion_is_valid(super::Query::ChecksumRange{
+            start: String::from("a"),
+            end: String::from("m")
+        });
+        query_conversion_is_valid(super::Query::Diff{
+            row: String::from("row"),
+            from_ts: 100,
+            to_ts: 200
+        });
+        query_conversion_is_valid(super::Query::FreezeCompaction);
+        query_conversion_is_valid(super::Query::UnfreezeCompaction);
+        query_conversion_is_valid(super::Query::Explain(Box::new(super::Query::new_select("row", &["name"]))));
+        query_conversion_is_valid(super::Query::parse(
+            r#"{"explain": {"scan": {"start": "a", "end": "m", "limit": 10}}}"#
+        ).unwrap());
+    }
+
+    #[test]
+    fn can_classify_writes() {
+        assert!(super::Query::parse(r#"{"insert": { "row": "row1", "set": {} }}"#).unwrap().is_write());
+        assert!(super::Query::parse(r#"{"delete_range": { "start_row": "a", "end_row": "b" }}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"select": { "row": "row1", "get": [] }}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"stats": {}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"describe": {"row": "row1"}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"top_keys": {}}"#).unwrap().is_write());
+        assert!(super::Query::parse(r#"{"soft_delete": {"row": "row1"}}"#).unwrap().is_write());
+        assert!(super::Query::parse(r#"{"undelete": {"row": "row1"}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"history": {"row": "row1", "column": "name"}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"execute": {"handle": 7, "row": "row1"}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"checksum": {"row": "row1"}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"checksum_range": {"start": "a", "end": "b"}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"diff": {"row": "row1", "from_ts": 100}}"#).unwrap().is_write());
+        assert!(!super::Query::parse(r#"{"explain": {"select": {"row": "row1", "get": []}}}"#).unwrap().is_write());
+        assert!(super::Query::parse(r#"{"transaction": {"updates": {"a": {"x": "1"}, "b": {"y": "2"}}}}"#).unwrap().is_write());
+
+        // Freezing compaction is saved in the manifest, so it's a write.
+        assert!(super::Query::FreezeCompaction.is_write());
+        assert!(super::Query::UnfreezeCompaction.is_write());
+    }
+
+    #[test]
+    fn can_parse_debug_requests() {
+        let (q, debug) = super::Query::parse_request(r#"{"select": {"row": "row1", "get": ["a"]}, "debug": true}"#).unwrap();
+        assert_eq!(format!("{}", q), r#"{"select":{"row":"row1","get":["a"]}}"#);
+        assert!(debug);
+        assert!(!super::Query::parse_request(r#"{"stats": {}}"#).unwrap().1);
+        assert!(super::Query::parse_request(r#"{"stats": {}, "debug": "yes"}"#).is_err());
+        assert!(super::Query::parse_request(r#"{"debug": true}"#).is_err());
+    }
+
+    #[test]
+    fn can_evaluate_projections() {
+        let body = b"hello, world";
+        assert_eq!(super::Projection::Length(String::from("body")).evaluate(body), b"12".to_vec());
+        assert_eq!(super::Projection::Hash(String::from("body")).evaluate(b""), b"cbf29ce484222325".to_vec());
+        assert_eq!(
+            super::Projection::Substring{column: String::from("body"), start: 0, length: 5}.evaluate(body),
+            b"hello".to_vec()
+        );
+        assert_eq!(
+            super::Projection::Substring{column: String::from("body"), start: 50, length: 5}.evaluate(body),
+            b"".to_vec()
+        );
+        assert_eq!(
+            super::Projection::Substring{column: String::from("body"), start: 7, length: u64::max_value()}.evaluate(body),
+            b"world".to_vec()
+        );
+    }
+
+    #[test]
+    fn can_check_ack_levels() {
+        for level in &[super::AckLevel::Leader, super::AckLevel::LeaderPlusOne, super::AckLevel::All] {
+            assert_eq!(super::AckLevel::parse(level.as_str()), Some(*level));
+        }
+        assert_eq!(super::AckLevel::parse("quorum"), None);
+
+        assert!(super::AckLevel::Leader.is_met(0, 2));
+        assert!(!super::AckLevel::LeaderPlusOne.is_met(0, 2));
+        assert!(super::AckLevel::LeaderPlusOne.is_met(1, 2));
+        assert!(!super::AckLevel::All.is_met(1, 2));
+        assert!(super::AckLevel::All.is_met(2, 2));
+        assert!(!super::AckLevel::All.is_met(0, 0));
+    }
+
+    #[test]
+    fn can_display_queryresults() {
+        assert_eq!(
+            format!("{}", super::QueryResult::NotImplemented),
+            "Not implemented."
+        );
+
+        assert_eq!(
+            format!("{}", super::QueryResult::RowAlreadyExists),
+            "Row already exists."
+        );
+
+        assert_eq!(
+            format!("{}", super::QueryResult::InternalError{ error: String::new() }),
+            "Internal error."
+        );
+
+        assert_eq!(
+            format!("{}", super::QueryResult::InternalError{ error: String::from("disk failure") }),
+            "Internal error: disk failure"
+        );
+
+        assert_eq!(
+            format!("{}", super::QueryResult::PartialCommit{ error: String::new() }),
+            "Partial commit (!)"
+        );
+
+        assert_eq!(
+            format!("{}", super::QueryResult::OutOfSpace),
+            "Out of disk space."
+        );
+    }
+
+    #[test]
+    fn can_print_update() {
+        let q = super::Query::new_update(
+            "row1",
+            vec![super::MUpdate::new("test", vec![120, 121])]
+        );
+
+        assert_eq!(
+            format!("{}", q),
+            r#"{"update":{"row":"row1","set":{"test":"xy"}}}"#
+        );
+    }
+
+    #[test]
+    fn can_print_insert() {
+        let q = super::Query::new_insert(
+            "row1",
+            vec![super::MUpdate::new("test", vec![120, 121])]
+        );
+
+        assert_eq!(
+            format!("{}", q),
+            r#"{"insert":{"row":"row1","set":{"test":"xy"}}}"#
+        );
+    }
+
+    #[test]
+    fn can_parse_queries() {
+        super::Query::parse(r#"{"select": { "row": "test 1 2 3", "get": [] }}"#).unwrap();
+        super::Query::parse(r#"{"select": { "row": "row1", "get": [ "col5" ] }}"#).unwrap();
+        super::Query::parse(r#"{"update": { "row": "row1", "set": {} }}"#).unwrap();
+        super::Query::parse(r#"{"update": { "row": "row1", "set": { "col5": "value" } }}"#).unwrap();
+        super::Query::parse(r#"{"insert": { "row": "row1", "set": { "col5": "value", "col7": "value" } }}"#).unwrap();
+        super::Query::parse(r#"{"stats": {}}"#).unwrap();
+        super::Query::parse(r#"{"list_keys": {}}"#).unwrap();
+        super::Query::parse(r#"{"list_keys": { "start": "row1", "limit": 10 }}"#).unwrap();
+        super::Query::parse(r#"{"delete_range": { "start_row": "row1", "end_row": "row5" }}"#).unwrap();
+        super::Query::parse(r#"{"create_snapshot": {}}"#).unwrap();
+        super::Query::parse(r#"{"freeze_compaction": {}}"#).unwrap();
+        super::Query::parse(r#"{"select": { "row": "row1", "get": [ "col5" ], "snapshot": 3 }}"#).unwrap();
+        super::Query::parse(r#"{"select": { "row": "row1", "get": [], "column_start": "col5", "column_limit": 100 }}"#).unwrap();
+        super::Query::parse(r#"{"append": { "row": "row1", "set": { "events": "login" } }}"#).unwrap();
+        super::Query::parse(r#"{"select_list": { "row": "row1", "column": "events", "limit": 10 }}"#).unwrap();
+        super::Query::parse(r#"{"select": { "row": "row1", "get": [], "project": [ {"hash": "col5"} ] }}"#).unwrap();
+    }
+
+    #[bench]
+    fn query_parsing(b: &mut test::Bencher) {
+        b.iter(|| {
+            super::Query::parse(r#"{"select": { "row": "test 1 2 3", "get": [] }}"#).unwrap();
+            super::Query::parse(r#"{"select": { "row": "row1", "get": [ "col5" ] }}"#).unwrap();
+            super::Query::parse(r#"{"update": { "row": "row1", "set": {} }}"#).unwrap();
+            super::Query::parse(r#"{"update": { "row": "row1", "set": { "col5": "value" } }}"#).unwrap();
+            super::Query::parse(r#"{"insert": { "row": "row1", "set": { "col5": "value", "col7": "value" } }}"#).unwrap();
+        })
+    }
+}
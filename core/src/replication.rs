@@ -0,0 +1,257 @@
+/*
+    replication.rs
+
+    Bootstrapping a replica from its primary. The replica asks for a
+    snapshot, which is streamed to it as a series of files: the live
+    dtables, with their headers, and the commit log, which rebuilds the
+    memtable when it's replayed. The snapshot ends with the position in
+    the primary's commit log that it was taken at, and from then on the
+    replica tails the commit log from that position.
+
+    A position is the primary's generation, which changes every time the
+    commit log is truncated, and the offset into the commit log. Once the
+    primary has moved on to a newer generation, the rest of the old
+    commit log can only be read from the commit log archive, if there is
+    one (see logarchive.rs). Otherwise the replica has fallen too far
+    behind, and needs a new snapshot.
+
+    Each file in the stream is written as the length of its name (4 bytes),
+    the name, the length of its contents (8 bytes), and then the contents.
+    The last one has an empty name, and the position as its contents.
+*/
+
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use serde_json;
+
+use storage;
+
+// The largest file name accepted in a snapshot.
+const MAX_NAME_LENGTH: u32 = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub generation: u64,
+    pub offset: u64
+}
+
+impl Position {
+    // Whether a replica which has read up to this position has every
+    // entry before the other one. A later generation comes after the
+    // whole of an earlier one's commit log.
+    pub fn reaches(&self, other: &Position) -> bool {
+        (self.generation, self.offset) >= (other.generation, other.offset)
+    }
+
+    // Parse a position written as generation:offset, the same as it's
+    // displayed.
+    pub fn parse(s: &str) -> Option<Position> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().map(|g| g.parse()), parts.next().map(|o| o.parse())) {
+            (Some(Ok(g)), Some(Ok(o)))  => Some(Position{generation: g, offset: o}),
+            _                           => None
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.generation, self.offset)
+    }
+}
+
+// A snapshot whose files were opened while the database was locked, so
+// that it can be sent after it's been unlocked. A dtable is never written
+// to once it's live, and one which is compacted away in the meantime can
+// still be read from the open file. The commit log is truncated in place
+// when the memtable is flushed, so it's copied instead.
+pub struct Snapshot {
+    pub position: Position,
+    pub files: Vec<(String, u64, Box<storage::StorageFile>)>,
+    pub commit_log: (String, Vec<u8>)
+}
+
+impl Snapshot {
+    pub fn write_to<W: Write>(mut self, out: &mut W) -> io::Result<Position> {
+        for &mut (ref name, size, ref mut f) in self.files.iter_mut() {
+            write_file(out, name, size, f)?;
+        }
+        let (ref name, ref data) = self.commit_log;
+        write_file(out, name, data.len() as u64, &mut &data[..])?;
+        write_end(out, &self.position)?;
+        Ok(self.position)
+    }
+}
+
+pub fn write_file<R: Read, W: Write>(out: &mut W, name: &str, size: u64, contents: &mut R) -> io::Result<()> {
+    out.write_u32::<LittleEndian>(name.len() as u32)?;
+    out.write_all(name.as_bytes())?;
+    out.write_u64::<LittleEndian>(size)?;
+    let copied = io::copy(&mut contents.by_ref().take(size), out)?;
+    if copied != size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} was cut short", name)));
+    }
+    Ok(())
+}
+
+pub fn write_end<W: Write>(out: &mut W, position: &Position) -> io::Result<()> {
+    let encoded = serde_json::to_vec(position)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to encode position"))?;
+    write_file(out, "", encoded.len() as u64, &mut &encoded[..])?;
+    out.flush()
+}
+
+// Files are only ever written straight into the directory, so names with
+// a path in them are refused.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.contains('\\') && name != "." && name != ".."
+}
+
+// Write the files in the snapshot into the directory, and return the
+// position that it was taken at. Fails if the stream ends early, which
+// leaves the files written so far behind.
+pub fn install_snapshot<R: Read>(input: &mut R, storage: &Arc<storage::Storage>, directory: &str) -> io::Result<(Position, usize)> {
+    storage.create_dir_all(directory)?;
+    let mut files = 0;
+    loop {
+        let length = input.read_u32::<LittleEndian>()?;
+        if length > MAX_NAME_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("file name of {} bytes is too long", length)));
+        }
+        let mut name = vec![0; length as usize];
+        input.read_exact(&mut name)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "file name isn't valid UTF-8"))?;
+        let size = input.read_u64::<LittleEndian>()?;
+
+        if name.is_empty() {
+            let mut encoded = vec![];
+            input.by_ref().take(size).read_to_end(&mut encoded)?;
+            let position = serde_json::from_slice::<Position>(&encoded)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid snapshot position"))?;
+            return Ok((position, files));
+        }
+        if !valid_name(&name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid file name: {}", name)));
+        }
+
+        let path = format!("{}/{}", directory, name);
+        let mut f = storage.create(&path)?;
+        let copied = io::copy(&mut input.by_ref().take(size), &mut f)?;
+        if copied != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} was cut short", name)));
+        }
+        f.sync()?;
+        files += 1;
+    }
+}
+
+// The length of the longest run of whole commit log entries at the start
+// of the data which fits in max_bytes. The first entry is always
+// included, however big it is, so that tailing can't get stuck on it.
+pub fn whole_entries(data: &[u8], max_bytes: usize) -> usize {
+    let mut end = 0;
+    while end + 4 <= data.len() {
+        let next = end + 4 + LittleEndian::read_u32(&data[end..end + 4]) as usize;
+        if next > data.len() || (end > 0 && next > max_bytes) {
+            break;
+        }
+        end = next;
+    }
+    end
+}
+
+// Read the whole commit log entries after the offset which fit in
+// max_bytes, the same as whole_entries, without reading the rest of the
+// log, which is length bytes long.
+pub fn read_entries<R: Read + Seek>(log: &mut R, offset: u64, length: u64, max_bytes: usize) -> io::Result<Vec<u8>> {
+    let available = length.saturating_sub(offset);
+    let mut data = vec![];
+    log.seek(io::SeekFrom::Start(offset))?;
+    log.by_ref().take(cmp::min(available, cmp::max(max_bytes, 4) as u64)).read_to_end(&mut data)?;
+
+    // The first entry is sent whole, however big it is.
+    if data.len() >= 4 {
+        let first = 4 + LittleEndian::read_u32(&data[..4]) as u64;
+        if first > data.len() as u64 && first <= available {
+            let rest = first - data.len() as u64;
+            log.by_ref().take(rest).read_to_end(&mut data)?;
+        }
+    }
+
+    let end = whole_entries(&data, max_bytes);
+    data.truncate(end);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Read;
+    use std::sync::Arc;
+    use storage;
+
+    #[test]
+    fn can_install_snapshots() {
+        let mut stream = vec![];
+        super::write_file(&mut stream, "1.dtable", 5, &mut &b"hello"[..]).unwrap();
+        super::write_file(&mut stream, "commit.log", 0, &mut &b""[..]).unwrap();
+        super::write_end(&mut stream, &super::Position{generation: 3, offset: 40}).unwrap();
+
+        let storage: Arc<storage::Storage> = Arc::new(storage::MemoryStorage::new());
+        let (position, files) = super::install_snapshot(&mut &stream[..], &storage, "/replica").unwrap();
+        assert_eq!(position, super::Position{generation: 3, offset: 40});
+        assert_eq!(files, 2);
+
+        let mut contents = String::new();
+        storage.open("/replica/1.dtable").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+
+        // A stream which is cut short, or which tries to write outside of
+        // the directory, is refused.
+        assert!(super::install_snapshot(&mut &stream[..stream.len() - 1], &storage, "/replica").is_err());
+        let mut escape = vec![];
+        super::write_file(&mut escape, "../MANIFEST", 0, &mut &b""[..]).unwrap();
+        assert!(super::install_snapshot(&mut &escape[..], &storage, "/replica").is_err());
+    }
+
+    #[test]
+    fn can_parse_positions() {
+        let position = super::Position{generation: 12, offset: 3456};
+        assert_eq!(super::Position::parse(&format!("{}", position)), Some(position));
+        assert_eq!(super::Position::parse("12"), None);
+        assert_eq!(super::Position::parse("12:x"), None);
+
+        assert!(position.reaches(&position));
+        assert!(position.reaches(&super::Position{generation: 12, offset: 3000}));
+        assert!(position.reaches(&super::Position{generation: 11, offset: 5000}));
+        assert!(!position.reaches(&super::Position{generation: 13, offset: 0}));
+    }
+
+    #[test]
+    fn splits_on_whole_entries() {
+        let data = [2, 0, 0, 0, 1, 2, 3, 0, 0, 0, 1, 2, 3, 1, 0, 0, 0];
+        assert_eq!(super::whole_entries(&data, 100), 13);
+        assert_eq!(super::whole_entries(&data, 10), 6);
+        assert_eq!(super::whole_entries(&data, 1), 6);
+        assert_eq!(super::whole_entries(&[], 1), 0);
+    }
+
+    #[test]
+    fn reads_whole_entries_from_the_offset() {
+        let data = [2, 0, 0, 0, 1, 2, 3, 0, 0, 0, 1, 2, 3, 1, 0, 0, 0];
+        let read = |offset, max_bytes| {
+            super::read_entries(&mut io::Cursor::new(&data[..]), offset, data.len() as u64, max_bytes).unwrap()
+        };
+        assert_eq!(read(0, 100), &data[..13]);
+        assert_eq!(read(6, 100), &data[6..13]);
+        assert_eq!(read(6, 1), &data[6..13]);
+        assert_eq!(read(0, 10), &data[..6]);
+        assert_eq!(read(13, 100), Vec::<u8>::new());
+    }
+}
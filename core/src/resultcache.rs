@@ -0,0 +1,173 @@
+/*
+    resultcache.rs
+
+    The ResultCache remembers the results of recent selects, for tables
+    which ask for it. Dashboards tend to ask for the same columns of the
+    same rows over and over, and a cached result can be handed back
+    without reading the row at all. Selects are grouped into buckets of
+    time as long as the table's TTL, and a select in the same bucket as
+    a cached one gets the cached result. Any write to the row drops its
+    cached results, so the only staleness comes from writes timestamped
+    in the future.
+*/
+
+use std::collections::HashMap;
+
+// The number of results which are cached by default.
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+// A cached result is the selected columns, or None if the row wasn't
+// found.
+pub type CachedResult = Option<Vec<Option<Vec<u8>>>>;
+
+struct Entry {
+    result: CachedResult,
+    expires: u64
+}
+
+// A ResultCache holds up to capacity results. Once it's full, expired
+// results are cleared out, and if there still isn't room, new results
+// aren't cached until there is. A capacity of zero disables it.
+pub struct ResultCache {
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+
+    // The results for each row, by the selected columns and time bucket.
+    rows: HashMap<String, HashMap<(Vec<String>, u64), Entry>>,
+    len: usize
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize) -> ResultCache {
+        ResultCache{
+            capacity: capacity,
+            hits: 0,
+            misses: 0,
+            rows: HashMap::new(),
+            len: 0
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // The time bucket which a select at the timestamp falls into.
+    fn bucket(timestamp: u64, ttl_ms: u64) -> u64 {
+        timestamp / (ttl_ms * 1_000_000)
+    }
+
+    pub fn get(&mut self, row: &str, columns: &[String], timestamp: u64, ttl_ms: u64) -> Option<CachedResult> {
+        if self.capacity == 0 || ttl_ms == 0 {
+            return None;
+        }
+
+        let key = (columns.to_vec(), ResultCache::bucket(timestamp, ttl_ms));
+        let result = match self.rows.get(row).and_then(|results| results.get(&key)) {
+            Some(e) if e.expires > timestamp => Some(e.result.clone()),
+            _ => None
+        };
+        match result {
+            Some(r) => {
+                self.hits += 1;
+                Some(r)
+            },
+            None    => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, row: &str, columns: &[String], timestamp: u64, ttl_ms: u64, result: CachedResult) {
+        if self.capacity == 0 || ttl_ms == 0 {
+            return;
+        }
+
+        if self.len >= self.capacity {
+            self.remove_expired(timestamp);
+            if self.len >= self.capacity {
+                return;
+            }
+        }
+
+        let bucket = ResultCache::bucket(timestamp, ttl_ms);
+        let entry = Entry{
+            result: result,
+            expires: (bucket + 1) * ttl_ms * 1_000_000
+        };
+        let results = self.rows.entry(row.to_owned()).or_insert_with(HashMap::new);
+        if results.insert((columns.to_vec(), bucket), entry).is_none() {
+            self.len += 1;
+        }
+    }
+
+    // Drop every result for the row.
+    pub fn invalidate(&mut self, row: &str) {
+        if let Some(results) = self.rows.remove(row) {
+            self.len -= results.len();
+        }
+    }
+
+    // Drop every result for the rows in [start, end). An empty end key
+    // means the range has no upper bound.
+    pub fn invalidate_range(&mut self, start: &str, end: &str) {
+        let dropped = self.rows.keys()
+            .filter(|k| k.as_str() >= start && (end.is_empty() || k.as_str() < end))
+            .cloned()
+            .collect::<Vec<_>>();
+        for row in dropped {
+            self.invalidate(&row);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.len = 0;
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        for results in self.rows.values_mut() {
+            results.retain(|_, e| e.expires > now);
+        }
+        self.rows.retain(|_, results| !results.is_empty());
+        self.len = self.rows.values().map(|r| r.len()).sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn columns() -> Vec<String> {
+        vec![String::from("status")]
+    }
+
+    #[test]
+    fn caches_results_within_a_bucket() {
+        let mut cache = super::ResultCache::new(10);
+        let result = Some(vec![Some(b"OK".to_vec())]);
+        cache.insert("row", &columns(), 1_500_000_000, 1000, result.clone());
+
+        assert_eq!(cache.get("row", &columns(), 1_900_000_000, 1000), Some(result.clone()));
+        assert_eq!(cache.get("row", &columns(), 2_000_000_000, 1000), None);
+        assert_eq!(cache.get("row", &[], 1_900_000_000, 1000), None);
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+
+        cache.invalidate("row");
+        assert_eq!(cache.get("row", &columns(), 1_900_000_000, 1000), None);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn makes_room_by_dropping_expired_results() {
+        let mut cache = super::ResultCache::new(1);
+        cache.insert("a", &columns(), 0, 1000, None);
+        cache.insert("b", &columns(), 0, 1000, None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get("b", &columns(), 0, 1000), None);
+
+        cache.insert("b", &columns(), 1_000_000_000, 1000, None);
+        assert_eq!(cache.get("b", &columns(), 1_000_000_000, 1000), Some(None));
+        assert_eq!(cache.len(), 1);
+    }
+}
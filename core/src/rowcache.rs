@@ -0,0 +1,219 @@
+/*
+    rowcache.rs
+
+    The RowCache keeps recently read rows in memory, already merged
+    across the memtable and dtables, so that reading a hot row doesn't
+    have to search every dtable again. Writes keep it up to date in one
+    of two ways, depending on the mode: either the written row is dropped
+    from the cache, or the write is applied to the cached copy as well
+    (write-through). Either way, a read after a write never sees a stale
+    row. Whenever the set of dtables changes, e.g. after a compaction,
+    the whole cache is dropped.
+*/
+
+use std::fmt;
+use std::collections::{BTreeMap, HashMap};
+
+use protobuf;
+
+use generated::dtable::{DRow, DColumn, DEntry};
+use query::MUpdate;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    #[serde(rename = "invalidate")]
+    Invalidate,
+    #[serde(rename = "write_through")]
+    WriteThrough
+}
+
+impl fmt::Display for CacheMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match *self {
+                CacheMode::Invalidate   => "invalidate",
+                CacheMode::WriteThrough => "write_through"
+            }
+        )
+    }
+}
+
+// A RowCache holds up to capacity rows. When it's full, the least
+// recently used row is dropped. A capacity of zero disables it.
+pub struct RowCache {
+    pub capacity: usize,
+    pub mode: CacheMode,
+    pub hits: u64,
+    pub misses: u64,
+
+    // Each row is stored along with when it was last used, and order
+    // maps those times back to rows, oldest first.
+    rows: HashMap<String, (DRow, u64)>,
+    order: BTreeMap<u64, String>,
+    clock: u64
+}
+
+impl RowCache {
+    pub fn new(capacity: usize) -> RowCache {
+        RowCache{
+            capacity: capacity,
+            mode: CacheMode::Invalidate,
+            hits: 0,
+            misses: 0,
+            rows: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    // Look up the row, marking it as recently used.
+    pub fn get(&mut self, row: &str) -> Option<&DRow> {
+        self.clock += 1;
+        match self.rows.get_mut(row) {
+            Some(&mut (ref r, ref mut used)) => {
+                self.order.remove(used);
+                self.order.insert(self.clock, row.to_owned());
+                *used = self.clock;
+                self.hits += 1;
+                Some(r)
+            },
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, row: &str, value: DRow) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.invalidate(row);
+        while self.rows.len() >= self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&t) => t,
+                None     => break
+            };
+            if let Some(k) = self.order.remove(&oldest) {
+                self.rows.remove(&k);
+            }
+        }
+
+        self.clock += 1;
+        self.order.insert(self.clock, row.to_owned());
+        self.rows.insert(row.to_owned(), (value, self.clock));
+    }
+
+    // Called for every write to the row. In write-through mode, a cached
+    // row gets the new values as well, and otherwise it's dropped.
+    pub fn write(&mut self, row: &str, updates: &[MUpdate], timestamp: u64) {
+        if self.mode == CacheMode::Invalidate {
+            return self.invalidate(row);
+        }
+
+        if let Some(&mut (ref mut r, _)) = self.rows.get_mut(row) {
+            let written = updates_to_drow(updates, timestamp);
+            *r = DRow::from_vec(&[r.clone(), written]);
+        }
+    }
+
+    pub fn invalidate(&mut self, row: &str) {
+        if let Some((_, used)) = self.rows.remove(row) {
+            self.order.remove(&used);
+        }
+    }
+
+    // Drop every row in [start, end). An empty end key means the range
+    // has no upper bound.
+    pub fn invalidate_range(&mut self, start: &str, end: &str) {
+        let dropped = self.rows.keys()
+            .filter(|k| k.as_str() >= start && (end.is_empty() || k.as_str() < end))
+            .cloned()
+            .collect::<Vec<_>>();
+        for row in dropped {
+            self.invalidate(&row);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.clear();
+        self.order.clear();
+    }
+}
+
+fn updates_to_drow(updates: &[MUpdate], timestamp: u64) -> DRow {
+    let mut columns: BTreeMap<&str, DColumn> = BTreeMap::new();
+    for u in updates {
+        let mut e = DEntry::new();
+        e.set_timestamp(timestamp);
+        e.set_value(u.value.clone());
+        columns.entry(u.key.as_str()).or_insert_with(DColumn::new).mut_entries().push(e);
+    }
+
+    let mut d = DRow::new();
+    d.set_keys(protobuf::RepeatedField::from_vec(columns.keys().map(|k| k.to_string()).collect()));
+    d.set_columns(protobuf::RepeatedField::from_vec(columns.into_iter().map(|(_, c)| c).collect()));
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use query::MUpdate;
+    use generated::dtable::DRow;
+
+    fn row(value: &str, timestamp: u64) -> DRow {
+        super::updates_to_drow(&[MUpdate::new("status", value.as_bytes().to_vec())], timestamp)
+    }
+
+    fn status(cache: &mut super::RowCache, key: &str) -> Option<Vec<u8>> {
+        cache.get(key).map(|r| r.get_latest_value("status").unwrap().get_value().to_vec())
+    }
+
+    #[test]
+    fn evicts_least_recently_used_rows() {
+        let mut cache = super::RowCache::new(2);
+        cache.insert("a", row("1", 1));
+        cache.insert("b", row("2", 1));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c", row("3", 1));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert_eq!((cache.hits, cache.misses), (3, 1));
+
+        cache.invalidate_range("a", "c");
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn applies_writes() {
+        let mut cache = super::RowCache::new(10);
+        cache.insert("a", row("old", 1));
+        cache.write("a", &[MUpdate::new("status", b"new".to_vec())], 2);
+        assert_eq!(status(&mut cache, "a"), None);
+
+        cache.mode = super::CacheMode::WriteThrough;
+        cache.insert("a", row("old", 1));
+        cache.write("a", &[MUpdate::new("status", b"new".to_vec())], 2);
+        assert_eq!(status(&mut cache, "a"), Some(b"new".to_vec()));
+        assert_eq!(cache.get("a").unwrap().get_column("status").unwrap().get_entries().len(), 2);
+
+        // Writes to rows which aren't cached don't add them.
+        cache.write("b", &[MUpdate::new("status", b"new".to_vec())], 2);
+        assert!(cache.get("b").is_none());
+    }
+}
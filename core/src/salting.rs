@@ -0,0 +1,247 @@
+/*
+    salting.rs
+
+    Tables whose keys only ever grow, like timestamps or sequence numbers,
+    send every write to the end of the keyspace. Salting spreads them
+    out: a salted table's keys are stored with a bucket, picked by a hash
+    of the rest of the key, in front of them, e.g. "events/123" is stored
+    as "events/3f/123". Point reads and writes are salted on the way in,
+    and scans of the table are run over every bucket and merged back into
+    key order, with the buckets taken out of the keys again.
+*/
+
+use std::cmp;
+
+use keys;
+use query;
+use scan;
+
+// Buckets are written as two hex digits.
+pub const MAX_BUCKETS: u64 = 256;
+const BUCKET_LENGTH: usize = 3;
+
+pub struct Salting {
+    tables: Vec<(String, u64)>
+}
+
+// FNV-1a of the key, after the table's prefix.
+fn bucket(key: &str, buckets: u64) -> u64 {
+    let hash = key.as_bytes().iter()
+        .fold(0xcbf29ce484222325u64, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    hash % buckets
+}
+
+fn bucket_prefix(prefix: &str, bucket: u64) -> String {
+    format!("{}{:02x}/", prefix, bucket)
+}
+
+impl Salting {
+    pub fn new() -> Salting {
+        Salting{
+            tables: vec![]
+        }
+    }
+
+    // Salt the keys of the table with the given prefix into a number of
+    // buckets, up to MAX_BUCKETS. Zero or one buckets turns salting off.
+    pub fn set_table(&mut self, prefix: &str, buckets: u64) {
+        self.tables.retain(|&(ref p, _)| p != prefix);
+        if buckets > 1 {
+            self.tables.push((prefix.to_owned(), cmp::min(buckets, MAX_BUCKETS)));
+        }
+    }
+
+    // The salted table which the row belongs to, which is the one with
+    // the longest matching prefix.
+    fn table(&self, row: &str) -> Option<&(String, u64)> {
+        self.tables.iter()
+            .filter(|&&(ref p, _)| row.starts_with(p.as_str()))
+            .max_by_key(|&&(ref p, _)| p.len())
+    }
+
+    pub fn salt(&self, row: &str) -> String {
+        match self.table(row) {
+            Some(&(ref p, buckets)) => {
+                let rest = &row[p.len()..];
+                format!("{}{}", bucket_prefix(p, bucket(rest, buckets)), rest)
+            },
+            None => row.to_owned()
+        }
+    }
+
+    // The end of the salted table which the row belongs to, if any.
+    pub fn table_end(&self, row: &str) -> Option<String> {
+        self.table(row).and_then(|&(ref p, _)| keys::prefix_end(p))
+    }
+
+    pub fn unsalt(&self, row: &str) -> String {
+        match self.table(row) {
+            Some(&(ref p, _)) if row.len() >= p.len() + BUCKET_LENGTH =>
+                format!("{}{}", p, &row[p.len() + BUCKET_LENGTH..]),
+            _ => row.to_owned()
+        }
+    }
+
+    // Salt the row keys of queries which read or write a single row,
+    // including the ones being explained. Queries over ranges of keys are
+    // split up by bucket_ranges instead.
+    pub fn salt_query(&self, q: query::Query) -> query::Query {
+        if self.tables.is_empty() {
+            return q;
+        }
+
+        match q {
+            query::Query::Select{row: r, get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl} =>
+                query::Query::Select{row: self.salt(&r), get: g, snapshot: s, include_deleted: d, project: p, strict: t, column_start: cs, column_limit: cl},
+            query::Query::Insert{row: r, set: s} =>
+                query::Query::Insert{row: self.salt(&r), set: s},
+            query::Query::Update{row: r, set: s} =>
+                query::Query::Update{row: self.salt(&r), set: s},
+            query::Query::Append{row: r, set: s} =>
+                query::Query::Append{row: self.salt(&r), set: s},
+            query::Query::SelectList{row: r, column: c, limit: l, start: s, end: e} =>
+                query::Query::SelectList{row: self.salt(&r), column: c, limit: l, start: s, end: e},
+            query::Query::Transaction{updates: u} =>
+                query::Query::Transaction{updates: u.into_iter().map(|(r, s)| (self.salt(&r), s)).collect()},
+            query::Query::History{row: r, column: c, limit: l} =>
+                query::Query::History{row: self.salt(&r), column: c, limit: l},
+            query::Query::Describe{row: r} =>
+                query::Query::Describe{row: self.salt(&r)},
+            query::Query::Checksum{row: r} =>
+                query::Query::Checksum{row: self.salt(&r)},
+            query::Query::Diff{row: r, from_ts: f, to_ts: t} =>
+                query::Query::Diff{row: self.salt(&r), from_ts: f, to_ts: t},
+            query::Query::SoftDelete{row: r} =>
+                query::Query::SoftDelete{row: self.salt(&r)},
+            query::Query::Undelete{row: r} =>
+                query::Query::Undelete{row: self.salt(&r)},
+            query::Query::Explain(q) =>
+                query::Query::Explain(Box::new(self.salt_query(*q))),
+            x => x
+        }
+    }
+
+    // If the range lies within a salted table, the matching range in
+    // each of its buckets. Ranges which reach outside of the table are
+    // scanned as they are, and see the salted keys.
+    pub fn bucket_ranges(&self, range: &scan::KeyRange) -> Option<Vec<scan::KeyRange>> {
+        let &(ref prefix, buckets) = self.table(&range.start)?;
+        let end = match keys::prefix_end(prefix) {
+            Some(e) => e,
+            None    => return None
+        };
+        let end_rest = match range.end.as_str() {
+            e if e == end.as_str()          => None,
+            e if e.starts_with(prefix.as_str()) => Some(&e[prefix.len()..]),
+            _                               => return None
+        };
+
+        let start_rest = &range.start[prefix.len()..];
+        Some((0..buckets).map(|b| {
+            let p = bucket_prefix(prefix, b);
+            scan::KeyRange{
+                start: format!("{}{}", p, start_rest),
+                end: match end_rest {
+                    Some(e) => format!("{}{}", p, e),
+                    None    => keys::prefix_end(&p).unwrap()
+                }
+            }
+        }).collect())
+    }
+
+    // Merge the results of scanning each bucket back into one page of up
+    // to limit rows, in key order. The continuation key is the first
+    // row which wasn't returned, from any bucket.
+    pub fn merge_scans(&self, results: Vec<query::QueryResult>, limit: usize) -> query::QueryResult {
+        let mut rows = vec![];
+        let mut next = vec![];
+        for result in results {
+            match result {
+                query::QueryResult::Rows{rows: r, next: n} => {
+                    rows.extend(r.into_iter().map(|row| query::ScanRow{
+                        key: self.unsalt(&row.key),
+                        columns: row.columns
+                    }));
+                    if !n.is_empty() {
+                        next.push(self.unsalt(&n));
+                    }
+                },
+                x => return x
+            }
+        }
+
+        rows.sort_by(|a, b| a.key.cmp(&b.key));
+        if rows.len() > limit {
+            next.push(rows[limit].key.clone());
+            rows.truncate(limit);
+        }
+        query::QueryResult::Rows{
+            rows: rows,
+            next: next.into_iter().min().unwrap_or_default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use query;
+    use scan;
+
+    fn row(key: &str) -> query::ScanRow {
+        query::ScanRow{key: key.to_owned(), columns: vec![]}
+    }
+
+    #[test]
+    fn salts_keys_in_salted_tables() {
+        let mut salting = super::Salting::new();
+        salting.set_table("events/", 16);
+
+        let salted = salting.salt("events/123");
+        assert!(salted.starts_with("events/"));
+        assert_eq!(salted.len(), "events/123".len() + 3);
+        assert_eq!(&salted[salted.len() - 4..], "/123");
+        assert_eq!(salting.unsalt(&salted), "events/123");
+        assert_eq!(salting.salt("users/123"), "users/123");
+
+        // Sequential keys are spread over the buckets.
+        let mut buckets = (0..100).map(|i| salting.salt(&format!("events/{}", i))[..10].to_owned()).collect::<Vec<_>>();
+        buckets.sort();
+        buckets.dedup();
+        assert!(buckets.len() > 8);
+    }
+
+    #[test]
+    fn splits_scans_into_buckets() {
+        let mut salting = super::Salting::new();
+        salting.set_table("events/", 2);
+
+        let ranges = salting.bucket_ranges(&scan::KeyRange::new("events/1", "events/5")).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!((ranges[0].start.as_str(), ranges[0].end.as_str()), ("events/00/1", "events/00/5"));
+        assert_eq!((ranges[1].start.as_str(), ranges[1].end.as_str()), ("events/01/1", "events/01/5"));
+
+        let ranges = salting.bucket_ranges(&scan::KeyRange::new("events/", "events0")).unwrap();
+        assert_eq!((ranges[1].start.as_str(), ranges[1].end.as_str()), ("events/01/", "events/010"));
+
+        assert!(salting.bucket_ranges(&scan::KeyRange::new("events/", "")).is_none());
+        assert!(salting.bucket_ranges(&scan::KeyRange::new("a", "z")).is_none());
+    }
+
+    #[test]
+    fn merges_bucket_scans() {
+        let mut salting = super::Salting::new();
+        salting.set_table("e/", 2);
+
+        let merged = salting.merge_scans(vec![
+            query::QueryResult::Rows{rows: vec![row("e/00/1"), row("e/00/4")], next: String::from("e/00/6")},
+            query::QueryResult::Rows{rows: vec![row("e/01/2"), row("e/01/3")], next: String::new()}
+        ], 2);
+        assert_eq!(format!("{}", merged), "Rows: [e/1: {}, e/2: {}], next: \"e/3\"");
+
+        let merged = salting.merge_scans(vec![
+            query::QueryResult::Rows{rows: vec![row("e/00/1"), row("e/00/4")], next: String::from("e/00/6")},
+            query::QueryResult::Rows{rows: vec![row("e/01/2")], next: String::new()}
+        ], 4);
+        assert_eq!(format!("{}", merged), "Rows: [e/1: {}, e/2: {}, e/4: {}], next: \"e/6\"");
+    }
+}
@@ -0,0 +1,309 @@
+/*
+    scan.rs
+
+    This file contains the iterators used to stream rows out of the
+    database in order, merging the memtable and dtables as they go.
+*/
+
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::slice;
+use std::collections::btree_map;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use mtable;
+use dtable;
+use generated::dtable::*;
+
+// A KeyRange covers the row keys in [start, end). An empty end key means
+// the range has no upper bound.
+#[derive(Debug, Clone, Default)]
+pub struct KeyRange {
+    pub start: String,
+    pub end: String
+}
+
+impl KeyRange {
+    pub fn new(start: &str, end: &str) -> KeyRange {
+        KeyRange{
+            start: start.to_owned(),
+            end: end.to_owned()
+        }
+    }
+
+    // A KeyRange covering every row.
+    pub fn all() -> KeyRange {
+        KeyRange::default()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        key >= self.start.as_str() && (self.end.is_empty() || key < self.end.as_str())
+    }
+}
+
+pub struct RowIter<'a> {
+    memtable: Peekable<btree_map::Range<'a, String, mtable::MRow>>,
+    // For each dtable, its reader, its header entries from the start of
+    // the range, the index of the first of those entries in the header,
+    // and how many of them have been read.
+    disktables: Vec<(dtable::ReadAhead<'a>, Cow<'a, [DTableHeaderEntry]>, usize, usize)>,
+    tombstones: Vec<&'a RangeTombstone>,
+    range: KeyRange,
+    timestamp: u64
+}
+
+// A RowView is a single row, merged together from every table which
+// contains data for it.
+pub struct RowView {
+    key: String,
+    row: DRow,
+    timestamp: u64,
+    deleted_at: u64
+}
+
+// The ColumnIter yields the name and value of each column in a row which
+// is visible at the timestamp the row was read at.
+pub struct ColumnIter<'a> {
+    keys: slice::Iter<'a, String>,
+    columns: slice::Iter<'a, DColumn>,
+    timestamp: u64,
+    deleted_at: u64
+}
+
+// The TimestampedColumnIter is like the ColumnIter, but also yields the
+// time that each value was written.
+pub struct TimestampedColumnIter<'a> {
+    columns: ColumnIter<'a>
+}
+
+impl<'a> RowIter<'a> {
+    pub fn new(
+        memtable: &'a mtable::MTable,
+        disktables: &'a [dtable::DTable],
+        tombstones: Vec<&'a RangeTombstone>,
+        range: KeyRange,
+        timestamp: u64,
+        readahead_bytes: u64
+    ) -> RowIter<'a> {
+        RowIter{
+            memtable: memtable.range_from(&range.start).peekable(),
+            disktables: disktables.iter()
+                .filter(|d| d.may_overlap(&range.start, &range.end))
+                .filter_map(|d| match d.lower_bound(&range.start).and_then(|i| d.entries_after(i).map(|e| (i, e))) {
+                    Ok((first, entries)) => Some((d.read_ahead(readahead_bytes), entries, first, 0)),
+                    Err(e) => {
+                        error!("Unable to read the header of {}: {}", d.filename(), e);
+                        None
+                    }
+                })
+                .collect(),
+            tombstones: tombstones,
+            range: range,
+            timestamp: timestamp
+        }
+    }
+
+    // Find the smallest key that any of the tables has yet to produce.
+    fn next_key(&mut self) -> Option<String> {
+        let mut key = self.memtable.peek().map(|&(k, _)| k.as_str());
+        for &(_, ref entries, _, read) in self.disktables.iter() {
+            key = match (key, entries.get(read)) {
+                (Some(k), Some(e)) if e.get_key() < k => Some(e.get_key()),
+                (None, Some(e)) => Some(e.get_key()),
+                (k, _) => k
+            };
+        }
+        key.map(|k| k.to_owned())
+    }
+}
+
+impl<'a> Iterator for RowIter<'a> {
+    type Item = RowView;
+
+    fn next(&mut self) -> Option<RowView> {
+        loop {
+            let key = match self.next_key() {
+                Some(ref k) if !self.range.contains(k) => return None,
+                Some(k) => k,
+                None    => return None
+            };
+
+            // Collect the row from every table which contains the key,
+            // and advance those tables past it.
+            let mut rows = vec![];
+            if self.memtable.peek().map(|&(k, _)| k == &key).unwrap_or(false) {
+                rows.push(self.memtable.next().unwrap().1.to_drow());
+            }
+            for &mut (ref mut reader, ref entries, first, ref mut read) in self.disktables.iter_mut() {
+                if entries.get(*read).map(|e| e.get_key() == key).unwrap_or(false) {
+                    match reader.get_row(first + *read) {
+                        Ok(row) => rows.push(row),
+                        Err(e)  => error!("Unable to read row {}: {}", key, e)
+                    };
+                    *read += 1;
+                }
+            }
+
+            let view = RowView{
+                deleted_at: dtable::deleted_at(self.tombstones.iter().cloned(), &key, self.timestamp),
+                key: key,
+                row: DRow::from_vec(&rows),
+                timestamp: self.timestamp
+            };
+
+            // Rows without any visible columns were either deleted or
+            // written after the timestamp, so they're skipped.
+            if view.iter_columns().next().is_some() {
+                return Some(view);
+            }
+        }
+    }
+}
+
+impl RowView {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn iter_columns(&self) -> ColumnIter {
+        ColumnIter{
+            keys: self.row.get_keys().iter(),
+            columns: self.row.get_columns().iter(),
+            timestamp: self.timestamp,
+            deleted_at: self.deleted_at
+        }
+    }
+
+    pub fn iter_timestamped_columns(&self) -> TimestampedColumnIter {
+        TimestampedColumnIter{
+            columns: self.iter_columns()
+        }
+    }
+
+    // Look up the value of a single column.
+    pub fn get(&self, column: &str) -> Option<&[u8]> {
+        self.iter_columns()
+            .find(|&(k, _)| k == column)
+            .map(|(_, v)| v)
+    }
+
+    pub fn is_soft_deleted(&self) -> bool {
+        self.get(dtable::SOFT_DELETE_COLUMN).and_then(dtable::soft_deleted_at).is_some()
+    }
+
+    // A hash (FNV-1a) of the latest value of each column, other than the
+    // soft delete marker. Columns are sorted by name, and each name and
+    // value is prefixed with its length so that they can't run together.
+    pub fn checksum(&self) -> u64 {
+        self.iter_columns()
+            .filter(|&(k, _)| k != dtable::SOFT_DELETE_COLUMN)
+            .fold(FNV_OFFSET, |hash, (k, v)| fnv1a_field(fnv1a_field(hash, k.as_bytes()), v))
+    }
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// Hash the length of the data and then the data itself.
+pub fn fnv1a_field(hash: u64, data: &[u8]) -> u64 {
+    let mut length = [0u8; 8];
+    LittleEndian::write_u64(&mut length, data.len() as u64);
+    length.iter().chain(data.iter())
+        .fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+impl<'a> ColumnIter<'a> {
+    fn next_entry(&mut self) -> Option<(&'a str, &'a DEntry)> {
+        loop {
+            let (key, column) = match (self.keys.next(), self.columns.next()) {
+                (Some(k), Some(c)) => (k, c),
+                _ => return None
+            };
+
+            // Entries are sorted by timestamp, so the newest one at or
+            // before the timestamp is the value of the column.
+            let timestamp = self.timestamp;
+            match column.get_entries().iter().rev().find(|e| e.get_timestamp() <= timestamp) {
+                Some(e) if e.get_timestamp() > self.deleted_at => return Some((key.as_str(), e)),
+                _ => continue
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for ColumnIter<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a str, &'a [u8])> {
+        self.next_entry().map(|(k, e)| (k, e.get_value()))
+    }
+}
+
+impl<'a> Iterator for TimestampedColumnIter<'a> {
+    type Item = (&'a str, u64, &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a str, u64, &'a [u8])> {
+        self.columns.next_entry().map(|(k, e)| (k, e.get_timestamp(), e.get_value()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base;
+    use query;
+
+    fn collect(database: &base::Base, range: super::KeyRange, timestamp: u64) -> Vec<String> {
+        database.iter_rows(range, timestamp)
+            .map(|r| format!(
+                "{}: {}",
+                r.key(),
+                r.iter_columns()
+                    .map(|(k, v)| format!("{}={}", k, String::from_utf8(v.to_vec()).unwrap()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+            .collect()
+    }
+
+    #[test]
+    fn can_iterate_rows_across_tables() {
+        let mut database = base::Base::new_stub();
+        database.insert("a", vec![query::MUpdate::new("x", b"1".to_vec())], 100);
+        database.insert("c", vec![query::MUpdate::new("x", b"3".to_vec())], 100);
+        database.empty_memtable().unwrap();
+
+        database.insert("b", vec![query::MUpdate::new("x", b"2".to_vec())], 200);
+        database.update("c", vec![query::MUpdate::new("y", b"4".to_vec())], 200);
+        database.insert("d", vec![query::MUpdate::new("x", b"5".to_vec())], 200);
+
+        assert_eq!(
+            collect(&database, super::KeyRange::all(), 300),
+            vec!["a: x=1", "b: x=2", "c: x=3, y=4", "d: x=5"]
+        );
+
+        // Reading at an earlier timestamp hides the newer writes.
+        assert_eq!(
+            collect(&database, super::KeyRange::all(), 150),
+            vec!["a: x=1", "c: x=3"]
+        );
+
+        assert_eq!(
+            collect(&database, super::KeyRange::new("b", "d"), 300),
+            vec!["b: x=2", "c: x=3, y=4"]
+        );
+    }
+
+    #[test]
+    fn skips_deleted_rows() {
+        let mut database = base::Base::new_stub();
+        database.insert("a", vec![query::MUpdate::new("x", b"1".to_vec())], 100);
+        database.insert("b", vec![query::MUpdate::new("x", b"2".to_vec())], 100);
+        database.empty_memtable().unwrap();
+        database.delete_range("a", "b", 150);
+
+        let rows = database.iter_rows(super::KeyRange::all(), 200).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].key(), "b");
+        assert_eq!(rows[0].get("x"), Some(&b"2"[..]));
+    }
+}
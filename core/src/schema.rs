@@ -0,0 +1,403 @@
+/*
+    schema.rs
+
+    Tables are row key prefixes, like "users/". A table can optionally
+    declare its columns, and then writes to it are checked against the
+    declarations: columns which aren't declared are rejected, values must
+    match the declared type, and new rows must have every required column.
+    Tables without declarations accept anything, as before.
+
+    Columns can also have defaults, which are filled in when a row is
+    inserted without them, before it's written, and transforms, which
+    rewrite their values as they're written (see transform.rs). Columns
+    which are written over and over with the same value can be
+    deduplicated, so that only changes to the value are kept.
+*/
+
+use std::collections::HashMap as Map;
+use std::str;
+
+use compositekey;
+use keys;
+use query;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    #[serde(rename = "bytes")]
+    Bytes,
+    #[serde(rename = "string")]
+    String,
+    #[serde(rename = "int")]
+    Int,
+    #[serde(rename = "float")]
+    Float,
+    #[serde(rename = "bool")]
+    Bool
+}
+
+impl Default for ColumnType {
+    fn default() -> ColumnType {
+        ColumnType::Bytes
+    }
+}
+
+impl ColumnType {
+    // Numbers and booleans are stored as text, e.g. "42", "1.5", "true".
+    pub fn accepts(&self, value: &[u8]) -> bool {
+        let text = match *self {
+            ColumnType::Bytes   => return true,
+            _                   => match str::from_utf8(value) {
+                Ok(t)   => t,
+                Err(_)  => return false
+            }
+        };
+
+        match *self {
+            ColumnType::Int     => text.parse::<i64>().is_ok(),
+            ColumnType::Float   => text.parse::<f64>().is_ok(),
+            ColumnType::Bool    => text == "true" || text == "false",
+            _                   => true
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ColumnType::Bytes   => "bytes",
+            ColumnType::String  => "string",
+            ColumnType::Int     => "int",
+            ColumnType::Float   => "float",
+            ColumnType::Bool    => "bool"
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ColumnSchema {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub column_type: ColumnType,
+    #[serde(default)]
+    pub required: bool,
+
+    // The value for inserted rows which don't set the column. If
+    // default_timestamp is set, the default is the write's timestamp
+    // (in nanoseconds) instead, e.g. for a created_at column.
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub default_timestamp: bool,
+
+    // If dedup is set, updates which don't change the column's latest
+    // value are left out. If touch_column is also set, the timestamp of
+    // a write which was left out is written to that column instead.
+    #[serde(default)]
+    pub dedup: bool,
+    #[serde(default)]
+    pub touch_column: Option<String>
+}
+
+impl ColumnSchema {
+    fn default_value(&self, timestamp: u64) -> Option<Vec<u8>> {
+        if self.default_timestamp {
+            return Some(format!("{}", timestamp).into_bytes());
+        }
+        self.default.as_ref().map(|d| d.as_bytes().to_vec())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TableSchema {
+    pub prefix: String,
+    #[serde(default)]
+    pub columns: Vec<ColumnSchema>,
+
+    // If this is set, select results from the table are cached for up
+    // to this long (see resultcache.rs).
+    #[serde(default)]
+    pub result_cache_ttl_ms: u64,
+    #[serde(default)]
+    pub transforms: Vec<TransformSchema>,
+
+    // How the table's row keys are ordered and compared (see keys.rs).
+    #[serde(default)]
+    pub key_order: keys::KeyOrder,
+
+    // If the table's row keys are composite keys (see compositekey.rs),
+    // the types of their parts, after the prefix.
+    #[serde(default)]
+    pub key_parts: Vec<compositekey::KeyPartType>,
+
+    // If this is more than one, the table's keys are spread over this
+    // many buckets (see salting.rs).
+    #[serde(default)]
+    pub salt_buckets: u64
+}
+
+// Names a registered transform to run on a column's values, and the
+// options to create it with.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TransformSchema {
+    pub column: String,
+    pub transform: String,
+    #[serde(default)]
+    pub options: Map<String, String>
+}
+
+impl TableSchema {
+    fn column(&self, name: &str) -> Option<&ColumnSchema> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+}
+
+pub struct Schemas {
+    tables: Vec<TableSchema>
+}
+
+impl Schemas {
+    pub fn new() -> Schemas {
+        Schemas{
+            tables: vec![]
+        }
+    }
+
+    pub fn add_table(&mut self, table: TableSchema) {
+        self.tables.retain(|t| t.prefix != table.prefix);
+        self.tables.push(table);
+    }
+
+    // The table which the row belongs to, which is the one with the
+    // longest matching prefix.
+    pub fn table(&self, row: &str) -> Option<&TableSchema> {
+        self.tables.iter()
+            .filter(|t| row.starts_with(t.prefix.as_str()))
+            .max_by_key(|t| t.prefix.len())
+    }
+
+    // The declaration of the column in the row's table, if it has one.
+    pub fn column(&self, row: &str, name: &str) -> Option<&ColumnSchema> {
+        self.table(row).and_then(|t| t.column(name))
+    }
+
+    // How long select results from the row's table may be cached for,
+    // or zero if they aren't.
+    pub fn result_cache_ttl_ms(&self, row: &str) -> u64 {
+        self.table(row).map(|t| t.result_cache_ttl_ms).unwrap_or(0)
+    }
+
+    // Fill in the defaults for any columns that an insert leaves out.
+    pub fn apply_defaults(&self, q: query::Query, timestamp: u64) -> query::Query {
+        match q {
+            query::Query::Insert{row, mut set} => {
+                if let Some(table) = self.table(&row) {
+                    for column in table.columns.iter() {
+                        if set.contains_key(&column.name) {
+                            continue;
+                        }
+                        if let Some(value) = column.default_value(timestamp) {
+                            set.insert(column.name.clone(), value);
+                        }
+                    }
+                }
+                query::Query::Insert{row: row, set: set}
+            },
+            x => x
+        }
+    }
+
+    // Check a write against the schema of its table, and describe what's
+    // wrong with it if it doesn't fit.
+    pub fn validate(&self, q: &query::Query) -> Result<(), String> {
+        match *q {
+            query::Query::Insert{ref row, ref set} => self.validate_row(row, set, true),
+            query::Query::Update{ref row, ref set} |
+            query::Query::Append{ref row, ref set} => self.validate_row(row, set, false),
+            query::Query::Transaction{ref updates} => {
+                let mut rows = updates.keys().collect::<Vec<_>>();
+                rows.sort();
+                for row in rows {
+                    self.validate_row(row, &updates[row], false)?;
+                }
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn validate_row(&self, row: &str, set: &Map<String, Vec<u8>>, new_row: bool) -> Result<(), String> {
+        let table = match self.table(row) {
+            Some(t) => t,
+            None    => return Ok(())
+        };
+
+        if !table.key_parts.is_empty() {
+            if let Err(reason) = compositekey::decode(&row[table.prefix.len()..], &table.key_parts) {
+                return Err(format!("row key doesn't fit table \"{}\": {}", table.prefix, reason));
+            }
+        }
+
+        if table.columns.is_empty() {
+            return Ok(());
+        }
+
+        let mut names = set.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let column = match table.column(name) {
+                Some(c) => c,
+                None    => return Err(format!("column \"{}\" isn't declared in table \"{}\"", name, table.prefix))
+            };
+            if !column.column_type.accepts(&set[name]) {
+                return Err(format!("column \"{}\" in table \"{}\" must be of type {}", name, table.prefix, column.column_type.name()));
+            }
+        }
+
+        if new_row {
+            if let Some(c) = table.columns.iter().find(|c| c.required && !set.contains_key(&c.name)) {
+                return Err(format!("column \"{}\" is required in table \"{}\"", c.name, table.prefix));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap as Map;
+    use std::iter::FromIterator;
+    use serde_json;
+    use query;
+    use compositekey::Key;
+
+    fn schemas() -> super::Schemas {
+        let mut s = super::Schemas::new();
+        s.add_table(serde_json::from_str(r#"{
+            "prefix": "users/",
+            "columns": [
+                {"name": "name", "type": "string", "required": true},
+                {"name": "age", "type": "int"},
+                {"name": "avatar"}
+            ]
+        }"#).unwrap());
+        s.add_table(serde_json::from_str(r#"{"prefix": "users/archive/"}"#).unwrap());
+        s
+    }
+
+    fn insert(row: &str, values: &[(&str, &str)]) -> query::Query {
+        query::Query::Insert{
+            row: row.to_owned(),
+            set: Map::from_iter(values.iter().map(|&(k, v)| (k.to_owned(), v.as_bytes().to_vec())))
+        }
+    }
+
+    #[test]
+    fn validates_writes() {
+        let s = schemas();
+        assert!(s.validate(&insert("users/1", &[("name", "alice"), ("age", "30")])).is_ok());
+        assert_eq!(
+            s.validate(&insert("users/1", &[("name", "alice"), ("agee", "30")])),
+            Err(String::from(r#"column "agee" isn't declared in table "users/""#))
+        );
+        assert_eq!(
+            s.validate(&insert("users/1", &[("name", "alice"), ("age", "thirty")])),
+            Err(String::from(r#"column "age" in table "users/" must be of type int"#))
+        );
+        assert_eq!(
+            s.validate(&insert("users/1", &[("age", "30")])),
+            Err(String::from(r#"column "name" is required in table "users/""#))
+        );
+
+        // Updates don't have to repeat the required columns.
+        assert!(s.validate(&query::Query::new_update("users/1", vec![
+            query::MUpdate::new("age", b"31".to_vec())
+        ])).is_ok());
+
+        // Every row in a transaction has to fit.
+        assert_eq!(
+            s.validate(&query::Query::Transaction{updates: Map::from_iter(vec![
+                (String::from("orders/1"), Map::from_iter(vec![(String::from("x"), b"y".to_vec())])),
+                (String::from("users/1"), Map::from_iter(vec![(String::from("age"), b"old".to_vec())]))
+            ])}),
+            Err(String::from(r#"column "age" in table "users/" must be of type int"#))
+        );
+
+        // Other tables, and tables without declarations, accept anything.
+        assert!(s.validate(&insert("orders/1", &[("anything", "x")])).is_ok());
+        assert!(s.validate(&insert("users/archive/1", &[("anything", "x")])).is_ok());
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let mut s = super::Schemas::new();
+        s.add_table(serde_json::from_str(r#"{
+            "prefix": "orders/",
+            "columns": [
+                {"name": "item", "required": true},
+                {"name": "status", "type": "string", "required": true, "default": "new"},
+                {"name": "created_at", "type": "int", "default_timestamp": true}
+            ]
+        }"#).unwrap());
+
+        let q = s.apply_defaults(insert("orders/1", &[("item", "book")]), 1234);
+        assert!(s.validate(&q).is_ok());
+        match q {
+            query::Query::Insert{set, ..} => {
+                assert_eq!(set["status"], b"new".to_vec());
+                assert_eq!(set["created_at"], b"1234".to_vec());
+            },
+            _ => panic!("expected an insert")
+        };
+
+        // Values which are provided win over the defaults, and updates
+        // are left alone.
+        match s.apply_defaults(insert("orders/2", &[("item", "pen"), ("status", "paid")]), 1) {
+            query::Query::Insert{set, ..} => assert_eq!(set["status"], b"paid".to_vec()),
+            _ => panic!("expected an insert")
+        };
+        match s.apply_defaults(query::Query::new_update("orders/1", vec![]), 1) {
+            query::Query::Update{set, ..} => assert!(set.is_empty()),
+            _ => panic!("expected an update")
+        };
+    }
+
+    #[test]
+    fn validates_composite_keys() {
+        let mut s = super::Schemas::new();
+        s.add_table(serde_json::from_str(r#"{"prefix": "events/", "key_parts": ["string", "u64"]}"#).unwrap());
+
+        let key = Key::builder().prefix("events/").string("alice").u64(42).build();
+        assert!(s.validate(&insert(&key, &[("type", "click")])).is_ok());
+        assert_eq!(
+            s.validate(&insert("events/alice|42", &[("type", "click")])),
+            Err(String::from(r#"row key doesn't fit table "events/": part 0 is a string without an end"#))
+        );
+        let key = Key::builder().prefix("events/").string("alice").build();
+        assert_eq!(
+            s.validate(&query::Query::new_update(&key, vec![])),
+            Err(String::from(r#"row key doesn't fit table "events/": part 1 isn't a u64"#))
+        );
+    }
+
+    #[test]
+    fn finds_column_declarations() {
+        let mut s = schemas();
+        s.add_table(serde_json::from_str(r#"{
+            "prefix": "sensors/",
+            "columns": [{"name": "reading", "dedup": true, "touch_column": "seen_at"}]
+        }"#).unwrap());
+
+        let reading = s.column("sensors/1", "reading").unwrap();
+        assert!(reading.dedup);
+        assert_eq!(reading.touch_column, Some(String::from("seen_at")));
+        assert!(!s.column("users/1", "name").unwrap().dedup);
+        assert!(s.column("users/1", "missing").is_none());
+        assert!(s.column("orders/1", "reading").is_none());
+    }
+
+    #[test]
+    fn checks_types() {
+        assert!(super::ColumnType::Float.accepts(b"-1.5"));
+        assert!(!super::ColumnType::Bool.accepts(b"yes"));
+        assert!(!super::ColumnType::String.accepts(&[0xff, 0xfe]));
+        assert!(super::ColumnType::Bytes.accepts(&[0xff, 0xfe]));
+    }
+}
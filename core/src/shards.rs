@@ -0,0 +1,146 @@
+/*
+    shards.rs
+
+    A shard map splits the rows of a cluster into key ranges, and says
+    which server holds each one. Every change to the map gets a higher
+    version, so that servers and clients can tell which of two maps is
+    newer. A server which is sent a row it doesn't hold refuses it with
+    WrongShard, along with the version of its own map, and clients which
+    have an older map then fetch the new one from /topology.
+
+        version: 3
+        shards:
+          - {start: "", end: "m", address: "db1:8080"}
+          - {start: "m", address: "db2:8080"}
+
+    The start of a range is included and the end isn't. An empty end
+    means that the range has no end.
+*/
+
+use query;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Shard {
+    #[serde(default)]
+    pub start: String,
+    #[serde(default)]
+    pub end: String,
+    pub address: String
+}
+
+impl Shard {
+    pub fn contains(&self, row: &str) -> bool {
+        row >= self.start.as_str() && (self.end.is_empty() || row < self.end.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ShardMap {
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub shards: Vec<Shard>
+}
+
+impl ShardMap {
+    // A cluster without a shard map isn't sharded, and every server
+    // holds every row.
+    pub fn is_enabled(&self) -> bool {
+        !self.shards.is_empty()
+    }
+
+    pub fn shard_for(&self, row: &str) -> Option<&Shard> {
+        self.shards.iter().find(|s| s.contains(row))
+    }
+
+    // Check that every shard has an address, and that no two shards'
+    // ranges overlap. Gaps are allowed, e.g. while a range is being moved.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut shards = self.shards.iter().collect::<Vec<_>>();
+        shards.sort_by(|a, b| a.start.cmp(&b.start));
+        for (i, s) in shards.iter().enumerate() {
+            if s.address.is_empty() {
+                return Err(format!("the shard starting at \"{}\" has no address", s.start));
+            }
+            if !s.end.is_empty() && s.end <= s.start {
+                return Err(format!("the shard starting at \"{}\" ends before it starts", s.start));
+            }
+            if let Some(next) = shards.get(i + 1) {
+                if s.end.is_empty() || s.end > next.start {
+                    return Err(format!("the shards starting at \"{}\" and \"{}\" overlap", s.start, next.start));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The rows which a query reads or writes, which decide where it's sent.
+// Queries over ranges of rows, or none at all, aren't routed.
+pub fn query_rows(q: &query::Query) -> Vec<&str> {
+    match *q {
+        query::Query::Select{row: ref r, ..} |
+        query::Query::Insert{row: ref r, ..} |
+        query::Query::Update{row: ref r, ..} |
+        query::Query::Append{row: ref r, ..} |
+        query::Query::SelectList{row: ref r, ..} |
+        query::Query::History{row: ref r, ..} |
+        query::Query::Describe{row: ref r} |
+        query::Query::Execute{row: ref r, ..} |
+        query::Query::Checksum{row: ref r} |
+        query::Query::Diff{row: ref r, ..} |
+        query::Query::SoftDelete{row: ref r} |
+        query::Query::Undelete{row: ref r} => vec![r.as_str()],
+        query::Query::Transaction{ref updates} => {
+            let mut rows = updates.keys().map(|r| r.as_str()).collect::<Vec<_>>();
+            rows.sort();
+            rows
+        },
+        query::Query::Explain(ref e) => query_rows(e),
+        _ => vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use query;
+
+    fn shard_map() -> super::ShardMap {
+        serde_json::from_str(r#"{
+            "version": 3,
+            "shards": [
+                {"start": "", "end": "m", "address": "db1:8080"},
+                {"start": "m", "address": "db2:8080"}
+            ]
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn finds_shards() {
+        let map = shard_map();
+        assert!(map.validate().is_ok());
+        assert_eq!(map.shard_for("apple").map(|s| s.address.as_str()), Some("db1:8080"));
+        assert_eq!(map.shard_for("m").map(|s| s.address.as_str()), Some("db2:8080"));
+        assert_eq!(map.shard_for("zebra").map(|s| s.address.as_str()), Some("db2:8080"));
+        assert!(!super::ShardMap::default().is_enabled());
+    }
+
+    #[test]
+    fn refuses_overlapping_shards() {
+        let mut map = shard_map();
+        map.shards[0].end = String::from("n");
+        assert_eq!(map.validate(), Err(String::from(r#"the shards starting at "" and "m" overlap"#)));
+
+        let mut map = shard_map();
+        map.shards[1].address = String::new();
+        assert!(map.validate().is_err());
+    }
+
+    #[test]
+    fn finds_query_rows() {
+        assert_eq!(super::query_rows(&query::Query::new_select("a", &["x"])), vec!["a"]);
+        assert_eq!(super::query_rows(&query::Query::parse(r#"{"transaction": {"updates": {"b": {"x": "1"}, "a": {"x": "2"}}}}"#).unwrap()), vec!["a", "b"]);
+        assert!(super::query_rows(&query::Query::Stats).is_empty());
+    }
+}
@@ -0,0 +1,25 @@
+/*
+    spans.rs
+
+    Spans record how long each part of a traced query took, like reading
+    a dtable or syncing the commit log. The database hands them to a
+    SpanSink, which can export them to a tracing system.
+*/
+
+// A Span is a timed piece of work which is part of a trace. The times
+// are in nanoseconds, from time::precise_time_ns(). An empty span_id
+// means that the span has no children, so its ID can be made up later.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: String,
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    pub attributes: Vec<(String, String)>
+}
+
+pub trait SpanSink: Send + Sync {
+    fn record(&self, span: Span);
+}
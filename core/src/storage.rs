@@ -0,0 +1,470 @@
+/*
+    storage.rs
+
+    This file contains the abstractions that the database uses to talk
+    to the filesystem and the clock. Normally these are backed by the
+    real disk and system time, but the simulated versions make it
+    possible to test crash recovery deterministically.
+*/
+
+use std;
+use std::io;
+use std::io::{Read, Write, Seek};
+use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+
+use time;
+use libc;
+
+// A StorageFile is an open file which can be read, written, and synced.
+pub trait StorageFile: Read + Write + Seek + Send {
+    fn sync(&self) -> Result<(), io::Error>;
+    fn len(&self) -> Result<u64, io::Error>;
+
+    // Tell the operating system that the file's cached pages won't be
+    // needed again soon. Pages which haven't been written back yet stay
+    // in the cache.
+    fn drop_cache(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    // Reserve space on disk for the file to grow to length bytes, without
+    // changing its size, so that it isn't fragmented as it's written.
+    fn allocate(&self, _length: u64) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    // Give back any space which was reserved past the end of the file.
+    fn trim(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+pub trait Storage: Send + Sync {
+    // Create a file for writing, truncating it if it already exists.
+    fn create(&self, path: &str) -> Result<Box<StorageFile>, io::Error>;
+
+    // Open an existing file for reading.
+    fn open(&self, path: &str) -> Result<Box<StorageFile>, io::Error>;
+
+    // Open a file for appending, creating it if it doesn't exist.
+    fn append(&self, path: &str) -> Result<Box<StorageFile>, io::Error>;
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error>;
+    fn remove(&self, path: &str) -> Result<(), io::Error>;
+    fn create_dir_all(&self, path: &str) -> Result<(), io::Error>;
+
+    // List the paths of the files in the directory with the extension.
+    fn list(&self, directory: &str, extension: &str) -> Result<Vec<String>, io::Error>;
+}
+
+pub trait Clock: Send + Sync {
+    // Returns the current time, in nanoseconds.
+    fn now(&self) -> u64;
+}
+
+pub struct DiskStorage;
+pub struct SystemClock;
+
+// Tells fallocate not to change the size of the file.
+#[cfg(target_os = "linux")]
+const FALLOC_FL_KEEP_SIZE: libc::c_int = 1;
+
+impl StorageFile for std::fs::File {
+    fn sync(&self) -> Result<(), io::Error> {
+        self.sync_all()
+    }
+
+    fn len(&self) -> Result<u64, io::Error> {
+        self.metadata().map(|m| m.len())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn drop_cache(&self) -> Result<(), io::Error> {
+        use std::os::unix::io::AsRawFd;
+        match unsafe { libc::posix_fadvise(self.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e))
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn allocate(&self, length: u64) -> Result<(), io::Error> {
+        use std::os::unix::io::AsRawFd;
+        match unsafe { libc::fallocate(self.as_raw_fd(), FALLOC_FL_KEEP_SIZE, 0, length as libc::off_t) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error())
+        }
+    }
+
+    // Truncating the file to its own size frees the blocks past its end.
+    fn trim(&self) -> Result<(), io::Error> {
+        self.set_len(self.metadata()?.len())
+    }
+}
+
+impl Storage for DiskStorage {
+    fn create(&self, path: &str) -> Result<Box<StorageFile>, io::Error> {
+        Ok(Box::new(std::fs::File::create(path)?))
+    }
+
+    fn open(&self, path: &str) -> Result<Box<StorageFile>, io::Error> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+
+    fn append(&self, path: &str) -> Result<Box<StorageFile>, io::Error> {
+        Ok(Box::new(
+            std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)?
+        ))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), io::Error> {
+        std::fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<(), io::Error> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn list(&self, directory: &str, extension: &str) -> Result<Vec<String>, io::Error> {
+        let mut paths = vec![];
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if let Some(p) = path.to_str() {
+                if p.ends_with(extension) {
+                    paths.push(p.to_owned());
+                }
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        time::precise_time_ns()
+    }
+}
+
+// The contents of a simulated file. Data which hasn't been synced yet is
+// lost when the simulated machine crashes.
+struct MemoryNode {
+    data: Vec<u8>,
+    synced: Option<Vec<u8>>
+}
+
+// The MemoryStorage keeps every file in memory, and keeps track of which
+// writes have been synced. It can also be told to start failing after a
+// number of operations, to simulate a crash at that point, or to fail
+// opening files, to simulate read errors.
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, Arc<Mutex<MemoryNode>>>>,
+    remaining_operations: Arc<Mutex<Option<usize>>>,
+    failing_opens: Mutex<usize>
+}
+
+pub struct MemoryFile {
+    node: Arc<Mutex<MemoryNode>>,
+    remaining_operations: Arc<Mutex<Option<usize>>>,
+    position: u64,
+    append: bool
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage{
+            files: Mutex::new(BTreeMap::new()),
+            remaining_operations: Arc::new(Mutex::new(None)),
+            failing_opens: Mutex::new(0)
+        }
+    }
+
+    // The next count opens of files for reading will fail.
+    pub fn fail_opens(&self, count: usize) {
+        *self.failing_opens.lock().unwrap() = count;
+    }
+
+    // After this many more operations which modify the filesystem (creates,
+    // renames, removals and syncs), every operation will fail.
+    pub fn fail_after(&self, operations: usize) {
+        *self.remaining_operations.lock().unwrap() = Some(operations);
+    }
+
+    // Simulate the machine crashing: anything that wasn't synced is lost,
+    // and the storage starts working again.
+    pub fn crash(&self) {
+        let mut files = self.files.lock().unwrap();
+        let lost = files.iter()
+            .filter(|&(_, node)| node.lock().unwrap().synced.is_none())
+            .map(|(path, _)| path.to_owned())
+            .collect::<Vec<_>>();
+        for path in lost {
+            files.remove(&path);
+        }
+
+        for node in files.values() {
+            let mut n = node.lock().unwrap();
+            n.data = n.synced.clone().unwrap();
+        }
+
+        *self.remaining_operations.lock().unwrap() = None;
+    }
+
+    fn check_operation(&self) -> Result<(), io::Error> {
+        check_operation(&self.remaining_operations)
+    }
+
+    fn file(&self, node: Arc<Mutex<MemoryNode>>, append: bool) -> Box<StorageFile> {
+        Box::new(MemoryFile{
+            node: node,
+            remaining_operations: self.remaining_operations.clone(),
+            position: 0,
+            append: append
+        })
+    }
+}
+
+fn check_operation(remaining: &Mutex<Option<usize>>) -> Result<(), io::Error> {
+    let mut remaining = remaining.lock().unwrap();
+    match *remaining {
+        Some(0) => Err(io::Error::new(io::ErrorKind::Other, "simulated crash")),
+        Some(n) => {
+            *remaining = Some(n - 1);
+            Ok(())
+        },
+        None => Ok(())
+    }
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, path: &str) -> Result<Box<StorageFile>, io::Error> {
+        self.check_operation()?;
+        let mut files = self.files.lock().unwrap();
+        let node = files.entry(path.to_owned())
+            .or_insert_with(|| Arc::new(Mutex::new(MemoryNode{data: vec![], synced: None})))
+            .clone();
+        node.lock().unwrap().data.clear();
+        Ok(self.file(node, false))
+    }
+
+    fn open(&self, path: &str) -> Result<Box<StorageFile>, io::Error> {
+        {
+            let mut failing = self.failing_opens.lock().unwrap();
+            if *failing > 0 {
+                *failing -= 1;
+                return Err(io::Error::new(io::ErrorKind::Other, "simulated read error"));
+            }
+        }
+
+        match self.files.lock().unwrap().get(path) {
+            Some(node) => Ok(self.file(node.clone(), false)),
+            None       => Err(not_found(path))
+        }
+    }
+
+    fn append(&self, path: &str) -> Result<Box<StorageFile>, io::Error> {
+        let mut files = self.files.lock().unwrap();
+        let node = files.entry(path.to_owned())
+            .or_insert_with(|| Arc::new(Mutex::new(MemoryNode{data: vec![], synced: None})))
+            .clone();
+        Ok(self.file(node, true))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        self.check_operation()?;
+        let mut files = self.files.lock().unwrap();
+        match files.remove(from) {
+            Some(node) => {
+                files.insert(to.to_owned(), node);
+                Ok(())
+            },
+            None => Err(not_found(from))
+        }
+    }
+
+    fn remove(&self, path: &str) -> Result<(), io::Error> {
+        self.check_operation()?;
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None    => Err(not_found(path))
+        }
+    }
+
+    // Directories are implied by the paths of the files within them.
+    fn create_dir_all(&self, _: &str) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn list(&self, directory: &str, extension: &str) -> Result<Vec<String>, io::Error> {
+        let prefix = format!("{}/", directory);
+        Ok(self.files.lock().unwrap()
+            .keys()
+            .filter(|p| p.starts_with(&prefix) && !p[prefix.len()..].contains('/') && p.ends_with(extension))
+            .cloned()
+            .collect())
+    }
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let node = self.node.lock().unwrap();
+        let start = std::cmp::min(self.position as usize, node.data.len());
+        let n = std::cmp::min(buf.len(), node.data.len() - start);
+        buf[..n].copy_from_slice(&node.data[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let mut node = self.node.lock().unwrap();
+        if self.append {
+            self.position = node.data.len() as u64;
+        }
+
+        let start = self.position as usize;
+        if node.data.len() < start + buf.len() {
+            node.data.resize(start + buf.len(), 0);
+        }
+        node.data[start..start + buf.len()].copy_from_slice(buf);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> Result<u64, io::Error> {
+        let length = self.node.lock().unwrap().data.len() as i64;
+        let position = match pos {
+            io::SeekFrom::Start(n)      => n as i64,
+            io::SeekFrom::End(n)        => length + n,
+            io::SeekFrom::Current(n)    => self.position as i64 + n
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn sync(&self) -> Result<(), io::Error> {
+        check_operation(&self.remaining_operations)?;
+        let mut node = self.node.lock().unwrap();
+        node.synced = Some(node.data.clone());
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64, io::Error> {
+        Ok(self.node.lock().unwrap().data.len() as u64)
+    }
+}
+
+// The SimulatedClock only moves forward when it's told to.
+pub struct SimulatedClock {
+    now: Mutex<u64>
+}
+
+impl SimulatedClock {
+    pub fn new(start: u64) -> SimulatedClock {
+        SimulatedClock{
+            now: Mutex::new(start)
+        }
+    }
+
+    pub fn advance(&self, nanoseconds: u64) {
+        *self.now.lock().unwrap() += nanoseconds;
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> u64 {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use tempdir;
+    use super::Storage;
+
+    #[test]
+    fn crash_loses_unsynced_data() {
+        let storage = super::MemoryStorage::new();
+
+        let mut f = storage.create("/sim/synced").unwrap();
+        f.write_all(b"hello").unwrap();
+        f.sync().unwrap();
+        f.write_all(b" world").unwrap();
+
+        let mut g = storage.create("/sim/unsynced").unwrap();
+        g.write_all(b"lost").unwrap();
+
+        storage.crash();
+
+        let mut contents = String::new();
+        storage.open("/sim/synced").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+        assert!(storage.open("/sim/unsynced").is_err());
+        assert_eq!(storage.list("/sim", "").unwrap(), vec!["/sim/synced"]);
+    }
+
+    #[test]
+    fn allocates_without_changing_size() {
+        let directory = tempdir::TempDir::new("storage").unwrap();
+        let path = format!("{}/allocated", directory.path());
+        let mut f = super::DiskStorage.create(&path).unwrap();
+        f.allocate(1 << 20).unwrap();
+        assert_eq!(f.len().unwrap(), 0);
+
+        f.write_all(b"hello").unwrap();
+        f.trim().unwrap();
+        assert_eq!(f.len().unwrap(), 5);
+        let mut contents = String::new();
+        super::DiskStorage.open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+    }
+
+    #[test]
+    fn fails_after_operations() {
+        let storage = super::MemoryStorage::new();
+        storage.fail_after(1);
+        storage.create("/sim/a").unwrap();
+        assert!(storage.create("/sim/b").is_err());
+
+        storage.crash();
+        storage.create("/sim/b").unwrap();
+    }
+
+    #[test]
+    fn drops_synced_files_from_the_cache() {
+        let mut f = super::DiskStorage.create("./data/drop_cache.bin").unwrap();
+        f.write_all(&[0; 4096]).unwrap();
+        f.sync().unwrap();
+        f.drop_cache().unwrap();
+
+        let mut contents = vec![];
+        super::DiskStorage.open("./data/drop_cache.bin").unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 4096);
+    }
+}
@@ -0,0 +1,69 @@
+/*
+    tempdir.rs
+
+    A TempDir is a fresh directory under /tmp/largetable, which is removed
+    along with everything in it when the TempDir is dropped, so that the
+    databases created by tests don't pile up in /tmp. Set the
+    LARGETABLE_KEEP_TEMP_DIRS environment variable to keep them, e.g. to
+    look at the files that a failing test left behind.
+*/
+
+use std::env;
+use std::fs;
+use std::io;
+
+use time;
+
+pub const KEEP_TEMP_DIRS_ENV: &'static str = "LARGETABLE_KEEP_TEMP_DIRS";
+
+pub struct TempDir {
+    path: String
+}
+
+impl TempDir {
+    // Create a directory named after the prefix, e.g. "largetable" gives
+    // /tmp/largetable/largetable-<ns>.
+    pub fn new(prefix: &str) -> io::Result<TempDir> {
+        let path = format!("/tmp/largetable/{}-{}", prefix, time::precise_time_ns());
+        fs::create_dir_all(&path)?;
+        Ok(TempDir{
+            path: path
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if env::var_os(KEEP_TEMP_DIRS_ENV).is_some() {
+            info!("Keeping temporary directory {}.", self.path);
+            return;
+        }
+
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Unable to remove temporary directory {}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn removes_directory_when_dropped() {
+        let directory = super::TempDir::new("tempdir").unwrap();
+        let path = directory.path().to_owned();
+        fs::create_dir_all(format!("{}/nested", path)).unwrap();
+        fs::File::create(format!("{}/nested/file", path)).unwrap();
+        assert!(Path::new(&path).exists());
+
+        drop(directory);
+        assert_eq!(Path::new(&path).exists(), env::var_os(super::KEEP_TEMP_DIRS_ENV).is_some());
+    }
+}
@@ -0,0 +1,270 @@
+/*
+    transform.rs
+
+    Transforms rewrite the values of a table's columns as they're written,
+    before they reach the memtable or the commit log, e.g. to normalize
+    them or to cap their size. Each kind of transform is compiled in and
+    registered by name, and tables pick the ones they use in the config:
+
+        tables:
+          - prefix: "users/"
+            transforms:
+              - {column: "email", transform: "lowercase"}
+              - {column: "bio", transform: "max_bytes", options: {limit: "1024"}}
+
+    The built in transforms are lowercase, trim and max_bytes. Applications
+    which embed the engine can register their own with Transforms::register,
+    e.g. to hash columns which hold personal information.
+*/
+
+use std::collections::HashMap as Map;
+use std::str;
+
+use query;
+use schema;
+
+// A Transform returns the value to store in place of the one that was
+// written, or the reason that the write should be refused.
+pub trait Transform: Send {
+    fn apply(&self, row: &str, column: &str, value: Vec<u8>) -> Result<Vec<u8>, String>;
+}
+
+// Creates a transform from the options that a table gives it.
+pub type Factory = fn(&Map<String, String>) -> Result<Box<Transform>, String>;
+
+struct ColumnTransform {
+    column: String,
+    name: String,
+    transform: Box<Transform>
+}
+
+struct TableTransforms {
+    prefix: String,
+    columns: Vec<ColumnTransform>
+}
+
+pub struct Transforms {
+    factories: Map<String, Factory>,
+    tables: Vec<TableTransforms>
+}
+
+impl Transforms {
+    pub fn new() -> Transforms {
+        let mut t = Transforms{
+            factories: Map::new(),
+            tables: vec![]
+        };
+        t.register("lowercase", new_lowercase);
+        t.register("trim", new_trim);
+        t.register("max_bytes", new_max_bytes);
+        t
+    }
+
+    pub fn register(&mut self, name: &str, factory: Factory) {
+        self.factories.insert(name.to_owned(), factory);
+    }
+
+    // Set up the transforms that the table asks for, replacing the ones
+    // it had before. Fails if a transform isn't registered, or doesn't
+    // accept its options.
+    pub fn add_table(&mut self, table: &schema::TableSchema) -> Result<(), String> {
+        let mut columns = vec![];
+        for t in table.transforms.iter() {
+            let factory = match self.factories.get(&t.transform) {
+                Some(f) => f,
+                None    => return Err(format!("unknown transform \"{}\" in table \"{}\"", t.transform, table.prefix))
+            };
+            let transform = factory(&t.options)
+                .map_err(|e| format!("transform \"{}\" in table \"{}\": {}", t.transform, table.prefix, e))?;
+            columns.push(ColumnTransform{
+                column: t.column.clone(),
+                name: t.transform.clone(),
+                transform: transform
+            });
+        }
+
+        // Tables without transforms are kept too, so that they still
+        // shadow shorter prefixes, the same way as schemas do.
+        self.tables.retain(|t| t.prefix != table.prefix);
+        self.tables.push(TableTransforms{
+            prefix: table.prefix.clone(),
+            columns: columns
+        });
+        Ok(())
+    }
+
+    // Rewrite the updates with the transforms of the row's table, which
+    // is the one with the longest matching prefix. A column's transforms
+    // run in the order they're listed.
+    pub fn apply(&self, row: &str, mut updates: Vec<query::MUpdate>) -> Result<Vec<query::MUpdate>, String> {
+        let table = match self.tables.iter()
+            .filter(|t| row.starts_with(t.prefix.as_str()))
+            .max_by_key(|t| t.prefix.len()) {
+            Some(t) if !t.columns.is_empty() => t,
+            _ => return Ok(updates)
+        };
+
+        for update in updates.iter_mut() {
+            for c in table.columns.iter().filter(|c| c.column == update.key) {
+                let value = ::std::mem::replace(&mut update.value, vec![]);
+                update.value = c.transform.apply(row, &update.key, value).map_err(|e| format!(
+                    "column \"{}\" in table \"{}\" was refused by transform \"{}\": {}",
+                    update.key, table.prefix, c.name, e
+                ))?;
+            }
+        }
+        Ok(updates)
+    }
+}
+
+fn utf8(value: &[u8]) -> Result<&str, String> {
+    str::from_utf8(value).map_err(|_| String::from("value isn't valid UTF-8"))
+}
+
+struct Lowercase;
+
+impl Transform for Lowercase {
+    fn apply(&self, _: &str, _: &str, value: Vec<u8>) -> Result<Vec<u8>, String> {
+        Ok(utf8(&value)?.to_lowercase().into_bytes())
+    }
+}
+
+fn new_lowercase(_: &Map<String, String>) -> Result<Box<Transform>, String> {
+    Ok(Box::new(Lowercase))
+}
+
+struct Trim;
+
+impl Transform for Trim {
+    fn apply(&self, _: &str, _: &str, value: Vec<u8>) -> Result<Vec<u8>, String> {
+        Ok(utf8(&value)?.trim().as_bytes().to_vec())
+    }
+}
+
+fn new_trim(_: &Map<String, String>) -> Result<Box<Transform>, String> {
+    Ok(Box::new(Trim))
+}
+
+// Refuses values longer than the limit, or cuts them down to it if the
+// truncate option is "true".
+struct MaxBytes {
+    limit: usize,
+    truncate: bool
+}
+
+impl Transform for MaxBytes {
+    fn apply(&self, _: &str, _: &str, mut value: Vec<u8>) -> Result<Vec<u8>, String> {
+        if value.len() <= self.limit {
+            return Ok(value);
+        }
+        if !self.truncate {
+            return Err(format!("value is longer than {} bytes", self.limit));
+        }
+        value.truncate(self.limit);
+        Ok(value)
+    }
+}
+
+fn new_max_bytes(options: &Map<String, String>) -> Result<Box<Transform>, String> {
+    let limit = match options.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(l)) => l,
+        _           => return Err(String::from("the limit option must be a number of bytes"))
+    };
+    let truncate = match options.get("truncate").map(|t| t.as_str()) {
+        None | Some("false")    => false,
+        Some("true")            => true,
+        Some(_)                 => return Err(String::from("the truncate option must be true or false"))
+    };
+    Ok(Box::new(MaxBytes{limit: limit, truncate: truncate}))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap as Map;
+    use serde_json;
+    use query;
+
+    fn transforms() -> super::Transforms {
+        let mut t = super::Transforms::new();
+        t.add_table(&serde_json::from_str(r#"{
+            "prefix": "users/",
+            "transforms": [
+                {"column": "email", "transform": "trim"},
+                {"column": "email", "transform": "lowercase"},
+                {"column": "bio", "transform": "max_bytes", "options": {"limit": "5"}},
+                {"column": "motto", "transform": "max_bytes", "options": {"limit": "3", "truncate": "true"}}
+            ]
+        }"#).unwrap()).unwrap();
+        t.add_table(&serde_json::from_str(r#"{"prefix": "users/archive/"}"#).unwrap()).unwrap();
+        t
+    }
+
+    fn apply(t: &super::Transforms, row: &str, values: &[(&str, &str)]) -> Result<Vec<(String, String)>, String> {
+        let updates = values.iter()
+            .map(|&(k, v)| query::MUpdate::new(k, v.as_bytes().to_vec()))
+            .collect();
+        t.apply(row, updates).map(|u| u.into_iter()
+            .map(|u| (u.key, String::from_utf8(u.value).unwrap()))
+            .collect())
+    }
+
+    #[test]
+    fn transforms_values() {
+        let t = transforms();
+        assert_eq!(
+            apply(&t, "users/1", &[("email", " Alice@Example.COM "), ("name", " Alice "), ("motto", "carpe diem")]),
+            Ok(vec![
+                (String::from("email"), String::from("alice@example.com")),
+                (String::from("name"), String::from(" Alice ")),
+                (String::from("motto"), String::from("car"))
+            ])
+        );
+        assert_eq!(
+            apply(&t, "users/1", &[("bio", "too long")]),
+            Err(String::from(r#"column "bio" in table "users/" was refused by transform "max_bytes": value is longer than 5 bytes"#))
+        );
+
+        // Other tables, including ones under a longer prefix, are left alone.
+        assert_eq!(
+            apply(&t, "users/archive/1", &[("email", "Bob")]),
+            Ok(vec![(String::from("email"), String::from("Bob"))])
+        );
+        assert_eq!(
+            apply(&t, "orders/1", &[("email", "Bob")]),
+            Ok(vec![(String::from("email"), String::from("Bob"))])
+        );
+    }
+
+    #[test]
+    fn checks_transform_config() {
+        let mut t = super::Transforms::new();
+        assert_eq!(
+            t.add_table(&serde_json::from_str(r#"{"prefix": "a/", "transforms": [{"column": "x", "transform": "rot13"}]}"#).unwrap()),
+            Err(String::from(r#"unknown transform "rot13" in table "a/""#))
+        );
+        assert!(t.add_table(&serde_json::from_str(r#"{"prefix": "a/", "transforms": [{"column": "x", "transform": "max_bytes"}]}"#).unwrap()).is_err());
+    }
+
+    struct Redact;
+
+    impl super::Transform for Redact {
+        fn apply(&self, _: &str, _: &str, _: Vec<u8>) -> Result<Vec<u8>, String> {
+            Ok(b"<redacted>".to_vec())
+        }
+    }
+
+    fn new_redact(_: &Map<String, String>) -> Result<Box<super::Transform>, String> {
+        Ok(Box::new(Redact))
+    }
+
+    #[test]
+    fn can_register_transforms() {
+        let mut t = super::Transforms::new();
+        t.register("redact", new_redact);
+        t.add_table(&serde_json::from_str(r#"{"prefix": "", "transforms": [{"column": "ssn", "transform": "redact"}]}"#).unwrap()).unwrap();
+        assert_eq!(
+            apply(&t, "anything", &[("ssn", "123-45-6789")]),
+            Ok(vec![(String::from("ssn"), String::from("<redacted>"))])
+        );
+    }
+}
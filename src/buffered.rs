@@ -0,0 +1,173 @@
+/*
+    buffered.rs
+
+    A BufferedWriter collects updates in memory and sends them together,
+    as a single transaction, for writers which update lots of columns a
+    few at a time. Updates to a column which is already waiting replace
+    the waiting value, so only the latest one is sent.
+
+    The buffer is flushed when it gets too big, when its oldest update
+    has waited too long (checked whenever the writer is used), when it's
+    flushed explicitly, and when the writer is dropped. A flush which
+    fails keeps its updates, so that the next flush retries them.
+*/
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use query;
+use LargeClient;
+
+pub struct BufferedWriter {
+    client: LargeClient,
+    rows: HashMap<String, HashMap<String, Vec<u8>>>,
+    bytes: usize,
+    oldest: Option<Instant>,
+
+    // The buffer is flushed once it holds this many rows or bytes of
+    // keys and values, or once its oldest update is max_delay old.
+    pub max_rows: usize,
+    pub max_bytes: usize,
+    pub max_delay: Duration
+}
+
+// What happened to the updates sent by a flush.
+#[derive(Debug)]
+pub struct Flush {
+    pub rows: usize,
+    pub columns: usize,
+    pub result: query::QueryResult
+}
+
+impl Flush {
+    pub fn is_ok(&self) -> bool {
+        match self.result {
+            query::QueryResult::Done => true,
+            _                        => false
+        }
+    }
+}
+
+impl BufferedWriter {
+    pub fn new(client: &LargeClient) -> BufferedWriter {
+        BufferedWriter{
+            client: client.clone(),
+            rows: HashMap::new(),
+            bytes: 0,
+            oldest: None,
+            max_rows: 1000,
+            max_bytes: 1 << 20,
+            max_delay: Duration::from_millis(100)
+        }
+    }
+
+    // Buffer the updates to the row. If that fills up the buffer, or it
+    // was due to be flushed anyway, it's flushed, and the flush is
+    // returned.
+    pub fn update(&mut self, row: &str, updates: Vec<query::MUpdate>) -> Option<Flush> {
+        if !self.rows.contains_key(row) {
+            self.bytes += row.len();
+        }
+        {
+            let columns = self.rows.entry(row.to_owned()).or_insert_with(HashMap::new);
+            for u in updates {
+                let key_length = u.key.len();
+                self.bytes += key_length + u.value.len();
+                if let Some(old) = columns.insert(u.key, u.value) {
+                    self.bytes -= key_length + old.len();
+                }
+            }
+        }
+        if self.oldest.is_none() {
+            self.oldest = Some(Instant::now());
+        }
+
+        match self.rows.len() >= self.max_rows || self.bytes >= self.max_bytes {
+            true  => self.flush(),
+            false => self.poll()
+        }
+    }
+
+    // Flush the buffer if its oldest update has waited for max_delay.
+    // Writers which can go quiet should call this now and then, so that
+    // their last updates aren't held back.
+    pub fn poll(&mut self) -> Option<Flush> {
+        match self.oldest {
+            Some(t) if t.elapsed() >= self.max_delay => self.flush(),
+            _ => None
+        }
+    }
+
+    // Send everything in the buffer, or return None if it's empty.
+    pub fn flush(&mut self) -> Option<Flush> {
+        if self.rows.is_empty() {
+            return None;
+        }
+
+        let rows = self.rows.len();
+        let columns = self.rows.values().map(|c| c.len()).sum();
+        let result = self.client.query(query::Query::Transaction{updates: self.rows.clone()});
+        if let query::QueryResult::Done = result {
+            self.rows.clear();
+            self.bytes = 0;
+            self.oldest = None;
+        }
+
+        Some(Flush{
+            rows: rows,
+            columns: columns,
+            result: result
+        })
+    }
+
+    // The number of rows and bytes waiting to be flushed.
+    pub fn pending(&self) -> (usize, usize) {
+        (self.rows.len(), self.bytes)
+    }
+}
+
+impl Drop for BufferedWriter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use query;
+    use LargeClient;
+
+    #[test]
+    fn coalesces_updates() {
+        let mut writer = super::BufferedWriter::new(&LargeClient::new("localhost:8080").unwrap());
+        writer.max_delay = Duration::from_secs(3600);
+        assert!(writer.update("row", vec![query::MUpdate::new("a", b"1".to_vec())]).is_none());
+        assert!(writer.update("row", vec![
+            query::MUpdate::new("a", b"22".to_vec()),
+            query::MUpdate::new("b", b"3".to_vec())
+        ]).is_none());
+        assert_eq!(writer.pending(), (1, "row".len() + "a22".len() + "b3".len()));
+        assert_eq!(writer.rows["row"]["a"], b"22".to_vec());
+
+        // Clear the buffer, so that dropping the writer doesn't send it.
+        writer.rows.clear();
+    }
+
+    #[test]
+    fn keeps_updates_when_flushes_fail() {
+        let mut writer = super::BufferedWriter::new(&LargeClient::new("localhost:1").unwrap());
+        writer.max_rows = 2;
+        writer.max_delay = Duration::from_secs(3600);
+        assert!(writer.update("a", vec![query::MUpdate::new("x", b"1".to_vec())]).is_none());
+
+        let flush = writer.update("b", vec![query::MUpdate::new("x", b"2".to_vec())]).unwrap();
+        assert!(!flush.is_ok());
+        assert_eq!((flush.rows, flush.columns), (2, 2));
+        assert_eq!(writer.pending().0, 2);
+
+        writer.rows.clear();
+        assert!(writer.flush().is_none());
+    }
+}
@@ -9,18 +9,50 @@ extern crate time;
 extern crate rand;
 extern crate hyper;
 extern crate getopts;
+extern crate serde_json;
 extern crate largeclient;
+extern crate largetable_core;
 
 use largeclient::query as query;
+use largetable_core::migration;
+use largetable_core::export;
+use largetable_core::scan;
+use largetable_core::shards;
+use largetable_core::keys;
 use std::env;
+use std::fs;
 use std::io;
-
-mod generated;
+use std::io::{Read, Write};
+use std::mem;
+use std::process;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 
 use linefeed::{Reader, ReadResult};
+use linefeed::complete::{Completer, Completion};
+use linefeed::terminal::Terminal;
+
+// The number of lines of history which are kept between sessions, and
+// of recently used row keys which are offered as completions.
+const HISTORY_LINES: usize = 1000;
+const RECENT_KEYS: usize = 100;
+
+// The most row keys fetched from the server for a completion.
+const COMPLETION_KEYS: u64 = 20;
+
+// The rows shown on each page of the scan browser, and the most
+// characters shown in each of its cells.
+const SCAN_PAGE_ROWS: u64 = 20;
+const MAX_CELL_WIDTH: usize = 30;
+
+const KEYWORDS: &'static [&'static str] = &[
+    "select", "insert", "update", "append", "scan", "list_keys",
+    "row", "get", "set", "start", "end", "limit"
+];
 
 fn print_usage(program: &str, opts: getopts::Options) {
-    let brief = format!("Usage: {} HOSTNAME:PORT [options]", program);
+    let brief = format!("Usage: {} HOSTNAME:PORT|unix:PATH [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
@@ -28,6 +60,12 @@ struct StdinSource {}
 
 trait LineSource {
     fn next_line(&mut self) -> Option<String>;
+
+    // Called with true while a query is spread over several lines.
+    fn set_continued(&mut self, _continued: bool) {}
+
+    // Called with the row keys of each query which is run.
+    fn remember_keys(&mut self, _keys: Vec<String>) {}
 }
 
 impl LineSource for StdinSource {
@@ -49,7 +87,9 @@ impl StdinSource {
 }
 
 struct CLISource {
-    reader: Reader<linefeed::terminal::DefaultTerminal>
+    reader: Reader<linefeed::terminal::DefaultTerminal>,
+    history_file: Option<String>,
+    recent_keys: Rc<RefCell<VecDeque<String>>>
 }
 
 impl LineSource for CLISource {
@@ -59,33 +99,125 @@ impl LineSource for CLISource {
                 // Record the command history, if the command isn't blank.
                 if !input.trim().is_empty() {
                     self.reader.add_history(input.clone());
+                    self.save_history(&input);
                 }
                 Some(input)
             },
             _ => None
         }
     }
+
+    fn set_continued(&mut self, continued: bool) {
+        self.reader.set_prompt(match continued {
+            true  => "       ...> ",
+            false => "largetable> "
+        });
+    }
+
+    fn remember_keys(&mut self, keys: Vec<String>) {
+        let mut recent = self.recent_keys.borrow_mut();
+        for key in keys {
+            recent.retain(|k| *k != key);
+            recent.push_front(key);
+        }
+        recent.truncate(RECENT_KEYS);
+    }
 }
 
 impl CLISource {
-    fn new() -> CLISource {
+    fn new(client: &largeclient::LargeClient) -> CLISource {
         println!("largetable-cli v{}", env!("CARGO_PKG_VERSION"));
         let mut reader = Reader::new("largetable").unwrap();
         reader.set_prompt("largetable> ");
+
+        let recent_keys = Rc::new(RefCell::new(VecDeque::new()));
+        reader.set_completer(Rc::new(QueryCompleter{
+            client: client.clone(),
+            recent_keys: recent_keys.clone()
+        }));
+
+        // The history from earlier sessions is kept in the home directory.
+        let history_file = env::var("HOME").ok().map(|h| format!("{}/.largetable_history", h));
+        if let Some(ref path) = history_file {
+            let mut body = String::new();
+            if fs::File::open(path).and_then(|mut f| f.read_to_string(&mut body)).is_ok() {
+                let lines = body.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>();
+                for line in &lines[lines.len().saturating_sub(HISTORY_LINES)..] {
+                    reader.add_history(line.to_string());
+                }
+            }
+        }
+
         CLISource{
-            reader: reader
+            reader: reader,
+            history_file: history_file,
+            recent_keys: recent_keys
+        }
+    }
+
+    // Append the line to the history file. The CLI still works if it
+    // can't be written, just without saving its history.
+    fn save_history(&self, line: &str) {
+        if let Some(ref path) = self.history_file {
+            fs::OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut f| writeln!(f, "{}", line))
+                .unwrap_or(());
         }
     }
 }
 
+// Completes query keywords, and row keys: ones used recently, and ones
+// on the server which start with what's been typed so far.
+struct QueryCompleter {
+    client: largeclient::LargeClient,
+    recent_keys: Rc<RefCell<VecDeque<String>>>
+}
+
+impl<Term: Terminal> Completer<Term> for QueryCompleter {
+    fn complete(&self, word: &str, _reader: &Reader<Term>, _start: usize, _end: usize) -> Option<Vec<Completion>> {
+        let server_keys = match word {
+            "" => vec![],
+            w  => match self.client.query(query::Query::ListKeys{start: w.to_owned(), limit: COMPLETION_KEYS}) {
+                query::QueryResult::Keys{keys: k} => k,
+                _ => vec![]
+            }
+        };
+        let candidates = completions(word, &self.recent_keys.borrow(), &server_keys);
+        Some(candidates.into_iter().map(Completion::simple).collect())
+    }
+}
+
+// The keywords and keys which start with the word, without duplicates.
+fn completions(word: &str, recent_keys: &VecDeque<String>, server_keys: &[String]) -> Vec<String> {
+    let mut candidates: Vec<String> = vec![];
+    let words = KEYWORDS.iter().map(|k| k.to_string())
+        .chain(recent_keys.iter().cloned())
+        .chain(server_keys.iter().cloned());
+    for w in words {
+        if w.starts_with(word) && !candidates.contains(&w) {
+            candidates.push(w);
+        }
+    }
+    candidates
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
     let mut opts = getopts::Options::new();
     opts.optflag("s", "stdin", "read input from stdin");
+    opts.optopt("e", "execute", "run this query, print the result and exit", "QUERY");
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("v", "version", "print the version number");
+    opts.optopt("", "move-to", "move the rows in --start..--end to this host", "HOSTNAME:PORT");
+    opts.optopt("", "start", "the first row to move", "ROW");
+    opts.optopt("", "end", "the row after the last one to move (default: no limit)", "ROW");
+    opts.optopt("", "migrate", "rename columns or map their values, as described in the JSON file", "FILE");
+    opts.optopt("", "data", "with --migrate, migrate this data directory instead of a running server", "DIRECTORY");
+    opts.optopt("", "export", "with --data, export the data directory to this file as a SQL script for SQLite", "FILE");
+    opts.optopt("", "restore-archive", "with --data, replay the commit log segments archived in this directory into a restored data directory", "DIRECTORY");
+    opts.optopt("", "as-of", "with --export or --restore-archive, use the data as it was at this time, in nanoseconds since the epoch (default: now)", "TIMESTAMP");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -100,6 +232,15 @@ fn main() {
         println!("{}", env!("CARGO_PKG_VERSION"));
         return;
     }
+    if let (Some(file), Some(directory)) = (matches.opt_str("migrate"), matches.opt_str("data")) {
+        return migrate_offline(&file, &directory);
+    }
+    if let (Some(file), Some(directory)) = (matches.opt_str("export"), matches.opt_str("data")) {
+        return export_offline(&file, &directory, matches.opt_str("as-of"));
+    }
+    if let (Some(archive), Some(directory)) = (matches.opt_str("restore-archive"), matches.opt_str("data")) {
+        return restore_offline(&archive, &directory, matches.opt_str("as-of"));
+    }
     let hostname = if !matches.free.is_empty() {
         matches.free[0].clone()
     } else {
@@ -107,30 +248,459 @@ fn main() {
         return;
     };
 
+    if let Some(file) = matches.opt_str("migrate") {
+        return migrate_online(&file, &hostname);
+    }
+
+    let client = connect(&hostname);
+    if let Some(destination) = matches.opt_str("move-to") {
+        let start = matches.opt_str("start").unwrap_or(String::new());
+        let end = matches.opt_str("end").unwrap_or(String::new());
+        return move_range(&client, &connect(&destination), &start, &end);
+    }
+
+    if let Some(q) = matches.opt_str("execute") {
+        if run_query(&client, &q).is_none() {
+            process::exit(1);
+        }
+        return;
+    }
+
     let mut source: Box<LineSource> = if matches.opt_present("s") {
         Box::new(StdinSource::new())
     } else {
-        Box::new(CLISource::new())
+        Box::new(CLISource::new(&client))
     };
 
-    let client = largeclient::LargeClient::new(hostname.as_str()).unwrap();
+    let mut buffer = QueryBuffer::new();
+    while let Some(input) = source.next_line() {
+        // Commands are only recognized at the start of a query.
+        if buffer.is_empty() {
+            match input.trim() {
+                "exit" => {
+                    println!("bye!");
+                    break;
+                },
+                x if x.starts_with("\\i ") => {
+                    run_file(&client, x["\\i ".len()..].trim());
+                    continue;
+                },
+                x if x == "scan" || x.starts_with("scan ") => {
+                    let mut args = x["scan".len()..].split_whitespace();
+                    let prefix = args.next().unwrap_or("");
+                    let columns = args.next()
+                        .map(|c| c.split(',').map(|c| c.to_owned()).collect::<Vec<_>>())
+                        .unwrap_or(vec![]);
+                    browse(&client, &mut *source, prefix, &columns);
+                    continue;
+                },
+                _ => ()
+            }
+        }
 
-    while let Some(ref input) = source.next_line() {
-        // Read the input and process the query.
-        match input.as_str() {
-            x if x == "exit" => {
-                println!("bye!");
-                break;
-            },
-            x => {
-                match query::Query::parse(x) {
-                    Ok(q)   => {
-                        // Submit the query to the database.
-                        println!("{}", client.query(q));
-                    }
-                    Err(_)  => println!("That didn't parse.")
+        // Queries can go on over several lines, until their braces are
+        // balanced.
+        if let Some(keys) = buffer.push(&input).and_then(|q| run_query(&client, &q)) {
+            source.remember_keys(keys);
+        }
+        source.set_continued(!buffer.is_empty());
+    }
+}
+
+// Collects lines of input until they make up a whole JSON query, by
+// keeping track of how deeply nested the braces and brackets are.
+// Braces inside strings don't count.
+struct QueryBuffer {
+    text: String,
+    depth: i64,
+    in_string: bool,
+    escaped: bool
+}
+
+impl QueryBuffer {
+    fn new() -> QueryBuffer {
+        QueryBuffer{
+            text: String::new(),
+            depth: 0,
+            in_string: false,
+            escaped: false
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    // Add a line, and return the query if that finishes it.
+    fn push(&mut self, line: &str) -> Option<String> {
+        for c in line.chars() {
+            if self.in_string {
+                match c {
+                    _ if self.escaped   => self.escaped = false,
+                    '\\'                => self.escaped = true,
+                    '"'                 => self.in_string = false,
+                    _                   => ()
+                }
+                continue;
+            }
+            match c {
+                '"'         => self.in_string = true,
+                '{' | '['   => self.depth += 1,
+                '}' | ']'   => self.depth -= 1,
+                _           => ()
+            }
+        }
+
+        if self.text.is_empty() && line.trim().is_empty() {
+            return None;
+        }
+        self.text.push_str(line.trim_right());
+        self.text.push('\n');
+        if self.depth > 0 || self.in_string {
+            return None;
+        }
+
+        self.depth = 0;
+        Some(mem::replace(&mut self.text, String::new()).trim().to_owned())
+    }
+}
+
+// Run the query and print the result. Returns the query's row keys, or
+// None if it didn't parse.
+fn run_query(client: &largeclient::LargeClient, text: &str) -> Option<Vec<String>> {
+    match query::Query::parse(text) {
+        Ok(q)   => {
+            let keys = shards::query_rows(&q).iter().map(|k| k.to_string()).collect();
+            // Submit the query to the database.
+            println!("{}", client.query(q));
+            Some(keys)
+        }
+        Err(_)  => {
+            println!("That didn't parse.");
+            None
+        }
+    }
+}
+
+// Browse the rows which start with the prefix, a page at a time. Each
+// page is only fetched once the user asks for it. The table shows the
+// given columns, or the names of each row's columns if there aren't any,
+// and a row can be shown in full by typing its number.
+fn browse(client: &largeclient::LargeClient, source: &mut LineSource, prefix: &str, columns: &[String]) {
+    let end = keys::prefix_end(prefix).unwrap_or_default();
+    let mut next = prefix.to_owned();
+    let mut shown = 0;
+    loop {
+        let page = client.query(query::Query::Scan{
+            start: next.clone(),
+            end: end.clone(),
+            get: columns.to_vec(),
+            limit: SCAN_PAGE_ROWS,
+            snapshot: 0
+        });
+        let (rows, continuation) = match page {
+            query::QueryResult::Rows{rows: r, next: n} => (r, n),
+            x => return println!("{}", x)
+        };
+        for line in format_table(&rows, columns, shown) {
+            println!("{}", line);
+        }
+        let first = shown;
+        shown += rows.len();
+
+        loop {
+            println!("{}", match continuation.is_empty() {
+                true  => "-- end of rows; <number>: show a row, enter: quit --",
+                false => "-- enter: next page, <number>: show a row, q: quit --"
+            });
+            let input = match source.next_line() {
+                Some(i) => i,
+                None    => return
+            };
+            match input.trim() {
+                "q"         => return,
+                ""          => break,
+                x           => match x.parse::<usize>() {
+                    Ok(i) if i >= first && i < shown => show_row(client, &rows[i - first].key),
+                    _ => println!("Type the number of a row on this page.")
                 }
             }
         }
+
+        if continuation.is_empty() {
+            return;
+        }
+        next = continuation;
+    }
+}
+
+// Print every column of the row.
+fn show_row(client: &largeclient::LargeClient, row: &str) {
+    // No other key sorts between the row and the row followed by a zero
+    // byte, so the scan only reads the row.
+    match client.query(query::Query::Scan{
+        start: row.to_owned(),
+        end: format!("{}\0", row),
+        get: vec![],
+        limit: 1,
+        snapshot: 0
+    }) {
+        query::QueryResult::Rows{rows: r, ..} => for row in r {
+            println!("{}", row.key);
+            for &(ref k, ref v) in row.columns.iter() {
+                println!("  {}: {}", k, String::from_utf8_lossy(v));
+            }
+        },
+        x => println!("{}", x)
+    };
+}
+
+// Lay out the rows as a table, numbered from first, with a column for
+// each of the columns, or for the names of the row's columns if there
+// aren't any. Long values are cut short.
+fn format_table(rows: &[query::ScanRow], columns: &[String], first: usize) -> Vec<String> {
+    let mut header = vec![String::from("#"), String::from("row")];
+    match columns.is_empty() {
+        true  => header.push(String::from("columns")),
+        false => header.extend(columns.iter().cloned())
+    };
+
+    let mut table = vec![header];
+    for (i, row) in rows.iter().enumerate() {
+        let mut cells = vec![format!("{}", first + i), row.key.clone()];
+        if columns.is_empty() {
+            cells.push(row.columns.iter().map(|&(ref k, _)| k.as_str()).collect::<Vec<_>>().join(", "));
+        }
+        for c in columns {
+            cells.push(row.columns.iter()
+                .find(|&&(ref k, _)| k == c)
+                .map(|&(_, ref v)| String::from_utf8_lossy(v).into_owned())
+                .unwrap_or_default());
+        }
+        table.push(cells.into_iter().map(|c| shorten(&c)).collect());
+    }
+
+    let widths = (0..table[0].len())
+        .map(|i| table.iter().map(|r| r[i].chars().count()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+    table.iter()
+        .map(|r| r.iter().zip(widths.iter())
+            .map(|(c, &w)| format!("{:1$}", c, w))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_right()
+            .to_owned())
+        .collect()
+}
+
+fn shorten(text: &str) -> String {
+    match text.chars().count() > MAX_CELL_WIDTH {
+        true  => format!("{}...", text.chars().take(MAX_CELL_WIDTH - 3).collect::<String>()),
+        false => text.replace('\n', " ")
+    }
+}
+
+// Run each of the queries in the file, in order.
+fn run_file(client: &largeclient::LargeClient, file: &str) {
+    let mut body = String::new();
+    if let Err(e) = fs::File::open(file).and_then(|mut f| f.read_to_string(&mut body)) {
+        return println!("Unable to read {}: {}", file, e);
+    }
+
+    let mut buffer = QueryBuffer::new();
+    for line in body.lines() {
+        if let Some(q) = buffer.push(line) {
+            run_query(client, &q);
+        }
+    }
+    if !buffer.is_empty() {
+        println!("The last query in {} isn't finished.", file);
+    }
+}
+
+fn connect(hostname: &str) -> largeclient::LargeClient {
+    if hostname.starts_with("unix:") {
+        largeclient::LargeClient::new_unix(&hostname["unix:".len()..]).unwrap()
+    } else {
+        largeclient::LargeClient::new(hostname).unwrap()
+    }
+}
+
+// Copy the range to the destination, then wait for the operator to
+// point clients at the destination before deleting it from the source.
+fn move_range(source: &largeclient::LargeClient, destination: &largeclient::LargeClient, start: &str, end: &str) {
+    let mover = largeclient::migrate::RangeMove::new(source, destination, start, end);
+    match mover.copy() {
+        Ok(n)   => println!("Copied {} rows.", n),
+        Err(e)  => {
+            println!("Copy failed: {:?}", e);
+            return;
+        }
+    };
+
+    println!("Switch clients for the range over to the destination, then press enter to delete it here.");
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line).is_err() {
+        return;
+    }
+
+    match mover.delete_source() {
+        Ok(_)   => println!("Deleted the range from the source."),
+        Err(e)  => println!("Delete failed: {:?}", e)
+    };
+}
+
+fn read_migration(file: &str) -> Option<String> {
+    let mut body = String::new();
+    match fs::File::open(file).and_then(|mut f| f.read_to_string(&mut body)) {
+        Ok(_)   => Some(body),
+        Err(e)  => {
+            println!("Unable to read {}: {}", file, e);
+            None
+        }
+    }
+}
+
+// Migrate the data directory directly. The server mustn't be running.
+fn migrate_offline(file: &str, directory: &str) {
+    let migration = match read_migration(file).map(|b| serde_json::from_str::<migration::ColumnMigration>(&b)) {
+        Some(Ok(m)) => m,
+        Some(Err(e)) => return println!("Invalid migration: {}", e),
+        None => return
+    };
+
+    let database = match largetable_core::Database::open(directory) {
+        Ok(d)   => d,
+        Err(e)  => return println!("Unable to open {}: {}", directory, e)
+    };
+    match database.lock().migrate_columns(&migration) {
+        Ok(n)   => println!("Migrated {} dtables.", n),
+        Err(e)  => println!("Migration failed: {}", e)
+    };
+}
+
+// Export the data directory as a SQL script. The server mustn't be running.
+fn export_offline(file: &str, directory: &str, as_of: Option<String>) {
+    let timestamp = match as_of.map(|t| t.parse::<u64>()) {
+        Some(Ok(t)) => t,
+        Some(Err(_)) => return println!("--as-of must be a number of nanoseconds."),
+        None => {
+            let now = time::get_time();
+            now.sec as u64 * 1_000_000_000 + now.nsec as u64
+        }
+    };
+    if !fs::metadata(directory).map(|m| m.is_dir()).unwrap_or(false) {
+        return println!("{} isn't a data directory.", directory);
+    }
+
+    let database = match largetable_core::Database::open(directory) {
+        Ok(d)   => d,
+        Err(e)  => return println!("Unable to open {}: {}", directory, e)
+    };
+    let mut out = match fs::File::create(file) {
+        Ok(f)   => io::BufWriter::new(f),
+        Err(e)  => return println!("Unable to create {}: {}", file, e)
+    };
+
+    let base = database.lock();
+    let rows = base.iter_rows(scan::KeyRange::all(), timestamp);
+    match export::export_sql(rows, "largetable", &mut out) {
+        Ok(stats)   => println!("Exported {} to {}.", stats, file),
+        Err(e)      => println!("Export failed: {}", e)
+    };
+}
+
+// Bring a data directory restored from a backup up to date from the
+// commit log archive. The server mustn't be running.
+fn restore_offline(archive: &str, directory: &str, as_of: Option<String>) {
+    let until = match as_of.map(|t| t.parse::<u64>()) {
+        Some(Ok(t)) => t,
+        Some(Err(_)) => return println!("--as-of must be a number of nanoseconds."),
+        None => 0
+    };
+    if !fs::metadata(archive).map(|m| m.is_dir()).unwrap_or(false) {
+        return println!("{} isn't a commit log archive.", archive);
+    }
+
+    let database = match largetable_core::Database::open(directory) {
+        Ok(d)   => d,
+        Err(e)  => return println!("Unable to open {}: {}", directory, e)
+    };
+    match database.lock().restore_from_archive(archive, until) {
+        Ok(n)   => println!("Restored {} segments.", n),
+        Err(e)  => println!("Restore failed: {}", e)
+    };
+}
+
+// Ask a running server to migrate its data.
+fn migrate_online(file: &str, hostname: &str) {
+    let body = match read_migration(file) {
+        Some(b) => b,
+        None    => return
+    };
+    if hostname.starts_with("unix:") {
+        return println!("Migrations can't be sent over unix sockets yet.");
+    }
+
+    let url = format!("http://{}/migrate", hostname);
+    match hyper::Client::new().post(&url).body(body.as_str()).send() {
+        Ok(mut res) => {
+            let mut result = String::new();
+            res.read_to_string(&mut result).unwrap_or(0);
+            println!("{}: {}", res.status, result);
+        },
+        Err(e)      => println!("Unable to reach {}: {}", hostname, e)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn collects_queries_over_several_lines() {
+        let mut buffer = super::QueryBuffer::new();
+        assert_eq!(buffer.push(""), None);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.push(r#"{"select": {"#), None);
+        assert_eq!(buffer.push(r#"  "row": "a}\"{", "get": ["#), None);
+        assert!(!buffer.is_empty());
+        assert_eq!(
+            buffer.push(r#"  "status"]}}"#),
+            Some(String::from("{\"select\": {\n  \"row\": \"a}\\\"{\", \"get\": [\n  \"status\"]}}"))
+        );
+        assert!(buffer.is_empty());
+
+        // Text which isn't JSON is finished at the end of the line, so
+        // that it's reported as not parsing.
+        assert_eq!(buffer.push("oops"), Some(String::from("oops")));
+    }
+
+    #[test]
+    fn formats_scan_pages_as_tables() {
+        let rows = vec![
+            ::query::ScanRow{key: String::from("user/a"), columns: vec![(String::from("name"), b"alice".to_vec())]},
+            ::query::ScanRow{key: String::from("user/bob"), columns: vec![
+                (String::from("age"), b"7".to_vec()),
+                (String::from("name"), vec![b'b'; 40])
+            ]}
+        ];
+        assert_eq!(super::format_table(&rows, &[String::from("name"), String::from("age")], 20), vec![
+            String::from("#   row       name                            age"),
+            String::from("20  user/a    alice"),
+            format!("21  user/bob  {}...  7", "b".repeat(27))
+        ]);
+        assert_eq!(super::format_table(&rows, &[], 0), vec![
+            "#  row       columns",
+            "0  user/a    name",
+            "1  user/bob  age, name"
+        ]);
+    }
+
+    #[test]
+    fn completes_keywords_and_keys() {
+        let recent = vec![String::from("user/sam"), String::from("session/1")].into_iter().collect();
+        let server = vec![String::from("session/1"), String::from("session/2")];
+        assert_eq!(super::completions("se", &recent, &server), vec!["select", "set", "session/1", "session/2"]);
+        assert_eq!(super::completions("u", &recent, &[]), vec!["update", "user/sam"]);
+        assert!(super::completions("x", &recent, &server).is_empty());
     }
 }
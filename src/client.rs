@@ -1,7 +1,5 @@
 #![feature(test)]
 
-#[macro_use]
-extern crate serde_derive;
 extern crate serde_json;
 #[cfg(test)]
 extern crate serde_yaml;
@@ -13,63 +11,405 @@ extern crate byteorder;
 extern crate time;
 extern crate rand;
 extern crate hyper;
+extern crate flate2;
+extern crate largetable_core;
 
 #[cfg(test)]
 extern crate test;
 
-pub mod query;
-mod generated;
+use std::io;
+use std::io::Write;
 
+pub mod unix;
+pub mod compression;
+pub mod scanner;
+pub mod session;
+pub mod buffered;
+pub mod prepared;
+pub mod hedged;
+pub mod sharded;
+pub mod migrate;
+pub mod test_support;
+pub mod mock;
+
+pub use largetable_core::query;
+pub use largetable_core::compositekey::Key;
+use largetable_core::generated;
+use largetable_core::shards;
+
+// The server reads trace IDs from this header, and sends back the one
+// that it used.
+const TRACE_ID_HEADER: &'static str = "X-Trace-Id";
+
+// Writes sent with the same key in this header are only applied once.
+const IDEMPOTENCY_KEY_HEADER: &'static str = "Idempotency-Key";
+
+// The server says which timestamp each query ran at, and reads can ask
+// to run no earlier than a given timestamp.
+const TIMESTAMP_HEADER: &'static str = "X-Largetable-Timestamp";
+const MIN_READ_TIMESTAMP_HEADER: &'static str = "X-Largetable-Min-Read-Timestamp";
+
+// Writes are only acknowledged once the replicas named in this header
+// have stored them.
+const ACK_LEVEL_HEADER: &'static str = "X-Largetable-Ack";
+
+// Make up a new idempotency key for a write.
+pub fn new_idempotency_key() -> String {
+    format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())
+}
+
+// The interface that the clients have in common, so that application
+// code can be handed a LargeClient, a sharded::ShardedClient, or a
+// mock::MockClient in unit tests, without depending on which.
+pub trait Client {
+    fn query(&self, q: query::Query) -> query::QueryResult;
+
+    // Run the queries one after another, and return their results in
+    // the same order. They aren't applied atomically; use a transaction
+    // for that.
+    fn batch(&self, queries: Vec<query::Query>) -> Vec<query::QueryResult> {
+        queries.into_iter().map(|q| self.query(q)).collect()
+    }
+
+    // Read a page of up to limit rows in [start, end), with the columns
+    // in get, or every column if it's empty. The result's next key is
+    // where the following page starts, or empty at the end of the range.
+    fn scan(&self, start: &str, end: &str, get: &[&str], limit: u64) -> query::QueryResult {
+        self.query(query::Query::Scan{
+            start: start.to_owned(),
+            end: end.to_owned(),
+            get: get.iter().map(|g| g.to_string()).collect(),
+            limit: limit,
+            snapshot: 0
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct LargeClient {
-    hostname: hyper::Url
+    hostname: hyper::Url,
+
+    // If set, requests go to this unix domain socket instead of
+    // connecting to the hostname over TCP.
+    unix_socket: Option<String>,
+
+    // Sent as a bearer token with every request, for servers which have
+    // access control rules.
+    auth_token: String,
+
+    // If set, large queries are compressed, and the server is asked to
+    // compress its responses.
+    compression: bool,
+
+    // Which replicas must store a write before the server reports it
+    // as done.
+    ack_level: query::AckLevel
+}
+
+// Optional extras which are sent along with a query.
+#[derive(Default)]
+struct RequestOptions {
+    trace_id: String,
+    idempotency_key: String,
+    min_read_timestamp: u64
+}
+
+// What the server sent back for a query: the result, the trace ID that
+// it used, and the timestamp that the query ran at (zero if unknown).
+struct QueryResponse {
+    result: query::QueryResult,
+    trace_id: String,
+    timestamp: u64
 }
 
 #[derive(Debug)]
 pub enum ClientError {
-    ConfigurationError
+    ConfigurationError,
+    NetworkError,
+    NotFound,
+    Busy,
+    PermissionDenied,
+    RequestFailed
 }
 
 impl LargeClient {
     pub fn new(hostname: &str) -> Result<LargeClient, ClientError> {
         Ok(LargeClient{
             hostname: hyper::Url::parse(format!("http://{}",hostname).as_str())
-                .map_err(|_| ClientError::ConfigurationError)?
+                .map_err(|_| ClientError::ConfigurationError)?,
+            unix_socket: None,
+            auth_token: String::new(),
+            compression: false,
+            ack_level: query::AckLevel::Leader
         })
     }
 
-    pub fn query(&self, q: query::Query) -> query::QueryResult {
-        let req = match hyper::client::request::Request::new(
-            hyper::method::Method::Post,
-            self.hostname.to_owned()
-        ) {
+    // Connect to a server on the same machine through its unix socket,
+    // which avoids setting up a TCP connection for every query.
+    pub fn new_unix(path: &str) -> Result<LargeClient, ClientError> {
+        if path.is_empty() {
+            return Err(ClientError::ConfigurationError);
+        }
+
+        Ok(LargeClient{
+            hostname: hyper::Url::parse("http://localhost").unwrap(),
+            unix_socket: Some(path.to_owned()),
+            auth_token: String::new(),
+            compression: false,
+            ack_level: query::AckLevel::Leader
+        })
+    }
+
+    pub fn set_auth_token(&mut self, token: &str) {
+        self.auth_token = token.to_owned();
+    }
+
+    // Compress the request and response bodies with gzip. This is worth
+    // it for big batches of writes over slow links, but costs CPU time
+    // on both ends.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    // Ask for writes to be acknowledged only once enough replicas have
+    // stored them. If the server can't meet the level in time, writes
+    // fail with InsufficientReplicas, though they've still been applied
+    // on the server.
+    pub fn set_ack_level(&mut self, level: query::AckLevel) {
+        self.ack_level = level;
+    }
+
+    // Post the query to the path on the server, and return the response.
+    // Any options which are set are sent along with the query.
+    fn send(&self, path: &str, q: query::Query, options: &RequestOptions) -> Result<hyper::client::Response, ClientError> {
+        let mut body = vec![];
+        if q.write_to_writer(&mut body).is_err() {
+            println!("failed to encode query.");
+            return Err(ClientError::NetworkError);
+        }
+        self.request(hyper::method::Method::Post, path, body, options)
+    }
+
+    fn request(&self, method: hyper::method::Method, path: &str, mut body: Vec<u8>, options: &RequestOptions) -> Result<hyper::client::Response, ClientError> {
+        let url = self.hostname.join(path).map_err(|_| ClientError::ConfigurationError)?;
+        let req = match self.unix_socket {
+            Some(ref path) => hyper::client::request::Request::with_connector(
+                method,
+                url,
+                &unix::UnixConnector{path: path.clone()}
+            ),
+            None => hyper::client::request::Request::new(
+                method,
+                url
+            )
+        };
+        let mut req = match req {
             Ok(r) => r,
             Err(e) => {
                 println!("failed to create request: {} (hostname={})", e, self.hostname.clone());
-                return query::QueryResult::NetworkError
+                return Err(ClientError::NetworkError)
             }
         };
 
+        if !options.trace_id.is_empty() {
+            req.headers_mut().set_raw(TRACE_ID_HEADER, vec![options.trace_id.as_bytes().to_vec()]);
+        }
+
+        if !options.idempotency_key.is_empty() {
+            req.headers_mut().set_raw(IDEMPOTENCY_KEY_HEADER, vec![options.idempotency_key.as_bytes().to_vec()]);
+        }
+
+        if self.ack_level != query::AckLevel::Leader {
+            req.headers_mut().set_raw(ACK_LEVEL_HEADER, vec![self.ack_level.as_str().as_bytes().to_vec()]);
+        }
+
+        if options.min_read_timestamp > 0 {
+            req.headers_mut().set_raw(MIN_READ_TIMESTAMP_HEADER, vec![format!("{}", options.min_read_timestamp).into_bytes()]);
+        }
+
+        if !self.auth_token.is_empty() {
+            req.headers_mut().set_raw("Authorization", vec![format!("Bearer {}", self.auth_token).into_bytes()]);
+        }
+
+        if self.compression {
+            req.headers_mut().set_raw("Accept-Encoding", vec![compression::GZIP.as_bytes().to_vec()]);
+            if body.len() >= compression::MIN_COMPRESSED_SIZE {
+                body = compression::compress(&body).map_err(|_| ClientError::NetworkError)?;
+                req.headers_mut().set_raw("Content-Encoding", vec![compression::GZIP.as_bytes().to_vec()]);
+            }
+        }
+
         let mut w = match req.start() {
             Ok(writer)  => writer,
             Err(_)      => {
                 println!("failed to connect to host");
-                return query::QueryResult::NetworkError
+                return Err(ClientError::NetworkError)
             }
         };
 
-        if q.write_to_writer(&mut w).is_err() {
+        if w.write_all(&body).is_err() {
             println!("failed to write message to host.");
-            return query::QueryResult::NetworkError;
+            return Err(ClientError::NetworkError);
         }
 
-        let mut read = match w.send() {
+        w.send().map_err(|_| ClientError::NetworkError)
+    }
+
+    pub fn query(&self, q: query::Query) -> query::QueryResult {
+        self.traced_query(q, "").0
+    }
+
+    // Run the query as part of a trace, so that it can be found in the
+    // server's logs. If the trace ID is empty, the server picks one.
+    // Returns the result, along with the trace ID that the server used.
+    pub fn traced_query(&self, q: query::Query, trace_id: &str) -> (query::QueryResult, String) {
+        let response = self.run(q, RequestOptions{
+            trace_id: trace_id.to_owned(),
+            ..Default::default()
+        });
+        (response.result, response.trace_id)
+    }
+
+    // Run a write which is safe to retry: if the server has already
+    // applied a write with the same key, it isn't applied again. Use a
+    // new key (see new_idempotency_key) for each distinct write, and the
+    // same key for each retry of it.
+    pub fn idempotent_query(&self, q: query::Query, idempotency_key: &str) -> query::QueryResult {
+        self.run(q, RequestOptions{
+            idempotency_key: idempotency_key.to_owned(),
+            ..Default::default()
+        }).result
+    }
+
+    // Start a session, whose reads always see the writes made through it.
+    pub fn session(&self) -> session::Session {
+        session::Session::new(self)
+    }
+
+    // Buffer writes, to send them in batches.
+    pub fn buffered_writer(&self) -> buffered::BufferedWriter {
+        buffered::BufferedWriter::new(self)
+    }
+
+    // Prepare a select of the columns, to be run on lots of rows.
+    pub fn prepare(&self, get: &[&str]) -> prepared::PreparedSelect {
+        prepared::PreparedSelect::new(self, get)
+    }
+
+    fn run(&self, q: query::Query, options: RequestOptions) -> QueryResponse {
+        let failed = |result, trace_id| QueryResponse{result: result, trace_id: trace_id, timestamp: 0};
+        let response = match self.send("/v1/query", q, &options) {
+            Ok(r)   => r,
+            Err(_)  => return failed(query::QueryResult::NetworkError, options.trace_id)
+        };
+
+        let used = header_value(&response.headers, TRACE_ID_HEADER)
+            .unwrap_or(options.trace_id);
+        let timestamp = header_value(&response.headers, TIMESTAMP_HEADER)
+            .and_then(|t| t.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let headers = response.headers.clone();
+        let mut read = match compression::decode(&headers, response, std::u64::MAX) {
             Ok(r)   => r,
-            Err(_)  => return query::QueryResult::NetworkError
+            Err(e)  => return failed(query::QueryResult::InternalError{
+                error: format!("unable to decode response: {}", e)
+            }, used)
         };
 
         match protobuf::parse_from_reader::<generated::query::QueryResult>(&mut read) {
-            Ok(result) => query::QueryResult::from_generated(result),
-            Err(_) => query::QueryResult::InternalError
+            Ok(result) => QueryResponse{
+                result: query::QueryResult::from_generated(result),
+                trace_id: used,
+                timestamp: timestamp
+            },
+            Err(e) => failed(query::QueryResult::InternalError{
+                error: format!("unable to parse response: {}", e)
+            }, used)
         }
     }
+
+    // Read a single column of a row. The value is streamed from the
+    // server in chunks, so the returned reader can be consumed before
+    // the whole value has arrived.
+    pub fn select_stream(&self, row: &str, column: &str) -> Result<Box<io::Read>, ClientError> {
+        let response = self.send("/stream", query::Query::new_select(row, &[column]), &RequestOptions::default())?;
+        match response.status {
+            hyper::status::StatusCode::Ok                   => Ok(Box::new(response)),
+            hyper::status::StatusCode::NotFound             => Err(ClientError::NotFound),
+            hyper::status::StatusCode::ServiceUnavailable   => Err(ClientError::Busy),
+            hyper::status::StatusCode::Forbidden            => Err(ClientError::PermissionDenied),
+            _                                               => Err(ClientError::RequestFailed)
+        }
+    }
+
+    // Fetch the server's shard map (see largetable_core::shards).
+    pub fn shard_map(&self) -> Result<shards::ShardMap, ClientError> {
+        let response = self.request(hyper::method::Method::Get, "/topology", vec![], &RequestOptions::default())?;
+        match response.status {
+            hyper::status::StatusCode::Ok           => (),
+            hyper::status::StatusCode::Forbidden    => return Err(ClientError::PermissionDenied),
+            _                                       => return Err(ClientError::RequestFailed)
+        };
+        serde_json::from_reader(response).map_err(|_| ClientError::RequestFailed)
+    }
+}
+
+impl Client for LargeClient {
+    fn query(&self, q: query::Query) -> query::QueryResult {
+        LargeClient::query(self, q)
+    }
+}
+
+// The first value of a header, if it's present and valid UTF-8.
+fn header_value(headers: &hyper::header::Headers, name: &str) -> Option<String> {
+    headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| String::from_utf8(value.clone()).ok())
+}
+
+// Read the context of a query from the headers of a request to the
+// server: its trace ID, or a new one if it doesn't have a valid one, its
+// bearer token, idempotency key, minimum read timestamp and ack level.
+// The server and the test server both use this, so that they agree.
+pub fn request_context(req: &hyper::server::Request) -> query::QueryContext {
+    let provided = header_value(&req.headers, TRACE_ID_HEADER)
+        .and_then(|id| {
+            let valid = !id.is_empty() && id.len() <= 64 &&
+                id.chars().all(|c| match c {
+                    'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' => true,
+                    _ => false
+                });
+            if valid { Some(id) } else { None }
+        });
+
+    let mut context = match provided {
+        Some(id)    => query::QueryContext::with_trace_id(&id),
+        None        => query::QueryContext::with_trace_id(
+            &format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())
+        )
+    };
+    context.auth_token = header_value(&req.headers, "Authorization")
+        .and_then(|value| bearer_token(&value))
+        .unwrap_or(String::new());
+    context.client_address = format!("{}", req.remote_addr.ip());
+    context.idempotency_key = header_value(&req.headers, IDEMPOTENCY_KEY_HEADER)
+        .map(|key| key.trim().to_owned())
+        .unwrap_or(String::new());
+    context.min_read_timestamp = header_value(&req.headers, MIN_READ_TIMESTAMP_HEADER)
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    context.ack_level = header_value(&req.headers, ACK_LEVEL_HEADER)
+        .and_then(|value| query::AckLevel::parse(value.trim()))
+        .unwrap_or(query::AckLevel::Leader);
+    context
+}
+
+// The token in an Authorization header, which must use the Bearer scheme.
+fn bearer_token(value: &str) -> Option<String> {
+    if value.starts_with("Bearer ") {
+        Some(value["Bearer ".len()..].trim().to_owned())
+    } else {
+        None
+    }
 }
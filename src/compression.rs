@@ -0,0 +1,137 @@
+/*
+    compression.rs
+
+    Optional gzip compression of request and response bodies. The client
+    asks for compressed responses with "Accept-Encoding: gzip", and marks
+    compressed requests with "Content-Encoding: gzip", like any other
+    HTTP client would. Only gzip is supported: snappy would need another
+    dependency, and gzip is what HTTP clients already speak.
+*/
+
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use hyper::header::Headers;
+
+pub const GZIP: &'static str = "gzip";
+
+// Bodies smaller than this are sent as they are, since compressing them
+// doesn't save enough to be worth it.
+pub const MIN_COMPRESSED_SIZE: usize = 1024;
+
+// Check whether any of the values of the header include the encoding,
+// e.g. "Accept-Encoding: deflate, gzip;q=0.8".
+fn has_encoding(headers: &Headers, header: &str, encoding: &str) -> bool {
+    headers.get_raw(header)
+        .map(|values| values.iter().any(|value| {
+            String::from_utf8_lossy(value).split(',')
+                .map(|e| e.split(';').next().unwrap_or("").trim().to_lowercase())
+                .any(|e| e == encoding)
+        }))
+        .unwrap_or(false)
+}
+
+pub fn accepts_gzip(headers: &Headers) -> bool {
+    has_encoding(headers, "Accept-Encoding", GZIP)
+}
+
+pub fn is_gzipped(headers: &Headers) -> bool {
+    has_encoding(headers, "Content-Encoding", GZIP)
+}
+
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::Default);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// Wrap the body in a decoder if it's compressed. Reading more than limit
+// bytes of the decoded body fails, so that a small compressed body can't
+// be inflated into more than that.
+pub fn decode<'a, R: Read + 'a>(headers: &Headers, body: R, limit: u64) -> io::Result<Box<Read + 'a>> {
+    if is_gzipped(headers) {
+        Ok(Box::new(Limited{inner: GzDecoder::new(body)?, remaining: limit}))
+    } else {
+        Ok(Box::new(Limited{inner: body, remaining: limit}))
+    }
+}
+
+// Like Read::take, except that it fails rather than cutting the body
+// short, since a truncated query might still parse.
+struct Limited<R> {
+    inner: R,
+    remaining: u64
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            return match self.inner.read(&mut [0u8])? {
+                0 => Ok(0),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "the body is too large"))
+            };
+        }
+
+        let max = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use hyper::header::Headers;
+
+    #[test]
+    fn can_negotiate_encodings() {
+        let mut headers = Headers::new();
+        assert!(!super::accepts_gzip(&headers));
+
+        headers.set_raw("Accept-Encoding", vec![b"deflate, GZIP;q=0.8".to_vec()]);
+        assert!(super::accepts_gzip(&headers));
+
+        headers.set_raw("Content-Encoding", vec![b"gzipped".to_vec()]);
+        assert!(!super::is_gzipped(&headers));
+    }
+
+    #[test]
+    fn can_round_trip_bodies() {
+        let data = vec![42u8; 100000];
+        let compressed = super::compress(&data).unwrap();
+        assert!(compressed.len() < data.len() / 10);
+
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Encoding", vec![b"gzip".to_vec()]);
+        let mut decoded = vec![];
+        super::decode(&headers, &compressed[..], 100000).unwrap().read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        // Bodies without an encoding are passed through.
+        let mut plain = vec![];
+        super::decode(&Headers::new(), &data[..], 100000).unwrap().read_to_end(&mut plain).unwrap();
+        assert_eq!(plain, data);
+    }
+
+    #[test]
+    fn refuses_bodies_over_the_limit() {
+        let data = vec![42u8; 100000];
+        let compressed = super::compress(&data).unwrap();
+
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Encoding", vec![b"gzip".to_vec()]);
+        let mut decoded = vec![];
+        assert!(super::decode(&headers, &compressed[..], 99999).unwrap().read_to_end(&mut decoded).is_err());
+
+        let mut plain = vec![];
+        assert!(super::decode(&Headers::new(), &data[..], 99999).unwrap().read_to_end(&mut plain).is_err());
+    }
+}
@@ -11,6 +11,17 @@ use std::env;
 use std::fs::File;
 use serde_yaml;
 use serde_json;
+use serde::de::{self, Deserializer, Visitor, SeqVisitor};
+
+use largetable_core::base::{DTablePlacement, FsyncPolicy};
+use largetable_core::keys::KeyNormalization;
+use largetable_core::acl::AccessRule;
+use largetable_core::schema::TableSchema;
+use largetable_core::families::FamilyPolicy;
+use largetable_core::rowcache::CacheMode;
+use largetable_core::shards::ShardMap;
+
+use logger;
 
 #[derive(Debug, Deserialize)]
 pub enum Mode {
@@ -35,25 +46,272 @@ impl fmt::Display for Mode {
 pub struct ApplicationConfig {
     #[serde(default="default_mode")]
     pub mode: Mode,
+    #[serde(default="default_bind_address")]
+    pub bind_address: String,
     #[serde(default="default_port")]
     pub port: u32,
-    #[serde(default="default_directory")]
-    pub datadirectory: String,
+    #[serde(default="default_read_port")]
+    pub read_port: u32,
+    #[serde(default="default_write_port")]
+    pub write_port: u32,
+    #[serde(default="default_unix_socket")]
+    pub unix_socket: String,
+    #[serde(default="default_directory", deserialize_with="deserialize_directories")]
+    pub datadirectory: Vec<String>,
+    #[serde(default="default_dtable_placement")]
+    pub dtable_placement: DTablePlacement,
     #[serde(default="default_memtable_size_limit")]
     pub memtable_size_limit: usize,
     #[serde(default="default_disktable_limit")]
-    pub disktable_limit: usize
+    pub disktable_limit: usize,
+    #[serde(default="default_max_dtable_bytes")]
+    pub max_dtable_bytes: u64,
+    #[serde(default="default_min_free_bytes")]
+    pub min_free_bytes: u64,
+    #[serde(default="default_compaction_bytes_per_second")]
+    pub compaction_bytes_per_second: u64,
+    #[serde(default="default_compaction_drop_cache")]
+    pub compaction_drop_cache: bool,
+    #[serde(default="default_max_merge_fan_in")]
+    pub max_merge_fan_in: usize,
+    #[serde(default="default_compaction_min_overlap")]
+    pub compaction_min_overlap: f64,
+    #[serde(default="default_readahead_bytes")]
+    pub readahead_bytes: u64,
+    #[serde(default="default_snapshot_ttl_ms")]
+    pub snapshot_ttl_ms: u64,
+    #[serde(default="default_soft_delete_retention_ms")]
+    pub soft_delete_retention_ms: u64,
+    #[serde(default="default_fsync")]
+    pub fsync: FsyncPolicy,
+    #[serde(default="default_fsync_interval_ms")]
+    pub fsync_interval_ms: u64,
+    #[serde(default="default_checkpoint_interval_ms")]
+    pub checkpoint_interval_ms: u64,
+    #[serde(default="default_archive_after_days")]
+    pub archive_after_days: u64,
+    #[serde(default="default_archive_directory")]
+    pub archive_directory: String,
+    #[serde(default="default_gc_garbage_ratio")]
+    pub gc_garbage_ratio: f64,
+    #[serde(default="default_key_max_length")]
+    pub key_max_length: usize,
+    #[serde(default="default_key_charset")]
+    pub key_charset: String,
+    #[serde(default="default_reject_empty_keys")]
+    pub reject_empty_keys: bool,
+    #[serde(default="default_key_normalization")]
+    pub key_normalization: KeyNormalization,
+    #[serde(default="default_worker_threads")]
+    pub worker_threads: usize,
+    #[serde(default="default_queue_depth")]
+    pub queue_depth: usize,
+    #[serde(default="default_max_query_bytes")]
+    pub max_query_bytes: u64,
+    #[serde(default="default_max_concurrent_query_bytes")]
+    pub max_concurrent_query_bytes: u64,
+    #[serde(default="default_max_body_bytes")]
+    pub max_body_bytes: u64,
+    #[serde(default="default_estimated_value_bytes")]
+    pub estimated_value_bytes: u64,
+    #[serde(default="default_slow_query_ms")]
+    pub slow_query_ms: u64,
+    #[serde(default="default_otlp_endpoint")]
+    pub otlp_endpoint: String,
+    #[serde(default="default_access_control")]
+    pub access_control: Vec<AccessRule>,
+    #[serde(default="default_audit_directory")]
+    pub audit_directory: String,
+    #[serde(default="default_audit_max_bytes")]
+    pub audit_max_bytes: u64,
+    #[serde(default="default_audit_max_files")]
+    pub audit_max_files: usize,
+    #[serde(default="default_commit_log_archive_directory")]
+    pub commit_log_archive_directory: String,
+    #[serde(default="default_shard_map")]
+    pub shard_map: ShardMap,
+    #[serde(default="default_shard_address")]
+    pub shard_address: String,
+    #[serde(default="default_replicate_from")]
+    pub replicate_from: String,
+    #[serde(default="default_replication_auth_token")]
+    pub replication_auth_token: String,
+    #[serde(default="default_replication_poll_ms")]
+    pub replication_poll_ms: u64,
+    #[serde(default="default_hint_directory")]
+    pub hint_directory: String,
+    #[serde(default="default_hint_max_bytes")]
+    pub hint_max_bytes: u64,
+    #[serde(default="default_hint_max_age_ms")]
+    pub hint_max_age_ms: u64,
+    #[serde(default="default_node_id")]
+    pub node_id: String,
+    #[serde(default="default_lease_ms")]
+    pub lease_ms: u64,
+    #[serde(default="default_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    #[serde(default="default_idempotency_keys")]
+    pub idempotency_keys: usize,
+    #[serde(default="default_tables")]
+    pub tables: Vec<TableSchema>,
+    #[serde(default="default_families")]
+    pub families: Vec<FamilyPolicy>,
+    #[serde(default="default_log_level")]
+    pub log_level: String,
+    #[serde(default="default_log_file")]
+    pub log_file: String,
+    #[serde(default="default_log_max_bytes")]
+    pub log_max_bytes: u64,
+    #[serde(default="default_log_max_age_hours")]
+    pub log_max_age_hours: u64,
+    #[serde(default="default_log_max_files")]
+    pub log_max_files: usize,
+    #[serde(default="default_row_cache_rows")]
+    pub row_cache_rows: usize,
+    #[serde(default="default_row_cache_mode")]
+    pub row_cache_mode: CacheMode,
+    #[serde(default="default_hot_row_qps")]
+    pub hot_row_qps: u64,
+    #[serde(default="default_result_cache_entries")]
+    pub result_cache_entries: usize,
+    #[serde(default="default_prepared_queries")]
+    pub prepared_queries: usize,
+    #[serde(default="default_lazy_headers")]
+    pub lazy_headers: bool,
+    #[serde(default="default_header_cache_blocks")]
+    pub header_cache_blocks: usize
 }
 
-// These three functions set the default values of the config
+// These functions set the default values of the config
 // values.
 fn default_mode() -> Mode { Mode::Production }
+fn default_bind_address() -> String { String::from("0.0.0.0") }
 fn default_port() -> u32 { 8080 }
-fn default_directory() -> String { String::from("./data") }
+fn default_read_port() -> u32 { 0 }
+fn default_write_port() -> u32 { 0 }
+fn default_unix_socket() -> String { String::new() }
+fn default_directory() -> Vec<String> { vec![String::from("./data")] }
+fn default_dtable_placement() -> DTablePlacement { DTablePlacement::RoundRobin }
 fn default_memtable_size_limit() -> usize { 32 * (1 << 20) }
 fn default_disktable_limit() -> usize { 2 }
+fn default_max_dtable_bytes() -> u64 { 0 }
+fn default_min_free_bytes() -> u64 { 256 * (1 << 20) }
+fn default_compaction_bytes_per_second() -> u64 { 0 }
+fn default_compaction_drop_cache() -> bool { false }
+fn default_max_merge_fan_in() -> usize { 32 }
+fn default_compaction_min_overlap() -> f64 { 0.0 }
+fn default_readahead_bytes() -> u64 { 64 * 1024 }
+fn default_snapshot_ttl_ms() -> u64 { 60000 }
+fn default_soft_delete_retention_ms() -> u64 { 7 * 24 * 3600 * 1000 }
+fn default_fsync() -> FsyncPolicy { FsyncPolicy::Always }
+fn default_fsync_interval_ms() -> u64 { 1000 }
+fn default_checkpoint_interval_ms() -> u64 { 0 }
+fn default_archive_after_days() -> u64 { 0 }
+fn default_archive_directory() -> String { String::from("./data/archive") }
+fn default_gc_garbage_ratio() -> f64 { 0.0 }
+fn default_key_max_length() -> usize { 0 }
+fn default_key_charset() -> String { String::new() }
+fn default_reject_empty_keys() -> bool { false }
+fn default_key_normalization() -> KeyNormalization { KeyNormalization::None }
+fn default_worker_threads() -> usize { 4 }
+fn default_queue_depth() -> usize { 64 }
+fn default_max_query_bytes() -> u64 { 256 * (1 << 20) }
+fn default_max_concurrent_query_bytes() -> u64 { 1 << 30 }
+fn default_max_body_bytes() -> u64 { 256 * (1 << 20) }
+fn default_estimated_value_bytes() -> u64 { 1024 }
+fn default_slow_query_ms() -> u64 { 1000 }
+fn default_otlp_endpoint() -> String { String::new() }
+fn default_access_control() -> Vec<AccessRule> { vec![] }
+fn default_audit_directory() -> String { String::new() }
+fn default_audit_max_bytes() -> u64 { 64 * (1 << 20) }
+fn default_audit_max_files() -> usize { 0 }
+fn default_commit_log_archive_directory() -> String { String::new() }
+fn default_shard_map() -> ShardMap { ShardMap::default() }
+fn default_shard_address() -> String { String::new() }
+fn default_replicate_from() -> String { String::new() }
+fn default_replication_auth_token() -> String { String::new() }
+fn default_replication_poll_ms() -> u64 { 100 }
+fn default_hint_directory() -> String { String::new() }
+fn default_hint_max_bytes() -> u64 { 64 * (1 << 20) }
+fn default_hint_max_age_ms() -> u64 { 24 * 3600 * 1000 }
+fn default_node_id() -> String { String::new() }
+fn default_lease_ms() -> u64 { 0 }
+fn default_ack_timeout_ms() -> u64 { 5000 }
+fn default_idempotency_keys() -> usize { 10000 }
+fn default_tables() -> Vec<TableSchema> { vec![] }
+fn default_families() -> Vec<FamilyPolicy> { vec![] }
+fn default_log_level() -> String { String::from("info") }
+fn default_log_file() -> String { String::new() }
+fn default_log_max_bytes() -> u64 { 64 * (1 << 20) }
+fn default_log_max_age_hours() -> u64 { 0 }
+fn default_log_max_files() -> usize { 5 }
+fn default_row_cache_rows() -> usize { 0 }
+fn default_row_cache_mode() -> CacheMode { CacheMode::Invalidate }
+fn default_hot_row_qps() -> u64 { 0 }
+fn default_result_cache_entries() -> usize { 10000 }
+fn default_prepared_queries() -> usize { 10000 }
+fn default_lazy_headers() -> bool { false }
+fn default_header_cache_blocks() -> usize { 256 }
+
+// The data directory can either be given as a single directory, or
+// as a list of directories which dtables will be spread across.
+fn deserialize_directories<D>(deserializer: D) -> Result<Vec<String>, D::Error>
+    where D: Deserializer
+{
+    struct Directories;
+
+    impl Visitor for Directories {
+        type Value = Vec<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a directory or a list of directories")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Vec<String>, E>
+            where E: de::Error
+        {
+            Ok(vec![value.to_owned()])
+        }
+
+        fn visit_seq<V>(self, mut visitor: V) -> Result<Vec<String>, V::Error>
+            where V: SeqVisitor
+        {
+            let mut directories = vec![];
+            while let Some(directory) = visitor.visit()? {
+                directories.push(directory);
+            }
+
+            if directories.is_empty() {
+                return Err(de::Error::custom("at least one data directory is required"));
+            }
+
+            Ok(directories)
+        }
+    }
+
+    deserializer.deserialize(Directories)
+}
 
 impl ApplicationConfig {
+    // The address to listen on for the port. IPv6 addresses need to be
+    // wrapped in brackets to tell them apart from the port.
+    // The name this server goes by in leader elections and hinted
+    // handoff, which is its address unless node_id is set.
+    pub fn node_name(&self) -> String {
+        match self.node_id.as_str() {
+            ""  => self.listen_address(self.port),
+            id  => id.to_owned()
+        }
+    }
+
+    pub fn listen_address(&self, port: u32) -> String {
+        if self.bind_address.contains(':') {
+            format!("[{}]:{}", self.bind_address, port)
+        } else {
+            format!("{}:{}", self.bind_address, port)
+        }
+    }
+
     // This function will try to read the given filename, decode the
     // contents as YAML, and read it into an ApplicationConfig struct.
     pub fn from_yaml(filename: &str) -> Result<ApplicationConfig, io::Error> {
@@ -79,12 +337,44 @@ impl ApplicationConfig {
             }
         }
 
+        if let Ok(value) = env::var("LARGETABLE_BIND_ADDRESS") {
+            config.bind_address = value;
+        }
+
         if let Ok(value) = env::var("LARGETABLE_PORT") {
             config.port = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_PORT."))?;
         }
 
+        if let Ok(value) = env::var("LARGETABLE_READ_PORT") {
+            config.read_port = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_READ_PORT."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_WRITE_PORT") {
+            config.write_port = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_WRITE_PORT."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_UNIX_SOCKET") {
+            config.unix_socket = value;
+        }
+
+        // Multiple data directories can be separated by commas.
         if let Ok(value) = env::var("LARGETABLE_DATADIRECTORY") {
-            config.datadirectory = value;
+            config.datadirectory = value.split(',')
+                .filter(|d| !d.is_empty())
+                .map(|d| d.to_owned())
+                .collect();
+            if config.datadirectory.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_DATADIRECTORY."));
+            }
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_DTABLE_PLACEMENT") {
+            config.dtable_placement = match value.to_lowercase().as_str() {
+                "round_robin"       => DTablePlacement::RoundRobin,
+                "least_used"        => DTablePlacement::LeastUsed,
+                "most_free_space"   => DTablePlacement::MostFreeSpace,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_DTABLE_PLACEMENT."))
+            };
         }
 
         if let Ok(value) = env::var("LARGETABLE_DISKTABLE_LIMIT") {
@@ -95,6 +385,260 @@ impl ApplicationConfig {
             config.memtable_size_limit = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_MEMTABLE_SIZE_LIMIT."))?;
         }
 
+        if let Ok(value) = env::var("LARGETABLE_MAX_DTABLE_BYTES") {
+            config.max_dtable_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_MAX_DTABLE_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_MIN_FREE_BYTES") {
+            config.min_free_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_MIN_FREE_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_COMPACTION_BYTES_PER_SECOND") {
+            config.compaction_bytes_per_second = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_COMPACTION_BYTES_PER_SECOND."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_COMPACTION_DROP_CACHE") {
+            config.compaction_drop_cache = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_COMPACTION_DROP_CACHE."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_MAX_MERGE_FAN_IN") {
+            config.max_merge_fan_in = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_MAX_MERGE_FAN_IN."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_COMPACTION_MIN_OVERLAP") {
+            config.compaction_min_overlap = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_COMPACTION_MIN_OVERLAP."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_READAHEAD_BYTES") {
+            config.readahead_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_READAHEAD_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_SNAPSHOT_TTL_MS") {
+            config.snapshot_ttl_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_SNAPSHOT_TTL_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_SOFT_DELETE_RETENTION_MS") {
+            config.soft_delete_retention_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_SOFT_DELETE_RETENTION_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_FSYNC") {
+            config.fsync = match value.to_lowercase().as_str() {
+                "always"        => FsyncPolicy::Always,
+                "every_n_ms"    => FsyncPolicy::EveryNMs,
+                "on_flush_only" => FsyncPolicy::OnFlushOnly,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_FSYNC."))
+            };
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_FSYNC_INTERVAL_MS") {
+            config.fsync_interval_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_FSYNC_INTERVAL_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_CHECKPOINT_INTERVAL_MS") {
+            config.checkpoint_interval_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_CHECKPOINT_INTERVAL_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_ARCHIVE_AFTER_DAYS") {
+            config.archive_after_days = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_ARCHIVE_AFTER_DAYS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_ARCHIVE_DIRECTORY") {
+            config.archive_directory = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_GC_GARBAGE_RATIO") {
+            config.gc_garbage_ratio = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_GC_GARBAGE_RATIO."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_KEY_MAX_LENGTH") {
+            config.key_max_length = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_KEY_MAX_LENGTH."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_KEY_CHARSET") {
+            config.key_charset = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_REJECT_EMPTY_KEYS") {
+            config.reject_empty_keys = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_REJECT_EMPTY_KEYS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_KEY_NORMALIZATION") {
+            config.key_normalization = match value.to_lowercase().as_str() {
+                "none"      => KeyNormalization::None,
+                "lowercase" => KeyNormalization::Lowercase,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_KEY_NORMALIZATION."))
+            };
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_WORKER_THREADS") {
+            config.worker_threads = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_WORKER_THREADS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_QUEUE_DEPTH") {
+            config.queue_depth = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_QUEUE_DEPTH."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_MAX_QUERY_BYTES") {
+            config.max_query_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_MAX_QUERY_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_MAX_CONCURRENT_QUERY_BYTES") {
+            config.max_concurrent_query_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_MAX_CONCURRENT_QUERY_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_MAX_BODY_BYTES") {
+            config.max_body_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_MAX_BODY_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_ESTIMATED_VALUE_BYTES") {
+            config.estimated_value_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_ESTIMATED_VALUE_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_SLOW_QUERY_MS") {
+            config.slow_query_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_SLOW_QUERY_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_OTLP_ENDPOINT") {
+            config.otlp_endpoint = value;
+        }
+
+        // Access control rules are given as a JSON list, in the same
+        // form as in the config file.
+        if let Ok(value) = env::var("LARGETABLE_ACCESS_CONTROL") {
+            config.access_control = serde_json::from_str(&value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_ACCESS_CONTROL."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_AUDIT_DIRECTORY") {
+            config.audit_directory = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_AUDIT_MAX_BYTES") {
+            config.audit_max_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_AUDIT_MAX_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_AUDIT_MAX_FILES") {
+            config.audit_max_files = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_AUDIT_MAX_FILES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_COMMIT_LOG_ARCHIVE_DIRECTORY") {
+            config.commit_log_archive_directory = value;
+        }
+
+        // The shard map is given as JSON, in the same form as in the
+        // config file.
+        if let Ok(value) = env::var("LARGETABLE_SHARD_MAP") {
+            config.shard_map = serde_json::from_str(&value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_SHARD_MAP."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_SHARD_ADDRESS") {
+            config.shard_address = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_REPLICATE_FROM") {
+            config.replicate_from = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_REPLICATION_AUTH_TOKEN") {
+            config.replication_auth_token = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_REPLICATION_POLL_MS") {
+            config.replication_poll_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_REPLICATION_POLL_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_HINT_DIRECTORY") {
+            config.hint_directory = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_HINT_MAX_BYTES") {
+            config.hint_max_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_HINT_MAX_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_HINT_MAX_AGE_MS") {
+            config.hint_max_age_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_HINT_MAX_AGE_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_NODE_ID") {
+            config.node_id = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_LEASE_MS") {
+            config.lease_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_LEASE_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_ACK_TIMEOUT_MS") {
+            config.ack_timeout_ms = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_ACK_TIMEOUT_MS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_IDEMPOTENCY_KEYS") {
+            config.idempotency_keys = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_IDEMPOTENCY_KEYS."))?;
+        }
+
+        // Table schemas are given as a JSON list, in the same form as in
+        // the config file.
+        if let Ok(value) = env::var("LARGETABLE_TABLES") {
+            config.tables = serde_json::from_str(&value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_TABLES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_FAMILIES") {
+            config.families = serde_json::from_str(&value).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_FAMILIES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_LOG") {
+            config.log_level = value;
+        }
+        if logger::parse_level(&config.log_level).is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for log_level."));
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_LOG_FILE") {
+            config.log_file = value;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_LOG_MAX_BYTES") {
+            config.log_max_bytes = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_LOG_MAX_BYTES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_LOG_MAX_AGE_HOURS") {
+            config.log_max_age_hours = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_LOG_MAX_AGE_HOURS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_LOG_MAX_FILES") {
+            config.log_max_files = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_LOG_MAX_FILES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_ROW_CACHE_ROWS") {
+            config.row_cache_rows = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_ROW_CACHE_ROWS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_ROW_CACHE_MODE") {
+            config.row_cache_mode = match value.to_lowercase().as_str() {
+                "invalidate"    => CacheMode::Invalidate,
+                "write_through" => CacheMode::WriteThrough,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_ROW_CACHE_MODE."))
+            };
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_HOT_ROW_QPS") {
+            config.hot_row_qps = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_HOT_ROW_QPS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_RESULT_CACHE_ENTRIES") {
+            config.result_cache_entries = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_RESULT_CACHE_ENTRIES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_PREPARED_QUERIES") {
+            config.prepared_queries = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_PREPARED_QUERIES."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_LAZY_HEADERS") {
+            config.lazy_headers = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_LAZY_HEADERS."))?;
+        }
+
+        if let Ok(value) = env::var("LARGETABLE_HEADER_CACHE_BLOCKS") {
+            config.header_cache_blocks = value.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid value specified for LARGETABLE_HEADER_CACHE_BLOCKS."))?;
+        }
+
         Ok(config)
     }
 }
@@ -0,0 +1,161 @@
+/*
+    hedged.rs
+
+    The HedgedClient talks to several replicas which serve the same data.
+    Reads go to the replica which has been answering fastest, and if it
+    hasn't answered within hedge_delay_ms, the same read is sent to the
+    next fastest as well. Whichever answers first wins. This cuts the tail
+    latency caused by one slow or stalled server, at the cost of a few
+    duplicate reads. Writes always go to the first replica, the primary.
+*/
+
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+
+use time;
+
+use query;
+use {LargeClient, ClientError, Client};
+
+// A request which fails counts as taking this long, so that replicas
+// which are down sink to the back of the queue.
+const FAILURE_PENALTY_MS: u64 = 1000;
+
+pub struct HedgedClient {
+    replicas: Vec<LargeClient>,
+
+    // A moving average of each replica's response time, in microseconds.
+    // Zero means that the replica hasn't answered yet.
+    latencies: Arc<Mutex<Vec<u64>>>,
+
+    // How long to wait for a read before also sending it to the next
+    // replica.
+    pub hedge_delay_ms: u64
+}
+
+impl HedgedClient {
+    // The first replica is the primary, which receives all the writes.
+    pub fn new(replicas: Vec<LargeClient>) -> Result<HedgedClient, ClientError> {
+        if replicas.is_empty() {
+            return Err(ClientError::ConfigurationError);
+        }
+
+        Ok(HedgedClient{
+            latencies: Arc::new(Mutex::new(vec![0; replicas.len()])),
+            replicas: replicas,
+            hedge_delay_ms: 20
+        })
+    }
+
+    pub fn query(&self, q: query::Query) -> query::QueryResult {
+        if q.is_write() || self.replicas.len() == 1 {
+            return self.replicas[0].query(q);
+        }
+
+        let order = fastest_first(&self.latencies.lock().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        let launch = |index: usize| {
+            let client = self.replicas[index].clone();
+            let latencies = self.latencies.clone();
+            let sender = sender.clone();
+            let q = q.clone();
+            thread::spawn(move || {
+                let started = time::precise_time_ns();
+                let result = client.query(q);
+                let elapsed = if failed(&result) {
+                    FAILURE_PENALTY_MS * 1000
+                } else {
+                    (time::precise_time_ns() - started) / 1000
+                };
+                record_latency(&mut latencies.lock().unwrap()[index], elapsed);
+
+                // If another replica already answered, nobody is waiting.
+                sender.send(result).unwrap_or(());
+            });
+        };
+
+        launch(order[0]);
+        let mut launched = 1;
+        let mut pending = 1;
+        let mut last = query::QueryResult::NetworkError;
+        while pending > 0 {
+            let received = if launched < order.len() {
+                receiver.recv_timeout(Duration::from_millis(self.hedge_delay_ms)).ok()
+            } else {
+                receiver.recv().ok()
+            };
+
+            // A replica which can't answer is no reason to wait for the
+            // hedge delay before trying another.
+            if let Some(result) = received {
+                if !failed(&result) {
+                    return result;
+                }
+                last = result;
+                pending -= 1;
+            }
+
+            if launched < order.len() {
+                launch(order[launched]);
+                launched += 1;
+                pending += 1;
+            }
+        }
+        last
+    }
+}
+
+impl Client for HedgedClient {
+    fn query(&self, q: query::Query) -> query::QueryResult {
+        HedgedClient::query(self, q)
+    }
+}
+
+// Whether the replica couldn't answer, so another should be asked.
+fn failed(result: &query::QueryResult) -> bool {
+    match *result {
+        query::QueryResult::NetworkError | query::QueryResult::Busy |
+        query::QueryResult::ResourceExhausted{..} | query::QueryResult::NotPrepared |
+        query::QueryResult::ReplicaBehind => true,
+        _ => false
+    }
+}
+
+// The replica indices, ordered from the fastest to the slowest. Replicas
+// which haven't answered yet come first, so that every one is tried.
+fn fastest_first(latencies: &[u64]) -> Vec<usize> {
+    let mut order = (0..latencies.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| latencies[i]);
+    order
+}
+
+fn record_latency(average: &mut u64, sample: u64) {
+    *average = match *average {
+        0 => sample,
+        a => (a * 7 + sample) / 8
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn prefers_fastest_replicas() {
+        let mut latencies = vec![0, 0, 0];
+        super::record_latency(&mut latencies[0], 500);
+        super::record_latency(&mut latencies[1], 100);
+        assert_eq!(super::fastest_first(&latencies), vec![2, 1, 0]);
+
+        // One fast answer doesn't make up for a history of slow ones.
+        super::record_latency(&mut latencies[2], 300);
+        super::record_latency(&mut latencies[0], 1);
+        assert_eq!(latencies[0], 437);
+        assert_eq!(super::fastest_first(&latencies), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn needs_a_replica() {
+        assert!(super::HedgedClient::new(vec![]).is_err());
+    }
+}
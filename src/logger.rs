@@ -1,37 +1,151 @@
 /*
     logger.rs
 
-    An implementation of the logger, which prints out info/debug
-    information.
+    An implementation of the logger, which prints out messages at or
+    above the configured level, either to stdout or to a log file. Log
+    files are rotated once they get too big or too old: the current file
+    becomes <file>.1, the previous <file>.1 becomes <file>.2, and so on,
+    up to the number of rotated files which are kept.
 */
 
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+
 use time;
 use log;
 use log::{LogRecord, LogMetadata, SetLoggerError, LogLevelFilter};
 
-pub struct ApplicationLogger;
+pub struct ApplicationLogger {
+    level: LogLevelFilter,
+    file: Option<Mutex<LogFile>>
+}
 
 impl log::Log for ApplicationLogger {
-    fn enabled(&self, _: &LogMetadata) -> bool { true }
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
 
     fn log(&self, record: &LogRecord) {
-        if self.enabled(record.metadata()) {
-            println!("[{}] {}", time::strftime("%b %d, %I:%M:%S%P", &time::now()).unwrap(), record.args());
+        if !self.enabled(record.metadata()) {
+            return;
         }
+
+        let line = format!("[{}] {}", time::strftime("%b %d, %I:%M:%S%P", &time::now()).unwrap(), record.args());
+        match self.file {
+            Some(ref f) => {
+                // If the log file can't be written, the message still
+                // shouldn't be lost.
+                let written = f.lock()
+                    .map(|mut f| f.write_line(&line, time::precise_time_ns()).is_ok())
+                    .unwrap_or(false);
+                if !written {
+                    println!("{}", line);
+                }
+            },
+            None => println!("{}", line)
+        };
     }
 }
 
 impl ApplicationLogger {
     pub fn init() -> Result<(), SetLoggerError> {
+        ApplicationLogger::init_with(LogLevelFilter::Info, None)
+    }
+
+    // Log messages at or above the level, to the file if there is one, and
+    // otherwise to stdout.
+    pub fn init_with(level: LogLevelFilter, file: Option<LogFile>) -> Result<(), SetLoggerError> {
         log::set_logger(|max_log_level| {
-            max_log_level.set(LogLevelFilter::Info);
-            Box::new(ApplicationLogger)
+            max_log_level.set(level);
+            Box::new(ApplicationLogger{
+                level: level,
+                file: file.map(Mutex::new)
+            })
         })
     }
 }
 
+// Log levels are written as in the config, e.g. "warn" or "debug". "off"
+// turns logging off.
+pub fn parse_level(level: &str) -> Option<LogLevelFilter> {
+    level.trim().parse::<LogLevelFilter>().ok()
+}
+
+pub struct LogFile {
+    path: String,
+    file: fs::File,
+    bytes: u64,
+    opened: u64,
+
+    // The size at which the file is rotated, and how long it's written
+    // to before it's rotated, or 0 for no limit. max_files: the number of
+    // rotated files to keep.
+    pub max_bytes: u64,
+    pub max_age_ms: u64,
+    pub max_files: usize
+}
+
+impl LogFile {
+    pub fn open(path: &str) -> io::Result<LogFile> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let bytes = file.metadata()?.len();
+        Ok(LogFile{
+            path: path.to_owned(),
+            file: file,
+            bytes: bytes,
+            opened: time::precise_time_ns(),
+            max_bytes: 0,
+            max_age_ms: 0,
+            max_files: 5
+        })
+    }
+
+    // Append the line at time `now`, rotating the file first if it's due.
+    pub fn write_line(&mut self, line: &str, now: u64) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        let too_big = self.max_bytes > 0 && self.bytes > 0 && self.bytes + length > self.max_bytes;
+        let too_old = self.max_age_ms > 0 && now.saturating_sub(self.opened) > self.max_age_ms * 1_000_000;
+        if too_big || too_old {
+            self.rotate(now)?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.bytes += length;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> String {
+        format!("{}.{}", self.path, index)
+    }
+
+    fn rotate(&mut self, now: u64) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::remove_file(self.rotated_path(self.max_files)).unwrap_or(());
+            for index in (1..self.max_files).rev() {
+                fs::rename(self.rotated_path(index), self.rotated_path(index + 1)).unwrap_or(());
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.bytes = 0;
+        self.opened = now;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use log::LogLevelFilter;
+    use largetable_core::tempdir;
+
     #[test]
     fn can_use_logger() {
         super::ApplicationLogger::init().unwrap();
@@ -39,4 +153,42 @@ mod tests {
         warn!("Warning message!");
         error!("Error message!");
     }
+
+    #[test]
+    fn can_parse_levels() {
+        assert_eq!(super::parse_level("warn"), Some(LogLevelFilter::Warn));
+        assert_eq!(super::parse_level("DEBUG"), Some(LogLevelFilter::Debug));
+        assert_eq!(super::parse_level("off"), Some(LogLevelFilter::Off));
+        assert_eq!(super::parse_level("loud"), None);
+    }
+
+    #[test]
+    fn rotates_log_files() {
+        let directory = tempdir::TempDir::new("logs").unwrap();
+        let path = format!("{}/largetable.log", directory.path());
+        let mut f = super::LogFile::open(&path).unwrap();
+        f.max_bytes = 10;
+        f.max_age_ms = 1000;
+        f.max_files = 2;
+
+        // Each line fills up the file, so every write after the first
+        // rotates it, and only the two newest rotated files are kept.
+        for (i, line) in ["first", "second", "third", "fourth"].iter().enumerate() {
+            f.write_line(&format!("{:<8}", line), i as u64).unwrap();
+        }
+        let read = |p: &str| {
+            let mut s = String::new();
+            fs::File::open(p).unwrap().read_to_string(&mut s).unwrap();
+            s
+        };
+        assert_eq!(read(&path), "fourth  \n");
+        assert_eq!(read(&format!("{}.1", path)), "third   \n");
+        assert_eq!(read(&format!("{}.2", path)), "second  \n");
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+
+        // Files are also rotated once they're old enough.
+        f.max_bytes = 0;
+        f.write_line("late", 2_000_000_000).unwrap();
+        assert_eq!(read(&path), "late\n");
+    }
 }
@@ -15,50 +15,728 @@ extern crate serde_derive;
 extern crate test;
 
 extern crate protobuf;
+extern crate serde;
 extern crate serde_yaml;
 extern crate serde_json;
-extern crate rand;
 extern crate time;
-extern crate regex;
-extern crate glob;
-extern crate byteorder;
+extern crate rand;
+extern crate largetable_core;
+extern crate largeclient;
 
 extern crate hyper;
 use hyper::server::{Server, Request, Response, Handler};
 use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use hyper::header::ContentType;
 
-use std::io::Write;
-use std::sync::Mutex;
+use std::io::{Read, Write};
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
 use protobuf::Message;
+use serde::Serialize;
+
+use largetable_core::{audit, base, budget, election, migration, query, replication, shards, spans, Database};
+use largeclient::{compression, request_context, unix};
 
-mod base;
 mod config;
-mod generated;
-mod mtable;
-mod dtable;
-mod query;
 mod logger;
+mod otlp;
+mod pool;
+mod replica;
+
+// The number of queries shown on the status page.
+const RECENT_QUERIES_LENGTH: usize = 20;
+
+// Streamed values are written to the client in chunks of this size.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Requests can carry a trace ID in this header, and it's sent back with
+// the response. Requests without one are given a new trace ID.
+const TRACE_ID_HEADER: &'static str = "X-Trace-Id";
+
+// Responses say which timestamp the query ran at, which for a write is
+// its commit timestamp.
+const TIMESTAMP_HEADER: &'static str = "X-Largetable-Timestamp";
+
+// Replicas are sent at most this many bytes of the commit log at once.
+const REPLICATION_BATCH_BYTES: usize = 1 << 20;
+
+// How often a write with an ack level checks whether enough replicas
+// have read it yet.
+const ACK_POLL_MS: u64 = 5;
+
+// Protobuf responses say which version of the wire protocol they use.
+const PROTOCOL_HEADER: &'static str = "X-Largetable-Protocol";
+const PROTOBUF_CONTENT_TYPE: &'static str = "application/x-protobuf";
+
+#[derive(Serialize, Clone)]
+struct RecentQuery {
+    query: String,
+    result: String,
+    trace_id: String,
+    timestamp: u64
+}
+
+// Status is what the admin UI polls to draw the status page.
+#[derive(Serialize)]
+struct Status {
+    now: u64,
+    stats: query::Stats,
+    recent_queries: Vec<RecentQuery>,
+    compactions: Vec<base::Compaction>,
+    workers: pool::PoolStats
+}
+
+// A DebugResponse is the answer to a JSON query with "debug": true.
+#[derive(Serialize)]
+struct DebugResponse<'a> {
+    result: &'a query::QueryResult,
+    debug: query::QueryCost
+}
+
+// An AuditSearch asks for the most recent audit log entries which
+// changed a row.
+#[derive(Deserialize)]
+struct AuditSearch {
+    row: String,
+    #[serde(default="default_audit_search_limit")]
+    limit: usize
+}
+
+fn default_audit_search_limit() -> usize { 100 }
+
+#[derive(Serialize)]
+struct AuditSearchResult {
+    entries: Vec<audit::AuditEntry>
+}
+
+#[derive(Serialize)]
+struct MigrationResult {
+    migrated_dtables: usize
+}
+
+// Listeners and paths can be restricted to only reads or only writes,
+// so that the two kinds of traffic can be firewalled and routed
+// separately.
+#[derive(Clone, Copy)]
+enum Access {
+    All,
+    ReadOnly,
+    WriteOnly
+}
+
+impl Access {
+    fn allows(&self, q: &query::Query) -> bool {
+        match *self {
+            Access::All         => true,
+            Access::ReadOnly    => !q.is_write(),
+            Access::WriteOnly   => q.is_write()
+        }
+    }
+}
 
 struct RequestHandler {
-    database: Mutex<base::Base>,
-    config: config::ApplicationConfig
+    database: Arc<Database>,
+    pool: pool::WorkerPool,
+    exporter: Option<Arc<otlp::Exporter>>,
+    election: Option<Arc<Mutex<election::Election>>>,
+    config: config::ApplicationConfig,
+    recent_queries: Mutex<VecDeque<RecentQuery>>
+}
+
+impl RequestHandler {
+    // Runs the query on the worker pool, and remembers it so that it can
+    // be shown on the status page. If the pool's queue is full, the
+    // query isn't run and the result is Busy. The query is only run if
+    // every one of the access rules allows it. A write is only reported
+    // as done once its ack level is met. Returns the result, the
+    // timestamp that the query ran at, or zero if it wasn't run, and what
+    // the query cost.
+    fn run_query(&self, q: query::Query, access: &[Access], context: &query::QueryContext) -> (query::QueryResult, u64, query::QueryCost) {
+        let description = format!("{}", q);
+        let write = q.is_write();
+        let (result, timestamp, cost) = if !access.iter().all(|a| a.allows(&q)) {
+            (query::QueryResult::NotAllowed, 0, query::QueryCost::default())
+        } else {
+            match self.pool.run(q, context.clone()) {
+                Ok(r)                       => r,
+                Err(pool::PoolError::Busy)  => (query::QueryResult::Busy, 0, query::QueryCost::default())
+            }
+        };
+        let result = match result {
+            query::QueryResult::Done if write && context.ack_level != query::AckLevel::Leader =>
+                self.await_replicas(context.ack_level),
+            r => r
+        };
+
+        let mut recent = self.recent_queries.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == RECENT_QUERIES_LENGTH {
+            recent.pop_front();
+        }
+        recent.push_back(RecentQuery{
+            query: description,
+            result: format!("{}", result),
+            trace_id: context.trace_id.clone(),
+            timestamp: time::precise_time_ns()
+        });
+
+        (result, timestamp, cost)
+    }
+
+    fn status(&self) -> Status {
+        let database = self.database.lock();
+        Status{
+            now: time::precise_time_ns(),
+            stats: match database.stats() {
+                query::QueryResult::Stats{stats: s} => s,
+                _ => query::Stats::default()
+            },
+            recent_queries: self.recent_queries.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect(),
+            compactions: database.compaction_history(),
+            workers: self.pool.stats()
+        }
+    }
+
+    // Runs a query written in the same JSON format that the CLI accepts,
+    // and responds with the result encoded as JSON. This makes it easy
+    // to poke at the database with curl. If the query asks for debug
+    // output, the result is wrapped in an object along with its cost.
+    fn handle_json(&self, mut req: Request, mut res: Response, access: Access, context: &query::QueryContext) {
+        let gzip = compression::accepts_gzip(&req.headers);
+        let parsed = read_body(&mut req, self.config.max_body_bytes).and_then(|body| {
+            String::from_utf8(body).ok().and_then(|b| query::Query::parse_request(&b).ok())
+        });
+
+        res.headers_mut().set(ContentType::json());
+        match parsed {
+            Some((q, debug)) => {
+                let (result, timestamp, cost) = self.run_query(q, &[access], context);
+                *res.status_mut() = status_code(&result);
+                set_timestamp(&mut res, timestamp);
+                let json = match debug {
+                    true  => serde_json::to_string(&DebugResponse{result: &result, debug: cost}).map_err(|_| ()),
+                    false => result.as_json().map_err(|_| ())
+                };
+                match json {
+                    Ok(json) => send_body(res, json.as_bytes(), gzip),
+                    Err(_)   => *res.status_mut() = StatusCode::InternalServerError
+                };
+            },
+            None    => {
+                info!("received JSON query with invalid data (trace_id={})", context.trace_id);
+                *res.status_mut() = StatusCode::BadRequest;
+                send_body(res, br#"{"error":"invalid query"}"#, false);
+            }
+        };
+    }
+
+    // Runs a protobuf select for a single column, and streams the value
+    // back as the raw response body, in chunks. This way a big value
+    // isn't wrapped in a protobuf message which both sides would need
+    // to hold in memory at once, although the value itself is still read
+    // into memory here before it's sent. A missing row or column is a 404.
+    fn handle_stream(&self, mut req: Request, mut res: Response, access: Access, context: &query::QueryContext) {
+        let q = match read_body(&mut req, self.config.max_body_bytes).and_then(|body| query::Query::from_bytes(&mut &body[..]).ok()) {
+            Some(q) => q,
+            None    => {
+                info!("received stream query with invalid data (trace_id={})", context.trace_id);
+                *res.status_mut() = StatusCode::BadRequest;
+                return;
+            }
+        };
+
+        match q {
+            query::Query::Select{ref get, ref project, ref column_start, column_limit: 0, ..}
+                if get.len() + project.len() == 1 && column_start.is_empty() => (),
+            _ => {
+                info!("stream queries must select exactly one column");
+                *res.status_mut() = StatusCode::BadRequest;
+                return;
+            }
+        };
+
+        let value = match self.run_query(q, &[access], context).0 {
+            query::QueryResult::Data{columns: mut c} => c.pop().and_then(|x| x),
+            query::QueryResult::RowNotFound |
+            query::QueryResult::SnapshotNotFound => None,
+            r => {
+                *res.status_mut() = match status_code(&r) {
+                    StatusCode::Ok  => StatusCode::InternalServerError,
+                    s               => s
+                };
+                return;
+            }
+        };
+
+        match value {
+            Some(v) => {
+                let mut w = match res.start() {
+                    Ok(w)   => w,
+                    Err(e)  => {
+                        info!("unable to start streaming a value: {}", e);
+                        return;
+                    }
+                };
+                for chunk in v.chunks(STREAM_CHUNK_SIZE) {
+                    if w.write_all(chunk).and_then(|_| w.flush()).is_err() {
+                        info!("client went away while streaming a value");
+                        return;
+                    }
+                }
+                w.end().unwrap_or(());
+            },
+            None    => *res.status_mut() = StatusCode::NotFound
+        };
+    }
+
+    // Searches the audit log for changes to a row. This counts as a read
+    // of the row, so the caller's token has to be able to read it.
+    fn handle_audit(&self, mut req: Request, mut res: Response, access: Access, context: &query::QueryContext) {
+        let search = read_body(&mut req, self.config.max_body_bytes)
+            .and_then(|body| serde_json::from_slice::<AuditSearch>(&body).ok());
+
+        res.headers_mut().set(ContentType::json());
+        let search = match search {
+            Some(s) => s,
+            None    => {
+                *res.status_mut() = StatusCode::BadRequest;
+                send_body(res, br#"{"error":"invalid search"}"#, false);
+                return;
+            }
+        };
+
+        if let Access::WriteOnly = access {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        let database = self.database.lock();
+        if !database.access_control.can_read(&context.auth_token, &search.row) {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        match database.search_audit_log(&search.row, search.limit) {
+            Ok(entries) => {
+                send_json(res, &AuditSearchResult{entries: entries}, context);
+            },
+            Err(e)      => {
+                error!("unable to search the audit log: {} (trace_id={})", e, context.trace_id);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        };
+    }
+
+    // Runs a column migration (see migration.rs) while the server is up.
+    // The database is locked until it finishes. It counts as a write to
+    // every row with the migration's prefix.
+    fn handle_migrate(&self, mut req: Request, mut res: Response, access: Access, context: &query::QueryContext) {
+        let migration = read_body(&mut req, self.config.max_body_bytes)
+            .and_then(|body| serde_json::from_slice::<migration::ColumnMigration>(&body).ok());
+
+        res.headers_mut().set(ContentType::json());
+        let migration = match migration {
+            Some(m) => m,
+            None    => {
+                *res.status_mut() = StatusCode::BadRequest;
+                send_body(res, br#"{"error":"invalid migration"}"#, false);
+                return;
+            }
+        };
+
+        if let Access::ReadOnly = access {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        let mut database = self.database.lock();
+        if !database.access_control.can_write(&context.auth_token, &migration.prefix) {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+        if !database.accepts_writes() {
+            *res.status_mut() = StatusCode::ServiceUnavailable;
+            send_body(res, br#"{"error":"not the leader"}"#, false);
+            return;
+        }
+
+        match database.migrate_columns(&migration) {
+            Ok(n)   => {
+                info!("migrated {} dtables (trace_id={})", n, context.trace_id);
+                send_json(res, &MigrationResult{migrated_dtables: n}, context);
+            },
+            Err(e)  => {
+                error!("unable to migrate columns: {} (trace_id={})", e, context.trace_id);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        };
+    }
+
+    // Streams a snapshot to a new replica (see replication.rs). Every row
+    // is sent, so the caller's token has to be able to read all of them.
+    // The database is only locked while the files are opened. If it fails
+    // part way, the replica sees the stream cut short.
+    fn handle_snapshot(&self, mut res: Response, access: Access, context: &query::QueryContext) {
+        if let Access::WriteOnly = access {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        let snapshot = {
+            let database = self.database.lock();
+            if !database.access_control.can_read(&context.auth_token, "") {
+                *res.status_mut() = StatusCode::Forbidden;
+                return;
+            }
+            database.open_snapshot()
+        };
+        let snapshot = match snapshot {
+            Ok(s)   => s,
+            Err(e)  => {
+                error!("unable to take a snapshot: {} (trace_id={})", e, context.trace_id);
+                *res.status_mut() = StatusCode::InternalServerError;
+                return;
+            }
+        };
+
+        let mut w = match res.start() {
+            Ok(w)   => w,
+            Err(e)  => {
+                info!("unable to start streaming a snapshot: {}", e);
+                return;
+            }
+        };
+        match snapshot.write_to(&mut w) {
+            Ok(p)   => info!("sent a snapshot at {} (trace_id={})", p, context.trace_id),
+            Err(e)  => {
+                error!("unable to send a snapshot: {} (trace_id={})", e, context.trace_id);
+                return;
+            }
+        };
+        w.end().unwrap_or(());
+    }
+
+    // Replaces the shard map with a newer one, after resharding. Servers
+    // don't tell each other, so the new map has to be posted to each of
+    // them. It counts as a write to every row.
+    fn handle_topology(&self, mut req: Request, mut res: Response, access: Access, context: &query::QueryContext) {
+        let map = read_body(&mut req, self.config.max_body_bytes)
+            .and_then(|body| serde_json::from_slice::<shards::ShardMap>(&body).ok());
+
+        res.headers_mut().set(ContentType::json());
+        let map = match map {
+            Some(m) => m,
+            None    => {
+                *res.status_mut() = StatusCode::BadRequest;
+                send_body(res, br#"{"error":"invalid shard map"}"#, false);
+                return;
+            }
+        };
+
+        if let Access::ReadOnly = access {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        let mut database = self.database.lock();
+        if !database.access_control.can_write(&context.auth_token, "") {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        match database.update_shard_map(map) {
+            Ok(_)   => send_json(res, &database.shard_map, context),
+            Err(e)  => {
+                info!("refused shard map: {} (trace_id={})", e, context.trace_id);
+                *res.status_mut() = StatusCode::BadRequest;
+                send_body(res, br#"{"error":"invalid shard map"}"#, false);
+            }
+        };
+    }
+
+    // Wait for enough replicas to have read the commit log as far as it's
+    // got, after a write with the ack level, for up to ack_timeout_ms.
+    // The database isn't locked in between, so that the replicas can
+    // read it. Either way, the write has been applied here.
+    fn await_replicas(&self, level: query::AckLevel) -> query::QueryResult {
+        let position = match self.database.lock().replication_position() {
+            Ok(p)   => p,
+            Err(e)  => return query::QueryResult::InternalError{error: format!("{}", e)}
+        };
+        let deadline = time::precise_time_ns() + self.config.ack_timeout_ms * 1_000_000;
+        loop {
+            let (in_sync, followers) = self.database.lock().replicas_at(&position);
+            if level.is_met(in_sync, followers) {
+                return query::QueryResult::Done;
+            }
+            if time::precise_time_ns() >= deadline {
+                return query::QueryResult::InsufficientReplicas;
+            }
+            thread::sleep(Duration::from_millis(ACK_POLL_MS));
+        }
+    }
+
+    // This server's lease, encoded for a replica, if it's the leader.
+    fn current_lease(&self) -> Option<String> {
+        let now = time::precise_time_ns();
+        let e = match self.election {
+            Some(ref e) => e.lock().unwrap(),
+            None        => return None
+        };
+        match e.is_leader(now) {
+            true    => e.lease().map(|l| replica::encode_lease(l, now)),
+            false   => None
+        }
+    }
+
+    // Sends a replica the commit log entries after the position in the
+    // request, and the position to carry on from in a header. If they've
+    // been truncated away and weren't archived, the response is a 410,
+    // and the replica needs a new snapshot.
+    fn handle_replicate(&self, mut req: Request, mut res: Response, access: Access, context: &query::QueryContext) {
+        let position = read_body(&mut req, self.config.max_body_bytes)
+            .and_then(|body| serde_json::from_slice::<replication::Position>(&body).ok());
+        let position = match position {
+            Some(p) => p,
+            None    => {
+                *res.status_mut() = StatusCode::BadRequest;
+                send_body(res, b"invalid position", false);
+                return;
+            }
+        };
+
+        if let Access::WriteOnly = access {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        let peer = req.headers.get_raw(replica::PEER_HEADER)
+            .and_then(|v| v.first())
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .unwrap_or("")
+            .to_owned();
+        let mut database = self.database.lock();
+        if !database.access_control.can_read(&context.auth_token, "") {
+            *res.status_mut() = StatusCode::Forbidden;
+            return;
+        }
+
+        match database.replicate_to(&peer, &position, REPLICATION_BATCH_BYTES) {
+            Ok(Some((data, next))) => {
+                res.headers_mut().set_raw(replica::POSITION_HEADER, vec![format!("{}", next).into_bytes()]);
+                if let Ok(Some(timestamp)) = database.replicated_through(&next) {
+                    res.headers_mut().set_raw(replica::CAUGHT_UP_HEADER, vec![format!("{}", timestamp).into_bytes()]);
+                }
+                if let Some(lease) = self.current_lease() {
+                    res.headers_mut().set_raw(replica::LEASE_HEADER, vec![lease.into_bytes()]);
+                }
+                send_body(res, &data, false);
+            },
+            Ok(None)    => *res.status_mut() = StatusCode::Gone,
+            Err(e)      => {
+                error!("unable to read the commit log at {}: {} (trace_id={})", position, e, context.trace_id);
+                *res.status_mut() = StatusCode::InternalServerError;
+            }
+        };
+    }
+}
+
+// Read the whole request body, decompressing it if the client sent it
+// compressed. Bodies which are larger than limit once decompressed are
+// refused, unless the limit is 0.
+fn read_body(req: &mut Request, limit: u64) -> Option<Vec<u8>> {
+    let limit = if limit == 0 { std::u64::MAX } else { limit };
+    let headers = req.headers.clone();
+    let mut body = vec![];
+    match compression::decode(&headers, req, limit).and_then(|mut r| r.read_to_end(&mut body)) {
+        Ok(_)   => Some(body),
+        Err(_)  => None
+    }
+}
+
+// Send the response body, compressed if the client accepts that and it's
+// big enough to be worth compressing.
+fn send_body(mut res: Response, body: &[u8], gzip: bool) {
+    if gzip && body.len() >= compression::MIN_COMPRESSED_SIZE {
+        if let Ok(compressed) = compression::compress(body) {
+            res.headers_mut().set_raw("Content-Encoding", vec![compression::GZIP.as_bytes().to_vec()]);
+            res.send(&compressed).unwrap_or(());
+            return;
+        }
+    }
+    res.send(body).unwrap_or(());
 }
 
-impl Handler for RequestHandler {
-    fn handle(&self, mut req: Request, mut res: Response) {
+// Send the value encoded as JSON.
+fn send_json<T: Serialize>(mut res: Response, value: &T, context: &query::QueryContext) {
+    match serde_json::to_string(value) {
+        Ok(json) => send_body(res, json.as_bytes(), false),
+        Err(e)   => {
+            error!("unable to encode response: {} (trace_id={})", e, context.trace_id);
+            *res.status_mut() = StatusCode::InternalServerError;
+        }
+    };
+}
+
+// The HTTP status which goes along with a query result, for clients
+// which don't decode the result itself.
+fn status_code(result: &query::QueryResult) -> StatusCode {
+    match *result {
+        query::QueryResult::Busy        => StatusCode::ServiceUnavailable,
+        query::QueryResult::NotAllowed  => StatusCode::Forbidden,
+        query::QueryResult::PermissionDenied => StatusCode::Forbidden,
+        query::QueryResult::InvalidKey  => StatusCode::BadRequest,
+        query::QueryResult::SchemaViolation{..} => StatusCode::BadRequest,
+        query::QueryResult::InsufficientReplicas => StatusCode::ServiceUnavailable,
+        query::QueryResult::ReplicaBehind => StatusCode::ServiceUnavailable,
+        query::QueryResult::NotLeader => StatusCode::ServiceUnavailable,
+        query::QueryResult::ResourceExhausted{..} => StatusCode::ServiceUnavailable,
+        // 421 Misdirected Request.
+        query::QueryResult::WrongShard{..} => StatusCode::Unregistered(421),
+        query::QueryResult::InternalError{..} => StatusCode::InternalServerError,
+        query::QueryResult::PartialCommit{..} => StatusCode::InternalServerError,
+        _                               => StatusCode::Ok
+    }
+}
+
+// Tell the client which timestamp the query ran at, if it was run.
+fn set_timestamp(res: &mut Response, timestamp: u64) {
+    if timestamp > 0 {
+        res.headers_mut().set_raw(TIMESTAMP_HEADER, vec![format!("{}", timestamp).into_bytes()]);
+    }
+}
+
+// A Listener serves requests arriving on one port, which might be
+// restricted to only reads or only writes.
+struct Listener {
+    handler: Arc<RequestHandler>,
+    access: Access
+}
+
+impl Handler for Listener {
+    fn handle(&self, req: Request, mut res: Response) {
+        let mut context = request_context(&req);
+        res.headers_mut().set_raw(TRACE_ID_HEADER, vec![context.trace_id.clone().into_bytes()]);
+
+        // When spans are being exported, the whole request is a span, and
+        // the spans recorded by the database are its children.
+        let exporter = match self.handler.exporter {
+            Some(ref e) => e.clone(),
+            None        => return self.serve_safely(req, res, &context)
+        };
+
+        context.span_id = otlp::new_span_id();
+        let name = format!("{} {}", req.method, req.uri);
+        let start = time::precise_time_ns();
+        self.serve_safely(req, res, &context);
+        spans::SpanSink::record(&*exporter, spans::Span{
+            trace_id: context.trace_id.clone(),
+            span_id: context.span_id.clone(),
+            parent_span_id: String::new(),
+            name: name,
+            start: start,
+            end: time::precise_time_ns(),
+            attributes: vec![]
+        });
+    }
+}
+
+impl Listener {
+    // Serve the request, making sure that a bug in handling it doesn't
+    // take down the connection thread. By the time a panic is caught the
+    // response has been sent or dropped, so all that's left is to log it.
+    fn serve_safely(&self, req: Request, res: Response, context: &query::QueryContext) {
+        let served = panic::catch_unwind(panic::AssertUnwindSafe(|| self.serve(req, res, context)));
+        if let Err(e) = served {
+            error!("request handler panicked: {} (trace_id={})", pool::panic_message(&e), context.trace_id);
+        }
+    }
+
+    fn serve(&self, mut req: Request, mut res: Response, context: &query::QueryContext) {
+        let h = &self.handler;
         match req.method {
             hyper::Post => {
-                match query::Query::from_bytes(&mut req) {
-                    Ok(q)   => {
-                        let result = self.database.lock().unwrap().query_now(q);
-                        result.into_generated().write_to_writer(&mut res.start().unwrap()).unwrap();
+                let path = match req.uri {
+                    RequestUri::AbsolutePath(ref path) => path.clone(),
+                    _ => String::new()
+                };
+
+                // Protobuf queries posted to /read or /write are also
+                // restricted to that kind of query. The /v1/ paths are the
+                // versioned protocol for other clients, and they behave
+                // the same as the unversioned ones.
+                let path_access = match path.as_str() {
+                    "/json"                 => return h.handle_json(req, res, self.access, context),
+                    "/stream"               => return h.handle_stream(req, res, self.access, context),
+                    "/audit"                => return h.handle_audit(req, res, self.access, context),
+                    "/migrate"              => return h.handle_migrate(req, res, self.access, context),
+                    "/snapshot"             => return h.handle_snapshot(res, self.access, context),
+                    "/replicate"            => return h.handle_replicate(req, res, self.access, context),
+                    "/topology"             => return h.handle_topology(req, res, self.access, context),
+                    "/read" | "/v1/read"    => Access::ReadOnly,
+                    "/write" | "/v1/write"  => Access::WriteOnly,
+                    _                       => Access::All
+                };
+
+                res.headers_mut().set_raw(PROTOCOL_HEADER, vec![format!("{}", query::PROTOCOL_VERSION).into_bytes()]);
+                let gzip = compression::accepts_gzip(&req.headers);
+                match read_body(&mut req, h.config.max_body_bytes).and_then(|body| query::Query::from_bytes(&mut &body[..]).ok()) {
+                    Some(q) => {
+                        let (result, timestamp, _) = h.run_query(q, &[self.access, path_access], context);
+                        *res.status_mut() = status_code(&result);
+                        set_timestamp(&mut res, timestamp);
+                        res.headers_mut().set_raw("Content-Type", vec![PROTOBUF_CONTENT_TYPE.as_bytes().to_vec()]);
+                        match result.into_generated().write_to_bytes() {
+                            Ok(body) => send_body(res, &body, gzip),
+                            Err(e)   => {
+                                error!("unable to encode query result: {} (trace_id={})", e, context.trace_id);
+                                *res.status_mut() = StatusCode::InternalServerError;
+                            }
+                        };
                     },
-                    Err(_)  => {
-                        info!("received query with invalid data");
-                        res.start().unwrap().write_all(b"invalid data").unwrap();
+                    None    => {
+                        info!("received query with invalid data (trace_id={})", context.trace_id);
+                        *res.status_mut() = StatusCode::BadRequest;
+                        send_body(res, b"invalid data", false);
                     }
                 };
             },
+            hyper::Get => {
+                match req.uri {
+                    RequestUri::AbsolutePath(ref path) if path == "/healthz" => {
+                        let (out_of_space, degraded) = {
+                            let database = h.database.lock();
+                            (database.out_of_space(), database.is_degraded())
+                        };
+                        if out_of_space {
+                            *res.status_mut() = StatusCode::ServiceUnavailable;
+                            send_body(res, b"out of disk space", false);
+                        } else if degraded {
+                            *res.status_mut() = StatusCode::ServiceUnavailable;
+                            send_body(res, b"degraded: a dtable can't be read", false);
+                        } else {
+                            send_body(res, b"ok", false);
+                        }
+                    },
+                    RequestUri::AbsolutePath(ref path) if path == "/topology" => {
+                        res.headers_mut().set(ContentType::json());
+                        let map = h.database.lock().shard_map.clone();
+                        send_json(res, &map, context);
+                    },
+                    RequestUri::AbsolutePath(ref path) if path == "/ui" => {
+                        res.headers_mut().set(ContentType::html());
+                        send_body(res, include_str!("ui.html").as_bytes(), false);
+                    },
+                    RequestUri::AbsolutePath(ref path) if path == "/ui/status" => {
+                        res.headers_mut().set(ContentType::json());
+                        send_json(res, &h.status(), context);
+                    },
+                    _ => *res.status_mut() = StatusCode::NotFound
+                };
+            },
             _ => *res.status_mut() = StatusCode::MethodNotAllowed
         }
     }
@@ -66,11 +744,27 @@ impl Handler for RequestHandler {
 
 fn main() {
     println!("largetable v{}", env!("CARGO_PKG_VERSION"));
-    logger::ApplicationLogger::init().unwrap();
-    info!("loading config file ./config/config.yml");
+
+    // With --check, the data files are checked and the server exits
+    // instead of starting.
+    let check = std::env::args().skip(1).any(|a| a == "--check");
+
+    // The logger is set up by the config, so it only starts once the
+    // config is loaded.
     let config = config::ApplicationConfig::from_yaml(
         "./config/config.yml"
     ).unwrap();
+    let log_file = if config.log_file.is_empty() {
+        None
+    } else {
+        let mut f = logger::LogFile::open(&config.log_file).unwrap();
+        f.max_bytes = config.log_max_bytes;
+        f.max_age_ms = config.log_max_age_hours * 3600 * 1000;
+        f.max_files = config.log_max_files;
+        Some(f)
+    };
+    logger::ApplicationLogger::init_with(logger::parse_level(&config.log_level).unwrap(), log_file).unwrap();
+    info!("loaded config file ./config/config.yml");
 
     info!("loading database, mode = {}", config.mode);
     let mut database = match config.mode {
@@ -80,20 +774,202 @@ fn main() {
             base.memtable_size_limit = config.memtable_size_limit;
             base
         },
-        config::Mode::Production    => base::Base::new(
-            config.datadirectory.as_str(),
-            config.memtable_size_limit,
-            config.disktable_limit
-        )
+        config::Mode::Production    => {
+            // The manifest and commit log live in the first data
+            // directory, while dtables are spread across all of them.
+            let mut base = base::Base::new(
+                config.datadirectory[0].as_str(),
+                config.memtable_size_limit,
+                config.disktable_limit
+            );
+            base.data_directories = config.datadirectory.clone();
+            base.dtable_placement = config.dtable_placement;
+            base
+        }
+    };
+
+    database.min_free_bytes = config.min_free_bytes;
+    database.max_dtable_bytes = config.max_dtable_bytes;
+    database.compaction_bytes_per_second = config.compaction_bytes_per_second;
+    database.compaction_drop_cache = config.compaction_drop_cache;
+    database.max_merge_fan_in = config.max_merge_fan_in;
+    database.compaction_min_overlap = config.compaction_min_overlap;
+    database.readahead_bytes = config.readahead_bytes;
+    database.lazy_headers = config.lazy_headers;
+    database.header_cache_blocks = config.header_cache_blocks;
+    database.snapshot_ttl_ms = config.snapshot_ttl_ms;
+    database.soft_delete_retention_ms = config.soft_delete_retention_ms;
+    database.fsync_policy = config.fsync;
+    database.fsync_interval_ms = config.fsync_interval_ms;
+    database.checkpoint_interval_ms = config.checkpoint_interval_ms;
+    database.archive_after_days = config.archive_after_days;
+    database.archive_directory = config.archive_directory.clone();
+    database.gc_garbage_ratio = config.gc_garbage_ratio;
+    database.key_rules.max_length = config.key_max_length;
+    database.key_rules.reject_empty = config.reject_empty_keys;
+    database.key_rules.normalization = config.key_normalization;
+    database.key_rules.set_allowed_characters(&config.key_charset).unwrap();
+    database.slow_query_ms = config.slow_query_ms;
+    database.idempotency.capacity = config.idempotency_keys;
+    database.row_cache.get_mut().capacity = config.row_cache_rows;
+    database.row_cache.get_mut().mode = config.row_cache_mode;
+    database.hot_rows.qps_limit = config.hot_row_qps;
+    database.result_cache.capacity = config.result_cache_entries;
+    database.prepared.capacity = config.prepared_queries;
+    for rule in config.access_control.iter() {
+        database.access_control.add_rule(rule.clone());
+    }
+    for table in config.tables.iter() {
+        database.key_rules.set_table_order(&table.prefix, table.key_order);
+        database.salting.set_table(&table.prefix, table.salt_buckets);
+        database.schemas.add_table(table.clone());
+        database.transforms.add_table(table).unwrap();
+    }
+    for policy in config.families.iter() {
+        database.families.add_family(policy.clone());
+    }
+    if check {
+        let report = database.check();
+        println!("{}", report);
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
+    if !config.audit_directory.is_empty() {
+        info!("recording writes in the audit log at {}", config.audit_directory);
+        database.enable_audit_log(
+            &config.audit_directory,
+            config.audit_max_bytes,
+            config.audit_max_files
+        ).unwrap();
+    }
+    if !config.commit_log_archive_directory.is_empty() {
+        info!("archiving the commit log in {}", config.commit_log_archive_directory);
+        database.enable_commit_log_archive(&config.commit_log_archive_directory).unwrap();
+    }
+    if !config.hint_directory.is_empty() {
+        info!("keeping hints for replicas in {}", config.hint_directory);
+        database.hint_directory = config.hint_directory.clone();
+        database.hint_max_bytes = config.hint_max_bytes;
+        database.hint_max_age_ms = config.hint_max_age_ms;
+    }
+    if config.shard_map.is_enabled() {
+        info!("serving shard {} of shard map version {}", config.shard_address, config.shard_map.version);
+        database.shard_address = config.shard_address.clone();
+        database.update_shard_map(config.shard_map.clone()).unwrap();
+    }
+    if database.access_control.is_enabled() {
+        info!("access control enabled for {} tokens", config.access_control.len());
+    }
+
+    let exporter = match config.otlp_endpoint.as_str() {
+        ""          => None,
+        endpoint    => {
+            info!("exporting spans to {}", endpoint);
+            Some(otlp::Exporter::start(endpoint))
+        }
+    };
+    if let Some(ref e) = exporter {
+        let sink: Arc<spans::SpanSink> = e.clone();
+        database.span_sink = Some(sink);
+    }
+    info!("key normalization = {}", config.key_normalization);
+    info!("fsync policy = {}", config.fsync);
+    if config.row_cache_rows > 0 {
+        info!("row cache = {} rows, mode = {}", config.row_cache_rows, config.row_cache_mode);
+    }
+
+    // A new replica starts from a snapshot of its primary.
+    let replication = match config.replicate_from.as_str() {
+        ""          => None,
+        primary     => {
+            info!("bootstrapping from {}", primary);
+            Some(replica::bootstrap(&mut database, primary, &config.replication_auth_token).unwrap())
+        }
     };
 
     database.load().unwrap();
+    database.replica = replication.is_some();
 
-    let h = RequestHandler{
-        database: Mutex::new(database),
-        config: config
+    // With leader elections turned on, the primary holds a lease, and a
+    // replica is ready to take over once the primary's lease runs out.
+    // Terms carry on from the newest fencing token in the commit log.
+    // Writes are refused until the lease has been taken out.
+    let election = match config.lease_ms {
+        0           => None,
+        lease_ms    => {
+            let mut e = election::Election::new(&config.node_name(), lease_ms);
+            if database.fencing_token > 0 {
+                e.observe(election::Lease{holder: String::new(), term: database.fencing_token, expires: 0});
+            }
+            database.lease_expires = Some(0);
+            Some(Arc::new(Mutex::new(e)))
+        }
     };
 
-    info!("Listening on port {}.", h.config.port);
-    Server::http(format!("0.0.0.0:{}", h.config.port)).unwrap().handle(h).unwrap();
+    let fsync_policy = database.fsync_policy;
+    let database = Arc::new(Database::from_base(database));
+    if fsync_policy == base::FsyncPolicy::EveryNMs {
+        Database::sync_in_background(&database);
+    }
+    if let Some(position) = replication {
+        info!("tailing the commit log of {} from {}", config.replicate_from, position);
+        replica::tail(
+            database.clone(),
+            config.replicate_from.clone(),
+            config.replication_auth_token.clone(),
+            config.node_name(),
+            config.replication_poll_ms,
+            position,
+            election.clone()
+        );
+    } else if let Some(ref e) = election {
+        info!("leading as {}, with a lease of {}ms", config.node_name(), config.lease_ms);
+        replica::lead(database.clone(), e.clone());
+    }
+    let worker_threads = std::cmp::max(config.worker_threads, 1);
+    info!("worker threads = {}, queue depth = {}", worker_threads, config.queue_depth);
+
+    let h = Arc::new(RequestHandler{
+        pool: pool::WorkerPool::new(database.clone(), worker_threads, config.queue_depth, budget::MemoryBudget::new(
+            config.max_query_bytes,
+            config.max_concurrent_query_bytes,
+            config.estimated_value_bytes
+        )),
+        exporter: exporter,
+        election: election,
+        database: database,
+        config: config,
+        recent_queries: Mutex::new(VecDeque::new())
+    });
+
+    // There are enough connection threads to fill the worker pool and its
+    // queue, with some to spare for turning away requests when it's full
+    // and for serving the health check and status page.
+    let connection_threads = worker_threads + h.config.queue_depth + 4;
+
+    // The optional read and write listeners run alongside the main one,
+    // which accepts every kind of query.
+    for &(port, access, kind) in &[(h.config.read_port, Access::ReadOnly, "reads"), (h.config.write_port, Access::WriteOnly, "writes")] {
+        if port == 0 {
+            continue;
+        }
+        info!("Listening for {} on port {}.", kind, port);
+        let listener = Listener{handler: h.clone(), access: access};
+        let address = h.config.listen_address(port);
+        std::thread::spawn(move || {
+            Server::http(address).unwrap().handle_threads(listener, connection_threads).unwrap();
+        });
+    }
+
+    if !h.config.unix_socket.is_empty() {
+        info!("Listening on unix socket {}.", h.config.unix_socket);
+        let listener = Listener{handler: h.clone(), access: Access::All};
+        let socket = unix::UnixListener::bind(&h.config.unix_socket).unwrap();
+        std::thread::spawn(move || {
+            Server::new(socket).handle_threads(listener, connection_threads).unwrap();
+        });
+    }
+
+    let address = h.config.listen_address(h.config.port);
+    info!("Listening on {}.", address);
+    Server::http(address).unwrap().handle_threads(Listener{handler: h, access: Access::All}, connection_threads).unwrap();
 }
@@ -0,0 +1,82 @@
+/*
+    migrate.rs
+
+    Moves a key range from one server to another, so that the cluster can
+    grow without downtime. Every row in the range is read from the source
+    (from its disktables and memtable alike) and written to the
+    destination. Once clients have been switched over to the destination
+    for that range, the rows are deleted from the source.
+
+    Only the newest value of each column is copied, so the older elements
+    of append-only lists are left behind. Writes to the range should be
+    stopped while it's being copied (e.g. with a read-only access rule),
+    or they may be missed.
+*/
+
+use scanner;
+use query;
+use {LargeClient, ClientError};
+
+pub struct RangeMove {
+    source: LargeClient,
+    destination: LargeClient,
+
+    // The range to move is [start, end). An empty end means that there's
+    // no upper bound.
+    pub start: String,
+    pub end: String,
+
+    // The number of sub-ranges which are copied at the same time.
+    pub parallelism: usize
+}
+
+impl RangeMove {
+    pub fn new(source: &LargeClient, destination: &LargeClient, start: &str, end: &str) -> RangeMove {
+        RangeMove{
+            source: source.clone(),
+            destination: destination.clone(),
+            start: start.to_owned(),
+            end: end.to_owned(),
+            parallelism: 4
+        }
+    }
+
+    // Copy every row in the range to the destination. Copying is safe to
+    // repeat if it fails partway through. Returns the number of rows
+    // which were copied.
+    pub fn copy(&self) -> Result<u64, ClientError> {
+        let mut scanner = scanner::Scanner::new(&self.source, &self.start, &self.end);
+        scanner.parallelism = self.parallelism;
+
+        let mut copied = 0;
+        for row in scanner.scan() {
+            let row = row?;
+            let update = query::Query::Update{
+                row: row.key,
+                set: row.columns.into_iter().collect()
+            };
+            done(self.destination.query(update))?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    // Delete the range from the source. Only do this after the range has
+    // been copied, and clients are reading it from the destination.
+    pub fn delete_source(&self) -> Result<(), ClientError> {
+        done(self.source.query(query::Query::DeleteRange{
+            start_row: self.start.clone(),
+            end_row: self.end.clone()
+        }))
+    }
+}
+
+fn done(result: query::QueryResult) -> Result<(), ClientError> {
+    match result {
+        query::QueryResult::Done                => Ok(()),
+        query::QueryResult::NetworkError        => Err(ClientError::NetworkError),
+        query::QueryResult::Busy                => Err(ClientError::Busy),
+        query::QueryResult::PermissionDenied    => Err(ClientError::PermissionDenied),
+        _                                       => Err(ClientError::RequestFailed)
+    }
+}
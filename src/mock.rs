@@ -0,0 +1,161 @@
+/*
+    mock.rs
+
+    A MockClient answers queries from an in-memory database in the same
+    process, so that applications which use the client can be unit
+    tested without a server. Its results are the real database's, but it
+    can also be told to fail queries with network errors, either the
+    next few or a fraction of them at random, and to add latency, to
+    test how the application copes.
+
+    Clones of a MockClient share its database and failure settings.
+*/
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rand;
+
+use largetable_core::base;
+use largetable_core::storage;
+use largetable_core::Database;
+
+use query;
+use Client;
+
+#[derive(Default)]
+struct Failures {
+    fail_next: usize,
+    error_rate: f64,
+    latency: Duration
+}
+
+#[derive(Clone)]
+pub struct MockClient {
+    // The database behind the client, e.g. to set up data or check on
+    // it without going through the client.
+    pub database: Arc<Database>,
+    failures: Arc<Mutex<Failures>>
+}
+
+impl MockClient {
+    pub fn new() -> MockClient {
+        let mut b = base::Base::with_storage(
+            "/mock",
+            10485760,
+            10,
+            Arc::new(storage::MemoryStorage::new()),
+            Arc::new(storage::SystemClock)
+        );
+        b.load().unwrap();
+
+        MockClient{
+            database: Arc::new(Database::from_base(b)),
+            failures: Arc::new(Mutex::new(Failures::default()))
+        }
+    }
+
+    // Fail the next n queries with network errors.
+    pub fn fail_next(&self, n: usize) {
+        self.failures.lock().unwrap().fail_next = n;
+    }
+
+    // Fail this fraction of queries, between 0 and 1, with network
+    // errors, chosen at random.
+    pub fn set_error_rate(&self, rate: f64) {
+        self.failures.lock().unwrap().error_rate = rate;
+    }
+
+    // Wait this long before answering each query, including failed ones.
+    pub fn set_latency(&self, latency: Duration) {
+        self.failures.lock().unwrap().latency = latency;
+    }
+
+    pub fn query(&self, q: query::Query) -> query::QueryResult {
+        let (fail, latency) = {
+            let mut failures = self.failures.lock().unwrap();
+            let fail = match failures.fail_next {
+                0 => failures.error_rate > 0.0 && rand::random::<f64>() < failures.error_rate,
+                _ => {
+                    failures.fail_next -= 1;
+                    true
+                }
+            };
+            (fail, failures.latency)
+        };
+
+        if latency > Duration::new(0, 0) {
+            thread::sleep(latency);
+        }
+        match fail {
+            true  => query::QueryResult::NetworkError,
+            false => self.database.query(q)
+        }
+    }
+}
+
+impl Client for MockClient {
+    fn query(&self, q: query::Query) -> query::QueryResult {
+        MockClient::query(self, q)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use query;
+    use Client;
+
+    // Application code only needs to know about the Client.
+    fn set_status<C: Client>(client: &C, row: &str) -> query::QueryResult {
+        client.query(query::Query::new_update(row, vec![query::MUpdate::new("status", b"OK".to_vec())]))
+    }
+
+    #[test]
+    fn answers_queries_in_memory() {
+        let client = super::MockClient::new();
+        assert_eq!(format!("{}", set_status(&client, "row")), "OK.");
+        assert_eq!(
+            format!("{}", client.clone().query(query::Query::new_select("row", &["status"]))),
+            r#"Data: ["OK"]"#
+        );
+        assert_eq!(format!("{}", client.query(query::Query::new_select("other", &["status"]))), "Row not found.");
+    }
+
+    #[test]
+    fn can_inject_failures() {
+        let client = super::MockClient::new();
+        client.fail_next(2);
+        assert_eq!(format!("{}", set_status(&client, "row")), format!("{}", query::QueryResult::NetworkError));
+        assert_eq!(format!("{}", set_status(&client, "row")), format!("{}", query::QueryResult::NetworkError));
+        assert_eq!(format!("{}", set_status(&client, "row")), "OK.");
+
+        client.set_error_rate(1.0);
+        assert_eq!(format!("{}", set_status(&client, "row")), format!("{}", query::QueryResult::NetworkError));
+        client.set_error_rate(0.0);
+
+        client.set_latency(Duration::from_millis(20));
+        let start = Instant::now();
+        set_status(&client, "row");
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn runs_batches_and_scans() {
+        let client = super::MockClient::new();
+        let results = client.batch(vec![
+            query::Query::new_update("a", vec![query::MUpdate::new("status", b"OK".to_vec())]),
+            query::Query::new_update("b", vec![query::MUpdate::new("status", b"OK".to_vec())]),
+            query::Query::new_select("a", &["status"])
+        ]);
+        assert_eq!(
+            results.iter().map(|r| format!("{}", r)).collect::<Vec<_>>(),
+            vec!["OK.", "OK.", r#"Data: ["OK"]"#]
+        );
+
+        assert_eq!(format!("{}", client.scan("a", "", &["status"], 1)), r#"Rows: [a: {status: "OK"}], next: "b""#);
+        assert_eq!(format!("{}", client.scan("b", "c", &[], 1)), r#"Rows: [b: {status: "OK"}], next: """#);
+    }
+}
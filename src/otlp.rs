@@ -0,0 +1,229 @@
+/*
+    otlp.rs
+
+    Exports spans to an OpenTelemetry collector, using the JSON encoding
+    of OTLP over HTTP. Spans are buffered, and sent in batches from a
+    background thread, so that queries never wait on the collector.
+*/
+
+use std::mem;
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+
+use hyper;
+use hyper::header::ContentType;
+use serde_json;
+use time;
+
+use largetable_core::spans;
+
+// How often buffered spans are sent to the collector.
+const EXPORT_INTERVAL_MS: u64 = 1000;
+
+// If the collector can't keep up, spans beyond this many are dropped
+// rather than using up memory.
+const MAX_BUFFERED_SPANS: usize = 10000;
+
+pub struct Exporter {
+    buffer: Mutex<Vec<spans::Span>>
+}
+
+impl spans::SpanSink for Exporter {
+    fn record(&self, span: spans::Span) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.len() < MAX_BUFFERED_SPANS {
+            buffer.push(span);
+        }
+    }
+}
+
+impl Exporter {
+    // Start exporting spans to the collector at the endpoint, e.g.
+    // "http://localhost:4318".
+    pub fn start(endpoint: &str) -> Arc<Exporter> {
+        let exporter = Arc::new(Exporter{
+            buffer: Mutex::new(vec![])
+        });
+
+        let url = format!("{}/v1/traces", endpoint.trim_right_matches('/'));
+        let e = exporter.clone();
+        thread::spawn(move || {
+            let client = hyper::Client::new();
+            loop {
+                thread::sleep(Duration::from_millis(EXPORT_INTERVAL_MS));
+                let batch = mem::replace(&mut *e.buffer.lock().unwrap(), vec![]);
+                if batch.is_empty() {
+                    continue;
+                }
+
+                let body = encode(&batch);
+                match client.post(&url).header(ContentType::json()).body(body.as_str()).send() {
+                    Ok(ref r) if r.status.is_success() => (),
+                    Ok(r)   => warn!("OTLP collector refused {} spans: {}", batch.len(), r.status),
+                    Err(e)  => warn!("Unable to export {} spans: {}", batch.len(), e)
+                };
+            }
+        });
+
+        exporter
+    }
+}
+
+#[derive(Serialize)]
+struct ExportRequest {
+    #[serde(rename="resourceSpans")]
+    resource_spans: Vec<ResourceSpans>
+}
+
+#[derive(Serialize)]
+struct ResourceSpans {
+    resource: Resource,
+    #[serde(rename="scopeSpans")]
+    scope_spans: Vec<ScopeSpans>
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>
+}
+
+#[derive(Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<OtlpSpan>
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: String
+}
+
+#[derive(Serialize)]
+struct OtlpSpan {
+    #[serde(rename="traceId")]
+    trace_id: String,
+    #[serde(rename="spanId")]
+    span_id: String,
+    #[serde(rename="parentSpanId", skip_serializing_if="String::is_empty")]
+    parent_span_id: String,
+    name: String,
+    kind: u32,
+    #[serde(rename="startTimeUnixNano")]
+    start_time_unix_nano: String,
+    #[serde(rename="endTimeUnixNano")]
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue
+}
+
+#[derive(Serialize)]
+struct AnyValue {
+    #[serde(rename="stringValue")]
+    string_value: String
+}
+
+fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue{
+        key: key.to_owned(),
+        value: AnyValue{string_value: value.to_owned()}
+    }
+}
+
+// Make up a new span ID, as OTLP expects: 16 hex characters.
+pub fn new_span_id() -> String {
+    format!("{:016x}", ::rand::random::<u64>())
+}
+
+fn fnv1a(seed: u64, data: &[u8]) -> u64 {
+    data.iter().fold(seed, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+// OTLP trace IDs are 32 hex characters. Trace IDs which are already in
+// that form are used as they are, and anything else is hashed into one,
+// so that the same trace ID always ends up in the same trace.
+fn otlp_trace_id(id: &str) -> String {
+    if id.len() == 32 && id.chars().all(|c| c.is_digit(16)) {
+        return id.to_lowercase();
+    }
+
+    format!(
+        "{:016x}{:016x}",
+        fnv1a(0xcbf29ce484222325, id.as_bytes()),
+        fnv1a(0x84222325cbf29ce4, id.as_bytes())
+    )
+}
+
+// Encode the spans as an OTLP export request. Span times come from the
+// monotonic clock, so they're shifted onto the wall clock here.
+fn encode(batch: &[spans::Span]) -> String {
+    let wall = time::get_time();
+    let wall_ns = wall.sec as u64 * 1_000_000_000 + wall.nsec as u64;
+    let offset = wall_ns.saturating_sub(time::precise_time_ns());
+
+    let request = ExportRequest{
+        resource_spans: vec![ResourceSpans{
+            resource: Resource{
+                attributes: vec![key_value("service.name", "largetable")]
+            },
+            scope_spans: vec![ScopeSpans{
+                scope: Scope{name: String::from("largetable")},
+                spans: batch.iter().map(|s| OtlpSpan{
+                    trace_id: otlp_trace_id(&s.trace_id),
+                    span_id: match s.span_id.as_str() {
+                        ""  => new_span_id(),
+                        id  => id.to_owned()
+                    },
+                    parent_span_id: s.parent_span_id.clone(),
+                    name: s.name.clone(),
+                    kind: 1,
+                    start_time_unix_nano: format!("{}", s.start + offset),
+                    end_time_unix_nano: format!("{}", s.end + offset),
+                    attributes: s.attributes.iter().map(|&(ref k, ref v)| key_value(k, v)).collect()
+                }).collect()
+            }]
+        }]
+    };
+
+    serde_json::to_string(&request).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use largetable_core::spans;
+
+    #[test]
+    fn can_convert_trace_ids() {
+        let id = "0af7651916cd43dd8448eb211c80319c";
+        assert_eq!(super::otlp_trace_id(id), id);
+
+        let hashed = super::otlp_trace_id("client-trace-1");
+        assert_eq!(hashed.len(), 32);
+        assert_eq!(hashed, super::otlp_trace_id("client-trace-1"));
+        assert!(hashed != super::otlp_trace_id("client-trace-2"));
+    }
+
+    #[test]
+    fn can_encode_spans() {
+        let encoded = super::encode(&[spans::Span{
+            trace_id: String::from("0af7651916cd43dd8448eb211c80319c"),
+            span_id: String::from("b7ad6b7169203331"),
+            parent_span_id: String::new(),
+            name: String::from("dtable.read"),
+            start: 100,
+            end: 200,
+            attributes: vec![(String::from("dtable"), String::from("./data/1.dtable"))]
+        }]);
+
+        assert!(encoded.contains(r#""traceId":"0af7651916cd43dd8448eb211c80319c""#));
+        assert!(encoded.contains(r#""spanId":"b7ad6b7169203331""#));
+        assert!(encoded.contains(r#""name":"dtable.read""#));
+        assert!(encoded.contains(r#"{"key":"dtable","value":{"stringValue":"./data/1.dtable"}}"#));
+        assert!(!encoded.contains("parentSpanId"));
+    }
+}
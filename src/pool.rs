@@ -0,0 +1,237 @@
+/*
+    pool.rs
+
+    The WorkerPool runs queries on a fixed number of threads, fed by a
+    bounded queue. When the queue is full, new queries are turned away
+    straight away instead of piling up behind the database lock. If a
+    query panics, it fails with an InternalError and the worker carries
+    on with the next one.
+
+    Queries in the queue or running hold a reservation of the memory
+    budget (see budget.rs), and ones which don't fit in it are turned
+    away with ResourceExhausted.
+
+    Along with its result, each query's cost (see QueryCost) is returned,
+    including how long it waited in the queue. The number of queries
+    waiting and running is kept for the status page.
+*/
+
+use std::any::Any;
+use std::panic;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+use time;
+
+use largetable_core::{budget, query, Database};
+
+struct Job {
+    query: query::Query,
+    context: query::QueryContext,
+    reply: mpsc::Sender<(query::QueryResult, u64, query::QueryCost)>,
+    reservation: budget::Reservation,
+    queued: u64
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    Busy
+}
+
+// How busy the pool is: the number of queries waiting in the queue and
+// being run, out of how many can be.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct PoolStats {
+    pub threads: usize,
+    pub queue_depth: usize,
+    pub queued: usize,
+    pub running: usize
+}
+
+pub struct WorkerPool {
+    queue: Mutex<mpsc::SyncSender<Job>>,
+    budget: budget::MemoryBudget,
+    threads: usize,
+    queue_depth: usize,
+    queued: Arc<AtomicUsize>,
+    running: Arc<AtomicUsize>
+}
+
+impl WorkerPool {
+    pub fn new(database: Arc<Database>, threads: usize, queue_depth: usize, budget: budget::MemoryBudget) -> WorkerPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..threads {
+            let database = database.clone();
+            let receiver = receiver.clone();
+            let queued = queued.clone();
+            let running = running.clone();
+            thread::spawn(move || loop {
+                let job = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                    Ok(j)   => j,
+                    Err(_)  => return
+                };
+                queued.fetch_sub(1, Ordering::SeqCst);
+                running.fetch_add(1, Ordering::SeqCst);
+
+                let queue_ns = time::precise_time_ns() - job.queued;
+                let context = job.context;
+                let query = job.query;
+                let mut result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    database.query_with_cost(query, &context)
+                })).unwrap_or_else(|e| {
+                    let message = panic_message(&e);
+                    error!("query panicked: {} (trace_id={})", message, context.trace_id);
+                    (query::QueryResult::InternalError{ error: message }, 0, query::QueryCost::default())
+                });
+                result.2.queue_ns = queue_ns;
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                // If the requester has gone away, there's nobody to
+                // tell about the result.
+                job.reply.send(result).unwrap_or(());
+                drop(job.reservation);
+            });
+        }
+
+        WorkerPool{
+            queue: Mutex::new(sender),
+            budget: budget,
+            threads: threads,
+            queue_depth: queue_depth,
+            queued: queued,
+            running: running
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats{
+            threads: self.threads,
+            queue_depth: self.queue_depth,
+            queued: self.queued.load(Ordering::SeqCst),
+            running: self.running.load(Ordering::SeqCst)
+        }
+    }
+
+    // Run the query on the pool, and wait for the result, the timestamp
+    // that the query ran at and its cost. Fails with Busy if the queue is
+    // already full.
+    pub fn run(&self, q: query::Query, context: query::QueryContext) -> Result<(query::QueryResult, u64, query::QueryCost), PoolError> {
+        let reservation = match self.budget.reserve(&q) {
+            Ok(r)   => r,
+            Err(e)  => {
+                info!("query is over the memory budget: {} (trace_id={})", e, context.trace_id);
+                return Ok((query::QueryResult::ResourceExhausted{reason: e}, 0, query::QueryCost::default()));
+            }
+        };
+
+        // The job is counted before it's sent, so that a worker never
+        // takes it off the count first.
+        let (reply, result) = mpsc::channel();
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+            .try_send(Job{
+                query: q,
+                context: context,
+                reply: reply,
+                reservation: reservation,
+                queued: time::precise_time_ns()
+            })
+            .map_err(|_| {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                PoolError::Busy
+            })?;
+        result.recv().map_err(|_| PoolError::Busy)
+    }
+}
+
+// The message that a thread panicked with, if it was a string.
+pub fn panic_message(payload: &Box<Any + Send>) -> String {
+    match payload.downcast_ref::<&'static str>() {
+        Some(s) => s.to_string(),
+        None    => match payload.downcast_ref::<String>() {
+            Some(s) => s.clone(),
+            None    => String::from("unknown panic")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use largetable_core::{base, budget, query, Database};
+
+    // Poll until the condition holds, failing if it takes too long.
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        for _ in 0..1000 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out waiting for the pool to settle");
+    }
+
+    #[test]
+    fn sheds_load_when_full() {
+        let mut b = base::Base::new_stub();
+        b.load().unwrap();
+        let database = Arc::new(Database::from_base(b));
+        let pool = Arc::new(super::WorkerPool::new(database.clone(), 1, 2, budget::MemoryBudget::new(0, 0, 1024)));
+        let finished = Arc::new(AtomicUsize::new(0));
+        let spawn_query = || {
+            let pool = pool.clone();
+            let finished = finished.clone();
+            thread::spawn(move || {
+                let ok = pool.run(query::Query::Stats, query::QueryContext::new()).is_ok();
+                finished.fetch_add(1, Ordering::SeqCst);
+                ok
+            })
+        };
+
+        // While the database is locked, the worker is stuck on the first
+        // query, so only two more fit in the queue and the rest are shed.
+        let guard = database.lock();
+        let mut handles = vec![spawn_query()];
+        wait_until(|| pool.stats().running == 1);
+        handles.extend((0..9).map(|_| spawn_query()));
+        wait_until(|| finished.load(Ordering::SeqCst) == 7);
+        assert_eq!(pool.stats(), super::PoolStats{threads: 1, queue_depth: 2, queued: 2, running: 1});
+        drop(guard);
+
+        let completed = handles.into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|&ok| ok)
+            .count();
+        assert_eq!(completed, 3);
+        assert_eq!((pool.stats().queued, pool.stats().running), (0, 0));
+    }
+
+    #[test]
+    fn refuses_queries_over_budget() {
+        let mut b = base::Base::new_stub();
+        b.load().unwrap();
+        let database = Arc::new(Database::from_base(b));
+        let pool = super::WorkerPool::new(database, 1, 2, budget::MemoryBudget::new(1024, 0, 1024));
+
+        let run = |columns: &[&str]| format!("{}", pool.run(query::Query::new_select("row", columns), query::QueryContext::new()).unwrap().0);
+        assert_eq!(run(&["a"]), "Row not found.");
+        assert_eq!(run(&["a", "b"]), "Resource exhausted: query needs about 2048 bytes, over the limit of 1024");
+    }
+
+    #[test]
+    fn can_describe_panics() {
+        let payload = thread::spawn(|| panic!("disk on fire")).join().unwrap_err();
+        assert_eq!(super::panic_message(&payload), "disk on fire");
+        let payload = thread::spawn(|| panic!("row {} is broken", 5)).join().unwrap_err();
+        assert_eq!(super::panic_message(&payload), "row 5 is broken");
+    }
+}
@@ -0,0 +1,63 @@
+/*
+    prepared.rs
+
+    A PreparedSelect runs the same select on lots of rows. The columns
+    are prepared on the server the first time it's used, and from then on
+    only the handle and the row are sent. If the server has forgotten the
+    handle, e.g. because it restarted, the columns are prepared again and
+    the select is retried.
+*/
+
+use std::sync::Mutex;
+
+use query;
+use LargeClient;
+
+pub struct PreparedSelect {
+    client: LargeClient,
+    get: Vec<String>,
+
+    // The handle from the server, or 0 if it hasn't been prepared yet.
+    handle: Mutex<u64>
+}
+
+impl PreparedSelect {
+    pub fn new(client: &LargeClient, get: &[&str]) -> PreparedSelect {
+        PreparedSelect{
+            client: client.clone(),
+            get: get.iter().map(|s| s.to_string()).collect(),
+            handle: Mutex::new(0)
+        }
+    }
+
+    // Prepare the columns on the server, and return the handle, or the
+    // result if it failed.
+    fn prepare(&self) -> Result<u64, query::QueryResult> {
+        match self.client.query(query::Query::Prepare{get: self.get.clone()}) {
+            query::QueryResult::Prepared{handle: h} => {
+                *self.handle.lock().unwrap() = h;
+                Ok(h)
+            },
+            x => Err(x)
+        }
+    }
+
+    // Select the columns from the row.
+    pub fn query(&self, row: &str) -> query::QueryResult {
+        let handle = match *self.handle.lock().unwrap() {
+            0 => match self.prepare() {
+                Ok(h)   => h,
+                Err(r)  => return r
+            },
+            h => h
+        };
+
+        match self.client.query(query::Query::new_execute(handle, row)) {
+            query::QueryResult::NotPrepared => match self.prepare() {
+                Ok(h)   => self.client.query(query::Query::new_execute(h, row)),
+                Err(r)  => r
+            },
+            x => x
+        }
+    }
+}
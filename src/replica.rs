@@ -0,0 +1,238 @@
+/*
+    replica.rs
+
+    Keeps a replica up to date with its primary. A new replica is
+    bootstrapped from a snapshot which the primary streams to it, and from
+    then on it tails the primary's commit log, applying the entries as
+    they arrive (see largetable_core::replication).
+
+    With leader elections turned on, the primary renews a lease and
+    announces it to the replica along with the commit log. If the primary
+    stops answering and its lease runs out, the replica takes over as the
+    leader, with the next term as its fencing token (see
+    largetable_core::election).
+*/
+
+use std::cmp;
+use std::io::Read;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use hyper;
+use hyper::header::Headers;
+use hyper::status::StatusCode;
+use serde_json;
+use time;
+
+use largetable_core::{base, election, replication, Database};
+
+// The primary says where a replica should carry on tailing from in this
+// header.
+pub const POSITION_HEADER: &'static str = "X-Largetable-Position";
+
+// A replica says who it is in this header, so that the primary can keep
+// hints for it (see largetable_core::hints).
+pub const PEER_HEADER: &'static str = "X-Largetable-Peer";
+
+// The primary announces its lease in this header, as the term, the number
+// of milliseconds it has left and the holder. Sending what's left of the
+// lease, rather than when it expires, means that the clocks of the two
+// servers don't have to agree.
+pub const LEASE_HEADER: &'static str = "X-Largetable-Lease";
+
+// Once a replica has read all of the commit log, the primary tells it the
+// timestamp that it has every write up to in this header, so that it can
+// serve reads which have to see writes up to then.
+pub const CAUGHT_UP_HEADER: &'static str = "X-Largetable-Caught-Up";
+
+pub fn encode_lease(lease: &election::Lease, now: u64) -> String {
+    format!("{} {} {}", lease.term, lease.expires.saturating_sub(now) / 1_000_000, lease.holder)
+}
+
+pub fn decode_lease(value: &str, now: u64) -> Option<election::Lease> {
+    let mut parts = value.splitn(3, ' ');
+    let term = parts.next()?.parse().ok()?;
+    let remaining_ms = parts.next()?.parse::<u64>().ok()?;
+    let holder = parts.next()?;
+    Some(election::Lease{
+        holder: holder.to_owned(),
+        term: term,
+        expires: now + remaining_ms * 1_000_000
+    })
+}
+
+fn post(primary: &str, path: &str, body: &str, auth_token: &str, peer: &str) -> Result<hyper::client::Response, String> {
+    let mut headers = Headers::new();
+    if !auth_token.is_empty() {
+        headers.set_raw("Authorization", vec![format!("Bearer {}", auth_token).into_bytes()]);
+    }
+    if !peer.is_empty() {
+        headers.set_raw(PEER_HEADER, vec![peer.as_bytes().to_vec()]);
+    }
+    let url = format!("http://{}{}", primary, path);
+    hyper::Client::new().post(&url).headers(headers).body(body).send()
+        .map_err(|e| format!("unable to reach {}: {}", primary, e))
+}
+
+// Install a snapshot of the primary into the replica's data directory,
+// which has to be empty, before the replica is loaded. Returns the
+// position to tail the primary's commit log from.
+pub fn bootstrap(database: &mut base::Base, primary: &str, auth_token: &str) -> Result<replication::Position, String> {
+    let mut res = post(primary, "/snapshot", "", auth_token, "")?;
+    if res.status != StatusCode::Ok {
+        return Err(format!("{} refused to send a snapshot: {}", primary, res.status));
+    }
+    database.install_snapshot(&mut res).map_err(|e| format!("unable to install the snapshot: {}", e))
+}
+
+// The commit log entries which a replica read from its primary.
+struct Fetched {
+    data: Vec<u8>,
+    next: replication::Position,
+    lease: Option<String>,
+    caught_up: Option<u64>
+}
+
+// Read the commit log entries after the position from the primary, the
+// position to carry on from, the primary's lease, if it announced one,
+// and the timestamp the replica has caught up to, if it's read all of
+// them. None means that the primary doesn't have the entries any more.
+fn fetch(primary: &str, auth_token: &str, peer: &str, position: &replication::Position) -> Result<Option<Fetched>, String> {
+    let body = serde_json::to_string(position).map_err(|e| format!("{}", e))?;
+    let mut res = post(primary, "/replicate", &body, auth_token, peer)?;
+    match res.status {
+        StatusCode::Ok      => (),
+        StatusCode::Gone    => return Ok(None),
+        s                   => return Err(format!("{} responded with {}", primary, s))
+    };
+
+    let next = res.headers.get_raw(POSITION_HEADER)
+        .and_then(|v| v.first())
+        .and_then(|v| str::from_utf8(v).ok())
+        .and_then(replication::Position::parse)
+        .ok_or_else(|| format!("{} didn't say which position to carry on from", primary))?;
+    let lease = res.headers.get_raw(LEASE_HEADER)
+        .and_then(|v| v.first())
+        .and_then(|v| str::from_utf8(v).ok())
+        .map(|v| v.to_owned());
+    let caught_up = res.headers.get_raw(CAUGHT_UP_HEADER)
+        .and_then(|v| v.first())
+        .and_then(|v| str::from_utf8(v).ok())
+        .and_then(|v| v.parse().ok());
+    let mut data = vec![];
+    res.read_to_end(&mut data).map_err(|e| format!("unable to read from {}: {}", primary, e))?;
+    Ok(Some(Fetched{data: data, next: next, lease: lease, caught_up: caught_up}))
+}
+
+// Keep renewing this server's lease, every third of the lease, and stamp
+// its term onto the commit log entries that it writes. Writes are only
+// accepted until the lease expires. If another replica has taken over in
+// the meantime, or the lease ran out before it was renewed, it stops.
+pub fn lead(database: Arc<Database>, election: Arc<Mutex<election::Election>>) {
+    thread::spawn(move || {
+        loop {
+            let (lease, interval) = {
+                let mut e = election.lock().unwrap();
+                (e.campaign(time::precise_time_ns()), cmp::max(e.lease_ms / 3, 1))
+            };
+            match lease {
+                Some(l) => {
+                    let mut database = database.lock();
+                    if l.term > database.fencing_token {
+                        info!("leading with term {}", l.term);
+                        database.fencing_token = l.term;
+                    }
+                    database.lease_expires = Some(l.expires);
+                },
+                None => {
+                    warn!("another replica holds the lease, or this one's ran out, so it has stopped leading");
+                    return;
+                }
+            };
+            thread::sleep(Duration::from_millis(interval));
+        }
+    });
+}
+
+// Campaign to take over from the primary, which only succeeds once its
+// lease has run out. A replica which has never heard of a lease doesn't
+// try, since the primary may not be running elections at all.
+fn take_over(election: &Mutex<election::Election>) -> bool {
+    let mut e = election.lock().unwrap();
+    e.lease().is_some() && e.campaign(time::precise_time_ns()).is_some()
+}
+
+// Tail the primary's commit log in the background, polling it every
+// poll_ms while the replica is caught up. If the replica falls too far
+// behind, or an entry can't be applied, it stops, and the replica has to
+// be bootstrapped again. With an election, it also stops once it has
+// taken over as the leader.
+pub fn tail(database: Arc<Database>, primary: String, auth_token: String, peer: String, poll_ms: u64, position: replication::Position, election: Option<Arc<Mutex<election::Election>>>) {
+    thread::spawn(move || {
+        let mut position = position;
+        loop {
+            match fetch(&primary, &auth_token, &peer, &position) {
+                Ok(Some(fetched)) => {
+                    let lease = fetched.lease.and_then(|l| decode_lease(&l, time::precise_time_ns()));
+                    if let (Some(e), Some(l)) = (election.as_ref(), lease) {
+                        e.lock().unwrap().observe(l);
+                    }
+
+                    {
+                        let mut database = database.lock();
+                        if let Err(e) = database.apply_replicated(&fetched.data) {
+                            error!("unable to apply the commit log of {} at {}: {}", primary, position, e);
+                            return;
+                        }
+                        if let Some(timestamp) = fetched.caught_up {
+                            database.caught_up(timestamp);
+                        }
+                    }
+                    position = fetched.next;
+
+                    // While there's more to catch up on, don't wait.
+                    if !fetched.data.is_empty() {
+                        continue;
+                    }
+                },
+                Ok(None) => {
+                    error!("fell too far behind {} at {}, so the replica needs to be bootstrapped again", primary, position);
+                    return;
+                },
+                Err(e) => {
+                    warn!("unable to tail the commit log: {}", e);
+                    if let Some(ref e) = election {
+                        if take_over(e) {
+                            info!("{} stopped answering and its lease ran out, so this replica is taking over", primary);
+                            database.lock().replica = false;
+                            lead(database.clone(), e.clone());
+                            return;
+                        }
+                    }
+                }
+            };
+            thread::sleep(Duration::from_millis(poll_ms));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use largetable_core::election;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn passes_leases_in_headers() {
+        let lease = election::Lease{holder: String::from("db1:8080"), term: 3, expires: 500 * MS};
+        let header = super::encode_lease(&lease, 200 * MS);
+        assert_eq!(header, "3 300 db1:8080");
+
+        // The replica's clock can be off, since only what's left counts.
+        let decoded = super::decode_lease(&header, 1000 * MS).unwrap();
+        assert_eq!(decoded, election::Lease{holder: String::from("db1:8080"), term: 3, expires: 1300 * MS});
+        assert_eq!(super::decode_lease("3 abc db1", 0), None);
+    }
+}
@@ -0,0 +1,237 @@
+/*
+    scanner.rs
+
+    The Scanner reads every row in a key range, for batch jobs which need
+    to look at a whole table. It splits the range into sub-ranges, scans
+    them in parallel, and follows each scan's continuation key until the
+    sub-range is exhausted. Rows are handed back through an iterator as
+    they arrive.
+*/
+
+use std::thread;
+use std::time::Duration;
+use std::sync::mpsc;
+
+use query;
+use {Key, LargeClient, ClientError};
+
+// How many times a scan request is retried while the server is busy,
+// and how long to wait before the first retry. The wait doubles after
+// each attempt.
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY_MS: u64 = 50;
+
+pub struct Scanner {
+    client: LargeClient,
+
+    // The range to scan is [start, end). An empty end means that there's
+    // no upper bound.
+    pub start: String,
+    pub end: String,
+
+    // The columns to read, or every column if empty.
+    pub columns: Vec<String>,
+
+    // The number of sub-ranges which are scanned at the same time, and
+    // how many rows are requested at once by each of them.
+    pub parallelism: usize,
+    pub batch_size: u64,
+
+    // Rows which have been fetched but not yet consumed, beyond which
+    // the scans wait for the consumer to catch up.
+    pub buffer_rows: usize
+}
+
+pub struct ScanIter {
+    rows: mpsc::Receiver<Result<query::ScanRow, ClientError>>
+}
+
+impl Iterator for ScanIter {
+    type Item = Result<query::ScanRow, ClientError>;
+
+    fn next(&mut self) -> Option<Result<query::ScanRow, ClientError>> {
+        self.rows.recv().ok()
+    }
+}
+
+impl Scanner {
+    pub fn new(client: &LargeClient, start: &str, end: &str) -> Scanner {
+        Scanner{
+            client: client.clone(),
+            start: start.to_owned(),
+            end: end.to_owned(),
+            columns: vec![],
+            parallelism: 4,
+            batch_size: 1000,
+            buffer_rows: 10000
+        }
+    }
+
+    // Scan every row whose composite key starts with the parts of the
+    // given key, e.g. all of a user's events with
+    // Key::builder().prefix("events/").string(user).
+    pub fn with_key_prefix(client: &LargeClient, key: &Key) -> Scanner {
+        let (start, end) = key.range();
+        Scanner::new(client, &start, &end)
+    }
+
+    // Start scanning. Rows within a sub-range arrive in key order, but
+    // the sub-ranges are interleaved. If a sub-range fails, its error is
+    // yielded and the other sub-ranges carry on.
+    pub fn scan(self) -> ScanIter {
+        let (sender, receiver) = mpsc::sync_channel(self.buffer_rows);
+        for (start, end) in split_range(&self.start, &self.end, self.parallelism) {
+            let sender = sender.clone();
+            let client = self.client.clone();
+            let columns = self.columns.clone();
+            let batch_size = self.batch_size;
+            thread::spawn(move || scan_range(client, start, end, columns, batch_size, sender));
+        }
+
+        ScanIter{
+            rows: receiver
+        }
+    }
+}
+
+fn scan_range(
+    client: LargeClient,
+    start: String,
+    end: String,
+    columns: Vec<String>,
+    batch_size: u64,
+    sender: mpsc::SyncSender<Result<query::ScanRow, ClientError>>
+) {
+    let mut next = start;
+    loop {
+        let (rows, continuation) = match scan_batch(&client, &next, &end, &columns, batch_size) {
+            Ok(r)   => r,
+            Err(e)  => {
+                sender.send(Err(e)).unwrap_or(());
+                return;
+            }
+        };
+
+        for row in rows {
+            // If the iterator has been dropped, nobody wants the rest.
+            if sender.send(Ok(row)).is_err() {
+                return;
+            }
+        }
+
+        if continuation.is_empty() {
+            return;
+        }
+        next = continuation;
+    }
+}
+
+// Request one batch of rows, retrying with backoff while the server is
+// busy. Returns the rows and the continuation key.
+fn scan_batch(
+    client: &LargeClient,
+    start: &str,
+    end: &str,
+    columns: &[String],
+    batch_size: u64
+) -> Result<(Vec<query::ScanRow>, String), ClientError> {
+    let mut delay = RETRY_DELAY_MS;
+    for attempt in 0..(MAX_RETRIES + 1) {
+        let result = client.query(query::Query::Scan{
+            start: start.to_owned(),
+            end: end.to_owned(),
+            get: columns.to_vec(),
+            limit: batch_size,
+            snapshot: 0
+        });
+
+        match result {
+            query::QueryResult::Rows{rows: r, next: n}  => return Ok((r, n)),
+            query::QueryResult::Busy if attempt < MAX_RETRIES => {
+                thread::sleep(Duration::from_millis(delay));
+                delay *= 2;
+            },
+            query::QueryResult::Busy                    => return Err(ClientError::Busy),
+            query::QueryResult::NetworkError            => return Err(ClientError::NetworkError),
+            query::QueryResult::PermissionDenied        => return Err(ClientError::PermissionDenied),
+            _                                           => return Err(ClientError::RequestFailed)
+        };
+    }
+    Err(ClientError::Busy)
+}
+
+// Split [start, end) into up to `parts` sub-ranges, by dividing up the
+// characters which can follow the part that start and end have in
+// common. This assumes keys are spread fairly evenly over ASCII
+// characters; keys outside ASCII all end up in the last sub-range.
+pub fn split_range(start: &str, end: &str, parts: usize) -> Vec<(String, String)> {
+    let prefix = start.chars()
+        .zip(end.chars())
+        .take_while(|&(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect::<String>();
+
+    let lo = start[prefix.len()..].chars().next().map(|c| c as u32).unwrap_or(0);
+    let hi = match end[prefix.len()..].chars().next() {
+        Some(c) if (c as u32) < 0x80 => c as u32,
+        _                            => 0x80
+    };
+
+    let mut boundaries = vec![];
+    if lo < hi {
+        for i in 1..parts {
+            let b = lo + (hi - lo) * i as u32 / parts as u32;
+            if b > lo && boundaries.last().map(|&x| b > x).unwrap_or(true) {
+                boundaries.push(b);
+            }
+        }
+    }
+
+    let mut ranges = vec![];
+    let mut from = start.to_owned();
+    for b in boundaries {
+        let to = format!("{}{}", prefix, ::std::char::from_u32(b).unwrap());
+        ranges.push((from, to.clone()));
+        from = to;
+    }
+    ranges.push((from, end.to_owned()));
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn can_split_ranges() {
+        let ranges = super::split_range("user/a", "user/e", 4);
+        assert_eq!(ranges, vec![
+            (String::from("user/a"), String::from("user/b")),
+            (String::from("user/b"), String::from("user/c")),
+            (String::from("user/c"), String::from("user/d")),
+            (String::from("user/d"), String::from("user/e"))
+        ]);
+
+        // The sub-ranges always cover the whole range, without gaps.
+        for &(start, end, parts) in &[("", "", 8), ("a", "", 3), ("row10", "row2", 16), ("x", "xyz", 4)] {
+            let ranges = super::split_range(start, end, parts);
+            assert!(ranges.len() >= 1 && ranges.len() <= parts);
+            assert_eq!(ranges.first().unwrap().0, start);
+            assert_eq!(ranges.last().unwrap().1, end);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].1, pair[1].0);
+                assert!(pair[0].0 < pair[0].1);
+            }
+        }
+
+        // There's nothing to split between adjacent keys.
+        assert_eq!(super::split_range("a", "b", 4).len(), 1);
+    }
+
+    #[test]
+    fn can_scan_composite_key_prefixes() {
+        let client = ::LargeClient::new("localhost:8080").unwrap();
+        let prefix = ::Key::builder().prefix("events/").string("alice");
+        let scanner = super::Scanner::with_key_prefix(&client, &prefix);
+        assert_eq!(scanner.start, "events/alice\0\x01");
+        assert_eq!(scanner.end, "events/alice\0\x02");
+    }
+}
@@ -0,0 +1,73 @@
+/*
+    session.rs
+
+    A Session gives read-your-writes guarantees: once a write made
+    through the session has succeeded, every later read through it sees
+    that write. It remembers the newest commit timestamp among its
+    writes, and asks for reads to run no earlier than that.
+
+    With a single server this only matters if its clock goes backwards.
+    A replica which hasn't caught up to the session's writes yet refuses
+    its reads with ReplicaBehind, rather than answering as if the writes
+    were lost, and the read can be retried, or sent to another server.
+*/
+
+use query;
+use {LargeClient, RequestOptions};
+
+pub struct Session {
+    client: LargeClient,
+    min_read_timestamp: u64
+}
+
+impl Session {
+    pub fn new(client: &LargeClient) -> Session {
+        Session{
+            client: client.clone(),
+            min_read_timestamp: 0
+        }
+    }
+
+    pub fn query(&mut self, q: query::Query) -> query::QueryResult {
+        let write = q.is_write();
+        let response = self.client.run(q, RequestOptions{
+            min_read_timestamp: if write { 0 } else { self.min_read_timestamp },
+            ..Default::default()
+        });
+
+        if let query::QueryResult::Done = response.result {
+            if write {
+                self.observe(response.timestamp);
+            }
+        }
+        response.result
+    }
+
+    // The newest commit timestamp among the session's writes, which is
+    // the earliest timestamp its reads are allowed to run at.
+    pub fn min_read_timestamp(&self) -> u64 {
+        self.min_read_timestamp
+    }
+
+    fn observe(&mut self, timestamp: u64) {
+        if timestamp > self.min_read_timestamp {
+            self.min_read_timestamp = timestamp;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use LargeClient;
+
+    #[test]
+    fn only_moves_forward() {
+        let mut session = super::Session::new(&LargeClient::new("localhost:8080").unwrap());
+        assert_eq!(session.min_read_timestamp(), 0);
+
+        session.observe(200);
+        session.observe(100);
+        session.observe(0);
+        assert_eq!(session.min_read_timestamp(), 200);
+    }
+}
@@ -0,0 +1,180 @@
+/*
+    sharded.rs
+
+    The ShardedClient talks to a cluster whose rows are split into shards
+    by key range (see largetable_core::shards). It caches the cluster's
+    shard map, and sends each query to the server holding its row. When a
+    server answers WrongShard with a newer version of the map than the
+    cached one, the cluster has been resharded, so the client fetches the
+    new map and tries again. That way clients keep working through a
+    reshard without being redeployed.
+*/
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use largetable_core::shards;
+
+use query;
+use {LargeClient, ClientError, Client};
+
+// The most times a query is retried after fetching a new shard map,
+// in case the cluster is resharded again in the meantime.
+const MAX_REFRESHES: usize = 3;
+
+struct Routing {
+    map: shards::ShardMap,
+    clients: HashMap<String, LargeClient>
+}
+
+pub struct ShardedClient {
+    // The servers asked for the shard map, along with the ones in it.
+    seeds: Vec<String>,
+    auth_token: String,
+    routing: RwLock<Routing>
+}
+
+impl ShardedClient {
+    // The shard map is fetched from the first of the seed servers which
+    // answers.
+    pub fn new(seeds: Vec<String>, auth_token: &str) -> Result<ShardedClient, ClientError> {
+        if seeds.is_empty() {
+            return Err(ClientError::ConfigurationError);
+        }
+
+        let client = ShardedClient{
+            seeds: seeds,
+            auth_token: auth_token.to_owned(),
+            routing: RwLock::new(Routing{
+                map: shards::ShardMap::default(),
+                clients: HashMap::new()
+            })
+        };
+        client.refresh(0)?;
+        Ok(client)
+    }
+
+    fn connect(&self, address: &str) -> Result<LargeClient, ClientError> {
+        let mut client = LargeClient::new(address)?;
+        client.set_auth_token(&self.auth_token);
+        Ok(client)
+    }
+
+    // The version of the cached shard map.
+    pub fn version(&self) -> u64 {
+        self.routing.read().unwrap().map.version
+    }
+
+    // Fetch the shard map again, asking the servers in the cached map and
+    // then the seeds, until one has at least the version. The newest map
+    // found replaces the cached one. Returns the version of the map.
+    pub fn refresh(&self, min_version: u64) -> Result<u64, ClientError> {
+        let mut addresses = self.routing.read().unwrap().map.shards.iter()
+            .map(|s| s.address.clone())
+            .collect::<Vec<_>>();
+        addresses.extend(self.seeds.iter().cloned());
+
+        let mut newest: Option<shards::ShardMap> = None;
+        let mut last_error = ClientError::NetworkError;
+        let mut asked = vec![];
+        for address in addresses {
+            if asked.contains(&address) {
+                continue;
+            }
+            let fetched = self.connect(&address).and_then(|c| c.shard_map());
+            asked.push(address);
+            match fetched {
+                Ok(m) => {
+                    if newest.as_ref().map(|n| m.version > n.version).unwrap_or(true) {
+                        newest = Some(m);
+                    }
+                    if newest.as_ref().map(|n| n.version >= min_version).unwrap_or(false) {
+                        break;
+                    }
+                },
+                Err(e) => last_error = e
+            };
+        }
+
+        let newest = match newest {
+            Some(m) => m,
+            None    => return Err(last_error)
+        };
+        let mut routing = self.routing.write().unwrap();
+        if newest.version > routing.map.version || !routing.map.is_enabled() {
+            routing.map = newest;
+        }
+        Ok(routing.map.version)
+    }
+
+    // The client for the server holding the row, according to the cached
+    // shard map.
+    pub fn client_for(&self, row: &str) -> Option<LargeClient> {
+        let address = match self.routing.read().unwrap().map.shard_for(row) {
+            Some(s) => s.address.clone(),
+            None    => return None
+        };
+
+        let mut routing = self.routing.write().unwrap();
+        if !routing.clients.contains_key(&address) {
+            let client = match self.connect(&address) {
+                Ok(c)   => c,
+                Err(_)  => return None
+            };
+            routing.clients.insert(address.clone(), client);
+        }
+        routing.clients.get(&address).cloned()
+    }
+
+    // Send the query to the shard holding its row. A transaction goes to
+    // the shard holding its first row, and fails if its rows aren't all
+    // in the same shard. Queries which aren't about particular rows can't
+    // be routed, and aren't allowed.
+    pub fn query(&self, q: query::Query) -> query::QueryResult {
+        let row = match shards::query_rows(&q).first() {
+            Some(r) => r.to_string(),
+            None    => return query::QueryResult::NotAllowed
+        };
+
+        let mut refreshes = 0;
+        loop {
+            // A row which isn't in any shard of the cached map might be in
+            // a newer one.
+            let client = match self.client_for(&row) {
+                Some(c) => c,
+                None if refreshes < MAX_REFRESHES => {
+                    refreshes += 1;
+                    if self.refresh(self.version() + 1).is_err() {
+                        return query::QueryResult::NetworkError;
+                    }
+                    continue;
+                },
+                None => return query::QueryResult::WrongShard{version: self.version()}
+            };
+
+            match client.query(q.clone()) {
+                query::QueryResult::WrongShard{version: v} if v > self.version() && refreshes < MAX_REFRESHES => {
+                    refreshes += 1;
+                    if self.refresh(v).is_err() {
+                        return query::QueryResult::NetworkError;
+                    }
+                },
+                r => return r
+            };
+        }
+    }
+}
+
+impl Client for ShardedClient {
+    fn query(&self, q: query::Query) -> query::QueryResult {
+        ShardedClient::query(self, q)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn needs_a_seed() {
+        assert!(super::ShardedClient::new(vec![], "").is_err());
+    }
+}
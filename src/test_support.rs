@@ -0,0 +1,135 @@
+/*
+    test_support.rs
+
+    An in-process server for integration tests, so that code which uses
+    the client can be tested without running largetable in Docker. The
+    server listens on an ephemeral port on localhost, and answers the
+    same protobuf queries as the real server from a stub database, which
+    is thrown away when the server is dropped.
+
+    Only queries are served: the JSON, streaming, audit and status
+    endpoints, access control config and the worker pool aren't there.
+*/
+
+use std::io::Read;
+use std::u64;
+use std::sync::Arc;
+
+use hyper::server::{Server, Request, Response, Handler, Listening};
+use hyper::status::StatusCode;
+use protobuf::Message;
+
+use largetable_core::base;
+use largetable_core::Database;
+
+use query;
+use compression;
+use {LargeClient, request_context};
+use {TRACE_ID_HEADER, TIMESTAMP_HEADER};
+
+pub struct TestServer {
+    // The database behind the server, e.g. to set up data or check on
+    // it without going through a client.
+    pub database: Arc<Database>,
+
+    // The "host:port" that the server is listening on.
+    pub address: String,
+    listening: Listening
+}
+
+impl TestServer {
+    pub fn client(&self) -> LargeClient {
+        LargeClient::new(&self.address).unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.listening.close().unwrap_or(());
+    }
+}
+
+// Start a server backed by a new, empty stub database.
+pub fn spawn_test_server() -> TestServer {
+    let mut b = base::Base::new_stub();
+    b.load().unwrap();
+    let database = Arc::new(Database::from_base(b));
+
+    let listening = Server::http("127.0.0.1:0").unwrap()
+        .handle(TestHandler{database: database.clone()})
+        .unwrap();
+
+    TestServer{
+        database: database,
+        address: format!("127.0.0.1:{}", listening.socket.port()),
+        listening: listening
+    }
+}
+
+struct TestHandler {
+    database: Arc<Database>
+}
+
+impl Handler for TestHandler {
+    fn handle(&self, mut req: Request, mut res: Response) {
+        let context = request_context(&req);
+        res.headers_mut().set_raw(TRACE_ID_HEADER, vec![context.trace_id.clone().into_bytes()]);
+
+        let headers = req.headers.clone();
+        let mut body = vec![];
+        let q = match compression::decode(&headers, &mut req, u64::MAX).and_then(|mut r| r.read_to_end(&mut body)) {
+            Ok(_)   => query::Query::from_bytes(&mut &body[..]).ok(),
+            Err(_)  => None
+        };
+        let q = match q {
+            Some(q) => q,
+            None    => {
+                *res.status_mut() = StatusCode::BadRequest;
+                res.send(b"invalid data").unwrap_or(());
+                return;
+            }
+        };
+
+        let (result, timestamp) = self.database.query_with_timestamp(q, &context);
+        if timestamp > 0 {
+            res.headers_mut().set_raw(TIMESTAMP_HEADER, vec![format!("{}", timestamp).into_bytes()]);
+        }
+        res.send(&result.into_generated().write_to_bytes().unwrap()).unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use query;
+
+    #[test]
+    fn can_query_test_server() {
+        let server = super::spawn_test_server();
+        let client = server.client();
+
+        assert_eq!(
+            format!("{}", client.query(query::Query::new_insert("row", vec![
+                query::MUpdate::new("status", b"OK".to_vec())
+            ]))),
+            format!("{}", query::QueryResult::Done)
+        );
+        assert_eq!(
+            format!("{}", client.query(query::Query::new_select("row", &["status"]))),
+            r#"Data: ["OK"]"#
+        );
+
+        // The write is also visible in the database directly.
+        assert_eq!(
+            format!("{}", server.database.query(query::Query::new_select("row", &["status"]))),
+            r#"Data: ["OK"]"#
+        );
+    }
+
+    #[test]
+    fn only_reads_bearer_tokens() {
+        assert_eq!(::bearer_token("Bearer secret"), Some(String::from("secret")));
+        assert_eq!(::bearer_token("Basic secret"), None);
+        assert_eq!(::bearer_token("secret"), None);
+        assert_eq!(::bearer_token("Bearer Bearer secret"), Some(String::from("Bearer secret")));
+    }
+}
@@ -0,0 +1,158 @@
+/*
+    unix.rs
+
+    Unix domain socket support for hyper, so that a server and client
+    on the same machine can talk without going through TCP.
+*/
+
+use std;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, Shutdown};
+use std::os::unix::net;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{NetworkConnector, NetworkListener, NetworkStream};
+
+// Unix sockets don't have a network address, but hyper asks for one
+// anyway, so they all report this placeholder.
+fn placeholder_addr() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+pub struct UnixStream(net::UnixStream);
+
+impl Clone for UnixStream {
+    fn clone(&self) -> UnixStream {
+        UnixStream(self.0.try_clone().expect("unable to clone unix socket"))
+    }
+}
+
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for UnixStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(placeholder_addr())
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+}
+
+#[derive(Clone)]
+pub struct UnixListener(Arc<net::UnixListener>);
+
+impl UnixListener {
+    // Listen on the socket file, replacing any stale one which was left
+    // behind by a previous run.
+    pub fn bind(path: &str) -> io::Result<UnixListener> {
+        match std::fs::remove_file(path) {
+            Ok(_)                                               => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound   => (),
+            Err(e)                                              => return Err(e)
+        };
+        Ok(UnixListener(Arc::new(net::UnixListener::bind(path)?)))
+    }
+}
+
+impl NetworkListener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&mut self) -> hyper::Result<UnixStream> {
+        let (stream, _) = self.0.accept()?;
+        Ok(UnixStream(stream))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(placeholder_addr())
+    }
+}
+
+// The UnixConnector connects to the socket file, whatever host is in the
+// URL being requested.
+pub struct UnixConnector {
+    pub path: String
+}
+
+impl NetworkConnector for UnixConnector {
+    type Stream = UnixStream;
+
+    fn connect(&self, _: &str, _: u16, _: &str) -> hyper::Result<UnixStream> {
+        Ok(UnixStream(net::UnixStream::connect(&self.path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net;
+    use hyper;
+    use hyper::net::NetworkListener;
+    use time;
+
+    #[test]
+    fn can_accept_connections() {
+        let path = format!("/tmp/largetable-{}.sock", time::precise_time_ns());
+        let mut listener = super::UnixListener::bind(&path).unwrap();
+
+        let mut client = net::UnixStream::connect(&path).unwrap();
+        client.write_all(b"ping").unwrap();
+
+        let mut server = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Binding again replaces the old socket file.
+        super::UnixListener::bind(&path).unwrap();
+    }
+
+    #[test]
+    fn can_send_requests_over_socket() {
+        let path = format!("/tmp/largetable-{}.sock", time::precise_time_ns());
+        let listener = super::UnixListener::bind(&path).unwrap();
+        let mut server = hyper::server::Server::new(listener)
+            .handle(|_: hyper::server::Request, res: hyper::server::Response| {
+                res.send(b"pong").unwrap();
+            })
+            .unwrap();
+
+        let connector = super::UnixConnector{path: path.clone()};
+        let req = hyper::client::request::Request::with_connector(
+            hyper::method::Method::Get,
+            hyper::Url::parse("http://localhost/").unwrap(),
+            &connector
+        ).unwrap();
+        let mut response = req.start().unwrap().send().unwrap();
+
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "pong");
+        server.close().unwrap();
+    }
+}
@@ -7,6 +7,7 @@
 */
 
 extern crate largeclient;
+extern crate hyper;
 
 #[test]
 fn connection_should_fail() {
@@ -20,6 +21,28 @@ fn connection_should_fail() {
     }
 }
 
+#[test]
+fn stream_connection_should_fail() {
+    let client = largeclient::LargeClient::new("fake_domain:9999").unwrap();
+    match client.select_stream("fake", "field") {
+        Err(largeclient::ClientError::NetworkError) => (),
+        _ => panic!("Expected to get NetworkError, but didn't.")
+    }
+}
+
+#[test]
+fn unix_connection_should_fail() {
+    let client = largeclient::LargeClient::new_unix("/tmp/largetable-missing.sock").unwrap();
+    match client.query(largeclient::query::Query::parse(r#"{
+            "select": { "row": "fake", "get": []}
+        }"#).unwrap())
+    {
+        largeclient::query::QueryResult::NetworkError => (),
+        _ => panic!("Expected to get NetworkError, but didn't.")
+    }
+    assert!(largeclient::LargeClient::new_unix("").is_err());
+}
+
 #[test]
 fn panics_invalid_connection_string() {
     assert!(largeclient::LargeClient::new("$!@#$").is_err());
@@ -48,3 +71,134 @@ fn can_connect_to_server() {
         e => panic!("Query didn't return expected result: {}", e)
     };
 }
+
+#[test]
+fn can_stream_large_values() {
+    use std::io::Read;
+
+    let hostname = option_env!("LARGETABLE_DOCKER_SERVICE").unwrap_or("localhost:8080");
+    let client = largeclient::LargeClient::new(hostname).unwrap();
+
+    // The value spans several of the server's stream chunks.
+    let value = (0..200000).map(|i| (b'a' + (i % 26) as u8) as char).collect::<String>();
+    match client.query(largeclient::query::Query::new_update(
+        "stream_test",
+        vec![largeclient::query::MUpdate::new("blob", value.clone().into_bytes())]
+    )) {
+        largeclient::query::QueryResult::Done => (),
+        e => panic!("Query didn't return expected result: {}", e)
+    };
+
+    let mut received = String::new();
+    client.select_stream("stream_test", "blob").unwrap().read_to_string(&mut received).unwrap();
+    assert_eq!(received, value);
+
+    match client.select_stream("stream_test", "missing") {
+        Err(largeclient::ClientError::NotFound) => (),
+        _ => panic!("Expected a missing column to be NotFound.")
+    }
+}
+
+#[test]
+fn can_trace_queries() {
+    let hostname = option_env!("LARGETABLE_DOCKER_SERVICE").unwrap_or("localhost:8080");
+    let client = largeclient::LargeClient::new(hostname).unwrap();
+
+    let (_, trace_id) = client.traced_query(largeclient::query::Query::Stats, "client-trace-1");
+    assert_eq!(trace_id, "client-trace-1");
+
+    // Without a trace ID, the server picks one.
+    let (_, trace_id) = client.traced_query(largeclient::query::Query::Stats, "");
+    assert!(!trace_id.is_empty());
+}
+
+#[test]
+fn can_compress_queries() {
+    let hostname = option_env!("LARGETABLE_DOCKER_SERVICE").unwrap_or("localhost:8080");
+    let mut client = largeclient::LargeClient::new(hostname).unwrap();
+    client.set_compression(true);
+
+    // Big enough that both the query and the result are compressed.
+    let value = vec![b'x'; 100000];
+    match client.query(largeclient::query::Query::new_update(
+        "compression_test",
+        vec![largeclient::query::MUpdate::new("blob", value.clone())]
+    )) {
+        largeclient::query::QueryResult::Done => (),
+        e => panic!("Query didn't return expected result: {}", e)
+    };
+
+    match client.query(largeclient::query::Query::new_select("compression_test", &["blob"])) {
+        largeclient::query::QueryResult::Data{columns: c} => assert_eq!(c, vec![Some(value)]),
+        e => panic!("Query didn't return expected result: {}", e)
+    };
+}
+
+#[test]
+fn can_scan_in_parallel() {
+    let hostname = option_env!("LARGETABLE_DOCKER_SERVICE").unwrap_or("localhost:8080");
+    let client = largeclient::LargeClient::new(hostname).unwrap();
+
+    // The keys are spread out, so that they fall in different sub-ranges.
+    let mut expected = (0..50)
+        .map(|i| format!("scan_test/{}{:02}", (b'A' + i as u8) as char, i))
+        .collect::<Vec<_>>();
+    for key in expected.iter() {
+        match client.query(largeclient::query::Query::new_update(
+            key,
+            vec![largeclient::query::MUpdate::new("value", key.clone().into_bytes())]
+        )) {
+            largeclient::query::QueryResult::Done => (),
+            e => panic!("Query didn't return expected result: {}", e)
+        };
+    }
+
+    // Small batches, so that each sub-range needs several requests.
+    let mut scanner = largeclient::scanner::Scanner::new(&client, "scan_test/", "scan_test/~");
+    scanner.batch_size = 3;
+    let mut keys = scanner.scan().map(|r| r.unwrap().key).collect::<Vec<_>>();
+    keys.sort();
+    expected.sort();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn can_speak_versioned_protocol() {
+    use std::io::Read;
+
+    // A stats query, encoded by hand the way any protobuf library would.
+    let hostname = option_env!("LARGETABLE_DOCKER_SERVICE").unwrap_or("localhost:8080");
+    let client = hyper::Client::new();
+    let mut response = client.post(&format!("http://{}/v1/query", hostname))
+        .body(&[0x08u8, 3][..])
+        .send()
+        .unwrap();
+
+    assert_eq!(response.headers.get_raw("X-Largetable-Protocol").unwrap()[0], b"1".to_vec());
+    let mut body = vec![];
+    response.read_to_end(&mut body).unwrap();
+
+    // The result type (field 1) comes first, and is ENGINE_STATS (8).
+    assert_eq!(&body[..2], &[0x08u8, 8][..]);
+}
+
+#[test]
+fn can_read_own_writes_in_session() {
+    let hostname = option_env!("LARGETABLE_DOCKER_SERVICE").unwrap_or("localhost:8080");
+    let client = largeclient::LargeClient::new(hostname).unwrap();
+    let mut session = client.session();
+
+    match session.query(largeclient::query::Query::new_update(
+        "session_test",
+        vec![largeclient::query::MUpdate::new("status", b"written".to_vec())]
+    )) {
+        largeclient::query::QueryResult::Done => (),
+        e => panic!("Query didn't return expected result: {}", e)
+    };
+    assert!(session.min_read_timestamp() > 0);
+
+    match session.query(largeclient::query::Query::new_select("session_test", &["status"])) {
+        largeclient::query::QueryResult::Data{columns: c} => assert_eq!(c, vec![Some(b"written".to_vec())]),
+        e => panic!("Query didn't return expected result: {}", e)
+    };
+}